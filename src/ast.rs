@@ -0,0 +1,630 @@
+//! The syntax tree produced by the parser
+//!
+//! Every node records where it came from so that errors and tools can point
+//! back at the source.
+//!
+//! The nodes of a chunk live in its `Ast`, in one array for each kind of
+//! node, and refer to each other by index: an `Id` for a single node and a
+//! `List` for nodes that follow one another, like the arguments of a call.
+//! Parsing then allocates a few growing arrays rather than a box or vector
+//! for every node, and the nodes of a function end up next to each other
+//! for the passes that walk them.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::ops::{Index, Range};
+use std::rc::Rc;
+
+use lexer::{Position, Span};
+use number::Number;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    IDiv,
+    Mod,
+    Pow,
+    Concat,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+}
+impl BinOp {
+    /// The left and right binding power, where a right power lower than
+    /// the left makes the operator right associative
+    pub fn precedence(self) -> (u8, u8) {
+        match self {
+            BinOp::Or => (1, 1),
+            BinOp::And => (2, 2),
+            BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge => (3, 3),
+            BinOp::BOr => (4, 4),
+            BinOp::BXor => (5, 5),
+            BinOp::BAnd => (6, 6),
+            BinOp::Shl | BinOp::Shr => (7, 7),
+            BinOp::Concat => (9, 8),
+            BinOp::Add | BinOp::Sub => (10, 10),
+            BinOp::Mul | BinOp::Div | BinOp::IDiv | BinOp::Mod => (11, 11),
+            BinOp::Pow => (14, 13),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+    Not,
+    Len,
+    BNot,
+}
+impl UnOp {
+    /// Binds tighter than every binary operator except `^`
+    pub const PRECEDENCE: u8 = 12;
+}
+
+/// A variable, field or label name, which is shared rather than copied
+/// when the tree is run
+pub type Name = Rc<str>;
+
+/// The chunk and source range a node was parsed from
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    /// the name of the chunk, usually a file name
+    pub chunk: Rc<str>,
+    pub span: Span,
+    /// the position of the node's first token
+    pub pos: Position,
+}
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.chunk, self.pos.line)
+    }
+}
+
+/// A node in an `Ast`, which is only meaningful in the tree it is from
+pub struct Id<T> {
+    index: u32,
+    node: PhantomData<fn() -> T>,
+}
+impl<T> Id<T> {
+    fn new(index: usize) -> Id<T> {
+        assert!(index <= u32::MAX as usize, "too many syntax tree nodes");
+        Id {
+            index: index as u32,
+            node: PhantomData,
+        }
+    }
+}
+// not derived, which would need `T` to implement them too
+impl<T> Copy for Id<T> {}
+impl<T> Clone for Id<T> {
+    fn clone(&self) -> Id<T> {
+        *self
+    }
+}
+impl<T> PartialEq for Id<T> {
+    fn eq(&self, other: &Id<T>) -> bool {
+        self.index == other.index
+    }
+}
+impl<T> Eq for Id<T> {}
+impl<T> ::std::hash::Hash for Id<T> {
+    fn hash<H: ::std::hash::Hasher>(&self, state: &mut H) {
+        self.index.hash(state);
+    }
+}
+impl<T> fmt::Debug for Id<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}", self.index)
+    }
+}
+
+/// Nodes that follow one another in an `Ast`, such as the statements of a
+/// block
+pub struct List<T> {
+    start: u32,
+    len: u32,
+    node: PhantomData<fn() -> T>,
+}
+impl<T> List<T> {
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+    fn range(&self) -> Range<usize> {
+        self.start as usize..(self.start + self.len) as usize
+    }
+}
+impl<T> Default for List<T> {
+    fn default() -> List<T> {
+        List {
+            start: 0,
+            len: 0,
+            node: PhantomData,
+        }
+    }
+}
+impl<T> Copy for List<T> {}
+impl<T> Clone for List<T> {
+    fn clone(&self) -> List<T> {
+        *self
+    }
+}
+impl<T> PartialEq for List<T> {
+    fn eq(&self, other: &List<T>) -> bool {
+        self.range() == other.range()
+    }
+}
+impl<T> fmt::Debug for List<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#{}..#{}", self.start, self.start + self.len)
+    }
+}
+
+pub type ExprId = Id<Expr>;
+pub type FuncId = Id<FuncBody>;
+pub type ExprList = List<Expr>;
+pub type StatList = List<Stat>;
+pub type FieldList = List<Field>;
+pub type NameList = List<Name>;
+/// A condition and the block it runs, in an `if` statement
+pub type Branch = (ExprId, Block);
+
+/// The syntax tree of a chunk, which owns all of its nodes
+///
+/// Nodes are looked up by indexing it with their `Id` or `List`. Its
+/// `Debug` output is the tree of nodes, starting from the chunk's block.
+#[derive(Clone, Default, PartialEq)]
+pub struct Ast {
+    exprs: Vec<Expr>,
+    stats: Vec<Stat>,
+    fields: Vec<Field>,
+    names: Vec<Name>,
+    branches: Vec<Branch>,
+    funcs: Vec<FuncBody>,
+}
+impl Ast {
+    /// The function running the whole chunk, which takes any arguments as
+    /// varargs and is parsed last
+    pub fn main(&self) -> FuncId {
+        Id::new(
+            self.funcs
+                .len()
+                .checked_sub(1)
+                .expect("a parsed tree has a main function"),
+        )
+    }
+    /// Every function of the chunk, those nested in others first and the
+    /// main one last
+    pub fn functions(&self) -> &[FuncBody] {
+        &self.funcs
+    }
+    /// The operands of a chain of concatenations like `a .. b .. c`, which
+    /// nest to the right, or just `expr` if it is not one
+    pub fn concat_operands<'a>(&'a self, expr: &'a Expr) -> Vec<&'a Expr> {
+        let mut operands = Vec::new();
+        let mut expr = expr;
+        while let ExprKind::Binary(BinOp::Concat, lhs, rhs) = expr.kind {
+            operands.push(&self[lhs]);
+            expr = &self[rhs];
+        }
+        operands.push(expr);
+        operands
+    }
+    pub(crate) fn add<T>(&mut self, node: T) -> Id<T>
+    where
+        Ast: Arena<T>,
+    {
+        let nodes = self.nodes();
+        nodes.push(node);
+        Id::new(nodes.len() - 1)
+    }
+    pub(crate) fn add_list<T, I>(&mut self, list: I) -> List<T>
+    where
+        Ast: Arena<T>,
+        I: IntoIterator<Item = T>,
+    {
+        let nodes = self.nodes();
+        let start = Id::<T>::new(nodes.len()).index;
+        nodes.extend(list);
+        List {
+            start,
+            len: Id::<T>::new(nodes.len()).index - start,
+            node: PhantomData,
+        }
+    }
+    /// How many nodes of each kind there are, to `truncate` back to
+    pub(crate) fn sizes(&self) -> [usize; 6] {
+        [
+            self.exprs.len(),
+            self.stats.len(),
+            self.fields.len(),
+            self.names.len(),
+            self.branches.len(),
+            self.funcs.len(),
+        ]
+    }
+    /// Drop the nodes added since it had `sizes`
+    pub(crate) fn truncate(&mut self, sizes: [usize; 6]) {
+        self.exprs.truncate(sizes[0]);
+        self.stats.truncate(sizes[1]);
+        self.fields.truncate(sizes[2]);
+        self.names.truncate(sizes[3]);
+        self.branches.truncate(sizes[4]);
+        self.funcs.truncate(sizes[5]);
+    }
+}
+
+/// The nodes of one kind in an `Ast`
+pub(crate) trait Arena<T> {
+    fn nodes(&mut self) -> &mut Vec<T>;
+}
+
+macro_rules! arenas {
+    ($($node:ty => $nodes:ident,)*) => {$(
+        impl Arena<$node> for Ast {
+            fn nodes(&mut self) -> &mut Vec<$node> {
+                &mut self.$nodes
+            }
+        }
+        impl Index<Id<$node>> for Ast {
+            type Output = $node;
+            fn index(&self, id: Id<$node>) -> &$node {
+                &self.$nodes[id.index as usize]
+            }
+        }
+        impl Index<List<$node>> for Ast {
+            type Output = [$node];
+            fn index(&self, list: List<$node>) -> &[$node] {
+                &self.$nodes[list.range()]
+            }
+        }
+    )*};
+}
+arenas! {
+    Expr => exprs,
+    Stat => stats,
+    Field => fields,
+    Name => names,
+    Branch => branches,
+    FuncBody => funcs,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub loc: Location,
+}
+impl Expr {
+    /// Whether this is a function or method call, which is a tail call
+    /// when it is all that a `return` gives
+    pub fn is_call(&self) -> bool {
+        matches!(self.kind, ExprKind::Call(..) | ExprKind::Method(..))
+    }
+    /// Whether this gives all of its values when last in a list, as calls
+    /// and `...` do, rather than exactly one
+    pub fn is_multi(&self) -> bool {
+        self.is_call() || matches!(self.kind, ExprKind::Vararg)
+    }
+    pub fn is_concat(&self) -> bool {
+        matches!(self.kind, ExprKind::Binary(BinOp::Concat, ..))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ExprKind {
+    Nil,
+    True,
+    False,
+    Number(Number),
+    String(Vec<u8>),
+    /// `...`
+    Vararg,
+    Function(FuncId),
+    Table(FieldList),
+    Name(Name),
+    Index(ExprId, ExprId),
+    Call(ExprId, ExprList),
+    /// `obj:name(args)`
+    Method(ExprId, Name, ExprList),
+    /// kept so that `(f())` can be truncated to one value
+    Paren(ExprId),
+    Binary(BinOp, ExprId, ExprId),
+    Unary(UnOp, ExprId),
+}
+
+/// An entry in a table constructor
+#[derive(Clone, Debug, PartialEq)]
+pub struct Field {
+    pub kind: FieldKind,
+    pub loc: Location,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FieldKind {
+    /// `name = val`
+    Named(Name, ExprId),
+    /// `[key] = val`
+    Indexed(ExprId, ExprId),
+    /// a value stored under the next integer key
+    Positional(ExprId),
+}
+
+/// The parameters and body of a function
+#[derive(Clone, Debug, PartialEq)]
+pub struct FuncBody {
+    pub params: NameList,
+    /// whether the parameters end with `...`
+    pub vararg: bool,
+    pub body: Block,
+    /// from the parameter list to the closing `end`
+    pub loc: Location,
+}
+
+/// The name in a `function a.b.c:m()` statement
+#[derive(Clone, Debug, PartialEq)]
+pub struct FuncName {
+    /// the variable and the fields indexed from it, which is never empty
+    pub path: NameList,
+    /// the method name after the `:`, which adds a `self` parameter
+    pub method: Option<Name>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Block {
+    pub stats: StatList,
+    /// the values of a `return` ending the block
+    pub ret: Option<ExprList>,
+    pub loc: Location,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stat {
+    pub kind: StatKind,
+    pub loc: Location,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum StatKind {
+    /// assignments to names and indexing expressions
+    Assign(ExprList, ExprList),
+    /// a function or method call, discarding its results
+    Call(ExprId),
+    Do(Block),
+    While(ExprId, Block),
+    Repeat(Block, ExprId),
+    /// the `if` and `elseif` branches in order, and the `else` block
+    If(List<Branch>, Option<Block>),
+    NumericFor {
+        var: Name,
+        start: ExprId,
+        limit: ExprId,
+        step: Option<ExprId>,
+        body: Block,
+    },
+    GenericFor {
+        vars: NameList,
+        exprs: ExprList,
+        body: Block,
+    },
+    Function(FuncName, FuncId),
+    LocalFunction(Name, FuncId),
+    Local(NameList, ExprList),
+    Break,
+    Goto(Name),
+    Label(Name),
+}
+
+impl fmt::Debug for Ast {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self[self.main()].body.show(self, f)
+    }
+}
+
+/// A node of `ast`, which formats as the nodes it refers to rather than
+/// their ids
+struct Tree<'a, T: ?Sized + 'a>(&'a Ast, &'a T);
+impl<'a, T: Show + ?Sized> fmt::Debug for Tree<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.1.show(self.0, f)
+    }
+}
+
+/// Formatting a node as `Debug` would if it contained its children
+trait Show {
+    fn show(&self, ast: &Ast, f: &mut fmt::Formatter) -> fmt::Result;
+}
+macro_rules! show_as_debug {
+    ($($ty:ty),*) => {$(
+        impl Show for $ty {
+            fn show(&self, _: &Ast, f: &mut fmt::Formatter) -> fmt::Result {
+                fmt::Debug::fmt(self, f)
+            }
+        }
+    )*};
+}
+show_as_debug!(Name, Location, Number, Vec<u8>, bool, BinOp, UnOp);
+
+impl<T: Show> Show for Id<T>
+where
+    Ast: Index<Id<T>, Output = T>,
+{
+    fn show(&self, ast: &Ast, f: &mut fmt::Formatter) -> fmt::Result {
+        ast[*self].show(ast, f)
+    }
+}
+impl<T: Show> Show for List<T>
+where
+    Ast: Index<List<T>, Output = [T]>,
+{
+    fn show(&self, ast: &Ast, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(ast[*self].iter().map(|node| Tree(ast, node)))
+            .finish()
+    }
+}
+impl<T: Show> Show for Option<T> {
+    fn show(&self, ast: &Ast, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Some(ref node) => f.debug_tuple("Some").field(&Tree(ast, node)).finish(),
+            None => f.write_str("None"),
+        }
+    }
+}
+impl<A: Show, B: Show> Show for (A, B) {
+    fn show(&self, ast: &Ast, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("")
+            .field(&Tree(ast, &self.0))
+            .field(&Tree(ast, &self.1))
+            .finish()
+    }
+}
+
+/// `Show` for a struct, by its name and fields
+macro_rules! show_struct {
+    ($ty:ident { $($field:ident),* }) => {
+        impl Show for $ty {
+            fn show(&self, ast: &Ast, f: &mut fmt::Formatter) -> fmt::Result {
+                f.debug_struct(stringify!($ty))
+                    $(.field(stringify!($field), &Tree(ast, &self.$field)))*
+                    .finish()
+            }
+        }
+    };
+}
+show_struct!(Expr { kind, loc });
+show_struct!(Field { kind, loc });
+show_struct!(FuncBody {
+    params,
+    vararg,
+    body,
+    loc
+});
+show_struct!(FuncName { path, method });
+show_struct!(Block { stats, ret, loc });
+show_struct!(Stat { kind, loc });
+
+/// A variant of an enum, by its name and fields
+fn variant(f: &mut fmt::Formatter, name: &str, fields: &[&dyn fmt::Debug]) -> fmt::Result {
+    if fields.is_empty() {
+        return f.write_str(name);
+    }
+    let mut tuple = f.debug_tuple(name);
+    for field in fields {
+        tuple.field(field);
+    }
+    tuple.finish()
+}
+
+impl Show for ExprKind {
+    fn show(&self, ast: &Ast, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ExprKind::Nil => variant(f, "Nil", &[]),
+            ExprKind::True => variant(f, "True", &[]),
+            ExprKind::False => variant(f, "False", &[]),
+            ExprKind::Number(ref num) => variant(f, "Number", &[num]),
+            ExprKind::String(ref bytes) => variant(f, "String", &[bytes]),
+            ExprKind::Vararg => variant(f, "Vararg", &[]),
+            ExprKind::Function(ref func) => variant(f, "Function", &[&Tree(ast, func)]),
+            ExprKind::Table(ref fields) => variant(f, "Table", &[&Tree(ast, fields)]),
+            ExprKind::Name(ref name) => variant(f, "Name", &[name]),
+            ExprKind::Index(ref obj, ref key) => {
+                variant(f, "Index", &[&Tree(ast, obj), &Tree(ast, key)])
+            }
+            ExprKind::Call(ref func, ref args) => {
+                variant(f, "Call", &[&Tree(ast, func), &Tree(ast, args)])
+            }
+            ExprKind::Method(ref obj, ref name, ref args) => {
+                variant(f, "Method", &[&Tree(ast, obj), name, &Tree(ast, args)])
+            }
+            ExprKind::Paren(ref inner) => variant(f, "Paren", &[&Tree(ast, inner)]),
+            ExprKind::Binary(ref op, ref lhs, ref rhs) => {
+                variant(f, "Binary", &[op, &Tree(ast, lhs), &Tree(ast, rhs)])
+            }
+            ExprKind::Unary(ref op, ref operand) => variant(f, "Unary", &[op, &Tree(ast, operand)]),
+        }
+    }
+}
+
+impl Show for FieldKind {
+    fn show(&self, ast: &Ast, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            FieldKind::Named(ref name, ref val) => variant(f, "Named", &[name, &Tree(ast, val)]),
+            FieldKind::Indexed(ref key, ref val) => {
+                variant(f, "Indexed", &[&Tree(ast, key), &Tree(ast, val)])
+            }
+            FieldKind::Positional(ref val) => variant(f, "Positional", &[&Tree(ast, val)]),
+        }
+    }
+}
+
+impl Show for StatKind {
+    fn show(&self, ast: &Ast, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StatKind::Assign(ref targets, ref exprs) => {
+                variant(f, "Assign", &[&Tree(ast, targets), &Tree(ast, exprs)])
+            }
+            StatKind::Call(ref call) => variant(f, "Call", &[&Tree(ast, call)]),
+            StatKind::Do(ref block) => variant(f, "Do", &[&Tree(ast, block)]),
+            StatKind::While(ref cond, ref body) => {
+                variant(f, "While", &[&Tree(ast, cond), &Tree(ast, body)])
+            }
+            StatKind::Repeat(ref body, ref cond) => {
+                variant(f, "Repeat", &[&Tree(ast, body), &Tree(ast, cond)])
+            }
+            StatKind::If(ref branches, ref else_block) => {
+                variant(f, "If", &[&Tree(ast, branches), &Tree(ast, else_block)])
+            }
+            StatKind::NumericFor {
+                ref var,
+                ref start,
+                ref limit,
+                ref step,
+                ref body,
+            } => f
+                .debug_struct("NumericFor")
+                .field("var", var)
+                .field("start", &Tree(ast, start))
+                .field("limit", &Tree(ast, limit))
+                .field("step", &Tree(ast, step))
+                .field("body", &Tree(ast, body))
+                .finish(),
+            StatKind::GenericFor {
+                ref vars,
+                ref exprs,
+                ref body,
+            } => f
+                .debug_struct("GenericFor")
+                .field("vars", &Tree(ast, vars))
+                .field("exprs", &Tree(ast, exprs))
+                .field("body", &Tree(ast, body))
+                .finish(),
+            StatKind::Function(ref name, ref func) => {
+                variant(f, "Function", &[&Tree(ast, name), &Tree(ast, func)])
+            }
+            StatKind::LocalFunction(ref name, ref func) => {
+                variant(f, "LocalFunction", &[name, &Tree(ast, func)])
+            }
+            StatKind::Local(ref names, ref exprs) => {
+                variant(f, "Local", &[&Tree(ast, names), &Tree(ast, exprs)])
+            }
+            StatKind::Break => variant(f, "Break", &[]),
+            StatKind::Goto(ref label) => variant(f, "Goto", &[label]),
+            StatKind::Label(ref name) => variant(f, "Label", &[name]),
+        }
+    }
+}
@@ -0,0 +1,217 @@
+//! Timing Lua functions over many runs, leaving out the runs far from the
+//! rest, such as those a page fault or another process slowed down
+//!
+//! A benchmark file either returns a table of functions, each of which is
+//! a benchmark named after its key, or returns something else, in which
+//! case the whole file is the benchmark. Either way the file first runs
+//! once, untimed, to find out which.
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use error::Result;
+use lua::Lua;
+use value::{ConvertValue, LuaString, Type, Value};
+
+/// Runs further than this many interquartile ranges outside the quartiles
+/// are outliers, as in Tukey's fences
+const FENCE: f64 = 1.5;
+
+/// How long the runs of a benchmark took, once the outliers are left out
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stats {
+    /// the runs that were kept
+    pub runs: usize,
+    /// the runs that were left out
+    pub outliers: usize,
+    pub mean: Duration,
+    /// the sample standard deviation, which is 0 with a single run
+    pub std_dev: Duration,
+    pub median: Duration,
+    pub min: Duration,
+    pub max: Duration,
+}
+impl Stats {
+    /// The statistics of `samples`, or `None` if there are none
+    pub fn of(samples: &[Duration]) -> Option<Stats> {
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<f64> = samples.iter().map(Duration::as_secs_f64).collect();
+        sorted.sort_by(|a, b| a.partial_cmp(b).expect("durations are never NaN"));
+        let (q1, q3) = (quantile(&sorted, 0.25), quantile(&sorted, 0.75));
+        let (low, high) = (q1 - FENCE * (q3 - q1), q3 + FENCE * (q3 - q1));
+        let kept: Vec<f64> = sorted
+            .iter()
+            .cloned()
+            .filter(|&t| low <= t && t <= high)
+            .collect();
+        let n = kept.len() as f64;
+        let mean = kept.iter().sum::<f64>() / n;
+        let var = match kept.len() {
+            1 => 0.0,
+            _ => kept.iter().map(|t| (t - mean) * (t - mean)).sum::<f64>() / (n - 1.0),
+        };
+        Some(Stats {
+            runs: kept.len(),
+            outliers: samples.len() - kept.len(),
+            mean: Duration::from_secs_f64(mean),
+            std_dev: Duration::from_secs_f64(var.sqrt()),
+            median: Duration::from_secs_f64(quantile(&kept, 0.5)),
+            min: Duration::from_secs_f64(kept[0]),
+            max: Duration::from_secs_f64(kept[kept.len() - 1]),
+        })
+    }
+}
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ± {} (min {}, max {}, {} runs",
+            Time(self.mean),
+            Time(self.std_dev),
+            Time(self.min),
+            Time(self.max),
+            self.runs
+        )?;
+        match self.outliers {
+            0 => write!(f, ")"),
+            1 => write!(f, ", 1 outlier)"),
+            n => write!(f, ", {} outliers)", n),
+        }
+    }
+}
+
+/// The value at `q` of the way through `sorted`, interpolating between
+/// neighbours
+fn quantile(sorted: &[f64], q: f64) -> f64 {
+    let pos = q * (sorted.len() - 1) as f64;
+    let (i, frac) = (pos.floor() as usize, pos.fract());
+    match sorted.get(i + 1) {
+        Some(next) => sorted[i] + (next - sorted[i]) * frac,
+        None => sorted[i],
+    }
+}
+
+/// A duration written in the unit that suits it, with three significant
+/// figures, or as whole nanoseconds
+pub struct Time(pub Duration);
+impl fmt::Display for Time {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let secs = self.0.as_secs_f64();
+        let (value, unit) = if secs >= 1.0 {
+            (secs, "s")
+        } else if secs >= 1e-3 {
+            (secs * 1e3, "ms")
+        } else if secs >= 1e-6 {
+            (secs * 1e6, "µs")
+        } else {
+            (secs * 1e9, "ns")
+        };
+        // nanoseconds are as fine as the clock goes
+        let places = match value {
+            v if v >= 100.0 || unit == "ns" => 0,
+            v if v >= 10.0 => 1,
+            _ => 2,
+        };
+        write!(f, "{:.*} {}", places, value, unit)
+    }
+}
+
+/// A benchmark, named after its key in the table the file returned, or
+/// after the file if it is the whole file
+#[derive(Clone, Debug)]
+pub struct Benchmark {
+    pub name: String,
+    pub stats: Stats,
+}
+
+/// Runs benchmark files, with how many runs of each benchmark to make
+pub struct Bencher {
+    warmup: usize,
+    iterations: usize,
+    filter: Option<String>,
+}
+impl Bencher {
+    /// A bencher making 3 untimed runs of each benchmark and then 20
+    /// timed ones
+    pub fn new() -> Bencher {
+        Bencher {
+            warmup: 3,
+            iterations: 20,
+            filter: None,
+        }
+    }
+    pub fn warmup(&self) -> usize {
+        self.warmup
+    }
+    /// Run each benchmark `warmup` times before timing it
+    pub fn set_warmup(&mut self, warmup: usize) {
+        self.warmup = warmup;
+    }
+    pub fn iterations(&self) -> usize {
+        self.iterations
+    }
+    /// Time `iterations` runs of each benchmark, where 0 means 1
+    pub fn set_iterations(&mut self, iterations: usize) {
+        self.iterations = iterations.max(1);
+    }
+    /// Only run the benchmarks whose names contain `filter`
+    pub fn set_filter(&mut self, filter: Option<String>) {
+        self.filter = filter;
+    }
+
+    /// Run the benchmarks in `source`, loaded into `lua` as the chunk
+    /// `name`, in the order of their names
+    pub fn run(&self, lua: &Lua, source: &[u8], name: &str) -> Result<Vec<Benchmark>> {
+        let main = lua.load(source, name)?;
+        let ret = main.call(Vec::new())?.into_first();
+        let mut benches = Vec::new();
+        if ret.type_of() == Type::Table {
+            let mut key = Value::nil();
+            while let Some((next, val)) = ret.raw_next(&key)? {
+                key = next;
+                if val.type_of() != Type::Function {
+                    continue;
+                }
+                if let Some(bytes) = LuaString::from_value(&key) {
+                    benches.push((String::from_utf8_lossy(bytes).into_owned(), val));
+                }
+            }
+            benches.sort_by(|a, b| a.0.cmp(&b.0));
+        } else {
+            benches.push((name.to_string(), main));
+        }
+        let mut results = Vec::new();
+        for (name, func) in benches {
+            let selected = self
+                .filter
+                .as_ref()
+                .is_none_or(|filter| name.contains(&**filter));
+            if selected {
+                let stats = self.time(&func)?;
+                results.push(Benchmark { name, stats });
+            }
+        }
+        Ok(results)
+    }
+
+    /// The statistics of the timed runs of `func`
+    fn time(&self, func: &Value) -> Result<Stats> {
+        for _ in 0..self.warmup {
+            func.call(Vec::new())?;
+        }
+        let mut samples = Vec::with_capacity(self.iterations);
+        for _ in 0..self.iterations {
+            let start = Instant::now();
+            func.call(Vec::new())?;
+            samples.push(start.elapsed());
+        }
+        Ok(Stats::of(&samples).expect("there is at least one iteration"))
+    }
+}
+impl Default for Bencher {
+    fn default() -> Bencher {
+        Bencher::new()
+    }
+}
@@ -0,0 +1,208 @@
+//! Compiled chunks, before functions are made from them, and the cache a
+//! state keeps of them
+//!
+//! Hosts often load the same source many times, such as a behavior script
+//! per entity. Compiling it is the costly part, and a chunk's syntax tree
+//! or prototype never changes once made, so loading a cached chunk only
+//! creates a new closure sharing it, constants included.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::rc::Rc;
+
+use ast::Ast;
+use error::Result;
+use interp;
+use limits::Limits;
+use lua::{Backend, LoadOptions};
+use parser;
+use value::{StringTable, Value};
+use vm::{self, Proto};
+
+/// How many chunks a state caches by default
+pub const DEFAULT_CACHE_LIMIT: usize = 64;
+
+/// A compiled chunk, which any number of functions can be made from
+#[derive(Clone)]
+pub enum Chunk {
+    Interpreted(Rc<Ast>),
+    Compiled(Rc<Proto>),
+}
+impl Chunk {
+    /// Compile a text or binary chunk, where binary chunks always run in
+    /// the VM
+    pub fn load(
+        source: &[u8],
+        name: &str,
+        options: LoadOptions,
+        strings: &StringTable,
+    ) -> Result<Chunk> {
+        if source.starts_with(vm::SIGNATURE) {
+            let proto = vm::undump(source, name, strings)?;
+            return Ok(Chunk::Compiled(Rc::new(proto)));
+        }
+        let ast = parser::parse_chunk(source, name)?;
+        Chunk::from_ast(ast, options, strings)
+    }
+    pub fn from_ast(ast: Ast, options: LoadOptions, strings: &StringTable) -> Result<Chunk> {
+        Ok(match options.backend {
+            Backend::Interpreter => Chunk::Interpreted(Rc::new(ast)),
+            Backend::Vm => {
+                let proto = vm::compile(&ast, options.optimize, strings)?;
+                Chunk::Compiled(Rc::new(proto))
+            }
+        })
+    }
+    /// A function running the chunk with `env` for its globals
+    pub fn function(&self, env: Value, limits: &Rc<Limits>, strings: &Rc<StringTable>) -> Value {
+        match *self {
+            Chunk::Interpreted(ref ast) => Value::interpreted(interp::Closure::chunk(
+                ast.clone(),
+                env,
+                limits.clone(),
+                strings.clone(),
+            )),
+            Chunk::Compiled(ref proto) => Value::compiled(vm::Closure::new(
+                proto.clone(),
+                env,
+                limits.clone(),
+                strings.clone(),
+            )),
+        }
+    }
+}
+
+/// Counters describing a chunk cache
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChunkCacheStats {
+    /// chunks that are cached
+    pub cached: usize,
+    /// loads that found their chunk already compiled
+    pub hits: u64,
+    /// loads that had to compile their chunk
+    pub misses: u64,
+}
+
+/// A cached chunk, with what it was compiled from to tell apart sources
+/// whose hashes collide
+struct Entry {
+    source: Box<[u8]>,
+    name: Box<str>,
+    backend: Backend,
+    optimize: bool,
+    chunk: Chunk,
+    /// when it was last loaded, by the cache's clock
+    used: u64,
+}
+impl Entry {
+    fn matches(&self, source: &[u8], name: &str, options: LoadOptions) -> bool {
+        *self.source == *source
+            && *self.name == *name
+            && self.backend == options.backend
+            && self.optimize == options.optimize
+    }
+}
+
+/// The chunks a state loaded most recently, keyed by a hash of their
+/// source, name and how they were compiled
+pub struct ChunkCache {
+    entries: HashMap<u64, Entry>,
+    limit: usize,
+    /// counts loads, to find the least recently used entry
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+impl ChunkCache {
+    pub fn new() -> ChunkCache {
+        ChunkCache {
+            entries: HashMap::new(),
+            limit: DEFAULT_CACHE_LIMIT,
+            clock: 0,
+            hits: 0,
+            misses: 0,
+        }
+    }
+    /// Get the chunk `source` compiles to, compiling it with `compile` if
+    /// it is not cached
+    pub fn get<F>(
+        &mut self,
+        source: &[u8],
+        name: &str,
+        options: LoadOptions,
+        compile: F,
+    ) -> Result<Chunk>
+    where
+        F: FnOnce() -> Result<Chunk>,
+    {
+        if self.limit == 0 {
+            return compile();
+        }
+        self.clock += 1;
+        let key = hash(source, name, options);
+        if let Some(entry) = self.entries.get_mut(&key) {
+            if entry.matches(source, name, options) {
+                entry.used = self.clock;
+                self.hits += 1;
+                return Ok(entry.chunk.clone());
+            }
+        }
+        self.misses += 1;
+        let chunk = compile()?;
+        if self.entries.len() >= self.limit && !self.entries.contains_key(&key) {
+            self.evict();
+        }
+        self.entries.insert(
+            key,
+            Entry {
+                source: source.into(),
+                name: name.into(),
+                backend: options.backend,
+                optimize: options.optimize,
+                chunk: chunk.clone(),
+                used: self.clock,
+            },
+        );
+        Ok(chunk)
+    }
+    /// The most chunks that are kept
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+    /// Change the most chunks that are kept, where 0 turns caching off,
+    /// dropping the least recently used ones that no longer fit
+    pub fn set_limit(&mut self, limit: usize) {
+        self.limit = limit;
+        while self.entries.len() > limit {
+            self.evict();
+        }
+    }
+    /// Drop the least recently used chunk
+    fn evict(&mut self) {
+        let oldest = self
+            .entries
+            .iter()
+            .min_by_key(|&(_, entry)| entry.used)
+            .map(|(&key, _)| key);
+        if let Some(oldest) = oldest {
+            self.entries.remove(&oldest);
+        }
+    }
+    pub fn stats(&self) -> ChunkCacheStats {
+        ChunkCacheStats {
+            cached: self.entries.len(),
+            hits: self.hits,
+            misses: self.misses,
+        }
+    }
+}
+
+fn hash(source: &[u8], name: &str, options: LoadOptions) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    source.hash(&mut hasher);
+    name.hash(&mut hasher);
+    options.backend.hash(&mut hasher);
+    options.optimize.hash(&mut hasher);
+    hasher.finish()
+}
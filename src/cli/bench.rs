@@ -0,0 +1,94 @@
+//! `looa bench`, which times the benchmarks in scripts and reports how
+//! long they took
+
+use std::process;
+
+use cli;
+use looa::bench::{Bencher, Time};
+use looa::{Backend, Lua};
+
+const USAGE: &str = "\
+usage: looa bench [options] script...
+Available options are:
+  -n runs       time each benchmark 'runs' times (default: 20)
+  -w runs       run each benchmark 'runs' times first, untimed (default: 3)
+  --vm          run with the bytecode VM rather than the interpreter
+  --filter text only run the benchmarks whose names contain 'text'
+A script that returns a table of functions has a benchmark for each of
+them, and any other script is a benchmark as a whole. Runs further than
+1.5 interquartile ranges outside the quartiles are left out as outliers.";
+
+/// Run the benchmarks in the scripts named in `args`, whose options start
+/// at `start`, exiting with 1 if any couldn't be run
+pub fn main(args: &[String], start: usize) -> ! {
+    let mut bencher = Bencher::new();
+    let mut backend = Backend::Interpreter;
+    let mut paths = Vec::new();
+    let mut args = args[start..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-n" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(runs) => bencher.set_iterations(runs),
+                None => usage("'-n' needs a number"),
+            },
+            "-w" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(runs) => bencher.set_warmup(runs),
+                None => usage("'-w' needs a number"),
+            },
+            "--vm" => backend = Backend::Vm,
+            "--filter" => match args.next() {
+                Some(text) => bencher.set_filter(Some(text.clone())),
+                None => usage("'--filter' needs argument"),
+            },
+            "--" => {
+                paths.extend(args);
+                break;
+            }
+            opt if opt.starts_with('-') => usage(&format!("unrecognized option '{}'", opt)),
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        usage("no input file given");
+    }
+    let mut failed = false;
+    for path in paths {
+        let source = match cli::read_script(path) {
+            Ok(source) => source,
+            Err(err) => {
+                eprintln!("looa: cannot open {}: {}", path, err);
+                failed = true;
+                continue;
+            }
+        };
+        // each script in a state of its own, so none sees another's globals
+        let mut lua = Lua::new();
+        lua.set_backend(backend);
+        match bencher.run(&lua, &source, path) {
+            Ok(benches) => {
+                let width = benches.iter().map(|b| b.name.len()).max().unwrap_or(0);
+                for bench in benches {
+                    println!("{:<width$}  {}", bench.name, bench.stats, width = width);
+                    if bench.stats.runs > 1 && bench.stats.std_dev > bench.stats.mean / 10 {
+                        println!(
+                            "{:<width$}  (median {}; noisy, try more runs)",
+                            "",
+                            Time(bench.stats.median),
+                            width = width
+                        );
+                    }
+                }
+            }
+            Err(err) => {
+                eprintln!("looa: {}", err);
+                failed = true;
+            }
+        }
+    }
+    process::exit(if failed { 1 } else { 0 })
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("looa: {}\n{}", msg, USAGE);
+    process::exit(1)
+}
@@ -0,0 +1,50 @@
+//! `looa check`, which parses scripts without running them and reports
+//! every syntax error in them, for editors and pre-commit hooks
+//!
+//! `looa -p` is the same, for hooks written for `luac -p`.
+
+use std::io::{self, Read};
+use std::process;
+
+use cli;
+use looa::{diagnostic, parser};
+
+const USAGE: &str = "\
+usage: looa check script...
+       looa -p script...
+'-' checks stdin.";
+
+/// Check the scripts named in `args` from `start`, exiting with 1 if any
+/// has errors
+pub fn main(args: &[String], start: usize) -> ! {
+    let paths = &args[start..];
+    if paths.is_empty() {
+        eprintln!("looa: no input file given\n{}", USAGE);
+        process::exit(1);
+    }
+    let color = cli::color(None).unwrap_or(false);
+    let mut failed = false;
+    for path in paths {
+        let source = if path == "-" {
+            let mut source = Vec::new();
+            io::stdin().read_to_end(&mut source).map(|_| source)
+        } else {
+            cli::read_script(path)
+        };
+        let source = match source {
+            Ok(source) => source,
+            Err(err) => {
+                eprintln!("looa: cannot open {}: {}", path, err);
+                failed = true;
+                continue;
+            }
+        };
+        let name = if path == "-" { "stdin" } else { path };
+        let (_, errors) = parser::parse_chunk_recovering(&source, name);
+        for err in &errors {
+            eprint!("{}", diagnostic::syntax_error(err, &source, "", color));
+        }
+        failed |= !errors.is_empty();
+    }
+    process::exit(if failed { 1 } else { 0 })
+}
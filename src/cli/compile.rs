@@ -0,0 +1,106 @@
+//! `looa compile`, which compiles a script to a binary chunk without
+//! running it, as `luac` does
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+
+use cli;
+use looa::Lua;
+
+const USAGE: &str = "\
+usage: looa compile [options] script...
+Available options are:
+  -o file   write the binary chunk to 'file' (default is \"luac.out\", and
+            '-' is stdout)
+  -s        strip debug information
+  -         compile stdin
+Several scripts are joined into one chunk that runs them in order.";
+
+/// Compile the scripts named in `args`, whose options start at `start`,
+/// and exit with its status
+pub fn main(args: &[String], start: usize) -> ! {
+    let mut output = "luac.out".to_string();
+    let mut strip = false;
+    let mut inputs = Vec::new();
+    let mut args = args[start..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-o" => match args.next() {
+                Some(file) => output = file.clone(),
+                None => usage("'-o' needs argument"),
+            },
+            "-s" => strip = true,
+            "--" => {
+                inputs.extend(args.by_ref());
+                break;
+            }
+            opt if opt.starts_with('-') && opt != "-" => {
+                usage(&format!("unrecognized option '{}'", opt))
+            }
+            _ => {
+                inputs.push(arg);
+                inputs.extend(args.by_ref());
+                break;
+            }
+        }
+    }
+    if inputs.is_empty() {
+        usage("no input file given");
+    }
+    let mut sources = Vec::new();
+    for input in &inputs {
+        let source = if *input == "-" {
+            let mut source = Vec::new();
+            io::stdin().read_to_end(&mut source).map(|_| source)
+        } else {
+            cli::read_script(input)
+        };
+        match source {
+            Ok(source) => sources.push(source),
+            Err(err) => {
+                eprintln!("looa: cannot open {}: {}", input, err);
+                process::exit(1);
+            }
+        }
+    }
+    let chunks: Vec<(&[u8], &str)> = inputs
+        .iter()
+        .zip(&sources)
+        .map(|(input, source)| {
+            let name = if *input == "-" {
+                "stdin"
+            } else {
+                input.as_str()
+            };
+            (&source[..], name)
+        })
+        .collect();
+    let lua = Lua::new();
+    let chunk = match chunks[..] {
+        [(source, name)] => lua.compile(source, name, strip),
+        _ => lua.compile_all(&chunks, strip),
+    };
+    let chunk = match chunk {
+        Ok(chunk) => chunk,
+        Err(err) => {
+            eprintln!("looa: {}", err);
+            process::exit(1);
+        }
+    };
+    let written = if output == "-" {
+        io::stdout().write_all(&chunk)
+    } else {
+        fs::write(&output, &chunk)
+    };
+    if let Err(err) = written {
+        eprintln!("looa: cannot write {}: {}", output, err);
+        process::exit(1);
+    }
+    process::exit(0)
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("looa: {}\n{}", msg, USAGE);
+    process::exit(1)
+}
@@ -0,0 +1,128 @@
+//! `looa cover`, which runs Lua test suites as `looa test` does, counting
+//! the lines they run, and reports how much of each file ran
+
+use std::fs;
+use std::process;
+
+use cli;
+use looa::coverage::{self, Coverage, FileCoverage};
+use looa::testing::{FileResult, Runner};
+use looa::Backend;
+
+const USAGE: &str = "\
+usage: looa cover [options] [dir|file...]
+Available options are:
+  --lcov file   write an LCOV report to 'file', for tools such as genhtml
+  -j n          run at most n files at once (default: one per processor)
+  --vm          run with the bytecode VM rather than the interpreter
+  --filter text only run the tests whose names contain 'text'
+Test files are found as 'looa test' finds them. After the tests' summary,
+each file that ran is listed with how many of its lines that could run
+did, and those that didn't.";
+
+/// Run the test files found from `args`, whose options start at `start`,
+/// with coverage, exiting with 1 if any test failed
+pub fn main(args: &[String], start: usize) -> ! {
+    let mut runner = Runner::new();
+    let mut lcov = None;
+    let mut paths = Vec::new();
+    let mut args = args[start..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--lcov" => match args.next() {
+                Some(file) => lcov = Some(file),
+                None => usage("'--lcov' needs argument"),
+            },
+            "-j" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(jobs) => runner.set_jobs(jobs),
+                None => usage("'-j' needs a number"),
+            },
+            "--vm" => runner.set_backend(Backend::Vm),
+            "--filter" => match args.next() {
+                Some(text) => runner.set_filter(Some(text.clone())),
+                None => usage("'--filter' needs argument"),
+            },
+            "--" => {
+                paths.extend(args);
+                break;
+            }
+            opt if opt.starts_with('-') => usage(&format!("unrecognized option '{}'", opt)),
+            _ => paths.push(arg),
+        }
+    }
+    let files = match cli::test::find_files(&paths) {
+        Ok(files) => files,
+        Err(msg) => {
+            eprintln!("looa: {}", msg);
+            process::exit(1);
+        }
+    };
+    let coverage = Coverage::new();
+    runner.set_setup(Some(coverage.setup()));
+    let results = runner.run(&files);
+    cli::test::summarize(&results);
+    let report = coverage.report();
+    summarize(&report);
+    if let Some(file) = lcov {
+        if let Err(err) = fs::write(file, coverage::lcov(&report)) {
+            eprintln!("looa: cannot write {}: {}", file, err);
+            process::exit(1);
+        }
+    }
+    let ok = results.iter().all(FileResult::ok);
+    process::exit(if ok { 0 } else { 1 })
+}
+
+/// Print a line for each file with how much of it ran, and the total
+fn summarize(report: &[FileCoverage]) {
+    let percent = |hit: usize, found: usize| match found {
+        0 => 100.0,
+        _ => 100.0 * hit as f64 / found as f64,
+    };
+    println!("\n{:>6} {:>6} {:>7}  file", "lines", "hit", "cover");
+    for file in report {
+        print!(
+            "{:>6} {:>6} {:>6.1}%  {}",
+            file.found(),
+            file.hit(),
+            percent(file.hit(), file.found()),
+            file.path
+        );
+        match ranges(&file.missed()) {
+            ref missed if missed.is_empty() => println!(),
+            missed => println!("  (missed {})", missed),
+        }
+    }
+    let found: usize = report.iter().map(FileCoverage::found).sum();
+    let hit: usize = report.iter().map(FileCoverage::hit).sum();
+    println!(
+        "{:>6} {:>6} {:>6.1}%  total",
+        found,
+        hit,
+        percent(hit, found)
+    );
+}
+
+/// `lines`, in order, written with runs of consecutive lines as ranges,
+/// such as `3, 7-9`
+fn ranges(lines: &[u32]) -> String {
+    let mut out: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let mut j = i;
+        while j + 1 < lines.len() && lines[j + 1] == lines[j] + 1 {
+            j += 1;
+        }
+        out.push(match j == i {
+            true => lines[i].to_string(),
+            false => format!("{}-{}", lines[i], lines[j]),
+        });
+        i = j + 1;
+    }
+    out.join(", ")
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("looa: {}\n{}", msg, USAGE);
+    process::exit(1)
+}
@@ -0,0 +1,239 @@
+//! `looa debug`, which runs a script under the debugger, pausing at its
+//! first line and taking commands like gdb's at a prompt whenever it stops
+
+use std::fs;
+use std::process;
+
+use cli::editor::{Editor, Input};
+use looa::{Debugger, FrameInfo, Lua, PauseReason, Paused, Resume};
+
+const HELP: &str = "\
+break [file:]line    pause at a line (b)
+delete [file:]line   remove a breakpoint (d)
+info breakpoints     list the breakpoints
+continue             run until a breakpoint (c)
+step                 run to the next line, going into calls (s)
+next                 run to the next line, stepping over calls (n)
+finish               run until the function returns
+backtrace            list the running functions (bt)
+frame n              select the function n levels out (f), or up and down
+print expr           evaluate an expression in the selected function (p)
+set name = expr      assign a variable of the selected function
+info locals          list the selected function's variables
+list                 show the source around its line (l)
+quit                 stop the script (q)
+An empty line repeats the last command.";
+
+/// Attach a debugger to `lua` that pauses at the first line it runs, with
+/// breakpoints given without a file set in `script`
+pub fn attach(lua: &mut Lua, script: &str) {
+    let mut session = Session {
+        script: script.to_string(),
+        editor: Editor::new(None),
+        last: String::new(),
+    };
+    let debugger = Debugger::attach(lua, move |paused| Ok(session.pause(paused)));
+    debugger.pause();
+    println!("debugging {}, \"help\" lists the commands", script);
+}
+
+struct Session {
+    script: String,
+    editor: Editor,
+    /// the last command, which an empty line repeats
+    last: String,
+}
+impl Session {
+    /// Take commands until one says how to carry on
+    fn pause(&mut self, paused: &Paused) -> Resume {
+        // the function commands look at
+        let mut level = 0;
+        let reason = match paused.reason() {
+            PauseReason::Breakpoint => "breakpoint at ",
+            PauseReason::Step => "",
+        };
+        println!("{}{}", reason, location(&paused.frames()[0]));
+        show_line(&paused.frames()[0]);
+        loop {
+            let line = self.read_command();
+            let line = line.trim();
+            let (command, rest) = match line.find(char::is_whitespace) {
+                Some(at) => (&line[..at], line[at..].trim()),
+                None => (line, ""),
+            };
+            let frames = paused.frames();
+            match command {
+                "c" | "continue" => return Resume::Continue,
+                "s" | "step" => return Resume::StepIn,
+                "n" | "next" => return Resume::StepOver,
+                "finish" => return Resume::StepOut,
+                "q" | "quit" => process::exit(0),
+                "b" | "break" => match self.breakpoint(rest, &frames[level]) {
+                    Some((file, line)) => {
+                        paused.debugger().set_breakpoint(&file, line);
+                        println!("breakpoint at {}:{}", file, line);
+                    }
+                    None => println!("usage: break [file:]line"),
+                },
+                "d" | "delete" => match self.breakpoint(rest, &frames[level]) {
+                    Some((file, line)) => {
+                        if !paused.debugger().clear_breakpoint(&file, line) {
+                            println!("no breakpoint at {}:{}", file, line);
+                        }
+                    }
+                    None => println!("usage: delete [file:]line"),
+                },
+                "bt" | "backtrace" | "where" => {
+                    for (i, frame) in frames.iter().enumerate() {
+                        let selected = if i == level { '*' } else { ' ' };
+                        let function = match frame.defined() {
+                            0 => "main chunk".to_string(),
+                            line => format!("function at line {}", line),
+                        };
+                        println!("{}#{} {} in {}", selected, i, location(frame), function);
+                    }
+                }
+                "f" | "frame" | "up" | "down" => {
+                    let to = match command {
+                        "up" => Some(level + 1),
+                        "down" => level.checked_sub(1),
+                        _ if rest.is_empty() => Some(level),
+                        _ => rest.parse().ok(),
+                    };
+                    match to {
+                        Some(to) if to < frames.len() => {
+                            level = to;
+                            println!("#{} {}", level, location(&frames[level]));
+                            show_line(&frames[level]);
+                        }
+                        _ => println!("no such frame"),
+                    }
+                }
+                "p" | "print" => {
+                    if rest.is_empty() {
+                        println!("usage: print expr");
+                    } else {
+                        match paused.eval(level, rest) {
+                            Ok(val) => println!("{:?}", val),
+                            Err(err) => println!("{}", err),
+                        }
+                    }
+                }
+                "set" => match rest.find('=') {
+                    Some(at) if !rest[at + 1..].starts_with('=') => {
+                        let name = rest[..at].trim();
+                        match paused.eval(level, &rest[at + 1..]) {
+                            Ok(val) => {
+                                if !frames[level].set(name, val) {
+                                    println!("no variable named {}", name);
+                                }
+                            }
+                            Err(err) => println!("{}", err),
+                        }
+                    }
+                    _ => println!("usage: set name = expr"),
+                },
+                "info" => match rest {
+                    "locals" => {
+                        let frame = &frames[level];
+                        for (name, val) in frame.upvalues().into_iter().chain(frame.locals()) {
+                            println!("{} = {:?}", name, val);
+                        }
+                    }
+                    "b" | "break" | "breakpoints" => {
+                        let breakpoints = paused.debugger().breakpoints();
+                        if breakpoints.is_empty() {
+                            println!("no breakpoints");
+                        }
+                        for (file, line) in breakpoints {
+                            println!("{}:{}", file, line);
+                        }
+                    }
+                    _ => println!("usage: info locals|breakpoints"),
+                },
+                "l" | "list" => {
+                    let frame = &frames[level];
+                    match (source(frame), frame.line()) {
+                        (Some(lines), Some(line)) => {
+                            let line = line as usize;
+                            let start = line.saturating_sub(5).max(1);
+                            for (i, text) in lines.iter().enumerate().skip(start - 1).take(10) {
+                                let marker = if i + 1 == line { '>' } else { ' ' };
+                                println!("{}{}\t{}", marker, i + 1, text);
+                            }
+                        }
+                        _ => println!("no source for {}", frame.chunk()),
+                    }
+                }
+                "h" | "help" => println!("{}", HELP),
+                "" => (),
+                _ => println!("unknown command \"{}\", \"help\" lists them", command),
+            }
+        }
+    }
+    /// Read a command, quitting at the end of the input
+    fn read_command(&mut self) -> String {
+        let line = loop {
+            match self.editor.read_line("(looa) ") {
+                Ok(Input::Line(line)) => break line,
+                Ok(Input::Cancelled) => (),
+                Ok(Input::Eof) | Err(_) => {
+                    println!();
+                    process::exit(0)
+                }
+            }
+        };
+        if line.trim().is_empty() {
+            self.last.clone()
+        } else {
+            self.editor.add_history(&line);
+            self.last = line.clone();
+            line
+        }
+    }
+    /// The location `[file:]line` names, with the file of `frame` or the
+    /// script if it leaves that out, or `None` if it is not one
+    fn breakpoint(&self, arg: &str, frame: &FrameInfo) -> Option<(String, u32)> {
+        let (file, line) = match arg.rfind(':') {
+            Some(at) => (arg[..at].to_string(), &arg[at + 1..]),
+            None => {
+                let file = frame.chunk().trim_start_matches('@');
+                let file = if fs::metadata(file).is_ok() {
+                    file
+                } else {
+                    &self.script
+                };
+                (file.to_string(), arg)
+            }
+        };
+        match line.parse() {
+            Ok(line) if !file.is_empty() => Some((file, line)),
+            _ => None,
+        }
+    }
+}
+
+fn location(frame: &FrameInfo) -> String {
+    match frame.line() {
+        Some(line) => format!("{}:{}", frame.chunk(), line),
+        None => format!("{}:?", frame.chunk()),
+    }
+}
+
+/// The lines of the file a function is from, if it was loaded from one
+fn source(frame: &FrameInfo) -> Option<Vec<String>> {
+    let text = fs::read(frame.chunk().trim_start_matches('@')).ok()?;
+    let text = String::from_utf8_lossy(&text);
+    Some(text.lines().map(str::to_string).collect())
+}
+
+/// Show the line a function is running
+fn show_line(frame: &FrameInfo) {
+    let text = source(frame).and_then(|lines| {
+        let line = frame.line()? as usize;
+        lines.get(line.checked_sub(1)?).cloned()
+    });
+    if let (Some(text), Some(line)) = (text, frame.line()) {
+        println!("{}\t{}", line, text);
+    }
+}
@@ -0,0 +1,110 @@
+//! `looa deps`, which prints the graph of the modules scripts require, and
+//! of the functions they call, for build systems to read, or lists the
+//! modules of a project that nothing requires
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use cli;
+use looa::graph::Graph;
+
+const USAGE: &str = "\
+usage: looa deps [options] script...
+Available options are:
+  -p path       look for modules along 'path', templates separated by ';'
+                as in package.path (default: [modules] path in looa.toml,
+                or \"./?.lua;./?/init.lua\")
+  --calls       also give the functions each module defines and calls
+  --json        print JSON rather than DOT
+  --unused dir  rather than the graph, list the modules in 'dir' that the
+                scripts never require
+Only requires of string constants are followed.";
+
+/// Graph the scripts named in `args`, whose options start at `start`,
+/// exiting with 1 if any module couldn't be read or parsed, or when
+/// listing unused modules, if there are any
+pub fn main(args: &[String], start: usize) -> ! {
+    let mut path = cli::config().module_path().to_string();
+    let mut calls = false;
+    let mut json = false;
+    let mut unused = None;
+    let mut scripts = Vec::new();
+    let mut args = args[start..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-p" => match args.next() {
+                Some(arg) => path = arg.clone(),
+                None => usage("'-p' needs argument"),
+            },
+            "--calls" => calls = true,
+            "--json" => json = true,
+            "--unused" => match args.next() {
+                Some(dir) => unused = Some(dir),
+                None => usage("'--unused' needs argument"),
+            },
+            "--" => {
+                scripts.extend(args);
+                break;
+            }
+            opt if opt.starts_with('-') => usage(&format!("unrecognized option '{}'", opt)),
+            _ => scripts.push(arg),
+        }
+    }
+    if scripts.is_empty() {
+        usage("no input file given");
+    }
+    let mut graph = Graph::new(&path);
+    let roots: Vec<usize> = scripts
+        .iter()
+        .map(|script| graph.add(Path::new(script)))
+        .collect();
+    if let Some(dir) = unused {
+        let mut files = Vec::new();
+        if let Err(err) = lua_files(Path::new(dir), &mut files) {
+            eprintln!("looa: cannot read {}: {}", dir, err);
+            process::exit(1);
+        }
+        files.sort();
+        for file in &files {
+            graph.add(file);
+        }
+    }
+    let mut failed = false;
+    for module in graph.modules() {
+        if let Some(ref err) = module.error {
+            eprintln!("looa: {}", err);
+            failed = true;
+        }
+    }
+    if unused.is_some() {
+        for module in graph.unused(&roots) {
+            println!("{}", graph.modules()[module].path.display());
+            failed = true;
+        }
+    } else if json {
+        print!("{}", graph.json(calls));
+    } else {
+        print!("{}", graph.dot(calls));
+    }
+    process::exit(if failed { 1 } else { 0 })
+}
+
+/// Add the `.lua` files in `dir` and the directories in it to `files`
+fn lua_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            lua_files(&path, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "lua") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("looa: {}\n{}", msg, USAGE);
+    process::exit(1)
+}
@@ -0,0 +1,237 @@
+//! `looa diff`, which runs scripts both here and with a reference `lua`
+//! binary and shows where what they print or return differs
+//!
+//! Here, the script runs in this process with `print` collecting what it
+//! prints. The reference binary runs a small driver read from its stdin,
+//! which runs the script with `dofile` in a `pcall` and then writes what
+//! it returned, or the error it raised, after a marker line. Addresses
+//! such as `table: 0x55d0c1e2` differ between any two runs, so they are
+//! all written as `0x?` before comparing.
+
+use std::cell::RefCell;
+use std::io::Write;
+use std::process::{self, Command, Stdio};
+use std::rc::Rc;
+
+use cli;
+use looa::{Backend, ConvertValue, Lua, LuaString, MultiValue, Result, Value};
+
+const USAGE: &str = "\
+usage: looa diff [options] script...
+Available options are:
+  --lua path    the reference binary to compare with (default: lua)
+  --vm          run here with the bytecode VM rather than the interpreter
+Each script runs both here and with the reference binary, and any lines
+of what they print, return or raise that differ are listed, with '-' for
+the reference's and '+' for this one's.";
+
+/// The line between what the driver's script printed and what it returned
+const MARKER: &str = "\u{0}looa diff\u{0}";
+
+/// Runs the script named by the first argument with the reference binary
+const DRIVER: &str = r#"
+local path = ...
+local r = table.pack(pcall(dofile, path))
+io.stdout:write("\0looa diff\0\n")
+if r[1] then
+  for i = 2, r.n do
+    io.stdout:write("return ", type(r[i]), " ", tostring(r[i]), "\n")
+  end
+else
+  io.stdout:write("error ", tostring(r[2]), "\n")
+end
+"#;
+
+/// Compare the scripts named in `args`, whose options start at `start`,
+/// exiting with 1 if any differ or couldn't be run
+pub fn main(args: &[String], start: usize) -> ! {
+    let mut reference = "lua".to_string();
+    let mut backend = Backend::Interpreter;
+    let mut paths = Vec::new();
+    let mut args = args[start..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--lua" => match args.next() {
+                Some(path) => reference = path.clone(),
+                None => usage("'--lua' needs argument"),
+            },
+            "--vm" => backend = Backend::Vm,
+            "--" => {
+                paths.extend(args);
+                break;
+            }
+            opt if opt.starts_with('-') => usage(&format!("unrecognized option '{}'", opt)),
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        usage("no input file given");
+    }
+    let mut failed = false;
+    for path in paths {
+        let source = match cli::read_script(path) {
+            Ok(source) => source,
+            Err(err) => {
+                eprintln!("looa: cannot open {}: {}", path, err);
+                failed = true;
+                continue;
+            }
+        };
+        let expected = match run_reference(&reference, path) {
+            Ok(lines) => lines,
+            Err(msg) => {
+                eprintln!("looa: {}", msg);
+                failed = true;
+                continue;
+            }
+        };
+        let actual = run_here(&source, path, backend);
+        if expected == actual {
+            println!("same {}", path);
+        } else {
+            println!("differs {}", path);
+            for line in diff(&expected, &actual) {
+                println!("{}", line);
+            }
+            failed = true;
+        }
+    }
+    process::exit(if failed { 1 } else { 0 })
+}
+
+/// The lines the script at `path` prints and then returns or raises when
+/// the binary `reference` runs it
+fn run_reference(reference: &str, path: &str) -> ::std::result::Result<Vec<String>, String> {
+    let mut child = Command::new(reference)
+        .args(["-", path])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("cannot run {}: {}", reference, err))?;
+    child
+        .stdin
+        .take()
+        .expect("stdin is piped")
+        .write_all(DRIVER.as_bytes())
+        .map_err(|err| format!("cannot run {}: {}", reference, err))?;
+    let output = child
+        .wait_with_output()
+        .map_err(|err| format!("cannot run {}: {}", reference, err))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let (printed, results) = match stdout.find(&format!("{}\n", MARKER)) {
+        Some(at) => (&stdout[..at], &stdout[at + MARKER.len() + 1..]),
+        None => {
+            let stderr = String::from_utf8_lossy(&output.stderr);
+            return Err(format!(
+                "{} did not finish running {}\n{}",
+                reference,
+                path,
+                stderr.trim_end()
+            ));
+        }
+    };
+    Ok(lines(printed).chain(lines(results)).collect())
+}
+
+/// The lines the script `source` prints and then returns or raises here
+fn run_here(source: &[u8], path: &str, backend: Backend) -> Vec<String> {
+    let mut lua = Lua::new();
+    lua.set_backend(backend);
+    let printed = Rc::new(RefCell::new(Vec::new()));
+    let out = printed.clone();
+    let print = Value::function(move |args: &[Value]| {
+        let mut out = out.borrow_mut();
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                out.push(b'\t');
+            }
+            let text = arg.lua_tostring()?;
+            out.extend_from_slice(LuaString::from_value(&text).expect("tostring gives strings"));
+        }
+        out.push(b'\n');
+        Ok(MultiValue::new())
+    });
+    lua.set_global("print", print).expect("globals can be set");
+    let results = lua
+        .load(source, path)
+        .and_then(|main| main.call(Vec::new()))
+        .and_then(|ret| returned(ret.into_vec()));
+    let results = match results {
+        Ok(results) => results,
+        Err(err) => format!("error {}\n", err),
+    };
+    let printed = String::from_utf8_lossy(&printed.borrow()).into_owned();
+    lines(&printed).chain(lines(&results)).collect()
+}
+
+/// A line for each of `vals`, as the driver writes them
+fn returned(vals: Vec<Value>) -> Result<String> {
+    let mut out = String::new();
+    for val in vals {
+        let text = val.lua_tostring()?;
+        out.push_str(&format!("return {} {}\n", val.type_of(), text));
+    }
+    Ok(out)
+}
+
+/// The lines of `text`, with addresses blanked out
+fn lines(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.lines().map(blank_addresses)
+}
+
+/// `line` with each `0x` and the hex digits after it written `0x?`
+fn blank_addresses(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(at) = rest.find(": 0x") {
+        let digits = rest[at + 4..]
+            .find(|c: char| !c.is_ascii_hexdigit())
+            .unwrap_or(rest.len() - at - 4);
+        out.push_str(&rest[..at + 4]);
+        out.push('?');
+        rest = &rest[at + 4 + digits..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The lines of `expected` and `actual`, marked `-` if only in `expected`,
+/// `+` if only in `actual`, and indented if in both, as found by their
+/// longest common subsequence
+fn diff(expected: &[String], actual: &[String]) -> Vec<String> {
+    let (n, m) = (expected.len(), actual.len());
+    // common[i][j] is the length of the longest common subsequence of
+    // expected[i..] and actual[j..]
+    let mut common = vec![vec![0; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut out = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && expected[i] == actual[j] {
+            out.push(format!("  {}", expected[i]));
+            i += 1;
+            j += 1;
+        } else if j == m || (i < n && common[i + 1][j] >= common[i][j + 1]) {
+            out.push(format!("- {}", expected[i]));
+            i += 1;
+        } else {
+            out.push(format!("+ {}", actual[j]));
+            j += 1;
+        }
+    }
+    out
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("looa: {}\n{}", msg, USAGE);
+    process::exit(1)
+}
@@ -0,0 +1,72 @@
+//! `looa dis`, which lists the VM instructions a script or binary chunk
+//! compiles to without running it
+
+use std::io::{self, Read};
+use std::process;
+
+use cli;
+use looa::Lua;
+
+const USAGE: &str = "\
+usage: looa dis [options] script
+Available options are:
+  -n        list the code before the peephole optimizer runs over it
+  -         list stdin";
+
+/// List the script named in `args`, whose options start at `start`, and
+/// exit with its status
+pub fn main(args: &[String], start: usize) -> ! {
+    let mut optimize = true;
+    let mut input = None;
+    let mut args = args[start..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-n" => optimize = false,
+            "--" => {
+                input = args.next();
+                break;
+            }
+            opt if opt.starts_with('-') && opt != "-" => {
+                usage(&format!("unrecognized option '{}'", opt))
+            }
+            _ => {
+                input = Some(arg);
+                break;
+            }
+        }
+    }
+    let input = match (input, args.next()) {
+        (Some(input), None) => input,
+        (None, _) => usage("no input file given"),
+        (Some(_), Some(_)) => usage("only one input file can be given"),
+    };
+    let source = if input == "-" {
+        let mut source = Vec::new();
+        io::stdin().read_to_end(&mut source).map(|_| source)
+    } else {
+        cli::read_script(input)
+    };
+    let source = match source {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("looa: cannot open {}: {}", input, err);
+            process::exit(1);
+        }
+    };
+    let mut lua = Lua::new();
+    lua.set_optimize(optimize);
+    let name = if input == "-" { "stdin" } else { input };
+    match lua.disassemble(&source, name) {
+        Ok(listing) => print!("{}", listing),
+        Err(err) => {
+            eprintln!("looa: {}", err);
+            process::exit(1);
+        }
+    }
+    process::exit(0)
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("looa: {}\n{}", msg, USAGE);
+    process::exit(1)
+}
@@ -0,0 +1,124 @@
+//! `looa doc`, which renders the doc comments of modules as Markdown or
+//! HTML pages
+
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use cli;
+use looa::diagnostic;
+use looa::doc::{self, ModuleDoc};
+
+const USAGE: &str = "\
+usage: looa doc [options] script...
+Available options are:
+  --html    write HTML rather than Markdown
+  --all     also document local functions
+  -o dir    write a page for each module and an index to 'dir', rather
+            than printing the pages
+Doc comments start with '---' or '--[[--' and take the tags @param,
+@tparam, @return, @treturn, @usage, @see and @module.";
+
+/// Document the scripts named in `args`, whose options start at `start`,
+/// exiting with 1 if any couldn't be read or parsed
+pub fn main(args: &[String], start: usize) -> ! {
+    let mut html = false;
+    let mut all = false;
+    let mut out = None;
+    let mut paths = Vec::new();
+    let mut args = args[start..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--html" => html = true,
+            "--all" => all = true,
+            "-o" => match args.next() {
+                Some(dir) => out = Some(dir),
+                None => usage("'-o' needs argument"),
+            },
+            "--" => {
+                paths.extend(args);
+                break;
+            }
+            opt if opt.starts_with('-') => usage(&format!("unrecognized option '{}'", opt)),
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        usage("no input file given");
+    }
+    let color = cli::color(None).unwrap_or(false);
+    let mut failed = false;
+    let mut docs = Vec::new();
+    for path in paths {
+        let source = match cli::read_script(path) {
+            Ok(source) => source,
+            Err(err) => {
+                eprintln!("looa: cannot open {}: {}", path, err);
+                failed = true;
+                continue;
+            }
+        };
+        match doc::extract(&source, path) {
+            Ok(mut doc) => {
+                for warning in &doc.warnings {
+                    eprintln!("looa: {}", warning);
+                }
+                if !all {
+                    doc.functions.retain(|function| !function.local);
+                }
+                docs.push(doc);
+            }
+            Err(err) => {
+                eprint!(
+                    "{}",
+                    diagnostic::syntax_error(&err, &source, "looa: ", color)
+                );
+                failed = true;
+            }
+        }
+    }
+    let page = |doc: &ModuleDoc| if html { doc.html() } else { doc.markdown() };
+    match out {
+        Some(dir) => {
+            if let Err(err) = write_pages(Path::new(dir), &docs, html, &page) {
+                eprintln!("looa: cannot write to {}: {}", dir, err);
+                failed = true;
+            }
+        }
+        None => {
+            for (i, doc) in docs.iter().enumerate() {
+                if i > 0 && !html {
+                    println!();
+                }
+                print!("{}", page(doc));
+            }
+        }
+    }
+    process::exit(if failed { 1 } else { 0 })
+}
+
+/// Write each module's page to `dir`, named after the module, and an
+/// index linking to them
+fn write_pages(
+    dir: &Path,
+    docs: &[ModuleDoc],
+    html: bool,
+    page: &dyn Fn(&ModuleDoc) -> String,
+) -> ::std::io::Result<()> {
+    fs::create_dir_all(dir)?;
+    let ext = if html { "html" } else { "md" };
+    for doc in docs {
+        fs::write(dir.join(format!("{}.{}", doc.name, ext)), page(doc))?;
+    }
+    let index = if html {
+        doc::html_index(docs)
+    } else {
+        doc::markdown_index(docs)
+    };
+    fs::write(dir.join(format!("index.{}", ext)), index)
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("looa: {}\n{}", msg, USAGE);
+    process::exit(1)
+}
@@ -0,0 +1,373 @@
+//! A line editor for the prompts, with the usual keys for moving around and
+//! editing a line, and a history that can be kept in a file
+//!
+//! This puts a Unix terminal in raw mode while a line is read. When the
+//! input is not a terminal, or on other systems, lines are read as they
+//! come, without editing or history.
+
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, Read, Write};
+use std::path::PathBuf;
+
+/// How many lines the history file keeps
+const HISTORY_LIMIT: usize = 1000;
+
+/// Whether the input and output are a terminal, which lines are edited on
+pub fn is_terminal() -> bool {
+    terminal::is_terminal()
+}
+
+/// Whether stderr is a terminal, where errors can be colored
+pub fn is_error_terminal() -> bool {
+    terminal::is_error_terminal()
+}
+
+/// What reading a line gave
+pub enum Input {
+    Line(String),
+    /// Ctrl-C threw away the line
+    Cancelled,
+    /// the input ended, or Ctrl-D was pressed on an empty line
+    Eof,
+}
+
+pub struct Editor {
+    history: Vec<String>,
+    /// where lines are added to the history as they are entered
+    file: Option<PathBuf>,
+    terminal: bool,
+}
+impl Editor {
+    /// Create an editor keeping its history in `file`, starting with the
+    /// lines already there
+    pub fn new(file: Option<PathBuf>) -> Editor {
+        let mut history: Vec<String> = file
+            .as_ref()
+            .and_then(|file| fs::read_to_string(file).ok())
+            .map_or_else(Vec::new, |text| text.lines().map(str::to_string).collect());
+        if history.len() > HISTORY_LIMIT {
+            history.drain(..history.len() - HISTORY_LIMIT);
+            if let Some(ref file) = file {
+                let _ = fs::write(file, history.join("\n") + "\n");
+            }
+        }
+        Editor {
+            history,
+            file,
+            terminal: is_terminal(),
+        }
+    }
+    /// Read a line after showing `prompt`
+    pub fn read_line(&mut self, prompt: &str) -> io::Result<Input> {
+        let mut stdout = io::stdout();
+        stdout.write_all(prompt.as_bytes())?;
+        stdout.flush()?;
+        if !self.terminal {
+            let mut line = String::new();
+            if io::stdin().lock().read_line(&mut line)? == 0 {
+                return Ok(Input::Eof);
+            }
+            let end = line.trim_end_matches(&['\n', '\r'][..]).len();
+            line.truncate(end);
+            return Ok(Input::Line(line));
+        }
+        let raw = terminal::Raw::enable()?;
+        let input = self.edit(prompt);
+        drop(raw);
+        stdout.write_all(b"\r\n")?;
+        stdout.flush()?;
+        input
+    }
+    /// Add a line to the history, and to the history file if the lines
+    /// are from a terminal
+    pub fn add_history(&mut self, line: &str) {
+        if line.trim().is_empty() || self.history.last().map(String::as_str) == Some(line) {
+            return;
+        }
+        self.history.push(line.to_string());
+        if let (true, Some(file)) = (self.terminal, &self.file) {
+            let file = OpenOptions::new().create(true).append(true).open(file);
+            if let Ok(mut file) = file {
+                let _ = writeln!(file, "{}", line);
+            }
+        }
+    }
+    /// Edit a line in raw mode until it is entered
+    fn edit(&mut self, prompt: &str) -> io::Result<Input> {
+        let mut line = Line {
+            prompt,
+            text: Vec::new(),
+            cursor: 0,
+        };
+        // the entry being shown, where `history.len()` is the new line,
+        // which is kept in `draft` while going through the others
+        let mut entry = self.history.len();
+        let mut draft = Vec::new();
+        let stdin = io::stdin();
+        let mut keys = Keys {
+            input: stdin.lock(),
+        };
+        loop {
+            let key = match keys.next()? {
+                Some(key) => key,
+                None => return Ok(Input::Eof),
+            };
+            match key {
+                Key::Enter => return Ok(Input::Line(line.text.iter().collect())),
+                Key::Ctrl('c') => {
+                    line.end()?;
+                    io::stdout().write_all(b"^C")?;
+                    return Ok(Input::Cancelled);
+                }
+                Key::Ctrl('d') if line.text.is_empty() => return Ok(Input::Eof),
+                Key::Ctrl('d') | Key::Delete => {
+                    if line.cursor < line.text.len() {
+                        line.text.remove(line.cursor);
+                    }
+                }
+                Key::Backspace | Key::Ctrl('h') => {
+                    if line.cursor > 0 {
+                        line.cursor -= 1;
+                        line.text.remove(line.cursor);
+                    }
+                }
+                Key::Left | Key::Ctrl('b') => line.cursor = line.cursor.saturating_sub(1),
+                Key::Right | Key::Ctrl('f') => line.cursor = (line.cursor + 1).min(line.text.len()),
+                Key::Home | Key::Ctrl('a') => line.cursor = 0,
+                Key::End | Key::Ctrl('e') => line.cursor = line.text.len(),
+                Key::Ctrl('k') => line.text.truncate(line.cursor),
+                Key::Ctrl('u') => {
+                    line.text.drain(..line.cursor);
+                    line.cursor = 0;
+                }
+                Key::Ctrl('w') => {
+                    let mut start = line.cursor;
+                    while start > 0 && line.text[start - 1].is_whitespace() {
+                        start -= 1;
+                    }
+                    while start > 0 && !line.text[start - 1].is_whitespace() {
+                        start -= 1;
+                    }
+                    line.text.drain(start..line.cursor);
+                    line.cursor = start;
+                }
+                Key::Ctrl('l') => io::stdout().write_all(b"\x1b[H\x1b[2J")?,
+                Key::Up | Key::Ctrl('p') | Key::Down | Key::Ctrl('n') => {
+                    let to = match key {
+                        Key::Up | Key::Ctrl('p') => entry.checked_sub(1),
+                        _ if entry < self.history.len() => Some(entry + 1),
+                        _ => None,
+                    };
+                    if let Some(to) = to {
+                        if entry == self.history.len() {
+                            draft = line.text.clone();
+                        }
+                        entry = to;
+                        line.text = match self.history.get(entry) {
+                            Some(text) => text.chars().collect(),
+                            None => draft.clone(),
+                        };
+                        line.cursor = line.text.len();
+                    }
+                }
+                Key::Char(c) => {
+                    line.text.insert(line.cursor, c);
+                    line.cursor += 1;
+                }
+                Key::Ctrl(_) | Key::Other => (),
+            }
+            line.draw()?;
+        }
+    }
+}
+
+/// A line being edited
+struct Line<'a> {
+    prompt: &'a str,
+    text: Vec<char>,
+    cursor: usize,
+}
+impl<'a> Line<'a> {
+    /// Redraw the line with the cursor where it is in the text
+    fn draw(&self) -> io::Result<()> {
+        let text: String = self.text.iter().collect();
+        let mut out = format!("\r{}{}\x1b[K", self.prompt, text);
+        let after = self.text.len() - self.cursor;
+        if after > 0 {
+            out += &format!("\x1b[{}D", after);
+        }
+        let mut stdout = io::stdout();
+        stdout.write_all(out.as_bytes())?;
+        stdout.flush()
+    }
+    /// Move the cursor to the end of the line
+    fn end(&mut self) -> io::Result<()> {
+        self.cursor = self.text.len();
+        self.draw()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Key {
+    Char(char),
+    /// a letter pressed with Ctrl
+    Ctrl(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    /// an escape sequence for a key that does nothing
+    Other,
+}
+
+/// Reads keys from the terminal in raw mode
+struct Keys<'a> {
+    input: io::StdinLock<'a>,
+}
+impl<'a> Keys<'a> {
+    fn byte(&mut self) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        loop {
+            match self.input.read(&mut byte) {
+                Ok(0) => return Ok(None),
+                Ok(_) => return Ok(Some(byte[0])),
+                Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+    /// The next key pressed, or `None` at the end of the input
+    fn next(&mut self) -> io::Result<Option<Key>> {
+        let byte = match self.byte()? {
+            Some(byte) => byte,
+            None => return Ok(None),
+        };
+        Ok(Some(match byte {
+            b'\r' | b'\n' => Key::Enter,
+            0x7f | 0x08 => Key::Backspace,
+            0x1b => self.escape()?,
+            0..=0x1f => Key::Ctrl((byte + b'a' - 1) as char),
+            0x20..=0x7e => Key::Char(byte as char),
+            _ => {
+                // the rest of a UTF-8 character
+                let len = match byte {
+                    0xf0..=0xff => 4,
+                    0xe0..=0xef => 3,
+                    _ => 2,
+                };
+                let mut bytes = vec![byte];
+                for _ in 1..len {
+                    match self.byte()? {
+                        Some(byte) => bytes.push(byte),
+                        None => break,
+                    }
+                }
+                match String::from_utf8(bytes) {
+                    Ok(text) => Key::Char(text.chars().next().unwrap_or('?')),
+                    Err(_) => Key::Other,
+                }
+            }
+        }))
+    }
+    /// The key an escape sequence is for, after its escape
+    fn escape(&mut self) -> io::Result<Key> {
+        let kind = self.byte()?;
+        let code = self.byte()?;
+        Ok(match (kind, code) {
+            (Some(b'['), Some(b'A')) | (Some(b'O'), Some(b'A')) => Key::Up,
+            (Some(b'['), Some(b'B')) | (Some(b'O'), Some(b'B')) => Key::Down,
+            (Some(b'['), Some(b'C')) | (Some(b'O'), Some(b'C')) => Key::Right,
+            (Some(b'['), Some(b'D')) | (Some(b'O'), Some(b'D')) => Key::Left,
+            (Some(b'['), Some(b'H')) | (Some(b'O'), Some(b'H')) => Key::Home,
+            (Some(b'['), Some(b'F')) | (Some(b'O'), Some(b'F')) => Key::End,
+            (Some(b'['), Some(digit @ b'0'..=b'9')) => {
+                // `ESC [ n ~`, possibly with modifiers after the number
+                let mut last = digit;
+                while let Some(byte) = self.byte()? {
+                    if byte != b';' && !byte.is_ascii_digit() {
+                        break;
+                    }
+                    last = byte;
+                }
+                match digit {
+                    b'1' | b'7' if last == digit => Key::Home,
+                    b'4' | b'8' if last == digit => Key::End,
+                    b'3' if last == digit => Key::Delete,
+                    _ => Key::Other,
+                }
+            }
+            _ => Key::Other,
+        })
+    }
+}
+
+#[cfg(unix)]
+mod terminal {
+    use libc;
+    use std::io;
+    use std::mem;
+
+    pub fn is_terminal() -> bool {
+        unsafe { libc::isatty(libc::STDIN_FILENO) == 1 && libc::isatty(libc::STDOUT_FILENO) == 1 }
+    }
+
+    pub fn is_error_terminal() -> bool {
+        unsafe { libc::isatty(libc::STDERR_FILENO) == 1 }
+    }
+
+    /// Keeps the terminal in raw mode until dropped
+    pub struct Raw {
+        original: libc::termios,
+    }
+    impl Raw {
+        pub fn enable() -> io::Result<Raw> {
+            unsafe {
+                let mut original = mem::zeroed();
+                if libc::tcgetattr(libc::STDIN_FILENO, &mut original) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                let mut raw = original;
+                raw.c_iflag &= !(libc::ICRNL | libc::IXON);
+                raw.c_lflag &= !(libc::ECHO | libc::ICANON | libc::IEXTEN | libc::ISIG);
+                raw.c_cc[libc::VMIN] = 1;
+                raw.c_cc[libc::VTIME] = 0;
+                if libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &raw) != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+                Ok(Raw { original })
+            }
+        }
+    }
+    impl Drop for Raw {
+        fn drop(&mut self) {
+            unsafe {
+                libc::tcsetattr(libc::STDIN_FILENO, libc::TCSAFLUSH, &self.original);
+            }
+        }
+    }
+}
+
+#[cfg(not(unix))]
+mod terminal {
+    use std::io;
+
+    pub fn is_terminal() -> bool {
+        false
+    }
+
+    pub fn is_error_terminal() -> bool {
+        false
+    }
+
+    pub struct Raw;
+    impl Raw {
+        pub fn enable() -> io::Result<Raw> {
+            Ok(Raw)
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! `looa fmt`, which rewrites scripts in one consistent style, keeping
+//! their comments
+//!
+//! Files are rewritten in place, and only if formatting changes them.
+//! Stdin, given as `-`, is formatted to stdout.
+
+use std::fs;
+use std::io::{self, Read, Write};
+use std::process;
+
+use cli;
+use looa::diagnostic;
+use looa::format::{self, Quotes, Style};
+
+const USAGE: &str = "\
+usage: looa fmt [options] script...
+Available options are:
+  --indent n        indent by n spaces (default is 4)
+  --tabs            indent with tabs
+  --quotes q        put short strings in 'double' (the default) or 'single'
+                    quotes
+  --width n         break lines longer than n columns where they can be
+                    (default is 80)
+  --check           only list the scripts that are not formatted, failing
+                    if there are any, rather than rewriting them
+  -                 format stdin to stdout
+Scripts are rewritten in place.";
+
+/// Format the scripts named in `args`, whose options start at `start`,
+/// and exit with its status
+pub fn main(args: &[String], start: usize) -> ! {
+    let mut style = Style::default();
+    let mut check = false;
+    let mut paths = Vec::new();
+    let mut args = args[start..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--indent" => {
+                let spaces = number(args.next(), "--indent");
+                style.indent = " ".repeat(spaces);
+            }
+            "--tabs" => style.indent = "\t".to_string(),
+            "--quotes" => {
+                style.quotes = match args.next().map(String::as_str) {
+                    Some("double") => Quotes::Double,
+                    Some("single") => Quotes::Single,
+                    Some(other) => usage(&format!("unknown quote style '{}'", other)),
+                    None => usage("'--quotes' needs argument"),
+                }
+            }
+            "--width" => style.width = number(args.next(), "--width"),
+            "--check" => check = true,
+            "--" => {
+                paths.extend(args);
+                break;
+            }
+            opt if opt.starts_with('-') && opt != "-" => {
+                usage(&format!("unrecognized option '{}'", opt))
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        usage("no input file given");
+    }
+    let color = cli::color(None).unwrap_or(false);
+    let mut failed = false;
+    for path in paths {
+        let source = if path == "-" {
+            let mut source = Vec::new();
+            io::stdin().read_to_end(&mut source).map(|_| source)
+        } else {
+            fs::read(path)
+        };
+        let source = match source {
+            Ok(source) => source,
+            Err(err) => {
+                eprintln!("looa: cannot open {}: {}", path, err);
+                failed = true;
+                continue;
+            }
+        };
+        // a `#!` line is kept as it is, and what follows is parsed from the
+        // newline ending it so that errors have the right line numbers
+        let shebang = if source.starts_with(b"#") {
+            source
+                .iter()
+                .position(|&c| c == b'\n')
+                .unwrap_or(source.len())
+        } else {
+            0
+        };
+        let mut formatted = source[..shebang].to_vec();
+        if shebang > 0 {
+            formatted.push(b'\n');
+        }
+        let code = &source[shebang..];
+        let name = if path == "-" { "stdin" } else { path };
+        match format::format(code, name, &style) {
+            Ok(code) => formatted.extend(code),
+            Err(err) => {
+                eprint!("{}", diagnostic::syntax_error(&err, code, "looa: ", color));
+                failed = true;
+                continue;
+            }
+        }
+        let unchanged = formatted == source;
+        let result = if check {
+            if !unchanged {
+                println!("{}", path);
+                failed = true;
+            }
+            Ok(())
+        } else if path == "-" {
+            io::stdout().write_all(&formatted)
+        } else if unchanged {
+            Ok(())
+        } else {
+            fs::write(path, &formatted)
+        };
+        if let Err(err) = result {
+            eprintln!("looa: cannot write {}: {}", path, err);
+            failed = true;
+        }
+    }
+    process::exit(if failed { 1 } else { 0 })
+}
+
+fn number(arg: Option<&String>, opt: &str) -> usize {
+    match arg.map(|arg| arg.parse()) {
+        Some(Ok(n)) => n,
+        Some(Err(_)) => usage(&format!("'{}' needs a number", opt)),
+        None => usage(&format!("'{}' needs argument", opt)),
+    }
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("looa: {}\n{}", msg, USAGE);
+    process::exit(1)
+}
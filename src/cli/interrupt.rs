@@ -0,0 +1,51 @@
+//! Ctrl-C stopping the code a state runs with an "interrupted!" error
+//! rather than ending the process, as the prompt and scripts both want
+//!
+//! The handler stands down once it has fired, so a second Ctrl-C still
+//! ends a script stuck where it doesn't take steps, such as reading stdin.
+
+#[cfg(unix)]
+pub use self::unix::{catch, release};
+
+#[cfg(unix)]
+mod unix {
+    use libc;
+    use std::sync::OnceLock;
+
+    use looa::{InterruptHandle, Lua};
+
+    /// the handle of the state running, which the signal handler uses
+    static HANDLE: OnceLock<InterruptHandle> = OnceLock::new();
+
+    extern "C" fn on_interrupt(_: libc::c_int) {
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+        }
+        if let Some(handle) = HANDLE.get() {
+            handle.interrupt();
+        }
+    }
+
+    /// Interrupt `lua` on Ctrl-C until `release` is called
+    pub fn catch(lua: &Lua) {
+        let handle = HANDLE.get_or_init(|| lua.interrupt_handle());
+        handle.reset();
+        let handler: extern "C" fn(libc::c_int) = on_interrupt;
+        unsafe {
+            libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        }
+    }
+
+    /// Let Ctrl-C end the process again
+    pub fn release() {
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+        }
+    }
+}
+
+#[cfg(not(unix))]
+pub fn catch(_: &::looa::Lua) {}
+
+#[cfg(not(unix))]
+pub fn release() {}
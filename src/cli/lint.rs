@@ -0,0 +1,111 @@
+//! `looa lint`, which reports likely mistakes in scripts without running
+//! them
+
+use std::collections::HashSet;
+use std::io::{self, Read};
+use std::process;
+
+use cli;
+use looa::{diagnostic, lint};
+use looa::{ConvertValue, Lua, Table, Value};
+
+const USAGE: &str = "\
+usage: looa lint [options] script...
+Available options are:
+  --globals names   also take the comma-separated 'names' as defined
+                    globals
+  -                 lint stdin
+The rules are undefined-global, unused-local, shadowed-local,
+unreachable-code and empty-if, and comments turn them off:
+  -- lint: ignore [rules]    on this line, or the next if alone on its line
+  -- lint: disable [rules]   from here on
+  -- lint: enable [rules]    from here on again
+where leaving out the rules means all of them.";
+
+/// Lint the scripts named in `args`, whose options start at `start`,
+/// exiting with 1 if there are any warnings
+pub fn main(args: &[String], start: usize) -> ! {
+    let mut globals = defined_globals();
+    let mut paths = Vec::new();
+    let mut args = args[start..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--globals" => match args.next() {
+                Some(names) => globals.extend(
+                    names
+                        .split(',')
+                        .filter(|name| !name.is_empty())
+                        .map(str::to_string),
+                ),
+                None => usage("'--globals' needs argument"),
+            },
+            "--" => {
+                paths.extend(args);
+                break;
+            }
+            opt if opt.starts_with('-') && opt != "-" => {
+                usage(&format!("unrecognized option '{}'", opt))
+            }
+            _ => paths.push(arg),
+        }
+    }
+    if paths.is_empty() {
+        usage("no input file given");
+    }
+    let color = cli::color(None).unwrap_or(false);
+    let mut failed = false;
+    for path in paths {
+        let source = if path == "-" {
+            let mut source = Vec::new();
+            io::stdin().read_to_end(&mut source).map(|_| source)
+        } else {
+            cli::read_script(path)
+        };
+        let source = match source {
+            Ok(source) => source,
+            Err(err) => {
+                eprintln!("looa: cannot open {}: {}", path, err);
+                failed = true;
+                continue;
+            }
+        };
+        let name = if path == "-" { "stdin" } else { path };
+        match lint::lint(&source, name, &globals) {
+            Ok(warnings) => {
+                for warning in &warnings {
+                    println!("{}", warning);
+                }
+                failed |= !warnings.is_empty();
+            }
+            Err(err) => {
+                eprint!(
+                    "{}",
+                    diagnostic::syntax_error(&err, &source, "looa: ", color)
+                );
+                failed = true;
+            }
+        }
+    }
+    process::exit(if failed { 1 } else { 0 })
+}
+
+/// The globals a script run by `looa` starts with
+fn defined_globals() -> HashSet<String> {
+    let lua = Lua::new();
+    let mut names = HashSet::new();
+    // set by the command line rather than the state
+    names.insert("arg".to_string());
+    if let Some(globals) = Table::from_value(lua.globals()) {
+        let mut key = Value::nil();
+        while let Ok(Some((next, _))) = globals.next(&key) {
+            names.insert(next.to_string());
+            key = next;
+        }
+    }
+    names
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("looa: {}\n{}", msg, USAGE);
+    process::exit(1)
+}
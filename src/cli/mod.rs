@@ -0,0 +1,73 @@
+//! The parts of the `looa` binary that take more than running a script
+
+pub mod bench;
+pub mod check;
+pub mod compile;
+pub mod cover;
+pub mod debug;
+pub mod deps;
+pub mod diff;
+pub mod dis;
+pub mod doc;
+pub mod editor;
+pub mod fmt;
+pub mod interrupt;
+pub mod lint;
+pub mod profile;
+pub mod repl;
+pub mod run;
+pub mod test;
+pub mod watch;
+
+use std::env;
+use std::fs;
+use std::io;
+use std::process;
+
+use looa::config::Config;
+
+/// Read a script, blanking out a `#!` line while keeping its newline, so
+/// that line numbers stay the same
+pub fn read_script(path: &str) -> io::Result<Vec<u8>> {
+    let mut source = fs::read(path)?;
+    if source.starts_with(b"#") {
+        let end = source
+            .iter()
+            .position(|&c| c == b'\n')
+            .unwrap_or(source.len());
+        source.drain(..end);
+    }
+    Ok(source)
+}
+
+/// The settings of the `looa.toml` files for the current directory,
+/// exiting if one can't be read
+pub fn config() -> Config {
+    let dir = env::current_dir().unwrap_or_default();
+    match Config::load(&dir) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("looa: {}", err);
+            process::exit(1);
+        }
+    }
+}
+
+/// Whether to write errors in color, given the value of a `--color`
+/// option if there was one: `always`, `never`, or `auto`, the default,
+/// which colors them when stderr is a terminal and `NO_COLOR` isn't set to
+/// something
+pub fn color(when: Option<&str>) -> Result<bool, String> {
+    match when.unwrap_or("auto") {
+        "always" => Ok(true),
+        "never" => Ok(false),
+        "auto" => {
+            let no_color = env::var_os("NO_COLOR").is_some_and(|val| !val.is_empty());
+            Ok(!no_color && editor::is_error_terminal())
+        }
+        other => Err(format!(
+            "'--color' needs 'always', 'never' or 'auto', not '{}'",
+            other
+        )),
+    }
+}
@@ -0,0 +1,171 @@
+//! `looa profile`, which runs a script with the profiler, writes what it
+//! found for flame graph tools, and lists the functions it spends the most
+//! steps in
+//!
+//! Functions are named as `looa deps --calls` names them, such as
+//! `M.parse (lib.lua:10)`, or by where they are defined if they have no
+//! name, and main chunks by their chunk's name.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+use cli;
+use looa::graph::Graph;
+use looa::profile::{Frame, Profiler};
+use looa::{Backend, Lua, Value};
+
+const USAGE: &str = "\
+usage: looa profile [options] script [args]
+Available options are:
+  --out file    write the samples to 'file' in the folded format that
+                flame graph tools read
+  -n count      list the 'count' functions with most samples (default: 10)
+  --every steps take a sample every 'steps' steps (default: 1000)
+  --vm          run with the bytecode VM rather than the interpreter
+Samples are of the Lua functions running, every so many steps; time spent
+in library functions counts towards the Lua function that called them.
+The list is written to stderr, after what the script prints.";
+
+/// Profile the script named in `args`, whose options start at `start`,
+/// exiting with 1 if it couldn't be run or raised an error
+pub fn main(args: &[String], start: usize) -> ! {
+    let mut profiler = Profiler::new();
+    let mut out = None;
+    let mut top = 10;
+    let mut vm = false;
+    let mut i = start;
+    while let Some(arg) = args.get(i) {
+        match arg.as_str() {
+            "--out" => {
+                i += 1;
+                match args.get(i) {
+                    Some(file) => out = Some(file),
+                    None => usage("'--out' needs argument"),
+                }
+            }
+            "-n" => {
+                i += 1;
+                match args.get(i).and_then(|n| n.parse().ok()) {
+                    Some(n) => top = n,
+                    None => usage("'-n' needs a number"),
+                }
+            }
+            "--every" => {
+                i += 1;
+                match args.get(i).and_then(|n| n.parse().ok()) {
+                    Some(steps) => profiler.set_interval(steps),
+                    None => usage("'--every' needs a number"),
+                }
+            }
+            "--vm" => vm = true,
+            "--" => {
+                i += 1;
+                break;
+            }
+            opt if opt.starts_with('-') => usage(&format!("unrecognized option '{}'", opt)),
+            _ => break,
+        }
+        i += 1;
+    }
+    if i >= args.len() {
+        usage("no input file given");
+    }
+    let path = &args[i];
+    let source = match cli::read_script(path) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("looa: cannot open {}: {}", path, err);
+            process::exit(1);
+        }
+    };
+    let config = cli::config();
+    let color = cli::color(config.color.as_deref()).expect("checked when read");
+    let mut lua = Lua::new();
+    let main = config
+        .apply(&mut lua)
+        .and_then(|()| {
+            if vm {
+                lua.set_backend(Backend::Vm);
+            }
+            cli::run::set_args(&mut lua, args, Some(i))
+        })
+        .and_then(|()| lua.load(&source, path));
+    let main = match main {
+        Ok(main) => main,
+        Err(err) => {
+            let script = Some((path.as_str(), &*source));
+            eprint!("{}", cli::run::report(&lua, &err, script, color));
+            process::exit(1);
+        }
+    };
+    let script_args = args[i + 1..].iter().map(Value::string).collect();
+    let (profile, result) = profiler.run(&mut lua, &main, script_args);
+    let mut failed = false;
+    if let Err(err) = result {
+        let script = Some((path.as_str(), &*source));
+        eprint!("{}", cli::run::report(&lua, &err, script, color));
+        failed = true;
+    }
+    let names = names(config.module_path(), path);
+    let name = |frame: &Frame| match names.get(frame) {
+        Some(name) => format!("{} ({})", name, frame),
+        None => frame.to_string(),
+    };
+    if let Some(out) = out {
+        if let Err(err) = fs::write(out, profile.folded(&name)) {
+            eprintln!("looa: cannot write {}: {}", out, err);
+            process::exit(1);
+        }
+    }
+    let samples = profile.samples();
+    eprintln!(
+        "\n{} {}, one every {} steps",
+        samples,
+        if samples == 1 { "sample" } else { "samples" },
+        profiler.interval()
+    );
+    let functions = profile.functions();
+    if !functions.is_empty() {
+        eprintln!("{:>7} {:>7}  function", "self", "total");
+    }
+    let percent = |n: u64| 100.0 * n as f64 / samples as f64;
+    for function in functions.iter().take(top) {
+        eprintln!(
+            "{:>6.1}% {:>6.1}%  {}",
+            percent(function.own),
+            percent(function.total),
+            name(&function.frame)
+        );
+    }
+    process::exit(if failed { 1 } else { 0 })
+}
+
+/// The names of the functions in the script at `path` and the modules it
+/// requires along `module_path`, by where they are defined
+fn names(module_path: &str, path: &str) -> HashMap<Frame, String> {
+    let mut graph = Graph::new(module_path);
+    graph.add(Path::new(path));
+    let mut names = HashMap::new();
+    for (m, module) in graph.modules().iter().enumerate() {
+        // the script is loaded by the name it was given
+        let chunk = match m {
+            0 => path.to_string(),
+            _ => module.path.to_string_lossy().into_owned(),
+        };
+        for function in module.functions.iter().filter(|f| !f.name.is_empty()) {
+            let frame = Frame {
+                chunk: chunk.clone(),
+                line: function.line,
+            };
+            names.insert(frame, function.name.clone());
+        }
+    }
+    names
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("looa: {}\n{}", msg, USAGE);
+    process::exit(1)
+}
@@ -0,0 +1,192 @@
+//! The interactive prompt `looa` starts without a script
+//!
+//! What is entered runs as soon as it makes up a statement, with `>>`
+//! prompting for more until it does, and the values of an expression are
+//! printed, tables with their contents unless `:set pretty off` was
+//! entered. The lines' history is kept in `~/.looa_history`. Ctrl-C throws
+//! away what is being typed, and stops the code it runs without leaving
+//! the prompt.
+
+use std::env;
+use std::path::PathBuf;
+
+use cli;
+use cli::editor::{Editor, Input};
+use looa::config::Config;
+use looa::{diagnostic, inspect, parser, Error, Lua, Result, Type, Value};
+
+/// How the prompt prints the values of expressions, which `:set` changes
+#[derive(Clone, Debug, PartialEq)]
+pub struct Settings {
+    /// print tables with their contents rather than their addresses, as
+    /// long as they have no `__tostring` metamethod
+    pub pretty: bool,
+    /// how many tables deep to print the contents of
+    pub depth: usize,
+}
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            pretty: true,
+            depth: 3,
+        }
+    }
+}
+impl Settings {
+    /// The settings `[repl]` in `looa.toml` gives, and the default for
+    /// those it doesn't
+    pub fn of(config: &Config) -> Settings {
+        let default = Settings::default();
+        Settings {
+            pretty: config.pretty.unwrap_or(default.pretty),
+            depth: config.depth.unwrap_or(default.depth),
+        }
+    }
+    /// Change the setting named in `line`, a `:set` command, or list the
+    /// settings if it names none
+    fn set(&mut self, line: &str) -> ::std::result::Result<(), String> {
+        let words: Vec<&str> = line.split_whitespace().skip(1).collect();
+        match words[..] {
+            [] => println!("pretty {}\ndepth {}", on_off(self.pretty), self.depth),
+            ["pretty", "on"] => self.pretty = true,
+            ["pretty", "off"] => self.pretty = false,
+            ["depth", n] => {
+                self.depth = n
+                    .parse()
+                    .map_err(|_| format!("depth must be a number, not '{}'", n))?
+            }
+            _ => return Err(format!("usage: {}", SET_USAGE)),
+        }
+        Ok(())
+    }
+}
+
+const SET_USAGE: &str = ":set [pretty on|off] [depth n]";
+
+fn on_off(on: bool) -> &'static str {
+    if on {
+        "on"
+    } else {
+        "off"
+    }
+}
+
+/// Run lines in `lua` until the input ends, printing values as `settings`
+/// says to start with
+pub fn run(lua: &mut Lua, mut settings: Settings) {
+    println!("looa {}, Ctrl-D exits", env!("CARGO_PKG_VERSION"));
+    let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(".looa_history"));
+    let mut editor = Editor::new(history);
+    let color = cli::color(None).unwrap_or(false);
+    loop {
+        let mut source = String::new();
+        let mut prompt = "> ";
+        // read lines until they make up a statement or expression
+        let loaded = loop {
+            match editor.read_line(prompt) {
+                // `:` can't start a statement, so the prompt's own commands
+                // start with it
+                Ok(Input::Line(ref line)) if source.is_empty() && is_command(line) => {
+                    editor.add_history(line);
+                    if let Err(msg) = settings.set(line) {
+                        eprintln!("{}", msg);
+                    }
+                    break None;
+                }
+                Ok(Input::Line(line)) => {
+                    editor.add_history(&line);
+                    source += &line;
+                }
+                Ok(Input::Cancelled) => break None,
+                Ok(Input::Eof) | Err(_) => return,
+            }
+            match load(lua, &source) {
+                Some(loaded) => break Some(loaded),
+                None => {
+                    source.push('\n');
+                    prompt = ">> ";
+                }
+            }
+        };
+        let loaded = match loaded {
+            Some(loaded) => loaded,
+            None => continue,
+        };
+        cli::interrupt::catch(lua);
+        let result = loaded.and_then(|(chunk, echo)| {
+            let vals = chunk.call(Vec::new())?.into_vec();
+            if echo && !vals.is_empty() {
+                let vals = vals
+                    .into_iter()
+                    .map(|val| shown(lua, val, &settings))
+                    .collect::<Result<_>>()?;
+                lua.get_global("print")?.call(vals)?;
+            }
+            Ok(())
+        });
+        cli::interrupt::release();
+        match result {
+            Ok(()) => (),
+            Err(err) => report(lua, &err, &source, color),
+        }
+        let _ = lua.run_finalizers();
+    }
+}
+
+/// Write out `err`, raised by the code in `source`, in color if `color` is
+/// set
+fn report(lua: &Lua, err: &Error, source: &str, color: bool) {
+    let source = source.as_bytes();
+    if let Error::Syntax(_) = *err {
+        if let Err(err) = parser::parse_chunk(source, "stdin") {
+            eprint!("{}", diagnostic::syntax_error(&err, source, "", color));
+            return;
+        }
+    }
+    let traceback = lua.traceback();
+    let script = Some(("stdin", source));
+    eprint!(
+        "{}",
+        diagnostic::runtime_error(lua, err, script, &traceback, "", color)
+    );
+}
+
+/// Whether `line` is one of the prompt's own commands rather than code,
+/// which only `:set` is
+fn is_command(line: &str) -> bool {
+    let line = line.trim_start();
+    line == ":set" || line.starts_with(":set ")
+}
+
+/// `val` as the prompt prints it, which is a string of its contents for a
+/// table if `settings` asks for that
+fn shown(lua: &Lua, val: Value, settings: &Settings) -> Result<Value> {
+    let custom = val
+        .get_metatable()
+        .is_some_and(|meta| !meta.raw_get(&lua.string("__tostring")).is_nil());
+    if !settings.pretty || val.type_of() != Type::Table || custom {
+        return Ok(val);
+    }
+    Ok(lua.string(inspect::inspect(&val, settings.depth)?))
+}
+
+/// Load what was entered as an expression whose values are printed, if it
+/// is one, or as a chunk, giving `None` if more lines could finish it
+fn load(lua: &Lua, source: &str) -> Option<Result<(Value, bool)>> {
+    let expr = format!("return {}", source);
+    if parser::parse_chunk(expr.as_bytes(), "stdin").is_ok() {
+        return Some(
+            lua.load(expr.as_bytes(), "stdin")
+                .map(|chunk| (chunk, true)),
+        );
+    }
+    match parser::parse_chunk(source.as_bytes(), "stdin") {
+        // errors at the end of the input, or in a long string or comment
+        // running up to it, are from something not being closed yet
+        Err(ref err) if err.found.is_none() || err.message.starts_with("unfinished long") => None,
+        _ => Some(
+            lua.load(source.as_bytes(), "stdin")
+                .map(|chunk| (chunk, false)),
+        ),
+    }
+}
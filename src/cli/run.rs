@@ -0,0 +1,358 @@
+//! Running scripts with the options the reference `lua` binary takes, so
+//! scripts and `#!` lines written for it work the same
+//!
+//! `-e` and `-l` run in the order given, then the script, with the
+//! arguments after it also in the global `arg` table, and then the prompt
+//! if `-i` was given. Without a script or `-e`, the prompt starts if the
+//! input is a terminal, and otherwise the input is run as the script.
+//! The `--dump-*` options list how the script compiles instead of running
+//! it, once the options before it have run. The settings of `looa.toml`
+//! files apply first, and the options override them. Ctrl-C stops what
+//! runs with an "interrupted!" error.
+
+use std::io::{self, Read};
+use std::process;
+
+use cli;
+use looa::{diagnostic, lexer, parser};
+use looa::{Backend, Error, Lua, LuaInteger, ParseError, Result, Table, Value};
+
+const USAGE: &str = "\
+usage: looa [options] [script [args]]
+       looa debug [options] script [args]
+       looa bench [options] script...
+       looa compile [-s] [-o file] script...
+       looa cover [options] [dir|file...]
+       looa check script...
+       looa -p script...
+       looa deps [options] script...
+       looa diff [--lua path] [--vm] script...
+       looa dis [-n] script
+       looa doc [--html] [--all] [-o dir] script...
+       looa fmt [options] script...
+       looa lint [--globals names] script...
+       looa profile [options] script [args]
+       looa test [options] [dir|file...]
+       looa watch [options] script [args]
+Available options are:
+  -e stat   run string 'stat'
+  -i        enter interactive mode after running the script
+  -l name   load library 'name' into global 'name'
+  -l g=name load library 'name' into global 'g'
+  -v        show version information
+  --vm      run with the bytecode VM rather than the interpreter, which is
+            faster but compiles each chunk first
+  --color when
+            color errors 'always', 'never' or 'auto', which colors them
+            when stderr is a terminal and NO_COLOR isn't set
+  --dump-tokens, --dump-ast, --dump-bytecode
+            list the script's tokens, syntax tree or VM instructions
+            rather than running it
+  --        stop handling options
+  -         run stdin and stop handling options
+Settings in looa.toml files, the user's and the project's, apply first.";
+
+enum Action {
+    Exec(String),
+    /// a global and the library to load into it
+    Load(String, String),
+}
+
+/// A listing of how the script is compiled, printed instead of running it
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Dump {
+    Tokens,
+    Ast,
+    Bytecode,
+}
+
+/// What the command line asks for
+struct Options {
+    actions: Vec<Action>,
+    interactive: bool,
+    version: bool,
+    vm: bool,
+    /// when errors are written in color, if `--color` was given
+    color: Option<String>,
+    /// the listings to print, in the order asked for
+    dumps: Vec<Dump>,
+    /// where the script's name is in the arguments, if one is given
+    script: Option<usize>,
+}
+impl Options {
+    /// Parse the options in `args`, starting at `start`, until the script
+    fn parse(args: &[String], start: usize) -> ::std::result::Result<Options, String> {
+        let mut options = Options {
+            actions: Vec::new(),
+            interactive: false,
+            version: false,
+            vm: false,
+            color: None,
+            dumps: Vec::new(),
+            script: None,
+        };
+        let mut i = start;
+        while i < args.len() {
+            let opt = &args[i];
+            if opt == "--" {
+                i += 1;
+                break;
+            }
+            if !opt.starts_with('-') || opt == "-" {
+                break;
+            }
+            let dump = match opt.as_str() {
+                "--vm" => {
+                    options.vm = true;
+                    i += 1;
+                    continue;
+                }
+                "--color" => {
+                    i += 1;
+                    let when = args.get(i).ok_or("'--color' needs argument")?;
+                    cli::color(Some(when))?;
+                    options.color = Some(when.clone());
+                    i += 1;
+                    continue;
+                }
+                "--dump-tokens" => Some(Dump::Tokens),
+                "--dump-ast" => Some(Dump::Ast),
+                "--dump-bytecode" => Some(Dump::Bytecode),
+                _ => None,
+            };
+            if let Some(dump) = dump {
+                options.dumps.push(dump);
+                i += 1;
+                continue;
+            }
+            match opt.get(..2).unwrap_or(opt) {
+                "-i" | "-v" if opt.len() > 2 => {
+                    return Err(format!("unrecognized option '{}'", opt));
+                }
+                "-i" => options.interactive = true,
+                "-v" => options.version = true,
+                "-e" | "-l" => {
+                    // the argument can be given with the option or after it
+                    let value = if opt.len() > 2 {
+                        opt[2..].to_string()
+                    } else {
+                        i += 1;
+                        match args.get(i) {
+                            Some(value) => value.clone(),
+                            None => return Err(format!("'{}' needs argument", opt)),
+                        }
+                    };
+                    options.actions.push(if opt.starts_with("-e") {
+                        Action::Exec(value)
+                    } else {
+                        match value.find('=') {
+                            Some(at) => {
+                                Action::Load(value[..at].to_string(), value[at + 1..].to_string())
+                            }
+                            None => Action::Load(value.clone(), value),
+                        }
+                    });
+                }
+                _ => return Err(format!("unrecognized option '{}'", opt)),
+            }
+            i += 1;
+        }
+        if i < args.len() {
+            options.script = Some(i);
+        }
+        Ok(options)
+    }
+}
+
+/// Run what the command line `args` asks for, where the options start at
+/// `start`, under the debugger if `debug` is set, and exit with its status
+pub fn main(args: &[String], start: usize, debug: bool) -> ! {
+    let options = match Options::parse(args, start) {
+        Ok(options) => options,
+        Err(msg) => {
+            eprintln!("looa: {}\n{}", msg, USAGE);
+            process::exit(1);
+        }
+    };
+    if (debug || !options.dumps.is_empty()) && options.script.is_none() {
+        eprintln!("{}", USAGE);
+        process::exit(1);
+    }
+    let config = cli::config();
+    let when = options.color.as_ref().or(config.color.as_ref());
+    let color = cli::color(when.map(String::as_str)).expect("checked when read");
+    let mut lua = Lua::new();
+    if let Err(err) = config.apply(&mut lua) {
+        fail(&lua, &err, None, color);
+    }
+    if options.vm {
+        lua.set_backend(Backend::Vm);
+    }
+    if options.version {
+        println!("looa {}", env!("CARGO_PKG_VERSION"));
+    }
+    if let Err(err) = set_args(&mut lua, args, options.script) {
+        fail(&lua, &err, None, color);
+    }
+    // Ctrl-C stops the code running with an error, which is reported as
+    // any other
+    cli::interrupt::catch(&lua);
+    for action in &options.actions {
+        let result = match *action {
+            Action::Exec(ref source) => lua
+                .load(source.as_bytes(), "(command line)")
+                .and_then(|chunk| chunk.call(Vec::new()))
+                .map(|_| ()),
+            Action::Load(ref global, ref name) => load_library(&mut lua, global, name),
+        };
+        if let Err(err) = result {
+            let script = match *action {
+                Action::Exec(ref source) => Some(("(command line)", source.as_bytes())),
+                Action::Load(..) => None,
+            };
+            fail(&lua, &err, script, color);
+        }
+    }
+    if let Some(script) = options.script {
+        let path = &args[script];
+        if debug {
+            cli::debug::attach(&mut lua, path);
+        }
+        let source = read_input(path);
+        let name = if path == "-" { "stdin" } else { path };
+        if !options.dumps.is_empty() {
+            dump(&lua, &options.dumps, &source, name, color);
+        }
+        // the script gets the arguments after its name as `...`
+        let script_args = args[script + 1..].iter().map(Value::string).collect();
+        let result = lua
+            .load(&source, name)
+            .and_then(|main| main.call(script_args));
+        if let Err(err) = result {
+            fail(&lua, &err, Some((name, &source)), color);
+        }
+        if debug {
+            println!("script finished");
+        }
+    }
+    let nothing_else = options.script.is_none() && options.actions.is_empty() && !options.version;
+    if options.interactive || nothing_else && cli::editor::is_terminal() {
+        cli::interrupt::release();
+        cli::repl::run(&mut lua, cli::repl::Settings::of(&config));
+    } else if nothing_else {
+        let mut source = Vec::new();
+        let result = match io::stdin().read_to_end(&mut source) {
+            Ok(_) => lua
+                .load(&source, "stdin")
+                .and_then(|main| main.call(Vec::new())),
+            Err(err) => Err(Error::Runtime(format!("cannot read stdin: {}", err))),
+        };
+        if let Err(err) = result {
+            fail(&lua, &err, Some(("stdin", &source)), color);
+        }
+    }
+    process::exit(0)
+}
+
+/// Read the script at `path`, or stdin for `-`, exiting if it can't be read
+fn read_input(path: &str) -> Vec<u8> {
+    let source = if path == "-" {
+        let mut source = Vec::new();
+        io::stdin().read_to_end(&mut source).map(|_| source)
+    } else {
+        cli::read_script(path)
+    };
+    match source {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("looa: cannot open {}: {}", path, err);
+            process::exit(1);
+        }
+    }
+}
+
+/// Print the listings in `dumps` of `source`, the script named `name`, and
+/// exit without running it
+fn dump(lua: &Lua, dumps: &[Dump], source: &[u8], name: &str, color: bool) -> ! {
+    let syntax_error = |err: ParseError| -> ! {
+        eprint!(
+            "{}",
+            diagnostic::syntax_error(&err, source, "looa: ", color)
+        );
+        process::exit(1)
+    };
+    for &dump in dumps {
+        match dump {
+            Dump::Tokens => match lexer::tokenize(source) {
+                Ok(tokens) => {
+                    for token in tokens {
+                        println!("{}\t{:?}", token.pos, token.kind);
+                    }
+                }
+                Err(mut err) => {
+                    // the lexer alone doesn't know the name
+                    err.chunk = Some(name.to_string());
+                    syntax_error(err)
+                }
+            },
+            Dump::Ast => match parser::parse_chunk(source, name) {
+                Ok(block) => println!("{:#?}", block),
+                Err(err) => syntax_error(err),
+            },
+            Dump::Bytecode => match lua.disassemble(source, name) {
+                Ok(listing) => print!("{}", listing),
+                Err(err) => {
+                    eprintln!("looa: {}", err);
+                    process::exit(1);
+                }
+            },
+        }
+    }
+    process::exit(0)
+}
+
+/// Set the global `arg` to a table with the script's name at 0, the
+/// arguments after it from 1, and those before it below 0
+pub fn set_args(lua: &mut Lua, args: &[String], script: Option<usize>) -> Result<()> {
+    let script = script.unwrap_or(0);
+    let table = Value::new(Table::new());
+    for (i, arg) in args.iter().enumerate() {
+        let index = Value::new(i as LuaInteger - script as LuaInteger);
+        table.raw_set(index, Value::string(arg))?;
+    }
+    lua.set_global("arg", table)
+}
+
+/// Load the library `name` with `require`, which looks for it on the
+/// module path `looa.toml` gives, into the global `global`
+fn load_library(lua: &mut Lua, global: &str, name: &str) -> Result<()> {
+    let module = lua
+        .get_global("require")?
+        .call(vec![Value::string(name)])?
+        .into_first();
+    lua.set_global(global, module)
+}
+
+/// Report an error that stopped `script`, its name and source if it was
+/// one, in color if `color` is set, and exit
+fn fail(lua: &Lua, err: &Error, script: Option<(&str, &[u8])>, color: bool) -> ! {
+    eprint!("{}", report(lua, err, script, color));
+    process::exit(1)
+}
+
+/// An error that stopped `script`, written out as [`fail`] reports it
+pub fn report(lua: &Lua, err: &Error, script: Option<(&str, &[u8])>, color: bool) -> String {
+    // the error loading a chunk only has the message, and parsing again
+    // finds where it was
+    if let (&Error::Syntax(_), Some((name, source))) = (err, script) {
+        if let Err(err) = parser::parse_chunk(source, name) {
+            return diagnostic::syntax_error(&err, source, "looa: ", color);
+        }
+    }
+    // syntax errors, and those raised by the options, have no traceback
+    let traceback = match *err {
+        Error::Syntax(_) => String::new(),
+        _ => lua.traceback(),
+    };
+    diagnostic::runtime_error(lua, err, script, &traceback, "looa: ", color)
+}
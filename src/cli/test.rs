@@ -0,0 +1,147 @@
+//! `looa test`, which runs the Lua test suites in a directory, in the
+//! style of busted, and reports how they went
+
+use std::path::{Path, PathBuf};
+use std::process;
+
+use looa::testing::{self, FileResult, Outcome, Runner};
+use looa::Backend;
+
+const USAGE: &str = "\
+usage: looa test [options] [dir|file...]
+Available options are:
+  -j n          run at most n files at once (default: one per processor)
+  --vm          run with the bytecode VM rather than the interpreter
+  --filter text only run the tests whose names contain 'text'
+  --tap         print a TAP report rather than a summary
+  --junit       print a JUnit XML report rather than a summary
+Test files are those named *_spec.lua or *_test.lua in 'dir', the current
+directory by default. They define tests with describe(name, f),
+it(name, f), pending(name), before_each(f) and after_each(f), and check
+them with assert.equal, same, near, truthy, falsy, is_nil, not_nil and
+has_error.";
+
+enum Format {
+    Summary,
+    Tap,
+    Junit,
+}
+
+/// Run the test files found from `args`, whose options start at `start`,
+/// exiting with 1 if any test failed
+pub fn main(args: &[String], start: usize) -> ! {
+    let mut runner = Runner::new();
+    let mut format = Format::Summary;
+    let mut paths = Vec::new();
+    let mut args = args[start..].iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-j" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(jobs) => runner.set_jobs(jobs),
+                None => usage("'-j' needs a number"),
+            },
+            "--vm" => runner.set_backend(Backend::Vm),
+            "--filter" => match args.next() {
+                Some(text) => runner.set_filter(Some(text.clone())),
+                None => usage("'--filter' needs argument"),
+            },
+            "--tap" => format = Format::Tap,
+            "--junit" => format = Format::Junit,
+            "--" => {
+                paths.extend(args);
+                break;
+            }
+            opt if opt.starts_with('-') => usage(&format!("unrecognized option '{}'", opt)),
+            _ => paths.push(arg),
+        }
+    }
+    let files = match find_files(&paths) {
+        Ok(files) => files,
+        Err(msg) => {
+            eprintln!("looa: {}", msg);
+            process::exit(1);
+        }
+    };
+    let results = runner.run(&files);
+    match format {
+        Format::Summary => summarize(&results),
+        Format::Tap => print!("{}", testing::tap(&results)),
+        Format::Junit => print!("{}", testing::junit(&results)),
+    }
+    let ok = results.iter().all(FileResult::ok);
+    process::exit(if ok { 0 } else { 1 })
+}
+
+/// The test files in the directories `paths` name, and the files they
+/// name, or those in the current directory if there are none
+pub fn find_files(paths: &[&String]) -> Result<Vec<PathBuf>, String> {
+    let dot = ".".to_string();
+    let paths = if paths.is_empty() {
+        vec![&dot]
+    } else {
+        paths.to_vec()
+    };
+    let mut files = Vec::new();
+    for path in paths {
+        let path = Path::new(path);
+        if path.is_dir() {
+            let found = testing::discover(path)
+                .map_err(|err| format!("cannot read {}: {}", path.display(), err))?;
+            // found in the current directory, they are named without `./`
+            files.extend(found.into_iter().map(|file| {
+                file.strip_prefix(".")
+                    .map_or(file.clone(), Path::to_path_buf)
+            }));
+        } else {
+            files.push(path.to_path_buf());
+        }
+    }
+    if files.is_empty() {
+        return Err("no test files found (named *_spec.lua or *_test.lua)".to_string());
+    }
+    Ok(files)
+}
+
+/// Print a line for each file, the failures, and the totals
+pub fn summarize(results: &[FileResult]) {
+    for file in results {
+        let status = if file.ok() { "ok" } else { "FAIL" };
+        println!(
+            "{:<4} {} ({} passed, {} failed, {} pending, {:.2}s)",
+            status,
+            file.path.display(),
+            file.passed(),
+            file.failed(),
+            file.pending(),
+            file.duration.as_secs_f64()
+        );
+    }
+    for file in results {
+        for test in &file.tests {
+            if let Outcome::Failed(ref msg) = test.outcome {
+                println!("\n{}: {}\n  {}", file.path.display(), test.name, msg);
+            }
+        }
+        if let Some(ref msg) = file.error {
+            println!("\n{}: stopped by an error\n  {}", file.path.display(), msg);
+        }
+    }
+    let sum = |count: fn(&FileResult) -> usize| results.iter().map(count).sum::<usize>();
+    let errors = results.iter().filter(|file| file.error.is_some()).count();
+    print!(
+        "\n{} passed, {} failed, {} pending",
+        sum(FileResult::passed),
+        sum(FileResult::failed),
+        sum(FileResult::pending)
+    );
+    if errors > 0 {
+        let files = if errors == 1 { "file" } else { "files" };
+        print!(", {} {} stopped by errors", errors, files);
+    }
+    println!();
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("looa: {}\n{}", msg, USAGE);
+    process::exit(1)
+}
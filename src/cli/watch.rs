@@ -0,0 +1,154 @@
+//! `looa watch`, which runs a script again each time it or a module it
+//! requires changes
+//!
+//! Each run is in a new state set up as `looa` sets one up, with the
+//! settings of `looa.toml`, and is followed by a line saying how long it
+//! took. The files watched are the script and the modules `looa deps`
+//! finds it requiring, found again before each run so that a new
+//! `require` is watched too. They are checked for a new modification time a few
+//! times a second.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use cli;
+use looa::bench::Time;
+use looa::config::Config;
+use looa::graph::Graph;
+use looa::{Backend, Lua, Value};
+
+const USAGE: &str = "\
+usage: looa watch [options] script [args]
+Available options are:
+  --vm          run with the bytecode VM rather than the interpreter
+  --interval ms how often to check the files for changes (default: 250)
+  --clear       clear the terminal before each run
+The script runs again in a new state whenever it, or a module it requires
+as 'looa deps' finds them, changes. Stop watching with Ctrl-C.";
+
+/// Run the script named in `args`, whose options start at `start`, each
+/// time the files it is made of change, until stopped
+pub fn main(args: &[String], start: usize) -> ! {
+    let mut vm = false;
+    let mut interval = Duration::from_millis(250);
+    let mut clear = false;
+    let mut i = start;
+    while let Some(arg) = args.get(i) {
+        match arg.as_str() {
+            "--vm" => vm = true,
+            "--interval" => {
+                i += 1;
+                match args.get(i).and_then(|ms| ms.parse().ok()) {
+                    Some(ms) => interval = Duration::from_millis(ms),
+                    None => usage("'--interval' needs a number"),
+                }
+            }
+            "--clear" => clear = true,
+            "--" => {
+                i += 1;
+                break;
+            }
+            opt if opt.starts_with('-') => usage(&format!("unrecognized option '{}'", opt)),
+            _ => break,
+        }
+        i += 1;
+    }
+    if i >= args.len() {
+        usage("no input file given");
+    }
+    let config = cli::config();
+    let color = cli::color(config.color.as_deref()).expect("checked when read");
+    let path = &args[i];
+    for run in 1.. {
+        if clear {
+            print!("\x1b[2J\x1b[H");
+        }
+        println!("--- looa watch: {} (run {}) ---", path, run);
+        // the times are taken first so a change while it runs is seen
+        let files = watched(&config, Path::new(path));
+        let before = modified(&files);
+        let start = Instant::now();
+        let ok = run_once(&config, vm, args, i, color);
+        println!(
+            "--- {} {} in {}; watching {} {} ---",
+            path,
+            if ok { "finished" } else { "failed" },
+            Time(start.elapsed()),
+            files.len(),
+            if files.len() == 1 { "file" } else { "files" }
+        );
+        while modified(&files) == before {
+            thread::sleep(interval);
+        }
+    }
+    unreachable!("runs until stopped")
+}
+
+/// Run the script at `args[script]`, with the arguments after it, giving
+/// whether it ran without an error
+fn run_once(config: &Config, vm: bool, args: &[String], script: usize, color: bool) -> bool {
+    let mut lua = Lua::new();
+    let path = &args[script];
+    let result = config
+        .apply(&mut lua)
+        .and_then(|()| {
+            if vm {
+                lua.set_backend(Backend::Vm);
+            }
+            cli::run::set_args(&mut lua, args, Some(script))
+        })
+        .map_err(|err| (err, None));
+    let source = match cli::read_script(path) {
+        Ok(source) => source,
+        Err(err) => {
+            eprintln!("looa: cannot open {}: {}", path, err);
+            return false;
+        }
+    };
+    let result = result.and_then(|()| {
+        let script_args = args[script + 1..].iter().map(Value::string).collect();
+        lua.load(&source, path)
+            .and_then(|main| main.call(script_args))
+            .map(|_| ())
+            .map_err(|err| (err, Some((path.as_str(), &*source))))
+    });
+    match result {
+        Ok(()) => true,
+        Err((err, script)) => {
+            eprint!("{}", cli::run::report(&lua, &err, script, color));
+            false
+        }
+    }
+}
+
+/// The script at `path` and the files of the modules it requires
+fn watched(config: &Config, path: &Path) -> Vec<PathBuf> {
+    // the script is the first module, even if it can't be read
+    let mut graph = Graph::new(config.module_path());
+    graph.add(path);
+    graph
+        .modules()
+        .iter()
+        .map(|module| module.path.clone())
+        .collect()
+}
+
+/// When each of `files` was last modified, or `None` for those that can't
+/// be read
+fn modified(files: &[PathBuf]) -> BTreeMap<&Path, Option<SystemTime>> {
+    files
+        .iter()
+        .map(|file| {
+            let time = file.metadata().and_then(|meta| meta.modified()).ok();
+            (file.as_path(), time)
+        })
+        .collect()
+}
+
+fn usage(msg: &str) -> ! {
+    eprintln!("looa: {}\n{}", msg, USAGE);
+    process::exit(1)
+}
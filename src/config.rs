@@ -0,0 +1,487 @@
+//! Settings for the `looa` binary kept in `looa.toml` files, so that a
+//! project's scripts run the same way for everyone working on it
+//!
+//! The user's file, `$XDG_CONFIG_HOME/looa/looa.toml` or
+//! `~/.config/looa/looa.toml`, is read first, and then the project's,
+//! the nearest `looa.toml` in the current directory or one above it, whose
+//! settings win. Options given on the command line win over both.
+//! `LOOA_CONFIG` names the only file to read instead, or if it is empty,
+//! turns the files off.
+//!
+//! ```toml
+//! [run]
+//! vm = true             # as --vm
+//! optimize = false      # turn the VM's peephole optimizer off
+//! color = "never"       # as --color
+//!
+//! [sandbox]
+//! instruction-limit = 100_000_000
+//! memory-limit = 268_435_456
+//! call-limit = 200
+//! remove = ["os.exit", "collectgarbage"]
+//!
+//! [modules]
+//! path = "./?.lua;./lib/?.lua;./lib/?/init.lua"
+//!
+//! [repl]
+//! pretty = true
+//! depth = 2
+//! ```
+//!
+//! The files are read as a part of TOML: tables, and keys given strings,
+//! integers, booleans or arrays of strings, with comments. Anything else,
+//! and any key not listed here, is an error, so a mistyped setting isn't
+//! silently ignored.
+
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use error::Result as LuaResult;
+use graph;
+use lua::{Backend, Lua};
+use value::{Type, Value};
+
+/// The name of a configuration file
+pub const FILE_NAME: &str = "looa.toml";
+
+/// What is wrong with a configuration file, and where
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConfigError {
+    /// the file, or how it was named
+    pub file: String,
+    /// the line, counting from 1, or 0 if the file couldn't be read
+    pub line: usize,
+    pub message: String,
+}
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            0 => write!(f, "{}: {}", self.file, self.message),
+            line => write!(f, "{}:{}: {}", self.file, line, self.message),
+        }
+    }
+}
+
+/// The settings of one or more configuration files, each `None` if none
+/// of them gives it
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Config {
+    /// `[run] vm`, run with the bytecode VM
+    pub vm: Option<bool>,
+    /// `[run] optimize`, optimize the VM's bytecode
+    pub optimize: Option<bool>,
+    /// `[run] color`, when to color errors: `always`, `never` or `auto`
+    pub color: Option<String>,
+    /// `[sandbox] instruction-limit`
+    pub instruction_limit: Option<u64>,
+    /// `[sandbox] memory-limit`, in bytes
+    pub memory_limit: Option<usize>,
+    /// `[sandbox] call-limit`
+    pub call_limit: Option<usize>,
+    /// `[sandbox] remove`, the globals to remove, or fields of them such
+    /// as `os.exit`
+    pub remove: Option<Vec<String>>,
+    /// `[modules] path`, which `package.path` is set to, where `require`
+    /// and `-l` look for modules
+    pub path: Option<String>,
+    /// `[repl] pretty`, print tables at the prompt with their contents
+    pub pretty: Option<bool>,
+    /// `[repl] depth`, how many tables deep the prompt prints
+    pub depth: Option<usize>,
+}
+
+/// A value in a configuration file
+#[derive(Clone, Debug, PartialEq)]
+enum Item {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+    Array(Vec<Item>),
+}
+impl Item {
+    fn kind(&self) -> &'static str {
+        match *self {
+            Item::String(_) => "a string",
+            Item::Integer(_) => "an integer",
+            Item::Boolean(_) => "a boolean",
+            Item::Array(_) => "an array",
+        }
+    }
+}
+
+impl Config {
+    /// The settings in `text`, the file named `file`
+    pub fn parse(text: &str, file: &str) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        let mut section = String::new();
+        let mut seen: Vec<String> = Vec::new();
+        let mut lines = text.lines().enumerate();
+        while let Some((i, line)) = lines.next() {
+            let error = |message: String| ConfigError {
+                file: file.to_string(),
+                line: i + 1,
+                message,
+            };
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line.starts_with('[') {
+                let name = line
+                    .strip_prefix('[')
+                    .and_then(|rest| rest.strip_suffix(']'))
+                    .map(str::trim)
+                    .filter(|name| is_key(name))
+                    .ok_or_else(|| error(format!("bad table header '{}'", line)))?;
+                if !["run", "sandbox", "modules", "repl"].contains(&name) {
+                    return Err(error(format!("unknown table [{}]", name)));
+                }
+                section = name.to_string();
+                continue;
+            }
+            let eq = line
+                .find('=')
+                .ok_or_else(|| error(format!("'=' expected after '{}'", line)))?;
+            let key = line[..eq].trim();
+            if !is_key(key) {
+                return Err(error(format!("bad key '{}'", key)));
+            }
+            // an array can go on over the lines up to its ']'
+            let mut value = line[eq + 1..].trim().to_string();
+            while value.starts_with('[') && !closed(&value) {
+                match lines.next() {
+                    Some((_, more)) => {
+                        value.push(' ');
+                        value.push_str(strip_comment(more).trim());
+                    }
+                    None => return Err(error("unfinished array".to_string())),
+                }
+            }
+            let item = parse_item(&value).map_err(&error)?;
+            let name = match section.as_str() {
+                "" => key.to_string(),
+                section => format!("{}.{}", section, key),
+            };
+            if seen.contains(&name) {
+                return Err(error(format!("'{}' is given twice", name)));
+            }
+            config.set(&name, item).map_err(&error)?;
+            seen.push(name);
+        }
+        Ok(config)
+    }
+
+    /// Set the setting `name`, its table and key joined by `.`, to `item`
+    fn set(&mut self, name: &str, item: Item) -> Result<(), String> {
+        let wrong = |item: &Item, wanted: &str| {
+            Err(format!("'{}' needs {}, not {}", name, wanted, item.kind()))
+        };
+        match (name, item) {
+            ("run.vm", Item::Boolean(b)) => self.vm = Some(b),
+            ("run.optimize", Item::Boolean(b)) => self.optimize = Some(b),
+            ("run.color", Item::String(s)) => {
+                if !["always", "never", "auto"].contains(&&*s) {
+                    return Err(format!(
+                        "'run.color' needs 'always', 'never' or 'auto', not '{}'",
+                        s
+                    ));
+                }
+                self.color = Some(s);
+            }
+            ("sandbox.instruction-limit", Item::Integer(n)) => {
+                self.instruction_limit = Some(count(name, n)? as u64)
+            }
+            ("sandbox.memory-limit", Item::Integer(n)) => self.memory_limit = Some(count(name, n)?),
+            ("sandbox.call-limit", Item::Integer(n)) => self.call_limit = Some(count(name, n)?),
+            ("sandbox.remove", Item::Array(items)) => {
+                let mut names = Vec::new();
+                for item in items {
+                    match item {
+                        Item::String(s) => names.push(s),
+                        item => return wrong(&item, "an array of strings"),
+                    }
+                }
+                self.remove = Some(names);
+            }
+            ("modules.path", Item::String(s)) => self.path = Some(s),
+            ("repl.pretty", Item::Boolean(b)) => self.pretty = Some(b),
+            ("repl.depth", Item::Integer(n)) => self.depth = Some(count(name, n)?),
+            ("run.vm", ref item) | ("run.optimize", ref item) | ("repl.pretty", ref item) => {
+                return wrong(item, "a boolean")
+            }
+            ("run.color", ref item) | ("modules.path", ref item) => return wrong(item, "a string"),
+            ("sandbox.instruction-limit", ref item)
+            | ("sandbox.memory-limit", ref item)
+            | ("sandbox.call-limit", ref item)
+            | ("repl.depth", ref item) => return wrong(item, "an integer"),
+            ("sandbox.remove", ref item) => return wrong(item, "an array of strings"),
+            _ => return Err(format!("unknown setting '{}'", name)),
+        }
+        Ok(())
+    }
+
+    /// The settings of the file at `path`
+    pub fn read(path: &Path) -> Result<Config, ConfigError> {
+        let file = path.display().to_string();
+        let text = fs::read_to_string(path).map_err(|err| ConfigError {
+            file: file.clone(),
+            line: 0,
+            message: format!("cannot read: {}", err),
+        })?;
+        Config::parse(&text, &file)
+    }
+
+    /// The settings of the files `looa` reads, as the module documentation
+    /// describes, from within the directory `dir`
+    pub fn load(dir: &Path) -> Result<Config, ConfigError> {
+        let mut config = Config::default();
+        for path in files(dir) {
+            config = config.merge(Config::read(&path)?);
+        }
+        Ok(config)
+    }
+
+    /// These settings with those `over` gives instead
+    pub fn merge(self, over: Config) -> Config {
+        Config {
+            vm: over.vm.or(self.vm),
+            optimize: over.optimize.or(self.optimize),
+            color: over.color.or(self.color),
+            instruction_limit: over.instruction_limit.or(self.instruction_limit),
+            memory_limit: over.memory_limit.or(self.memory_limit),
+            call_limit: over.call_limit.or(self.call_limit),
+            remove: over.remove.or(self.remove),
+            path: over.path.or(self.path),
+            pretty: over.pretty.or(self.pretty),
+            depth: over.depth.or(self.depth),
+        }
+    }
+
+    /// Where modules are looked for, [`graph::DEFAULT_PATH`] unless set
+    pub fn module_path(&self) -> &str {
+        self.path.as_deref().unwrap_or(graph::DEFAULT_PATH)
+    }
+
+    /// Set up `lua` as these settings say: its backend, its limits, and
+    /// the globals removed from it
+    pub fn apply(&self, lua: &mut Lua) -> LuaResult<()> {
+        if let Some(vm) = self.vm {
+            lua.set_backend(if vm {
+                Backend::Vm
+            } else {
+                Backend::Interpreter
+            });
+        }
+        if let Some(optimize) = self.optimize {
+            lua.set_optimize(optimize);
+        }
+        if let Some(limit) = self.instruction_limit {
+            lua.set_instruction_limit(Some(limit));
+        }
+        if let Some(limit) = self.memory_limit {
+            lua.set_memory_limit(Some(limit));
+        }
+        if let Some(limit) = self.call_limit {
+            lua.set_call_limit(limit);
+        }
+        if let Some(ref path) = self.path {
+            let package = lua.get_global("package")?;
+            if package.type_of() == Type::Table {
+                package.raw_set(Value::string("path"), Value::string(path))?;
+            }
+        }
+        for name in self.remove.iter().flatten() {
+            remove(lua, name)?;
+        }
+        Ok(())
+    }
+}
+
+/// The files [`Config::load`] reads from within `dir`, in order
+pub fn files(dir: &Path) -> Vec<PathBuf> {
+    if let Some(path) = env::var_os("LOOA_CONFIG") {
+        return match path.is_empty() {
+            true => Vec::new(),
+            false => vec![PathBuf::from(path)],
+        };
+    }
+    let user = match env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => Some(PathBuf::from(dir)),
+        None => env::var_os("HOME").map(|home| Path::new(&home).join(".config")),
+    };
+    let mut files: Vec<PathBuf> = user
+        .map(|dir| dir.join("looa").join(FILE_NAME))
+        .filter(|path| path.is_file())
+        .into_iter()
+        .collect();
+    let project = dir
+        .ancestors()
+        .map(|dir| dir.join(FILE_NAME))
+        .find(|path| path.is_file());
+    if let Some(project) = project {
+        if !files.contains(&project) {
+            files.push(project);
+        }
+    }
+    files
+}
+
+/// Remove the global `name` from `lua`, or the field of one given as in
+/// `os.exit`, if it is there
+fn remove(lua: &mut Lua, name: &str) -> LuaResult<()> {
+    let mut parts: Vec<&str> = name.split('.').collect();
+    let last = parts.pop().expect("split gives a part");
+    let mut table = lua.globals().clone();
+    for part in parts {
+        table = table.raw_get(&Value::string(part));
+        if table.type_of() != Type::Table {
+            return Ok(());
+        }
+    }
+    table.raw_set(Value::string(last), Value::nil())
+}
+
+/// `n` as a count, which can't be negative
+fn count(name: &str, n: i64) -> Result<usize, String> {
+    if n < 0 {
+        return Err(format!("'{}' can't be negative", name));
+    }
+    Ok(n as usize)
+}
+
+fn is_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .bytes()
+            .all(|c| c.is_ascii_alphanumeric() || c == b'_' || c == b'-')
+}
+
+/// `line` up to a `#` that isn't in a string
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+        escaped = false;
+    }
+    line
+}
+
+/// Whether the array at the start of `text` has its closing `]`
+fn closed(text: &str) -> bool {
+    let mut depth = 0;
+    let mut quote = None;
+    let mut escaped = false;
+    for c in text.chars() {
+        match (quote, c) {
+            (Some('"'), '\\') if !escaped => {
+                escaped = true;
+                continue;
+            }
+            (Some(q), c) if c == q && !escaped => quote = None,
+            (Some(_), _) => {}
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '[') => depth += 1,
+            (None, ']') => {
+                depth -= 1;
+                if depth == 0 {
+                    return true;
+                }
+            }
+            _ => {}
+        }
+        escaped = false;
+    }
+    false
+}
+
+/// The value written as `text`
+fn parse_item(text: &str) -> Result<Item, String> {
+    let (item, rest) = item(text)?;
+    match rest.trim() {
+        "" => Ok(item),
+        rest => Err(format!("unexpected '{}' after value", rest)),
+    }
+}
+
+/// The value at the start of `text`, and the text after it
+fn item(text: &str) -> Result<(Item, &str), String> {
+    let text = text.trim_start();
+    let bad = || format!("bad value '{}'", text);
+    match text.chars().next() {
+        Some('"') => {
+            let mut out = String::new();
+            let mut chars = text.char_indices().skip(1);
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '"' => return Ok((Item::String(out), &text[i + 1..])),
+                    '\\' => match chars.next().map(|(_, c)| c) {
+                        Some('"') => out.push('"'),
+                        Some('\\') => out.push('\\'),
+                        Some('n') => out.push('\n'),
+                        Some('t') => out.push('\t'),
+                        Some('r') => out.push('\r'),
+                        Some(c) => return Err(format!("unknown escape '\\{}'", c)),
+                        None => break,
+                    },
+                    c => out.push(c),
+                }
+            }
+            Err("unfinished string".to_string())
+        }
+        Some('\'') => match text[1..].find('\'') {
+            Some(end) => Ok((Item::String(text[1..end + 1].to_string()), &text[end + 2..])),
+            None => Err("unfinished string".to_string()),
+        },
+        Some('[') => {
+            let mut items = Vec::new();
+            let mut rest = text[1..].trim_start();
+            loop {
+                if let Some(after) = rest.strip_prefix(']') {
+                    return Ok((Item::Array(items), after));
+                }
+                let (item, after) = item(rest)?;
+                items.push(item);
+                rest = after.trim_start();
+                match rest.strip_prefix(',') {
+                    Some(after) => rest = after.trim_start(),
+                    None if rest.starts_with(']') => {}
+                    None => return Err("',' or ']' expected in array".to_string()),
+                }
+            }
+        }
+        Some(_) => {
+            let end = text
+                .find(|c: char| c == ',' || c == ']' || c.is_whitespace())
+                .unwrap_or(text.len());
+            let word = &text[..end];
+            let item = match word {
+                "true" => Item::Boolean(true),
+                "false" => Item::Boolean(false),
+                _ => {
+                    let digits = word.strip_prefix('+').unwrap_or(word);
+                    let no_underscores = !digits.starts_with('_')
+                        && !digits.ends_with('_')
+                        && !digits.contains("__");
+                    match digits.replace('_', "").parse() {
+                        Ok(n) if no_underscores => Item::Integer(n),
+                        _ => return Err(bad()),
+                    }
+                }
+            };
+            Ok((item, &text[end..]))
+        }
+        None => Err("value expected after '='".to_string()),
+    }
+}
@@ -0,0 +1,169 @@
+//! Counting which lines of Lua source run, such as while tests run, and
+//! writing that out as an LCOV report
+//!
+//! A line hook counts the lines each chunk runs, by the chunk's name. A
+//! report then covers the chunks named after files that can be read, with
+//! the lines statements start on as the lines that could have run, as
+//! well as any others that did, such as the lines of an `elseif` or a
+//! table constructor that the VM runs instructions of. Lines run by chunks
+//! of other names, such as those given to `load`, are left out.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use ast::{Ast, Block, StatKind};
+use error::ParseError;
+use hook::{HookEvent, HookMask};
+use lua::Lua;
+use parser;
+use testing::Setup;
+
+/// How many times each line of each chunk ran, by the chunk's name
+type Hits = HashMap<String, BTreeMap<u32, u64>>;
+
+/// Counts the lines run in the states it hooks, from any thread
+#[derive(Clone, Default)]
+pub struct Coverage {
+    hits: Arc<Mutex<Hits>>,
+}
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage::default()
+    }
+
+    /// Count the lines that the functions `lua` loaded run from now on,
+    /// which replaces any hook set on it
+    pub fn hook(&self, lua: &mut Lua) {
+        let hits = self.hits.clone();
+        let mask = HookMask {
+            line: true,
+            ..HookMask::default()
+        };
+        lua.set_hook(mask, move |info| {
+            if let HookEvent::Line(line) = info.event() {
+                let mut hits = hits.lock().expect("no hook panics holding it");
+                let lines = match hits.get_mut(info.chunk()) {
+                    Some(lines) => lines,
+                    None => hits.entry(info.chunk().to_string()).or_default(),
+                };
+                *lines.entry(line).or_insert(0) += 1;
+            }
+            Ok(())
+        });
+    }
+    /// A setup for a test [`Runner`](::testing::Runner) that counts the
+    /// lines run in each state
+    pub fn setup(&self) -> Setup {
+        let coverage = self.clone();
+        Arc::new(move |lua: &mut Lua| coverage.hook(lua))
+    }
+
+    /// The lines of each file run so far, in order of their paths
+    pub fn report(&self) -> Vec<FileCoverage> {
+        let hits = self.hits.lock().expect("no hook panics holding it").clone();
+        let mut files: Vec<FileCoverage> = hits
+            .into_iter()
+            .filter_map(|(chunk, hits)| {
+                let source = fs::read(Path::new(&chunk)).ok()?;
+                // a file that no longer parses has only the lines that ran
+                let mut lines: BTreeMap<u32, u64> = executable_lines(&source, &chunk)
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|line| (line, 0))
+                    .collect();
+                lines.extend(hits);
+                Some(FileCoverage { path: chunk, lines })
+            })
+            .collect();
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        files
+    }
+}
+
+/// Which lines of a file ran
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileCoverage {
+    pub path: String,
+    /// the lines that could have run, with how many times each did
+    pub lines: BTreeMap<u32, u64>,
+}
+impl FileCoverage {
+    /// How many of its lines could have run
+    pub fn found(&self) -> usize {
+        self.lines.len()
+    }
+    /// How many of its lines ran
+    pub fn hit(&self) -> usize {
+        self.lines.values().filter(|&&count| count > 0).count()
+    }
+    /// The lines that could have run but didn't
+    pub fn missed(&self) -> Vec<u32> {
+        self.lines
+            .iter()
+            .filter(|&(_, &count)| count == 0)
+            .map(|(&line, _)| line)
+            .collect()
+    }
+}
+
+/// The lines statements of `src`, the chunk `chunk`, start on, which are
+/// the lines a line hook is called for in both the interpreter and the VM
+pub fn executable_lines(src: &[u8], chunk: &str) -> Result<BTreeSet<u32>, ParseError> {
+    let ast = parser::parse_chunk(src, chunk)?;
+    let mut lines = BTreeSet::new();
+    for func in ast.functions() {
+        block_lines(&ast, &func.body, &mut lines);
+    }
+    Ok(lines)
+}
+
+fn block_lines(ast: &Ast, block: &Block, lines: &mut BTreeSet<u32>) {
+    for stat in &ast[block.stats] {
+        // labels don't run
+        if let StatKind::Label(_) = stat.kind {
+            continue;
+        }
+        lines.insert(stat.loc.pos.line);
+        match stat.kind {
+            StatKind::Do(ref body)
+            | StatKind::While(_, ref body)
+            | StatKind::Repeat(ref body, _)
+            | StatKind::NumericFor { ref body, .. }
+            | StatKind::GenericFor { ref body, .. } => block_lines(ast, body, lines),
+            StatKind::If(branches, ref otherwise) => {
+                for (_, body) in &ast[branches] {
+                    block_lines(ast, body, lines);
+                }
+                if let Some(ref body) = *otherwise {
+                    block_lines(ast, body, lines);
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(values) = block.ret {
+        match ast[values].first() {
+            Some(first) => lines.insert(first.loc.pos.line),
+            None => lines.insert(block.loc.pos.line),
+        };
+    }
+}
+
+/// `files` as an LCOV tracefile, which coverage tools such as `genhtml`
+/// read
+pub fn lcov(files: &[FileCoverage]) -> String {
+    let mut out = String::new();
+    for file in files {
+        out.push_str("TN:\n");
+        let _ = writeln!(out, "SF:{}", file.path);
+        for (line, count) in &file.lines {
+            let _ = writeln!(out, "DA:{},{}", line, count);
+        }
+        let _ = writeln!(out, "LF:{}\nLH:{}", file.found(), file.hit());
+        out.push_str("end_of_record\n");
+    }
+    out
+}
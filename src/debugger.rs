@@ -0,0 +1,245 @@
+//! A source-level debugger built on the hooks
+//!
+//! Attaching a debugger sets a line hook that pauses at breakpoints, and
+//! after a step, by calling a handler from inside the hook. The handler can
+//! look at the variables of every running Lua function, evaluate expressions
+//! where they are, and change the breakpoints, then says how to carry on.
+//! Each function's variables are those it had when it last started a line,
+//! so a caller is seen as it was at the line making the call.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use error::{Error, Result};
+use hook::{FrameInfo, HookEvent, HookInfo, HookMask};
+use interp;
+use limits;
+use lua::{chunk_name, Lua};
+use parser;
+use value::Value;
+
+/// How a paused script carries on
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Resume {
+    /// run until a breakpoint
+    Continue,
+    /// pause at the next line, in whichever function runs it
+    StepIn,
+    /// pause at the next line of this function or a caller
+    StepOver,
+    /// pause at the next line once this function returns
+    StepOut,
+}
+
+/// Why a script paused
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PauseReason {
+    Breakpoint,
+    /// a step finished, or the debugger was asked to pause
+    Step,
+}
+
+/// What the debugger is waiting for before it pauses again
+#[derive(Copy, Clone, Debug)]
+enum Mode {
+    Continue,
+    StepIn,
+    /// a line at this call depth or shallower
+    StepOver(usize),
+    /// a line shallower than this call depth
+    StepOut(usize),
+}
+
+struct State {
+    /// chunk names and lines
+    breakpoints: Vec<(String, u32)>,
+    mode: Mode,
+    /// the running functions, outermost first, with their call depths
+    frames: Vec<(usize, FrameInfo)>,
+}
+impl State {
+    fn should_pause(&self, chunk: &str, line: u32, depth: usize) -> Option<PauseReason> {
+        let breakpoint = self
+            .breakpoints
+            .iter()
+            .any(|(file, at)| *at == line && chunk_matches(file, chunk));
+        if breakpoint {
+            return Some(PauseReason::Breakpoint);
+        }
+        let step = match self.mode {
+            Mode::Continue => false,
+            Mode::StepIn => true,
+            Mode::StepOver(from) => depth <= from,
+            Mode::StepOut(from) => depth < from,
+        };
+        if step {
+            Some(PauseReason::Step)
+        } else {
+            None
+        }
+    }
+}
+
+/// Whether a breakpoint in `file` is in the chunk named `chunk`, which it
+/// is if the names are the same or `file` ends the path the chunk is named by
+fn chunk_matches(file: &str, chunk: &str) -> bool {
+    let chunk = chunk.trim_start_matches('@');
+    chunk == file
+        || chunk.len() > file.len()
+            && chunk.ends_with(file)
+            && chunk[..chunk.len() - file.len()].ends_with(['/', '\\'])
+}
+
+/// A debugger attached to the Lua code running on this thread, which can
+/// be cloned to change its breakpoints from anywhere
+#[derive(Clone)]
+pub struct Debugger {
+    state: Rc<RefCell<State>>,
+}
+impl Debugger {
+    /// Attach a debugger that calls `handler` whenever the script pauses,
+    /// carrying on as it says, or raising the error it returns where the
+    /// script paused
+    ///
+    /// This replaces any hook set on `lua`, and `Lua::remove_hook` detaches
+    /// it. Only the functions `lua` loaded pause. The handler runs with the hook off, so code it runs
+    /// doesn't pause.
+    pub fn attach<F>(lua: &mut Lua, handler: F) -> Debugger
+    where
+        F: FnMut(&Paused) -> Result<Resume> + 'static,
+    {
+        let debugger = Debugger {
+            state: Rc::new(RefCell::new(State {
+                breakpoints: Vec::new(),
+                mode: Mode::Continue,
+                frames: Vec::new(),
+            })),
+        };
+        let handler = RefCell::new(handler);
+        let attached = debugger.clone();
+        let mask = HookMask {
+            line: true,
+            ..HookMask::default()
+        };
+        lua.set_hook(mask, move |info| attached.line(info, &handler));
+        debugger
+    }
+    /// Pause at `line` of the chunk named `chunk`, or of any chunk named by
+    /// a path ending in it
+    pub fn set_breakpoint(&self, chunk: &str, line: u32) {
+        let mut state = self.state.borrow_mut();
+        if !state
+            .breakpoints
+            .iter()
+            .any(|(file, at)| file == chunk && *at == line)
+        {
+            state.breakpoints.push((chunk.to_string(), line));
+        }
+    }
+    /// Remove a breakpoint, giving whether it was set
+    pub fn clear_breakpoint(&self, chunk: &str, line: u32) -> bool {
+        let mut state = self.state.borrow_mut();
+        let before = state.breakpoints.len();
+        state
+            .breakpoints
+            .retain(|(file, at)| !(file == chunk && *at == line));
+        state.breakpoints.len() < before
+    }
+    /// The breakpoints set, in the order they were
+    pub fn breakpoints(&self) -> Vec<(String, u32)> {
+        self.state.borrow().breakpoints.clone()
+    }
+    /// Pause at the next line that runs, such as the first of a script
+    pub fn pause(&self) {
+        self.state.borrow_mut().mode = Mode::StepIn;
+    }
+    /// Follow a line starting, pausing there if it should
+    fn line<F>(&self, info: &HookInfo, handler: &RefCell<F>) -> Result<()>
+    where
+        F: FnMut(&Paused) -> Result<Resume>,
+    {
+        let line = match info.event() {
+            HookEvent::Line(line) => line,
+            _ => return Ok(()),
+        };
+        let depth = limits::depth();
+        let reason = {
+            let mut state = self.state.borrow_mut();
+            // functions at this depth or deeper have returned, or were left
+            // by an error
+            let gone = state
+                .frames
+                .iter()
+                .position(|&(at, _)| at >= depth)
+                .unwrap_or(state.frames.len());
+            state.frames.truncate(gone);
+            state.frames.push((depth, info.frame()));
+            match state.should_pause(info.chunk(), line, depth) {
+                Some(reason) => reason,
+                None => return Ok(()),
+            }
+        };
+        let paused = Paused {
+            debugger: self.clone(),
+            reason,
+            frames: self
+                .state
+                .borrow()
+                .frames
+                .iter()
+                .rev()
+                .map(|(_, frame)| frame.clone())
+                .collect(),
+        };
+        let resume = (handler.borrow_mut())(&paused)?;
+        self.state.borrow_mut().mode = match resume {
+            Resume::Continue => Mode::Continue,
+            Resume::StepIn => Mode::StepIn,
+            Resume::StepOver => Mode::StepOver(depth),
+            Resume::StepOut => Mode::StepOut(depth),
+        };
+        Ok(())
+    }
+}
+
+/// A script paused by a debugger, which its handler is given
+pub struct Paused {
+    debugger: Debugger,
+    reason: PauseReason,
+    /// innermost first
+    frames: Vec<FrameInfo>,
+}
+impl Paused {
+    pub fn reason(&self) -> PauseReason {
+        self.reason
+    }
+    /// The debugger that paused, to change its breakpoints
+    pub fn debugger(&self) -> &Debugger {
+        &self.debugger
+    }
+    /// The running Lua functions, starting with the paused one and ending
+    /// with the outermost, which leaves out Rust functions between them
+    pub fn frames(&self) -> &[FrameInfo] {
+        &self.frames
+    }
+    /// Evaluate the expression `source` where the function `level` frames
+    /// out from the paused one is, seeing its variables, and give its value,
+    /// which is the first for a call
+    ///
+    /// It runs in the interpreter, whichever backend runs the function.
+    pub fn eval(&self, level: usize, source: &str) -> Result<Value> {
+        let frame = match self.frames.get(level) {
+            Some(frame) => frame,
+            None => return Err(Error::Runtime(format!("no frame at level {}", level))),
+        };
+        let ast = parser::parse_expr(source.as_bytes(), &chunk_name(source))?;
+        let closure = interp::Closure::with_scope(
+            ast,
+            frame.env.clone(),
+            frame.scope(),
+            frame.limits.clone(),
+            frame.strings.clone(),
+        );
+        Ok(closure.call(Vec::new())?.into_first())
+    }
+}
@@ -0,0 +1,293 @@
+//! Error messages written out for people, with the source they are about,
+//! suggestions for the mistakes that commonly cause them, and optionally
+//! ANSI colors
+//!
+//! A syntax error is written as its message, then its line with the
+//! offending text underlined and labelled, then the line of the block it
+//! was meant to close if there is one, and then any suggestion:
+//!
+//! ```text
+//! looa: m.lua:1:6: 'then' expected near '='
+//! 1 | if x = 1 then end
+//!   |      ^ 'then' expected
+//! help: '=' assigns; compare with '=='
+//! ```
+//!
+//! A runtime error is written alike when it gives a line of the script,
+//! followed by the traceback, and suggests a global for one that is nil
+//! where one of a similar name isn't.
+
+use error::{Error, ParseError};
+use lua::Lua;
+use value::{ConvertValue, LuaString, Type, Value};
+
+const RESET: &str = "\x1b[0m";
+const BOLD: &str = "\x1b[1m";
+const RED: &str = "\x1b[1;31m";
+const BLUE: &str = "\x1b[34m";
+const CYAN: &str = "\x1b[1;36m";
+const DIM: &str = "\x1b[2m";
+
+/// Writes text in a color if colors are on, and as it is otherwise
+struct Paint(bool);
+impl Paint {
+    fn paint(&self, color: &str, text: &str) -> String {
+        match self.0 {
+            true if !text.is_empty() => format!("{}{}{}", color, text, RESET),
+            _ => text.to_string(),
+        }
+    }
+}
+
+/// `err`, an error parsing `src`, written out after `prefix` such as
+/// `looa: `, in colors if `color` is set, ending in a newline
+pub fn syntax_error(err: &ParseError, src: &[u8], prefix: &str, color: bool) -> String {
+    let paint = Paint(color);
+    let mut out = header(&paint, prefix, &err.to_string());
+    // the label is the message without what the header adds to it
+    let label = match err.message.find(" (") {
+        Some(at) => &err.message[..at],
+        None => &err.message,
+    };
+    let (line, indent, width) = err.marked_line(src);
+    let opener = opened_at(err).and_then(|(keyword, at)| {
+        let text = source_line(src, at)?;
+        let column = find_word(&text, keyword)?;
+        Some((at, text, column, keyword))
+    });
+    let gutter = opener
+        .iter()
+        .map(|o| o.0)
+        .chain(Some(err.pos.line))
+        .map(|line| line.to_string().len())
+        .max()
+        .unwrap_or(1);
+    if let Some((at, ref text, column, keyword)) = opener {
+        if at != err.pos.line {
+            let marks = format!("{}{}", " ".repeat(column), "-".repeat(keyword.len()));
+            let label = format!("{} this '{}'", marks, keyword);
+            out += &numbered(&paint, gutter, at, text);
+            out += &marked(&paint, gutter, BLUE, &label);
+        }
+    }
+    out += &numbered(&paint, gutter, err.pos.line, &line);
+    let carets = format!("{}{} {}", indent, "^".repeat(width), label);
+    out += &marked(&paint, gutter, RED, &carets);
+    if let Some(help) = suggestion(err, src) {
+        out += &format!("{} {}\n", paint.paint(CYAN, "help:"), help);
+    }
+    out
+}
+
+/// `err`, raised in `lua` running `script`, its name and source if it was
+/// one, written out after `prefix` with `traceback`, in colors if `color`
+/// is set, ending in a newline
+pub fn runtime_error(
+    lua: &Lua,
+    err: &Error,
+    script: Option<(&str, &[u8])>,
+    traceback: &str,
+    prefix: &str,
+    color: bool,
+) -> String {
+    let paint = Paint(color);
+    let msg = err.to_string();
+    let mut out = header(&paint, prefix, &msg);
+    let nil_global = nil_name(&msg, "global");
+    let located = script.and_then(|(chunk, source)| {
+        let rest = msg.strip_prefix(chunk)?.strip_prefix(':')?;
+        let line: u32 = rest[..rest.find(':')?].parse().ok()?;
+        Some((line, source_line(source, line)?))
+    });
+    if let Some((line, ref text)) = located {
+        let gutter = line.to_string().len();
+        out += &numbered(&paint, gutter, line, text);
+        if let Some(column) = nil_global.as_ref().and_then(|name| find_word(text, name)) {
+            let name = nil_global.as_ref().expect("found in the line");
+            let label = format!("{}{} is nil", " ".repeat(column), "^".repeat(name.len()));
+            out += &marked(&paint, gutter, RED, &label);
+        }
+    }
+    let similar = nil_global.and_then(|name| similar_global(lua, &name));
+    if let Some(name) = similar {
+        let help = format!("did you mean '{}'?", name);
+        out += &format!("{} {}\n", paint.paint(CYAN, "help:"), help);
+    }
+    if traceback.lines().count() > 1 {
+        for line in traceback.lines() {
+            out += &paint.paint(DIM, line);
+            out.push('\n');
+        }
+    }
+    out
+}
+
+/// The first line of an error, `prefix` and then `msg`
+fn header(paint: &Paint, prefix: &str, msg: &str) -> String {
+    format!("{}{}\n", paint.paint(RED, prefix), paint.paint(BOLD, msg))
+}
+
+/// A line of source after its number, in a gutter `width` wide
+fn numbered(paint: &Paint, width: usize, line: u32, text: &str) -> String {
+    let number = format!("{:>width$} |", line, width = width);
+    format!("{} {}\n", paint.paint(BLUE, &number), text)
+}
+
+/// Marks under a line of source, in `color` from the first of them
+fn marked(paint: &Paint, width: usize, color: &str, marks: &str) -> String {
+    let gutter = format!("{} |", " ".repeat(width));
+    let text = marks.trim_start();
+    let indent = &marks[..marks.len() - text.len()];
+    format!(
+        "{} {}{}\n",
+        paint.paint(BLUE, &gutter),
+        indent,
+        paint.paint(color, text)
+    )
+}
+
+/// Line `line` of `src`, counting from 1
+fn source_line(src: &[u8], line: u32) -> Option<String> {
+    let text = src
+        .split(|&c| c == b'\n')
+        .nth(line.checked_sub(1)? as usize)?;
+    let text = text.strip_suffix(b"\r").unwrap_or(text);
+    Some(String::from_utf8_lossy(text).into_owned())
+}
+
+/// Where `word` first is in `line` as a whole word, in characters
+fn find_word(line: &str, word: &str) -> Option<usize> {
+    let is_name = |c: char| c.is_alphanumeric() || c == '_';
+    let mut from = 0;
+    while let Some(at) = line[from..].find(word).map(|at| from + at) {
+        let end = at + word.len();
+        let before = line[..at].chars().next_back().is_none_or(|c| !is_name(c));
+        let after = line[end..].chars().next().is_none_or(|c| !is_name(c));
+        if before && after {
+            return Some(line[..at].chars().count());
+        }
+        from = end;
+    }
+    None
+}
+
+/// The keyword and line of the block an error says wasn't closed, from
+/// "... (to close 'function' at line 3)"
+fn opened_at(err: &ParseError) -> Option<(&str, u32)> {
+    let rest = &err.message[err.message.find("(to close '")? + "(to close '".len()..];
+    let keyword = &rest[..rest.find('\'')?];
+    let line = rest[rest.find(" at line ")? + " at line ".len()..].trim_end_matches(')');
+    Some((keyword, line.parse().ok()?))
+}
+
+/// What is likely to have been meant, for the mistakes that commonly cause
+/// `err`
+pub fn suggestion(err: &ParseError, src: &[u8]) -> Option<String> {
+    let found = err.found.as_deref();
+    let start = err.span.start.min(src.len());
+    let after = src.get(err.span.end).copied();
+    let before = String::from_utf8_lossy(&src[..start]);
+    let before = before.trim_end();
+    let line_before = before.rsplit('\n').next().unwrap_or("");
+    let msg = &*err.message;
+    let expecting = |keyword: &str| msg.starts_with(&format!("'{}' expected", keyword));
+    let help = if (expecting("then") || expecting("do")) && found == Some("=") {
+        "'=' assigns; compare with '=='".to_string()
+    } else if expecting("then") {
+        "a condition is followed by 'then'".to_string()
+    } else if expecting("do") {
+        "the loop's body starts with 'do'".to_string()
+    } else if found == Some("!") && after == Some(b'=') {
+        "Lua writes 'not equal' as '~='".to_string()
+    } else if found == Some("!") {
+        "Lua writes 'not' rather than '!'".to_string()
+    } else if matches!(found, Some("+" | "-" | "*" | "/")) && after == Some(b'=') {
+        let op = found.expect("matched");
+        format!("Lua has no '{0}='; write 'x = x {0} y'", op)
+    } else if expecting("end") && found.is_none() && has_words(before, "else", "if") {
+        "'else if' starts an 'if' needing an 'end' of its own; did you mean 'elseif'?".to_string()
+    } else if expecting("end") && has_word(line_before, "return") {
+        "'return' can only be the last statement of a block".to_string()
+    } else if expecting("}") {
+        "separate the fields of a table with ',' or ';'".to_string()
+    } else if found == Some(")") && before.ends_with(',') {
+        "remove the trailing ','".to_string()
+    } else if msg.starts_with("unfinished string") {
+        "strings end at the same quote they start with, on the same line; \
+         long strings are written [[...]]"
+            .to_string()
+    } else {
+        return None;
+    };
+    Some(help)
+}
+
+fn has_word(text: &str, word: &str) -> bool {
+    find_word(text, word).is_some()
+}
+
+/// Whether `text` has `first` followed by `second`, with only whitespace
+/// between them
+fn has_words(text: &str, first: &str, second: &str) -> bool {
+    let words: Vec<&str> = text.split_whitespace().collect();
+    words.windows(2).any(|pair| pair == [first, second])
+}
+
+/// The name in an error from using a nil value of the kind `kind`, such as
+/// `prnt` from "attempt to call a nil value (global 'prnt')"
+fn nil_name(msg: &str, kind: &str) -> Option<String> {
+    if !msg.contains("a nil value") {
+        return None;
+    }
+    let marker = format!("({} '", kind);
+    let rest = &msg[msg.find(&marker)? + marker.len()..];
+    Some(rest[..rest.find('\'')?].to_string())
+}
+
+/// The global in `lua` whose name is closest to `name`, if one is close
+/// enough to be a likely misspelling
+fn similar_global(lua: &Lua, name: &str) -> Option<String> {
+    let globals = lua.globals();
+    let mut key = Value::nil();
+    let mut best: Option<(usize, String)> = None;
+    let most = (name.chars().count() / 3).max(1);
+    while let Ok(Some((next, val))) = globals.raw_next(&key) {
+        key = next;
+        let candidate = match LuaString::from_value(&key) {
+            Some(bytes) if val.type_of() != Type::Nil => String::from_utf8_lossy(bytes),
+            _ => continue,
+        };
+        let distance = edit_distance(name, &candidate);
+        if distance == 0 || distance > most {
+            continue;
+        }
+        let better = best
+            .as_ref()
+            .is_none_or(|&(d, ref b)| (distance, &*candidate) < (d, &**b));
+        if better {
+            best = Some((distance, candidate.into_owned()));
+        }
+    }
+    best.map(|(_, name)| name)
+}
+
+/// How many characters must be inserted, removed or changed to turn `a`
+/// into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, &cb) in b.iter().enumerate() {
+            let above = row[j + 1];
+            row[j + 1] = if ca == cb {
+                diagonal
+            } else {
+                1 + diagonal.min(above).min(row[j])
+            };
+            diagonal = above;
+        }
+    }
+    row[b.len()]
+}
@@ -0,0 +1,663 @@
+//! API documentation from the doc comments of a module, in the style of
+//! LDoc, rendered as Markdown or HTML
+//!
+//! A doc comment is a run of line comments starting with `---`, or a
+//! block comment starting with `--[[--`, right before a function or a
+//! table field holding one. Its first sentence is the summary, and the
+//! rest up to the first tag is the description. Tags start lines:
+//!
+//! - `@param name text` and `@tparam type name text` describe a parameter
+//! - `@return text` and `@treturn type text` describe a result, once for
+//!   each in order
+//! - `@usage` starts example code, which runs to the next tag
+//! - `@see name` refers to another function
+//! - `@module name` names the module, in the first doc comment
+//!
+//! The parameters themselves come from the function's definition, so the
+//! documentation can't list other parameters than it has. A doc comment
+//! before anything but a function, if it is the first, documents the
+//! module.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::Path;
+
+use ast::{Ast, Expr, ExprKind, FieldKind, FuncId, StatKind};
+use error::ParseError;
+use lexer::{Lexer, TokenKind};
+use parser;
+
+/// The documentation of a module
+#[derive(Clone, Debug, Default)]
+pub struct ModuleDoc {
+    pub name: String,
+    pub summary: String,
+    pub description: String,
+    /// the documented functions, in the order they are defined
+    pub functions: Vec<FunctionDoc>,
+    /// tags that were misused, like `chunk:3: unknown tag '@foo'`
+    pub warnings: Vec<String>,
+}
+
+/// The documentation of a function
+#[derive(Clone, Debug, Default)]
+pub struct FunctionDoc {
+    /// the name it is defined by, like `M.f`, `M:method` or `f`
+    pub name: String,
+    pub line: u32,
+    /// whether it is a local, which isn't part of the module's interface
+    pub local: bool,
+    pub params: Vec<Param>,
+    /// whether it takes `...` after its parameters
+    pub vararg: bool,
+    pub returns: Vec<Return>,
+    pub summary: String,
+    pub description: String,
+    pub usage: Option<String>,
+    pub see: Vec<String>,
+}
+
+/// A parameter of a function, as defined, with what its doc comment says
+#[derive(Clone, Debug, Default)]
+pub struct Param {
+    pub name: String,
+    pub ty: Option<String>,
+    pub description: String,
+}
+
+/// A result of a function
+#[derive(Clone, Debug, Default)]
+pub struct Return {
+    pub ty: Option<String>,
+    pub description: String,
+}
+
+/// The documentation in `src`, whose chunk name `chunk` is a path that
+/// also names the module unless an `@module` tag does
+pub fn extract(src: &[u8], chunk: &str) -> Result<ModuleDoc, ParseError> {
+    let ast = parser::parse_chunk(src, chunk)?;
+    let comments = comments(src)?;
+    let mut extractor = Extractor {
+        ast: &ast,
+        chunk,
+        comments,
+        doc: ModuleDoc {
+            name: module_name(chunk),
+            ..ModuleDoc::default()
+        },
+    };
+    let main = &ast[ast.main()].body;
+    // the first doc comment is the module's unless it is a function's
+    let first = extractor.comments.keys().min().cloned();
+    for stat in &ast[main.stats] {
+        let start = stat.loc.span.start;
+        let line = stat.loc.pos.line;
+        match stat.kind {
+            StatKind::Function(ref name, func) => {
+                let mut path = ast[name.path].join(".");
+                if let Some(ref method) = name.method {
+                    path = format!("{}:{}", path, method);
+                }
+                extractor.function(start, path, func, false, line);
+            }
+            StatKind::LocalFunction(ref name, func) => {
+                extractor.function(start, name.to_string(), func, true, line)
+            }
+            StatKind::Assign(targets, values) => {
+                let targets = &ast[targets];
+                for (i, value) in ast[values].iter().enumerate() {
+                    if let Some(name) = targets.get(i).and_then(|target| path(&ast, target)) {
+                        extractor.value(start, name, value, false);
+                    }
+                }
+            }
+            StatKind::Local(names, values) => {
+                let names = &ast[names];
+                for (i, value) in ast[values].iter().enumerate() {
+                    if let Some(name) = names.get(i) {
+                        extractor.value(start, name.to_string(), value, true);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    let returned = main.ret.and_then(|values| ast[values].first());
+    // the fields of a returned table are named after the module, which the
+    // first doc comment can name, unless it is on the first field
+    let first_field = returned.and_then(|value| match value.kind {
+        ExprKind::Table(fields) => ast[fields].first().map(|field| field.loc.span.start),
+        _ => None,
+    });
+    if let Some(first) = first.filter(|&first| Some(first) != first_field) {
+        if let Some(comment) = extractor.comments.remove(&first) {
+            extractor.module(&comment);
+        }
+    }
+    if let Some(value) = returned {
+        let name = extractor
+            .doc
+            .name
+            .rsplit('.')
+            .next()
+            .unwrap_or("")
+            .to_string();
+        extractor.fields(name, value);
+    }
+    let mut doc = extractor.doc;
+    doc.functions.sort_by_key(|function| function.line);
+    Ok(doc)
+}
+
+/// The name of the module in the file at `path`, which is the file's name
+/// without `.lua`, or its directory's name for an `init.lua`
+pub fn module_name(path: &str) -> String {
+    let path = Path::new(path);
+    let stem = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .unwrap_or("");
+    match path.parent().and_then(|dir| dir.file_name()) {
+        Some(dir) if stem == "init" => dir.to_string_lossy().into_owned(),
+        _ => stem.to_string(),
+    }
+}
+
+/// A doc comment, with the comment markers taken off each line
+struct Comment {
+    lines: Vec<String>,
+    line: u32,
+}
+
+/// The doc comments of `src`, by the offset of the code that follows them
+fn comments(src: &[u8]) -> Result<HashMap<usize, Comment>, ParseError> {
+    let mut lexer = Lexer::new(src).with_comments(true);
+    let mut comments = HashMap::new();
+    let mut open: Option<(Comment, u32)> = None;
+    loop {
+        let token = lexer.next_token()?;
+        let text = String::from_utf8_lossy(&src[token.span.start..token.span.end]);
+        let text = text.trim_end();
+        if token.kind != TokenKind::Comment {
+            if let Some((comment, _)) = open.take() {
+                comments.insert(token.span.start, comment);
+            }
+            if token.kind == TokenKind::Eof {
+                return Ok(comments);
+            }
+            continue;
+        }
+        // a line comment right after a doc comment's lines continues it
+        if let Some((ref mut comment, ref mut last)) = open {
+            if text.starts_with("--") && !text.starts_with("--[") && token.pos.line == *last + 1 {
+                comment.lines.push(strip(&text[2..]));
+                *last = token.pos.line;
+                continue;
+            }
+        }
+        open = if text.starts_with("---") && !text[3..].starts_with('-') {
+            let lines = vec![strip(&text[3..])];
+            Some((
+                Comment {
+                    lines,
+                    line: token.pos.line,
+                },
+                token.pos.line,
+            ))
+        } else if text.starts_with("--[[--") {
+            let body = text.trim_start_matches("--[[--").trim_end_matches("]]");
+            let lines = body
+                .lines()
+                .map(|line| strip(line.trim_start_matches("--")))
+                .collect();
+            let end = token.pos.line + body.lines().count() as u32;
+            Some((
+                Comment {
+                    lines,
+                    line: token.pos.line,
+                },
+                end,
+            ))
+        } else {
+            None
+        };
+    }
+}
+
+/// A line of a doc comment without the one space after its marker
+fn strip(line: &str) -> String {
+    line.strip_prefix(' ').unwrap_or(line).to_string()
+}
+
+/// The dotted path `expr` is, like `a.b.c`, if it is a name indexed by
+/// field names
+fn path(ast: &Ast, expr: &Expr) -> Option<String> {
+    match expr.kind {
+        ExprKind::Name(ref name) => Some(name.to_string()),
+        ExprKind::Index(obj, key) => match ast[key].kind {
+            ExprKind::String(ref key) => Some(format!(
+                "{}.{}",
+                path(ast, &ast[obj])?,
+                String::from_utf8_lossy(key)
+            )),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// What a doc comment says, before it is matched with a definition
+#[derive(Default)]
+struct Text {
+    summary: String,
+    description: String,
+    params: Vec<Param>,
+    returns: Vec<Return>,
+    usage: Option<String>,
+    see: Vec<String>,
+    module: Option<String>,
+}
+
+/// A walk of a module's top-level definitions, taking their doc comments
+struct Extractor<'a> {
+    ast: &'a Ast,
+    chunk: &'a str,
+    comments: HashMap<usize, Comment>,
+    doc: ModuleDoc,
+}
+impl<'a> Extractor<'a> {
+    /// Document `value`, assigned to `name` in a statement starting at
+    /// `start`, if it is a function or a table of them
+    fn value(&mut self, start: usize, name: String, value: &Expr, local: bool) {
+        match value.kind {
+            ExprKind::Function(func) => self.function(start, name, func, local, value.loc.pos.line),
+            ExprKind::Table(_) => self.fields(name, value),
+            _ => {}
+        }
+    }
+    /// Document the functions in the fields of `table`, a table of `name`
+    fn fields(&mut self, name: String, table: &Expr) {
+        let ast = self.ast;
+        let fields = match table.kind {
+            ExprKind::Table(fields) => &ast[fields],
+            _ => return,
+        };
+        for field in fields {
+            if let FieldKind::Named(ref key, val) = field.kind {
+                let path = format!("{}.{}", name, key);
+                self.value(field.loc.span.start, path, &ast[val], false);
+            }
+        }
+    }
+    /// Document `func`, defined as `name` by the code at `start`, if a doc
+    /// comment is right before that
+    fn function(&mut self, start: usize, name: String, func: FuncId, local: bool, line: u32) {
+        let comment = match self.comments.remove(&start) {
+            Some(comment) => comment,
+            None => return,
+        };
+        let text = self.parse(&comment);
+        let body = &self.ast[func];
+        let skip = if name.contains(':') { 1 } else { 0 };
+        let mut params: Vec<Param> = self.ast[body.params][skip..]
+            .iter()
+            .map(|param| Param {
+                name: param.to_string(),
+                ..Param::default()
+            })
+            .collect();
+        for tagged in text.params {
+            match params.iter_mut().find(|param| param.name == tagged.name) {
+                Some(param) => *param = tagged,
+                None if tagged.name == "..." && body.vararg => {
+                    params.push(tagged);
+                }
+                None => {
+                    let msg = format!("'{}' is not a parameter of '{}'", tagged.name, name);
+                    self.warn(comment.line, msg);
+                }
+            }
+        }
+        if body.vararg && params.last().is_none_or(|param| param.name != "...") {
+            params.push(Param {
+                name: "...".to_string(),
+                ..Param::default()
+            });
+        }
+        self.doc.functions.push(FunctionDoc {
+            name,
+            line,
+            local,
+            params,
+            vararg: body.vararg,
+            returns: text.returns,
+            summary: text.summary,
+            description: text.description,
+            usage: text.usage,
+            see: text.see,
+        });
+    }
+    /// Take `comment` as the module's
+    fn module(&mut self, comment: &Comment) {
+        let text = self.parse(comment);
+        if let Some(name) = text.module {
+            self.doc.name = name;
+        }
+        self.doc.summary = text.summary;
+        self.doc.description = text.description;
+    }
+    fn parse(&mut self, comment: &Comment) -> Text {
+        let mut text = Text::default();
+        let mut prose = Vec::new();
+        // the tag each line went to, and the lines that continue it
+        let mut tags: Vec<(u32, String, Vec<String>)> = Vec::new();
+        for (i, line) in comment.lines.iter().enumerate() {
+            let line_no = comment.line + i as u32;
+            match line.trim_start().strip_prefix('@') {
+                Some(tag) => tags.push((line_no, tag.to_string(), Vec::new())),
+                None => match tags.last_mut() {
+                    Some(&mut (_, _, ref mut rest)) => rest.push(line.clone()),
+                    None => prose.push(line.trim()),
+                },
+            }
+        }
+        let prose = prose.join("\n");
+        let prose = prose.trim();
+        let end = prose
+            .find(". ")
+            .or_else(|| prose.find(".\n"))
+            .map(|at| at + 1)
+            .or_else(|| prose.find("\n\n"))
+            .unwrap_or(prose.len());
+        text.summary = prose[..end].replace('\n', " ");
+        text.description = prose[end..].trim().to_string();
+        for (line, tag, rest) in tags {
+            let (name, args) = match tag.find(char::is_whitespace) {
+                Some(at) => (&tag[..at], tag[at..].trim()),
+                None => (&*tag, ""),
+            };
+            let joined = || {
+                let mut args = args.to_string();
+                for line in &rest {
+                    args.push(' ');
+                    args.push_str(line.trim());
+                }
+                args.trim().to_string()
+            };
+            match name {
+                "param" | "tparam" => {
+                    let mut words = args.splitn(if name == "param" { 2 } else { 3 }, ' ');
+                    let ty = if name == "tparam" {
+                        words.next().map(str::to_string)
+                    } else {
+                        None
+                    };
+                    match words.next() {
+                        Some(param) if !param.is_empty() => {
+                            let mut description = words.next().unwrap_or("").to_string();
+                            for line in &rest {
+                                description.push(' ');
+                                description.push_str(line.trim());
+                            }
+                            text.params.push(Param {
+                                name: param.to_string(),
+                                ty,
+                                description: description.trim().to_string(),
+                            });
+                        }
+                        _ => self.warn(line, format!("'@{}' needs a parameter name", name)),
+                    }
+                }
+                "return" => text.returns.push(Return {
+                    ty: None,
+                    description: joined(),
+                }),
+                "treturn" => {
+                    let joined = joined();
+                    let (ty, description) = match joined.find(' ') {
+                        Some(at) => (&joined[..at], joined[at..].trim()),
+                        None => (&*joined, ""),
+                    };
+                    text.returns.push(Return {
+                        ty: Some(ty.to_string()),
+                        description: description.to_string(),
+                    });
+                }
+                "usage" => {
+                    let mut code: Vec<&str> = Vec::new();
+                    if !args.is_empty() {
+                        code.push(args);
+                    }
+                    code.extend(rest.iter().map(String::as_str));
+                    text.usage = Some(dedent(&code));
+                }
+                "see" => text.see.push(joined()),
+                "module" => text.module = Some(joined()),
+                _ => self.warn(line, format!("unknown tag '@{}'", name)),
+            }
+        }
+        text
+    }
+    fn warn(&mut self, line: u32, msg: String) {
+        let warning = format!("{}:{}: {}", self.chunk, line, msg);
+        self.doc.warnings.push(warning);
+    }
+}
+
+/// `lines` with the indentation they all have taken off
+fn dedent(lines: &[&str]) -> String {
+    let indent = lines
+        .iter()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start().len())
+        .min()
+        .unwrap_or(0);
+    let lines: Vec<&str> = lines
+        .iter()
+        .map(|line| line.get(indent..).unwrap_or("").trim_end())
+        .collect();
+    lines.join("\n").trim_matches('\n').to_string()
+}
+
+impl FunctionDoc {
+    /// How it is called, like `M.f(a, b, ...)`
+    pub fn signature(&self) -> String {
+        let params: Vec<&str> = self.params.iter().map(|param| &*param.name).collect();
+        format!("{}({})", self.name, params.join(", "))
+    }
+}
+
+impl ModuleDoc {
+    /// The documentation as a Markdown page
+    pub fn markdown(&self) -> String {
+        let mut out = format!("# Module `{}`\n\n", self.name);
+        for text in &[&self.summary, &self.description] {
+            if !text.is_empty() {
+                let _ = write!(out, "{}\n\n", text);
+            }
+        }
+        if self.functions.is_empty() {
+            return out;
+        }
+        out.push_str("## Functions\n\n");
+        for function in &self.functions {
+            let _ = writeln!(out, "- `{}`: {}", function.signature(), function.summary);
+        }
+        out.push('\n');
+        for function in &self.functions {
+            let _ = write!(out, "### `{}`\n\n", function.signature());
+            for text in &[&function.summary, &function.description] {
+                if !text.is_empty() {
+                    let _ = write!(out, "{}\n\n", text);
+                }
+            }
+            if !function.params.is_empty() {
+                out.push_str("Parameters:\n\n");
+                for param in &function.params {
+                    let _ = write!(out, "- `{}`", param.name);
+                    if let Some(ref ty) = param.ty {
+                        let _ = write!(out, " (`{}`)", ty);
+                    }
+                    if !param.description.is_empty() {
+                        let _ = write!(out, ": {}", param.description);
+                    }
+                    out.push('\n');
+                }
+                out.push('\n');
+            }
+            if !function.returns.is_empty() {
+                out.push_str("Returns:\n\n");
+                for (i, ret) in function.returns.iter().enumerate() {
+                    let _ = write!(out, "{}. ", i + 1);
+                    if let Some(ref ty) = ret.ty {
+                        let _ = write!(out, "(`{}`) ", ty);
+                    }
+                    let _ = writeln!(out, "{}", ret.description);
+                }
+                out.push('\n');
+            }
+            if let Some(ref usage) = function.usage {
+                let _ = write!(out, "Usage:\n\n```lua\n{}\n```\n\n", usage);
+            }
+            if !function.see.is_empty() {
+                let see: Vec<String> = function.see.iter().map(|s| format!("`{}`", s)).collect();
+                let _ = write!(out, "See: {}\n\n", see.join(", "));
+            }
+        }
+        out.truncate(out.trim_end().len() + 1);
+        out
+    }
+    /// The documentation as an HTML page, its functions linked from a list
+    /// at the top by their names
+    pub fn html(&self) -> String {
+        let title = escape(&self.name);
+        let mut out = format!(
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+             <title>{0}</title>\n</head>\n<body>\n<h1>Module <code>{0}</code></h1>\n",
+            title
+        );
+        for text in &[&self.summary, &self.description] {
+            if !text.is_empty() {
+                let _ = writeln!(out, "<p>{}</p>", escape(text));
+            }
+        }
+        if !self.functions.is_empty() {
+            out.push_str("<h2>Functions</h2>\n<ul>\n");
+            for function in &self.functions {
+                let _ = writeln!(
+                    out,
+                    "<li><a href=\"#{}\"><code>{}</code></a>: {}</li>",
+                    escape(&function.name),
+                    escape(&function.signature()),
+                    escape(&function.summary)
+                );
+            }
+            out.push_str("</ul>\n");
+        }
+        for function in &self.functions {
+            let _ = writeln!(
+                out,
+                "<h3 id=\"{}\"><code>{}</code></h3>",
+                escape(&function.name),
+                escape(&function.signature())
+            );
+            for text in &[&function.summary, &function.description] {
+                if !text.is_empty() {
+                    let _ = writeln!(out, "<p>{}</p>", escape(text));
+                }
+            }
+            if !function.params.is_empty() {
+                out.push_str("<h4>Parameters</h4>\n<ul>\n");
+                for param in &function.params {
+                    let _ = write!(out, "<li><code>{}</code>", escape(&param.name));
+                    if let Some(ref ty) = param.ty {
+                        let _ = write!(out, " (<code>{}</code>)", escape(ty));
+                    }
+                    if !param.description.is_empty() {
+                        let _ = write!(out, ": {}", escape(&param.description));
+                    }
+                    out.push_str("</li>\n");
+                }
+                out.push_str("</ul>\n");
+            }
+            if !function.returns.is_empty() {
+                out.push_str("<h4>Returns</h4>\n<ol>\n");
+                for ret in &function.returns {
+                    out.push_str("<li>");
+                    if let Some(ref ty) = ret.ty {
+                        let _ = write!(out, "(<code>{}</code>) ", escape(ty));
+                    }
+                    let _ = writeln!(out, "{}</li>", escape(&ret.description));
+                }
+                out.push_str("</ol>\n");
+            }
+            if let Some(ref usage) = function.usage {
+                let _ = writeln!(
+                    out,
+                    "<h4>Usage</h4>\n<pre><code>{}</code></pre>",
+                    escape(usage)
+                );
+            }
+            if !function.see.is_empty() {
+                let see: Vec<String> = function
+                    .see
+                    .iter()
+                    .map(|s| format!("<a href=\"#{0}\"><code>{0}</code></a>", escape(s)))
+                    .collect();
+                let _ = writeln!(out, "<p>See: {}</p>", see.join(", "));
+            }
+        }
+        out.push_str("</body>\n</html>\n");
+        out
+    }
+}
+
+/// A Markdown page listing `docs`, linking to their pages as `looa doc -o`
+/// names them
+pub fn markdown_index(docs: &[ModuleDoc]) -> String {
+    let mut out = String::from("# Modules\n\n");
+    for doc in docs {
+        let _ = write!(out, "- [`{0}`]({0}.md)", doc.name);
+        if !doc.summary.is_empty() {
+            let _ = write!(out, ": {}", doc.summary);
+        }
+        out.push('\n');
+    }
+    out
+}
+
+/// An HTML page listing `docs`, linking to their pages as `looa doc -o`
+/// names them
+pub fn html_index(docs: &[ModuleDoc]) -> String {
+    let mut out = String::from(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n\
+         <title>Modules</title>\n</head>\n<body>\n<h1>Modules</h1>\n<ul>\n",
+    );
+    for doc in docs {
+        let _ = write!(
+            out,
+            "<li><a href=\"{0}.html\"><code>{0}</code></a>",
+            escape(&doc.name)
+        );
+        if !doc.summary.is_empty() {
+            let _ = write!(out, ": {}", escape(&doc.summary));
+        }
+        out.push_str("</li>\n");
+    }
+    out.push_str("</ul>\n</body>\n</html>\n");
+    out
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
@@ -0,0 +1,158 @@
+use std::error;
+use std::fmt;
+
+use lexer::{Position, Span};
+use trace;
+use value::{Event, Type, Value};
+
+/// An error raised while loading or running a chunk
+#[derive(Clone, Debug, PartialEq)]
+pub enum Error {
+    /// the source text could not be parsed
+    Syntax(String),
+    /// an error raised by Rust code while running a chunk
+    Runtime(String),
+    /// an error value raised by Lua code, which for errors in operations
+    /// is a message giving the position they happened at
+    Lua(Value),
+}
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Syntax(ref msg) => write!(f, "syntax error: {}", msg),
+            Error::Runtime(ref msg) => f.write_str(msg),
+            Error::Lua(ref val) => match val.type_of() {
+                Type::String | Type::Number => fmt::Display::fmt(val, f),
+                // an object with `__tostring` describes itself, as `lua` does
+                ty => match val.metamethod(Event::ToString).map(|_| val.lua_tostring()) {
+                    Some(Ok(text)) => fmt::Display::fmt(&text, f),
+                    _ => write!(f, "(error object is a {} value)", ty),
+                },
+            },
+        }
+    }
+}
+impl error::Error for Error {}
+impl Error {
+    /// Prefix the message of an error raised by Rust code with where it
+    /// happened, as in `chunk:3: attempt to call a nil value`, so that
+    /// outer locations do not prefix it again
+    pub(crate) fn located<D>(self, place: D) -> Error
+    where
+        D: fmt::Display,
+    {
+        match self {
+            Error::Runtime(msg) if trace::locate() => {
+                Error::Lua(Value::string(format!("{}: {}", place, msg)))
+            }
+            err => err,
+        }
+    }
+    /// The value a script catching the error sees, which is the message
+    /// unless Lua code raised some other value
+    pub fn into_value(self) -> Value {
+        match self {
+            Error::Syntax(msg) | Error::Runtime(msg) => Value::string(msg),
+            Error::Lua(val) => val,
+        }
+    }
+}
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Error {
+        Error::Syntax(err.to_string())
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// Where and why the lexer or parser rejected some source
+#[derive(Clone, Debug, PartialEq)]
+pub struct ParseError {
+    /// the name of the chunk, which the lexer alone does not know
+    pub chunk: Option<String>,
+    pub message: String,
+    pub pos: Position,
+    /// the offending token or characters
+    pub span: Span,
+    /// the text at `span`, or `None` at the end of the input
+    pub found: Option<String>,
+    /// what would have been accepted instead, when that is known
+    pub expected: Vec<String>,
+}
+impl ParseError {
+    pub(crate) fn new(
+        message: String,
+        span: Span,
+        pos: Position,
+        found: Option<String>,
+    ) -> ParseError {
+        ParseError {
+            chunk: None,
+            message,
+            pos,
+            span,
+            found,
+            expected: Vec::new(),
+        }
+    }
+    /// The line of `src` (the source that failed to parse) with the
+    /// offending text underlined, like
+    ///
+    /// ```text
+    ///  3 | x = = 1
+    ///    |     ^
+    /// ```
+    pub fn snippet(&self, src: &[u8]) -> String {
+        let (line, indent, width) = self.marked_line(src);
+        let gutter = self.pos.line.to_string();
+        format!(
+            "{} | {}\n{} | {}{}",
+            gutter,
+            line,
+            " ".repeat(gutter.len()),
+            indent,
+            "^".repeat(width)
+        )
+    }
+    /// The line of `src` the error is on, the whitespace up to the
+    /// offending text, and how many characters wide it is, at least 1
+    pub(crate) fn marked_line(&self, src: &[u8]) -> (String, String, usize) {
+        let line_start = src[..self.span.start.min(src.len())]
+            .iter()
+            .rposition(|&c| c == b'\n' || c == b'\r')
+            .map_or(0, |i| i + 1);
+        let line_end = src[line_start..]
+            .iter()
+            .position(|&c| c == b'\n' || c == b'\r')
+            .map_or(src.len(), |i| line_start + i);
+        let line = String::from_utf8_lossy(&src[line_start..line_end]).into_owned();
+        let mut start = (self.pos.column as usize - 1).min(line.len());
+        while !line.is_char_boundary(start) {
+            start -= 1;
+        }
+        let mut end = (start + self.span.end - self.span.start).min(line.len());
+        while !line.is_char_boundary(end) {
+            end += 1;
+        }
+        // tabs are kept so the caret lines up however they are displayed
+        let indent: String = line[..start]
+            .chars()
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = line[start..end].chars().count().max(1);
+        (line, indent, width)
+    }
+}
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref chunk) = self.chunk {
+            write!(f, "{}:", chunk)?;
+        }
+        write!(f, "{}: {} near ", self.pos, self.message)?;
+        match self.found {
+            Some(ref text) => write!(f, "'{}'", text),
+            None => f.write_str("<eof>"),
+        }
+    }
+}
+impl error::Error for ParseError {}
@@ -0,0 +1,835 @@
+//! Printing a parsed chunk back out as source in one consistent style
+//!
+//! The tree is turned into a document of text, the places lines can break
+//! and groups that are either kept on one line or broken at all of their
+//! line breaks, following Wadler's "prettier printer". A group stays on one
+//! line when it fits in the width left, and otherwise breaks.
+//!
+//! The tree has no comments, so they are lexed separately and put back at
+//! the nearest place a statement, block, field or closing keyword starts:
+//! a comment on the same line as the code before it stays after that code,
+//! and others go on their own line before what follows them. Numbers keep
+//! the way they were written, as do long strings, and the choices the tree
+//! does not record, such as `a.b` against `a["b"]`, are read back from the
+//! source.
+
+use ast::{Ast, BinOp, Block, Expr, ExprKind, Field, FieldKind, FuncBody, Stat, StatKind, UnOp};
+use error::ParseError;
+use lexer::{Lexer, Span, TokenKind};
+use parser;
+
+/// The quote style short strings are written in
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Quotes {
+    Double,
+    Single,
+}
+
+/// How `format` lays source out
+#[derive(Clone, Debug)]
+pub struct Style {
+    /// what each level of nesting is indented by
+    pub indent: String,
+    /// the quotes of short strings, which are kept as they are when the
+    /// other kind would take more escapes
+    pub quotes: Quotes,
+    /// the column lines are broken to stay within where they can be
+    pub width: usize,
+}
+impl Default for Style {
+    fn default() -> Style {
+        Style {
+            indent: "    ".to_string(),
+            quotes: Quotes::Double,
+            width: 80,
+        }
+    }
+}
+
+/// Format the chunk `src`, named `chunk` in errors, in `style`
+pub fn format(src: &[u8], chunk: &str, style: &Style) -> Result<Vec<u8>, ParseError> {
+    let ast = parser::parse_chunk(src, chunk)?;
+    let mut formatter = Formatter::new(src, &ast, style)?;
+    let items = formatter.block(&ast[ast.main()].body, src.len());
+    let mut docs = Vec::new();
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            docs.push(Doc::HardLine);
+            if item.blank {
+                docs.push(Doc::HardLine);
+            }
+        }
+        docs.push(item.doc);
+    }
+    let mut out = print(&Doc::Concat(docs), style);
+    if !out.is_empty() {
+        out.push(b'\n');
+    }
+    Ok(out)
+}
+
+enum Doc {
+    Text(Vec<u8>),
+    /// a space, or a new line if the group breaks
+    Line,
+    /// nothing, or a new line if the group breaks
+    SoftLine,
+    /// a new line, which breaks every group around it
+    HardLine,
+    /// text written only if the group breaks, such as a trailing comma
+    IfBreak(&'static str),
+    /// breaks every group around it without writing anything, after a
+    /// comment that runs to the end of the line
+    BreakParent,
+    Concat(Vec<Doc>),
+    /// indents the lines started inside it by one more level
+    Nest(Vec<Doc>),
+    Group(Vec<Doc>),
+}
+
+fn text<T: AsRef<[u8]>>(text: T) -> Doc {
+    Doc::Text(text.as_ref().to_vec())
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Mode {
+    Flat,
+    Break,
+}
+
+/// The columns `text` takes up, counting characters rather than bytes
+fn width(text: &[u8]) -> usize {
+    text.iter()
+        .map(|&c| match c {
+            b'\t' => 4,
+            0x80..=0xbf => 0,
+            _ => 1,
+        })
+        .sum()
+}
+
+/// Lay `doc` out in `style`
+fn print(doc: &Doc, style: &Style) -> Vec<u8> {
+    let mut printer = Printer {
+        style,
+        out: Vec::new(),
+        column: 0,
+        pending: None,
+    };
+    let mut stack = vec![(0, Mode::Break, doc)];
+    while let Some((level, mode, doc)) = stack.pop() {
+        match *doc {
+            Doc::Text(ref bytes) => printer.text(bytes),
+            Doc::Line if mode == Mode::Flat => printer.text(b" "),
+            Doc::SoftLine if mode == Mode::Flat => {}
+            Doc::Line | Doc::SoftLine | Doc::HardLine => printer.newline(level),
+            Doc::IfBreak(bytes) if mode == Mode::Break => printer.text(bytes.as_bytes()),
+            Doc::IfBreak(_) | Doc::BreakParent => {}
+            Doc::Concat(ref docs) => stack.extend(docs.iter().rev().map(|doc| (level, mode, doc))),
+            Doc::Nest(ref docs) => {
+                stack.extend(docs.iter().rev().map(|doc| (level + 1, mode, doc)))
+            }
+            Doc::Group(ref docs) => {
+                let room = style.width as isize - printer.column as isize;
+                let mode = if mode == Mode::Flat || fits(docs, &stack, room) {
+                    Mode::Flat
+                } else {
+                    Mode::Break
+                };
+                stack.extend(docs.iter().rev().map(|doc| (level, mode, doc)));
+            }
+        }
+    }
+    printer.out
+}
+
+struct Printer<'a> {
+    style: &'a Style,
+    out: Vec<u8>,
+    column: usize,
+    /// the indentation level of the line just started, which is written
+    /// with the text after it so that blank lines have none
+    pending: Option<usize>,
+}
+impl<'a> Printer<'a> {
+    fn text(&mut self, bytes: &[u8]) {
+        if let Some(level) = self.pending.take() {
+            for _ in 0..level {
+                self.out.extend_from_slice(self.style.indent.as_bytes());
+            }
+        }
+        self.out.extend_from_slice(bytes);
+        self.column = match bytes.iter().rposition(|&c| c == b'\n') {
+            Some(at) => width(&bytes[at + 1..]),
+            None => self.column + width(bytes),
+        };
+    }
+    fn newline(&mut self, level: usize) {
+        self.out.push(b'\n');
+        self.pending = Some(level);
+        self.column = level * width(self.style.indent.as_bytes());
+    }
+}
+
+/// Whether the group of `docs` fits in `room` columns on one line, along
+/// with what follows it in `rest` up to the next line break
+fn fits(docs: &[Doc], rest: &[(usize, Mode, &Doc)], mut room: isize) -> bool {
+    let mut todo: Vec<(Mode, &Doc)> = docs.iter().rev().map(|doc| (Mode::Flat, doc)).collect();
+    let mut rest = rest.iter().rev();
+    loop {
+        let (mode, doc) = match todo.pop() {
+            Some(next) => next,
+            None => match rest.next() {
+                Some(&(_, mode, doc)) => (mode, doc),
+                None => return true,
+            },
+        };
+        match *doc {
+            Doc::Text(ref bytes) => {
+                let line = match bytes.iter().position(|&c| c == b'\n') {
+                    Some(at) => &bytes[..at],
+                    None => bytes,
+                };
+                room -= width(line) as isize;
+                if room < 0 {
+                    return false;
+                }
+                if line.len() < bytes.len() {
+                    return true;
+                }
+            }
+            Doc::Line | Doc::SoftLine if mode == Mode::Break => return true,
+            Doc::HardLine | Doc::BreakParent if mode == Mode::Flat => return false,
+            Doc::HardLine => return true,
+            Doc::Line => room -= 1,
+            Doc::IfBreak(bytes) if mode == Mode::Break => room -= bytes.len() as isize,
+            Doc::SoftLine | Doc::IfBreak(_) | Doc::BreakParent => {}
+            Doc::Concat(ref docs) | Doc::Nest(ref docs) | Doc::Group(ref docs) => {
+                todo.extend(docs.iter().rev().map(|doc| (mode, doc)));
+            }
+        }
+        if room < 0 {
+            return false;
+        }
+    }
+}
+
+/// A statement or comment in a block, on its own line
+struct Item {
+    doc: Doc,
+    /// whether a blank line came before it in the source
+    blank: bool,
+}
+
+/// Builds the document for a chunk, taking the comments from the source in
+/// order as the places they go in are reached
+struct Formatter<'a> {
+    src: &'a [u8],
+    ast: &'a Ast,
+    style: &'a Style,
+    /// every token, comments included
+    tokens: Vec<Span>,
+    /// the tokens that are not comments
+    code: Vec<Span>,
+    comments: Vec<Span>,
+    /// how many comments have been placed
+    next: usize,
+}
+impl<'a> Formatter<'a> {
+    fn new(src: &'a [u8], ast: &'a Ast, style: &'a Style) -> Result<Formatter<'a>, ParseError> {
+        let mut lexer = Lexer::new(src).with_comments(true);
+        let mut formatter = Formatter {
+            src,
+            ast,
+            style,
+            tokens: Vec::new(),
+            code: Vec::new(),
+            comments: Vec::new(),
+            next: 0,
+        };
+        loop {
+            let token = lexer.next_token()?;
+            match token.kind {
+                TokenKind::Eof => return Ok(formatter),
+                TokenKind::Comment => formatter.comments.push(token.span),
+                _ => formatter.code.push(token.span),
+            }
+            formatter.tokens.push(token.span);
+        }
+    }
+
+    fn source(&self, span: Span) -> &'a [u8] {
+        &self.src[span.start..span.end]
+    }
+    /// The number of line breaks between the token before `offset` and
+    /// `offset`, or 0 at the start of the source
+    fn breaks_before(&self, offset: usize) -> usize {
+        let i = self.tokens.partition_point(|span| span.start < offset);
+        match i.checked_sub(1) {
+            Some(before) => self.src[self.tokens[before].end..offset]
+                .iter()
+                .filter(|&&c| c == b'\n')
+                .count(),
+            None => 0,
+        }
+    }
+    /// The first token that is not a comment at or after `offset`
+    fn code_at(&self, offset: usize) -> Option<Span> {
+        let i = self.code.partition_point(|span| span.start < offset);
+        self.code.get(i).cloned()
+    }
+    /// Where the keyword closing `block` starts
+    fn closing(&self, block: &Block) -> usize {
+        self.code_at(block.loc.span.end)
+            .map_or(self.src.len(), |span| span.start)
+    }
+    /// Where the `return` ending `block` starts
+    fn return_start(&self, block: &Block) -> usize {
+        let from = self.ast[block.stats]
+            .last()
+            .map_or(block.loc.span.start, |stat| stat.loc.span.end);
+        let i = self.code.partition_point(|span| span.start < from);
+        self.code[i..]
+            .iter()
+            .find(|&&span| self.source(span) == b"return")
+            .map_or(block.loc.span.end, |span| span.start)
+    }
+
+    /// The next comment if it starts before `offset`
+    fn comment_before(&self, offset: usize) -> Option<Span> {
+        self.comments
+            .get(self.next)
+            .cloned()
+            .filter(|span| span.start < offset)
+    }
+    /// A comment, with what it needs after it
+    fn comment(&mut self, span: Span) -> Doc {
+        self.next += 1;
+        let bytes = self.source(span);
+        let after = &bytes[2..];
+        let long =
+            after.starts_with(b"[") && after[1..].iter().find(|&&c| c != b'=') == Some(&b'[');
+        if long {
+            text(bytes)
+        } else {
+            let end = bytes
+                .iter()
+                .rposition(|c| !c.is_ascii_whitespace())
+                .map_or(0, |at| at + 1);
+            Doc::Concat(vec![text(&bytes[..end]), Doc::BreakParent])
+        }
+    }
+    /// The comments before `offset` on the same line as the code before
+    /// them, to go after that code
+    fn trailing(&mut self, offset: usize) -> Doc {
+        let mut docs = Vec::new();
+        while let Some(span) = self.comment_before(offset) {
+            if self.breaks_before(span.start) > 0 {
+                break;
+            }
+            docs.push(text(" "));
+            docs.push(self.comment(span));
+        }
+        Doc::Concat(docs)
+    }
+    /// The comments before `offset` on lines of their own
+    fn leading(&mut self, offset: usize, items: &mut Vec<Item>) {
+        while let Some(span) = self.comment_before(offset) {
+            let blank = self.breaks_before(span.start) > 1;
+            let mut docs = vec![self.comment(span)];
+            docs.push(self.trailing(offset));
+            items.push(Item {
+                doc: Doc::Concat(docs),
+                blank,
+            });
+        }
+    }
+
+    /// The statements and comments of `block`, up to `end`
+    fn block(&mut self, block: &Block, end: usize) -> Vec<Item> {
+        let ast = self.ast;
+        let mut items = Vec::new();
+        let ret = block.ret.map(|ret| (&ast[ret], self.return_start(block)));
+        let stats = &ast[block.stats];
+        for (i, stat) in stats.iter().enumerate() {
+            let start = stat.loc.span.start;
+            self.leading(start, &mut items);
+            let blank = self.breaks_before(start) > 1;
+            let mut docs = Vec::new();
+            // a statement starting with a `(` would otherwise continue
+            // the call or expression before it
+            if i > 0 && starts_with_paren(ast, stat) {
+                docs.push(text(";"));
+            }
+            docs.push(self.stat(stat));
+            let next = match stats.get(i + 1) {
+                Some(next) => next.loc.span.start,
+                None => ret.map_or(end, |(_, start)| start),
+            };
+            docs.push(self.trailing(next));
+            items.push(Item {
+                doc: Doc::Concat(docs),
+                blank,
+            });
+        }
+        if let Some((values, start)) = ret {
+            self.leading(start, &mut items);
+            let blank = self.breaks_before(start) > 1;
+            let mut docs = vec![text("return")];
+            if !values.is_empty() {
+                docs.push(text(" "));
+                docs.push(self.list(values));
+            }
+            docs.push(self.trailing(end));
+            items.push(Item {
+                doc: Doc::Concat(docs),
+                blank,
+            });
+        }
+        self.leading(end, &mut items);
+        items
+    }
+    /// `header`, then `block` indented on the lines after it, ending where
+    /// the keyword closing it goes, and kept on one line if it is empty and
+    /// `inline` is set
+    fn nested(&mut self, header: Doc, block: &Block, inline: bool) -> Doc {
+        let end = self.closing(block);
+        let first = match self.ast[block.stats].first() {
+            Some(stat) => stat.loc.span.start,
+            None if block.ret.is_some() => self.return_start(block),
+            None => end,
+        };
+        let empty = block.stats.is_empty() && block.ret.is_none();
+        if inline && empty && self.comment_before(end).is_none() {
+            return Doc::Concat(vec![header, text(" ")]);
+        }
+        let trailing = self.trailing(first);
+        let mut body = Vec::new();
+        for (i, item) in self.block(block, end).into_iter().enumerate() {
+            body.push(Doc::HardLine);
+            if i > 0 && item.blank {
+                body.push(Doc::HardLine);
+            }
+            body.push(item.doc);
+        }
+        Doc::Concat(vec![header, trailing, Doc::Nest(body), Doc::HardLine])
+    }
+
+    fn stat(&mut self, stat: &Stat) -> Doc {
+        let ast = self.ast;
+        match stat.kind {
+            StatKind::Assign(targets, values) => {
+                let targets = self.list(&ast[targets]);
+                Doc::Concat(vec![targets, text(" = "), self.list(&ast[values])])
+            }
+            StatKind::Call(call) => self.expr(&ast[call]),
+            StatKind::Do(ref body) => {
+                Doc::Concat(vec![self.nested(text("do"), body, true), text("end")])
+            }
+            StatKind::While(cond, ref body) => {
+                let header = Doc::Concat(vec![text("while "), self.expr(&ast[cond]), text(" do")]);
+                Doc::Concat(vec![self.nested(header, body, true), text("end")])
+            }
+            StatKind::Repeat(ref body, cond) => {
+                let body = self.nested(text("repeat"), body, true);
+                Doc::Concat(vec![body, text("until "), self.expr(&ast[cond])])
+            }
+            StatKind::If(branches, ref otherwise) => {
+                let mut docs = Vec::new();
+                for (i, &(cond, ref body)) in ast[branches].iter().enumerate() {
+                    let keyword = if i == 0 { "if " } else { "elseif " };
+                    let cond = self.expr(&ast[cond]);
+                    let header = Doc::Concat(vec![text(keyword), cond, text(" then")]);
+                    docs.push(self.nested(header, body, false));
+                }
+                if let Some(ref body) = *otherwise {
+                    docs.push(self.nested(text("else"), body, false));
+                }
+                docs.push(text("end"));
+                Doc::Concat(docs)
+            }
+            StatKind::NumericFor {
+                ref var,
+                start,
+                limit,
+                step,
+                ref body,
+            } => {
+                let mut header = vec![
+                    text(format!("for {} = ", var)),
+                    self.expr(&ast[start]),
+                    text(", "),
+                    self.expr(&ast[limit]),
+                ];
+                if let Some(step) = step {
+                    header.push(text(", "));
+                    header.push(self.expr(&ast[step]));
+                }
+                header.push(text(" do"));
+                Doc::Concat(vec![
+                    self.nested(Doc::Concat(header), body, true),
+                    text("end"),
+                ])
+            }
+            StatKind::GenericFor {
+                vars,
+                exprs,
+                ref body,
+            } => {
+                let header = Doc::Concat(vec![
+                    text(format!("for {} in ", ast[vars].join(", "))),
+                    self.list(&ast[exprs]),
+                    text(" do"),
+                ]);
+                Doc::Concat(vec![self.nested(header, body, true), text("end")])
+            }
+            StatKind::Function(ref name, body) => {
+                let mut header = format!("function {}", ast[name.path].join("."));
+                if let Some(ref method) = name.method {
+                    header.push(':');
+                    header.push_str(method);
+                }
+                let is_method = name.method.is_some();
+                Doc::Concat(vec![text(header), self.func_body(&ast[body], is_method)])
+            }
+            StatKind::LocalFunction(ref name, body) => Doc::Concat(vec![
+                text(format!("local function {}", name)),
+                self.func_body(&ast[body], false),
+            ]),
+            StatKind::Local(names, values) => {
+                let mut docs = vec![text(format!("local {}", ast[names].join(", ")))];
+                if !values.is_empty() {
+                    docs.push(text(" = "));
+                    docs.push(self.list(&ast[values]));
+                }
+                Doc::Concat(docs)
+            }
+            StatKind::Break => text("break"),
+            StatKind::Goto(ref label) => text(format!("goto {}", label)),
+            StatKind::Label(ref label) => text(format!("::{}::", label)),
+        }
+    }
+    /// The parameters and body of a function, leaving out the `self` a
+    /// method is given
+    fn func_body(&mut self, body: &FuncBody, is_method: bool) -> Doc {
+        let skip = if is_method { 1 } else { 0 };
+        let mut params: Vec<&str> = self.ast[body.params][skip..]
+            .iter()
+            .map(|name| &**name)
+            .collect();
+        if body.vararg {
+            params.push("...");
+        }
+        let header = text(format!("({})", params.join(", ")));
+        Doc::Concat(vec![self.nested(header, &body.body, true), text("end")])
+    }
+
+    /// Expressions separated by commas
+    fn list(&mut self, exprs: &[Expr]) -> Doc {
+        let mut docs = Vec::new();
+        for (i, expr) in exprs.iter().enumerate() {
+            if i > 0 {
+                docs.push(text(", "));
+            }
+            docs.push(self.expr(expr));
+        }
+        Doc::Concat(docs)
+    }
+    fn expr(&mut self, expr: &Expr) -> Doc {
+        let ast = self.ast;
+        match expr.kind {
+            ExprKind::Nil => text("nil"),
+            ExprKind::True => text("true"),
+            ExprKind::False => text("false"),
+            ExprKind::Vararg => text("..."),
+            ExprKind::Number(_) => text(self.source(expr.loc.span)),
+            ExprKind::String(_) => text(requote(self.source(expr.loc.span), self.style.quotes)),
+            ExprKind::Name(ref name) => text(&**name),
+            ExprKind::Function(body) => {
+                Doc::Concat(vec![text("function"), self.func_body(&ast[body], false)])
+            }
+            ExprKind::Table(fields) => self.table(&ast[fields], expr.loc.span.end - 1),
+            ExprKind::Index(obj, key) => {
+                let (obj, key) = (self.expr(&ast[obj]), &ast[key]);
+                let source = self.source(key.loc.span);
+                let is_name = match key.kind {
+                    ExprKind::String(ref bytes) => bytes.as_slice() == source,
+                    _ => false,
+                };
+                if is_name {
+                    return Doc::Concat(vec![obj, text("."), text(source)]);
+                }
+                // `[[` would start a long string
+                let open = if source.starts_with(b"[") { "[ " } else { "[" };
+                let close = if source.starts_with(b"[") { " ]" } else { "]" };
+                Doc::Concat(vec![obj, text(open), self.expr(key), text(close)])
+            }
+            ExprKind::Call(func, args) => {
+                let func = &ast[func];
+                let func_end = func.loc.span.end;
+                let func = self.expr(func);
+                let args = self.args(&ast[args], func_end, 0);
+                Doc::Concat(vec![func, args])
+            }
+            ExprKind::Method(obj, ref name, args) => {
+                let obj = &ast[obj];
+                let obj_end = obj.loc.span.end;
+                let obj = self.expr(obj);
+                // the arguments come after the `:` and the name
+                let args = self.args(&ast[args], obj_end, 2);
+                Doc::Concat(vec![obj, text(format!(":{}", name)), args])
+            }
+            ExprKind::Paren(inner) => {
+                Doc::Concat(vec![text("("), self.expr(&ast[inner]), text(")")])
+            }
+            ExprKind::Binary(op, _, _) => {
+                let mut operands = Vec::new();
+                chain(ast, expr, op.precedence(), &mut operands);
+                let mut rest = Vec::new();
+                let first = self.expr(operands[0].1);
+                for &(op, operand) in &operands[1..] {
+                    let op = op.expect("only the first operand has no operator");
+                    rest.push(Doc::Line);
+                    rest.push(text(format!("{} ", binary_text(op))));
+                    rest.push(self.expr(operand));
+                }
+                Doc::Group(vec![first, Doc::Nest(rest)])
+            }
+            ExprKind::Unary(op, operand) => {
+                let operand = &ast[operand];
+                let op = match op {
+                    // `- -x` is not a comment
+                    UnOp::Neg if starts_with_minus(ast, operand) => "- ",
+                    UnOp::Neg => "-",
+                    UnOp::Not => "not ",
+                    UnOp::Len => "#",
+                    UnOp::BNot => "~",
+                };
+                Doc::Concat(vec![text(op), self.expr(operand)])
+            }
+        }
+    }
+    /// The arguments of a call, whose callee ends at `callee_end` and which
+    /// start `skip` tokens after it
+    fn args(&mut self, args: &[Expr], callee_end: usize, skip: usize) -> Doc {
+        let i = self.code.partition_point(|span| span.start < callee_end) + skip;
+        let parens = self
+            .code
+            .get(i)
+            .is_none_or(|&span| self.source(span) == b"(");
+        if !parens {
+            // a lone string or table, written without parentheses
+            return Doc::Concat(vec![text(" "), self.expr(&args[0])]);
+        }
+        if args.is_empty() {
+            return text("()");
+        }
+        let hug = matches!(
+            args[args.len() - 1].kind,
+            ExprKind::Function(_) | ExprKind::Table(_)
+        );
+        if hug {
+            // the function or table breaks instead, keeping `(` and `)`
+            // around it
+            return Doc::Concat(vec![text("("), self.list(args), text(")")]);
+        }
+        let mut docs = vec![Doc::SoftLine];
+        for (i, arg) in args.iter().enumerate() {
+            if i > 0 {
+                docs.push(text(","));
+                docs.push(Doc::Line);
+            }
+            docs.push(self.expr(arg));
+        }
+        Doc::Group(vec![text("("), Doc::Nest(docs), Doc::SoftLine, text(")")])
+    }
+    /// A table constructor whose `}` is at `close`, on one line if it fits
+    /// and has no comments, and otherwise with a field on each line
+    fn table(&mut self, fields: &[Field], close: usize) -> Doc {
+        if fields.is_empty() && self.comment_before(close).is_none() {
+            return text("{}");
+        }
+        let mut docs = vec![Doc::SoftLine];
+        for (i, field) in fields.iter().enumerate() {
+            let start = field.loc.span.start;
+            if i > 0 && self.breaks_before(start) > 1 {
+                docs.push(Doc::HardLine);
+            }
+            while let Some(span) = self.comment_before(start) {
+                docs.push(self.comment(span));
+                docs.push(self.trailing(start));
+                docs.push(Doc::HardLine);
+            }
+            docs.push(self.field(field));
+            let next = fields.get(i + 1).map_or(close, |next| next.loc.span.start);
+            if i + 1 < fields.len() {
+                docs.push(text(","));
+                docs.push(self.trailing(next));
+                docs.push(Doc::Line);
+            } else {
+                docs.push(Doc::IfBreak(","));
+                docs.push(self.trailing(next));
+            }
+        }
+        while let Some(span) = self.comment_before(close) {
+            if !fields.is_empty() {
+                docs.push(Doc::HardLine);
+            }
+            docs.push(self.comment(span));
+            docs.push(self.trailing(close));
+        }
+        Doc::Group(vec![text("{"), Doc::Nest(docs), Doc::SoftLine, text("}")])
+    }
+    fn field(&mut self, field: &Field) -> Doc {
+        let ast = self.ast;
+        match field.kind {
+            FieldKind::Named(ref name, val) => {
+                Doc::Concat(vec![text(format!("{} = ", name)), self.expr(&ast[val])])
+            }
+            FieldKind::Indexed(key, val) => {
+                let (key, val) = (&ast[key], &ast[val]);
+                let long = self.source(key.loc.span).starts_with(b"[");
+                let (open, close) = if long { ("[ ", " ] = ") } else { ("[", "] = ") };
+                Doc::Concat(vec![
+                    text(open),
+                    self.expr(key),
+                    text(close),
+                    self.expr(val),
+                ])
+            }
+            FieldKind::Positional(val) => self.expr(&ast[val]),
+        }
+    }
+}
+
+/// The operands of the chain of operators as tight as `precedence` that
+/// `expr` is the last of, each with the operator before it, so that
+/// `a + b - c` breaks before either operator rather than only one
+fn chain<'e>(
+    ast: &'e Ast,
+    expr: &'e Expr,
+    precedence: (u8, u8),
+    operands: &mut Vec<(Option<BinOp>, &'e Expr)>,
+) {
+    let right_assoc = precedence.1 < precedence.0;
+    match expr.kind {
+        ExprKind::Binary(op, lhs, rhs) if op.precedence() == precedence => {
+            if right_assoc {
+                operands.push((None, &ast[lhs]));
+                let at = operands.len();
+                chain(ast, &ast[rhs], precedence, operands);
+                operands[at].0 = Some(op);
+            } else {
+                chain(ast, &ast[lhs], precedence, operands);
+                operands.push((Some(op), &ast[rhs]));
+            }
+        }
+        _ => operands.push((None, expr)),
+    }
+}
+
+fn binary_text(op: BinOp) -> &'static str {
+    match op {
+        BinOp::Add => "+",
+        BinOp::Sub => "-",
+        BinOp::Mul => "*",
+        BinOp::Div => "/",
+        BinOp::IDiv => "//",
+        BinOp::Mod => "%",
+        BinOp::Pow => "^",
+        BinOp::Concat => "..",
+        BinOp::Eq => "==",
+        BinOp::Ne => "~=",
+        BinOp::Lt => "<",
+        BinOp::Le => "<=",
+        BinOp::Gt => ">",
+        BinOp::Ge => ">=",
+        BinOp::And => "and",
+        BinOp::Or => "or",
+        BinOp::BAnd => "&",
+        BinOp::BOr => "|",
+        BinOp::BXor => "~",
+        BinOp::Shl => "<<",
+        BinOp::Shr => ">>",
+    }
+}
+
+fn starts_with_minus(ast: &Ast, expr: &Expr) -> bool {
+    match expr.kind {
+        ExprKind::Unary(UnOp::Neg, _) => true,
+        ExprKind::Binary(_, lhs, _) => starts_with_minus(ast, &ast[lhs]),
+        _ => false,
+    }
+}
+
+fn starts_with_paren(ast: &Ast, stat: &Stat) -> bool {
+    let mut expr = match stat.kind {
+        StatKind::Call(call) => &ast[call],
+        StatKind::Assign(targets, _) => &ast[targets][0],
+        _ => return false,
+    };
+    loop {
+        expr = match expr.kind {
+            ExprKind::Call(inner, _)
+            | ExprKind::Method(inner, _, _)
+            | ExprKind::Index(inner, _) => &ast[inner],
+            ExprKind::Paren(_) => return true,
+            _ => return false,
+        };
+    }
+}
+
+/// A short string literal in `quotes`, unless that takes more escapes than
+/// the quotes it has, or a long string as it is
+fn requote(literal: &[u8], quotes: Quotes) -> Vec<u8> {
+    let (want, other) = match quotes {
+        Quotes::Double => (b'"', b'\''),
+        Quotes::Single => (b'\'', b'"'),
+    };
+    if literal[0] != other {
+        return literal.to_vec();
+    }
+    let body = &literal[1..literal.len() - 1];
+    // the quotes that would need escaping, and the escaped ones that would
+    // not
+    let (mut wanted, mut escaped) = (0, 0);
+    let mut i = 0;
+    while i < body.len() {
+        if body[i] == b'\\' {
+            if body.get(i + 1) == Some(&other) {
+                escaped += 1;
+            }
+            i += 1;
+        } else if body[i] == want {
+            wanted += 1;
+        }
+        i += 1;
+    }
+    if wanted > escaped {
+        return literal.to_vec();
+    }
+    let mut out = vec![want];
+    let mut i = 0;
+    while i < body.len() {
+        match body[i] {
+            b'\\' if body.get(i + 1) == Some(&other) => {
+                out.push(other);
+                i += 2;
+                continue;
+            }
+            b'\\' => {
+                out.push(b'\\');
+                if let Some(&next) = body.get(i + 1) {
+                    out.push(next);
+                }
+                i += 2;
+                continue;
+            }
+            c if c == want => out.extend_from_slice(&[b'\\', want]),
+            c => out.push(c),
+        }
+        i += 1;
+    }
+    out.push(want);
+    out
+}
@@ -0,0 +1,653 @@
+//! The garbage collector, which frees the values that nothing in use can
+//! reach, and the finalization of those with a `__gc` metamethod
+//!
+//! Values live on a heap, which each thread has one of, shared by the
+//! states on it since values pass freely between them. Dropping the last
+//! reference to a value frees nothing; instead the thread keeps lists of
+//! everything on its heap, and a collection frees whatever nothing in use
+//! refers to, however the garbage refers to itself, like a recursive local
+//! function and the variable it is stored in.
+//!
+//! The tables, Lua functions, userdata and coroutines on the heap are
+//! traced: for each of them a collection counts how many of its references
+//! come from the others. One referred to from anywhere else, such as a Rust
+//! variable, a VM stack or a state's globals, is a root, and is in use
+//! along with everything it refers to. The rest are garbage, and are freed
+//! all at once. Strings, Rust functions and the other values that refer to
+//! nothing the collector can see are freed by the collection after nothing
+//! refers to them. Rust functions and the Rust data of userdata can't be
+//! looked into, so what they refer to is always taken to be in use.
+//!
+//! Running Lua code while values are being freed could observe them half
+//! freed, so the garbage with a `__gc` metamethod is kept alive instead,
+//! along with everything it refers to, and queued to be finalized at a
+//! safe point. A later collection frees it if it is still garbage by then.
+//! Values that are still alive when the process ends are finalized too, if
+//! it ends by `os.exit` closing the state, so each thread also keeps weak
+//! references to every value it marked for finalization.
+//!
+//! States collect a step at a time as they allocate, so that a script is
+//! never paused to look at every value at once. Each step counts the
+//! references of, or marks, a share of the values there were when the
+//! collection started. Those change in between, so the counts are only a
+//! guess at which values are garbage, but the values there and then are
+//! garbage are always among those left unmarked, since nothing can refer to
+//! them. The last step counts the references of the unmarked values again
+//! all at once, and only frees those that really are garbage, which is
+//! cheap when there isn't much. Nothing is freed until then, which is what
+//! lets the steps in between hold on to values without counting as
+//! referring to them.
+//!
+//! States can collect by generation instead, since most values don't last
+//! long. The values a collection leaves are old, and a minor collection
+//! only counts the references of the values created since, taking any from
+//! old values to mean they are in use. Only a major collection, which looks
+//! at every value, frees old values.
+//!
+//! Whatever is left on a thread's heap when the thread ends is leaked, as
+//! the lists of it are gone by then, and what it refers to may already be.
+
+use std::cell::RefCell;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::mem;
+use std::rc::{Rc, Weak};
+use std::thread::LocalKey;
+
+use error::Result;
+use value::{self, Event, Object, Value, WeakValue};
+
+thread_local! {
+    static PENDING: RefCell<Vec<Value>> = const { RefCell::new(Vec::new()) };
+    /// values marked for finalization, in the order they were marked,
+    /// which can include some that have since been collected
+    static TRACKED: RefCell<Vec<WeakValue>> = const { RefCell::new(Vec::new()) };
+    /// everything created on the heap since the last collection
+    static YOUNG: RefCell<Generation> = const { RefCell::new(Generation::new()) };
+    /// what was left by a collection, which only major collections free
+    static OLD: RefCell<Generation> = const { RefCell::new(Generation::new()) };
+    /// the collection being done a step at a time, if one has started
+    static CYCLE: RefCell<Option<Cycle>> = const { RefCell::new(None) };
+}
+
+/// A variable captured by closures, which they share
+pub(crate) type Cell = Rc<RefCell<Value>>;
+
+/// A reference that keeps a value or a captured variable alive, which the
+/// types the collector traces pass to it
+///
+/// The references passed must be exactly the ones held, since any the
+/// collector is told of that are not held would hide a reference from
+/// elsewhere, and free a value in use. Leaving some out only keeps what
+/// they refer to alive.
+pub(crate) enum Edge<'a> {
+    Value(&'a Value),
+    Cell(&'a Cell),
+    /// the captured variables of a compiled function, which its calls share
+    Cells(&'a Rc<[Cell]>),
+}
+
+/// Values on the heap, in the order they were created, which own them
+struct Generation {
+    /// those that are traced
+    objects: Vec<Object>,
+    /// those that refer to nothing the collector can see
+    leaves: Vec<Object>,
+}
+impl Generation {
+    const fn new() -> Generation {
+        Generation {
+            objects: Vec::new(),
+            leaves: Vec::new(),
+        }
+    }
+}
+
+/// Remember a value that was just marked for finalization
+pub fn track(val: &Value) {
+    if let Some(weak) = val.downgrade() {
+        TRACKED.with(|tracked| {
+            let mut tracked = tracked.borrow_mut();
+            // forget collected values before the list would have to grow,
+            // and grow it anyway if most are alive
+            if tracked.len() == tracked.capacity() {
+                tracked.retain(WeakValue::is_alive);
+                let alive = tracked.len();
+                if alive > tracked.capacity() / 2 {
+                    tracked.reserve(alive);
+                }
+            }
+            tracked.push(weak);
+        });
+    }
+}
+
+/// The values that are traced, old ones first
+fn objects() -> Vec<Object> {
+    let mut all = OLD.with(|old| old.borrow().objects.clone());
+    YOUNG.with(|young| all.extend_from_slice(&young.borrow().objects));
+    all
+}
+
+/// Make every value created so far old
+fn promote() {
+    let young = YOUNG.with(|young| mem::replace(&mut *young.borrow_mut(), Generation::new()));
+    OLD.with(|old| {
+        let mut old = old.borrow_mut();
+        old.objects.extend(young.objects);
+        old.leaves.extend(young.leaves);
+    });
+}
+
+/// Hand a value that was just created on the heap to the collector, which
+/// frees it once it is garbage, and traces it if `traced`
+pub(crate) fn register(object: Object, traced: bool) {
+    // values can be created during thread teardown, after the lists are
+    // gone, and are leaked along with the rest
+    let _ = YOUNG.try_with(|young| {
+        let mut young = young.borrow_mut();
+        if traced {
+            young.objects.push(object);
+        } else {
+            young.leaves.push(object);
+        }
+    });
+}
+
+/// Queue a collected value to be passed to its `__gc` metamethod
+pub fn schedule(val: Value) {
+    // during thread teardown the queue may already be gone, and nothing
+    // would be left to run the finalizer anyway
+    let _ = PENDING.try_with(|pending| pending.borrow_mut().push(val));
+}
+
+/// Run the finalizers of every collected value, including any collected by
+/// the finalizers themselves
+///
+/// Every finalizer runs even if some fail, and the first error is returned.
+pub fn run_finalizers() -> Result<()> {
+    let mut result = Ok(());
+    while let Some(val) = PENDING.with(|pending| pending.borrow_mut().pop()) {
+        if let Some(handler) = val.metamethod(Event::Gc) {
+            let outcome = handler.call(vec![val]);
+            if result.is_ok() {
+                result = outcome.map(|_| ());
+            }
+        }
+    }
+    result
+}
+
+/// Run the finalizers of every value marked for finalization, collected or
+/// still alive, in the reverse order they were marked, as when the process
+/// is about to end
+///
+/// As with [`run_finalizers`], every finalizer runs and the first error is
+/// returned.
+pub fn finalize_all() -> Result<()> {
+    let mut result = run_finalizers();
+    let tracked = TRACKED.with(|tracked| tracked.replace(Vec::new()));
+    for weak in tracked.iter().rev() {
+        let val = match weak.upgrade() {
+            Some(val) => val,
+            None => continue,
+        };
+        if !val.take_finalizer() {
+            continue;
+        }
+        if let Some(handler) = val.metamethod(Event::Gc) {
+            let outcome = handler.call(vec![val]);
+            if result.is_ok() {
+                result = outcome.map(|_| ());
+            }
+        }
+    }
+    let rest = run_finalizers();
+    result.and(rest)
+}
+
+/// Something the collector follows references through
+enum Node {
+    Value(Object),
+    Cell(Cell),
+    Cells(Rc<[Cell]>),
+}
+impl Node {
+    fn trace(&self, visit: &mut dyn FnMut(Edge)) {
+        match *self {
+            Node::Value(object) => object.trace(visit),
+            Node::Cell(ref cell) => {
+                if let Ok(val) = cell.try_borrow() {
+                    visit(Edge::Value(&val));
+                }
+            }
+            Node::Cells(ref cells) => {
+                for cell in cells.iter() {
+                    visit(Edge::Cell(cell));
+                }
+            }
+        }
+    }
+    /// How many references to it there are, leaving out the one here
+    fn count(&self) -> usize {
+        match *self {
+            Node::Value(object) => object.refs(),
+            Node::Cell(ref cell) => Rc::strong_count(cell) - 1,
+            Node::Cells(ref cells) => Rc::strong_count(cells) - 1,
+        }
+    }
+}
+
+/// A node that the collection in progress keeps without keeping it alive,
+/// which also keeps its address from being reused
+enum WeakNode {
+    /// which nothing frees while the collection is in progress
+    Value(Object),
+    Cell(Weak<RefCell<Value>>),
+    Cells(Weak<[Cell]>),
+}
+impl WeakNode {
+    fn upgrade(&self) -> Option<Node> {
+        match *self {
+            WeakNode::Value(object) => Some(Node::Value(object)),
+            WeakNode::Cell(ref weak) => weak.upgrade().map(Node::Cell),
+            WeakNode::Cells(ref weak) => weak.upgrade().map(Node::Cells),
+        }
+    }
+    fn addr(&self) -> usize {
+        match *self {
+            WeakNode::Value(object) => object.addr(),
+            WeakNode::Cell(ref weak) => weak.as_ptr() as usize,
+            WeakNode::Cells(ref weak) => weak.as_ptr() as *const () as usize,
+        }
+    }
+}
+
+/// A collection being done a step at a time
+///
+/// Every collection that frees values cancels this one, as it would leave
+/// it with values that are gone.
+struct Cycle {
+    /// the values there were when it started, then the variables they
+    /// were found to have captured
+    nodes: Vec<WeakNode>,
+    /// where each node counted so far is in `nodes`, by address
+    index: HashMap<usize, usize>,
+    /// how many references each node counted so far had
+    counts: Vec<usize>,
+    /// how many references to each address come from the nodes counted
+    /// so far
+    internal: HashMap<usize, usize>,
+    /// how many nodes have been checked for references from elsewhere, once
+    /// all have been counted
+    checked: usize,
+    marked: Vec<bool>,
+    /// nodes found to be in use whose references are still to be marked
+    pending: Vec<usize>,
+}
+impl Cycle {
+    fn new() -> Cycle {
+        let nodes: Vec<WeakNode> = objects().into_iter().map(WeakNode::Value).collect();
+        Cycle {
+            index: HashMap::with_capacity(nodes.len()),
+            counts: Vec::with_capacity(nodes.len()),
+            internal: HashMap::new(),
+            checked: 0,
+            marked: Vec::new(),
+            pending: Vec::new(),
+            nodes,
+        }
+    }
+    /// Do about `work` nodes and references worth of the collection,
+    /// giving whether all the nodes are marked
+    fn step(&mut self, mut work: usize) -> bool {
+        while work > 0 {
+            work -= 1;
+            if self.counts.len() < self.nodes.len() {
+                work = work.saturating_sub(self.count());
+            } else if let Some(i) = self.pending.pop() {
+                work = work.saturating_sub(self.mark(i));
+            } else if self.checked < self.nodes.len() {
+                let i = self.checked;
+                self.checked += 1;
+                let internal = self.internal.get(&self.nodes[i].addr()).cloned();
+                if self.counts[i] > internal.unwrap_or(0) {
+                    self.pending.push(i);
+                }
+            } else {
+                return true;
+            }
+        }
+        false
+    }
+    /// Count the references of the next node and those it holds, giving
+    /// how many it holds
+    fn count(&mut self) -> usize {
+        let i = self.counts.len();
+        let node = match self.nodes[i].upgrade() {
+            Some(node) => node,
+            None => {
+                self.counts.push(0);
+                return 0;
+            }
+        };
+        self.counts.push(node.count());
+        self.index.entry(self.nodes[i].addr()).or_insert(i);
+        let (mut held, nodes, index, internal) =
+            (0, &mut self.nodes, &mut self.index, &mut self.internal);
+        node.trace(&mut |edge| {
+            let addr = match target(&edge) {
+                Some(addr) => addr,
+                None => return,
+            };
+            held += 1;
+            *internal.entry(addr).or_insert(0) += 1;
+            let weak = match edge {
+                Edge::Value(_) => return,
+                Edge::Cell(cell) => WeakNode::Cell(Rc::downgrade(cell)),
+                Edge::Cells(cells) => WeakNode::Cells(Rc::downgrade(cells)),
+            };
+            if let Entry::Vacant(entry) = index.entry(addr) {
+                entry.insert(nodes.len());
+                nodes.push(weak);
+            }
+        });
+        held
+    }
+    /// Mark node `i` as in use, giving how many references it holds
+    fn mark(&mut self, i: usize) -> usize {
+        if self.marked.len() < self.nodes.len() {
+            self.marked.resize(self.nodes.len(), false);
+        }
+        if mem::replace(&mut self.marked[i], true) {
+            return 0;
+        }
+        let node = match self.nodes[i].upgrade() {
+            Some(node) => node,
+            None => return 0,
+        };
+        let (mut held, index, marked, pending) = (0, &self.index, &self.marked, &mut self.pending);
+        node.trace(&mut |edge| {
+            held += 1;
+            if let Some(&j) = target(&edge).and_then(|addr| index.get(&addr)) {
+                if !marked[j] {
+                    pending.push(j);
+                }
+            }
+        });
+        held
+    }
+    /// The values left unmarked, which include every value that was
+    /// garbage when the collection started
+    fn unmarked(&self) -> Vec<Object> {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| !self.marked.get(i).cloned().unwrap_or(false))
+            .filter_map(|(_, node)| match *node {
+                WeakNode::Value(object) => Some(object),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// The address identifying what `edge` refers to
+fn target(edge: &Edge) -> Option<usize> {
+    match *edge {
+        Edge::Value(val) if val.is_collectable() => Some(val.addr()),
+        Edge::Value(_) => None,
+        Edge::Cell(cell) => Some(Rc::as_ptr(cell) as usize),
+        Edge::Cells(cells) => Some(Rc::as_ptr(cells) as *const () as usize),
+    }
+}
+
+/// Every value that is traced, with the captured variables they refer to
+/// and whether each is in use
+struct Graph {
+    nodes: Vec<Node>,
+    /// where each node is in `nodes`, by address
+    index: HashMap<usize, usize>,
+    live: Vec<bool>,
+}
+impl Graph {
+    /// Every value on the thread that is traced
+    fn all() -> Graph {
+        Graph::of(objects())
+    }
+    /// The values in `objects`, taking any reference to them from elsewhere
+    /// to mean they are in use, which holds for any of them
+    fn of(objects: Vec<Object>) -> Graph {
+        let mut graph = Graph {
+            nodes: Vec::new(),
+            index: HashMap::new(),
+            live: Vec::new(),
+        };
+        for object in objects {
+            graph.index.insert(object.addr(), graph.nodes.len());
+            graph.nodes.push(Node::Value(object));
+        }
+        // find the variables they captured
+        let mut next = 0;
+        while next < graph.nodes.len() {
+            let mut found = Vec::new();
+            graph.nodes[next].trace(&mut |edge| {
+                let node = match edge {
+                    Edge::Value(_) => return,
+                    Edge::Cell(cell) => Node::Cell(cell.clone()),
+                    Edge::Cells(cells) => Node::Cells(cells.clone()),
+                };
+                found.push((target(&edge), node));
+            });
+            for (addr, node) in found {
+                let addr = addr.expect("captured variables have addresses");
+                if !graph.index.contains_key(&addr) {
+                    graph.index.insert(addr, graph.nodes.len());
+                    graph.nodes.push(node);
+                }
+            }
+            next += 1;
+        }
+        // the references from outside are those that are left once the
+        // ones from each other are taken away
+        let mut outside: Vec<isize> = graph
+            .nodes
+            .iter()
+            .map(|node| node.count() as isize)
+            .collect();
+        for node in &graph.nodes {
+            let index = &graph.index;
+            node.trace(&mut |edge| {
+                if let Some(&i) = target(&edge).and_then(|addr| index.get(&addr)) {
+                    outside[i] -= 1;
+                }
+            });
+        }
+        graph.live = vec![false; graph.nodes.len()];
+        let roots = (0..graph.nodes.len()).filter(|&i| outside[i] > 0).collect();
+        graph.mark(roots);
+        graph
+    }
+    /// Mark the nodes in `from` as in use, along with everything they refer to
+    fn mark(&mut self, mut from: Vec<usize>) {
+        while let Some(i) = from.pop() {
+            if mem::replace(&mut self.live[i], true) {
+                continue;
+            }
+            let index = &self.index;
+            self.nodes[i].trace(&mut |edge| {
+                if let Some(&j) = target(&edge).and_then(|addr| index.get(&addr)) {
+                    from.push(j);
+                }
+            });
+        }
+    }
+    /// The values that are not in use, in the order they were created
+    fn garbage(&self) -> Vec<Object> {
+        self.nodes
+            .iter()
+            .zip(&self.live)
+            .filter_map(|(node, &live)| match *node {
+                Node::Value(object) if !live => Some(object),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+/// Free every value on this thread that nothing in use refers to, giving
+/// how many there were
+///
+/// Those with a `__gc` metamethod, and everything they refer to, are kept
+/// alive instead, and queued to be finalized. They are freed by a later
+/// collection if they are still garbage by then.
+pub fn collect() -> usize {
+    // a full collection finds everything one in progress would
+    CYCLE.with(|cycle| cycle.borrow_mut().take());
+    let mut collected = sweep(Graph::all(), true);
+    collected += sweep_leaves(&YOUNG) + sweep_leaves(&OLD);
+    promote();
+    collected
+}
+
+/// Free the values created since the last collection that nothing in use
+/// refers to, giving how many there were, and make the rest old
+///
+/// This is a minor collection. Older values are taken to be in use, along
+/// with everything they refer to, until the next major one, which
+/// `collect` is.
+pub fn collect_young() -> usize {
+    CYCLE.with(|cycle| cycle.borrow_mut().take());
+    let young = YOUNG.with(|young| young.borrow().objects.clone());
+    let mut collected = sweep(Graph::of(young), false);
+    collected += sweep_leaves(&YOUNG);
+    promote();
+    collected
+}
+
+/// Free the values `graph` found to be garbage, giving how many there were,
+/// where they are all young unless `old`
+fn sweep(mut graph: Graph, old: bool) -> usize {
+    let finalized: Vec<usize> = (0..graph.nodes.len())
+        .filter(|&i| match graph.nodes[i] {
+            Node::Value(object) => !graph.live[i] && object.finalizes(),
+            _ => false,
+        })
+        .collect();
+    graph.mark(finalized.clone());
+    for i in finalized {
+        if let Node::Value(object) = graph.nodes[i] {
+            object.take_finalizer();
+            schedule(object.value());
+        }
+    }
+    let garbage = graph.garbage();
+    if garbage.is_empty() {
+        return 0;
+    }
+    let freed: HashSet<usize> = garbage.iter().map(|object| object.addr()).collect();
+    let forget = |generation: &RefCell<Generation>| {
+        let mut generation = generation.borrow_mut();
+        generation
+            .objects
+            .retain(|object| !freed.contains(&object.addr()));
+    };
+    YOUNG.with(forget);
+    if old {
+        OLD.with(forget);
+    }
+    // the captured variables the graph holds can be what keeps some of
+    // them referred to
+    drop(graph);
+    value::free(&garbage);
+    garbage.len()
+}
+
+/// Free the values in `generation` that refer to nothing the collector can
+/// see once nothing refers to them, giving how many there were
+fn sweep_leaves(generation: &'static LocalKey<RefCell<Generation>>) -> usize {
+    // freeing a Rust function runs whatever its captures do as they are
+    // dropped, which can create values
+    let leaves = generation.with(|gen| mem::take(&mut gen.borrow_mut().leaves));
+    let mut kept = Vec::with_capacity(leaves.len());
+    let mut collected = 0;
+    // newest first, so that those freed can leave older ones they
+    // referred to to be freed too
+    for &leaf in leaves.iter().rev() {
+        if leaf.refs() == 0 {
+            value::free(&[leaf]);
+            collected += 1;
+        } else {
+            kept.push(leaf);
+        }
+    }
+    kept.reverse();
+    generation.with(|gen| {
+        let mut gen = gen.borrow_mut();
+        let created = mem::replace(&mut gen.leaves, kept);
+        gen.leaves.extend(created);
+    });
+    collected
+}
+
+/// Do about `work` nodes and references worth of collecting garbage,
+/// giving whether that finished a collection, and how many values it freed
+///
+/// A collection is started if none is in progress.
+pub fn step(work: usize) -> Option<usize> {
+    let mut cycle = CYCLE
+        .with(|cycle| cycle.borrow_mut().take())
+        .unwrap_or_else(Cycle::new);
+    if !cycle.step(work) {
+        CYCLE.with(|slot| *slot.borrow_mut() = Some(cycle));
+        return None;
+    }
+    let collected = sweep(Graph::of(cycle.unmarked()), true);
+    Some(collected + sweep_leaves(&YOUNG) + sweep_leaves(&OLD))
+}
+
+/// The values on this thread that nothing in use refers to, and that refer
+/// to other values, grouped by which of them refer to each other, directly
+/// or not
+pub fn find_garbage() -> Vec<Vec<Value>> {
+    let graph = Graph::all();
+    // join the groups of the nodes each node refers to, where each group
+    // is named by one of its nodes
+    let mut group: Vec<usize> = (0..graph.nodes.len()).collect();
+    for (i, node) in graph.nodes.iter().enumerate() {
+        if graph.live[i] {
+            continue;
+        }
+        // what is in use, like the globals, doesn't join what refers to it
+        node.trace(
+            &mut |edge| match target(&edge).and_then(|addr| graph.index.get(&addr)) {
+                Some(&j) if !graph.live[j] => {
+                    let (a, b) = (find(&mut group, i), find(&mut group, j));
+                    group[a] = b;
+                }
+                _ => (),
+            },
+        );
+    }
+    let mut groups: Vec<Vec<Value>> = Vec::new();
+    let mut which = HashMap::new();
+    for object in graph.garbage() {
+        let root = find(&mut group, graph.index[&object.addr()]);
+        let n = *which.entry(root).or_insert_with(|| {
+            groups.push(Vec::new());
+            groups.len() - 1
+        });
+        groups[n].push(object.value());
+    }
+    groups
+}
+
+/// The node naming the group `i` is in, where `group` gives the node each
+/// node was joined to
+fn find(group: &mut [usize], mut i: usize) -> usize {
+    while group[i] != i {
+        group[i] = group[group[i]];
+        i = group[i];
+    }
+    i
+}
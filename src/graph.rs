@@ -0,0 +1,698 @@
+//! The modules of a project, found by following the modules each one
+//! `require`s, and the functions they define and call, without running
+//! any of them
+//!
+//! Only requires of a string constant are followed, which is how nearly
+//! every module is loaded. Each is looked for along a search path of
+//! templates as in `package.path`, where `?` is the module name with its
+//! dots made slashes.
+//!
+//! The call graph is a guess from names alone. A function is named after
+//! what it is assigned to, as in `function M.f()`, `local function f()`
+//! or `local M = {f = function() end}`, or for the fields of a table the
+//! module returns, after the last part of the module's name, as in
+//! `fmt.wrap` in module `lib.fmt`. A call is to the function of the name
+//! called in the same module. A call through a local given a
+//! module, as in `local m = require "m"` and then `m.f()`, is to that
+//! module's function with the name called after the module's own name,
+//! here `M.f` or `M:f`. Anonymous functions count as part of the function
+//! they are in, and calls of functions that are passed around are not
+//! found.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use ast::{Ast, Block, Expr, ExprKind, FieldKind, FuncId, Stat, StatKind};
+use parser;
+
+/// The search path `looa` uses for `-l`, which finds `a.b` at `a/b.lua`
+/// or `a/b/init.lua`
+pub const DEFAULT_PATH: &str = "./?.lua;./?/init.lua";
+
+/// A source file of the project
+#[derive(Clone, Debug)]
+pub struct Module {
+    /// the name it is required by, or the path it was added by if nothing
+    /// on the search path names it
+    pub name: String,
+    pub path: PathBuf,
+    pub requires: Vec<Require>,
+    /// the functions it defines, the first being its main chunk
+    pub functions: Vec<Function>,
+    /// why it couldn't be read or parsed, in which case it requires and
+    /// defines nothing
+    pub error: Option<String>,
+    /// the locals given a module by a `require`, with the module's name
+    aliases: HashMap<String, String>,
+}
+
+/// A `require` of a string constant
+#[derive(Clone, Debug)]
+pub struct Require {
+    pub name: String,
+    pub line: u32,
+    /// the function of its module that requires it
+    pub from: usize,
+    /// the module it loads, or `None` if none was found on the search path
+    pub module: Option<usize>,
+}
+
+/// A function, named by what it is assigned to
+#[derive(Clone, Debug)]
+pub struct Function {
+    /// the name, like `M.f`, `M:method` or `f`, which is empty for the
+    /// main chunk
+    pub name: String,
+    pub line: u32,
+    pub calls: Vec<Call>,
+}
+
+/// A call of a named function
+#[derive(Clone, Debug)]
+pub struct Call {
+    /// the name called, like `print`, `M.f` or `obj:method`
+    pub name: String,
+    pub line: u32,
+    /// the module and function called, if it is one of the project's
+    pub target: Option<(usize, usize)>,
+}
+
+/// The modules found so far, and how they depend on each other
+pub struct Graph {
+    /// the templates of the search path
+    path: Vec<String>,
+    modules: Vec<Module>,
+    /// the module of each file, by its canonical path
+    files: HashMap<PathBuf, usize>,
+}
+
+impl Graph {
+    /// An empty graph finding modules along `path`, a list of templates
+    /// separated by `;`
+    pub fn new(path: &str) -> Graph {
+        Graph {
+            path: path
+                .split(';')
+                .filter(|template| template.contains('?'))
+                .map(str::to_string)
+                .collect(),
+            modules: Vec::new(),
+            files: HashMap::new(),
+        }
+    }
+    pub fn modules(&self) -> &[Module] {
+        &self.modules
+    }
+    /// Add the module at `path`, and every module it requires, however
+    /// indirectly, giving the index of the first
+    ///
+    /// A file already added is not added again.
+    pub fn add(&mut self, path: &Path) -> usize {
+        let module = self.module(path, None);
+        self.link();
+        module
+    }
+    /// The file the module `name` is loaded from, which is the first the
+    /// search path gives that exists
+    pub fn resolve(&self, name: &str) -> Option<PathBuf> {
+        let name = name.replace('.', "/");
+        self.path
+            .iter()
+            .map(|template| PathBuf::from(template.replace('?', &name)))
+            .find(|path| path.is_file())
+    }
+    /// The modules that nothing in `roots` requires, however indirectly
+    pub fn unused(&self, roots: &[usize]) -> Vec<usize> {
+        let mut used = vec![false; self.modules.len()];
+        let mut stack = roots.to_vec();
+        while let Some(module) = stack.pop() {
+            if used[module] {
+                continue;
+            }
+            used[module] = true;
+            stack.extend(
+                self.modules[module]
+                    .requires
+                    .iter()
+                    .filter_map(|r| r.module),
+            );
+        }
+        (0..self.modules.len()).filter(|&m| !used[m]).collect()
+    }
+
+    /// The graph in the DOT language of Graphviz, with an edge from each
+    /// module to each it requires, or if `calls` is set, with a node for
+    /// each function, in a cluster for each module
+    ///
+    /// In a call graph, requires are dashed edges from the function that
+    /// requires a module to the module's main chunk. Modules that weren't
+    /// found are dashed nodes.
+    pub fn dot(&self, calls: bool) -> String {
+        let mut out = String::from("digraph modules {\n");
+        for (m, module) in self.modules.iter().enumerate() {
+            if !calls {
+                let _ = writeln!(out, "    {};", quote(&module.name));
+            } else {
+                let _ = writeln!(out, "    subgraph {} {{", quote(&format!("cluster_{}", m)));
+                let _ = writeln!(out, "        label = {};", quote(&module.name));
+                for f in 0..module.functions.len() {
+                    let _ = writeln!(
+                        out,
+                        "        {} [label = {}];",
+                        self.node(m, f),
+                        quote(self.label(m, f))
+                    );
+                }
+                out.push_str("    }\n");
+            }
+            for require in &module.requires {
+                let (from, to) = match require.module {
+                    Some(to) if calls => (self.node(m, require.from), self.node(to, 0)),
+                    Some(to) => (quote(&module.name), quote(&self.modules[to].name)),
+                    None => {
+                        let missing = quote(&require.name);
+                        let _ = writeln!(out, "    {} [style = dashed];", missing);
+                        let from = if calls {
+                            self.node(m, require.from)
+                        } else {
+                            quote(&module.name)
+                        };
+                        (from, missing)
+                    }
+                };
+                let style = if calls { " [style = dashed]" } else { "" };
+                let _ = writeln!(out, "    {} -> {}{};", from, to, style);
+            }
+            if calls {
+                for (f, function) in module.functions.iter().enumerate() {
+                    for &(to, g) in function.calls.iter().filter_map(|c| c.target.as_ref()) {
+                        let _ = writeln!(out, "    {} -> {};", self.node(m, f), self.node(to, g));
+                    }
+                }
+            }
+        }
+        out.push_str("}\n");
+        out
+    }
+    /// The graph as JSON, an object with a `modules` array giving each
+    /// module's name, path, requires and error, and if `calls` is set, its
+    /// functions with the calls they make
+    ///
+    /// Modules are referred to by name and functions by module and name,
+    /// or `null` where they weren't found.
+    pub fn json(&self, calls: bool) -> String {
+        let mut out = String::from("{\"modules\": [");
+        for (m, module) in self.modules.iter().enumerate() {
+            if m > 0 {
+                out.push(',');
+            }
+            let _ = write!(
+                out,
+                "\n  {{\"name\": {}, \"path\": {}, \"error\": {}, \"requires\": [",
+                quote(&module.name),
+                quote(&module.path.to_string_lossy()),
+                module
+                    .error
+                    .as_ref()
+                    .map_or("null".to_string(), |e| quote(e))
+            );
+            for (i, require) in module.requires.iter().enumerate() {
+                let _ = write!(
+                    out,
+                    "{}\n    {{\"name\": {}, \"line\": {}, \"path\": {}}}",
+                    if i > 0 { "," } else { "" },
+                    quote(&require.name),
+                    require.line,
+                    require.module.map_or("null".to_string(), |to| {
+                        quote(&self.modules[to].path.to_string_lossy())
+                    })
+                );
+            }
+            out.push_str(if module.requires.is_empty() {
+                "]"
+            } else {
+                "\n  ]"
+            });
+            if calls {
+                out.push_str(", \"functions\": [");
+                for (f, function) in module.functions.iter().enumerate() {
+                    let _ = write!(
+                        out,
+                        "{}\n    {{\"name\": {}, \"line\": {}, \"calls\": [",
+                        if f > 0 { "," } else { "" },
+                        quote(&function.name),
+                        function.line,
+                    );
+                    for (i, call) in function.calls.iter().enumerate() {
+                        let target = match call.target {
+                            Some((to, g)) => format!(
+                                "{{\"module\": {}, \"name\": {}}}",
+                                quote(&self.modules[to].name),
+                                quote(&self.modules[to].functions[g].name)
+                            ),
+                            None => "null".to_string(),
+                        };
+                        let _ = write!(
+                            out,
+                            "{}\n      {{\"name\": {}, \"line\": {}, \"function\": {}}}",
+                            if i > 0 { "," } else { "" },
+                            quote(&call.name),
+                            call.line,
+                            target
+                        );
+                    }
+                    out.push_str(if function.calls.is_empty() {
+                        "]}"
+                    } else {
+                        "\n    ]}"
+                    });
+                }
+                out.push_str("\n  ]");
+            }
+            out.push('}');
+        }
+        out.push_str("\n]}\n");
+        out
+    }
+    /// The DOT node of function `f` of module `m`
+    fn node(&self, m: usize, f: usize) -> String {
+        let module = &self.modules[m];
+        quote(&format!("{}:{}", module.name, module.functions[f].name))
+    }
+    fn label(&self, m: usize, f: usize) -> &str {
+        match &*self.modules[m].functions[f].name {
+            "" => "main chunk",
+            name => name,
+        }
+    }
+
+    /// The module of the file at `path`, which is added along with the
+    /// modules it requires if it is new, with `name` if it was required
+    /// by that name
+    fn module(&mut self, path: &Path, name: Option<&str>) -> usize {
+        let file = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if let Some(&module) = self.files.get(&file) {
+            return module;
+        }
+        let name = name
+            .map(str::to_string)
+            .or_else(|| self.name_of(path))
+            .unwrap_or_else(|| path.to_string_lossy().into_owned());
+        let index = self.modules.len();
+        self.modules.push(scan(name, path));
+        self.files.insert(file, index);
+        for r in 0..self.modules[index].requires.len() {
+            let name = self.modules[index].requires[r].name.clone();
+            if let Some(found) = self.resolve(&name) {
+                let module = self.module(&found, Some(&name));
+                self.modules[index].requires[r].module = Some(module);
+            }
+        }
+        index
+    }
+    /// The shortest name the search path finds `path` by, if any does
+    fn name_of(&self, path: &Path) -> Option<String> {
+        let path = path.to_str()?.trim_start_matches("./");
+        self.path
+            .iter()
+            .filter_map(|template| {
+                let template = template.trim_start_matches("./");
+                let at = template.find('?')?;
+                let (prefix, suffix) = (&template[..at], &template[at + 1..]);
+                if path.len() > prefix.len() + suffix.len()
+                    && path.starts_with(prefix)
+                    && path.ends_with(suffix)
+                {
+                    Some(path[prefix.len()..path.len() - suffix.len()].replace('/', "."))
+                } else {
+                    None
+                }
+            })
+            .min_by_key(String::len)
+    }
+    /// Find the functions each call is of, now that more modules may have
+    /// been added
+    fn link(&mut self) {
+        let mut targets = Vec::new();
+        for (m, module) in self.modules.iter().enumerate() {
+            for (f, function) in module.functions.iter().enumerate() {
+                for (c, call) in function.calls.iter().enumerate() {
+                    targets.push(((m, f, c), self.target(m, &call.name)));
+                }
+            }
+        }
+        for ((m, f, c), target) in targets {
+            self.modules[m].functions[f].calls[c].target = target;
+        }
+    }
+    /// The function a call of `name` in module `m` is of
+    fn target(&self, m: usize, name: &str) -> Option<(usize, usize)> {
+        let module = &self.modules[m];
+        let name = name.replace(':', ".");
+        if let Some(f) = find(module, |function| function == name) {
+            return Some((m, f));
+        }
+        let (alias, rest) = match name.find('.') {
+            Some(at) => (&name[..at], &name[at..]),
+            None => return None,
+        };
+        let required = module.aliases.get(alias)?;
+        let to = module
+            .requires
+            .iter()
+            .find(|require| require.name == *required)?
+            .module?;
+        let f = find(&self.modules[to], |function| {
+            function.find('.').is_some_and(|at| function[at..] == *rest)
+        })?;
+        Some((to, f))
+    }
+}
+
+/// The function of `module` whose name, with methods named like fields,
+/// matches
+fn find<F>(module: &Module, matches: F) -> Option<usize>
+where
+    F: Fn(&str) -> bool,
+{
+    (1..module.functions.len()).find(|&f| matches(&module.functions[f].name.replace(':', ".")))
+}
+
+/// `s` as a string in JSON, which DOT quotes the same way
+fn quote(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// Read and parse the module at `path`, finding what it requires and
+/// defines
+fn scan(name: String, path: &Path) -> Module {
+    let mut module = Module {
+        name,
+        path: path.to_path_buf(),
+        requires: Vec::new(),
+        functions: vec![Function {
+            name: String::new(),
+            line: 0,
+            calls: Vec::new(),
+        }],
+        error: None,
+        aliases: HashMap::new(),
+    };
+    let source = match fs::read(path) {
+        Ok(source) => source,
+        Err(err) => {
+            module.error = Some(format!("cannot read {}: {}", path.display(), err));
+            return module;
+        }
+    };
+    // a `#!` line is blanked out, as when the file is run
+    let source = if source.starts_with(b"#") {
+        let end = source
+            .iter()
+            .position(|&c| c == b'\n')
+            .unwrap_or(source.len());
+        source[end..].to_vec()
+    } else {
+        source
+    };
+    match parser::parse_chunk(&source, &path.to_string_lossy()) {
+        Ok(ast) => {
+            let mut scanner = Scanner {
+                ast: &ast,
+                module: &mut module,
+                function: 0,
+                depth: 0,
+            };
+            scanner.block(&ast[ast.main()].body);
+        }
+        Err(err) => module.error = Some(err.to_string()),
+    }
+    module
+}
+
+/// A walk of a module's tree, noting its requires, functions and calls
+struct Scanner<'a> {
+    ast: &'a Ast,
+    module: &'a mut Module,
+    /// the function of the module being walked
+    function: usize,
+    /// how many function bodies the walk is in, named or not
+    depth: usize,
+}
+impl<'a> Scanner<'a> {
+    fn block(&mut self, block: &Block) {
+        let ast = self.ast;
+        for stat in &ast[block.stats] {
+            self.stat(stat);
+        }
+        if let Some(values) = block.ret {
+            let values = &ast[values];
+            match values.split_first() {
+                // what the module gives `require`
+                Some((first, rest)) if self.depth == 0 => {
+                    let name = self.module.name.rsplit('.').next().unwrap_or("");
+                    self.value(first, Some(name.to_string()));
+                    self.exprs(rest);
+                }
+                _ => self.exprs(values),
+            }
+        }
+    }
+    fn stat(&mut self, stat: &Stat) {
+        let ast = self.ast;
+        match stat.kind {
+            StatKind::Assign(targets, values) => {
+                let targets = &ast[targets];
+                for target in targets {
+                    self.expr(target);
+                }
+                for (i, value) in ast[values].iter().enumerate() {
+                    let name = targets.get(i).and_then(|target| path(ast, target));
+                    self.value(value, name);
+                }
+            }
+            StatKind::Local(names, values) => {
+                let names = &ast[names];
+                for (i, value) in ast[values].iter().enumerate() {
+                    let name = names.get(i).map(|name| name.to_string());
+                    if let (Some(name), Some(required)) = (&name, require(ast, value)) {
+                        self.module
+                            .aliases
+                            .insert(name.clone(), required.to_string());
+                    }
+                    self.value(value, name);
+                }
+            }
+            StatKind::Call(call) => self.expr(&ast[call]),
+            StatKind::Do(ref body) => self.block(body),
+            StatKind::While(cond, ref body) | StatKind::Repeat(ref body, cond) => {
+                self.expr(&ast[cond]);
+                self.block(body);
+            }
+            StatKind::If(branches, ref otherwise) => {
+                for &(cond, ref body) in &ast[branches] {
+                    self.expr(&ast[cond]);
+                    self.block(body);
+                }
+                if let Some(ref otherwise) = *otherwise {
+                    self.block(otherwise);
+                }
+            }
+            StatKind::NumericFor {
+                start,
+                limit,
+                step,
+                ref body,
+                ..
+            } => {
+                self.expr(&ast[start]);
+                self.expr(&ast[limit]);
+                if let Some(step) = step {
+                    self.expr(&ast[step]);
+                }
+                self.block(body);
+            }
+            StatKind::GenericFor {
+                exprs, ref body, ..
+            } => {
+                self.exprs(&ast[exprs]);
+                self.block(body);
+            }
+            StatKind::Function(ref name, func) => {
+                let mut path = ast[name.path].join(".");
+                if let Some(ref method) = name.method {
+                    path = format!("{}:{}", path, method);
+                }
+                self.function(func, Some(path), stat.loc.pos.line);
+            }
+            StatKind::LocalFunction(ref name, func) => {
+                self.function(func, Some(name.to_string()), stat.loc.pos.line)
+            }
+            StatKind::Label(_) | StatKind::Goto(_) | StatKind::Break => {}
+        }
+    }
+    /// Walk `expr`, the value of what `name` is the path of
+    fn value(&mut self, expr: &Expr, name: Option<String>) {
+        let ast = self.ast;
+        match (&expr.kind, name) {
+            (&ExprKind::Function(func), name) => self.function(func, name, expr.loc.pos.line),
+            (&ExprKind::Table(fields), Some(name)) => {
+                for field in &ast[fields] {
+                    match field.kind {
+                        FieldKind::Named(ref key, val) => {
+                            self.value(&ast[val], Some(format!("{}.{}", name, key)))
+                        }
+                        FieldKind::Positional(val) => self.expr(&ast[val]),
+                        FieldKind::Indexed(key, val) => {
+                            self.expr(&ast[key]);
+                            self.expr(&ast[val]);
+                        }
+                    }
+                }
+            }
+            _ => self.expr(expr),
+        }
+    }
+    /// Walk the body of `func`, as a function of its own if it is named
+    fn function(&mut self, func: FuncId, name: Option<String>, line: u32) {
+        let outer = self.function;
+        if let Some(name) = name {
+            self.function = self.module.functions.len();
+            self.module.functions.push(Function {
+                name,
+                line,
+                calls: Vec::new(),
+            });
+        }
+        self.depth += 1;
+        self.block(&self.ast[func].body);
+        self.depth -= 1;
+        self.function = outer;
+    }
+    fn exprs(&mut self, exprs: &[Expr]) {
+        for expr in exprs {
+            self.expr(expr);
+        }
+    }
+    fn expr(&mut self, expr: &Expr) {
+        let ast = self.ast;
+        let line = expr.loc.pos.line;
+        match expr.kind {
+            ExprKind::Nil
+            | ExprKind::True
+            | ExprKind::False
+            | ExprKind::Number(_)
+            | ExprKind::String(_)
+            | ExprKind::Vararg
+            | ExprKind::Name(_) => {}
+            ExprKind::Function(func) => self.function(func, None, line),
+            ExprKind::Table(fields) => {
+                for field in &ast[fields] {
+                    match field.kind {
+                        FieldKind::Named(_, val) | FieldKind::Positional(val) => {
+                            self.expr(&ast[val])
+                        }
+                        FieldKind::Indexed(key, val) => {
+                            self.expr(&ast[key]);
+                            self.expr(&ast[val]);
+                        }
+                    }
+                }
+            }
+            ExprKind::Index(obj, key) => {
+                self.expr(&ast[obj]);
+                self.expr(&ast[key]);
+            }
+            ExprKind::Call(func, args) => {
+                if let Some(name) = require(ast, expr) {
+                    self.module.requires.push(Require {
+                        name: name.to_string(),
+                        line,
+                        from: self.function,
+                        module: None,
+                    });
+                } else if let Some(name) = path(ast, &ast[func]) {
+                    self.call(name, line);
+                }
+                self.expr(&ast[func]);
+                self.exprs(&ast[args]);
+            }
+            ExprKind::Method(obj, ref method, args) => {
+                if let Some(name) = path(ast, &ast[obj]) {
+                    self.call(format!("{}:{}", name, method), line);
+                }
+                self.expr(&ast[obj]);
+                self.exprs(&ast[args]);
+            }
+            ExprKind::Paren(inner) | ExprKind::Unary(_, inner) => self.expr(&ast[inner]),
+            ExprKind::Binary(_, lhs, rhs) => {
+                self.expr(&ast[lhs]);
+                self.expr(&ast[rhs]);
+            }
+        }
+    }
+    fn call(&mut self, name: String, line: u32) {
+        self.module.functions[self.function].calls.push(Call {
+            name,
+            line,
+            target: None,
+        });
+    }
+}
+
+/// The module `expr` requires, if it is a `require` of a string constant
+fn require<'a>(ast: &'a Ast, expr: &Expr) -> Option<&'a str> {
+    let (func, args) = match expr.kind {
+        ExprKind::Call(func, args) => (&ast[func], &ast[args]),
+        _ => return None,
+    };
+    match (&func.kind, args.first().map(|arg| &arg.kind)) {
+        (ExprKind::Name(name), Some(ExprKind::String(bytes))) if &**name == "require" => {
+            ::std::str::from_utf8(bytes).ok()
+        }
+        _ => None,
+    }
+}
+
+/// The dotted path `expr` is, like `a.b.c`, if it is a name indexed by
+/// field names
+fn path(ast: &Ast, expr: &Expr) -> Option<String> {
+    match expr.kind {
+        ExprKind::Name(ref name) => Some(name.to_string()),
+        ExprKind::Index(obj, key) => match ast[key].kind {
+            ExprKind::String(ref key) if is_name(key) => Some(format!(
+                "{}.{}",
+                path(ast, &ast[obj])?,
+                String::from_utf8_lossy(key)
+            )),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn is_name(bytes: &[u8]) -> bool {
+    match bytes.split_first() {
+        Some((&first, rest)) => {
+            (first.is_ascii_alphabetic() || first == b'_')
+                && rest.iter().all(|&c| c.is_ascii_alphanumeric() || c == b'_')
+        }
+        None => false,
+    }
+}
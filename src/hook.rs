@@ -0,0 +1,278 @@
+//! Debug hooks, which are called as Lua code runs, for profilers, debuggers
+//! and coverage tools
+//!
+//! A hook can be told when a Lua function is called or returns, when a new
+//! line starts running, and after every so many steps, counted as the
+//! instruction limit counts them. The VM follows its instructions' lines and
+//! the interpreter the lines its statements start on, and both report a line
+//! again when a loop goes back to it. Events are not reported while a hook
+//! runs.
+//!
+//! Each state has its own hook, which its limits hold so that the functions
+//! it loads find it however they are called, and which only sees those
+//! functions run. The state removes it when it is closed.
+
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use ast::Name;
+use error::Result;
+use limits::Limits;
+use value::{StringTable, Value};
+
+/// A variable shared with the function it belongs to
+type Variable = Rc<RefCell<Value>>;
+
+/// Something a hook is called for
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum HookEvent {
+    /// a Lua function was called, before its body runs
+    Call,
+    /// a Lua function was called by a tail call, replacing its caller, whose
+    /// return is not reported
+    TailCall,
+    /// a Lua function is returning
+    Return,
+    /// a line is about to run, or a loop went back to it
+    Line(u32),
+    /// the number of steps the hook asked for have run
+    Count,
+}
+impl HookEvent {
+    /// The name `debug.sethook` gives hooks for the event
+    pub fn name(self) -> &'static str {
+        match self {
+            HookEvent::Call => "call",
+            HookEvent::TailCall => "tail call",
+            HookEvent::Return => "return",
+            HookEvent::Line(_) => "line",
+            HookEvent::Count => "count",
+        }
+    }
+}
+
+/// Which events a hook is called for
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct HookMask {
+    /// calls, including tail calls
+    pub call: bool,
+    pub ret: bool,
+    pub line: bool,
+    /// how many steps run between count events, or 0 for none
+    pub count: u32,
+}
+impl HookMask {
+    fn wants(self, event: HookEvent) -> bool {
+        match event {
+            HookEvent::Call | HookEvent::TailCall => self.call,
+            HookEvent::Return => self.ret,
+            HookEvent::Line(_) => self.line,
+            HookEvent::Count => self.count > 0,
+        }
+    }
+    fn is_empty(self) -> bool {
+        !self.call && !self.ret && !self.line && self.count == 0
+    }
+}
+
+/// What a hook is told about the event it is called for
+pub struct HookInfo<'a> {
+    event: HookEvent,
+    chunk: &'a str,
+    line: Option<u32>,
+    /// takes a snapshot of the running function, which only debuggers need
+    frame: &'a dyn Fn() -> FrameInfo,
+}
+impl<'a> HookInfo<'a> {
+    pub fn event(&self) -> HookEvent {
+        self.event
+    }
+    /// The name of the chunk the running function is from
+    pub fn chunk(&self) -> &'a str {
+        self.chunk
+    }
+    /// The line running, or where the function starts for calls, unless
+    /// the function was loaded from a stripped binary chunk
+    pub fn line(&self) -> Option<u32> {
+        self.line
+    }
+    /// The variables of the running function, which takes a snapshot of
+    /// them
+    pub fn frame(&self) -> FrameInfo {
+        (self.frame)()
+    }
+}
+
+/// A running Lua function's variables, as they were when it was last
+/// reported to a hook
+///
+/// The variables are shared with the function where they live in cells,
+/// which is all of them in the interpreter, and in the VM those captured by
+/// nested functions and its upvalues, so that they can be seen changing and
+/// can be assigned. Its other locals are copied.
+#[derive(Clone)]
+pub struct FrameInfo {
+    pub(crate) chunk: Rc<str>,
+    /// the line the function is defined on, which is 0 for a chunk
+    pub(crate) defined: u32,
+    pub(crate) line: Option<u32>,
+    /// the locals in scope, innermost last
+    pub(crate) locals: Vec<(Name, Variable)>,
+    pub(crate) upvalues: Vec<(Name, Variable)>,
+    /// the table the function reads globals from
+    pub(crate) env: Value,
+    /// the limits of the state the function belongs to
+    pub(crate) limits: Rc<Limits>,
+    /// and its string table
+    pub(crate) strings: Rc<StringTable>,
+}
+impl FrameInfo {
+    /// The name of the chunk the function is from
+    pub fn chunk(&self) -> &str {
+        &self.chunk
+    }
+    /// The line the function is defined on, which is 0 for a main chunk
+    pub fn defined(&self) -> u32 {
+        self.defined
+    }
+    /// The line it was running, unless it was loaded from a stripped
+    /// binary chunk
+    pub fn line(&self) -> Option<u32> {
+        self.line
+    }
+    /// The names and values of the locals in scope, innermost last, which
+    /// has more than one with the same name if some are shadowed
+    pub fn locals(&self) -> Vec<(&str, Value)> {
+        variables(&self.locals)
+    }
+    /// The names and values of the enclosing functions' variables it can see
+    pub fn upvalues(&self) -> Vec<(&str, Value)> {
+        variables(&self.upvalues)
+    }
+    /// Assign the innermost variable called `name`, local or upvalue,
+    /// giving whether there is one
+    ///
+    /// In the VM, assigning a local that no nested function captured only
+    /// changes the snapshot.
+    pub fn set(&self, name: &str, val: Value) -> bool {
+        let mut found = self.locals.iter().rev().chain(self.upvalues.iter().rev());
+        match found.find(|(local, _)| &**local == name) {
+            Some((_, cell)) => {
+                *cell.borrow_mut() = val;
+                true
+            }
+            None => false,
+        }
+    }
+    /// The variables it can see, outermost first, which evaluating code in
+    /// the frame starts with
+    pub(crate) fn scope(&self) -> Vec<(Name, Variable)> {
+        self.upvalues.iter().chain(&self.locals).cloned().collect()
+    }
+}
+
+fn variables(cells: &[(Name, Variable)]) -> Vec<(&str, Value)> {
+    cells
+        .iter()
+        .map(|(name, cell)| (&**name, cell.borrow().clone()))
+        .collect()
+}
+
+type HookFn = dyn Fn(&HookInfo) -> Result<()>;
+
+struct Hook {
+    mask: HookMask,
+    func: Rc<HookFn>,
+    /// the function `debug.sethook` was given, which `debug.gethook` gives
+    /// back
+    value: Option<Value>,
+}
+
+/// A state's hook, if it has one
+#[derive(Default)]
+pub(crate) struct Hooks {
+    hook: RefCell<Option<Hook>>,
+    /// whether there is a hook and it is not running, which is checked at
+    /// every step
+    active: Cell<bool>,
+    /// how many more steps until the next count event
+    countdown: Cell<u32>,
+}
+impl Hooks {
+    /// Call `func` for the events in `mask`, replacing any hook already
+    /// set, with `value` the Lua function it calls, if there is one
+    pub fn set(&self, mask: HookMask, func: Rc<HookFn>, value: Option<Value>) {
+        if mask.is_empty() {
+            return self.remove();
+        }
+        self.countdown.set(mask.count);
+        let old = self.hook.replace(Some(Hook { mask, func, value }));
+        self.active.set(true);
+        // dropping a Lua function can run code, which is done once the
+        // new hook is in place
+        drop(old);
+    }
+    /// Stop calling the hook
+    pub fn remove(&self) {
+        self.active.set(false);
+        let old = self.hook.borrow_mut().take();
+        drop(old);
+    }
+    /// The events the hook is called for, and the Lua function it calls,
+    /// if there is one
+    pub fn get(&self) -> Option<(HookMask, Option<Value>)> {
+        let hook = self.hook.borrow();
+        hook.as_ref().map(|hook| (hook.mask, hook.value.clone()))
+    }
+    /// Whether events are reported, which they are while there is a hook
+    /// and it is not running
+    #[inline]
+    pub fn active(&self) -> bool {
+        self.active.get()
+    }
+    /// Count a step towards the next count event, giving whether it is due
+    pub fn count(&self) -> bool {
+        let count = self
+            .hook
+            .borrow()
+            .as_ref()
+            .map_or(0, |hook| hook.mask.count);
+        if count == 0 {
+            return false;
+        }
+        match self.countdown.get() {
+            0 | 1 => {
+                self.countdown.set(count);
+                true
+            }
+            left => {
+                self.countdown.set(left - 1);
+                false
+            }
+        }
+    }
+    /// Call the hook for `event`, if it asked for it, with the running
+    /// function at `line` in `chunk`, and `frame` taking a snapshot of it
+    pub fn report(
+        &self,
+        event: HookEvent,
+        chunk: &str,
+        line: Option<u32>,
+        frame: &dyn Fn() -> FrameInfo,
+    ) -> Result<()> {
+        let func = match *self.hook.borrow() {
+            Some(ref hook) if hook.mask.wants(event) => hook.func.clone(),
+            _ => return Ok(()),
+        };
+        self.active.set(false);
+        let result = func(&HookInfo {
+            event,
+            chunk,
+            line,
+            frame,
+        });
+        // the hook may have been removed or replaced while it ran
+        self.active.set(self.hook.borrow().is_some());
+        result
+    }
+}
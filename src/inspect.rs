@@ -0,0 +1,147 @@
+//! Writing values out for people to read, with the contents of tables
+//! rather than their addresses
+//!
+//! Tables are written as constructors, `{ 1, 2, name = "x", [true] = 3 }`,
+//! with the sequence first and then the other keys in order: numbers,
+//! strings, booleans, and then anything else by its address. A table with
+//! a metatable ends with a `<metatable>` entry. Tables that don't fit on
+//! a line are written one entry per line. Tables deeper than the depth
+//! asked for are written `{...}`, and one within itself as `<cycle>`.
+
+use std::cmp::Ordering;
+
+use error::Result;
+use lexer::TokenKind;
+use number;
+use value::{ConvertValue, LuaString, Type, Value};
+
+/// How long a table written on one line may be, indentation aside
+const WIDTH: usize = 72;
+
+/// `val` written out, going at most `depth` tables deep, where 0 writes
+/// even a table at the top as `{...}`
+pub fn inspect(val: &Value, depth: usize) -> Result<String> {
+    let mut path = Vec::new();
+    write(val, depth, &mut path, 0)
+}
+
+/// `val` written at `indent` levels of indentation, within the tables in
+/// `path`
+fn write(val: &Value, depth: usize, path: &mut Vec<Value>, indent: usize) -> Result<String> {
+    match val.type_of() {
+        Type::String => Ok(quote(LuaString::from_value(val).expect("checked type"))),
+        Type::Table => table(val, depth, path, indent),
+        _ => Ok(val.to_string()),
+    }
+}
+
+fn table(val: &Value, depth: usize, path: &mut Vec<Value>, indent: usize) -> Result<String> {
+    if path.iter().any(|outer| outer == val) {
+        return Ok("<cycle>".to_string());
+    }
+    let mut key = Value::nil();
+    let mut others = Vec::new();
+    let len = val.raw_len().unwrap_or(0).max(0);
+    while let Some((next, _)) = val.raw_next(&key)? {
+        key = next;
+        let in_sequence = key
+            .as_integer()
+            .is_some_and(|i| key.type_of() == Type::Number && 1 <= i && i <= len);
+        if !in_sequence {
+            others.push(key.clone());
+        }
+    }
+    let meta = val.get_metatable();
+    if len == 0 && others.is_empty() && meta.is_none() {
+        return Ok("{}".to_string());
+    }
+    if depth == 0 {
+        return Ok("{...}".to_string());
+    }
+    others.sort_by(key_order);
+    path.push(val.clone());
+    let mut entries = Vec::new();
+    for i in 1..=len {
+        let item = val.raw_get(&Value::new(i));
+        entries.push(write(&item, depth - 1, path, indent + 1)?);
+    }
+    for key in others {
+        let item = val.raw_get(&key);
+        let item = write(&item, depth - 1, path, indent + 1)?;
+        entries.push(format!("{} = {}", key_text(&key)?, item));
+    }
+    if let Some(meta) = meta {
+        let meta = write(&meta, depth - 1, path, indent + 1)?;
+        entries.push(format!("<metatable> = {}", meta));
+    }
+    path.pop();
+    let length: usize = entries.iter().map(|entry| entry.len() + 2).sum();
+    if length <= WIDTH && !entries.iter().any(|entry| entry.contains('\n')) {
+        return Ok(format!("{{ {} }}", entries.join(", ")));
+    }
+    let inner = "  ".repeat(indent + 1);
+    let mut out = "{\n".to_string();
+    for entry in entries {
+        out.push_str(&format!("{}{},\n", inner, entry));
+    }
+    out.push_str(&"  ".repeat(indent));
+    out.push('}');
+    Ok(out)
+}
+
+/// A key as written before ` = `, bare if it is a name and otherwise in
+/// brackets
+fn key_text(key: &Value) -> Result<String> {
+    if let Some(bytes) = LuaString::from_value(key) {
+        let is_name = bytes
+            .first()
+            .is_some_and(|&c| c.is_ascii_alphabetic() || c == b'_')
+            && bytes
+                .iter()
+                .all(|&c| c.is_ascii_alphanumeric() || c == b'_')
+            && TokenKind::keyword(bytes).is_none();
+        if is_name {
+            return Ok(String::from_utf8_lossy(bytes).into_owned());
+        }
+    }
+    let mut path = Vec::new();
+    Ok(format!("[{}]", write(key, 0, &mut path, 0)?))
+}
+
+/// The order keys are written in after the sequence
+fn key_order(a: &Value, b: &Value) -> Ordering {
+    let rank = |key: &Value| match key.type_of() {
+        Type::Number => 0,
+        Type::String => 1,
+        Type::Boolean => 2,
+        _ => 3,
+    };
+    rank(a)
+        .cmp(&rank(b))
+        .then_with(|| match (a.number_value(), b.number_value()) {
+            (Some(x), Some(y)) => number::cmp(x, y).unwrap_or(Ordering::Equal),
+            _ => match (LuaString::from_value(a), LuaString::from_value(b)) {
+                (Some(x), Some(y)) => x.cmp(y),
+                _ => a.to_string().cmp(&b.to_string()),
+            },
+        })
+}
+
+/// `bytes` as a string literal, with escapes for the bytes that aren't
+/// printable
+fn quote(bytes: &[u8]) -> String {
+    let mut out = vec![b'"'];
+    for &c in bytes {
+        match c {
+            b'"' => out.extend_from_slice(b"\\\""),
+            b'\\' => out.extend_from_slice(b"\\\\"),
+            b'\n' => out.extend_from_slice(b"\\n"),
+            b'\t' => out.extend_from_slice(b"\\t"),
+            b'\r' => out.extend_from_slice(b"\\r"),
+            c if c < 0x20 || c == 0x7f => out.extend(format!("\\{:03}", c).bytes()),
+            c => out.push(c),
+        }
+    }
+    out.push(b'"');
+    String::from_utf8_lossy(&out).into_owned()
+}
@@ -0,0 +1,1146 @@
+//! A tree-walking interpreter, which runs chunks by evaluating their syntax
+//! trees directly
+
+use std::cell::{OnceCell, RefCell};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use ast::{
+    Ast, BinOp, Block, Expr, ExprKind, Field, FieldKind, FieldList, FuncBody, FuncId, FuncName,
+    Location, Name, Stat, StatKind, UnOp,
+};
+use error::{Error, Result};
+use gc::Edge;
+use hook::{FrameInfo, HookEvent};
+use limits::{self, Limits};
+use number::{self, Number};
+use table::Table;
+use trace;
+use value::{
+    ArithOp, ConvertValue, Event, LuaFunction, LuaInteger, LuaNumber, MultiValue, StringTable,
+    Type, Value,
+};
+use vm::{self, UpvalSource};
+
+/// A local variable, which closures share with the scope declaring it
+type Cell = Rc<RefCell<Value>>;
+
+/// The names the functions of a chunk use from enclosing functions, found
+/// the first time a closure of each is made
+type FreeNames = RefCell<HashMap<FuncId, Rc<[Name]>>>;
+
+/// The most spare argument lists and scopes kept per thread, and the most
+/// values one can hold to be kept, so that a deep or wide call doesn't pin
+/// its memory
+const SPARE_LIMIT: usize = 32;
+const SPARE_CAPACITY: usize = 256;
+
+thread_local! {
+    /// Argument lists and scopes of calls that have returned, which later
+    /// calls reuse rather than allocating their own
+    static SPARE_ARGS: RefCell<Vec<Vec<Value>>> = const { RefCell::new(Vec::new()) };
+    static SPARE_SCOPES: RefCell<Vec<Vec<(Name, Cell)>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// An empty argument list, reused if there is one spare
+fn take_args() -> Vec<Value> {
+    SPARE_ARGS.with(|spare| spare.borrow_mut().pop().unwrap_or_default())
+}
+
+/// Keep `args` for a later call, once the values in it are dropped
+fn spare_args(mut args: Vec<Value>) {
+    args.clear();
+    if args.capacity() <= SPARE_CAPACITY {
+        SPARE_ARGS.with(|spare| {
+            let mut spare = spare.borrow_mut();
+            if spare.len() < SPARE_LIMIT {
+                spare.push(args);
+            }
+        });
+    }
+}
+
+fn take_scope() -> Vec<(Name, Cell)> {
+    SPARE_SCOPES.with(|spare| spare.borrow_mut().pop().unwrap_or_default())
+}
+
+fn spare_scope(mut scope: Vec<(Name, Cell)>) {
+    scope.clear();
+    if scope.capacity() <= SPARE_CAPACITY {
+        SPARE_SCOPES.with(|spare| {
+            let mut spare = spare.borrow_mut();
+            if spare.len() < SPARE_LIMIT {
+                spare.push(scope);
+            }
+        });
+    }
+}
+
+/// Call `func` with `args`, reusing the list once Rust functions, which
+/// only borrow it, have returned
+fn call_value(func: &Value, args: Vec<Value>) -> Result<MultiValue> {
+    match LuaFunction::from_value(func) {
+        Some(native) => {
+            let vals = native(&args);
+            spare_args(args);
+            vals
+        }
+        None => func.call(args),
+    }
+}
+
+/// A function written in Lua, along with the variables it captured
+pub struct Closure {
+    /// the syntax tree of the chunk the function is from
+    ast: Rc<Ast>,
+    func: FuncId,
+    /// the locals of enclosing functions that it uses, outermost first
+    captured: Vec<(Name, Cell)>,
+    /// shared by the closures of the chunk the function is from
+    free: Rc<FreeNames>,
+    /// the table that global variables are read from and written to
+    env: Value,
+    limits: Rc<Limits>,
+    /// the string table of the state it belongs to, which its names and
+    /// literals are interned in
+    strings: Rc<StringTable>,
+    /// whether it runs a whole chunk, which tracebacks say
+    main: bool,
+    /// the function compiled for the VM, once a coroutine has called it
+    compiled: OnceCell<Option<vm::Closure>>,
+}
+impl Closure {
+    /// The function running a whole chunk, which is the main function of
+    /// its tree
+    pub fn chunk(
+        ast: Rc<Ast>,
+        env: Value,
+        limits: Rc<Limits>,
+        strings: Rc<StringTable>,
+    ) -> Closure {
+        Closure {
+            func: ast.main(),
+            ast,
+            captured: Vec::new(),
+            free: Rc::new(RefCell::new(HashMap::new())),
+            env,
+            limits,
+            strings,
+            main: true,
+            compiled: OnceCell::new(),
+        }
+    }
+    /// The function running a chunk that can see the variables in `scope`,
+    /// outermost first, as a debugger evaluates code in a paused function
+    pub fn with_scope(
+        ast: Ast,
+        env: Value,
+        scope: Vec<(Name, Cell)>,
+        limits: Rc<Limits>,
+        strings: Rc<StringTable>,
+    ) -> Closure {
+        Closure {
+            captured: scope,
+            ..Closure::chunk(Rc::new(ast), env, limits, strings)
+        }
+    }
+    fn func(&self) -> &FuncBody {
+        &self.ast[self.func]
+    }
+    /// Call the closure, running any closures it tail calls in turn rather
+    /// than nested, so that tail recursion takes no space
+    pub fn call(&self, args: Vec<Value>) -> Result<MultiValue> {
+        let (mut func, mut args) = match self.run(args, false)? {
+            Flow::TailCall(func, args) => (func, args),
+            flow => return Ok(flow.into_results()),
+        };
+        loop {
+            let flow = match func.as_interpreted() {
+                Some(closure) => closure.run(args, true)?,
+                None => return call_value(&func, args),
+            };
+            match flow {
+                Flow::TailCall(next, next_args) => {
+                    func = next;
+                    args = next_args;
+                }
+                flow => return Ok(flow.into_results()),
+            }
+        }
+    }
+    /// Pass the variables it captured and its environment to `visit`, for
+    /// the collector
+    pub(crate) fn trace(&self, visit: &mut dyn FnMut(Edge)) {
+        visit(Edge::Value(&self.env));
+        for (_, cell) in &self.captured {
+            visit(Edge::Cell(cell));
+        }
+        if let Some(Some(compiled)) = self.compiled.get() {
+            compiled.trace(visit);
+        }
+    }
+    /// The closure compiled for the VM, sharing the variables this one
+    /// captured, which coroutines run so that it can yield, or `None` if it
+    /// is too big to compile
+    pub fn compiled(&self) -> Option<&vm::Closure> {
+        self.compiled
+            .get_or_init(|| {
+                let scope: Vec<Name> = self.captured.iter().map(|(name, _)| name.clone()).collect();
+                let proto =
+                    vm::compile_function(&self.ast, self.func, &scope, true, &self.strings).ok()?;
+                let upvals = proto
+                    .upvals
+                    .iter()
+                    .map(|upval| match upval.source {
+                        UpvalSource::Local(i) => self.captured[i as usize].1.clone(),
+                        UpvalSource::Upval(_) => unreachable!("it has no enclosing function"),
+                    })
+                    .collect();
+                Some(vm::Closure::with_upvals(
+                    proto,
+                    self.env.clone(),
+                    upvals,
+                    self.limits.clone(),
+                    self.strings.clone(),
+                ))
+            })
+            .as_ref()
+    }
+    /// Run the body, stopping at a tail call, where `tail` is whether this
+    /// was called by one
+    fn run(&self, args: Vec<Value>, tail: bool) -> Result<Flow> {
+        self.limits.enter_native()?;
+        limits::enter_calls(1);
+        let func = self.func();
+        let defined = if self.main { 0 } else { func.loc.pos.line };
+        trace::enter(&func.loc.chunk, defined, func.loc.pos.line);
+        let mut locals = take_scope();
+        locals.extend_from_slice(&self.captured);
+        let mut frame = Frame {
+            closure: self,
+            ast: &self.ast,
+            locals,
+            varargs: Vec::new(),
+            failed: None,
+            line: None,
+        };
+        let mut args = args;
+        let params = &self.ast[func.params];
+        let named = params.len().min(args.len());
+        let mut passed = args.drain(..named);
+        for param in params {
+            frame.declare(param, passed.next().unwrap_or_else(Value::nil));
+        }
+        drop(passed);
+        // what is left over are the varargs
+        if func.vararg {
+            frame.varargs = args;
+        } else {
+            spare_args(args);
+        }
+        let flow = frame
+            .hook_call(tail)
+            .and_then(|()| frame.run_block(&func.body))
+            .and_then(|flow| match flow {
+                // the function called takes over reporting its return
+                Flow::TailCall(..) => Ok(flow),
+                flow => frame.hook_return().map(|()| flow),
+            });
+        if flow.is_err() {
+            let place = frame.failed.as_ref().unwrap_or(&func.loc);
+            if self.main {
+                trace::unwind(place, "main chunk");
+            } else {
+                trace::unwind(place, format_args!("function <{}>", func.loc));
+            }
+        }
+        limits::leave_calls(1);
+        trace::leave(1);
+        limits::leave_native();
+        flow
+    }
+}
+
+/// How a statement finished
+enum Flow {
+    Normal,
+    Break,
+    /// a jump to the label, which some enclosing block declares
+    Goto(Name),
+    Return(MultiValue),
+    /// a `return` of a call to a Lua function, which is made once the
+    /// frame returning it is gone
+    TailCall(Value, Vec<Value>),
+}
+impl Flow {
+    /// What a function ending with this returns, other than by a tail call
+    fn into_results(self) -> MultiValue {
+        match self {
+            Flow::Return(vals) => vals,
+            // the parser rejects a `break` outside of a loop
+            Flow::Normal | Flow::Break => MultiValue::new(),
+            Flow::Goto(_) => unreachable!("the parser rejects a goto without a label"),
+            Flow::TailCall(..) => unreachable!("tail calls are made by the caller"),
+        }
+    }
+}
+
+/// Somewhere a value can be assigned
+enum Place {
+    Local(Cell),
+    Global(Name),
+    Index(Value, Value),
+}
+
+/// The state of one call to a closure
+struct Frame<'a> {
+    closure: &'a Closure,
+    /// the tree of the closure's chunk, which the nodes run are from
+    ast: &'a Ast,
+    /// the variables in scope, innermost last, starting with those of
+    /// enclosing functions that the closure captured
+    locals: Vec<(Name, Cell)>,
+    /// the arguments beyond the named parameters
+    varargs: Vec<Value>,
+    /// where the error leaving the frame, if there is one, was raised or
+    /// passed through, innermost first
+    failed: Option<Location>,
+    /// the line last reported to the hook, which a loop clears when it
+    /// goes back to the start of its body
+    line: Option<u32>,
+}
+/// Its scope and varargs are kept for later calls
+impl<'a> Drop for Frame<'a> {
+    fn drop(&mut self) {
+        spare_scope(::std::mem::take(&mut self.locals));
+        spare_args(::std::mem::take(&mut self.varargs));
+    }
+}
+impl<'a> Frame<'a> {
+    fn declare(&mut self, name: &Name, val: Value) {
+        self.locals.push((name.clone(), Rc::new(RefCell::new(val))));
+    }
+    fn lookup(&self, name: &str) -> Option<&Cell> {
+        self.position(name).map(|i| &self.locals[i].1)
+    }
+    /// Where the innermost variable called `name` is in `locals`
+    fn position(&self, name: &str) -> Option<usize> {
+        self.locals.iter().rposition(|(local, _)| &**local == name)
+    }
+    /// Give an error raised by Rust code at `loc` that position, which is
+    /// also where the frame is at in the error's traceback
+    fn locate(&mut self, err: Error, loc: &Location) -> Error {
+        if self.failed.is_none() {
+            self.failed = Some(loc.clone());
+        }
+        err.located(loc)
+    }
+    /// Count a step against the limits, which is the statement at `loc` or
+    /// without one another iteration of a loop, reporting it to the hook
+    fn step(&mut self, loc: Option<&Location>) -> Result<()> {
+        self.closure.limits.step()?;
+        if let Some(loc) = loc {
+            trace::set_line(loc.pos.line);
+        }
+        if self.closure.limits.hooks.active() {
+            self.hook_step(loc)?;
+        }
+        Ok(())
+    }
+    /// Report a step to the hook, as a line if it starts a different line
+    /// from the last statement
+    #[cold]
+    fn hook_step(&mut self, loc: Option<&Location>) -> Result<()> {
+        let chunk = &self.closure.func().loc.chunk;
+        if self.closure.limits.hooks.count() {
+            self.closure
+                .limits
+                .hooks
+                .report(HookEvent::Count, chunk, self.line, &|| self.snapshot())?;
+        }
+        match loc {
+            Some(loc) if self.line != Some(loc.pos.line) => {
+                self.line = Some(loc.pos.line);
+                let event = HookEvent::Line(loc.pos.line);
+                self.closure
+                    .limits
+                    .hooks
+                    .report(event, chunk, self.line, &|| self.snapshot())
+            }
+            Some(_) => Ok(()),
+            None => {
+                self.line = None;
+                Ok(())
+            }
+        }
+    }
+    /// Report the start of the call to the hook, where `tail` is whether
+    /// it was a tail call
+    fn hook_call(&mut self, tail: bool) -> Result<()> {
+        if !self.closure.limits.hooks.active() {
+            return Ok(());
+        }
+        let event = if tail {
+            HookEvent::TailCall
+        } else {
+            HookEvent::Call
+        };
+        let loc = &self.closure.func().loc;
+        self.closure
+            .limits
+            .hooks
+            .report(event, &loc.chunk, Some(loc.pos.line), &|| self.snapshot())
+            .map_err(|err| self.locate(err, loc))
+    }
+    /// Report the call returning to the hook
+    fn hook_return(&mut self) -> Result<()> {
+        if !self.closure.limits.hooks.active() {
+            return Ok(());
+        }
+        let loc = &self.closure.func().loc;
+        self.closure
+            .limits
+            .hooks
+            .report(HookEvent::Return, &loc.chunk, self.line, &|| {
+                self.snapshot()
+            })
+            .map_err(|err| self.locate(err, loc))
+    }
+    /// The frame's variables for debuggers, sharing their cells
+    fn snapshot(&self) -> FrameInfo {
+        let closure = self.closure;
+        let captured = closure.captured.len();
+        FrameInfo {
+            chunk: closure.func().loc.chunk.clone(),
+            defined: if closure.main {
+                0
+            } else {
+                closure.func().loc.pos.line
+            },
+            line: self.line,
+            locals: self.locals[captured..].to_vec(),
+            upvalues: self.locals[..captured].to_vec(),
+            env: closure.env.clone(),
+            limits: closure.limits.clone(),
+            strings: closure.strings.clone(),
+        }
+    }
+    /// Run a block in its own scope
+    fn exec_block(&mut self, block: &Block) -> Result<Flow> {
+        let mark = self.locals.len();
+        let flow = self.run_block(block);
+        self.locals.truncate(mark);
+        flow
+    }
+    /// Run a block, leaving its locals in scope
+    fn run_block(&mut self, block: &Block) -> Result<Flow> {
+        let ast = self.ast;
+        let stats = &ast[block.stats];
+        let mark = self.locals.len();
+        let mut next = 0;
+        while let Some(stat) = stats.get(next) {
+            next += 1;
+            match self.exec(stat)? {
+                Flow::Normal => {}
+                Flow::Goto(label) => match find_label(stats, &label) {
+                    Some(at) => {
+                        // leave the scope of the locals declared after it
+                        self.locals.truncate(mark + declared(&stats[..at]));
+                        next = at + 1;
+                    }
+                    None => return Ok(Flow::Goto(label)),
+                },
+                flow => return Ok(flow),
+            }
+        }
+        let exprs = match block.ret {
+            Some(exprs) => &ast[exprs],
+            None => return Ok(Flow::Normal),
+        };
+        if let Some(expr) = exprs.first() {
+            // a return counts as a statement, starting where its values do
+            self.step(Some(&expr.loc))
+                .map_err(|err| self.locate(err, &expr.loc))?;
+        }
+        match *exprs {
+            [ref expr] if expr.is_call() => self.tail_call(expr),
+            [ref expr] => Ok(Flow::Return(self.eval_multi(expr)?)),
+            _ => {
+                // the list is returned, rather than reused
+                let mut vals = Vec::with_capacity(exprs.len());
+                self.eval_into(exprs, &mut vals)?;
+                Ok(Flow::Return(vals.into()))
+            }
+        }
+    }
+    fn exec(&mut self, stat: &Stat) -> Result<Flow> {
+        self.step(Some(&stat.loc))
+            .map_err(|err| self.locate(err, &stat.loc))?;
+        self.exec_kind(stat)
+            .map_err(|err| self.locate(err, &stat.loc))
+    }
+    fn exec_kind(&mut self, stat: &Stat) -> Result<Flow> {
+        let ast = self.ast;
+        match stat.kind {
+            StatKind::Assign(targets, exprs) => self.assign_all(&ast[targets], &ast[exprs])?,
+            StatKind::Call(call) => {
+                self.eval_multi(&ast[call])?;
+            }
+            StatKind::Do(ref block) => return self.exec_block(block),
+            StatKind::While(cond, ref body) => {
+                while self.eval(&ast[cond])?.to_bool() {
+                    self.step(None)?;
+                    match self.exec_block(body)? {
+                        Flow::Normal => {}
+                        Flow::Break => break,
+                        flow => return Ok(flow),
+                    }
+                }
+            }
+            StatKind::Repeat(ref body, cond) => return self.repeat(body, &ast[cond]),
+            StatKind::If(branches, ref else_block) => {
+                for &(cond, ref block) in &ast[branches] {
+                    if self.eval(&ast[cond])?.to_bool() {
+                        return self.exec_block(block);
+                    }
+                }
+                if let Some(ref block) = *else_block {
+                    return self.exec_block(block);
+                }
+            }
+            StatKind::NumericFor {
+                ref var,
+                start,
+                limit,
+                step,
+                ref body,
+            } => {
+                let start = self.eval(&ast[start])?;
+                let limit = self.eval(&ast[limit])?;
+                let step = match step {
+                    Some(step) => self.eval(&ast[step])?,
+                    None => 1.into_value(),
+                };
+                return self.numeric_for(var, start, limit, step, body);
+            }
+            StatKind::GenericFor {
+                vars,
+                exprs,
+                ref body,
+            } => return self.generic_for(&ast[vars], &ast[exprs], body),
+            StatKind::Function(ref name, func) => {
+                let closure = self.closure(func);
+                self.assign_function(name, closure)?;
+            }
+            StatKind::LocalFunction(ref name, func) => {
+                // declared first so that the function can call itself
+                self.declare(name, Value::nil());
+                let closure = self.closure(func);
+                *self.lookup(name).expect("just declared").borrow_mut() = closure;
+            }
+            StatKind::Local(names, exprs) => {
+                let mut vals = self.eval_list(&ast[exprs], names.len())?;
+                for (name, val) in ast[names].iter().zip(vals.drain(..)) {
+                    self.declare(name, val);
+                }
+                spare_args(vals);
+            }
+            StatKind::Break => return Ok(Flow::Break),
+            StatKind::Goto(ref label) => return Ok(Flow::Goto(label.clone())),
+            StatKind::Label(_) => {}
+        }
+        Ok(Flow::Normal)
+    }
+    // kept out of `exec_kind`, whose stack frame is on the stack for every
+    // call nested in a statement, as is `numeric_for`
+    fn assign_all(&mut self, targets: &[Expr], exprs: &[Expr]) -> Result<()> {
+        let places = targets
+            .iter()
+            .map(|target| self.place(target))
+            .collect::<Result<Vec<_>>>()?;
+        let mut vals = self.eval_list(exprs, places.len())?;
+        for (place, val) in places.into_iter().zip(vals.drain(..)) {
+            self.assign(place, val)?;
+        }
+        spare_args(vals);
+        Ok(())
+    }
+    fn generic_for(&mut self, vars: &[Name], exprs: &[Expr], body: &Block) -> Result<Flow> {
+        let mut vals = self.eval_list(exprs, 3)?.into_iter();
+        let func = vals.next().expect("padded to three values");
+        let state = vals.next().expect("padded to three values");
+        let mut control = vals.next().expect("padded to three values");
+        loop {
+            self.step(None)?;
+            let mut args = take_args();
+            args.push(state.clone());
+            args.push(control.clone());
+            let mut vals = call_value(&func, args)?;
+            vals.resize(vars.len(), Value::nil());
+            if vals[0].is_nil() {
+                break;
+            }
+            control = vals[0].clone();
+            let mark = self.locals.len();
+            for (var, val) in vars.iter().zip(vals) {
+                self.declare(var, val);
+            }
+            let flow = self.exec_block(body);
+            self.locals.truncate(mark);
+            match flow? {
+                Flow::Normal => {}
+                Flow::Break => break,
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Normal)
+    }
+    fn repeat(&mut self, body: &Block, cond: &Expr) -> Result<Flow> {
+        loop {
+            self.step(None)?;
+            // the condition can see the body's locals
+            let mark = self.locals.len();
+            let flow = self.run_block(body);
+            let done = match flow {
+                Ok(Flow::Normal) => self.eval(cond).map(|val| val.to_bool()),
+                Ok(Flow::Break) => Ok(true),
+                Ok(flow) => {
+                    self.locals.truncate(mark);
+                    return Ok(flow);
+                }
+                Err(err) => Err(err),
+            };
+            self.locals.truncate(mark);
+            if done? {
+                break;
+            }
+        }
+        Ok(Flow::Normal)
+    }
+    fn numeric_for(
+        &mut self,
+        var: &Name,
+        start: Value,
+        limit: Value,
+        step: Value,
+        body: &Block,
+    ) -> Result<Flow> {
+        let counter = match for_prep(start, limit, step)? {
+            None => return Ok(Flow::Normal),
+            Some([Number::Int(start), Number::Int(limit), Number::Int(step)]) => Counter::Int {
+                next: Some(start),
+                limit,
+                step,
+            },
+            Some([start, limit, step]) => Counter::Float {
+                next: start.to_float(),
+                limit: limit.to_float(),
+                step: step.to_float(),
+            },
+        };
+        for val in counter {
+            self.step(None)?;
+            let mark = self.locals.len();
+            self.declare(var, val);
+            let flow = self.exec_block(body);
+            self.locals.truncate(mark);
+            match flow? {
+                Flow::Normal => {}
+                Flow::Break => break,
+                flow => return Ok(flow),
+            }
+        }
+        Ok(Flow::Normal)
+    }
+    /// Store the function from a `function a.b:c()` statement
+    fn assign_function(&mut self, name: &FuncName, closure: Value) -> Result<()> {
+        let path = &self.ast[name.path];
+        let (fields, key) = match name.method {
+            Some(ref method) => (&path[1..], method),
+            None if path.len() == 1 => {
+                let place = self.variable(&path[0]);
+                return self.assign(place, closure);
+            }
+            None => (
+                &path[1..path.len() - 1],
+                path.last().expect("checked the length"),
+            ),
+        };
+        let mut obj = self.get_variable(&path[0])?;
+        for field in fields {
+            obj = obj.get_index(&self.closure.strings.intern(field.as_bytes()))?;
+        }
+        obj.set_index(self.closure.strings.intern(key.as_bytes()), closure)
+    }
+    fn variable(&self, name: &Name) -> Place {
+        match self.lookup(name) {
+            Some(cell) => Place::Local(cell.clone()),
+            None => Place::Global(name.clone()),
+        }
+    }
+    fn get_variable(&self, name: &str) -> Result<Value> {
+        match self.lookup(name) {
+            Some(cell) => Ok(cell.borrow().clone()),
+            None => self
+                .closure
+                .env
+                .get_index(&self.closure.strings.intern(name.as_bytes())),
+        }
+    }
+    /// Evaluate the parts of an assignment target
+    fn place(&mut self, target: &Expr) -> Result<Place> {
+        let ast = self.ast;
+        match target.kind {
+            ExprKind::Name(ref name) => Ok(self.variable(name)),
+            ExprKind::Index(obj, key) => {
+                let obj = &ast[obj];
+                let val = self.eval(obj)?;
+                let key = self.eval(&ast[key])?;
+                if val.type_of() != Type::Table && val.metamethod(Event::NewIndex).is_none() {
+                    return Err(
+                        self.described(format!("attempt to index a {} value", val.type_of()), obj)
+                    );
+                }
+                Ok(Place::Index(val, key))
+            }
+            _ => unreachable!("the parser only allows names and fields as targets"),
+        }
+    }
+    fn assign(&mut self, place: Place, val: Value) -> Result<()> {
+        match place {
+            Place::Local(cell) => {
+                *cell.borrow_mut() = val;
+                Ok(())
+            }
+            Place::Global(name) => self
+                .closure
+                .env
+                .set_index(self.closure.strings.intern(name.as_bytes()), val),
+            Place::Index(obj, key) => obj.set_index(key, val),
+        }
+    }
+    /// Add what `expr` names to an error message about its value, as in
+    /// `attempt to call a nil value (global 'f')`
+    fn described(&self, msg: String, expr: &Expr) -> Error {
+        let what = match expr.kind {
+            ExprKind::Name(ref name) => match self.position(name) {
+                Some(i) if i < self.closure.captured.len() => format!(" (upvalue '{}')", name),
+                Some(_) => format!(" (local '{}')", name),
+                None => format!(" (global '{}')", name),
+            },
+            ExprKind::Index(_, key) => match self.ast[key].kind {
+                ExprKind::String(ref bytes) => {
+                    format!(" (field '{}')", String::from_utf8_lossy(bytes))
+                }
+                _ => String::new(),
+            },
+            _ => String::new(),
+        };
+        Error::Runtime(msg + &what)
+    }
+    fn closure(&self, func: FuncId) -> Value {
+        let free = self
+            .closure
+            .free
+            .borrow_mut()
+            .entry(func)
+            .or_insert_with(|| vm::free(self.ast, &self.ast[func]).into())
+            .clone();
+        // names that aren't locals here are globals
+        let mut used: Vec<usize> = free.iter().filter_map(|name| self.position(name)).collect();
+        used.sort_unstable();
+        Value::interpreted(Closure {
+            ast: self.closure.ast.clone(),
+            func,
+            captured: used.into_iter().map(|i| self.locals[i].clone()).collect(),
+            free: self.closure.free.clone(),
+            env: self.closure.env.clone(),
+            limits: self.closure.limits.clone(),
+            strings: self.closure.strings.clone(),
+            main: false,
+            compiled: OnceCell::new(),
+        })
+    }
+    /// Evaluate `exprs`, then drop or pad with nils to get `want` values
+    fn eval_list(&mut self, exprs: &[Expr], want: usize) -> Result<Vec<Value>> {
+        let mut vals = self.eval_all(exprs)?;
+        vals.resize(want, Value::nil());
+        Ok(vals)
+    }
+    /// Evaluate `exprs`, keeping every value of a call at the end
+    fn eval_all(&mut self, exprs: &[Expr]) -> Result<Vec<Value>> {
+        let mut vals = take_args();
+        self.eval_into(exprs, &mut vals)?;
+        Ok(vals)
+    }
+    /// Evaluate `exprs` as `eval_all` does, adding the values to `vals`
+    fn eval_into(&mut self, exprs: &[Expr], vals: &mut Vec<Value>) -> Result<()> {
+        if let Some((last, init)) = exprs.split_last() {
+            for expr in init {
+                vals.push(self.eval(expr)?);
+            }
+            vals.extend(self.eval_multi(last)?);
+        }
+        Ok(())
+    }
+    /// Evaluate an expression for all of its values
+    fn eval_multi(&mut self, expr: &Expr) -> Result<MultiValue> {
+        match expr.kind {
+            ExprKind::Vararg => Ok(self.varargs.clone().into()),
+            ExprKind::Call(..) | ExprKind::Method(..) => {
+                self.call(expr).map_err(|err| self.locate(err, &expr.loc))
+            }
+            _ => self.eval(expr).map(MultiValue::from),
+        }
+    }
+    fn eval(&mut self, expr: &Expr) -> Result<Value> {
+        self.eval_kind(expr)
+            .map_err(|err| self.locate(err, &expr.loc))
+    }
+    /// Call a function or method, giving all of its results
+    fn call(&mut self, expr: &Expr) -> Result<MultiValue> {
+        let (callee, args) = self.callee(expr)?;
+        call_value(&callee, args)
+    }
+    /// Return the results of a call, leaving calls to Lua functions to be
+    /// made once this frame is gone
+    ///
+    /// Other functions are called here, so that errors they raise are
+    /// still given where they were called from.
+    fn tail_call(&mut self, expr: &Expr) -> Result<Flow> {
+        let (callee, args) = self
+            .callee(expr)
+            .map_err(|err| self.locate(err, &expr.loc))?;
+        if callee.as_interpreted().is_some() {
+            return Ok(Flow::TailCall(callee, args));
+        }
+        let vals = call_value(&callee, args).map_err(|err| self.locate(err, &expr.loc))?;
+        Ok(Flow::Return(vals))
+    }
+    /// Evaluate the function and arguments of a call or method call
+    fn callee(&mut self, expr: &Expr) -> Result<(Value, Vec<Value>)> {
+        let ast = self.ast;
+        match expr.kind {
+            ExprKind::Call(func, args) => {
+                let func = &ast[func];
+                let callee = self.eval(func)?;
+                let args = self.eval_all(&ast[args])?;
+                if !is_callable(&callee) {
+                    return Err(self.described(
+                        format!("attempt to call a {} value", callee.type_of()),
+                        func,
+                    ));
+                }
+                Ok((callee, args))
+            }
+            ExprKind::Method(obj, ref name, args) => {
+                let obj = self.eval(&ast[obj])?;
+                let method = obj.get_index(&self.closure.strings.intern(name.as_bytes()))?;
+                let mut vals = take_args();
+                vals.push(obj);
+                self.eval_into(&ast[args], &mut vals)?;
+                if !is_callable(&method) {
+                    return Err(Error::Runtime(format!(
+                        "attempt to call a {} value (method '{}')",
+                        method.type_of(),
+                        name
+                    )));
+                }
+                Ok((method, vals))
+            }
+            _ => unreachable!("only calls are called"),
+        }
+    }
+    fn eval_kind(&mut self, expr: &Expr) -> Result<Value> {
+        let ast = self.ast;
+        Ok(match expr.kind {
+            ExprKind::Nil => Value::nil(),
+            ExprKind::True => true.into_value(),
+            ExprKind::False => false.into_value(),
+            ExprKind::Number(num) => num.into_value(),
+            ExprKind::String(ref bytes) => self.closure.strings.intern(bytes),
+            ExprKind::Vararg => self.varargs.first().cloned().unwrap_or_else(Value::nil),
+            ExprKind::Function(func) => self.closure(func),
+            ExprKind::Table(fields) => self.table(fields)?,
+            ExprKind::Name(ref name) => self.get_variable(name)?,
+            ExprKind::Index(obj, key) => self.index(&ast[obj], &ast[key])?,
+            ExprKind::Call(..) | ExprKind::Method(..) => self.call(expr)?.into_first(),
+            ExprKind::Paren(inner) => self.eval(&ast[inner])?,
+            ExprKind::Binary(BinOp::And, lhs, rhs) => {
+                let lhs = self.eval(&ast[lhs])?;
+                if lhs.to_bool() {
+                    self.eval(&ast[rhs])?
+                } else {
+                    lhs
+                }
+            }
+            ExprKind::Binary(BinOp::Or, lhs, rhs) => {
+                let lhs = self.eval(&ast[lhs])?;
+                if lhs.to_bool() {
+                    lhs
+                } else {
+                    self.eval(&ast[rhs])?
+                }
+            }
+            ExprKind::Binary(BinOp::Concat, _, rhs) if ast[rhs].is_concat() => self.concat(expr)?,
+            ExprKind::Binary(op, lhs, rhs) => {
+                let lhs = self.eval(&ast[lhs])?;
+                let rhs = self.eval(&ast[rhs])?;
+                binary(op, &lhs, &rhs)?
+            }
+            ExprKind::Unary(op, operand) => self.unary(op, &ast[operand])?,
+        })
+    }
+    /// A chain of more than one concatenation, evaluating its operands
+    /// before joining them all at once
+    fn concat(&mut self, chain: &Expr) -> Result<Value> {
+        let operands = self.ast.concat_operands(chain);
+        let mut values = Vec::with_capacity(operands.len());
+        for operand in operands {
+            values.push(self.eval(operand)?);
+        }
+        Value::concat_all(&values)
+    }
+    // kept out of `eval_kind`, whose stack frame is on the stack twice for
+    // every call nested in an expression, as are those below
+    fn table(&mut self, fields: FieldList) -> Result<Value> {
+        let ast = self.ast;
+        let fields = &ast[fields];
+        let (narr, nrec) = constructor_size(ast, fields);
+        let table = Table::with_capacity(narr, nrec);
+        let mut next: LuaInteger = 1;
+        for (i, field) in fields.iter().enumerate() {
+            match field.kind {
+                FieldKind::Named(ref name, val) => {
+                    let val = self.eval(&ast[val])?;
+                    table.set(self.closure.strings.intern(name.as_bytes()), val)?;
+                }
+                FieldKind::Indexed(key, val) => {
+                    let key = self.eval(&ast[key])?;
+                    let val = self.eval(&ast[val])?;
+                    table.set(key, val)?;
+                }
+                // a call ending the constructor fills in all its values
+                FieldKind::Positional(val) if i == fields.len() - 1 => {
+                    let vals = self.eval_multi(&ast[val])?;
+                    table.reserve(vals.len());
+                    for val in vals {
+                        table.set(next.into_value(), val)?;
+                        next += 1;
+                    }
+                }
+                FieldKind::Positional(val) => {
+                    let val = self.eval(&ast[val])?;
+                    table.set(next.into_value(), val)?;
+                    next += 1;
+                }
+            }
+        }
+        Ok(table.into_value())
+    }
+    fn index(&mut self, obj: &Expr, key: &Expr) -> Result<Value> {
+        let val = self.eval(obj)?;
+        let key = self.eval(key)?;
+        if val.type_of() != Type::Table && val.metamethod(Event::Index).is_none() {
+            return Err(self.described(format!("attempt to index a {} value", val.type_of()), obj));
+        }
+        val.get_index(&key)
+    }
+    fn unary(&mut self, op: UnOp, operand: &Expr) -> Result<Value> {
+        let val = self.eval(operand)?;
+        match op {
+            UnOp::Neg => val.arith(ArithOp::Unm, &val),
+            UnOp::Not => Ok((!val.to_bool()).into_value()),
+            UnOp::Len => val.len(),
+            UnOp::BNot => val.arith(ArithOp::BNot, &val),
+        }
+    }
+}
+
+/// Where the statements of a block declare `label`, if they do
+fn find_label(stats: &[Stat], label: &Name) -> Option<usize> {
+    stats
+        .iter()
+        .position(|stat| matches!(stat.kind, StatKind::Label(ref name) if name == label))
+}
+
+/// How many locals `stats` declare in their block
+fn declared(stats: &[Stat]) -> usize {
+    stats
+        .iter()
+        .map(|stat| match stat.kind {
+            StatKind::Local(ref names, _) => names.len(),
+            StatKind::LocalFunction(..) => 1,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// The values taken by a numeric `for` loop's variable
+enum Counter {
+    Int {
+        /// `None` once the count would overflow
+        next: Option<LuaInteger>,
+        limit: LuaInteger,
+        step: LuaInteger,
+    },
+    Float {
+        next: LuaNumber,
+        limit: LuaNumber,
+        step: LuaNumber,
+    },
+}
+impl Iterator for Counter {
+    type Item = Value;
+    fn next(&mut self) -> Option<Value> {
+        match *self {
+            Counter::Int {
+                ref mut next,
+                limit,
+                step,
+            } => {
+                let val = (*next)?;
+                if (step > 0 && val > limit) || (step < 0 && val < limit) {
+                    return None;
+                }
+                *next = val.checked_add(step);
+                Some(val.into_value())
+            }
+            Counter::Float {
+                ref mut next,
+                limit,
+                step,
+            } => {
+                let val = *next;
+                if (step > 0.0 && val > limit) || (step < 0.0 && val < limit) {
+                    return None;
+                }
+                *next += step;
+                Some(val.into_value())
+            }
+        }
+    }
+}
+
+/// A numeric `for` loop's parameter, which unlike in arithmetic must
+/// already be a number
+fn for_number(val: Value, what: &str) -> Result<Number> {
+    match val.number_value() {
+        Some(num) => Ok(num),
+        None => Err(Error::Runtime(format!("'for' {} must be a number", what))),
+    }
+}
+
+/// How many items a table constructor puts in the sequence and how many
+/// other entries it sets, leaving out the values of a call or `...` that
+/// ends it
+pub(crate) fn constructor_size(ast: &Ast, fields: &[Field]) -> (usize, usize) {
+    let (mut narr, mut nrec) = (0, 0);
+    for (i, field) in fields.iter().enumerate() {
+        match field.kind {
+            FieldKind::Positional(val) if i + 1 == fields.len() && ast[val].is_multi() => {}
+            FieldKind::Positional(_) => narr += 1,
+            _ => nrec += 1,
+        }
+    }
+    (narr, nrec)
+}
+
+/// Check and convert a numeric `for` loop's initial value, limit and step,
+/// giving `None` if the loop doesn't run at all
+///
+/// As in Lua 5.4, the loop counts in integers when the initial value and
+/// the step are integers, with a float limit rounded towards the initial
+/// value and clipped to the integer range, and in floats otherwise.
+pub(crate) fn for_prep(start: Value, limit: Value, step: Value) -> Result<Option<[Number; 3]>> {
+    let start = for_number(start, "initial value")?;
+    let limit = for_number(limit, "limit")?;
+    let step = for_number(step, "step")?;
+    if step.to_float() == 0.0 {
+        return Err(Error::Runtime("'for' step is zero".to_string()));
+    }
+    let params = match (start, limit, step) {
+        (Number::Int(_), Number::Int(_), Number::Int(_)) => [start, limit, step],
+        (Number::Int(_), Number::Float(limit), Number::Int(step)) => {
+            let rounded = if step > 0 {
+                limit.floor()
+            } else {
+                limit.ceil()
+            };
+            let limit = match number::float_to_int(rounded) {
+                Some(limit) => limit,
+                // past the end of the integer range the loop runs until the
+                // counter would overflow, and before the start it never runs
+                None if !limit.is_nan() && (limit > 0.0) == (step > 0) => {
+                    if step > 0 {
+                        LuaInteger::MAX
+                    } else {
+                        LuaInteger::MIN
+                    }
+                }
+                None => return Ok(None),
+            };
+            [start, Number::Int(limit), Number::Int(step)]
+        }
+        _ => [
+            Number::Float(start.to_float()),
+            Number::Float(limit.to_float()),
+            Number::Float(step.to_float()),
+        ],
+    };
+    Ok(if for_in_range(params[0], params[1], params[2]) {
+        Some(params)
+    } else {
+        None
+    })
+}
+
+/// Whether a numeric `for` loop runs with its variable at `val`
+pub(crate) fn for_in_range(val: Number, limit: Number, step: Number) -> bool {
+    match (val, limit, step) {
+        (Number::Int(val), Number::Int(limit), Number::Int(step)) => {
+            if step > 0 {
+                val <= limit
+            } else {
+                val >= limit
+            }
+        }
+        _ => {
+            if step.to_float() > 0.0 {
+                val.to_float() <= limit.to_float()
+            } else {
+                val.to_float() >= limit.to_float()
+            }
+        }
+    }
+}
+
+fn binary(op: BinOp, lhs: &Value, rhs: &Value) -> Result<Value> {
+    let arith = match op {
+        BinOp::Add => ArithOp::Add,
+        BinOp::Sub => ArithOp::Sub,
+        BinOp::Mul => ArithOp::Mul,
+        BinOp::Div => ArithOp::Div,
+        BinOp::IDiv => ArithOp::IDiv,
+        BinOp::Mod => ArithOp::Mod,
+        BinOp::Pow => ArithOp::Pow,
+        BinOp::BAnd => ArithOp::BAnd,
+        BinOp::BOr => ArithOp::BOr,
+        BinOp::BXor => ArithOp::BXor,
+        BinOp::Shl => ArithOp::Shl,
+        BinOp::Shr => ArithOp::Shr,
+        BinOp::Concat => return lhs.concat(rhs),
+        BinOp::Eq => return lhs.lua_eq(rhs).map(ConvertValue::into_value),
+        BinOp::Ne => return lhs.lua_eq(rhs).map(|eq| (!eq).into_value()),
+        BinOp::Lt => return lhs.lua_lt(rhs).map(ConvertValue::into_value),
+        BinOp::Le => return lhs.lua_le(rhs).map(ConvertValue::into_value),
+        BinOp::Gt => return rhs.lua_lt(lhs).map(ConvertValue::into_value),
+        BinOp::Ge => return rhs.lua_le(lhs).map(ConvertValue::into_value),
+        BinOp::And | BinOp::Or => unreachable!("short-circuit operators are evaluated lazily"),
+    };
+    lhs.arith(arith, rhs)
+}
+
+pub(crate) fn is_callable(val: &Value) -> bool {
+    val.type_of() == Type::Function || val.metamethod(Event::Call).is_some()
+}
@@ -0,0 +1,686 @@
+//! Splitting Lua source into tokens
+//!
+//! The lexer works on bytes, since Lua source need not be valid UTF-8 inside
+//! strings and comments. Every token records the byte range it came from and
+//! where it starts as a line and column, so the parser and any other tools
+//! can point back into the source.
+
+use std::fmt;
+
+use error::ParseError;
+
+type Result<T> = ::std::result::Result<T, ParseError>;
+use number::{self, Number};
+
+/// A range of bytes in the source
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+impl Span {
+    pub fn new(start: usize, end: usize) -> Span {
+        Span { start, end }
+    }
+    /// The smallest span covering both `self` and `other`
+    pub fn to(self, other: Span) -> Span {
+        Span::new(self.start.min(other.start), self.end.max(other.end))
+    }
+}
+
+/// A 1-based line and column, where columns count bytes
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Position {
+    pub line: u32,
+    pub column: u32,
+}
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TokenKind {
+    And,
+    Break,
+    Do,
+    Else,
+    ElseIf,
+    End,
+    False,
+    For,
+    Function,
+    Goto,
+    If,
+    In,
+    Local,
+    Nil,
+    Not,
+    Or,
+    Repeat,
+    Return,
+    Then,
+    True,
+    Until,
+    While,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    DoubleSlash,
+    Percent,
+    Caret,
+    Hash,
+    Ampersand,
+    Tilde,
+    Pipe,
+    ShiftLeft,
+    ShiftRight,
+    Equal,
+    NotEqual,
+    LessEqual,
+    GreaterEqual,
+    Less,
+    Greater,
+    Assign,
+    LeftParen,
+    RightParen,
+    LeftBrace,
+    RightBrace,
+    LeftBracket,
+    RightBracket,
+    DoubleColon,
+    Semicolon,
+    Colon,
+    Comma,
+    Dot,
+    Concat,
+    Dots,
+    Name(String),
+    Number(Number),
+    /// the contents of a string literal, with escapes processed
+    String(Vec<u8>),
+    /// only produced when the lexer is asked to keep comments, and whose
+    /// text is the token's span of the source
+    Comment,
+    Eof,
+}
+impl TokenKind {
+    pub(crate) fn keyword(name: &[u8]) -> Option<TokenKind> {
+        Some(match name {
+            b"and" => TokenKind::And,
+            b"break" => TokenKind::Break,
+            b"do" => TokenKind::Do,
+            b"else" => TokenKind::Else,
+            b"elseif" => TokenKind::ElseIf,
+            b"end" => TokenKind::End,
+            b"false" => TokenKind::False,
+            b"for" => TokenKind::For,
+            b"function" => TokenKind::Function,
+            b"goto" => TokenKind::Goto,
+            b"if" => TokenKind::If,
+            b"in" => TokenKind::In,
+            b"local" => TokenKind::Local,
+            b"nil" => TokenKind::Nil,
+            b"not" => TokenKind::Not,
+            b"or" => TokenKind::Or,
+            b"repeat" => TokenKind::Repeat,
+            b"return" => TokenKind::Return,
+            b"then" => TokenKind::Then,
+            b"true" => TokenKind::True,
+            b"until" => TokenKind::Until,
+            b"while" => TokenKind::While,
+            _ => return None,
+        })
+    }
+    /// The source text of keywords and symbols
+    pub fn text(&self) -> Option<&'static str> {
+        Some(match *self {
+            TokenKind::And => "and",
+            TokenKind::Break => "break",
+            TokenKind::Do => "do",
+            TokenKind::Else => "else",
+            TokenKind::ElseIf => "elseif",
+            TokenKind::End => "end",
+            TokenKind::False => "false",
+            TokenKind::For => "for",
+            TokenKind::Function => "function",
+            TokenKind::Goto => "goto",
+            TokenKind::If => "if",
+            TokenKind::In => "in",
+            TokenKind::Local => "local",
+            TokenKind::Nil => "nil",
+            TokenKind::Not => "not",
+            TokenKind::Or => "or",
+            TokenKind::Repeat => "repeat",
+            TokenKind::Return => "return",
+            TokenKind::Then => "then",
+            TokenKind::True => "true",
+            TokenKind::Until => "until",
+            TokenKind::While => "while",
+            TokenKind::Plus => "+",
+            TokenKind::Minus => "-",
+            TokenKind::Star => "*",
+            TokenKind::Slash => "/",
+            TokenKind::DoubleSlash => "//",
+            TokenKind::Percent => "%",
+            TokenKind::Caret => "^",
+            TokenKind::Hash => "#",
+            TokenKind::Ampersand => "&",
+            TokenKind::Tilde => "~",
+            TokenKind::Pipe => "|",
+            TokenKind::ShiftLeft => "<<",
+            TokenKind::ShiftRight => ">>",
+            TokenKind::Equal => "==",
+            TokenKind::NotEqual => "~=",
+            TokenKind::LessEqual => "<=",
+            TokenKind::GreaterEqual => ">=",
+            TokenKind::Less => "<",
+            TokenKind::Greater => ">",
+            TokenKind::Assign => "=",
+            TokenKind::LeftParen => "(",
+            TokenKind::RightParen => ")",
+            TokenKind::LeftBrace => "{",
+            TokenKind::RightBrace => "}",
+            TokenKind::LeftBracket => "[",
+            TokenKind::RightBracket => "]",
+            TokenKind::DoubleColon => "::",
+            TokenKind::Semicolon => ";",
+            TokenKind::Colon => ":",
+            TokenKind::Comma => ",",
+            TokenKind::Dot => ".",
+            TokenKind::Concat => "..",
+            TokenKind::Dots => "...",
+            _ => return None,
+        })
+    }
+    pub fn is_keyword(&self) -> bool {
+        self.text()
+            .is_some_and(|text| text.as_bytes()[0].is_ascii_alphabetic())
+    }
+}
+/// Describes the kind of token, as used in "expected" messages
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TokenKind::Name(_) => f.write_str("<name>"),
+            TokenKind::Number(_) => f.write_str("<number>"),
+            TokenKind::String(_) => f.write_str("<string>"),
+            TokenKind::Comment => f.write_str("<comment>"),
+            TokenKind::Eof => f.write_str("<eof>"),
+            _ => write!(f, "'{}'", self.text().expect("every other kind has text")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+    /// where the token starts
+    pub pos: Position,
+}
+
+fn is_name_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+fn is_name_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+/// Append `value` in UTF-8, extended as in Lua to values up to 2^31 using
+/// sequences of up to six bytes
+fn utf8_encode(value: u32, bytes: &mut Vec<u8>) {
+    if value < 0x80 {
+        bytes.push(value as u8);
+        return;
+    }
+    // fill continuation bytes from the end until the rest fits in the first
+    let mut tail = Vec::new();
+    let mut rest = value;
+    let mut first_max = 0x3F;
+    while rest > first_max {
+        tail.push(0x80 | (rest & 0x3F) as u8);
+        rest >>= 6;
+        first_max >>= 1;
+    }
+    let lead = (!first_max << 1) & 0xFF;
+    bytes.push((lead | rest) as u8);
+    bytes.extend(tail.iter().rev());
+}
+
+/// Produces tokens from Lua source, one at a time
+pub struct Lexer<'a> {
+    src: &'a [u8],
+    pos: usize,
+    line: u32,
+    /// the offset of the start of the current line
+    line_start: usize,
+    comments: bool,
+}
+impl<'a> Lexer<'a> {
+    pub fn new(src: &'a [u8]) -> Lexer<'a> {
+        Lexer {
+            src,
+            pos: 0,
+            line: 1,
+            line_start: 0,
+            comments: false,
+        }
+    }
+    /// Produce `TokenKind::Comment` tokens rather than skipping comments
+    pub fn with_comments(mut self, comments: bool) -> Lexer<'a> {
+        self.comments = comments;
+        self
+    }
+    /// The position of the next byte to be read
+    pub fn current_position(&self) -> Position {
+        self.position_of(self.pos)
+    }
+    /// Step over one character, so that lexing can resume after an error
+    pub(crate) fn skip_char(&mut self) {
+        match self.peek() {
+            None => {}
+            c if Lexer::is_newline(c) => self.newline(),
+            _ => self.pos += 1,
+        }
+    }
+    fn position_of(&self, offset: usize) -> Position {
+        if offset >= self.line_start {
+            return Position {
+                line: self.line,
+                column: (offset - self.line_start) as u32 + 1,
+            };
+        }
+        // the start of a token running over several lines, which only
+        // errors need, so the lines before it are counted again
+        let (mut line, mut line_start, mut i) = (1, 0, 0);
+        while i < offset {
+            let c = self.src[i];
+            i += 1;
+            if c == b'\n' || c == b'\r' {
+                let next = self.src.get(i).cloned();
+                if Lexer::is_newline(next) && next != Some(c) {
+                    i += 1;
+                }
+                line += 1;
+                line_start = i;
+            }
+        }
+        Position {
+            line,
+            column: (offset - line_start) as u32 + 1,
+        }
+    }
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).cloned()
+    }
+    fn peek_at(&self, ahead: usize) -> Option<u8> {
+        self.src.get(self.pos + ahead).cloned()
+    }
+    fn is_newline(c: Option<u8>) -> bool {
+        c == Some(b'\n') || c == Some(b'\r')
+    }
+    /// Consume a newline, treating `\r\n` and `\n\r` as one
+    fn newline(&mut self) {
+        let first = self.peek();
+        self.pos += 1;
+        if Lexer::is_newline(self.peek()) && self.peek() != first {
+            self.pos += 1;
+        }
+        self.line += 1;
+        self.line_start = self.pos;
+    }
+    fn error<T>(&self, msg: &str, start: usize) -> Result<T> {
+        let end = self.pos.min(self.src.len());
+        let near = String::from_utf8_lossy(&self.src[start..end]).into_owned();
+        Err(ParseError::new(
+            msg.to_string(),
+            Span::new(start, end),
+            self.position_of(start),
+            Some(near),
+        ))
+    }
+    /// Skip whitespace, and comments unless they are kept
+    fn skip_space(&mut self) -> Result<()> {
+        loop {
+            match self.peek() {
+                Some(b'\n') | Some(b'\r') => self.newline(),
+                Some(c) if c.is_ascii_whitespace() => self.pos += 1,
+                Some(b'-') if self.peek_at(1) == Some(b'-') && !self.comments => {
+                    self.skip_comment()?;
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+    /// Skip a comment, starting at its `--`, which is a long comment if a
+    /// long bracket follows and otherwise runs to the end of the line
+    fn skip_comment(&mut self) -> Result<()> {
+        let start = self.pos;
+        self.pos += 2;
+        if let Some(level) = self.long_bracket() {
+            return self.long_string(level, start, "comment").map(|_| ());
+        }
+        while self.peek().is_some() && !Lexer::is_newline(self.peek()) {
+            self.pos += 1;
+        }
+        Ok(())
+    }
+    /// The level of the opening long bracket here (the number of `=` in
+    /// `[==[`), if there is one
+    fn long_bracket(&self) -> Option<usize> {
+        if self.peek() != Some(b'[') {
+            return None;
+        }
+        let level = self.src[self.pos + 1..]
+            .iter()
+            .take_while(|&&c| c == b'=')
+            .count();
+        if self.peek_at(level + 1) == Some(b'[') {
+            Some(level)
+        } else {
+            None
+        }
+    }
+    /// Read a long string or comment, starting at its opening bracket
+    ///
+    /// As in Lua, a newline straight after the opening bracket is skipped
+    /// and every other newline sequence is read as `\n`.
+    fn long_string(&mut self, level: usize, start: usize, what: &str) -> Result<Vec<u8>> {
+        self.pos += level + 2;
+        if Lexer::is_newline(self.peek()) {
+            self.newline();
+        }
+        let mut bytes = Vec::new();
+        loop {
+            match self.peek() {
+                None => return self.error(&format!("unfinished long {}", what), start),
+                Some(b'\n') | Some(b'\r') => {
+                    self.newline();
+                    bytes.push(b'\n');
+                }
+                Some(b']')
+                    if self.src[self.pos + 1..]
+                        .iter()
+                        .take_while(|&&c| c == b'=')
+                        .count()
+                        == level
+                        && self.peek_at(level + 1) == Some(b']') =>
+                {
+                    self.pos += level + 2;
+                    return Ok(bytes);
+                }
+                Some(c) => {
+                    bytes.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+    pub fn next_token(&mut self) -> Result<Token> {
+        self.skip_space()?;
+        let start = self.pos;
+        let pos = self.current_position();
+        let kind = self.token_kind(start)?;
+        Ok(Token {
+            kind,
+            span: Span::new(start, self.pos),
+            pos,
+        })
+    }
+    fn token_kind(&mut self, start: usize) -> Result<TokenKind> {
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return Ok(TokenKind::Eof),
+        };
+        if is_name_start(c) {
+            while self.peek().is_some_and(is_name_char) {
+                self.pos += 1;
+            }
+            let name = &self.src[start..self.pos];
+            return Ok(TokenKind::keyword(name).unwrap_or_else(|| {
+                TokenKind::Name(String::from_utf8(name.to_vec()).expect("names are ASCII"))
+            }));
+        }
+        if c.is_ascii_digit() || (c == b'.' && self.peek_at(1).is_some_and(|c| c.is_ascii_digit()))
+        {
+            return self.number(start);
+        }
+        if c == b'"' || c == b'\'' {
+            return self.string(start);
+        }
+        let next = self.peek_at(1);
+        let (kind, len) = match (c, next) {
+            (b'-', Some(b'-')) => {
+                self.skip_comment()?;
+                return Ok(TokenKind::Comment);
+            }
+            (b'[', _) if self.long_bracket().is_some() => {
+                let level = self.long_bracket().expect("just checked");
+                return self
+                    .long_string(level, start, "string")
+                    .map(TokenKind::String);
+            }
+            (b'.', Some(b'.')) if self.peek_at(2) == Some(b'.') => (TokenKind::Dots, 3),
+            (b'.', Some(b'.')) => (TokenKind::Concat, 2),
+            (b'/', Some(b'/')) => (TokenKind::DoubleSlash, 2),
+            (b'<', Some(b'<')) => (TokenKind::ShiftLeft, 2),
+            (b'>', Some(b'>')) => (TokenKind::ShiftRight, 2),
+            (b'=', Some(b'=')) => (TokenKind::Equal, 2),
+            (b'~', Some(b'=')) => (TokenKind::NotEqual, 2),
+            (b'<', Some(b'=')) => (TokenKind::LessEqual, 2),
+            (b'>', Some(b'=')) => (TokenKind::GreaterEqual, 2),
+            (b':', Some(b':')) => (TokenKind::DoubleColon, 2),
+            (b'+', _) => (TokenKind::Plus, 1),
+            (b'-', _) => (TokenKind::Minus, 1),
+            (b'*', _) => (TokenKind::Star, 1),
+            (b'/', _) => (TokenKind::Slash, 1),
+            (b'%', _) => (TokenKind::Percent, 1),
+            (b'^', _) => (TokenKind::Caret, 1),
+            (b'#', _) => (TokenKind::Hash, 1),
+            (b'&', _) => (TokenKind::Ampersand, 1),
+            (b'~', _) => (TokenKind::Tilde, 1),
+            (b'|', _) => (TokenKind::Pipe, 1),
+            (b'<', _) => (TokenKind::Less, 1),
+            (b'>', _) => (TokenKind::Greater, 1),
+            (b'=', _) => (TokenKind::Assign, 1),
+            (b'(', _) => (TokenKind::LeftParen, 1),
+            (b')', _) => (TokenKind::RightParen, 1),
+            (b'{', _) => (TokenKind::LeftBrace, 1),
+            (b'}', _) => (TokenKind::RightBrace, 1),
+            (b'[', _) => (TokenKind::LeftBracket, 1),
+            (b']', _) => (TokenKind::RightBracket, 1),
+            (b';', _) => (TokenKind::Semicolon, 1),
+            (b':', _) => (TokenKind::Colon, 1),
+            (b',', _) => (TokenKind::Comma, 1),
+            (b'.', _) => (TokenKind::Dot, 1),
+            _ => {
+                self.pos += 1;
+                return self.error("unexpected symbol", start);
+            }
+        };
+        self.pos += len;
+        Ok(kind)
+    }
+    fn number(&mut self, start: usize) -> Result<TokenKind> {
+        // like Lua, take everything that could belong to a numeral and
+        // then check it, so `3x` is one malformed number
+        let mut exponent = b"Ee";
+        if self.peek() == Some(b'0') && matches!(self.peek_at(1), Some(b'x') | Some(b'X')) {
+            exponent = b"Pp";
+            self.pos += 2;
+        }
+        loop {
+            match self.peek() {
+                Some(c) if exponent.contains(&c) => {
+                    self.pos += 1;
+                    if matches!(self.peek(), Some(b'+') | Some(b'-')) {
+                        self.pos += 1;
+                    }
+                }
+                Some(c) if is_name_char(c) || c == b'.' => self.pos += 1,
+                _ => break,
+            }
+        }
+        let text = String::from_utf8_lossy(&self.src[start..self.pos]);
+        match number::parse(&text) {
+            Some(num) => Ok(TokenKind::Number(num)),
+            None => self.error("malformed number", start),
+        }
+    }
+    /// Read a string delimited by quotes
+    fn string(&mut self, start: usize) -> Result<TokenKind> {
+        let quote = self.src[self.pos];
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            match self.peek() {
+                None => return self.error("unfinished string", start),
+                Some(b'\n') | Some(b'\r') => return self.error("unfinished string", start),
+                Some(c) if c == quote => {
+                    self.pos += 1;
+                    return Ok(TokenKind::String(bytes));
+                }
+                Some(b'\\') => self.escape(&mut bytes, start)?,
+                Some(c) => {
+                    bytes.push(c);
+                    self.pos += 1;
+                }
+            }
+        }
+    }
+    /// Read an escape sequence in a string, starting at its backslash
+    fn escape(&mut self, bytes: &mut Vec<u8>, start: usize) -> Result<()> {
+        self.pos += 1;
+        let c = match self.peek() {
+            Some(c) => c,
+            None => return self.error("unfinished string", start),
+        };
+        let byte = match c {
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'f' => 0x0C,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'v' => 0x0B,
+            b'\\' | b'"' | b'\'' => c,
+            b'\n' | b'\r' => {
+                self.newline();
+                bytes.push(b'\n');
+                return Ok(());
+            }
+            b'x' => {
+                self.pos += 1;
+                let mut byte = 0;
+                for _ in 0..2 {
+                    match self.peek().and_then(|c| (c as char).to_digit(16)) {
+                        Some(digit) => byte = byte * 16 + digit as u8,
+                        None => {
+                            self.pos += usize::from(self.peek().is_some());
+                            return self.error("hexadecimal digit expected", start);
+                        }
+                    }
+                    self.pos += 1;
+                }
+                bytes.push(byte);
+                return Ok(());
+            }
+            b'u' => return self.utf8_escape(bytes, start),
+            b'z' => {
+                // skip the following whitespace, including line breaks
+                self.pos += 1;
+                loop {
+                    match self.peek() {
+                        Some(b'\n') | Some(b'\r') => self.newline(),
+                        Some(c) if c.is_ascii_whitespace() => self.pos += 1,
+                        _ => return Ok(()),
+                    }
+                }
+            }
+            b'0'..=b'9' => {
+                // up to three decimal digits
+                let mut value = 0u32;
+                for _ in 0..3 {
+                    match self.peek() {
+                        Some(c) if c.is_ascii_digit() => value = value * 10 + (c - b'0') as u32,
+                        _ => break,
+                    }
+                    self.pos += 1;
+                }
+                if value > 0xFF {
+                    return self.error("decimal escape too large", start);
+                }
+                bytes.push(value as u8);
+                return Ok(());
+            }
+            _ => {
+                self.pos += 1;
+                return self.error("invalid escape sequence", start);
+            }
+        };
+        self.pos += 1;
+        bytes.push(byte);
+        Ok(())
+    }
+    /// Read a `\u{XXX}` escape after its backslash, which like Lua 5.4 may
+    /// encode any value below 2^31 in the extended UTF-8 scheme
+    fn utf8_escape(&mut self, bytes: &mut Vec<u8>, start: usize) -> Result<()> {
+        self.pos += 1;
+        if self.peek() != Some(b'{') {
+            self.pos += usize::from(self.peek().is_some());
+            return self.error("missing '{' in \\u{xxxx}", start);
+        }
+        self.pos += 1;
+        let mut value: u32 = 0;
+        let mut digits = 0;
+        while let Some(digit) = self.peek().and_then(|c| (c as char).to_digit(16)) {
+            self.pos += 1;
+            digits += 1;
+            value = match value.checked_mul(16).map(|v| v + digit) {
+                Some(v) if v <= 0x7FFF_FFFF => v,
+                _ => return self.error("UTF-8 value too large", start),
+            };
+        }
+        if digits == 0 {
+            self.pos += usize::from(self.peek().is_some());
+            return self.error("hexadecimal digit expected", start);
+        }
+        if self.peek() != Some(b'}') {
+            self.pos += usize::from(self.peek().is_some());
+            return self.error("missing '}' in \\u{xxxx}", start);
+        }
+        self.pos += 1;
+        utf8_encode(value, bytes);
+        Ok(())
+    }
+}
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Token>;
+    /// Produce tokens up to but not including `TokenKind::Eof`
+    fn next(&mut self) -> Option<Result<Token>> {
+        match self.next_token() {
+            Ok(Token {
+                kind: TokenKind::Eof,
+                ..
+            }) => None,
+            token => Some(token),
+        }
+    }
+}
+
+/// Split all of `src` into tokens, ending with `TokenKind::Eof`
+pub fn tokenize(src: &[u8]) -> Result<Vec<Token>> {
+    let mut lexer = Lexer::new(src);
+    let mut tokens = Vec::new();
+    loop {
+        let token = lexer.next_token()?;
+        let done = token.kind == TokenKind::Eof;
+        tokens.push(token);
+        if done {
+            return Ok(tokens);
+        }
+    }
+}
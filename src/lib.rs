@@ -0,0 +1,46 @@
+#[cfg(target_os = "linux")]
+extern crate libc;
+
+pub mod ast;
+pub mod bench;
+mod chunk;
+pub mod config;
+pub mod coverage;
+mod debugger;
+pub mod diagnostic;
+pub mod doc;
+mod error;
+pub mod format;
+mod gc;
+pub mod graph;
+mod hook;
+pub mod inspect;
+mod interp;
+pub mod lexer;
+mod limits;
+pub mod lint;
+mod lua;
+mod number;
+pub mod parser;
+pub mod profile;
+mod stdlib;
+mod table;
+pub mod testing;
+mod trace;
+mod userdata;
+mod value;
+mod vm;
+
+pub use chunk::ChunkCacheStats;
+pub use debugger::{Debugger, PauseReason, Paused, Resume};
+pub use error::{Error, ParseError, Result};
+pub use hook::{FrameInfo, HookEvent, HookInfo, HookMask};
+pub use limits::{GcMode, InterruptHandle, MemoryStats, PoolCounts, PoolStats};
+pub use lua::{Backend, Lua};
+pub use number::Number;
+pub use table::Table;
+pub use userdata::{AnyUserData, LightUserdata, UserData, UserDataMethods};
+pub use value::{
+    ArithOp, ConvertValue, Frozen, InternStats, LuaBool, LuaFunction, LuaInteger, LuaNil,
+    LuaNumber, LuaString, LuaTable, LuaUserdata, MultiValue, Type, Value, WeakValue,
+};
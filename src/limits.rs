@@ -0,0 +1,698 @@
+//! Limits on what scripts can do, which are set per state
+//!
+//! A state shares its limits with the functions it loads, and they with
+//! the functions they create, so the limits hold however a function ends up
+//! being called. How deeply calls nest is counted per thread, like
+//! tracebacks, since nested calls share the thread's stack whichever state
+//! their functions belong to.
+//!
+//! Memory is counted per state instead. Values are charged to the state
+//! whose function is running, or whose API made them, when they are
+//! created, and credited back to it when they are freed, so what one state
+//! leaves behind never counts against another. It is also what decides
+//! when garbage is collected: a collection starts once a state's values
+//! have grown by the state's pause since the last one ended, at its next
+//! step, and goes on a step each time they grow by another `STEP_SIZE`.
+//! In generational mode, the values created since the last collection are
+//! collected all at once instead each time the values grow by the minor
+//! multiplier, and every value once they grow by the major multiplier.
+
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::RandomState;
+use std::fmt;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use error::{Error, Result};
+use gc;
+use hook::Hooks;
+use table::{HashPart, Slot};
+use value::GcBox;
+
+/// How deeply calls can nest on the thread's stack by default, which the
+/// stack running out usually stops well before, but which keeps a script
+/// on a huge stack from recursing for ever
+const DEFAULT_CALL_LIMIT: usize = 200_000;
+
+/// How much of the thread's stack is kept free for what runs between two
+/// calls that nest on it, such as a metamethod's or a library function's
+/// code, which takes up to a few tens of KiB in a debug build, along with
+/// raising and reporting the error once the stack runs out
+const STACK_RESERVE: usize = 256 * 1024;
+
+/// How much stack the calls can use from where the outermost one started,
+/// on systems where how big the thread's stack is can't be found out, which
+/// is what a thread Rust spawns has by default less the reserve
+const FALLBACK_STACK: usize = 2 * 1024 * 1024 - STACK_RESERVE;
+
+/// How many stack slots the calls in a VM thread can use by default, which
+/// is the limit Lua itself has
+const DEFAULT_STACK_LIMIT: usize = 1_000_000;
+
+/// How many bytes a state's values can take up before garbage is first
+/// collected, so that small scripts never have to wait for a collection
+const FIRST_COLLECTION: usize = 256 * 1024;
+
+/// How many bytes a state allocates between the steps of a collection
+const STEP_SIZE: usize = 8 * 1024;
+
+/// How many values and references a collection looks at for each kilobyte
+/// allocated, with the default step multiplier
+const WORK_PER_KB: usize = 32;
+
+/// How much values can grow after a collection before the next starts by
+/// default, as a percentage of what they took up, so that they double
+const DEFAULT_PAUSE: u32 = 200;
+
+/// How fast a collection goes by default, as a percentage of `WORK_PER_KB`
+const DEFAULT_STEP_MULTIPLIER: u32 = 100;
+
+/// How much values can grow between minor collections by default, as a
+/// percentage of what they took up after the last major one
+const DEFAULT_MINOR_MULTIPLIER: u32 = 20;
+
+/// How much values can grow before a major collection by default, as a
+/// percentage of what they took up after the last one
+const DEFAULT_MAJOR_MULTIPLIER: u32 = 100;
+
+/// How many allocations of each kind a state keeps for reuse by default
+const DEFAULT_POOL_LIMIT: usize = 256;
+
+thread_local! {
+    /// how many calls to Lua functions are running
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+    /// how many of the calls running nest on the thread's stack, which are
+    /// those the interpreter makes, calls into the VM from Rust code and
+    /// coroutines being resumed
+    static NATIVE: Cell<usize> = const { Cell::new(0) };
+    /// the lowest address the thread's stack can grow to before calls
+    /// nesting on it fail, where how big the stack is isn't known
+    static STACK_END: Cell<usize> = const { Cell::new(0) };
+    /// the accounts of the states running, innermost last, which new
+    /// values are charged to
+    static CHARGED: RefCell<Vec<Rc<Account>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Roughly how many bytes the values charged to a state take up, by type
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub strings: usize,
+    /// tables along with their entries
+    pub tables: usize,
+    /// Rust and Lua functions, without the chunks that Lua functions share
+    pub functions: usize,
+    pub userdata: usize,
+    /// coroutines, without their stacks
+    pub threads: usize,
+}
+impl MemoryStats {
+    /// All of them together, which is what the memory limit is checked
+    /// against
+    pub fn total(&self) -> usize {
+        self.strings + self.tables + self.functions + self.userdata + self.threads
+    }
+    fn count(&mut self, kind: Kind) -> &mut usize {
+        match kind {
+            Kind::Strings => &mut self.strings,
+            Kind::Tables => &mut self.tables,
+            Kind::Functions => &mut self.functions,
+            Kind::Userdata => &mut self.userdata,
+            Kind::Threads => &mut self.threads,
+        }
+    }
+}
+
+/// How a state collects garbage
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum GcMode {
+    /// look at every value, a step at a time
+    Incremental,
+    /// look at the values created since the last collection most of the
+    /// time, which is quicker when most values don't last long, and at
+    /// every value now and then
+    Generational,
+}
+
+/// How a state's pools of allocations have been used, for tuning how many
+/// they keep
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    /// payloads of values: strings, Rust functions and, with NaN-boxing,
+    /// integers, which values of any type can reuse
+    pub values: PoolCounts,
+    /// the room for the sequences of small tables
+    pub arrays: PoolCounts,
+    /// the room for the other entries of small tables
+    pub hashes: PoolCounts,
+}
+
+/// How one of a state's pools has been used
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct PoolCounts {
+    /// how many allocations it keeps now
+    pub kept: usize,
+    /// how many allocations were reused from it
+    pub hits: u64,
+    /// how many had to be made as it was empty
+    pub misses: u64,
+}
+
+/// Allocations kept once what they held is freed, so that a script making
+/// and dropping values in a loop doesn't go to the allocator every time
+///
+/// What it keeps isn't counted as memory the state's values take up. It is
+/// only borrowed briefly, as freeing a value can free others.
+pub(crate) struct Pool<T> {
+    kept: RefCell<Vec<T>>,
+    limit: Cell<usize>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+}
+impl<T> Pool<T> {
+    /// An allocation to reuse, if there are any
+    pub fn take(&self) -> Option<T> {
+        let kept = self.kept.borrow_mut().pop();
+        let count = if kept.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        count.set(count.get() + 1);
+        kept
+    }
+    /// Whether there is room to keep another allocation, which is worth
+    /// checking before getting one ready to keep
+    pub fn has_room(&self) -> bool {
+        self.kept.borrow().len() < self.limit.get()
+    }
+    /// Keep `item` for reuse, unless the pool is full
+    pub fn give(&self, item: T) {
+        let full = {
+            let mut kept = self.kept.borrow_mut();
+            if kept.len() < self.limit.get() {
+                kept.push(item);
+                None
+            } else {
+                Some(item)
+            }
+        };
+        drop(full);
+    }
+    fn set_limit(&self, limit: usize) {
+        self.limit.set(limit);
+        let extra = {
+            let mut kept = self.kept.borrow_mut();
+            let len = kept.len().min(limit);
+            kept.split_off(len)
+        };
+        drop(extra);
+    }
+    fn counts(&self) -> PoolCounts {
+        PoolCounts {
+            kept: self.kept.borrow().len(),
+            hits: self.hits.get(),
+            misses: self.misses.get(),
+        }
+    }
+}
+impl<T> Default for Pool<T> {
+    fn default() -> Pool<T> {
+        Pool {
+            kept: RefCell::default(),
+            limit: Cell::new(DEFAULT_POOL_LIMIT),
+            hits: Cell::default(),
+            misses: Cell::default(),
+        }
+    }
+}
+impl<T> fmt::Debug for Pool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&self.counts(), f)
+    }
+}
+
+/// Which of the counts in `MemoryStats` a value is charged to
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Kind {
+    Strings,
+    Tables,
+    Functions,
+    Userdata,
+    Threads,
+}
+
+/// Roughly how many bytes a state's values take up, which the values keep
+/// alive along with it so they can be credited back however long they last
+///
+/// It also holds the keys that the state's tables hash with. Each state
+/// draws its own, so keys crafted to collide in one state's tables don't
+/// collide in another's, and can't be worked out from the source. And it
+/// holds the state's pools, whose allocations go back to the state whose
+/// values they were.
+#[derive(Debug, Default)]
+pub(crate) struct Account {
+    used: Cell<usize>,
+    stats: Cell<MemoryStats>,
+    hasher: RandomState,
+    pub values: Pool<Box<GcBox>>,
+    pub arrays: Pool<Vec<Slot>>,
+    pub hashes: Pool<HashPart>,
+}
+impl Account {
+    pub fn used(&self) -> usize {
+        self.used.get()
+    }
+    pub fn stats(&self) -> MemoryStats {
+        self.stats.get()
+    }
+    pub fn hasher(&self) -> &RandomState {
+        &self.hasher
+    }
+    pub fn pool_stats(&self) -> PoolStats {
+        PoolStats {
+            values: self.values.counts(),
+            arrays: self.arrays.counts(),
+            hashes: self.hashes.counts(),
+        }
+    }
+    /// Keep up to `limit` allocations in each pool, dropping any beyond
+    /// that
+    pub fn set_pool_limit(&self, limit: usize) {
+        self.values.set_limit(limit);
+        self.arrays.set_limit(limit);
+        self.hashes.set_limit(limit);
+    }
+    /// Count `bytes` being allocated for a value
+    pub fn allocate(&self, kind: Kind, bytes: usize) {
+        self.used.set(self.used.get() + bytes);
+        let mut stats = self.stats.get();
+        *stats.count(kind) += bytes;
+        self.stats.set(stats);
+    }
+    /// Count `bytes` that a value allocated being freed
+    pub fn free(&self, kind: Kind, bytes: usize) {
+        self.used.set(self.used.get() - bytes);
+        let mut stats = self.stats.get();
+        *stats.count(kind) -= bytes;
+        self.stats.set(stats);
+    }
+}
+
+pub(crate) struct Limits {
+    /// how deeply calls can nest on the thread's stack
+    calls: Cell<usize>,
+    /// how many stack slots the calls in a VM thread can use
+    stack: Cell<usize>,
+    /// how many more steps can be run, if that is limited
+    steps: Cell<Option<u64>>,
+    /// how many bytes values can take up, if that is limited
+    memory: Cell<Option<usize>>,
+    /// how many bytes they do take up
+    account: Rc<Account>,
+    /// how many bytes they can take up before the next collection step
+    collect_at: Cell<usize>,
+    /// how much they can grow after a collection, as a percentage
+    pause: Cell<u32>,
+    /// how fast collections go, as a percentage
+    step_multiplier: Cell<u32>,
+    mode: Cell<GcMode>,
+    /// how much they can grow between minor collections, as a percentage
+    minor_multiplier: Cell<u32>,
+    /// how much they can grow before a major collection, as a percentage
+    major_multiplier: Cell<u32>,
+    /// how many bytes they took up after the last major collection
+    after_major: Cell<usize>,
+    interrupt: InterruptHandle,
+    /// the hook called as the state's functions run
+    pub hooks: Hooks,
+}
+impl Limits {
+    pub fn new() -> Limits {
+        Limits {
+            calls: Cell::new(DEFAULT_CALL_LIMIT),
+            stack: Cell::new(DEFAULT_STACK_LIMIT),
+            steps: Cell::new(None),
+            memory: Cell::new(None),
+            account: Rc::default(),
+            collect_at: Cell::new(FIRST_COLLECTION),
+            pause: Cell::new(DEFAULT_PAUSE),
+            step_multiplier: Cell::new(DEFAULT_STEP_MULTIPLIER),
+            mode: Cell::new(GcMode::Incremental),
+            minor_multiplier: Cell::new(DEFAULT_MINOR_MULTIPLIER),
+            major_multiplier: Cell::new(DEFAULT_MAJOR_MULTIPLIER),
+            after_major: Cell::new(0),
+            interrupt: InterruptHandle::default(),
+            hooks: Hooks::default(),
+        }
+    }
+    pub fn call_limit(&self) -> usize {
+        self.calls.get()
+    }
+    pub fn set_call_limit(&self, limit: usize) {
+        self.calls.set(limit);
+    }
+    pub fn stack_limit(&self) -> usize {
+        self.stack.get()
+    }
+    pub fn set_stack_limit(&self, limit: usize) {
+        self.stack.set(limit);
+    }
+    pub fn steps_left(&self) -> Option<u64> {
+        self.steps.get()
+    }
+    pub fn set_steps_left(&self, steps: Option<u64>) {
+        self.steps.set(steps);
+    }
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.memory.get()
+    }
+    pub fn set_memory_limit(&self, limit: Option<usize>) {
+        self.memory.set(limit);
+        self.schedule_collection();
+    }
+    pub fn pause(&self) -> u32 {
+        self.pause.get()
+    }
+    pub fn set_pause(&self, pause: u32) {
+        self.pause.set(pause);
+    }
+    pub fn step_multiplier(&self) -> u32 {
+        self.step_multiplier.get()
+    }
+    pub fn set_step_multiplier(&self, multiplier: u32) {
+        self.step_multiplier.set(multiplier);
+    }
+    pub fn gc_mode(&self) -> GcMode {
+        self.mode.get()
+    }
+    /// Switch to collecting garbage in `mode`, giving the mode before
+    ///
+    /// Switching to generational mode collects every value, which makes
+    /// all those left old.
+    pub fn set_gc_mode(&self, mode: GcMode) -> GcMode {
+        let previous = self.mode.replace(mode);
+        if previous == mode {
+            return previous;
+        }
+        match mode {
+            GcMode::Generational => self.collect(),
+            GcMode::Incremental => self.schedule_collection(),
+        }
+        previous
+    }
+    pub fn minor_multiplier(&self) -> u32 {
+        self.minor_multiplier.get()
+    }
+    pub fn set_minor_multiplier(&self, multiplier: u32) {
+        self.minor_multiplier.set(multiplier);
+    }
+    pub fn major_multiplier(&self) -> u32 {
+        self.major_multiplier.get()
+    }
+    pub fn set_major_multiplier(&self, multiplier: u32) {
+        self.major_multiplier.set(multiplier);
+    }
+    /// Roughly how many bytes the values charged to this state take up
+    pub fn memory_used(&self) -> usize {
+        self.account.used()
+    }
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.account.stats()
+    }
+    pub fn pool_stats(&self) -> PoolStats {
+        self.account.pool_stats()
+    }
+    pub fn pool_limit(&self) -> usize {
+        self.account.values.limit.get()
+    }
+    pub fn set_pool_limit(&self, limit: usize) {
+        self.account.set_pool_limit(limit);
+    }
+    /// Run `f`, charging the values it creates to this state
+    pub fn charge<T, F>(&self, f: F) -> T
+    where
+        F: FnOnce() -> T,
+    {
+        CHARGED.with(|charged| charged.borrow_mut().push(self.account.clone()));
+        let result = f();
+        CHARGED.with(|charged| charged.borrow_mut().pop());
+        result
+    }
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.interrupt.clone()
+    }
+    /// Count a step, which is an instruction in the VM, or a statement or
+    /// loop iteration in the interpreter, failing once there are none left,
+    /// values take up more memory than the state allows or the state has
+    /// been interrupted
+    ///
+    /// Nothing is counted once the budget runs out, so a script that
+    /// catches the error fails again at its next step. A step of collecting
+    /// garbage is done first if the values have grown enough.
+    #[inline]
+    pub fn step(&self) -> Result<()> {
+        if self.interrupt.is_interrupted() {
+            return Err(Error::Runtime("interrupted!".to_string()));
+        }
+        match self.steps.get() {
+            None => {}
+            Some(0) => return Err(Error::Runtime("instruction limit exceeded".to_string())),
+            Some(left) => self.steps.set(Some(left - 1)),
+        }
+        if self.account.used() >= self.collect_at.get() {
+            self.collect_step();
+        }
+        match self.memory.get() {
+            Some(limit) if self.account.used() > limit => {
+                Err(Error::Runtime("not enough memory".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+    /// Collect garbage all at once and run the finalizers of what has been
+    /// collected
+    #[cold]
+    pub fn collect(&self) {
+        // the finalizers run steps too, which mustn't collect again
+        self.collect_at.set(usize::MAX);
+        gc::collect();
+        // as at a safe point, errors in finalizers are dropped
+        let _ = gc::run_finalizers();
+        self.after_major.set(self.account.used());
+        self.schedule_collection();
+    }
+    /// Do a step of collecting garbage, as if `bytes` had been allocated,
+    /// and run the finalizers of what has been collected, giving whether
+    /// that finished a collection
+    ///
+    /// In generational mode, a step is a whole minor or major collection.
+    pub fn collect_some(&self, bytes: usize) -> bool {
+        if self.mode.get() == GcMode::Generational {
+            self.collect_young();
+            return true;
+        }
+        self.collect_at.set(usize::MAX);
+        let work = bytes / 1024 * WORK_PER_KB * self.step_multiplier.get() as usize / 100;
+        let done = gc::step(work.max(1)).is_some();
+        let _ = gc::run_finalizers();
+        if done {
+            self.schedule_collection();
+        } else {
+            self.schedule(self.account.used().saturating_add(STEP_SIZE));
+        }
+        done
+    }
+    /// The step that values growing enough calls for, which collects all
+    /// at once as they reach the memory limit, so that garbage is freed
+    /// before the limit is enforced
+    #[cold]
+    fn collect_step(&self) {
+        match self.memory.get() {
+            Some(limit) if self.account.used() >= limit => self.collect(),
+            _ => {
+                self.collect_some(STEP_SIZE);
+            }
+        }
+    }
+    /// Collect the values created since the last collection, or every value
+    /// if they have grown by the major multiplier since the last major one,
+    /// and run the finalizers of what has been collected
+    fn collect_young(&self) {
+        let major = self.major_multiplier.get() as usize;
+        if self.account.used() >= self.since_major().saturating_mul(100 + major) {
+            return self.collect();
+        }
+        self.collect_at.set(usize::MAX);
+        gc::collect_young();
+        let _ = gc::run_finalizers();
+        self.schedule_collection();
+    }
+    /// A hundredth of what the values took up after the last major
+    /// collection, or the first collection when that was smaller
+    fn since_major(&self) -> usize {
+        self.after_major.get().max(FIRST_COLLECTION) / 100
+    }
+    /// Start the next collection once the values have grown by the pause,
+    /// or by the minor multiplier in generational mode
+    fn schedule_collection(&self) {
+        let used = self.account.used();
+        let next = match self.mode.get() {
+            GcMode::Incremental => {
+                let next = used / 100 * self.pause.get() as usize;
+                next.max(FIRST_COLLECTION)
+            }
+            GcMode::Generational => {
+                let minor = self.minor_multiplier.get().max(1) as usize;
+                used.saturating_add(self.since_major() * minor)
+            }
+        };
+        self.schedule(next);
+    }
+    /// Do the next collection step once the values take up `next` bytes,
+    /// or as they reach the memory limit
+    fn schedule(&self, mut next: usize) {
+        if let Some(limit) = self.memory.get() {
+            // unless collecting didn't bring them under it, which would
+            // collect again at every step
+            if self.account.used() < limit {
+                next = next.min(limit);
+            }
+        }
+        self.collect_at.set(next);
+    }
+    /// Count a call that nests on the thread's stack starting, which fails
+    /// once those nest as deeply as the state allows, or the stack has too
+    /// little room left for another, and charge the values created until it
+    /// ends to this state
+    ///
+    /// It is counted apart from the call to the Lua function it makes, as
+    /// calls between functions in a VM thread don't nest on the stack.
+    pub fn enter_native(&self) -> Result<()> {
+        NATIVE.with(|depth| {
+            if depth.get() >= self.calls.get() || stack_exhausted(depth.get()) {
+                return Err(Error::Runtime("stack overflow".to_string()));
+            }
+            depth.set(depth.get() + 1);
+            Ok(())
+        })?;
+        CHARGED.with(|charged| charged.borrow_mut().push(self.account.clone()));
+        Ok(())
+    }
+    /// Check that a VM thread's calls can use `slots` stack slots
+    pub fn check_stack(&self, slots: usize) -> Result<()> {
+        if slots > self.stack.get() {
+            return Err(Error::Runtime("stack overflow".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// A handle that stops a state's functions from another thread, which
+/// raise an "interrupted!" error at their next step once it is tripped
+///
+/// The handle stays tripped until it is reset, so a script that catches
+/// the error fails again straight away and the error reaches the host.
+#[derive(Clone, Debug, Default)]
+pub struct InterruptHandle {
+    tripped: Arc<AtomicBool>,
+}
+impl InterruptHandle {
+    /// Make the state's functions stop at their next step
+    pub fn interrupt(&self) {
+        self.tripped.store(true, Ordering::Relaxed);
+    }
+    /// Let the state's functions run again
+    pub fn reset(&self) {
+        self.tripped.store(false, Ordering::Relaxed);
+    }
+    pub fn is_interrupted(&self) -> bool {
+        self.tripped.load(Ordering::Relaxed)
+    }
+}
+
+/// Whether the thread's stack is too close to running out for another call
+/// to nest on it, where `depth` is how many already do
+fn stack_exhausted(depth: usize) -> bool {
+    let here = stack_pointer();
+    let end = match stack_start() {
+        Some(start) => start + STACK_RESERVE,
+        // the outermost call marks how far the calls can go from it
+        None => STACK_END.with(|end| {
+            if depth == 0 {
+                end.set(here.saturating_sub(FALLBACK_STACK));
+            }
+            end.get()
+        }),
+    };
+    here < end
+}
+
+/// Roughly where the thread's stack is at, which is lower the more it
+/// holds
+#[inline(never)]
+fn stack_pointer() -> usize {
+    let marker = 0u8;
+    &marker as *const u8 as usize
+}
+
+/// The lowest address of the thread's stack, if it can be found out
+#[cfg(target_os = "linux")]
+fn stack_start() -> Option<usize> {
+    use std::{mem, ptr};
+    thread_local! {
+        static START: Option<usize> = unsafe {
+            let mut attr: libc::pthread_attr_t = mem::zeroed();
+            if libc::pthread_getattr_np(libc::pthread_self(), &mut attr) != 0 {
+                None
+            } else {
+                let mut addr = ptr::null_mut();
+                let mut size = 0;
+                let found = libc::pthread_attr_getstack(&attr, &mut addr, &mut size) == 0;
+                libc::pthread_attr_destroy(&mut attr);
+                if found && size > STACK_RESERVE {
+                    Some(addr as usize)
+                } else {
+                    None
+                }
+            }
+        };
+    }
+    START.with(|start| *start)
+}
+#[cfg(not(target_os = "linux"))]
+fn stack_start() -> Option<usize> {
+    None
+}
+
+/// How many calls to Lua functions are running on this thread
+pub fn depth() -> usize {
+    DEPTH.with(Cell::get)
+}
+
+/// Count `n` calls to Lua functions starting, or being resumed along with
+/// their coroutine
+pub fn enter_calls(n: usize) {
+    DEPTH.with(|depth| depth.set(depth.get() + n));
+}
+
+/// Count `n` calls ending, or being suspended along with their coroutine
+pub fn leave_calls(n: usize) {
+    DEPTH.with(|depth| depth.set(depth.get() - n));
+}
+
+/// Count a call counted by `Limits::enter_native` ending
+pub fn leave_native() {
+    NATIVE.with(|depth| depth.set(depth.get() - 1));
+    CHARGED.with(|charged| charged.borrow_mut().pop());
+}
+
+/// The account of the innermost state running, which a value created now
+/// is charged to, if there is one
+pub fn charged() -> Option<Rc<Account>> {
+    // values can be created during thread teardown, after the list is gone
+    CHARGED
+        .try_with(|charged| charged.borrow().last().cloned())
+        .ok()
+        .flatten()
+}
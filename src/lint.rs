@@ -0,0 +1,599 @@
+//! Finding likely mistakes in a chunk without running it
+//!
+//! The parsed tree is walked with the locals in scope at each point, as
+//! the compilers resolve names, to find:
+//!
+//! - `undefined-global`: reads of globals that nothing in the chunk assigns
+//!   and that are not defined before it runs
+//! - `unused-local`: locals, local functions and loop variables that are
+//!   never read, unless their names start with `_`
+//! - `shadowed-local`: locals declared with the name of one already in
+//!   scope
+//! - `unreachable-code`: statements after a `return`, `break` or `goto`
+//!   that no label makes reachable again
+//! - `empty-if`: branches of an `if` with nothing in them
+//!
+//! Comments of the form `-- lint: ignore [rules]` turn the rules listed, or
+//! all of them, off for the line they are on, or the next line if they are
+//! on a line of their own. `-- lint: disable [rules]` turns them off until
+//! a `-- lint: enable [rules]` turns them back on.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::rc::Rc;
+
+use ast::{Ast, Block, Expr, ExprKind, FieldKind, FuncBody, Location, Name, Stat, StatKind};
+use error::ParseError;
+use lexer::{Lexer, Token, TokenKind};
+use parser;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Rule {
+    UndefinedGlobal,
+    UnusedLocal,
+    ShadowedLocal,
+    UnreachableCode,
+    EmptyIf,
+}
+impl Rule {
+    pub const ALL: [Rule; 5] = [
+        Rule::UndefinedGlobal,
+        Rule::UnusedLocal,
+        Rule::ShadowedLocal,
+        Rule::UnreachableCode,
+        Rule::EmptyIf,
+    ];
+    /// The name the rule is given in lint comments and warnings
+    pub fn name(self) -> &'static str {
+        match self {
+            Rule::UndefinedGlobal => "undefined-global",
+            Rule::UnusedLocal => "unused-local",
+            Rule::ShadowedLocal => "shadowed-local",
+            Rule::UnreachableCode => "unreachable-code",
+            Rule::EmptyIf => "empty-if",
+        }
+    }
+    pub fn from_name(name: &str) -> Option<Rule> {
+        Rule::ALL.iter().cloned().find(|rule| rule.name() == name)
+    }
+}
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+    /// the rule broken, or `None` for a lint comment that cannot be read
+    pub rule: Option<Rule>,
+    pub message: String,
+    pub loc: Location,
+}
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: {}",
+            self.loc.chunk, self.loc.pos.line, self.loc.pos.column, self.message
+        )?;
+        match self.rule {
+            Some(rule) => write!(f, " [{}]", rule),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Lint the chunk `src`, named `chunk`, where `globals` are the globals
+/// defined before it runs, giving the warnings in the order of the source
+pub fn lint(
+    src: &[u8],
+    chunk: &str,
+    globals: &HashSet<String>,
+) -> Result<Vec<Warning>, ParseError> {
+    let ast = parser::parse_chunk(src, chunk)?;
+    let mut linter = Linter::new(src, chunk, &ast)?;
+    linter.scopes.push(Vec::new());
+    linter.stats(&ast[ast.main()].body);
+    linter.end_scope();
+    let Linter {
+        mut warnings,
+        reads,
+        writes,
+        ..
+    } = linter;
+    for (name, loc) in reads {
+        if !writes.contains(&name) && !globals.contains(&*name) {
+            warnings.push(Warning {
+                rule: Some(Rule::UndefinedGlobal),
+                message: format!("global '{}' is never assigned", name),
+                loc,
+            });
+        }
+    }
+    let directives = Directives::read(src, chunk, &mut warnings)?;
+    warnings.retain(|warning| match warning.rule {
+        Some(rule) => !directives.disables(rule, warning.loc.pos.line),
+        None => true,
+    });
+    warnings.sort_by_key(|warning| warning.loc.span.start);
+    Ok(warnings)
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum Kind {
+    Local,
+    Function,
+    LoopVariable,
+    Parameter,
+    /// the `self` of a method, which is not written in the source
+    SelfParameter,
+}
+
+struct Local {
+    name: Name,
+    kind: Kind,
+    loc: Location,
+    used: bool,
+}
+
+struct Linter<'a> {
+    ast: &'a Ast,
+    /// the tokens of the source, to find where names are declared
+    tokens: Vec<Token>,
+    chunk: Rc<str>,
+    scopes: Vec<Vec<Local>>,
+    /// the globals read, with where
+    reads: Vec<(Name, Location)>,
+    /// the globals assigned anywhere in the chunk
+    writes: HashSet<Name>,
+    warnings: Vec<Warning>,
+}
+impl<'a> Linter<'a> {
+    fn new(src: &[u8], chunk: &str, ast: &'a Ast) -> Result<Linter<'a>, ParseError> {
+        let mut lexer = Lexer::new(src);
+        let mut tokens = Vec::new();
+        loop {
+            let token = lexer.next_token()?;
+            if token.kind == TokenKind::Eof {
+                break;
+            }
+            tokens.push(token);
+        }
+        Ok(Linter {
+            ast,
+            tokens,
+            chunk: chunk.into(),
+            scopes: Vec::new(),
+            reads: Vec::new(),
+            writes: HashSet::new(),
+            warnings: Vec::new(),
+        })
+    }
+
+    fn warn(&mut self, rule: Rule, message: String, loc: Location) {
+        self.warnings.push(Warning {
+            rule: Some(rule),
+            message,
+            loc,
+        });
+    }
+    /// Where the token `skip` tokens after the one at `offset` is, such as
+    /// a name declared after a keyword
+    fn token_loc(&self, offset: usize, skip: usize) -> Location {
+        let i = self
+            .tokens
+            .partition_point(|token| token.span.start < offset);
+        self.loc_of(i + skip)
+    }
+    /// Where the token before the one at `offset` is, such as the keyword
+    /// before a block
+    fn token_before(&self, offset: usize) -> Location {
+        let i = self
+            .tokens
+            .partition_point(|token| token.span.start < offset);
+        self.loc_of(i.saturating_sub(1))
+    }
+    fn loc_of(&self, i: usize) -> Location {
+        let token = &self.tokens[i.min(self.tokens.len() - 1)];
+        Location {
+            chunk: self.chunk.clone(),
+            span: token.span,
+            pos: token.pos,
+        }
+    }
+
+    fn declare(&mut self, name: &Name, kind: Kind, loc: Location) {
+        if kind != Kind::SelfParameter && !name.starts_with('_') {
+            let shadowed = self
+                .scopes
+                .iter()
+                .rev()
+                .flat_map(|scope| scope.iter().rev())
+                .find(|local| local.name == *name)
+                .map(|local| local.loc.pos.line);
+            if let Some(line) = shadowed {
+                let message = format!("'{}' shadows the one declared on line {}", name, line);
+                self.warn(Rule::ShadowedLocal, message, loc.clone());
+            }
+        }
+        let local = Local {
+            name: name.clone(),
+            kind,
+            loc,
+            used: false,
+        };
+        self.scopes
+            .last_mut()
+            .expect("declared inside a scope")
+            .push(local);
+    }
+    fn end_scope(&mut self) {
+        let scope = self.scopes.pop().expect("ended a scope that was started");
+        for local in scope {
+            if local.used || local.name.starts_with('_') {
+                continue;
+            }
+            let what = match local.kind {
+                Kind::Local => "local",
+                Kind::Function => "function",
+                Kind::LoopVariable => "loop variable",
+                Kind::Parameter | Kind::SelfParameter => continue,
+            };
+            let message = format!("unused {} '{}'", what, local.name);
+            self.warn(Rule::UnusedLocal, message, local.loc);
+        }
+    }
+    /// The local `name` refers to here, if it is one
+    fn resolve(&mut self, name: &Name) -> Option<&mut Local> {
+        self.scopes
+            .iter_mut()
+            .rev()
+            .flat_map(|scope| scope.iter_mut().rev())
+            .find(|local| local.name == *name)
+    }
+    fn read(&mut self, name: &Name, loc: &Location) {
+        match self.resolve(name) {
+            Some(local) => local.used = true,
+            None => self.reads.push((name.clone(), loc.clone())),
+        }
+    }
+    fn write(&mut self, name: &Name) {
+        if self.resolve(name).is_none() {
+            self.writes.insert(name.clone());
+        }
+    }
+
+    fn block(&mut self, block: &Block) {
+        self.scopes.push(Vec::new());
+        self.stats(block);
+        self.end_scope();
+    }
+    /// The statements of `block`, in the current scope
+    fn stats(&mut self, block: &Block) {
+        let ast = self.ast;
+        let mut exited = false;
+        let mut reported = false;
+        for stat in &ast[block.stats] {
+            if let StatKind::Label(_) = stat.kind {
+                exited = false;
+            } else if exited && !reported {
+                self.warn(
+                    Rule::UnreachableCode,
+                    "unreachable code".to_string(),
+                    stat.loc.clone(),
+                );
+                reported = true;
+            }
+            self.stat(stat);
+            exited |= exits(ast, stat);
+        }
+        if let Some(values) = block.ret {
+            let values = &ast[values];
+            match values.first() {
+                Some(first) if exited && !reported => self.warn(
+                    Rule::UnreachableCode,
+                    "unreachable code".to_string(),
+                    first.loc.clone(),
+                ),
+                _ => {}
+            }
+            self.exprs(values);
+        }
+    }
+    fn stat(&mut self, stat: &Stat) {
+        let ast = self.ast;
+        let start = stat.loc.span.start;
+        match stat.kind {
+            StatKind::Assign(targets, values) => {
+                self.exprs(&ast[values]);
+                for target in &ast[targets] {
+                    match target.kind {
+                        ExprKind::Name(ref name) => self.write(name),
+                        _ => self.expr(target),
+                    }
+                }
+            }
+            StatKind::Call(call) => self.expr(&ast[call]),
+            StatKind::Do(ref body) => self.block(body),
+            StatKind::While(cond, ref body) => {
+                self.expr(&ast[cond]);
+                self.block(body);
+            }
+            StatKind::Repeat(ref body, cond) => {
+                // the condition can see the body's locals
+                self.scopes.push(Vec::new());
+                self.stats(body);
+                self.expr(&ast[cond]);
+                self.end_scope();
+            }
+            StatKind::If(branches, ref otherwise) => {
+                for (i, &(cond, ref body)) in ast[branches].iter().enumerate() {
+                    let cond = &ast[cond];
+                    self.expr(cond);
+                    if is_empty(body) {
+                        let (what, loc) = if i == 0 {
+                            ("if", stat.loc.clone())
+                        } else {
+                            ("elseif", self.token_before(cond.loc.span.start))
+                        };
+                        let message = format!("empty '{}' branch", what);
+                        self.warn(Rule::EmptyIf, message, loc);
+                    }
+                    self.block(body);
+                }
+                if let Some(ref body) = *otherwise {
+                    if is_empty(body) {
+                        let loc = self.token_before(body.loc.span.start);
+                        self.warn(Rule::EmptyIf, "empty 'else' branch".to_string(), loc);
+                    }
+                    self.block(body);
+                }
+            }
+            StatKind::NumericFor {
+                ref var,
+                start,
+                limit,
+                step,
+                ref body,
+            } => {
+                self.expr(&ast[start]);
+                self.expr(&ast[limit]);
+                if let Some(step) = step {
+                    self.expr(&ast[step]);
+                }
+                self.scopes.push(Vec::new());
+                let loc = self.token_loc(stat.loc.span.start, 1);
+                self.declare(var, Kind::LoopVariable, loc);
+                self.block(body);
+                self.end_scope();
+            }
+            StatKind::GenericFor {
+                vars,
+                exprs,
+                ref body,
+            } => {
+                self.exprs(&ast[exprs]);
+                self.scopes.push(Vec::new());
+                for (i, var) in ast[vars].iter().enumerate() {
+                    let loc = self.token_loc(start, 1 + 2 * i);
+                    self.declare(var, Kind::LoopVariable, loc);
+                }
+                self.block(body);
+                self.end_scope();
+            }
+            StatKind::Function(ref name, body) => {
+                let path = &ast[name.path];
+                if path.len() == 1 && name.method.is_none() {
+                    self.write(&path[0]);
+                } else {
+                    let loc = self.token_loc(start, 1);
+                    self.read(&path[0], &loc);
+                }
+                self.function(&ast[body], name.method.is_some());
+            }
+            StatKind::LocalFunction(ref name, body) => {
+                let loc = self.token_loc(start, 2);
+                self.declare(name, Kind::Function, loc);
+                self.function(&ast[body], false);
+            }
+            StatKind::Local(names, values) => {
+                self.exprs(&ast[values]);
+                for (i, name) in ast[names].iter().enumerate() {
+                    let loc = self.token_loc(start, 1 + 2 * i);
+                    self.declare(name, Kind::Local, loc);
+                }
+            }
+            StatKind::Break | StatKind::Goto(_) | StatKind::Label(_) => {}
+        }
+    }
+    fn function(&mut self, body: &FuncBody, is_method: bool) {
+        let params = &self.ast[body.params];
+        self.scopes.push(Vec::new());
+        let skip = if is_method { 1 } else { 0 };
+        if is_method {
+            let loc = body.loc.clone();
+            self.declare(&params[0], Kind::SelfParameter, loc);
+        }
+        for (i, param) in params[skip..].iter().enumerate() {
+            // after the `(` and any names and commas before it
+            let loc = self.token_loc(body.loc.span.start, 1 + 2 * i);
+            self.declare(param, Kind::Parameter, loc);
+        }
+        self.stats(&body.body);
+        self.end_scope();
+    }
+
+    fn exprs(&mut self, exprs: &[Expr]) {
+        for expr in exprs {
+            self.expr(expr);
+        }
+    }
+    fn expr(&mut self, expr: &Expr) {
+        let ast = self.ast;
+        match expr.kind {
+            ExprKind::Nil
+            | ExprKind::True
+            | ExprKind::False
+            | ExprKind::Number(_)
+            | ExprKind::String(_)
+            | ExprKind::Vararg => {}
+            ExprKind::Function(body) => self.function(&ast[body], false),
+            ExprKind::Table(fields) => {
+                for field in &ast[fields] {
+                    match field.kind {
+                        FieldKind::Named(_, val) | FieldKind::Positional(val) => {
+                            self.expr(&ast[val])
+                        }
+                        FieldKind::Indexed(key, val) => {
+                            self.expr(&ast[key]);
+                            self.expr(&ast[val]);
+                        }
+                    }
+                }
+            }
+            ExprKind::Name(ref name) => self.read(name, &expr.loc),
+            ExprKind::Index(obj, key) => {
+                self.expr(&ast[obj]);
+                self.expr(&ast[key]);
+            }
+            ExprKind::Call(func, args) => {
+                self.expr(&ast[func]);
+                self.exprs(&ast[args]);
+            }
+            ExprKind::Method(obj, _, args) => {
+                self.expr(&ast[obj]);
+                self.exprs(&ast[args]);
+            }
+            ExprKind::Paren(inner) | ExprKind::Unary(_, inner) => self.expr(&ast[inner]),
+            ExprKind::Binary(_, lhs, rhs) => {
+                self.expr(&ast[lhs]);
+                self.expr(&ast[rhs]);
+            }
+        }
+    }
+}
+
+fn is_empty(block: &Block) -> bool {
+    block.stats.is_empty() && block.ret.is_none()
+}
+
+/// Whether nothing after `stat` in its block runs, short of a label
+fn exits(ast: &Ast, stat: &Stat) -> bool {
+    match stat.kind {
+        StatKind::Break | StatKind::Goto(_) => true,
+        StatKind::Do(ref body) => block_exits(ast, body),
+        StatKind::If(branches, Some(ref otherwise)) => {
+            ast[branches].iter().all(|(_, body)| block_exits(ast, body))
+                && block_exits(ast, otherwise)
+        }
+        _ => false,
+    }
+}
+fn block_exits(ast: &Ast, block: &Block) -> bool {
+    block.ret.is_some()
+        || ast[block.stats]
+            .iter()
+            .fold(false, |exited, stat| match stat.kind {
+                StatKind::Label(_) => false,
+                _ => exited || exits(ast, stat),
+            })
+}
+
+/// The rules turned off by lint comments
+struct Directives {
+    /// the lines rules are ignored on, with the rules or `None` for all
+    ignored: Vec<(u32, Option<Vec<Rule>>)>,
+    /// the lines rules are disabled or enabled from, in order
+    toggled: Vec<(u32, bool, Option<Vec<Rule>>)>,
+}
+impl Directives {
+    /// Read the lint comments in `src`, warning about those that cannot be
+    /// read
+    fn read(
+        src: &[u8],
+        chunk: &str,
+        warnings: &mut Vec<Warning>,
+    ) -> Result<Directives, ParseError> {
+        let mut directives = Directives {
+            ignored: Vec::new(),
+            toggled: Vec::new(),
+        };
+        let mut lexer = Lexer::new(src).with_comments(true);
+        let mut last_line = 0;
+        loop {
+            let token = lexer.next_token()?;
+            match token.kind {
+                TokenKind::Eof => return Ok(directives),
+                TokenKind::Comment => {}
+                _ => {
+                    // the line the token ends on
+                    let text = &src[token.span.start..token.span.end];
+                    let breaks = text.iter().filter(|&&c| c == b'\n').count();
+                    last_line = token.pos.line + breaks as u32;
+                    continue;
+                }
+            }
+            let text = String::from_utf8_lossy(&src[token.span.start + 2..token.span.end]);
+            let text = text.trim();
+            if !text.starts_with("lint:") {
+                continue;
+            }
+            let loc = Location {
+                chunk: chunk.into(),
+                span: token.span,
+                pos: token.pos,
+            };
+            let mut words = text["lint:".len()..]
+                .split(|c: char| c.is_whitespace() || c == ',')
+                .filter(|word| !word.is_empty());
+            let action = words.next().unwrap_or("");
+            let mut rules = Vec::new();
+            let mut named = false;
+            for word in words {
+                named = true;
+                match Rule::from_name(word) {
+                    Some(rule) => rules.push(rule),
+                    None => warnings.push(Warning {
+                        rule: None,
+                        message: format!("unknown lint rule '{}'", word),
+                        loc: loc.clone(),
+                    }),
+                }
+            }
+            // only a comment naming no rules at all is about every rule
+            let rules = if named { Some(rules) } else { None };
+            let line = token.pos.line;
+            match action {
+                // a comment after code is about its own line
+                "ignore" if last_line == line => directives.ignored.push((line, rules)),
+                "ignore" => directives.ignored.push((line + 1, rules)),
+                "disable" => directives.toggled.push((line, true, rules)),
+                "enable" => directives.toggled.push((line, false, rules)),
+                _ => warnings.push(Warning {
+                    rule: None,
+                    message: format!(
+                        "unknown lint comment '{}', expected 'ignore', 'disable' or 'enable'",
+                        action
+                    ),
+                    loc,
+                }),
+            }
+        }
+    }
+    fn disables(&self, rule: Rule, line: u32) -> bool {
+        let covers =
+            |rules: &Option<Vec<Rule>>| rules.as_ref().is_none_or(|rules| rules.contains(&rule));
+        if self
+            .ignored
+            .iter()
+            .any(|&(at, ref rules)| at == line && covers(rules))
+        {
+            return true;
+        }
+        self.toggled
+            .iter()
+            .rfind(|&&(at, _, ref rules)| at <= line && covers(rules))
+            .is_some_and(|&(_, disable, _)| disable)
+    }
+}
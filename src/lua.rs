@@ -0,0 +1,487 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+
+use chunk::{Chunk, ChunkCache, ChunkCacheStats};
+use error::Result;
+use gc;
+use hook::{HookInfo, HookMask};
+use limits::{GcMode, InterruptHandle, Limits, MemoryStats, PoolStats};
+use parser;
+use stdlib;
+use table::Table;
+use trace;
+use value::{ConvertValue, InternStats, MultiValue, StringTable, Value};
+use vm;
+
+/// How a state runs the chunks it loads
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Backend {
+    /// evaluate the syntax tree directly, which is the default
+    Interpreter,
+    /// compile to bytecode for a register-based VM, which runs faster
+    Vm,
+}
+
+/// How the chunks a state loads are compiled, shared with `load`
+#[derive(Copy, Clone, Debug)]
+pub(crate) struct LoadOptions {
+    pub backend: Backend,
+    /// whether the VM's code goes through the peephole optimizer
+    pub optimize: bool,
+}
+
+/// An interpreter state that chunks are loaded and run in
+pub struct Lua {
+    globals: Value,
+    /// shared with every function loaded, which intern their names and
+    /// constants in it
+    strings: Rc<StringTable>,
+    /// shared with `load`, which compiles chunks the same way
+    options: Rc<Cell<LoadOptions>>,
+    /// shared with every function loaded
+    limits: Rc<Limits>,
+    /// the chunks `load` compiled most recently
+    chunks: RefCell<ChunkCache>,
+}
+impl Lua {
+    /// Create a state with the standard library loaded
+    pub fn new() -> Lua {
+        let options = Rc::new(Cell::new(LoadOptions {
+            backend: Backend::Interpreter,
+            optimize: true,
+        }));
+        let limits = Rc::new(Limits::new());
+        let strings = Rc::new(StringTable::new());
+        let globals = limits.charge(|| {
+            let globals = Table::new().into_value();
+            stdlib::open(&globals, &options, &limits, &strings);
+            globals
+        });
+        Lua {
+            globals,
+            strings,
+            options,
+            limits,
+            chunks: RefCell::new(ChunkCache::new()),
+        }
+    }
+    /// The table holding global variables, which scripts see as `_G`
+    pub fn globals(&self) -> &Value {
+        &self.globals
+    }
+    pub fn get_global(&self, name: &str) -> Result<Value> {
+        self.globals.get_index(&self.string(name))
+    }
+    pub fn set_global(&mut self, name: &str, val: Value) -> Result<()> {
+        let name = self.string(name);
+        self.globals.set_index(name, val)
+    }
+    /// Create a string value, interned in this state if it is short
+    pub fn string<S>(&self, bytes: S) -> Value
+    where
+        S: AsRef<[u8]>,
+    {
+        self.limits.charge(|| self.strings.intern(bytes.as_ref()))
+    }
+    /// Create an empty table
+    pub fn create_table(&self) -> Value {
+        self.limits.charge(|| Table::new().into_value())
+    }
+    /// Create an empty table with room for `narr` items in its sequence
+    /// and `nrec` other entries, so filling it in doesn't grow it
+    pub fn create_table_with_capacity(&self, narr: usize, nrec: usize) -> Value {
+        self.limits
+            .charge(|| Table::with_capacity(narr, nrec).into_value())
+    }
+    /// Statistics about this state's interned strings
+    pub fn intern_stats(&self) -> InternStats {
+        self.strings.stats()
+    }
+    /// The longest string that `string` interns, 40 bytes by default
+    pub fn intern_limit(&self) -> usize {
+        self.strings.limit()
+    }
+    /// Change the longest string that `string` interns
+    pub fn set_intern_limit(&mut self, limit: usize) {
+        self.strings.set_limit(limit);
+    }
+    /// How chunks loaded from now on are run
+    pub fn backend(&self) -> Backend {
+        self.options.get().backend
+    }
+    /// Change how chunks loaded from now on are run, which does not affect
+    /// functions already loaded
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.options.set(LoadOptions {
+            backend,
+            ..self.options.get()
+        });
+    }
+    /// Whether the VM's bytecode is optimized, which it is by default
+    pub fn optimize(&self) -> bool {
+        self.options.get().optimize
+    }
+    /// Turn the VM's peephole optimizer on or off for chunks loaded from
+    /// now on, which is useful when debugging the compiler, as the code
+    /// then follows the source more closely
+    pub fn set_optimize(&mut self, optimize: bool) {
+        self.options.set(LoadOptions {
+            optimize,
+            ..self.options.get()
+        });
+    }
+    /// How deeply calls that take up space on the thread's stack can nest,
+    /// 200000 by default
+    pub fn call_limit(&self) -> usize {
+        self.limits.call_limit()
+    }
+    /// Change how deeply calls to functions this state loaded can nest on
+    /// the thread's stack, beyond which a call raises a "stack overflow"
+    /// error, which applies to functions already loaded too
+    ///
+    /// Every call in the interpreter takes up space on the thread's stack,
+    /// as do calls into the VM from Rust code, such as from metamethods and
+    /// library functions, and resuming coroutines. Those calls also raise
+    /// the error once the stack has too little room left for another,
+    /// however big it is, so on a thread with the usual 2 MiB stack they
+    /// nest about a hundred deep in a debug build and a few hundred in a
+    /// release build. Running scripts on a thread with a bigger stack lets
+    /// them go deeper. Calls between functions the VM runs are limited by
+    /// `set_stack_limit` instead.
+    pub fn set_call_limit(&mut self, limit: usize) {
+        self.limits.set_call_limit(limit);
+    }
+    /// How many stack slots the VM's calls between Lua functions can use,
+    /// a million by default
+    pub fn stack_limit(&self) -> usize {
+        self.limits.stack_limit()
+    }
+    /// Change how many stack slots the VM's calls between functions this
+    /// state loaded can use, beyond which a call raises a "stack overflow"
+    /// error, which takes a few slots per call and applies to each
+    /// coroutine on its own
+    pub fn set_stack_limit(&mut self, limit: usize) {
+        self.limits.set_stack_limit(limit);
+    }
+    /// How many more steps functions this state loaded can run, or `None`
+    /// if that is not limited, which it is not by default
+    pub fn instruction_limit(&self) -> Option<u64> {
+        self.limits.steps_left()
+    }
+    /// Limit how many more steps functions this state loaded can run,
+    /// beyond which they raise an "instruction limit exceeded" error
+    ///
+    /// A step is an instruction in the VM, or a statement or an iteration
+    /// of a loop in the interpreter. The budget is shared by everything
+    /// the state runs, so setting it before each call limits that call.
+    /// Once it has run out, a script that catches the error fails again at
+    /// its next step, so the error always reaches the host.
+    pub fn set_instruction_limit(&mut self, limit: Option<u64>) {
+        self.limits.set_steps_left(limit);
+    }
+    /// How many bytes values can take up while functions this state loaded
+    /// run, or `None` if that is not limited, which it is not by default
+    pub fn memory_limit(&self) -> Option<usize> {
+        self.limits.memory_limit()
+    }
+    /// Limit how many bytes values can take up while functions this state
+    /// loaded run, beyond which they raise a "not enough memory" error
+    ///
+    /// Memory is counted at each step, as the instruction limit counts
+    /// them, so a single allocation, such as by `string.rep`, can go over
+    /// the limit before the error is raised. The error lets go of the
+    /// values that its calls were using as it unwinds them, so a script
+    /// that catches it can carry on if that brings it back under the limit.
+    pub fn set_memory_limit(&mut self, limit: Option<usize>) {
+        self.limits.set_memory_limit(limit);
+    }
+    /// Roughly how many bytes the values charged to this state take up,
+    /// which is what the memory limit is checked against
+    ///
+    /// Values are charged to the state whose function created them, or
+    /// whose methods such as `load` and `create_table` did, and count
+    /// against it until they are freed, even if they outlive it or end up
+    /// in another state. Values the host creates directly, such as with
+    /// `Value::string`, are charged to no state.
+    pub fn memory_used(&self) -> usize {
+        self.limits.memory_used()
+    }
+    /// How `memory_used` breaks down by the types of the values, which
+    /// scripts can see the total of with `collectgarbage("count")`
+    pub fn memory_stats(&self) -> MemoryStats {
+        self.limits.memory_stats()
+    }
+    /// How often this state reused the allocations of values it freed,
+    /// rather than allocating again
+    pub fn pool_stats(&self) -> PoolStats {
+        self.limits.pool_stats()
+    }
+    /// How many allocations of each kind this state keeps for reuse
+    pub fn pool_limit(&self) -> usize {
+        self.limits.pool_limit()
+    }
+    /// Keep up to `limit` allocations of each kind for reuse, where 0 turns
+    /// reuse off
+    ///
+    /// What they keep isn't counted against the memory limit. The room for
+    /// a table's entries is only kept if the table was small.
+    pub fn set_pool_limit(&mut self, limit: usize) {
+        self.limits.set_pool_limit(limit);
+    }
+    /// A handle that can interrupt functions this state loaded from another
+    /// thread, such as to time out a request
+    pub fn interrupt_handle(&self) -> InterruptHandle {
+        self.limits.interrupt_handle()
+    }
+    /// Call `hook` for the events in `mask` as the functions this state
+    /// loaded run, replacing any hook already set, such as by
+    /// `debug.sethook`
+    ///
+    /// Functions of other states don't call it, even on the same thread,
+    /// and it is removed when the state is closed. An error the hook
+    /// returns is raised where the event happened.
+    pub fn set_hook<F>(&mut self, mask: HookMask, hook: F)
+    where
+        F: Fn(&HookInfo) -> Result<()> + 'static,
+    {
+        self.limits.hooks.set(mask, Rc::new(hook), None);
+    }
+    /// Stop calling the hook set on this state
+    pub fn remove_hook(&mut self) {
+        self.limits.hooks.remove();
+    }
+    /// Compile `source` into a function that runs it as a chunk, naming it
+    /// `name` in error messages
+    ///
+    /// This also accepts binary chunks saved by `string.dump`, which always
+    /// run in the VM. The state caches the chunks it compiled most
+    /// recently, so loading the same source again with the same name and
+    /// options only makes a new function sharing the compiled one.
+    pub fn load(&self, source: &[u8], name: &str) -> Result<Value> {
+        let options = self.options.get();
+        self.limits.charge(|| {
+            let chunk = self.chunks.borrow_mut().get(source, name, options, || {
+                Chunk::load(source, name, options, &self.strings)
+            })?;
+            Ok(chunk.function(self.globals.clone(), &self.limits, &self.strings))
+        })
+    }
+    /// How many compiled chunks `load` keeps, 64 by default
+    pub fn chunk_cache_limit(&self) -> usize {
+        self.chunks.borrow().limit()
+    }
+    /// Change how many compiled chunks `load` keeps, where 0 turns the
+    /// cache off
+    pub fn set_chunk_cache_limit(&mut self, limit: usize) {
+        self.chunks.borrow_mut().set_limit(limit);
+    }
+    /// Statistics about the chunks `load` has cached
+    pub fn chunk_cache_stats(&self) -> ChunkCacheStats {
+        self.chunks.borrow().stats()
+    }
+    /// Compile `source` for the VM into a binary chunk, as `string.dump`
+    /// would save the function `load` gives, leaving out the debug
+    /// information if `strip` is set
+    ///
+    /// Nothing is run, so syntax errors are found without side effects. A
+    /// binary chunk is saved again as it is, or stripped.
+    pub fn compile(&self, source: &[u8], name: &str, strip: bool) -> Result<Vec<u8>> {
+        Ok(vm::dump(&self.compile_proto(source, name)?, strip))
+    }
+    /// Compile several chunks, each a source and its name, into one binary
+    /// chunk that runs them in the order given, as `luac` does with several
+    /// files
+    ///
+    /// Each runs with no arguments, and what they return is discarded.
+    pub fn compile_all(&self, sources: &[(&[u8], &str)], strip: bool) -> Result<Vec<u8>> {
+        let protos = sources
+            .iter()
+            .map(|&(source, name)| self.compile_proto(source, name))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(vm::dump(&vm::combine(protos, "(looa)"), strip))
+    }
+    /// The function `source` compiles to, or the one saved in it if it is a
+    /// binary chunk
+    fn compile_proto(&self, source: &[u8], name: &str) -> Result<vm::Proto> {
+        if source.starts_with(vm::SIGNATURE) {
+            vm::undump(source, name, &self.strings)
+        } else {
+            let ast = parser::parse_chunk(source, name)?;
+            vm::compile(&ast, self.options.get().optimize, &self.strings)
+        }
+    }
+    /// List the VM instructions `source` compiles to, along with the
+    /// constants, locals and upvalues of each function in it, without
+    /// running it
+    ///
+    /// A binary chunk is listed as it was saved.
+    pub fn disassemble(&self, source: &[u8], name: &str) -> Result<String> {
+        Ok(vm::disassemble(&self.compile_proto(source, name)?))
+    }
+    /// Run `source` as a chunk, discarding what it returns
+    pub fn exec(&mut self, source: &str) -> Result<()> {
+        let result = self
+            .load(source.as_bytes(), &chunk_name(source))
+            .and_then(|func| func.call(Vec::new()))
+            .map(|_| ());
+        self.safe_point();
+        result
+    }
+    /// Evaluate `source` as an expression and return its value, which is
+    /// the first for a call
+    pub fn eval(&mut self, source: &str) -> Result<Value> {
+        let result = self.limits.charge(|| {
+            parser::parse_expr(source.as_bytes(), &chunk_name(source))
+                .map_err(Into::into)
+                .and_then(|ast| {
+                    Chunk::from_ast(ast, self.options.get(), &self.strings)?
+                        .function(self.globals.clone(), &self.limits, &self.strings)
+                        .call(Vec::new())
+                })
+                .map(MultiValue::into_first)
+        });
+        self.safe_point();
+        result
+    }
+    /// The traceback of the last error a call into Lua code raised that
+    /// was not caught, listing the functions it left, innermost first,
+    /// then the Lua functions still running, if this is called from one
+    pub fn traceback(&self) -> String {
+        trace::traceback()
+    }
+    /// Run the `__gc` finalizers of values that have been collected,
+    /// returning the first error raised by one
+    pub fn run_finalizers(&mut self) -> Result<()> {
+        gc::run_finalizers()
+    }
+    /// Free every value that nothing in use refers to, giving how many
+    /// there were
+    ///
+    /// Values are only ever freed by a collection, which this state's
+    /// steps also do as its values grow. Values are on a heap shared by
+    /// every state on the thread, so like finalization this covers all of
+    /// their values. Values with a `__gc` metamethod are kept until they
+    /// have been finalized, which happens at the next safe point or call
+    /// to `run_finalizers`, and a later collection frees them.
+    pub fn collect_garbage(&mut self) -> usize {
+        gc::collect()
+    }
+    /// Do a step of collecting garbage, as if `kb` kilobytes had been
+    /// allocated, giving whether that finished a collection
+    ///
+    /// Steps are also done as this state's values grow, so collecting
+    /// garbage only pauses scripts briefly. A collection counts how many
+    /// references to the values on the thread come from each other over
+    /// its steps, and frees those that nothing else refers to. In
+    /// generational mode, each step is a whole minor collection, or a major
+    /// one when it is due.
+    pub fn gc_step(&mut self, kb: usize) -> bool {
+        self.limits.collect_some(kb.saturating_mul(1024))
+    }
+    /// How much this state's values can grow after a collection before the
+    /// next one starts, as a percentage of what they took up, 200 at first
+    pub fn gc_pause(&self) -> u32 {
+        self.limits.pause()
+    }
+    pub fn set_gc_pause(&mut self, pause: u32) {
+        self.limits.set_pause(pause);
+    }
+    /// How much a collection does at each step for what this state's values
+    /// grew by, as a percentage, 100 at first
+    pub fn gc_step_multiplier(&self) -> u32 {
+        self.limits.step_multiplier()
+    }
+    pub fn set_gc_step_multiplier(&mut self, multiplier: u32) {
+        self.limits.set_step_multiplier(multiplier);
+    }
+    /// How this state collects garbage, which is incrementally at first
+    pub fn gc_mode(&self) -> GcMode {
+        self.limits.gc_mode()
+    }
+    /// Switch how this state collects garbage, giving how it did before
+    ///
+    /// In generational mode, most collections are minor ones, which only
+    /// free values created since the last collection, and so take much
+    /// less time when most values don't last long. Every value is looked at
+    /// by a major collection once the values have grown by the major
+    /// multiplier since the last one.
+    pub fn set_gc_mode(&mut self, mode: GcMode) -> GcMode {
+        self.limits.set_gc_mode(mode)
+    }
+    /// How much this state's values can grow between minor collections, as
+    /// a percentage of what they took up after the last major one, 20 at
+    /// first
+    pub fn gc_minor_multiplier(&self) -> u32 {
+        self.limits.minor_multiplier()
+    }
+    pub fn set_gc_minor_multiplier(&mut self, multiplier: u32) {
+        self.limits.set_minor_multiplier(multiplier);
+    }
+    /// How much this state's values can grow before a major collection, as
+    /// a percentage of what they took up after the last one, 100 at first
+    pub fn gc_major_multiplier(&self) -> u32 {
+        self.limits.major_multiplier()
+    }
+    pub fn set_gc_major_multiplier(&mut self, multiplier: u32) {
+        self.limits.set_major_multiplier(multiplier);
+    }
+    /// The values `collect_garbage` would free that refer to other values,
+    /// grouped by which refer to each other, to find out what is leaking
+    pub fn find_garbage(&self) -> Vec<Vec<Value>> {
+        gc::find_garbage()
+    }
+    /// Called between chunks, where finalizers can safely run
+    fn safe_point(&mut self) {
+        // as with Lua's warnings, errors in implicit finalizers are dropped
+        let _ = gc::run_finalizers();
+    }
+}
+impl Drop for Lua {
+    fn drop(&mut self) {
+        // closing a state finalizes everything it still owns, which `_G`
+        // would keep alive from inside the globals
+        let name = self.string("_G");
+        if self.globals.raw_get(&name).raw_equal(&self.globals) {
+            let _ = self.globals.raw_set(name, Value::nil());
+        }
+        self.globals = Value::nil();
+        // the hook's function would keep the state's limits alive
+        self.limits.hooks.remove();
+        // and the cached chunks their constants
+        *self.chunks.get_mut() = ChunkCache::new();
+        gc::collect();
+        self.safe_point();
+    }
+}
+/// Compile a text or binary chunk into a function using `env` for its
+/// globals, held to `limits` and interning strings in `strings`
+pub(crate) fn load_chunk(
+    source: &[u8],
+    name: &str,
+    options: LoadOptions,
+    limits: &Rc<Limits>,
+    strings: &Rc<StringTable>,
+    env: Value,
+) -> Result<Value> {
+    Ok(Chunk::load(source, name, options, strings)?.function(env, limits, strings))
+}
+/// How chunks loaded from a string are named, which is by their first line
+pub(crate) fn chunk_name(source: &str) -> String {
+    const MAX_LEN: usize = 40;
+    let line = source.lines().next().unwrap_or("");
+    let mut end = line.len().min(MAX_LEN);
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    if end < source.trim_end().len() {
+        format!("[string \"{}...\"]", &line[..end])
+    } else {
+        format!("[string \"{}\"]", line)
+    }
+}
+
+impl Default for Lua {
+    fn default() -> Lua {
+        Lua::new()
+    }
+}
@@ -0,0 +1,329 @@
+//! Helpers for the integer and float subtypes of Lua numbers
+
+use std::cmp::Ordering;
+use std::fmt;
+
+use error::{Error, Result};
+use value::{ArithOp, ConvertValue, LuaInteger, LuaNumber, Value};
+
+/// Significant digits used when printing floats, as `%.14g` would
+#[cfg(any(not(feature = "f32"), feature = "nan-boxing"))]
+const FLOAT_DIGITS: usize = 14;
+#[cfg(all(feature = "f32", not(feature = "nan-boxing")))]
+const FLOAT_DIGITS: usize = 7;
+
+/// `2^63` as a float, one past the largest integer
+const TWO_POW_63: LuaNumber = -(LuaInteger::MIN as LuaNumber);
+
+/// A number that has been coerced for arithmetic
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Number {
+    Int(LuaInteger),
+    Float(LuaNumber),
+}
+impl Number {
+    pub fn to_float(self) -> LuaNumber {
+        match self {
+            Number::Int(i) => i as LuaNumber,
+            Number::Float(f) => f,
+        }
+    }
+    pub fn into_value(self) -> Value {
+        match self {
+            Number::Int(i) => i.into_value(),
+            Number::Float(f) => f.into_value(),
+        }
+    }
+}
+
+/// Perform an arithmetic operation, on integers if both operands are
+/// integers (except for `/` and `^`) and on floats otherwise
+pub fn arith(op: ArithOp, a: Number, b: Number) -> Result<Number> {
+    use self::Number::{Float, Int};
+    if op.is_bitwise() {
+        return bitwise(op, to_bits(a)?, to_bits(b)?).map(Int);
+    }
+    Ok(match (op, a, b) {
+        (ArithOp::Add, Int(a), Int(b)) => Int(a.wrapping_add(b)),
+        (ArithOp::Sub, Int(a), Int(b)) => Int(a.wrapping_sub(b)),
+        (ArithOp::Mul, Int(a), Int(b)) => Int(a.wrapping_mul(b)),
+        (ArithOp::Mod, Int(a), Int(b)) => match int_mod(a, b) {
+            Some(r) => Int(r),
+            None => return Err(Error::Runtime("attempt to perform 'n%0'".to_string())),
+        },
+        (ArithOp::IDiv, Int(a), Int(b)) => match int_floor_div(a, b) {
+            Some(q) => Int(q),
+            None => return Err(Error::Runtime("attempt to perform 'n//0'".to_string())),
+        },
+        (ArithOp::Unm, Int(a), _) => Int(a.wrapping_neg()),
+        (op, a, b) => {
+            let (a, b) = (a.to_float(), b.to_float());
+            Float(match op {
+                ArithOp::Add => a + b,
+                ArithOp::Sub => a - b,
+                ArithOp::Mul => a * b,
+                ArithOp::Div => a / b,
+                ArithOp::Mod => float_mod(a, b),
+                ArithOp::Pow => a.powf(b),
+                ArithOp::IDiv => (a / b).floor(),
+                ArithOp::Unm => -a,
+                _ => unreachable!("bitwise operators are handled above"),
+            })
+        }
+    })
+}
+
+/// The integer a bitwise operand stands for, which floats only have when
+/// their value is integral
+fn to_bits(num: Number) -> Result<LuaInteger> {
+    match num {
+        Number::Int(i) => Ok(i),
+        Number::Float(f) => float_to_int(f)
+            .ok_or_else(|| Error::Runtime("number has no integer representation".to_string())),
+    }
+}
+
+fn bitwise(op: ArithOp, a: LuaInteger, b: LuaInteger) -> Result<LuaInteger> {
+    Ok(match op {
+        ArithOp::BAnd => a & b,
+        ArithOp::BOr => a | b,
+        ArithOp::BXor => a ^ b,
+        ArithOp::Shl => shift_left(a, b),
+        ArithOp::Shr => shift_left(a, b.wrapping_neg()),
+        ArithOp::BNot => !a,
+        _ => unreachable!("not a bitwise operator"),
+    })
+}
+
+/// A logical shift, to the right for negative `n`, where shifting by 64 or
+/// more bits leaves nothing
+fn shift_left(a: LuaInteger, n: LuaInteger) -> LuaInteger {
+    if n <= -64 || n >= 64 {
+        0
+    } else if n >= 0 {
+        ((a as u64) << n) as LuaInteger
+    } else {
+        ((a as u64) >> -n) as LuaInteger
+    }
+}
+
+/// Convert a float to an integer if it has an exact integer representation
+pub fn float_to_int(f: LuaNumber) -> Option<LuaInteger> {
+    if f.fract() == 0.0 && (-TWO_POW_63..TWO_POW_63).contains(&f) {
+        Some(f as LuaInteger)
+    } else {
+        None
+    }
+}
+
+/// Compare an integer with a float without losing precision
+pub fn cmp_int_float(i: LuaInteger, f: LuaNumber) -> Option<Ordering> {
+    if f.is_nan() {
+        None
+    } else if f >= TWO_POW_63 {
+        Some(Ordering::Less)
+    } else if f < -TWO_POW_63 {
+        Some(Ordering::Greater)
+    } else {
+        let floor = f.floor();
+        match Ord::cmp(&i, &(floor as LuaInteger)) {
+            Ordering::Equal if f > floor => Some(Ordering::Less),
+            ord => Some(ord),
+        }
+    }
+}
+
+/// Compare two numbers mathematically, or `None` if either is NaN
+pub fn cmp(a: Number, b: Number) -> Option<Ordering> {
+    match (a, b) {
+        (Number::Int(a), Number::Int(b)) => Some(Ord::cmp(&a, &b)),
+        (Number::Float(a), Number::Float(b)) => PartialOrd::partial_cmp(&a, &b),
+        (Number::Int(a), Number::Float(b)) => cmp_int_float(a, b),
+        (Number::Float(a), Number::Int(b)) => cmp_int_float(b, a).map(Ordering::reverse),
+    }
+}
+
+/// Convert a string to a number following Lua's lexical rules, allowing
+/// surrounding whitespace and a leading sign
+///
+/// Decimal integers that overflow become floats, while hexadecimal integers
+/// wrap around. Anything else is read as a decimal or hexadecimal float.
+pub fn parse(s: &str) -> Option<Number> {
+    let s = s.trim();
+    let (negative, digits) = match s.as_bytes().first() {
+        Some(b'-') => (true, &s[1..]),
+        Some(b'+') => (false, &s[1..]),
+        _ => (false, s),
+    };
+    let num = match digits.get(..2) {
+        Some("0x") | Some("0X") => parse_hex(&digits[2..])?,
+        _ => parse_decimal(digits)?,
+    };
+    Some(match num {
+        Number::Int(i) if negative => Number::Int(i.wrapping_neg()),
+        Number::Float(f) if negative => Number::Float(-f),
+        num => num,
+    })
+}
+
+/// Parse an unsigned decimal numeral
+fn parse_decimal(s: &str) -> Option<Number> {
+    let valid = |c: char| c.is_ascii_digit() || ".eE+-".contains(c);
+    // Rust's float syntax also has `inf` and `nan`, which Lua's does not
+    if !s.starts_with(|c: char| c.is_ascii_digit() || c == '.') || !s.chars().all(valid) {
+        return None;
+    }
+    if s.bytes().all(|c| c.is_ascii_digit()) {
+        if let Ok(i) = s.parse() {
+            return Some(Number::Int(i));
+        }
+    }
+    s.parse().map(Number::Float).ok()
+}
+
+/// Parse an unsigned hexadecimal numeral, without its `0x`
+fn parse_hex(s: &str) -> Option<Number> {
+    let (mantissa, exponent) = match s.find(['p', 'P']) {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    let (int_part, frac_part) = match mantissa.find('.') {
+        Some(i) => (&mantissa[..i], Some(&mantissa[i + 1..])),
+        None => (mantissa, None),
+    };
+    let all_hex = |part: &str| part.bytes().all(|c| c.is_ascii_hexdigit());
+    let frac = frac_part.unwrap_or("");
+    if int_part.len() + frac.len() == 0 || !all_hex(int_part) || !all_hex(frac) {
+        return None;
+    }
+    if frac_part.is_none() && exponent.is_none() {
+        // integers wrap around, keeping the low 64 bits
+        let bits = int_part.bytes().fold(0u64, |acc, c| {
+            let digit = (c as char).to_digit(16).expect("checked digit") as u64;
+            acc.wrapping_mul(16).wrapping_add(digit)
+        });
+        return Some(Number::Int(bits as LuaInteger));
+    }
+    let mut exp: i64 = match exponent {
+        Some(e) => {
+            let digits = e.strip_prefix(['+', '-']).unwrap_or(e);
+            if digits.is_empty() || !digits.bytes().all(|c| c.is_ascii_digit()) {
+                return None;
+            }
+            // huge exponents saturate, which still over- or underflows
+            e.parse().unwrap_or(if e.starts_with('-') {
+                -100_000
+            } else {
+                100_000
+            })
+        }
+        None => 0,
+    };
+    // like Lua, keep at most 30 significant digits, counting the rest of
+    // the integer part as powers of 16 and ignoring the rest of the fraction
+    let mut value = 0f64;
+    let mut significant = 0;
+    let digit = |c: u8| (c as char).to_digit(16).expect("checked digit") as f64;
+    for c in int_part.bytes() {
+        if significant < 30 {
+            value = value * 16.0 + digit(c);
+            if value != 0.0 {
+                significant += 1;
+            }
+        } else {
+            exp += 4;
+        }
+    }
+    for c in frac.bytes() {
+        if significant < 30 {
+            value = value * 16.0 + digit(c);
+            if value != 0.0 {
+                significant += 1;
+            }
+            exp -= 4;
+        }
+    }
+    let exp = exp.clamp(-100_000, 100_000) as i32;
+    Some(Number::Float(
+        (value * 2f64.powi(exp / 2) * 2f64.powi(exp - exp / 2)) as LuaNumber,
+    ))
+}
+
+/// Integer floor division, or `None` when dividing by zero
+pub fn int_floor_div(a: LuaInteger, b: LuaInteger) -> Option<LuaInteger> {
+    match b {
+        0 => None,
+        // avoids overflowing on `MIN // -1`
+        -1 => Some(a.wrapping_neg()),
+        _ => {
+            let q = a / b;
+            if a % b != 0 && (a ^ b) < 0 {
+                Some(q - 1)
+            } else {
+                Some(q)
+            }
+        }
+    }
+}
+
+/// Integer modulo with the sign of the divisor, or `None` for modulo by zero
+pub fn int_mod(a: LuaInteger, b: LuaInteger) -> Option<LuaInteger> {
+    match b {
+        0 => None,
+        -1 => Some(0),
+        _ => {
+            let r = a % b;
+            if r != 0 && (r ^ b) < 0 {
+                Some(r + b)
+            } else {
+                Some(r)
+            }
+        }
+    }
+}
+
+/// Float modulo with the sign of the divisor
+pub fn float_mod(a: LuaNumber, b: LuaNumber) -> LuaNumber {
+    let r = a % b;
+    if (r > 0.0 && b < 0.0) || (r < 0.0 && b > 0.0) {
+        r + b
+    } else {
+        r
+    }
+}
+
+fn trim_fraction(s: &str) -> &str {
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        s
+    }
+}
+
+/// Write a float like C's `%g`, adding `.0` when it would look like an integer
+pub fn fmt_float(num: LuaNumber, f: &mut fmt::Formatter) -> fmt::Result {
+    if num.is_nan() {
+        return f.write_str(if num.is_sign_negative() {
+            "-nan"
+        } else {
+            "nan"
+        });
+    } else if num.is_infinite() {
+        return f.write_str(if num > 0.0 { "inf" } else { "-inf" });
+    }
+    let sci = format!("{:.*e}", FLOAT_DIGITS - 1, num);
+    let (mantissa, exp) = sci.split_at(sci.find('e').unwrap_or(sci.len()));
+    let exp: i32 = exp[1..].parse().unwrap_or(0);
+    if exp < -4 || exp >= FLOAT_DIGITS as i32 {
+        let sign = if exp < 0 { '-' } else { '+' };
+        write!(f, "{}e{}{:02}", trim_fraction(mantissa), sign, exp.abs())
+    } else {
+        let fixed = format!("{:.*}", (FLOAT_DIGITS as i32 - 1 - exp) as usize, num);
+        let fixed = trim_fraction(&fixed);
+        if fixed.contains('.') {
+            f.write_str(fixed)
+        } else {
+            write!(f, "{}.0", fixed)
+        }
+    }
+}
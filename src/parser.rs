@@ -0,0 +1,1025 @@
+//! Building a syntax tree from tokens
+
+use std::collections::HashSet;
+use std::mem;
+use std::rc::Rc;
+
+use ast::{
+    Arena, Ast, BinOp, Block, Branch, Expr, ExprKind, ExprList, Field, FieldKind, FuncBody, FuncId,
+    FuncName, Id, List, Location, Name, Stat, StatKind, UnOp,
+};
+use error::ParseError;
+use lexer::{Lexer, Position, Span, Token, TokenKind};
+
+type Result<T> = ::std::result::Result<T, ParseError>;
+
+pub struct Parser<'a> {
+    src: &'a [u8],
+    lexer: Lexer<'a>,
+    /// the current token
+    token: Token,
+    /// the token after the current one, once something has looked at it
+    ahead: Option<Token>,
+    /// where the last consumed token ended
+    last_end: usize,
+    chunk: Rc<str>,
+    /// whether to skip past errors, collecting them instead of failing
+    recover: bool,
+    errors: Vec<ParseError>,
+    /// how many loops enclose the current statement in this function
+    loops: u32,
+    /// whether this function takes `...`, as the main chunk does
+    vararg: bool,
+    /// the names of the locals in scope in this function, innermost last
+    active: Vec<Name>,
+    /// the labels visible in this function, innermost block last
+    labels: Vec<VisibleLabel>,
+    /// the `goto`s in this function whose label hasn't been seen yet
+    gotos: Vec<PendingGoto>,
+    /// the tree parsed so far
+    ast: Ast,
+    /// the nodes of the lists being parsed, innermost last, which are
+    /// moved into `ast` together once each list ends
+    scratch: Ast,
+    /// every name parsed so far, so that each is allocated once
+    names: HashSet<Name>,
+}
+
+/// A label that `goto`s can jump to
+struct VisibleLabel {
+    name: Name,
+    line: u32,
+    /// how many locals are in scope at the label
+    active: usize,
+}
+
+/// A `goto` to a label that may come later in an enclosing block
+struct PendingGoto {
+    label: Name,
+    loc: Location,
+    /// how many locals the jump can keep in scope, which is fewer once it
+    /// leaves blocks declaring them
+    active: usize,
+}
+
+/// Where a node started, for building its location once it is parsed
+#[derive(Copy, Clone)]
+struct Mark {
+    start: usize,
+    pos: Position,
+}
+impl<'a> Parser<'a> {
+    /// A parser for `src`, naming it `chunk` in locations and errors
+    pub fn new(src: &'a [u8], chunk: &str) -> Result<Parser<'a>> {
+        Parser::start(src, chunk, false)
+    }
+    /// A parser that skips to the next statement after a syntax error,
+    /// leaving the errors for `errors` and producing a partial tree
+    pub fn recovering(src: &'a [u8], chunk: &str) -> Parser<'a> {
+        Parser::start(src, chunk, true).expect("recovering parsers collect errors")
+    }
+    fn start(src: &'a [u8], chunk: &str, recover: bool) -> Result<Parser<'a>> {
+        let mut parser = Parser {
+            src,
+            lexer: Lexer::new(src),
+            token: Token {
+                kind: TokenKind::Eof,
+                span: Span::new(0, 0),
+                pos: Position { line: 1, column: 1 },
+            },
+            ahead: None,
+            last_end: 0,
+            chunk: Rc::from(chunk),
+            recover,
+            errors: Vec::new(),
+            loops: 0,
+            vararg: true,
+            active: Vec::new(),
+            labels: Vec::new(),
+            gotos: Vec::new(),
+            ast: Ast::default(),
+            scratch: Ast::default(),
+            names: HashSet::new(),
+        };
+        parser.token = parser.lex()?;
+        Ok(parser)
+    }
+    /// The errors skipped over so far while recovering
+    pub fn errors(&self) -> &[ParseError] {
+        &self.errors
+    }
+    fn mark(&self) -> Mark {
+        Mark {
+            start: self.token.span.start,
+            pos: self.token.pos,
+        }
+    }
+    /// The location from `mark` to the end of the last consumed token
+    fn loc(&self, mark: Mark) -> Location {
+        Location {
+            chunk: self.chunk.clone(),
+            span: Span::new(mark.start, self.last_end.max(mark.start)),
+            pos: mark.pos,
+        }
+    }
+    /// Add `node` to the tree
+    fn add<T>(&mut self, node: T) -> Id<T>
+    where
+        Ast: Arena<T>,
+    {
+        self.ast.add(node)
+    }
+    /// Where a list of nodes parsed from now on starts
+    fn open<T>(&mut self) -> usize
+    where
+        Ast: Arena<T>,
+    {
+        Arena::<T>::nodes(&mut self.scratch).len()
+    }
+    /// Add `node` to the innermost list being parsed
+    fn push<T>(&mut self, node: T)
+    where
+        Ast: Arena<T>,
+    {
+        self.scratch.nodes().push(node);
+    }
+    /// Add the nodes of the list started at `start` to the tree
+    fn close<T>(&mut self, start: usize) -> List<T>
+    where
+        Ast: Arena<T>,
+    {
+        let nodes = self.scratch.nodes().drain(start..);
+        self.ast.add_list(nodes)
+    }
+    fn expr_at(&self, kind: ExprKind, mark: Mark) -> Expr {
+        Expr {
+            kind,
+            loc: self.loc(mark),
+        }
+    }
+    /// Move on to the next token, returning the current one
+    fn advance(&mut self) -> Result<Token> {
+        let next = match self.ahead.take() {
+            Some(token) => token,
+            None => self.lex()?,
+        };
+        self.last_end = self.token.span.end;
+        Ok(mem::replace(&mut self.token, next))
+    }
+    fn peek(&mut self) -> Result<&TokenKind> {
+        if self.ahead.is_none() {
+            self.ahead = Some(self.lex()?);
+        }
+        Ok(&self.ahead.as_ref().expect("just filled").kind)
+    }
+    fn check(&self, kind: &TokenKind) -> bool {
+        self.token.kind == *kind
+    }
+    /// Consume the current token if it is `kind`
+    fn eat(&mut self, kind: &TokenKind) -> Result<bool> {
+        if self.check(kind) {
+            self.advance()?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+    fn expect(&mut self, kind: &TokenKind) -> Result<Token> {
+        if self.check(kind) {
+            self.advance()
+        } else {
+            self.expected(::std::slice::from_ref(kind))
+        }
+    }
+    /// Expect the token closing something opened at `open`, mentioning
+    /// the opener when it is on an earlier line
+    fn expect_match(&mut self, close: &TokenKind, what: &TokenKind, open: Mark) -> Result<Token> {
+        if self.check(close) || open.pos.line == self.token.pos.line {
+            return self.expect(close);
+        }
+        let mut err = self.error_here(&format!(
+            "{} expected (to close {} at line {})",
+            close, what, open.pos.line
+        ));
+        err.expected.push(close.to_string());
+        Err(err)
+    }
+    fn name(&mut self) -> Result<Name> {
+        let name = self.name_string()?;
+        Ok(self.intern(name))
+    }
+    /// The name next, as the lexer read it, for where it is a string
+    /// rather than a variable
+    fn name_string(&mut self) -> Result<String> {
+        match self.token.kind {
+            TokenKind::Name(_) => match self.advance()?.kind {
+                TokenKind::Name(name) => Ok(name),
+                _ => unreachable!(),
+            },
+            _ => self.expected(&[TokenKind::Name(String::new())]),
+        }
+    }
+    /// The name `name`, shared with any others the same
+    fn intern(&mut self, name: String) -> Name {
+        if let Some(name) = self.names.get(&*name) {
+            return name.clone();
+        }
+        let name = Name::from(name);
+        self.names.insert(name.clone());
+        name
+    }
+    fn lex(&mut self) -> Result<Token> {
+        loop {
+            let start = self.lexer.current_position();
+            let mut err = match self.lexer.next_token() {
+                Err(err) => err,
+                token => return token,
+            };
+            err.chunk = Some(self.chunk.to_string());
+            if !self.recover {
+                return Err(err);
+            }
+            self.errors.push(err);
+            if self.lexer.current_position() == start {
+                self.lexer.skip_char();
+            }
+        }
+    }
+    /// Record `err` and skip to the next statement if recovering, or
+    /// otherwise fail with it
+    fn recover_from(&mut self, err: ParseError) -> Result<()> {
+        if !self.recover {
+            return Err(err);
+        }
+        self.errors.push(err);
+        loop {
+            match self.token.kind {
+                TokenKind::Eof
+                | TokenKind::End
+                | TokenKind::Else
+                | TokenKind::ElseIf
+                | TokenKind::Until
+                | TokenKind::Local
+                | TokenKind::Function
+                | TokenKind::If
+                | TokenKind::While
+                | TokenKind::For
+                | TokenKind::Repeat
+                | TokenKind::Do
+                | TokenKind::Return
+                | TokenKind::DoubleColon
+                | TokenKind::Goto
+                | TokenKind::Break => return Ok(()),
+                TokenKind::Semicolon => {
+                    self.advance()?;
+                    return Ok(());
+                }
+                _ => {
+                    self.advance()?;
+                }
+            }
+        }
+    }
+    fn error<T>(&self, msg: &str) -> Result<T> {
+        Err(self.error_here(msg))
+    }
+    /// Record `err`, found about a statement already parsed, if recovering,
+    /// or otherwise fail with it
+    fn error_at(&mut self, msg: String, loc: &Location) -> Result<()> {
+        let found = String::from_utf8_lossy(&self.src[loc.span.start..loc.span.end]);
+        let mut err = ParseError::new(msg, loc.span, loc.pos, Some(found.into_owned()));
+        err.chunk = Some(self.chunk.to_string());
+        if !self.recover {
+            return Err(err);
+        }
+        self.errors.push(err);
+        Ok(())
+    }
+    /// An error at the current token
+    fn error_here(&self, msg: &str) -> ParseError {
+        let found = match self.token.kind {
+            TokenKind::Eof => None,
+            _ => Some(
+                String::from_utf8_lossy(&self.src[self.token.span.start..self.token.span.end])
+                    .into_owned(),
+            ),
+        };
+        let mut err = ParseError::new(msg.to_string(), self.token.span, self.token.pos, found);
+        err.chunk = Some(self.chunk.to_string());
+        err
+    }
+    /// An error saying which tokens would have been accepted here
+    fn expected<T>(&self, kinds: &[TokenKind]) -> Result<T> {
+        let expected: Vec<String> = kinds.iter().map(|kind| kind.to_string()).collect();
+        let mut err = self.error_here(&format!("{} expected", expected.join(" or ")));
+        err.expected = expected;
+        Err(err)
+    }
+    /// Fail unless every token has been consumed
+    pub fn finish(&mut self) -> Result<()> {
+        if self.check(&TokenKind::Eof) {
+            Ok(())
+        } else {
+            self.expected(&[TokenKind::Eof])
+        }
+    }
+
+    /// Parse statements up to the end of a block
+    fn block(&mut self) -> Result<Block> {
+        let mark = self.mark();
+        let (active, labels, gotos) = (self.active.len(), self.labels.len(), self.gotos.len());
+        let start = self.open::<Stat>();
+        let mut ret = None;
+        loop {
+            // what a statement with an error added is dropped
+            let sizes = (self.ast.sizes(), self.scratch.sizes());
+            match self.token.kind {
+                TokenKind::Return => match self.return_values() {
+                    Ok(values) => {
+                        ret = Some(values);
+                        // whatever follows is reported by the caller expecting the block's end
+                        break;
+                    }
+                    Err(err) => {
+                        self.drop_since(sizes);
+                        self.recover_from(err)?
+                    }
+                },
+                _ if self.block_follows() => break,
+                TokenKind::Semicolon => {
+                    self.advance()?;
+                }
+                _ => match self.stat() {
+                    Ok(stat) => self.push(stat),
+                    Err(err) => {
+                        self.drop_since(sizes);
+                        self.recover_from(err)?
+                    }
+                },
+            }
+        }
+        // labels at the end of a block are outside the scope of its locals,
+        // except before an `until`, whose condition can see them
+        if ret.is_none() && !self.check(&TokenKind::Until) {
+            let stats = &Arena::<Stat>::nodes(&mut self.scratch)[start..];
+            for stat in stats.iter().rev() {
+                match stat.kind {
+                    StatKind::Label(ref name) => {
+                        let label = self.labels[labels..]
+                            .iter_mut()
+                            .find(|label| label.name == *name);
+                        if let Some(label) = label {
+                            label.active = active;
+                        }
+                    }
+                    _ => break,
+                }
+            }
+        }
+        self.resolve_gotos(labels, gotos)?;
+        self.labels.truncate(labels);
+        self.end_scope(active);
+        Ok(Block {
+            stats: self.close(start),
+            ret,
+            loc: self.loc(mark),
+        })
+    }
+    /// Drop the nodes parsed since the tree and the lists being parsed had
+    /// `sizes`, which nothing kept refers to
+    fn drop_since(&mut self, sizes: ([usize; 6], [usize; 6])) {
+        self.ast.truncate(sizes.0);
+        self.scratch.truncate(sizes.1);
+    }
+    fn return_values(&mut self) -> Result<ExprList> {
+        self.advance()?;
+        let values = if self.block_follows() || self.check(&TokenKind::Semicolon) {
+            List::default()
+        } else {
+            self.expr_list()?
+        };
+        self.eat(&TokenKind::Semicolon)?;
+        Ok(values)
+    }
+    /// A whole chunk, up to the end of the input
+    pub fn chunk(&mut self) -> Result<Ast> {
+        let mut block = self.block()?;
+        while let Err(err) = self.finish() {
+            // a stray `end` or similar, so carry on after it
+            self.recover_from(err)?;
+            self.advance()?;
+            let rest = self.block()?;
+            let stats: Vec<Stat> = self.ast[block.stats]
+                .iter()
+                .chain(&self.ast[rest.stats])
+                .cloned()
+                .collect();
+            block.stats = self.ast.add_list(stats);
+            block.ret = rest.ret.or(block.ret);
+            block.loc.span = block.loc.span.to(rest.loc.span);
+        }
+        self.unresolved_gotos(0)?;
+        Ok(self.main(block))
+    }
+    /// A single expression, up to the end of the input, as a chunk that
+    /// returns its values
+    pub fn expr_chunk(&mut self) -> Result<Ast> {
+        let mark = self.mark();
+        let expr = self.expr()?;
+        self.finish()?;
+        let block = Block {
+            stats: List::default(),
+            ret: Some(self.ast.add_list(Some(expr))),
+            loc: self.loc(mark),
+        };
+        Ok(self.main(block))
+    }
+    /// The tree parsed, with `body` as the main function
+    fn main(&mut self, body: Block) -> Ast {
+        let loc = body.loc.clone();
+        self.add(FuncBody {
+            params: List::default(),
+            vararg: true,
+            body,
+            loc,
+        });
+        mem::take(&mut self.ast)
+    }
+    /// Take the `goto`s made in a block, from `gotos` in the pending ones, to
+    /// the labels declared in it, from `labels` in the visible ones
+    fn resolve_gotos(&mut self, labels: usize, gotos: usize) -> Result<()> {
+        let mut i = gotos;
+        while i < self.gotos.len() {
+            let label = self.labels[labels..]
+                .iter()
+                .find(|label| label.name == self.gotos[i].label);
+            let label_active = match label {
+                Some(label) => label.active,
+                None => {
+                    i += 1;
+                    continue;
+                }
+            };
+            let goto = self.gotos.remove(i);
+            if goto.active < label_active {
+                let msg = format!(
+                    "<goto {}> jumps into the scope of local '{}'",
+                    goto.label, self.active[goto.active]
+                );
+                self.error_at(msg, &goto.loc)?;
+            }
+        }
+        Ok(())
+    }
+    /// Fail on the `goto`s of a function, from `gotos` on, that are left
+    /// without a label once all of it is parsed
+    fn unresolved_gotos(&mut self, gotos: usize) -> Result<()> {
+        for goto in self.gotos.split_off(gotos) {
+            let msg = format!("no visible label '{}' for <goto>", goto.label);
+            self.error_at(msg, &goto.loc)?;
+        }
+        Ok(())
+    }
+    /// Bring `names` into scope as locals
+    fn declare<'n, I>(&mut self, names: I)
+    where
+        I: IntoIterator<Item = &'n Name>,
+    {
+        self.active.extend(names.into_iter().cloned());
+    }
+    /// Take the locals declared after the first `active` out of scope
+    fn end_scope(&mut self, active: usize) {
+        self.active.truncate(active);
+        for goto in &mut self.gotos {
+            goto.active = goto.active.min(active);
+        }
+    }
+    /// Parse the body of a loop with `parse`, where `break` is allowed
+    fn loop_body<F>(&mut self, parse: F) -> Result<Block>
+    where
+        F: FnOnce(&mut Parser<'a>) -> Result<Block>,
+    {
+        self.loops += 1;
+        let body = parse(self);
+        self.loops -= 1;
+        body
+    }
+    /// Whether the current token ends a block
+    fn block_follows(&self) -> bool {
+        matches!(
+            self.token.kind,
+            TokenKind::Eof
+                | TokenKind::End
+                | TokenKind::Else
+                | TokenKind::ElseIf
+                | TokenKind::Until
+        )
+    }
+    /// A block followed by `end`
+    fn block_end(&mut self, what: &TokenKind, open: Mark) -> Result<Block> {
+        let block = self.block()?;
+        self.expect_match(&TokenKind::End, what, open)?;
+        Ok(block)
+    }
+    fn stat(&mut self) -> Result<Stat> {
+        let mark = self.mark();
+        let kind = self.stat_kind(mark)?;
+        let stat = Stat {
+            kind,
+            loc: self.loc(mark),
+        };
+        match stat.kind {
+            StatKind::Local(names, _) => self.active.extend_from_slice(&self.ast[names]),
+            StatKind::Label(ref name) => self.label(name, &stat.loc)?,
+            StatKind::Goto(ref label) => self.goto(label, &stat.loc),
+            _ => {}
+        }
+        Ok(stat)
+    }
+    fn label(&mut self, name: &Name, loc: &Location) -> Result<()> {
+        if let Some(label) = self.labels.iter().find(|label| label.name == *name) {
+            let msg = format!("label '{}' already defined on line {}", name, label.line);
+            return self.error_at(msg, loc);
+        }
+        self.labels.push(VisibleLabel {
+            name: name.clone(),
+            line: loc.pos.line,
+            active: self.active.len(),
+        });
+        Ok(())
+    }
+    /// Record a `goto`, which needs no checks when its label is visible,
+    /// as jumping back can only leave the scope of locals
+    fn goto(&mut self, label: &Name, loc: &Location) {
+        if self.labels.iter().all(|visible| visible.name != *label) {
+            self.gotos.push(PendingGoto {
+                label: label.clone(),
+                loc: loc.clone(),
+                active: self.active.len(),
+            });
+        }
+    }
+    fn stat_kind(&mut self, mark: Mark) -> Result<StatKind> {
+        match self.token.kind {
+            TokenKind::If => self.if_stat(mark),
+            TokenKind::While => {
+                self.advance()?;
+                let cond = self.expr()?;
+                let cond = self.add(cond);
+                self.expect(&TokenKind::Do)?;
+                let body = self.loop_body(|parser| parser.block_end(&TokenKind::While, mark))?;
+                Ok(StatKind::While(cond, body))
+            }
+            TokenKind::Do => {
+                self.advance()?;
+                Ok(StatKind::Do(self.block_end(&TokenKind::Do, mark)?))
+            }
+            TokenKind::For => self.for_stat(mark),
+            TokenKind::Repeat => {
+                self.advance()?;
+                let body = self.loop_body(Parser::block)?;
+                self.expect_match(&TokenKind::Until, &TokenKind::Repeat, mark)?;
+                let cond = self.expr()?;
+                Ok(StatKind::Repeat(body, self.add(cond)))
+            }
+            TokenKind::Function => {
+                self.advance()?;
+                let start = self.open::<Name>();
+                loop {
+                    let name = self.name()?;
+                    self.push(name);
+                    if !self.eat(&TokenKind::Dot)? {
+                        break;
+                    }
+                }
+                let path = self.close(start);
+                let method = if self.eat(&TokenKind::Colon)? {
+                    Some(self.name()?)
+                } else {
+                    None
+                };
+                let body = self.func_body(method.is_some(), mark)?;
+                Ok(StatKind::Function(FuncName { path, method }, body))
+            }
+            TokenKind::Local => {
+                self.advance()?;
+                if self.eat(&TokenKind::Function)? {
+                    let name = self.name()?;
+                    // the function can refer to itself
+                    self.declare(Some(&name));
+                    let body = self.func_body(false, mark)?;
+                    return Ok(StatKind::LocalFunction(name, body));
+                }
+                let names = self.name_list()?;
+                let values = if self.eat(&TokenKind::Assign)? {
+                    self.expr_list()?
+                } else {
+                    List::default()
+                };
+                Ok(StatKind::Local(names, values))
+            }
+            TokenKind::DoubleColon => {
+                self.advance()?;
+                let name = self.name()?;
+                self.expect(&TokenKind::DoubleColon)?;
+                Ok(StatKind::Label(name))
+            }
+            TokenKind::Break if self.loops == 0 => self.error("break outside a loop"),
+            TokenKind::Break => {
+                self.advance()?;
+                Ok(StatKind::Break)
+            }
+            TokenKind::Goto => {
+                self.advance()?;
+                Ok(StatKind::Goto(self.name()?))
+            }
+            _ => self.expr_stat(),
+        }
+    }
+    fn if_stat(&mut self, mark: Mark) -> Result<StatKind> {
+        let start = self.open::<Branch>();
+        loop {
+            // the `if` or `elseif`
+            self.advance()?;
+            let cond = self.expr()?;
+            let cond = self.add(cond);
+            self.expect(&TokenKind::Then)?;
+            let block = self.block()?;
+            self.push((cond, block));
+            if !self.check(&TokenKind::ElseIf) {
+                break;
+            }
+        }
+        let branches = self.close(start);
+        let else_block = if self.eat(&TokenKind::Else)? {
+            Some(self.block()?)
+        } else {
+            None
+        };
+        self.expect_match(&TokenKind::End, &TokenKind::If, mark)?;
+        Ok(StatKind::If(branches, else_block))
+    }
+    fn for_stat(&mut self, mark: Mark) -> Result<StatKind> {
+        self.advance()?;
+        if *self.peek()? == TokenKind::Assign {
+            let var = self.name()?;
+            self.advance()?;
+            let start = self.expr()?;
+            let start = self.add(start);
+            self.expect(&TokenKind::Comma)?;
+            let limit = self.expr()?;
+            let limit = self.add(limit);
+            let step = if self.eat(&TokenKind::Comma)? {
+                let step = self.expr()?;
+                Some(self.add(step))
+            } else {
+                None
+            };
+            self.expect(&TokenKind::Do)?;
+            let active = self.active.len();
+            self.declare(Some(&var));
+            let body = self.loop_body(|parser| parser.block_end(&TokenKind::For, mark));
+            self.end_scope(active);
+            let body = body?;
+            return Ok(StatKind::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+                body,
+            });
+        }
+        let vars = self.name_list()?;
+        if !self.check(&TokenKind::In) {
+            return self.expected(&[TokenKind::Assign, TokenKind::In]);
+        }
+        self.advance()?;
+        let exprs = self.expr_list()?;
+        self.expect(&TokenKind::Do)?;
+        let active = self.active.len();
+        self.active.extend_from_slice(&self.ast[vars]);
+        let body = self.loop_body(|parser| parser.block_end(&TokenKind::For, mark));
+        self.end_scope(active);
+        Ok(StatKind::GenericFor {
+            vars,
+            exprs,
+            body: body?,
+        })
+    }
+    /// An assignment or a function call
+    fn expr_stat(&mut self) -> Result<StatKind> {
+        let expr = self.suffixed_expr()?;
+        if !self.check(&TokenKind::Assign) && !self.check(&TokenKind::Comma) {
+            return match expr.kind {
+                ExprKind::Call(..) | ExprKind::Method(..) => Ok(StatKind::Call(self.add(expr))),
+                _ => self.error("syntax error"),
+            };
+        }
+        let start = self.open::<Expr>();
+        self.push(expr);
+        while self.eat(&TokenKind::Comma)? {
+            let target = self.suffixed_expr()?;
+            self.push(target);
+        }
+        let targets = &Arena::<Expr>::nodes(&mut self.scratch)[start..];
+        if targets
+            .iter()
+            .any(|target| !matches!(target.kind, ExprKind::Name(_) | ExprKind::Index(..)))
+        {
+            return self.error("syntax error");
+        }
+        self.expect(&TokenKind::Assign)?;
+        let targets = self.close(start);
+        Ok(StatKind::Assign(targets, self.expr_list()?))
+    }
+    /// One or more comma-separated names
+    fn name_list(&mut self) -> Result<List<Name>> {
+        let start = self.open::<Name>();
+        loop {
+            let name = self.name()?;
+            self.push(name);
+            if !self.eat(&TokenKind::Comma)? {
+                return Ok(self.close(start));
+            }
+        }
+    }
+    /// The parameter list and body of a function, after its name, where
+    /// `open` is at the `function` keyword
+    fn func_body(&mut self, is_method: bool, open: Mark) -> Result<FuncId> {
+        let mark = self.mark();
+        self.expect(&TokenKind::LeftParen)?;
+        let start = self.open::<Name>();
+        if is_method {
+            let name = self.intern("self".to_string());
+            self.push(name);
+        }
+        let mut vararg = false;
+        if !self.check(&TokenKind::RightParen) {
+            loop {
+                if self.eat(&TokenKind::Dots)? {
+                    vararg = true;
+                    break;
+                }
+                let name = self.name()?;
+                self.push(name);
+                if !self.eat(&TokenKind::Comma)? {
+                    break;
+                }
+            }
+        }
+        self.expect(&TokenKind::RightParen)?;
+        let params: List<Name> = self.close(start);
+        // loops outside the function cannot be broken out of from inside
+        // it, nor can its labels be jumped to
+        let loops = mem::replace(&mut self.loops, 0);
+        let outer_vararg = mem::replace(&mut self.vararg, vararg);
+        let active = mem::replace(&mut self.active, self.ast[params].to_vec());
+        let labels = mem::take(&mut self.labels);
+        let gotos = mem::take(&mut self.gotos);
+        let body = self
+            .block_end(&TokenKind::Function, open)
+            .and_then(|body| self.unresolved_gotos(0).map(|_| body));
+        self.loops = loops;
+        self.vararg = outer_vararg;
+        self.active = active;
+        self.labels = labels;
+        self.gotos = gotos;
+        let body = body?;
+        let loc = self.loc(mark);
+        Ok(self.add(FuncBody {
+            params,
+            vararg,
+            body,
+            loc,
+        }))
+    }
+
+    fn expr(&mut self) -> Result<Expr> {
+        self.sub_expr(0)
+    }
+    /// Parse an expression whose binary operators bind tighter than `limit`
+    fn sub_expr(&mut self, limit: u8) -> Result<Expr> {
+        let mark = self.mark();
+        let mut lhs = match unary_op(&self.token.kind) {
+            Some(op) => {
+                self.advance()?;
+                let operand = self.sub_expr(UnOp::PRECEDENCE)?;
+                let operand = self.add(operand);
+                self.expr_at(ExprKind::Unary(op, operand), mark)
+            }
+            None => self.simple_expr()?,
+        };
+        while let Some(op) = binary_op(&self.token.kind) {
+            let (left, right) = op.precedence();
+            if left <= limit {
+                break;
+            }
+            self.advance()?;
+            let rhs = self.sub_expr(right)?;
+            let kind = ExprKind::Binary(op, self.add(lhs), self.add(rhs));
+            lhs = self.expr_at(kind, mark);
+        }
+        Ok(lhs)
+    }
+    fn simple_expr(&mut self) -> Result<Expr> {
+        let mark = self.mark();
+        let kind = match self.token.kind {
+            TokenKind::Nil => ExprKind::Nil,
+            TokenKind::True => ExprKind::True,
+            TokenKind::False => ExprKind::False,
+            TokenKind::Dots if !self.vararg => {
+                return self.error("cannot use '...' outside a vararg function")
+            }
+            TokenKind::Dots => ExprKind::Vararg,
+            TokenKind::Number(num) => ExprKind::Number(num),
+            TokenKind::String(_) => match self.advance()?.kind {
+                TokenKind::String(bytes) => return Ok(self.expr_at(ExprKind::String(bytes), mark)),
+                _ => unreachable!(),
+            },
+            TokenKind::LeftBrace => return self.table(),
+            TokenKind::Function => {
+                self.advance()?;
+                let body = self.func_body(false, mark)?;
+                return Ok(self.expr_at(ExprKind::Function(body), mark));
+            }
+            _ => return self.suffixed_expr(),
+        };
+        self.advance()?;
+        Ok(self.expr_at(kind, mark))
+    }
+    /// A name or parenthesized expression
+    fn primary_expr(&mut self) -> Result<Expr> {
+        let mark = self.mark();
+        let kind = match self.token.kind {
+            TokenKind::Name(_) => ExprKind::Name(self.name()?),
+            TokenKind::LeftParen => {
+                self.advance()?;
+                let expr = self.expr()?;
+                self.expect_match(&TokenKind::RightParen, &TokenKind::LeftParen, mark)?;
+                ExprKind::Paren(self.add(expr))
+            }
+            _ => return self.error("unexpected symbol"),
+        };
+        Ok(self.expr_at(kind, mark))
+    }
+    /// A primary expression followed by any indexing and calls
+    fn suffixed_expr(&mut self) -> Result<Expr> {
+        let mark = self.mark();
+        let mut expr = self.primary_expr()?;
+        loop {
+            let kind = match self.token.kind {
+                TokenKind::Dot => {
+                    self.advance()?;
+                    let key_mark = self.mark();
+                    let key = ExprKind::String(self.name_string()?.into_bytes());
+                    let key = self.expr_at(key, key_mark);
+                    ExprKind::Index(self.add(expr), self.add(key))
+                }
+                TokenKind::LeftBracket => {
+                    self.advance()?;
+                    let key = self.expr()?;
+                    self.expect(&TokenKind::RightBracket)?;
+                    ExprKind::Index(self.add(expr), self.add(key))
+                }
+                TokenKind::Colon => {
+                    self.advance()?;
+                    let name = self.name()?;
+                    let args = self.call_args()?;
+                    ExprKind::Method(self.add(expr), name, args)
+                }
+                TokenKind::LeftParen | TokenKind::LeftBrace | TokenKind::String(_) => {
+                    let args = self.call_args()?;
+                    ExprKind::Call(self.add(expr), args)
+                }
+                _ => return Ok(expr),
+            };
+            expr = self.expr_at(kind, mark);
+        }
+    }
+    /// Arguments in parentheses, or a single table or string
+    fn call_args(&mut self) -> Result<ExprList> {
+        let arg = match self.token.kind {
+            TokenKind::LeftBrace => self.table()?,
+            TokenKind::String(_) => self.simple_expr()?,
+            TokenKind::LeftParen => {
+                let open = self.mark();
+                self.advance()?;
+                let args = if self.check(&TokenKind::RightParen) {
+                    List::default()
+                } else {
+                    self.expr_list()?
+                };
+                self.expect_match(&TokenKind::RightParen, &TokenKind::LeftParen, open)?;
+                return Ok(args);
+            }
+            _ => return self.error("function arguments expected"),
+        };
+        Ok(self.ast.add_list(Some(arg)))
+    }
+    /// One or more comma-separated expressions
+    fn expr_list(&mut self) -> Result<ExprList> {
+        let start = self.open::<Expr>();
+        loop {
+            let expr = self.expr()?;
+            self.push(expr);
+            if !self.eat(&TokenKind::Comma)? {
+                return Ok(self.close(start));
+            }
+        }
+    }
+    fn table(&mut self) -> Result<Expr> {
+        let mark = self.mark();
+        self.expect(&TokenKind::LeftBrace)?;
+        let start = self.open::<Field>();
+        while !self.check(&TokenKind::RightBrace) {
+            let field_mark = self.mark();
+            let named =
+                matches!(self.token.kind, TokenKind::Name(_)) && *self.peek()? == TokenKind::Assign;
+            let kind = match self.token.kind {
+                TokenKind::LeftBracket => {
+                    self.advance()?;
+                    let key = self.expr()?;
+                    self.expect(&TokenKind::RightBracket)?;
+                    self.expect(&TokenKind::Assign)?;
+                    let key = self.add(key);
+                    let val = self.expr()?;
+                    FieldKind::Indexed(key, self.add(val))
+                }
+                TokenKind::Name(_) if named => {
+                    let name = self.name()?;
+                    self.advance()?;
+                    let val = self.expr()?;
+                    FieldKind::Named(name, self.add(val))
+                }
+                _ => {
+                    let val = self.expr()?;
+                    FieldKind::Positional(self.add(val))
+                }
+            };
+            let loc = self.loc(field_mark);
+            self.push(Field { kind, loc });
+            if !self.eat(&TokenKind::Comma)? && !self.eat(&TokenKind::Semicolon)? {
+                break;
+            }
+        }
+        self.expect_match(&TokenKind::RightBrace, &TokenKind::LeftBrace, mark)?;
+        let fields = self.close(start);
+        Ok(self.expr_at(ExprKind::Table(fields), mark))
+    }
+}
+
+fn unary_op(kind: &TokenKind) -> Option<UnOp> {
+    Some(match *kind {
+        TokenKind::Minus => UnOp::Neg,
+        TokenKind::Not => UnOp::Not,
+        TokenKind::Hash => UnOp::Len,
+        TokenKind::Tilde => UnOp::BNot,
+        _ => return None,
+    })
+}
+
+fn binary_op(kind: &TokenKind) -> Option<BinOp> {
+    Some(match *kind {
+        TokenKind::Plus => BinOp::Add,
+        TokenKind::Minus => BinOp::Sub,
+        TokenKind::Star => BinOp::Mul,
+        TokenKind::Slash => BinOp::Div,
+        TokenKind::DoubleSlash => BinOp::IDiv,
+        TokenKind::Percent => BinOp::Mod,
+        TokenKind::Caret => BinOp::Pow,
+        TokenKind::Concat => BinOp::Concat,
+        TokenKind::Equal => BinOp::Eq,
+        TokenKind::NotEqual => BinOp::Ne,
+        TokenKind::Less => BinOp::Lt,
+        TokenKind::LessEqual => BinOp::Le,
+        TokenKind::Greater => BinOp::Gt,
+        TokenKind::GreaterEqual => BinOp::Ge,
+        TokenKind::And => BinOp::And,
+        TokenKind::Or => BinOp::Or,
+        TokenKind::Ampersand => BinOp::BAnd,
+        TokenKind::Pipe => BinOp::BOr,
+        TokenKind::Tilde => BinOp::BXor,
+        TokenKind::ShiftLeft => BinOp::Shl,
+        TokenKind::ShiftRight => BinOp::Shr,
+        _ => return None,
+    })
+}
+
+/// Parse `src` as a chunk named `name`, which is a block that runs to the end
+pub fn parse_chunk(src: &[u8], name: &str) -> Result<Ast> {
+    Parser::new(src, name)?.chunk()
+}
+
+/// Parse as much of `src` as possible, skipping statements with errors,
+/// and return the partial tree along with every error found
+pub fn parse_chunk_recovering(src: &[u8], name: &str) -> (Ast, Vec<ParseError>) {
+    let mut parser = Parser::recovering(src, name);
+    let ast = parser.chunk().expect("recovering parsers collect errors");
+    (ast, parser.errors)
+}
+
+/// Parse `src` as a single expression in a chunk named `name`, whose main
+/// function returns its values
+pub fn parse_expr(src: &[u8], name: &str) -> Result<Ast> {
+    Parser::new(src, name)?.expr_chunk()
+}
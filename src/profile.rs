@@ -0,0 +1,174 @@
+//! Finding where Lua code spends its time, by sampling which functions are
+//! running every so many steps
+//!
+//! Every so many steps, counted as the instruction limit counts them, a
+//! hook adds a sample of the Lua functions running, as a traceback would
+//! list them. Counting steps rather than time makes a profile the same
+//! from one run to the next, and leaves out time spent in library
+//! functions, which have no steps of their own and count towards the Lua
+//! function calling them.
+//!
+//! A profile can be written out in the folded format that flame graph
+//! tools read, a line for each stack sampled, outermost function first,
+//! with how many samples it had:
+//!
+//! ```text
+//! main.lua;main.lua:12;main.lua:3 260
+//! ```
+
+use std::cell::RefCell;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::rc::Rc;
+
+use error::Result;
+use hook::HookMask;
+use lua::Lua;
+use trace;
+use value::{MultiValue, Value};
+
+/// A Lua function, by where it is defined
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Frame {
+    /// the name of the chunk it is from
+    pub chunk: String,
+    /// the line it is defined on, which is 0 for a main chunk
+    pub line: u32,
+}
+impl fmt::Display for Frame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            0 => write!(f, "{}", self.chunk),
+            line => write!(f, "{}:{}", self.chunk, line),
+        }
+    }
+}
+
+/// How many of a profile's samples a function was in
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FunctionSamples {
+    pub frame: Frame,
+    /// the samples it was running in itself
+    pub own: u64,
+    /// the samples it was anywhere on the stack in, counting recursive
+    /// calls once
+    pub total: u64,
+}
+
+/// The samples taken while profiling, by the stack they were of
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Profile {
+    stacks: HashMap<Vec<Frame>, u64>,
+}
+impl Profile {
+    /// How many samples were taken
+    pub fn samples(&self) -> u64 {
+        self.stacks.values().sum()
+    }
+    /// The stacks sampled, outermost function first, with how many times,
+    /// in order
+    pub fn stacks(&self) -> Vec<(&[Frame], u64)> {
+        let mut stacks: Vec<(&[Frame], u64)> = self
+            .stacks
+            .iter()
+            .map(|(stack, &count)| (&**stack, count))
+            .collect();
+        stacks.sort();
+        stacks
+    }
+    /// The profile in the folded format, with each function named by
+    /// `name`, in which `;` is written as `,` since it separates them
+    pub fn folded(&self, name: &dyn Fn(&Frame) -> String) -> String {
+        let mut out = String::new();
+        for (stack, count) in self.stacks() {
+            let names: Vec<String> = stack
+                .iter()
+                .map(|frame| name(frame).replace(';', ","))
+                .collect();
+            out.push_str(&format!("{} {}\n", names.join(";"), count));
+        }
+        out
+    }
+    /// Each function sampled, those running in themselves the most first
+    pub fn functions(&self) -> Vec<FunctionSamples> {
+        let mut functions: HashMap<&Frame, (u64, u64)> = HashMap::new();
+        for (stack, &count) in &self.stacks {
+            if let Some(top) = stack.last() {
+                functions.entry(top).or_insert((0, 0)).0 += count;
+            }
+            for (i, frame) in stack.iter().enumerate() {
+                if !stack[..i].contains(frame) {
+                    functions.entry(frame).or_insert((0, 0)).1 += count;
+                }
+            }
+        }
+        let mut functions: Vec<FunctionSamples> = functions
+            .into_iter()
+            .map(|(frame, (own, total))| FunctionSamples {
+                frame: frame.clone(),
+                own,
+                total,
+            })
+            .collect();
+        functions.sort_by_key(|f| (Reverse(f.own), Reverse(f.total), f.frame.clone()));
+        functions
+    }
+}
+
+/// Profiles calls of Lua functions, with how often to take samples
+pub struct Profiler {
+    interval: u32,
+}
+impl Profiler {
+    /// A profiler taking a sample every 1000 steps
+    pub fn new() -> Profiler {
+        Profiler { interval: 1000 }
+    }
+    pub fn interval(&self) -> u32 {
+        self.interval
+    }
+    /// Take a sample every `interval` steps, where 0 means 1
+    pub fn set_interval(&mut self, interval: u32) {
+        self.interval = interval.max(1);
+    }
+
+    /// Call `func` with `args` in `lua`, giving the profile of the call as
+    /// well as what it returned, even if it raised an error
+    ///
+    /// The profiler replaces any hook set on `lua` while it runs, and
+    /// removes it after.
+    pub fn run(
+        &self,
+        lua: &mut Lua,
+        func: &Value,
+        args: Vec<Value>,
+    ) -> (Profile, Result<MultiValue>) {
+        let profile = Rc::new(RefCell::new(Profile::default()));
+        let sampled = profile.clone();
+        let mask = HookMask {
+            count: self.interval,
+            ..HookMask::default()
+        };
+        lua.set_hook(mask, move |_| {
+            let stack = trace::running()
+                .into_iter()
+                .map(|(chunk, line)| Frame {
+                    chunk: chunk.to_string(),
+                    line,
+                })
+                .collect();
+            *sampled.borrow_mut().stacks.entry(stack).or_insert(0) += 1;
+            Ok(())
+        });
+        let result = func.call(args);
+        lua.remove_hook();
+        let profile = profile.borrow().clone();
+        (profile, result)
+    }
+}
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
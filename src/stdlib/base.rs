@@ -0,0 +1,609 @@
+//! The basic functions, which are stored directly in the globals table
+
+use std::cell::Cell;
+use std::io::{self, Write};
+use std::rc::Rc;
+use std::str;
+
+use error::{Error, Result};
+use limits::{GcMode, Limits};
+use lua::{self, LoadOptions};
+use number::{self, Number};
+use table::Table;
+use trace;
+use value::{
+    ConvertValue, Event, LuaInteger, LuaNumber, LuaString, MultiValue, StringTable, Type, Value,
+    WeakValue,
+};
+use vm;
+
+use super::{arg, arg_error, check_arg, check_integer, register, type_error};
+
+/// Register the base library into `globals`, which is the table in `env`
+pub fn open(
+    globals: &Table,
+    env: &Value,
+    options: &Rc<Cell<LoadOptions>>,
+    limits: &Rc<Limits>,
+    strings: &Rc<StringTable>,
+) {
+    // the state clears this when it is closed, so it doesn't keep the
+    // globals alive
+    globals
+        .set(Value::string("_G"), env.clone())
+        .expect("string keys are always valid");
+    // a strong reference would keep the globals alive from inside them
+    let env = env.downgrade().expect("tables can be collected");
+    let counted = limits.clone();
+    globals
+        .set(
+            Value::string("collectgarbage"),
+            Value::function(move |args| collectgarbage(args, &counted)),
+        )
+        .expect("string keys are always valid");
+    let options = options.clone();
+    let limits = limits.clone();
+    let strings = strings.clone();
+    globals
+        .set(
+            Value::string("load"),
+            Value::function(move |args| load(args, options.get(), &limits, &strings, &env)),
+        )
+        .expect("string keys are always valid");
+    // `pairs` and `ipairs` give the same iterator functions every time
+    let next = Value::function(next);
+    globals
+        .set(Value::string("next"), next.clone())
+        .expect("string keys are always valid");
+    globals
+        .set(
+            Value::string("pairs"),
+            Value::function(move |args| pairs(args, &next)),
+        )
+        .expect("string keys are always valid");
+    let ipairs_iter = Value::function(ipairs_iter);
+    globals
+        .set(
+            Value::string("ipairs"),
+            Value::function(move |args| ipairs(args, &ipairs_iter)),
+        )
+        .expect("string keys are always valid");
+    register(globals, "assert", assert);
+    register(globals, "error", error);
+    register(globals, "getmetatable", getmetatable);
+    globals
+        .set(Value::string("pcall"), PCALL.with(Value::clone))
+        .expect("string keys are always valid");
+    register(globals, "print", print);
+    register(globals, "rawequal", rawequal);
+    register(globals, "rawget", rawget);
+    register(globals, "rawlen", rawlen);
+    register(globals, "rawset", rawset);
+    register(globals, "select", select);
+    register(globals, "setmetatable", setmetatable);
+    register(globals, "tonumber", tonumber);
+    register(globals, "tostring", tostring);
+    register(globals, "type", type_name);
+    // as in Lua 5.1, from before it moved into the table library
+    register(globals, "unpack", super::table::unpack);
+    globals
+        .set(Value::string("xpcall"), XPCALL.with(Value::clone))
+        .expect("string keys are always valid");
+}
+
+thread_local! {
+    /// `pcall` and `xpcall`, which every state shares so that the VM can
+    /// recognize them and run what a coroutine calls through them in frames
+    /// of its own, where it can yield
+    static PCALL: Value = Value::function(pcall);
+    static XPCALL: Value = Value::function(xpcall);
+}
+
+/// Which of `pcall` and `xpcall` a function is
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Protected {
+    Pcall,
+    Xpcall,
+}
+
+/// Whether `func` is `pcall` or `xpcall`, and which
+pub fn protected(func: &Value) -> Option<Protected> {
+    if PCALL.with(|pcall| func.raw_equal(pcall)) {
+        Some(Protected::Pcall)
+    } else if XPCALL.with(|xpcall| func.raw_equal(xpcall)) {
+        Some(Protected::Xpcall)
+    } else {
+        None
+    }
+}
+
+/// `collectgarbage([opt [, ...]])`, where "collect", the default, frees the
+/// values nothing in use refers to and runs the finalizers of what has
+/// been collected, "step" does a step of collecting them, giving whether
+/// that finished a collection, and "count" gives how many kilobytes the
+/// state's values take up
+///
+/// "incremental" switches to collecting incrementally and "generational"
+/// to collecting by generation, giving the mode before, and set the pause
+/// and step multiplier or the minor and major multipliers where they are
+/// given and not 0. "setpause" and "setstepmul" set one at a time, giving
+/// the previous value.
+fn collectgarbage(args: &[Value], limits: &Limits) -> Result<MultiValue> {
+    let opt = match arg(args, 1) {
+        ref opt if opt.is_nil() => b"collect".to_vec(),
+        ref opt => match LuaString::from_value(opt) {
+            Some(opt) => opt.to_vec(),
+            None => return Err(type_error(args, 1, "collectgarbage", "string")),
+        },
+    };
+    Ok(match &opt[..] {
+        b"collect" => {
+            limits.collect();
+            0.into_value().into()
+        }
+        b"step" => {
+            let kb = optional_integer(args, 2)?.max(0) as usize;
+            limits
+                .collect_some(kb.saturating_mul(1024))
+                .into_value()
+                .into()
+        }
+        b"incremental" => {
+            let pause = optional_integer(args, 2)?;
+            if pause > 0 {
+                limits.set_pause(pause as u32);
+            }
+            let multiplier = optional_integer(args, 3)?;
+            if multiplier > 0 {
+                limits.set_step_multiplier(multiplier as u32);
+            }
+            mode_name(limits.set_gc_mode(GcMode::Incremental)).into()
+        }
+        b"generational" => {
+            let minor = optional_integer(args, 2)?;
+            if minor > 0 {
+                limits.set_minor_multiplier(minor as u32);
+            }
+            let major = optional_integer(args, 3)?;
+            if major > 0 {
+                limits.set_major_multiplier(major as u32);
+            }
+            mode_name(limits.set_gc_mode(GcMode::Generational)).into()
+        }
+        b"setpause" => {
+            let previous = limits.pause();
+            limits.set_pause(optional_integer(args, 2)?.max(0) as u32);
+            (previous as LuaInteger).into_value().into()
+        }
+        b"setstepmul" => {
+            let previous = limits.step_multiplier();
+            limits.set_step_multiplier(optional_integer(args, 2)?.max(0) as u32);
+            (previous as LuaInteger).into_value().into()
+        }
+        b"count" => (limits.memory_used() as LuaNumber / 1024.0)
+            .into_value()
+            .into(),
+        b"isrunning" => true.into_value().into(),
+        _ => {
+            let msg = format!("invalid option '{}'", String::from_utf8_lossy(&opt));
+            return Err(arg_error(1, "collectgarbage", &msg));
+        }
+    })
+}
+
+fn mode_name(mode: GcMode) -> Value {
+    Value::string(match mode {
+        GcMode::Incremental => "incremental",
+        GcMode::Generational => "generational",
+    })
+}
+
+/// The integer argument `n` of `collectgarbage` if it is given, or 0
+fn optional_integer(args: &[Value], n: usize) -> Result<LuaInteger> {
+    if arg(args, n).is_nil() {
+        Ok(0)
+    } else {
+        check_integer(args, n, "collectgarbage")
+    }
+}
+
+/// Whether `metatable` is protected by a `__metatable` field
+fn protected_field(metatable: &Value) -> Option<Value> {
+    let field = metatable.raw_get(&Value::string("__metatable"));
+    if field.is_nil() {
+        None
+    } else {
+        Some(field)
+    }
+}
+
+fn getmetatable(args: &[Value]) -> Result<Value> {
+    if args.is_empty() {
+        return Err(arg_error(1, "getmetatable", "value expected"));
+    }
+    Ok(match args[0].get_metatable() {
+        Some(mt) => protected_field(&mt).unwrap_or(mt),
+        None => Value::nil(),
+    })
+}
+
+/// `ipairs(t)`, which gives an iterator over `t[1]`, `t[2]` and so on up
+/// to the first nil, indexing `t` as the loop body would
+fn ipairs(args: &[Value], iter: &Value) -> Result<MultiValue> {
+    if args.is_empty() {
+        return Err(arg_error(1, "ipairs", "table expected, got no value"));
+    }
+    Ok(vec![iter.clone(), args[0].clone(), 0.into_value()].into())
+}
+
+fn ipairs_iter(args: &[Value]) -> Result<MultiValue> {
+    let i = check_integer(args, 2, "ipairs")?
+        .wrapping_add(1)
+        .into_value();
+    let val = arg(args, 1).get_index(&i)?;
+    Ok(if val.is_nil() {
+        val.into()
+    } else {
+        vec![i, val].into()
+    })
+}
+
+/// `load(chunk [, chunkname [, mode [, env]]])`, which gives nil and the
+/// message rather than raising an error when the chunk cannot be loaded
+fn load(
+    args: &[Value],
+    options: LoadOptions,
+    limits: &Rc<Limits>,
+    strings: &Rc<StringTable>,
+    globals: &WeakValue,
+) -> Result<MultiValue> {
+    let chunk = arg(args, 1);
+    if chunk.type_of() != Type::String && chunk.type_of() != Type::Function {
+        return Err(type_error(args, 1, "load", "string"));
+    }
+    for &(n, what) in &[(2, "chunkname"), (3, "mode")] {
+        let val = arg(args, n);
+        if !val.is_nil() && val.type_of() != Type::String {
+            return Err(arg_error(
+                n,
+                "load",
+                &format!("string expected for {}", what),
+            ));
+        }
+    }
+    let env = match args.get(3) {
+        Some(env) => env.clone(),
+        None => globals.upgrade().unwrap_or_else(Value::nil),
+    };
+    Ok(
+        match try_load(args, &chunk, options, limits, strings, env) {
+            Ok(func) => func.into(),
+            Err(err) => vec![Value::nil(), err.into_value()].into(),
+        },
+    )
+}
+
+fn try_load(
+    args: &[Value],
+    chunk: &Value,
+    options: LoadOptions,
+    limits: &Rc<Limits>,
+    strings: &Rc<StringTable>,
+    env: Value,
+) -> Result<Value> {
+    let source = match LuaString::from_value(chunk) {
+        Some(bytes) => bytes.to_vec(),
+        None => read_chunk(chunk)?,
+    };
+    let name = match LuaString::from_value(&arg(args, 2)) {
+        Some(name) => display_name(&String::from_utf8_lossy(name)),
+        None if chunk.type_of() == Type::String => {
+            lua::chunk_name(&String::from_utf8_lossy(&source))
+        }
+        None => "(load)".to_string(),
+    };
+    let mode = arg(args, 3);
+    let mode = LuaString::from_value(&mode).map_or(&b"bt"[..], |mode| &mode[..]);
+    let (kind, allowed) = if source.starts_with(vm::SIGNATURE) {
+        ("binary", mode.contains(&b'b'))
+    } else {
+        ("text", mode.contains(&b't'))
+    };
+    if !allowed {
+        return Err(Error::Runtime(format!(
+            "attempt to load a {} chunk (mode is '{}')",
+            kind,
+            String::from_utf8_lossy(mode)
+        )));
+    }
+    lua::load_chunk(&source, &name, options, limits, strings, env)
+}
+
+/// Concatenate the pieces returned by a reader function, which ends the
+/// chunk with nil or an empty string
+fn read_chunk(reader: &Value) -> Result<Vec<u8>> {
+    let mut source = Vec::new();
+    loop {
+        let piece = reader.call(Vec::new())?.into_first();
+        if piece.is_nil() {
+            return Ok(source);
+        }
+        match LuaString::from_value(&piece) {
+            Some(bytes) if bytes.is_empty() => return Ok(source),
+            Some(bytes) => source.extend_from_slice(bytes),
+            None => {
+                return Err(Error::Runtime(
+                    "reader function must return a string".to_string(),
+                ))
+            }
+        }
+    }
+}
+
+/// How a chunk name given to `load` is shown, where `=name` is shown as is,
+/// `@file` names a file and anything else is source text
+fn display_name(name: &str) -> String {
+    if name.starts_with('=') || name.starts_with('@') {
+        name[1..].to_string()
+    } else {
+        lua::chunk_name(name)
+    }
+}
+
+/// `next(t [, key])`, which gives the key and value after `key` in `t`, or
+/// the first ones if `key` is nil, or nil after the last
+fn next(args: &[Value]) -> Result<MultiValue> {
+    let table = check_arg(args, 1, "next", Type::Table)?;
+    Ok(match table.raw_next(&arg(args, 2))? {
+        Some((key, val)) => vec![key, val].into(),
+        None => Value::nil().into(),
+    })
+}
+
+/// `pairs(t)`, which gives `next`, `t` and nil to traverse all of `t`,
+/// unless its metatable has a `__pairs` field to call with `t` instead
+fn pairs(args: &[Value], next: &Value) -> Result<MultiValue> {
+    let val = arg(args, 1);
+    if let Some(handler) = val.metamethod(Event::Pairs) {
+        let mut vals = handler.call(vec![val])?.into_iter();
+        return Ok((0..3)
+            .map(|_| vals.next().unwrap_or_else(Value::nil))
+            .collect());
+    }
+    let table = check_arg(args, 1, "pairs", Type::Table)?;
+    Ok(vec![next.clone(), table, Value::nil()].into())
+}
+
+/// `error(message [, level])`, which gives a string message the position
+/// of the function `level` calls out, by default the one calling `error`,
+/// or none with a level of 0
+fn error(args: &[Value]) -> Result<Value> {
+    let val = arg(args, 1);
+    let level = match args.get(1) {
+        Some(_) => check_integer(args, 2, "error")?,
+        None => 1,
+    };
+    match LuaString::from_value(&val) {
+        Some(msg) if level > 0 => {
+            trace::raise(level as usize);
+            Err(Error::Runtime(String::from_utf8_lossy(msg).into_owned()))
+        }
+        _ => {
+            trace::raise(0);
+            Err(Error::Lua(val))
+        }
+    }
+}
+
+/// `assert(v [, message, ...])`, which gives all of its arguments if `v`
+/// is true, and otherwise raises `message` as it is, or "assertion failed!"
+fn assert(args: &[Value]) -> Result<MultiValue> {
+    if args.is_empty() {
+        return Err(arg_error(1, "assert", "value expected"));
+    }
+    if args[0].to_bool() {
+        return Ok(args.to_vec().into());
+    }
+    match args.get(1) {
+        Some(msg) => {
+            trace::raise(0);
+            Err(Error::Lua(msg.clone()))
+        }
+        None => Err(Error::Runtime("assertion failed!".to_string())),
+    }
+}
+
+/// `pcall(f, ...)`, which calls `f` with the other arguments and gives
+/// true and its results, or false and the error it raised
+fn pcall(args: &[Value]) -> Result<MultiValue> {
+    if args.is_empty() {
+        return Err(arg_error(1, "pcall", "value expected"));
+    }
+    match args[0].call(args[1..].to_vec()) {
+        Ok(vals) => Ok(Some(true.into_value()).into_iter().chain(vals).collect()),
+        Err(err) => {
+            trace::catch();
+            Ok(vec![false.into_value(), err.into_value()].into())
+        }
+    }
+}
+
+/// `xpcall(f, msgh, ...)`, which is `pcall` except that an error is passed
+/// to `msgh` first, where `debug.traceback` still sees where it was raised,
+/// and false and what `msgh` returns are given instead
+fn xpcall(args: &[Value]) -> Result<MultiValue> {
+    if args.len() < 2 {
+        return Err(arg_error(2, "xpcall", "value expected"));
+    }
+    match args[0].call(args[2..].to_vec()) {
+        Ok(vals) => Ok(Some(true.into_value()).into_iter().chain(vals).collect()),
+        Err(err) => {
+            let handled = trace::handling(|| args[1].call(vec![err.into_value()]));
+            trace::catch();
+            let val = match handled {
+                Ok(vals) => vals.into_first(),
+                Err(err) => err.into_value(),
+            };
+            Ok(vec![false.into_value(), val].into())
+        }
+    }
+}
+
+fn print(args: &[Value]) -> Result<MultiValue> {
+    let mut line = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        if i > 0 {
+            line.push(b'\t');
+        }
+        let text = arg.lua_tostring()?;
+        line.extend_from_slice(LuaString::from_value(&text).expect("tostring gives strings"));
+    }
+    line.push(b'\n');
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    stdout
+        .write_all(&line)
+        .and_then(|_| stdout.flush())
+        .map_err(|err| Error::Runtime(err.to_string()))?;
+    Ok(MultiValue::new())
+}
+
+/// `select(n, ...)`, giving the arguments after the `n`th, counting from
+/// the end if `n` is negative, or `select('#', ...)`, giving how many
+/// there are
+fn select(args: &[Value]) -> Result<MultiValue> {
+    let rest = args.get(1..).unwrap_or(&[]);
+    if LuaString::from_value(&arg(args, 1)).is_some_and(|n| &n[..] == b"#") {
+        return Ok((rest.len() as LuaInteger).into_value().into());
+    }
+    let n = check_integer(args, 1, "select")?;
+    let start = if n < 0 {
+        rest.len().checked_sub(n.unsigned_abs() as usize)
+    } else if n > 0 {
+        Some((n as usize - 1).min(rest.len()))
+    } else {
+        None
+    };
+    match start {
+        Some(start) => Ok(rest[start..].to_vec().into()),
+        None => Err(arg_error(1, "select", "index out of range")),
+    }
+}
+
+/// `type(v)`, which gives the name of the type of `v`
+fn type_name(args: &[Value]) -> Result<Value> {
+    match args.first() {
+        Some(val) => Ok(Value::string(val.type_of().to_string())),
+        None => Err(arg_error(1, "type", "value expected")),
+    }
+}
+
+/// `tostring(v)`, which converts `v` to a string as `print` does
+fn tostring(args: &[Value]) -> Result<Value> {
+    match args.first() {
+        Some(val) => val.lua_tostring(),
+        None => Err(arg_error(1, "tostring", "value expected")),
+    }
+}
+
+/// `tonumber(v [, base])`, which gives `v` if it is a number and converts
+/// strings that are numerals, giving nil for anything else, or reads `v`
+/// as an integer in `base` from 2 to 36, with letters as the digits from 10
+fn tonumber(args: &[Value]) -> Result<Value> {
+    if args.len() < 2 || args[1].is_nil() {
+        let val = match args.first() {
+            Some(val) => val,
+            None => return Err(arg_error(1, "tonumber", "value expected")),
+        };
+        return Ok(match val.type_of() {
+            Type::Number => val.clone(),
+            Type::String => {
+                let bytes = LuaString::from_value(val).expect("checked type");
+                str::from_utf8(bytes)
+                    .ok()
+                    .and_then(number::parse)
+                    .map_or_else(Value::nil, Number::into_value)
+            }
+            _ => Value::nil(),
+        });
+    }
+    let base = check_integer(args, 2, "tonumber")?;
+    let digits = check_arg(args, 1, "tonumber", Type::String)?;
+    if !(2..=36).contains(&base) {
+        return Err(arg_error(2, "tonumber", "base out of range"));
+    }
+    let digits = LuaString::from_value(&digits).expect("checked type");
+    Ok(parse_int(digits, base as u32).map_or_else(Value::nil, Value::new))
+}
+
+/// Read `digits` as an integer in `base`, with surrounding whitespace and
+/// an optional `-`, wrapping around on overflow
+fn parse_int(digits: &[u8], base: u32) -> Option<LuaInteger> {
+    let digits = digits.trim_ascii();
+    let (negative, digits) = match digits.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        _ => (false, digits),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: LuaInteger = 0;
+    for &c in digits {
+        let digit = (c as char).to_digit(base)?;
+        n = n
+            .wrapping_mul(base as LuaInteger)
+            .wrapping_add(digit as LuaInteger);
+    }
+    Some(if negative { n.wrapping_neg() } else { n })
+}
+
+fn setmetatable(args: &[Value]) -> Result<Value> {
+    let table = check_arg(args, 1, "setmetatable", Type::Table)?;
+    let metatable = match arg(args, 2) {
+        ref mt if mt.type_of() == Type::Table => Some(mt.clone()),
+        ref mt if mt.is_nil() => None,
+        _ => return Err(arg_error(2, "setmetatable", "nil or table expected")),
+    };
+    if table
+        .get_metatable()
+        .and_then(|mt| protected_field(&mt))
+        .is_some()
+    {
+        return Err(Error::Runtime(
+            "cannot change a protected metatable".to_string(),
+        ));
+    }
+    table.set_metatable(metatable)?;
+    Ok(table)
+}
+
+fn rawequal(args: &[Value]) -> Result<Value> {
+    if args.len() < 2 {
+        return Err(arg_error(args.len() + 1, "rawequal", "value expected"));
+    }
+    Ok(args[0].raw_equal(&args[1]).into_value())
+}
+
+fn rawget(args: &[Value]) -> Result<Value> {
+    let table = check_arg(args, 1, "rawget", Type::Table)?;
+    if args.len() < 2 {
+        return Err(arg_error(2, "rawget", "value expected"));
+    }
+    Ok(table.raw_get(&args[1]))
+}
+
+fn rawlen(args: &[Value]) -> Result<Value> {
+    match arg(args, 1).raw_len() {
+        Some(len) => Ok(len.into_value()),
+        None => Err(arg_error(1, "rawlen", "table or string expected")),
+    }
+}
+
+fn rawset(args: &[Value]) -> Result<Value> {
+    let table = check_arg(args, 1, "rawset", Type::Table)?;
+    if args.len() < 3 {
+        return Err(arg_error(args.len() + 1, "rawset", "value expected"));
+    }
+    table.raw_set(args[1].clone(), args[2].clone())?;
+    Ok(table)
+}
@@ -0,0 +1,69 @@
+//! The string buffer library, which is stored in the `buffer` global
+//!
+//! Building a string with `s = s .. piece` in a loop copies everything
+//! joined so far each time round. A buffer grows in place instead, so
+//! scripts that build up logs or output a piece at a time only copy what
+//! they put in, and once more when they take the string out.
+
+use error::Result;
+use table::Table;
+use userdata::{UserData, UserDataMethods};
+use value::{ConvertValue, LuaInteger, MultiValue, Value};
+
+use super::{arg_error, register};
+
+/// Register the buffer library into `globals`
+pub fn open(globals: &Table) {
+    let buffer = Table::new();
+    register(&buffer, "new", new);
+    globals
+        .set(Value::string("buffer"), buffer.into_value())
+        .expect("string keys are always valid");
+}
+
+/// A string being built up, which scripts append to with `put`
+#[derive(Default)]
+struct Buffer {
+    bytes: Vec<u8>,
+}
+impl Buffer {
+    /// `buf:put(...)`, which appends each string or number
+    fn put(&mut self, args: &[Value]) -> Result<MultiValue> {
+        for (i, val) in args.iter().enumerate() {
+            if !val.concat_into(&mut self.bytes) {
+                // counting the buffer itself
+                let msg = format!("string expected, got {}", val.type_of());
+                return Err(arg_error(i + 2, "put", &msg));
+            }
+        }
+        Ok(MultiValue::new())
+    }
+    /// `buf:get()`, which gives what has been put and empties the buffer
+    fn get(&mut self) -> Value {
+        let val = Value::string(&self.bytes);
+        self.bytes.clear();
+        val
+    }
+}
+impl UserData for Buffer {
+    const NAME: &'static str = "buffer";
+
+    fn add_methods(methods: &mut UserDataMethods<Self>) {
+        methods.add_method_mut("put", |buf, args| buf.put(args));
+        methods.add_method_mut("get", |buf, _| Ok(buf.get()));
+        methods.add_method("tostring", |buf, _| Ok(Value::string(&buf.bytes)));
+        methods.add_method_mut("reset", |buf, _| {
+            buf.bytes.clear();
+            Ok(MultiValue::new())
+        });
+        methods.add_meta_method("__tostring", |buf, _| Ok(Value::string(&buf.bytes)));
+        methods.add_meta_method("__len", |buf, _| {
+            Ok((buf.bytes.len() as LuaInteger).into_value())
+        });
+    }
+}
+
+/// `buffer.new()`, which gives an empty buffer
+fn new(_: &[Value]) -> Result<Value> {
+    Ok(Value::userdata(Buffer::default()))
+}
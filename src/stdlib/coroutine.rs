@@ -0,0 +1,77 @@
+//! The coroutine library, which is stored in the `coroutine` global
+
+use error::Result;
+use table::Table;
+use trace;
+use value::{ConvertValue, MultiValue, Type, Value};
+use vm::{self, Coroutine};
+
+use super::{check_arg, register};
+
+/// Register the coroutine library into `globals`
+pub fn open(globals: &Table) {
+    let coroutine = Table::new();
+    register(&coroutine, "close", close);
+    register(&coroutine, "create", create);
+    register(&coroutine, "isyieldable", isyieldable);
+    register(&coroutine, "resume", resume);
+    register(&coroutine, "running", running);
+    register(&coroutine, "status", status);
+    register(&coroutine, "wrap", wrap);
+    coroutine
+        .set(Value::string("yield"), vm::yield_function())
+        .expect("string keys are always valid");
+    globals
+        .set(Value::string("coroutine"), coroutine.into_value())
+        .expect("string keys are always valid");
+}
+
+fn close(args: &[Value]) -> Result<Value> {
+    let co = check_arg(args, 1, "close", Type::Thread)?;
+    co.as_thread().expect("checked to be a thread").close()?;
+    Ok(true.into_value())
+}
+
+fn create(args: &[Value]) -> Result<Value> {
+    let func = check_arg(args, 1, "create", Type::Function)?;
+    Ok(Value::thread(Coroutine::new(func)))
+}
+
+fn isyieldable(_: &[Value]) -> Result<Value> {
+    Ok(vm::running().is_some().into_value())
+}
+
+/// `coroutine.resume(co, ...)`, which gives true and what the coroutine
+/// yielded or returned, or false and the error it raised
+fn resume(args: &[Value]) -> Result<MultiValue> {
+    let co = check_arg(args, 1, "resume", Type::Thread)?;
+    match vm::resume(&co, args[1..].to_vec()) {
+        Ok(vals) => Ok(Some(true.into_value()).into_iter().chain(vals).collect()),
+        Err(err) => {
+            trace::catch();
+            Ok(vec![false.into_value(), err.into_value()].into())
+        }
+    }
+}
+
+/// `coroutine.running()`, which gives the running coroutine, or nil
+/// outside of one, and whether it is the main thread
+fn running(_: &[Value]) -> Result<MultiValue> {
+    let co = vm::running();
+    let main = co.is_none();
+    Ok(vec![co.unwrap_or_else(Value::nil), main.into_value()].into())
+}
+
+fn status(args: &[Value]) -> Result<Value> {
+    let co = check_arg(args, 1, "status", Type::Thread)?;
+    let status = co.as_thread().expect("checked to be a thread").status();
+    Ok(Value::string(status.name()))
+}
+
+/// `coroutine.wrap(f)`, which gives a function resuming a new coroutine
+/// running `f`, raising any error it raises
+fn wrap(args: &[Value]) -> Result<Value> {
+    let func = check_arg(args, 1, "wrap", Type::Function)?;
+    let co = Value::thread(Coroutine::new(func));
+    Ok(Value::function(move |args| vm::resume(&co, args.to_vec())))
+}
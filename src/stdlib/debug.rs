@@ -0,0 +1,121 @@
+//! The debug library, which is stored in the `debug` global
+
+use std::rc::Rc;
+
+use error::Result;
+use hook::{HookEvent, HookInfo, HookMask, Hooks};
+use limits::Limits;
+use table::Table;
+use trace;
+use value::{ConvertValue, LuaInteger, LuaString, MultiValue, Type, Value};
+
+use super::{arg, check_arg, check_integer, register, type_error};
+
+/// Register the debug library into `globals`, with hooks set on the state
+/// `limits` belongs to
+pub fn open(globals: &Table, limits: &Rc<Limits>) {
+    let debug = Table::new();
+    let hooked = limits.clone();
+    debug
+        .set(
+            Value::string("gethook"),
+            Value::function(move |args| gethook(args, &hooked.hooks)),
+        )
+        .expect("string keys are always valid");
+    let hooked = limits.clone();
+    debug
+        .set(
+            Value::string("sethook"),
+            Value::function(move |args| sethook(args, &hooked.hooks)),
+        )
+        .expect("string keys are always valid");
+    register(&debug, "traceback", traceback);
+    globals
+        .set(Value::string("debug"), debug.into_value())
+        .expect("string keys are always valid");
+}
+
+/// `debug.traceback([message])`, which adds the traceback of the calls
+/// running to `message`, and in a message handler given to `xpcall` those
+/// the error being handled left first
+///
+/// A message that is not a string is given back unchanged.
+fn traceback(args: &[Value]) -> Result<Value> {
+    let msg = arg(args, 1);
+    match msg.type_of() {
+        Type::Nil => Ok(Value::string(trace::current())),
+        Type::String | Type::Number => {
+            let mut text = LuaString::from_value(&msg.lua_tostring()?)
+                .expect("tostring gives strings")
+                .to_vec();
+            text.push(b'\n');
+            text.extend_from_slice(trace::current().as_bytes());
+            Ok(Value::string(text))
+        }
+        _ => Ok(msg),
+    }
+}
+
+/// `debug.sethook([hook, mask [, count]])`, which calls `hook` with the
+/// name of each event in `mask` and the line for line events, where `mask`
+/// has `c` for calls, `r` for returns and `l` for lines, and `count` asks
+/// for a count event every so many steps
+///
+/// Without a hook, or with an empty mask and no count, the hook is removed.
+/// The hook only sees the functions of the state whose `debug.sethook` set
+/// it.
+fn sethook(args: &[Value], hooks: &Hooks) -> Result<Value> {
+    let func = arg(args, 1);
+    if func.is_nil() {
+        hooks.remove();
+        return Ok(Value::nil());
+    }
+    let func = check_arg(args, 1, "sethook", Type::Function)?;
+    let mask = match LuaString::from_value(&arg(args, 2)) {
+        Some(mask) => mask.clone(),
+        None => return Err(type_error(args, 2, "sethook", "string")),
+    };
+    let count = if arg(args, 3).is_nil() {
+        0
+    } else {
+        check_integer(args, 3, "sethook")?.clamp(0, LuaInteger::from(u32::MAX)) as u32
+    };
+    let mask = HookMask {
+        call: mask.contains(&b'c'),
+        ret: mask.contains(&b'r'),
+        line: mask.contains(&b'l'),
+        count,
+    };
+    let handler = func.clone();
+    let call = move |info: &HookInfo| {
+        let mut args = vec![Value::string(info.event().name())];
+        if let HookEvent::Line(line) = info.event() {
+            args.push(LuaInteger::from(line).into_value());
+        }
+        handler.call(args).map(|_| ())
+    };
+    hooks.set(mask, Rc::new(call), Some(func));
+    Ok(Value::nil())
+}
+
+/// `debug.gethook()`, which gives the hook, its mask and its count, with
+/// "external hook" in place of a hook set from Rust, or nothing if there
+/// is no hook
+fn gethook(_: &[Value], hooks: &Hooks) -> Result<MultiValue> {
+    let (mask, func) = match hooks.get() {
+        Some(hook) => hook,
+        None => return Ok(MultiValue::new()),
+    };
+    let mut letters = Vec::new();
+    for &(set, letter) in &[(mask.call, b'c'), (mask.ret, b'r'), (mask.line, b'l')] {
+        if set {
+            letters.push(letter);
+        }
+    }
+    Ok(vec![
+        func.unwrap_or_else(|| Value::string("external hook")),
+        Value::string(letters),
+        LuaInteger::from(mask.count).into_value(),
+    ]
+    .into())
+}
@@ -0,0 +1,88 @@
+//! The standard library functions available to scripts
+
+pub mod base;
+pub mod buffer;
+pub mod coroutine;
+pub mod debug;
+pub mod os;
+pub mod package;
+pub mod string;
+pub mod table;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use error::Error;
+use limits::Limits;
+use lua::LoadOptions;
+use table::Table;
+use value::{ConvertValue, LuaInteger, LuaTable, MultiValue, StringTable, Type, Value};
+
+/// Register the standard libraries into `globals`, with `load` compiling
+/// chunks with whatever `options` hold at the time, held to `limits` and
+/// interning strings in `strings`
+pub fn open(
+    globals: &Value,
+    options: &Rc<Cell<LoadOptions>>,
+    limits: &Rc<Limits>,
+    strings: &Rc<StringTable>,
+) {
+    let table = LuaTable::from_value(globals).expect("globals are a table");
+    base::open(table, globals, options, limits, strings);
+    buffer::open(table);
+    coroutine::open(table);
+    debug::open(table, limits);
+    os::open(table);
+    string::open(table);
+    table::open(table);
+    package::open(table, globals, options, limits, strings);
+}
+
+/// Get argument `n` (counting from 1), or nil if it was not passed
+fn arg(args: &[Value], n: usize) -> Value {
+    args.get(n - 1).cloned().unwrap_or_else(Value::nil)
+}
+
+fn arg_error(n: usize, func: &str, msg: &str) -> Error {
+    Error::Runtime(format!("bad argument #{} to '{}' ({})", n, func, msg))
+}
+
+/// An error for argument `n` not being of the `expected` type
+fn type_error(args: &[Value], n: usize, func: &str, expected: &str) -> Error {
+    let got = match args.get(n - 1) {
+        Some(val) => val.type_of().to_string(),
+        None => "no value".to_string(),
+    };
+    arg_error(n, func, &format!("{} expected, got {}", expected, got))
+}
+
+/// Get argument `n`, raising an error unless it is of type `ty`
+fn check_arg(args: &[Value], n: usize, func: &str, ty: Type) -> Result<Value, Error> {
+    match args.get(n - 1) {
+        Some(val) if val.type_of() == ty => Ok(val.clone()),
+        _ => Err(type_error(args, n, func, &ty.to_string())),
+    }
+}
+
+/// Get argument `n` as an integer, which floats with an exact integer value
+/// and numeric strings convert to
+fn check_integer(args: &[Value], n: usize, func: &str) -> Result<LuaInteger, Error> {
+    let val = arg(args, n);
+    match val.as_integer() {
+        Some(i) => Ok(i),
+        None if val.type_of() == Type::Number => {
+            Err(arg_error(n, func, "number has no integer representation"))
+        }
+        None => Err(type_error(args, n, func, "number")),
+    }
+}
+
+/// Store the Rust function `func` in `table` under `name`
+fn register<R>(table: &Table, name: &str, func: fn(&[Value]) -> ::error::Result<R>)
+where
+    R: Into<MultiValue> + 'static,
+{
+    table
+        .set(Value::string(name), Value::function(func))
+        .expect("string keys are always valid");
+}
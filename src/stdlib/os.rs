@@ -0,0 +1,40 @@
+//! The operating system library, which is stored in the `os` global
+
+use std::io::{self, Write};
+use std::process;
+
+use error::Result;
+use gc;
+use table::Table;
+use value::{ConvertValue, MultiValue, Type, Value};
+
+use super::{arg, check_integer, register};
+
+/// Register the os library into `globals`
+pub fn open(globals: &Table) {
+    let os = Table::new();
+    register(&os, "exit", exit);
+    globals
+        .set(Value::string("os"), os.into_value())
+        .expect("string keys are always valid");
+}
+
+/// `os.exit([code [, close]])`, which ends the process with `code`, where
+/// true (the default) means success and false failure
+///
+/// If `close` is true, every value with a `__gc` metamethod is finalized
+/// first, as closing the state would.
+fn exit(args: &[Value]) -> Result<MultiValue> {
+    let code = match arg(args, 1).type_of() {
+        Type::Nil => 0,
+        Type::Boolean if arg(args, 1).to_bool() => 0,
+        Type::Boolean => 1,
+        _ => check_integer(args, 1, "exit")? as i32,
+    };
+    if arg(args, 2).to_bool() {
+        // as when a state is dropped, errors in finalizers are dropped
+        let _ = gc::finalize_all();
+    }
+    let _ = io::stdout().flush();
+    process::exit(code)
+}
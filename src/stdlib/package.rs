@@ -0,0 +1,148 @@
+//! The package library, which is `require` and the `package` global it
+//! keeps its state in
+//!
+//! Modules are looked for in `package.preload`, then on `package.path`,
+//! whose templates have `?` replaced by the module name with dots turned
+//! into slashes, as `looa deps` and `looa watch` find them.
+
+use std::cell::Cell;
+use std::fs;
+use std::rc::Rc;
+
+use error::{Error, Result};
+use graph::{self, Graph};
+use limits::Limits;
+use lua::{self, LoadOptions};
+use table::Table;
+use value::{ConvertValue, LuaString, MultiValue, StringTable, Type, Value, WeakValue};
+
+use super::check_arg;
+
+/// Register `require` and the `package` table into `globals`, which is the
+/// table in `env`, with modules compiled with whatever `options` hold at
+/// the time, held to `limits` and interning strings in `strings`
+pub fn open(
+    globals: &Table,
+    env: &Value,
+    options: &Rc<Cell<LoadOptions>>,
+    limits: &Rc<Limits>,
+    strings: &Rc<StringTable>,
+) {
+    let package = Table::new();
+    package
+        .set(Value::string("path"), Value::string(graph::DEFAULT_PATH))
+        .expect("string keys are always valid");
+    // the libraries opened so far are already loaded
+    let loaded = Table::new();
+    for name in &["buffer", "coroutine", "debug", "os", "string", "table"] {
+        let lib = globals.get(&Value::string(name));
+        if !lib.is_nil() {
+            loaded
+                .set(Value::string(name), lib)
+                .expect("string keys are always valid");
+        }
+    }
+    package
+        .set(Value::string("loaded"), loaded.into_value())
+        .expect("string keys are always valid");
+    package
+        .set(Value::string("preload"), Table::new().into_value())
+        .expect("string keys are always valid");
+    let package = package.into_value();
+    globals
+        .set(Value::string("package"), package.clone())
+        .expect("string keys are always valid");
+    // a strong reference would keep the globals alive from inside them
+    let env = env.downgrade().expect("tables can be collected");
+    let options = options.clone();
+    let limits = limits.clone();
+    let strings = strings.clone();
+    globals
+        .set(
+            Value::string("require"),
+            Value::function(move |args| {
+                require(args, &package, options.get(), &limits, &strings, &env)
+            }),
+        )
+        .expect("string keys are always valid");
+}
+
+/// `require(name)`, which loads the module `name` the first time it is
+/// required, giving what it returns (or true if that is nil) and where it
+/// was found
+///
+/// The module's value is kept in `package.loaded`, which later calls give
+/// without loading it again.
+fn require(
+    args: &[Value],
+    package: &Value,
+    options: LoadOptions,
+    limits: &Rc<Limits>,
+    strings: &Rc<StringTable>,
+    globals: &WeakValue,
+) -> Result<MultiValue> {
+    let name = check_arg(args, 1, "require", Type::String)?;
+    let loaded = field(package, "loaded")?;
+    let module = loaded.raw_get(&name);
+    if !module.is_nil() {
+        return Ok(module.into());
+    }
+    let text = String::from_utf8_lossy(LuaString::from_value(&name).expect("checked type"));
+    let (loader, place) = match field(package, "preload")?.raw_get(&name) {
+        loader if !loader.is_nil() => (loader, Value::string(":preload:")),
+        _ => {
+            let path = package.raw_get(&Value::string("path"));
+            let path = match LuaString::from_value(&path) {
+                Some(path) => String::from_utf8_lossy(path).into_owned(),
+                None => {
+                    return Err(Error::Runtime(
+                        "'package.path' must be a string".to_string(),
+                    ))
+                }
+            };
+            let file = match Graph::new(&path).resolve(&text) {
+                Some(file) => file.to_string_lossy().into_owned(),
+                None => return Err(Error::Runtime(not_found(&text, &path))),
+            };
+            let file = file.strip_prefix("./").unwrap_or(&file).to_string();
+            let source = fs::read(&file).map_err(|err| {
+                Error::Runtime(format!("error loading module '{}': {}", text, err))
+            })?;
+            let env = globals.upgrade().unwrap_or_else(Value::nil);
+            let loader = lua::load_chunk(&source, &file, options, limits, strings, env)?;
+            (loader, Value::string(file))
+        }
+    };
+    let module = loader.call(vec![name.clone(), place.clone()])?.into_first();
+    // a module can set its own entry while it loads
+    if !module.is_nil() {
+        loaded.raw_set(name.clone(), module)?;
+    }
+    if loaded.raw_get(&name).is_nil() {
+        loaded.raw_set(name.clone(), Value::new(true))?;
+    }
+    Ok(MultiValue::from(vec![loaded.raw_get(&name), place]))
+}
+
+/// The table in `package[key]`, failing if a script replaced it
+fn field(package: &Value, key: &str) -> Result<Value> {
+    match package.raw_get(&Value::string(key)) {
+        val if val.type_of() == Type::Table => Ok(val),
+        _ => Err(Error::Runtime(format!("'package.{}' must be a table", key))),
+    }
+}
+
+/// The error for the module `name` being on no path `path` gives
+fn not_found(name: &str, path: &str) -> String {
+    let base = name.replace('.', "/");
+    let mut msg = format!(
+        "module '{}' not found:\n\tno field package.preload['{}']",
+        name, name
+    );
+    for template in path.split(';') {
+        if template.contains('?') {
+            msg += &format!("\n\tno file '{}'", template.replace('?', &base));
+        }
+    }
+    msg
+}
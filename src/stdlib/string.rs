@@ -0,0 +1,31 @@
+//! The string library, which is stored in the `string` global
+
+use error::{Error, Result};
+use table::Table;
+use value::{ConvertValue, Type, Value};
+use vm;
+
+use super::{arg, check_arg, register};
+
+/// Register the string library into `globals`
+pub fn open(globals: &Table) {
+    let string = Table::new();
+    register(&string, "dump", dump);
+    globals
+        .set(Value::string("string"), string.into_value())
+        .expect("string keys are always valid");
+}
+
+/// `string.dump(f [, strip])`, which saves a Lua function as a binary
+/// chunk that `load` accepts, compiling it first if the interpreter runs it
+fn dump(args: &[Value]) -> Result<Value> {
+    let func = check_arg(args, 1, "dump", Type::Function)?;
+    let strip = arg(args, 2).to_bool();
+    let compiled = func
+        .as_compiled()
+        .or_else(|| func.as_interpreted()?.compiled());
+    match compiled {
+        Some(closure) => Ok(Value::string(vm::dump(closure.proto(), strip))),
+        None => Err(Error::Runtime("unable to dump given function".to_string())),
+    }
+}
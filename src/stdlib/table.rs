@@ -0,0 +1,58 @@
+//! The table library, which is stored in the `table` global
+
+use error::{Error, Result};
+use table::Table;
+use value::{ConvertValue, LuaInteger, MultiValue, Value};
+
+use super::{arg, check_integer, register};
+
+/// The most values `table.unpack` gives, which is as many as Lua's stack
+/// can hold
+const MAX_UNPACK: u64 = 1_000_000;
+
+/// Register the table library into `globals`
+pub fn open(globals: &Table) {
+    let table = Table::new();
+    register(&table, "pack", pack);
+    register(&table, "unpack", unpack);
+    globals
+        .set(Value::string("table"), table.into_value())
+        .expect("string keys are always valid");
+}
+
+/// `table.pack(...)`, which gives a list of the arguments with their count
+/// in the field `n`, as the length would not count trailing nils
+fn pack(args: &[Value]) -> Result<Value> {
+    let table = Table::new();
+    for (i, val) in args.iter().enumerate() {
+        table.set((i as LuaInteger + 1).into_value(), val.clone())?;
+    }
+    table.set(Value::string("n"), (args.len() as LuaInteger).into_value())?;
+    Ok(table.into_value())
+}
+
+/// `table.unpack(list [, i [, j]])`, which gives `list[i]` to `list[j]`,
+/// from 1 to the length of `list` by default
+pub fn unpack(args: &[Value]) -> Result<MultiValue> {
+    let list = arg(args, 1);
+    let first = match arg(args, 2) {
+        ref i if i.is_nil() => 1,
+        _ => check_integer(args, 2, "unpack")?,
+    };
+    let last = match arg(args, 3) {
+        ref j if j.is_nil() => list
+            .len()?
+            .as_integer()
+            .ok_or_else(|| Error::Runtime("object length is not an integer".to_string()))?,
+        _ => check_integer(args, 3, "unpack")?,
+    };
+    if first > last {
+        return Ok(MultiValue::new());
+    }
+    if last.wrapping_sub(first) as u64 >= MAX_UNPACK {
+        return Err(Error::Runtime("too many results to unpack".to_string()));
+    }
+    (first..=last)
+        .map(|i| list.get_index(&i.into_value()))
+        .collect()
+}
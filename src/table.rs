@@ -0,0 +1,580 @@
+use std::cell::{Cell, RefCell};
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::rc::Rc;
+
+use error::{Error, Result};
+use gc::Edge;
+use limits::{self, Account, Kind};
+use value::{ConvertValue, Event, LuaInteger, LuaString, Type, Value, WeakValue};
+
+/// The most entries a part of a dropped table can have room for to be kept
+/// for the state's next tables, which are mostly small when they come and
+/// go in a loop
+const POOLED_CAPACITY: usize = 16;
+
+/// A key or value in a table, held weakly if the table's mode asks for it
+#[derive(Clone)]
+pub(crate) enum Slot {
+    Strong(Value),
+    Weak(WeakValue),
+}
+impl Slot {
+    /// Wrap `val`, weakly if `weak` is set and it is a collectable type
+    fn new(val: Value, weak: bool) -> Slot {
+        match val.downgrade() {
+            Some(weak_val) if weak => Slot::Weak(weak_val),
+            _ => Slot::Strong(val),
+        }
+    }
+    /// The value, unless it has been collected
+    fn get(&self) -> Option<Value> {
+        match *self {
+            Slot::Strong(ref val) => Some(val.clone()),
+            Slot::Weak(ref weak) => weak.upgrade(),
+        }
+    }
+    /// The value, unless it is nil or has been collected
+    fn live(&self) -> Option<Value> {
+        self.get().filter(|val| !val.is_nil())
+    }
+    /// The type and address that identify a collectable value
+    fn identity(&self) -> Option<(Type, usize)> {
+        match *self {
+            Slot::Strong(ref val) if val.is_collectable() => Some((val.type_of(), val.addr())),
+            Slot::Strong(_) => None,
+            Slot::Weak(ref weak) => Some((weak.type_of(), weak.addr())),
+        }
+    }
+}
+impl PartialEq for Slot {
+    fn eq(&self, other: &Slot) -> bool {
+        match (self, other) {
+            (Slot::Strong(a), Slot::Strong(b)) => a == b,
+            // weak slots only hold collectable values, which compare by identity
+            _ => self.identity().is_some() && self.identity() == other.identity(),
+        }
+    }
+}
+impl Eq for Slot {}
+impl Hash for Slot {
+    fn hash<H>(&self, state: &mut H)
+    where
+        H: Hasher,
+    {
+        match *self {
+            Slot::Strong(ref val) => val.hash(state),
+            // the same as `Value` hashes reference types
+            Slot::Weak(ref weak) => {
+                weak.type_of().hash(state);
+                weak.addr().hash(state);
+            }
+        }
+    }
+}
+
+/// The hash part of a table, which keeps its entries in the order they were
+/// added so that `next` can carry on from any key without a search
+///
+/// As in reference Lua, removing an entry leaves its key behind with a nil
+/// value, so a traversal that clears fields can still continue from them.
+/// Dead entries are dropped once they make up half of the part and a new
+/// key is added, which a traversal doesn't do.
+///
+/// Keys hash with the keys of the state that created the table, or random
+/// ones for a table made outside any state.
+#[derive(Default)]
+pub(crate) struct HashPart {
+    /// where each key is in `entries`
+    index: HashMap<Slot, usize>,
+    entries: Vec<(Slot, Slot)>,
+    /// how many entries have been removed since the part was last compacted
+    dead: usize,
+}
+impl HashPart {
+    fn with_capacity(capacity: usize, hasher: RandomState) -> HashPart {
+        HashPart {
+            index: HashMap::with_capacity_and_hasher(capacity, hasher),
+            entries: Vec::with_capacity(capacity),
+            dead: 0,
+        }
+    }
+    fn get(&self, key: &Slot) -> Option<Value> {
+        self.index.get(key).and_then(|&i| self.entries[i].1.get())
+    }
+    /// Store `val` under `key`, giving whether the key is new
+    fn insert(&mut self, key: Slot, val: Slot) -> bool {
+        if let Some(&i) = self.index.get(&key) {
+            if self.entries[i].1.live().is_none() {
+                self.dead -= 1;
+            }
+            self.entries[i] = (key, val);
+            return false;
+        }
+        if self.dead > self.entries.len() / 2 {
+            self.compact();
+        }
+        self.index.insert(key.clone(), self.entries.len());
+        self.entries.push((key, val));
+        true
+    }
+    /// Remove the entry for `key`, giving its value unless it had none
+    fn remove(&mut self, key: &Slot) -> Option<Slot> {
+        let &i = self.index.get(key)?;
+        let val = mem::replace(&mut self.entries[i].1, Slot::Strong(Value::nil()));
+        val.live()?;
+        self.dead += 1;
+        Some(val)
+    }
+    /// An empty part hashing keys the same way
+    fn emptied(&self) -> HashPart {
+        HashPart::with_capacity(0, self.index.hasher().clone())
+    }
+    /// How many entries there are, counting dead ones
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+    /// Roughly how many bytes the entries and the index take up
+    fn size(&self) -> usize {
+        self.entries.capacity() * mem::size_of::<(Slot, Slot)>()
+            + self.index.capacity() * mem::size_of::<(Slot, usize)>()
+    }
+    /// Drop the entries that were removed or whose key or value was collected
+    fn compact(&mut self) {
+        self.entries
+            .retain(|(key, val)| key.get().is_some() && val.live().is_some());
+        // rebuilt in place, to keep hashing with the same keys
+        self.index.clear();
+        self.index.extend(
+            self.entries
+                .iter()
+                .enumerate()
+                .map(|(i, (key, _))| (key.clone(), i)),
+        );
+        // give back the room of a part that mostly emptied
+        if self.entries.capacity() > 4 * self.entries.len() {
+            self.entries.shrink_to(2 * self.entries.len());
+            self.index.shrink_to(2 * self.entries.len());
+        }
+        self.dead = 0;
+    }
+}
+
+/// Which parts of a table are weak, from the `__mode` metafield
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+struct Mode {
+    weak_keys: bool,
+    weak_values: bool,
+}
+impl Mode {
+    fn of(metatable: Option<&Value>) -> Mode {
+        let mode = metatable.map(|mt| mt.raw_get(&Value::string("__mode")));
+        match mode.as_ref().and_then(LuaString::from_value) {
+            Some(mode) => Mode {
+                weak_keys: mode.contains(&b'k'),
+                weak_values: mode.contains(&b'v'),
+            },
+            None => Mode::default(),
+        }
+    }
+    fn is_weak(self) -> bool {
+        self.weak_keys || self.weak_values
+    }
+}
+
+/// The array part index for `key`, if it is a number with a positive integer
+/// value
+fn array_index(key: &Value) -> Option<usize> {
+    match key.as_integer() {
+        Some(i) if i >= 1 && key.type_of() == Type::Number => usize::try_from(i - 1).ok(),
+        _ => None,
+    }
+}
+
+/// A Lua table, which can be mutated through shared references since it is
+/// shared between every value that refers to it
+///
+/// Like reference Lua, values under the keys `1..=n` live in an array part
+/// and everything else in a hash part. The array grows as a sequence is
+/// appended to and absorbs any following keys from the hash part, so the
+/// hash part never holds the key just past the end of the array.
+///
+/// A table whose metatable has a `__mode` field containing `k` and/or `v`
+/// holds its keys and/or values weakly, so entries disappear once their
+/// table, function or userdata has been collected. The mode is read when
+/// the metatable is set. The value of a weak key is held strongly whatever
+/// the key, so one that refers back to its own key keeps it alive.
+#[derive(Default)]
+pub struct Table {
+    /// values for the keys `1..=array.len()`, where nil marks a hole
+    array: RefCell<Vec<Slot>>,
+    hash: RefCell<HashPart>,
+    metatable: RefCell<Option<Value>>,
+    /// set when given a metatable with `__gc`, so the finalizer runs on drop
+    finalize: Cell<bool>,
+    mode: Cell<Mode>,
+    /// how many entries a weak table had when dead ones were last removed
+    pruned_len: Cell<usize>,
+    /// how many bytes the entries were counted as taking up
+    size: Cell<usize>,
+    /// the account of the state they are charged to
+    owner: Option<Rc<Account>>,
+    /// a bit for each `Event` found to be missing when this is used as a
+    /// metatable, which are cleared whenever it is assigned to
+    absent: Cell<u32>,
+}
+impl Table {
+    pub fn new() -> Table {
+        let owner = limits::charged();
+        let hasher = owner
+            .as_ref()
+            .map_or_else(RandomState::new, |owner| owner.hasher().clone());
+        Table {
+            array: RefCell::default(),
+            hash: RefCell::new(HashPart::with_capacity(0, hasher)),
+            metatable: RefCell::default(),
+            finalize: Cell::default(),
+            mode: Cell::default(),
+            pruned_len: Cell::default(),
+            size: Cell::default(),
+            owner,
+            absent: Cell::default(),
+        }
+    }
+    /// Create a table with room for `narr` items in the sequence `1..=narr`
+    /// and `nrec` other entries, so filling it in doesn't grow it
+    pub fn with_capacity(narr: usize, nrec: usize) -> Table {
+        let table = Table::new();
+        {
+            let mut array = table.array.borrow_mut();
+            if narr > 0 {
+                table.reuse_array(&mut array);
+            }
+            array.reserve_exact(narr);
+            let mut hash = table.hash.borrow_mut();
+            if nrec > 0 {
+                table.reuse_hash(&mut hash);
+            }
+            hash.index.reserve(nrec);
+            hash.entries.reserve(nrec);
+        }
+        table.resize(table.array.borrow().capacity(), &table.hash.borrow());
+        table
+    }
+    /// Make room for `n` more items at the end of the sequence
+    pub(crate) fn reserve(&self, n: usize) {
+        let mut array = self.array.borrow_mut();
+        array.reserve(n);
+        self.resize(array.capacity(), &self.hash.borrow());
+    }
+    /// Count the memory that the entries take up since the parts grew or
+    /// were rebuilt
+    fn resize(&self, array_capacity: usize, hash: &HashPart) {
+        let size = array_capacity * mem::size_of::<Slot>() + hash.size();
+        let old = self.size.replace(size);
+        if let Some(ref owner) = self.owner {
+            if size != old {
+                owner.free(Kind::Tables, old);
+                owner.allocate(Kind::Tables, size);
+            }
+        }
+    }
+    /// Take over the room a dropped table of the same state left for its
+    /// sequence, if the state kept it
+    fn reuse_array(&self, array: &mut Vec<Slot>) {
+        if let Some(spare) = self.owner.as_ref().and_then(|owner| owner.arrays.take()) {
+            *array = spare;
+        }
+    }
+    /// Take over the room a dropped table of the same state left for its
+    /// other entries, which hashes the same way, if the state kept it
+    fn reuse_hash(&self, hash: &mut HashPart) {
+        if let Some(spare) = self.owner.as_ref().and_then(|owner| owner.hashes.take()) {
+            *hash = spare;
+        }
+    }
+    /// Get the value stored under `key`, without invoking metamethods
+    pub fn get(&self, key: &Value) -> Value {
+        let array = self.array.borrow();
+        let slot = match array_index(key) {
+            Some(i) if i < array.len() => array[i].get(),
+            _ => self.hash.borrow().get(&Slot::Strong(key.clone())),
+        };
+        slot.unwrap_or_else(Value::nil)
+    }
+    /// The handler for `event`, when this is used as a metatable
+    pub(crate) fn metamethod(&self, event: Event) -> Option<Value> {
+        let bit = 1 << event as u32;
+        if self.absent.get() & bit != 0 {
+            return None;
+        }
+        let handler = self.get(&event.key());
+        if handler.is_nil() {
+            self.absent.set(self.absent.get() | bit);
+            return None;
+        }
+        Some(handler)
+    }
+    /// Store `val` under `key` without invoking metamethods, removing the
+    /// entry if `val` is nil
+    pub fn set(&self, key: Value, val: Value) -> Result<()> {
+        self.absent.set(0);
+        if !key.is_index() {
+            let msg = if key.type_of() == Type::Nil {
+                "index is nil"
+            } else {
+                "index is NaN"
+            };
+            return Err(Error::Runtime(msg.to_string()));
+        }
+        // floats with an integer value are stored under that integer
+        let key = match key.as_integer() {
+            Some(i) if key.type_of() == Type::Number => i.into_value(),
+            _ => key,
+        };
+        let mode = self.mode.get();
+        let mut array = self.array.borrow_mut();
+        let mut hash = self.hash.borrow_mut();
+        match array_index(&key) {
+            Some(i) if i < array.len() => {
+                array[i] = Slot::new(val, mode.weak_values);
+                // keep the last element of the array non-nil
+                while array
+                    .last()
+                    .is_some_and(|slot| slot.get().is_none_or(|val| val.is_nil()))
+                {
+                    array.pop();
+                }
+            }
+            Some(i) if i == array.len() && !val.is_nil() => {
+                if array.capacity() == 0 {
+                    self.reuse_array(&mut array);
+                }
+                array.push(Slot::new(val, mode.weak_values));
+                // move the rest of the sequence over from the hash part
+                let mut next = (array.len() as LuaInteger + 1).into_value();
+                let moved = array.len();
+                while let Some(slot) = hash.remove(&Slot::Strong(next.clone())) {
+                    array.push(slot);
+                    next = (array.len() as LuaInteger + 1).into_value();
+                }
+                // rather than keeping the moved entries' room until a new
+                // key is added
+                if array.len() > moved && hash.dead > hash.len() / 2 {
+                    hash.compact();
+                }
+                self.resize(array.capacity(), &hash);
+            }
+            _ if val.is_nil() => {
+                hash.remove(&Slot::Strong(key));
+            }
+            _ => {
+                if hash.entries.capacity() == 0 {
+                    self.reuse_hash(&mut hash);
+                }
+                let added = hash.insert(
+                    Slot::new(key, mode.weak_keys),
+                    Slot::new(val, mode.weak_values),
+                );
+                // dead entries are removed whenever a weak table doubles in size
+                if added && mode.is_weak() && hash.len() > 2 * self.pruned_len.get().max(8) {
+                    hash.compact();
+                    self.pruned_len.set(hash.len());
+                }
+                if added {
+                    self.resize(array.capacity(), &hash);
+                }
+            }
+        }
+        Ok(())
+    }
+    /// Find a border: an index `n` where `t[n]` is non-nil and `t[n + 1]` is
+    /// nil, or 0 if `t[1]` is nil, without invoking metamethods
+    ///
+    /// When the table has holes any border may be returned.
+    pub fn len(&self) -> LuaInteger {
+        // the last array element is non-nil unless it was weak and collected,
+        // and the next key is never in the hash part
+        {
+            let array = self.array.borrow();
+            match array.last() {
+                None => return 0,
+                Some(slot) if slot.get().is_some() => return array.len() as LuaInteger,
+                _ => (),
+            }
+        }
+        let has = |i: LuaInteger| !self.get(&i.into_value()).is_nil();
+        if !has(1) {
+            return 0;
+        }
+        // find some nil index past a non-nil one, then binary search between
+        let (mut lo, mut hi) = (1, 2);
+        while has(hi) {
+            lo = hi;
+            match hi.checked_mul(2) {
+                Some(next) => hi = next,
+                None => {
+                    // pathological table; fall back to a linear scan
+                    let mut n = 1;
+                    while n < LuaInteger::MAX && has(n + 1) {
+                        n += 1;
+                    }
+                    return n;
+                }
+            }
+        }
+        while hi - lo > 1 {
+            let mid = lo + (hi - lo) / 2;
+            if has(mid) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+    pub fn is_empty(&self) -> bool {
+        !self.array.borrow().iter().any(|slot| slot.live().is_some())
+            && !self
+                .hash
+                .borrow()
+                .entries
+                .iter()
+                .any(|(key, val)| key.get().is_some() && val.live().is_some())
+    }
+    /// The entry after `key` in the order `next` traverses the table, or
+    /// the first one if `key` is nil, without invoking metamethods
+    ///
+    /// The array part comes first, then the hash part in the order its keys
+    /// were added. Fields may be assigned or cleared during a traversal.
+    pub fn next(&self, key: &Value) -> Result<Option<(Value, Value)>> {
+        let array = self.array.borrow();
+        let hash = self.hash.borrow();
+        let (array_start, hash_start) = if key.is_nil() {
+            (0, 0)
+        } else {
+            match (array_index(key), hash.index.get(&Slot::Strong(key.clone()))) {
+                (Some(i), _) if i < array.len() => (i + 1, 0),
+                (_, Some(&i)) => (array.len(), i + 1),
+                // the end of the array was cleared during the traversal
+                (Some(_), None) => (array.len(), 0),
+                (None, None) => return Err(Error::Runtime("invalid key to 'next'".to_string())),
+            }
+        };
+        for (i, slot) in array.iter().enumerate().skip(array_start) {
+            if let Some(val) = slot.live() {
+                return Ok(Some(((i as LuaInteger + 1).into_value(), val)));
+            }
+        }
+        for (key, val) in &hash.entries[hash_start..] {
+            if let (Some(key), Some(val)) = (key.get(), val.live()) {
+                return Ok(Some((key, val)));
+            }
+        }
+        Ok(None)
+    }
+    pub fn metatable(&self) -> Option<Value> {
+        self.metatable.borrow().clone()
+    }
+    /// Whether this is to be finalized when collected
+    pub(crate) fn finalizes(&self) -> bool {
+        self.finalize.get()
+    }
+    /// Pass each value this holds strongly to `visit`, which counts the
+    /// keys twice since the index has copies of them
+    pub(crate) fn trace(&self, visit: &mut dyn FnMut(Edge)) {
+        let (array, hash, metatable) = match (
+            self.array.try_borrow(),
+            self.hash.try_borrow(),
+            self.metatable.try_borrow(),
+        ) {
+            (Ok(array), Ok(hash), Ok(metatable)) => (array, hash, metatable),
+            // what a table in the middle of being changed holds is taken to
+            // be in use
+            _ => return,
+        };
+        let keys = hash
+            .index
+            .keys()
+            .chain(hash.entries.iter().map(|(key, _)| key));
+        let slots = array
+            .iter()
+            .chain(keys)
+            .chain(hash.entries.iter().map(|(_, val)| val));
+        for slot in slots {
+            if let Slot::Strong(ref val) = *slot {
+                visit(Edge::Value(val));
+            }
+        }
+        if let Some(ref mt) = *metatable {
+            visit(Edge::Value(mt));
+        }
+    }
+    /// Stop this from being finalized when collected, giving whether it
+    /// would have been
+    pub(crate) fn take_finalizer(&self) -> bool {
+        self.finalize.replace(false)
+    }
+    /// Replace the metatable, which must be a table or `None`
+    pub fn set_metatable(&self, metatable: Option<Value>) {
+        debug_assert!(metatable
+            .as_ref()
+            .is_none_or(|mt| Table::from_value(mt).is_some()));
+        // like Lua, only a `__gc` present when the metatable is set counts
+        if let Some(ref mt) = metatable {
+            if !mt.raw_get(&Value::string("__gc")).is_nil() {
+                self.finalize.set(true);
+            }
+        }
+        let mode = Mode::of(metatable.as_ref());
+        *self.metatable.borrow_mut() = metatable;
+        if mode != self.mode.get() {
+            self.mode.set(mode);
+            self.absent.set(0);
+            let mut array = self.array.borrow_mut();
+            for slot in array.iter_mut() {
+                let val = slot.get().unwrap_or_else(Value::nil);
+                *slot = Slot::new(val, mode.weak_values);
+            }
+            let mut hash = self.hash.borrow_mut();
+            let emptied = hash.emptied();
+            for (key, val) in mem::replace(&mut *hash, emptied).entries {
+                if let (Some(key), Some(val)) = (key.get(), val.live()) {
+                    hash.insert(
+                        Slot::new(key, mode.weak_keys),
+                        Slot::new(val, mode.weak_values),
+                    );
+                }
+            }
+            self.pruned_len.set(hash.len());
+            self.resize(array.capacity(), &hash);
+        }
+    }
+}
+impl Drop for Table {
+    fn drop(&mut self) {
+        if let Some(owner) = self.owner.take() {
+            owner.free(Kind::Tables, self.size.get());
+            // keep the room of a small table for the state's next ones,
+            // emptied first as that can drop other tables
+            let array = self.array.get_mut();
+            if (1..=POOLED_CAPACITY).contains(&array.capacity()) && owner.arrays.has_room() {
+                let mut array = mem::take(array);
+                array.clear();
+                owner.arrays.give(array);
+            }
+            let hash = self.hash.get_mut();
+            if (1..=POOLED_CAPACITY).contains(&hash.entries.capacity()) && owner.hashes.has_room() {
+                let emptied = hash.emptied();
+                let mut hash = mem::replace(hash, emptied);
+                hash.index.clear();
+                hash.entries.clear();
+                hash.dead = 0;
+                owner.hashes.give(hash);
+            }
+        }
+    }
+}
@@ -0,0 +1,748 @@
+//! Running suites of Lua tests written in the style of busted, each file
+//! in a state of its own, several files at once
+//!
+//! A test file describes its tests with these globals:
+//!
+//! - `describe(name, f)` runs `f`, naming the tests in it after `name`
+//! - `it(name, f)` runs `f` as a test, which passes unless it raises an
+//!   error
+//! - `pending(name)` notes a test that is yet to be written
+//! - `before_each(f)` and `after_each(f)` run `f` around each test after
+//!   them in the same `describe`, including those in nested ones
+//!
+//! `assert` can still be called as usual, and also has fields that raise
+//! an error saying what was wrong: `assert.equal(expected, actual)`,
+//! `assert.same(expected, actual)`, which compares tables by their
+//! contents, `assert.near(expected, actual, tolerance)`,
+//! `assert.truthy(v)`, `assert.falsy(v)`, `assert.is_nil(v)`,
+//! `assert.not_nil(v)` and `assert.has_error(f [, text])`, which also
+//! checks the error message contains `text`.
+
+use std::cell::RefCell;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use error::{Error, Result};
+use lua::{Backend, Lua};
+use table::Table;
+use trace;
+use value::{ConvertValue, LuaString, MultiValue, Type, Value};
+
+/// The stack each file runs on, which deeply recursive tests need more of
+/// than threads get by default
+const STACK_SIZE: usize = 16 << 20;
+
+/// How a test went
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+    Passed,
+    /// with the error it raised
+    Failed(String),
+    Pending,
+}
+
+/// A test, named after the `describe`s it is in and then itself, with
+/// spaces between
+#[derive(Clone, Debug)]
+pub struct TestResult {
+    pub name: String,
+    pub outcome: Outcome,
+    pub duration: Duration,
+}
+
+/// The tests of a file
+#[derive(Clone, Debug)]
+pub struct FileResult {
+    pub path: PathBuf,
+    pub tests: Vec<TestResult>,
+    /// the error that stopped the file outside any test, such as a syntax
+    /// error, after which no more of its tests ran
+    pub error: Option<String>,
+    pub duration: Duration,
+}
+impl FileResult {
+    pub fn failed(&self) -> usize {
+        self.count(|outcome| matches!(outcome, Outcome::Failed(_)))
+    }
+    pub fn passed(&self) -> usize {
+        self.count(|outcome| *outcome == Outcome::Passed)
+    }
+    pub fn pending(&self) -> usize {
+        self.count(|outcome| *outcome == Outcome::Pending)
+    }
+    /// Whether the file ran and none of its tests failed
+    pub fn ok(&self) -> bool {
+        self.error.is_none() && self.failed() == 0
+    }
+    fn count<F>(&self, matches: F) -> usize
+    where
+        F: Fn(&Outcome) -> bool,
+    {
+        self.tests
+            .iter()
+            .filter(|test| matches(&test.outcome))
+            .count()
+    }
+}
+
+/// What is done to each state before a file runs in it
+pub type Setup = Arc<dyn Fn(&mut Lua) + Send + Sync>;
+
+/// Runs test files, with how many at once and in which states
+pub struct Runner {
+    jobs: usize,
+    backend: Backend,
+    filter: Option<String>,
+    setup: Option<Setup>,
+}
+impl Runner {
+    /// A runner of as many files at once as this machine has processors,
+    /// in states that interpret them
+    pub fn new() -> Runner {
+        Runner {
+            jobs: thread::available_parallelism().map_or(1, |n| n.get()),
+            backend: Backend::Interpreter,
+            filter: None,
+            setup: None,
+        }
+    }
+    pub fn jobs(&self) -> usize {
+        self.jobs
+    }
+    /// Run at most `jobs` files at once, where 0 means 1
+    pub fn set_jobs(&mut self, jobs: usize) {
+        self.jobs = jobs.max(1);
+    }
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+    }
+    /// Only run the tests whose names contain `filter`, skipping the
+    /// others as if they weren't there
+    pub fn set_filter(&mut self, filter: Option<String>) {
+        self.filter = filter;
+    }
+    /// Call `setup` on each state, on the thread that runs it, before the
+    /// file runs in it, such as to set a hook
+    pub fn set_setup(&mut self, setup: Option<Setup>) {
+        self.setup = setup;
+    }
+
+    /// Run each of `files` in a state of its own, giving their results in
+    /// the same order
+    pub fn run(&self, files: &[PathBuf]) -> Vec<FileResult> {
+        let next = Arc::new(Mutex::new(0));
+        let files = Arc::new(files.to_vec());
+        let (send, receive) = mpsc::channel();
+        let workers: Vec<_> = (0..self.jobs.min(files.len()))
+            .map(|_| {
+                let (next, files, send) = (next.clone(), files.clone(), send.clone());
+                let (backend, filter) = (self.backend, self.filter.clone());
+                let setup = self.setup.clone();
+                thread::Builder::new()
+                    .stack_size(STACK_SIZE)
+                    .spawn(move || loop {
+                        let i = {
+                            let mut next = next.lock().expect("no worker panics holding it");
+                            *next += 1;
+                            *next - 1
+                        };
+                        let path = match files.get(i) {
+                            Some(path) => path,
+                            None => return,
+                        };
+                        let result = run_file(path, backend, filter.as_deref(), setup.as_ref());
+                        if send.send((i, result)).is_err() {
+                            return;
+                        }
+                    })
+                    .expect("threads can be spawned")
+            })
+            .collect();
+        drop(send);
+        let mut results: Vec<Option<FileResult>> = vec![None; files.len()];
+        for (i, result) in receive {
+            results[i] = Some(result);
+        }
+        for worker in workers {
+            let _ = worker.join();
+        }
+        results
+            .into_iter()
+            .zip(files.iter())
+            .map(|(result, path)| {
+                result.unwrap_or_else(|| FileResult {
+                    path: path.clone(),
+                    tests: Vec::new(),
+                    error: Some("the test runner panicked".to_string()),
+                    duration: Duration::default(),
+                })
+            })
+            .collect()
+    }
+}
+impl Default for Runner {
+    fn default() -> Runner {
+        Runner::new()
+    }
+}
+
+/// The test files in `dir` and the directories in it, which are those
+/// whose names end with `_spec.lua` or `_test.lua`, in order of their paths
+///
+/// Directories whose names start with `.` are skipped.
+pub fn discover(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    find(dir, &mut files)?;
+    files.sort();
+    Ok(files)
+}
+fn find(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+        if path.is_dir() {
+            if !name.starts_with('.') {
+                find(&path, files)?;
+            }
+        } else if name.ends_with("_spec.lua") || name.ends_with("_test.lua") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// The tests run so far in a file, and the `describe` being run
+#[derive(Default)]
+struct Suite {
+    /// the names of the `describe`s being run, outermost first
+    names: Vec<String>,
+    /// the `before_each` and `after_each` functions of each of them, with
+    /// those of the file itself first
+    before: Vec<Vec<Value>>,
+    after: Vec<Vec<Value>>,
+    filter: Option<String>,
+    tests: Vec<TestResult>,
+}
+impl Suite {
+    fn name(&self, name: &str) -> String {
+        let mut names = self.names.clone();
+        names.push(name.to_string());
+        names.join(" ")
+    }
+}
+
+/// Run the tests in the file at `path`, in a new state
+fn run_file(
+    path: &Path,
+    backend: Backend,
+    filter: Option<&str>,
+    setup: Option<&Setup>,
+) -> FileResult {
+    let start = Instant::now();
+    let suite = Rc::new(RefCell::new(Suite {
+        before: vec![Vec::new()],
+        after: vec![Vec::new()],
+        filter: filter.map(str::to_string),
+        ..Suite::default()
+    }));
+    let error = {
+        let mut lua = Lua::new();
+        lua.set_backend(backend);
+        open(&mut lua, &suite);
+        if let Some(setup) = setup {
+            setup(&mut lua);
+        }
+        let name = path.to_string_lossy();
+        let result = fs::read(path)
+            .map_err(|err| Error::Runtime(format!("cannot read {}: {}", name, err)))
+            .map(|source| skip_shebang(&source))
+            .and_then(|source| lua.load(&source, &name))
+            .and_then(|main| main.call(Vec::new()));
+        result.err().map(|err| err.to_string())
+    };
+    let mut suite = suite.borrow_mut();
+    FileResult {
+        path: path.to_path_buf(),
+        tests: suite.tests.drain(..).collect(),
+        error,
+        duration: start.elapsed(),
+    }
+}
+
+/// `source` with a `#!` line blanked out, keeping its newline so that line
+/// numbers stay the same
+fn skip_shebang(source: &[u8]) -> Vec<u8> {
+    if !source.starts_with(b"#") {
+        return source.to_vec();
+    }
+    let end = source
+        .iter()
+        .position(|&c| c == b'\n')
+        .unwrap_or(source.len());
+    source[end..].to_vec()
+}
+
+/// Set the globals test files use in `lua`, recording into `suite`
+fn open(lua: &mut Lua, suite: &Rc<RefCell<Suite>>) {
+    let globals = lua.globals().clone();
+    let set = |name: &str, val: Value| {
+        globals
+            .raw_set(Value::string(name), val)
+            .expect("string keys are always valid");
+    };
+    let s = suite.clone();
+    set("describe", function(move |args| describe(&s, args)));
+    let s = suite.clone();
+    set("it", function(move |args| it(&s, args)));
+    let s = suite.clone();
+    set(
+        "pending",
+        function(move |args| {
+            let name = check_string(args, 1, "pending")?;
+            let mut suite = s.borrow_mut();
+            let name = suite.name(&name);
+            if selected(&suite, &name) {
+                suite.tests.push(TestResult {
+                    name,
+                    outcome: Outcome::Pending,
+                    duration: Duration::default(),
+                });
+            }
+            Ok(())
+        }),
+    );
+    let s = suite.clone();
+    set(
+        "before_each",
+        function(move |args| {
+            let f = check_function(args, 1, "before_each")?;
+            let mut suite = s.borrow_mut();
+            suite.before.last_mut().expect("the file's own").push(f);
+            Ok(())
+        }),
+    );
+    let s = suite.clone();
+    set(
+        "after_each",
+        function(move |args| {
+            let f = check_function(args, 1, "after_each")?;
+            let mut suite = s.borrow_mut();
+            suite.after.last_mut().expect("the file's own").push(f);
+            Ok(())
+        }),
+    );
+    set(
+        "assert",
+        assertions(globals.raw_get(&Value::string("assert"))),
+    );
+}
+
+/// A function value of `func`, which returns nothing
+fn function<F>(func: F) -> Value
+where
+    F: Fn(&[Value]) -> Result<()> + 'static,
+{
+    Value::function(move |args: &[Value]| func(args).map(|()| MultiValue::new()))
+}
+
+fn describe(suite: &Rc<RefCell<Suite>>, args: &[Value]) -> Result<()> {
+    let name = check_string(args, 1, "describe")?;
+    let body = check_function(args, 2, "describe")?;
+    {
+        let mut suite = suite.borrow_mut();
+        suite.names.push(name);
+        suite.before.push(Vec::new());
+        suite.after.push(Vec::new());
+    }
+    let result = body.call(Vec::new());
+    let mut suite = suite.borrow_mut();
+    let name = suite.names.join(" ");
+    suite.names.pop();
+    suite.before.pop();
+    suite.after.pop();
+    // an error outside the tests fails the `describe`, but not the file
+    if let Err(err) = result {
+        trace::catch();
+        suite.tests.push(TestResult {
+            name,
+            outcome: Outcome::Failed(err.to_string()),
+            duration: Duration::default(),
+        });
+    }
+    Ok(())
+}
+
+fn it(suite: &Rc<RefCell<Suite>>, args: &[Value]) -> Result<()> {
+    let name = check_string(args, 1, "it")?;
+    let body = check_function(args, 2, "it")?;
+    let (name, before, after) = {
+        let suite = suite.borrow();
+        let name = suite.name(&name);
+        if !selected(&suite, &name) {
+            return Ok(());
+        }
+        let before: Vec<Value> = suite.before.iter().flatten().cloned().collect();
+        // the innermost `after_each` runs first
+        let after: Vec<Value> = suite.after.iter().rev().flatten().cloned().collect();
+        (name, before, after)
+    };
+    let start = Instant::now();
+    let mut result = before
+        .iter()
+        .try_for_each(|f| f.call(Vec::new()).map(drop))
+        .and_then(|_| body.call(Vec::new()).map(drop));
+    for f in &after {
+        let ran = f.call(Vec::new()).map(drop);
+        result = result.and(ran);
+    }
+    let outcome = match result {
+        Ok(()) => Outcome::Passed,
+        Err(err) => {
+            trace::catch();
+            Outcome::Failed(err.to_string())
+        }
+    };
+    suite.borrow_mut().tests.push(TestResult {
+        name,
+        outcome,
+        duration: start.elapsed(),
+    });
+    Ok(())
+}
+
+/// Whether the test `name` is to run
+fn selected(suite: &Suite, name: &str) -> bool {
+    suite
+        .filter
+        .as_ref()
+        .is_none_or(|filter| name.contains(&**filter))
+}
+
+fn check_string(args: &[Value], n: usize, func: &str) -> Result<String> {
+    match args.get(n - 1).and_then(LuaString::from_value) {
+        Some(bytes) => Ok(String::from_utf8_lossy(bytes).into_owned()),
+        None => Err(bad_argument(args, n, func, "string")),
+    }
+}
+fn check_function(args: &[Value], n: usize, func: &str) -> Result<Value> {
+    match args.get(n - 1) {
+        Some(val) if val.type_of() == Type::Function => Ok(val.clone()),
+        _ => Err(bad_argument(args, n, func, "function")),
+    }
+}
+fn bad_argument(args: &[Value], n: usize, func: &str, expected: &str) -> Error {
+    let got = match args.get(n - 1) {
+        Some(val) => val.type_of().to_string(),
+        None => "no value".to_string(),
+    };
+    Error::Runtime(format!(
+        "bad argument #{} to '{}' ({} expected, got {})",
+        n, func, expected, got
+    ))
+}
+
+/// The `assert` table, which calls `assert`, the base library's, when it
+/// is called
+fn assertions(assert: Value) -> Value {
+    let table = Table::new();
+    let register = |name: &str, func: fn(&[Value]) -> Result<()>| {
+        table
+            .set(Value::string(name), function(func))
+            .expect("string keys are always valid");
+    };
+    register("equal", |args| {
+        let (expected, actual) = (arg(args, 1), arg(args, 2));
+        if expected == actual {
+            return Ok(());
+        }
+        fail(
+            args,
+            3,
+            format!("expected {:?}, got {:?}", expected, actual),
+        )
+    });
+    register("same", |args| {
+        let (expected, actual) = (arg(args, 1), arg(args, 2));
+        match difference(&expected, &actual, &mut Vec::new())? {
+            None => Ok(()),
+            Some(msg) => fail(args, 3, msg),
+        }
+    });
+    register("near", |args| {
+        let (expected, actual, tolerance) = (arg(args, 1), arg(args, 2), arg(args, 3));
+        for (i, val) in [&expected, &actual, &tolerance].iter().enumerate() {
+            if val.type_of() != Type::Number {
+                return Err(bad_argument(args, i + 1, "near", "number"));
+            }
+        }
+        if (expected.as_number() - actual.as_number()).abs() <= tolerance.as_number() {
+            return Ok(());
+        }
+        let msg = format!(
+            "expected {:?} within {:?}, got {:?}",
+            expected, tolerance, actual
+        );
+        fail(args, 4, msg)
+    });
+    register("truthy", |args| match arg(args, 1).to_bool() {
+        true => Ok(()),
+        false => fail(
+            args,
+            2,
+            format!("expected a truthy value, got {:?}", arg(args, 1)),
+        ),
+    });
+    register("falsy", |args| match arg(args, 1).to_bool() {
+        false => Ok(()),
+        true => fail(
+            args,
+            2,
+            format!("expected a falsy value, got {:?}", arg(args, 1)),
+        ),
+    });
+    register("is_nil", |args| match arg(args, 1).is_nil() {
+        true => Ok(()),
+        false => fail(args, 2, format!("expected nil, got {:?}", arg(args, 1))),
+    });
+    register("not_nil", |args| match arg(args, 1).is_nil() {
+        false => Ok(()),
+        true => fail(args, 2, "expected a value other than nil".to_string()),
+    });
+    register("has_error", |args| {
+        let f = check_function(args, 1, "has_error")?;
+        let err = match f.call(Vec::new()) {
+            Ok(_) => return fail(args, 3, "expected an error".to_string()),
+            Err(err) => {
+                trace::catch();
+                err.into_value()
+            }
+        };
+        let text = arg(args, 2);
+        if text.is_nil() || contains(&err, &text) {
+            return Ok(());
+        }
+        fail(
+            args,
+            3,
+            format!("expected an error containing {:?}, got {:?}", text, err),
+        )
+    });
+    let metatable = Table::new();
+    metatable
+        .set(
+            Value::string("__call"),
+            Value::function(move |args: &[Value]| -> Result<MultiValue> {
+                // the table itself comes first
+                assert.call(args[1..].to_vec())
+            }),
+        )
+        .expect("string keys are always valid");
+    let table = table.into_value();
+    table
+        .set_metatable(Some(metatable.into_value()))
+        .expect("new tables have no protected metatable");
+    table
+}
+
+fn arg(args: &[Value], n: usize) -> Value {
+    args.get(n - 1).cloned().unwrap_or_else(Value::nil)
+}
+
+/// Raise the error `msg`, or argument `n` instead if it was passed
+fn fail(args: &[Value], n: usize, msg: String) -> Result<()> {
+    match args.get(n - 1) {
+        Some(custom) if !custom.is_nil() => Err(Error::Runtime(custom.to_string())),
+        _ => Err(Error::Runtime(msg)),
+    }
+}
+
+/// Whether `err` is a string containing `text`
+fn contains(err: &Value, text: &Value) -> bool {
+    match (LuaString::from_value(err), LuaString::from_value(text)) {
+        (Some(err), Some(text)) => {
+            text.is_empty() || err.windows(text.len()).any(|window| window == &**text)
+        }
+        _ => false,
+    }
+}
+
+/// How `actual` differs from `expected`, comparing tables by their keys
+/// and values, where `path` is the keys the values are at
+fn difference(expected: &Value, actual: &Value, path: &mut Vec<Value>) -> Result<Option<String>> {
+    let at = |path: &[Value]| {
+        let mut at = String::new();
+        for key in path {
+            let _ = write!(at, "[{:?}]", key);
+        }
+        at
+    };
+    let tables = expected.type_of() == Type::Table && actual.type_of() == Type::Table;
+    if !tables || expected == actual {
+        if expected == actual {
+            return Ok(None);
+        }
+        let msg = match path.is_empty() {
+            true => format!("expected {:?}, got {:?}", expected, actual),
+            false => format!("expected {:?} at {}, got {:?}", expected, at(path), actual),
+        };
+        return Ok(Some(msg));
+    }
+    // tables containing themselves compare as far as the first repeat
+    if path.len() > 100 {
+        return Ok(None);
+    }
+    for (from, to, missing) in &[(expected, actual, false), (actual, expected, true)] {
+        let mut key = Value::nil();
+        while let Some((next, val)) = from.raw_next(&key)? {
+            key = next;
+            let other = to.raw_get(&key);
+            path.push(key.clone());
+            let diff = if *missing {
+                match other.is_nil() {
+                    true => Some(format!("unexpected {:?} at {}", val, at(path))),
+                    false => None,
+                }
+            } else {
+                difference(&val, &other, path)?
+            };
+            path.pop();
+            if diff.is_some() {
+                return Ok(diff);
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The results as a TAP version 13 report, with a test for each test run
+/// and for each file stopped by an error
+pub fn tap(results: &[FileResult]) -> String {
+    let mut out = String::from("TAP version 13\n");
+    let total: usize = results
+        .iter()
+        .map(|file| file.tests.len() + file.error.is_some() as usize)
+        .sum();
+    let _ = writeln!(out, "1..{}", total);
+    let mut n = 0;
+    for file in results {
+        let path = file.path.display();
+        for test in &file.tests {
+            n += 1;
+            match test.outcome {
+                Outcome::Passed => {
+                    let _ = writeln!(out, "ok {} - {}: {}", n, path, test.name);
+                }
+                Outcome::Pending => {
+                    let _ = writeln!(out, "ok {} - {}: {} # SKIP pending", n, path, test.name);
+                }
+                Outcome::Failed(ref msg) => {
+                    let _ = writeln!(out, "not ok {} - {}: {}", n, path, test.name);
+                    diagnostic(&mut out, msg);
+                }
+            }
+        }
+        if let Some(ref msg) = file.error {
+            n += 1;
+            let _ = writeln!(out, "not ok {} - {}", n, path);
+            diagnostic(&mut out, msg);
+        }
+    }
+    out
+}
+
+/// A YAML block giving why a TAP test failed
+fn diagnostic(out: &mut String, msg: &str) {
+    out.push_str("  ---\n  message: |\n");
+    for line in msg.lines() {
+        let _ = writeln!(out, "    {}", line);
+    }
+    out.push_str("  ...\n");
+}
+
+/// The results as a JUnit XML report, with a test suite for each file,
+/// where an error stopping a file is the error of a test named after it
+pub fn junit(results: &[FileResult]) -> String {
+    let count = |f: &dyn Fn(&FileResult) -> usize| results.iter().map(f).sum::<usize>();
+    let mut out = format!(
+        "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+         <testsuites tests=\"{}\" failures=\"{}\" errors=\"{}\" skipped=\"{}\" time=\"{:.3}\">\n",
+        count(&|file| file.tests.len()),
+        count(&FileResult::failed),
+        count(&|file| file.error.is_some() as usize),
+        count(&FileResult::pending),
+        results
+            .iter()
+            .map(|file| file.duration.as_secs_f64())
+            .sum::<f64>()
+    );
+    for file in results {
+        let path = escape(&file.path.to_string_lossy());
+        let _ = writeln!(
+            out,
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" errors=\"{}\" \
+             skipped=\"{}\" time=\"{:.3}\">",
+            path,
+            file.tests.len(),
+            file.failed(),
+            file.error.is_some() as usize,
+            file.pending(),
+            file.duration.as_secs_f64()
+        );
+        for test in &file.tests {
+            let _ = write!(
+                out,
+                "    <testcase name=\"{}\" classname=\"{}\" time=\"{:.3}\"",
+                escape(&test.name),
+                path,
+                test.duration.as_secs_f64()
+            );
+            match test.outcome {
+                Outcome::Passed => out.push_str("/>\n"),
+                Outcome::Pending => out.push_str(">\n      <skipped/>\n    </testcase>\n"),
+                Outcome::Failed(ref msg) => {
+                    let _ = write!(
+                        out,
+                        ">\n      <failure message=\"{0}\">{0}</failure>\n    </testcase>\n",
+                        escape(msg)
+                    );
+                }
+            }
+        }
+        if let Some(ref msg) = file.error {
+            let _ = write!(
+                out,
+                "    <testcase name=\"{0}\" classname=\"{0}\" time=\"0.000\">\n      \
+                 <error message=\"{1}\">{1}</error>\n    </testcase>\n",
+                path,
+                escape(msg)
+            );
+        }
+        out.push_str("  </testsuite>\n");
+    }
+    out.push_str("</testsuites>\n");
+    out
+}
+
+fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            '\n' => out.push_str("&#10;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
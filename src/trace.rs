@@ -0,0 +1,221 @@
+//! Tracebacks, which list the calls an error was raised through, or the
+//! calls running
+//!
+//! Each thread keeps a stack of the Lua functions running on it, with the
+//! line each is at, which functions push as they start and pop as they
+//! return. Recording every call that way is cheap, but working out where
+//! an error was raised through needs the stack as it was when the error
+//! was raised, so each function also adds itself as an error leaves it,
+//! innermost first. A message handler given to `xpcall` runs before that
+//! traceback is dropped, so it can still get it. As with finalizers, the
+//! traceback is per thread.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+
+/// How many of the innermost and outermost calls a long traceback lists,
+/// as in `luaL_traceback`, with the ones between left out
+const FIRST_LEVELS: usize = 10;
+const LAST_LEVELS: usize = 11;
+
+thread_local! {
+    static TRACE: RefCell<Trace> = const {
+        RefCell::new(Trace {
+            calls: Vec::new(),
+            skip: 0,
+            raised: false,
+        })
+    };
+    static LEVELS: RefCell<Vec<Level>> = const { RefCell::new(Vec::new()) };
+    /// how many message handlers are running, which see the traceback of
+    /// the error they handle
+    static HANDLING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// A Lua function that is running
+struct Level {
+    chunk: Rc<str>,
+    /// the line the function is defined on, which is 0 for a main chunk
+    defined: u32,
+    /// the line it is at, which is 0 if that isn't known
+    line: u32,
+}
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.line {
+            0 => write!(f, "{}: in ", self.chunk)?,
+            line => write!(f, "{}:{}: in ", self.chunk, line)?,
+        }
+        match self.defined {
+            0 => write!(f, "main chunk"),
+            defined => write!(f, "function <{}:{}>", self.chunk, defined),
+        }
+    }
+}
+
+/// Record that a Lua function from `chunk`, defined on the line `defined`
+/// or 0 for a main chunk, started running at the line `line`
+pub fn enter(chunk: &Rc<str>, defined: u32, line: u32) {
+    LEVELS.with(|levels| {
+        levels.borrow_mut().push(Level {
+            chunk: chunk.clone(),
+            defined,
+            line,
+        })
+    });
+}
+
+/// The functions running, outermost first, by their chunk and the line
+/// they are defined on
+pub fn running() -> Vec<(Rc<str>, u32)> {
+    LEVELS.with(|levels| {
+        let levels = levels.borrow();
+        levels
+            .iter()
+            .map(|level| (level.chunk.clone(), level.defined))
+            .collect()
+    })
+}
+
+/// Record that the innermost `n` functions running stopped, by returning,
+/// raising an error or yielding
+pub fn leave(n: usize) {
+    LEVELS.with(|levels| {
+        let mut levels = levels.borrow_mut();
+        let len = levels.len();
+        levels.truncate(len.saturating_sub(n));
+    });
+}
+
+/// Record that the innermost function running got to `line`
+#[inline]
+pub fn set_line(line: u32) {
+    LEVELS.with(|levels| {
+        if let Some(level) = levels.borrow_mut().last_mut() {
+            level.line = line;
+        }
+    });
+}
+
+/// Run `handler` as the message handler of an error, which sees the
+/// traceback of the error along with the functions running
+pub fn handling<T, F>(handler: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    HANDLING.with(|handling| handling.set(handling.get() + 1));
+    let result = handler();
+    HANDLING.with(|handling| handling.set(handling.get() - 1));
+    result
+}
+
+struct Trace {
+    /// where each function the error left was, innermost first
+    calls: Vec<String>,
+    /// how many more functions the error leaves before its position is
+    /// taken, for `error` with a level above 1
+    skip: usize,
+    /// whether `error` has started the traceback already
+    raised: bool,
+}
+
+/// Start the traceback of an error raised by `error`, whose position is to
+/// be that of the function `level` calls out, counting from 1
+pub fn raise(level: usize) {
+    TRACE.with(|trace| {
+        let mut trace = trace.borrow_mut();
+        trace.calls.clear();
+        trace.skip = level.saturating_sub(1);
+        trace.raised = true;
+    });
+}
+
+/// Whether an error raised by Rust code is to be given the position of the
+/// function it is in, which starts its traceback unless `error` did
+pub fn locate() -> bool {
+    TRACE.with(|trace| {
+        let mut trace = trace.borrow_mut();
+        if trace.skip > 0 {
+            return false;
+        }
+        if !trace.raised {
+            trace.calls.clear();
+        }
+        trace.raised = false;
+        true
+    })
+}
+
+/// Record that an error left `func`, where it was at `place`
+pub fn unwind<P, F>(place: P, func: F)
+where
+    P: fmt::Display,
+    F: fmt::Display,
+{
+    TRACE.with(|trace| {
+        let mut trace = trace.borrow_mut();
+        trace.calls.push(format!("{}: in {}", place, func));
+        trace.skip = trace.skip.saturating_sub(1);
+    });
+}
+
+/// Drop the traceback, once the error has been caught
+pub fn catch() {
+    TRACE.with(|trace| {
+        let mut trace = trace.borrow_mut();
+        trace.calls.clear();
+        trace.skip = 0;
+        trace.raised = false;
+    });
+}
+
+/// The traceback in the form Lua prints it, which lists the calls the last
+/// uncaught error left and then those running, as in
+///
+/// ```text
+/// stack traceback:
+///     script.lua:2: in function <script.lua:1>
+///     script.lua:4: in main chunk
+/// ```
+///
+/// Only the innermost 10 and outermost 11 calls of a longer traceback are
+/// listed.
+pub fn traceback() -> String {
+    let calls = TRACE.with(|trace| trace.borrow().calls.clone());
+    format(calls)
+}
+
+/// The traceback `debug.traceback` gives, which is that of the error being
+/// handled in a message handler and otherwise lists the calls running
+pub fn current() -> String {
+    if HANDLING.with(Cell::get) > 0 {
+        traceback()
+    } else {
+        format(Vec::new())
+    }
+}
+
+/// List `calls`, then the functions running
+fn format(mut calls: Vec<String>) -> String {
+    LEVELS.with(|levels| {
+        calls.extend(levels.borrow().iter().rev().map(Level::to_string));
+    });
+    let skipped = if calls.len() > FIRST_LEVELS + LAST_LEVELS {
+        FIRST_LEVELS..calls.len() - LAST_LEVELS
+    } else {
+        0..0
+    };
+    let mut out = "stack traceback:".to_string();
+    for (i, call) in calls.iter().enumerate() {
+        if i == skipped.start && !skipped.is_empty() {
+            out.push_str(&format!("\n\t...\t(skipping {} levels)", skipped.len()));
+        }
+        if skipped.contains(&i) {
+            continue;
+        }
+        out.push_str("\n\t");
+        out.push_str(call);
+    }
+    out
+}
@@ -0,0 +1,226 @@
+//! Userdata, which wraps a Rust value so Lua code can hold and call it
+
+use std::any::{Any, TypeId};
+use std::cell::{Cell, Ref, RefCell, RefMut};
+use std::collections::HashMap;
+use std::marker::PhantomData;
+
+use error::{Error, Result};
+use gc::Edge;
+use table::Table;
+use value::{ConvertValue, MultiValue, Value};
+
+/// A Rust type that can be passed to Lua as userdata with methods
+///
+/// Every value of the type shares one metatable, built from `add_methods`
+/// the first time a value is created on a thread.
+pub trait UserData: Any + Sized {
+    /// The name shown by `tostring` and in errors, stored as `__name`
+    const NAME: &'static str = "userdata";
+
+    /// Declare the methods and metamethods available to Lua code
+    fn add_methods(_methods: &mut UserDataMethods<Self>) {}
+}
+
+/// The methods and metamethods declared by a `UserData` type
+pub struct UserDataMethods<T> {
+    methods: Table,
+    metatable: Table,
+    _marker: PhantomData<T>,
+}
+impl<T> UserDataMethods<T>
+where
+    T: UserData,
+{
+    /// Add a method, called from Lua as `value:name(...)`, which can
+    /// return one `Value` or a `MultiValue`
+    pub fn add_method<F, R>(&mut self, name: &str, method: F)
+    where
+        F: Fn(&T, &[Value]) -> Result<R> + 'static,
+        R: Into<MultiValue>,
+    {
+        let func = self_function::<T, _>(name, move |this, args| {
+            Some(method(&*this.downcast_ref::<T>()?, args).map(Into::into))
+        });
+        register(&self.methods, name, func);
+    }
+    /// Add a method that can mutate the value
+    ///
+    /// The value stays borrowed while the method runs, so calling another
+    /// method on it from inside fails.
+    pub fn add_method_mut<F, R>(&mut self, name: &str, method: F)
+    where
+        F: Fn(&mut T, &[Value]) -> Result<R> + 'static,
+        R: Into<MultiValue>,
+    {
+        let func = self_function::<T, _>(name, move |this, args| {
+            Some(method(&mut *this.downcast_mut::<T>()?, args).map(Into::into))
+        });
+        register(&self.methods, name, func);
+    }
+    /// Add a metamethod such as `__add` or `__tostring`, whose first
+    /// argument must be a value of this type
+    pub fn add_meta_method<F, R>(&mut self, event: &str, method: F)
+    where
+        F: Fn(&T, &[Value]) -> Result<R> + 'static,
+        R: Into<MultiValue>,
+    {
+        let func = self_function::<T, _>(event, move |this, args| {
+            Some(method(&*this.downcast_ref::<T>()?, args).map(Into::into))
+        });
+        register(&self.metatable, event, func);
+    }
+}
+
+/// Wrap `method` into a function taking a `T` as its first argument,
+/// where `method` gives `None` if that argument can't be borrowed as one
+fn self_function<T, F>(name: &str, method: F) -> Value
+where
+    T: UserData,
+    F: Fn(&Value, &[Value]) -> Option<Result<MultiValue>> + 'static,
+{
+    let name = name.to_string();
+    Value::function(move |args| {
+        let this = args.first().cloned().unwrap_or_else(Value::nil);
+        method(&this, args.get(1..).unwrap_or(&[])).unwrap_or_else(|| {
+            Err(Error::Runtime(format!(
+                "bad argument #1 to '{}' ({} expected, got {})",
+                name,
+                T::NAME,
+                this.type_of()
+            )))
+        })
+    })
+}
+
+fn register(table: &Table, name: &str, func: Value) {
+    table
+        .set(Value::string(name), func)
+        .expect("string keys are always valid");
+}
+
+thread_local! {
+    static METATABLES: RefCell<HashMap<TypeId, Value>> = RefCell::new(HashMap::new());
+}
+
+/// The metatable shared by values of `T`, built on first use
+fn metatable<T>() -> Value
+where
+    T: UserData,
+{
+    let id = TypeId::of::<T>();
+    if let Some(mt) = METATABLES.with(|mts| mts.borrow().get(&id).cloned()) {
+        return mt;
+    }
+    let mut methods = UserDataMethods {
+        methods: Table::new(),
+        metatable: Table::new(),
+        _marker: PhantomData,
+    };
+    T::add_methods(&mut methods);
+    let UserDataMethods {
+        methods, metatable, ..
+    } = methods;
+    if !methods.is_empty() && metatable.get(&Value::string("__index")).is_nil() {
+        register(&metatable, "__index", methods.into_value());
+    }
+    if metatable.get(&Value::string("__name")).is_nil() {
+        register(&metatable, "__name", Value::string(T::NAME));
+    }
+    let mt = metatable.into_value();
+    METATABLES.with(|mts| mts.borrow_mut().insert(id, mt.clone()));
+    mt
+}
+
+/// A light userdata: a pointer-sized handle that Lua code can pass around
+/// and compare, which is never allocated or finalized
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LightUserdata(pub usize);
+impl LightUserdata {
+    pub fn from_ptr<T>(ptr: *const T) -> LightUserdata {
+        LightUserdata(ptr as usize)
+    }
+    pub fn as_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+}
+
+/// A userdata value: any Rust value, and an optional metatable
+pub struct AnyUserData {
+    data: RefCell<Box<dyn Any>>,
+    metatable: RefCell<Option<Value>>,
+    /// set when given a metatable with `__gc`, so the finalizer runs on drop
+    finalize: Cell<bool>,
+}
+impl AnyUserData {
+    /// Wrap `data` without a metatable
+    pub fn new<T>(data: T) -> AnyUserData
+    where
+        T: Any,
+    {
+        AnyUserData {
+            data: RefCell::new(Box::new(data)),
+            metatable: RefCell::new(None),
+            finalize: Cell::new(false),
+        }
+    }
+    /// Wrap `data` with the metatable holding its type's methods
+    pub fn with_methods<T>(data: T) -> AnyUserData
+    where
+        T: UserData,
+    {
+        let userdata = AnyUserData::new(data);
+        userdata.set_metatable(Some(metatable::<T>()));
+        userdata
+    }
+    /// Borrow the wrapped value, if it is a `T` and not mutably borrowed
+    pub fn downcast_ref<T>(&self) -> Option<Ref<'_, T>>
+    where
+        T: Any,
+    {
+        let data = self.data.try_borrow().ok()?;
+        Ref::filter_map(data, |data| data.downcast_ref::<T>()).ok()
+    }
+    /// Mutably borrow the wrapped value, if it is a `T` and not borrowed
+    pub fn downcast_mut<T>(&self) -> Option<RefMut<'_, T>>
+    where
+        T: Any,
+    {
+        let data = self.data.try_borrow_mut().ok()?;
+        RefMut::filter_map(data, |data| data.downcast_mut::<T>()).ok()
+    }
+    pub fn metatable(&self) -> Option<Value> {
+        self.metatable.borrow().clone()
+    }
+    /// Whether this is to be finalized when collected
+    pub(crate) fn finalizes(&self) -> bool {
+        self.finalize.get()
+    }
+    /// Pass the metatable to `visit`, since the Rust value can't be looked
+    /// into
+    pub(crate) fn trace(&self, visit: &mut dyn FnMut(Edge)) {
+        if let Ok(metatable) = self.metatable.try_borrow() {
+            if let Some(ref mt) = *metatable {
+                visit(Edge::Value(mt));
+            }
+        }
+    }
+    /// Stop this from being finalized when collected, giving whether it
+    /// would have been
+    pub(crate) fn take_finalizer(&self) -> bool {
+        self.finalize.replace(false)
+    }
+    /// Replace the metatable, which must be a table or `None`
+    pub fn set_metatable(&self, metatable: Option<Value>) {
+        debug_assert!(metatable
+            .as_ref()
+            .is_none_or(|mt| Table::from_value(mt).is_some()));
+        // like Lua, only a `__gc` present when the metatable is set counts
+        if let Some(ref mt) = metatable {
+            if !mt.raw_get(&Value::string("__gc")).is_nil() {
+                self.finalize.set(true);
+            }
+        }
+        *self.metatable.borrow_mut() = metatable;
+    }
+}
@@ -0,0 +1,260 @@
+//! Deeply immutable values, which any number of states can share, on any
+//! thread, without copying
+//!
+//! Freezing a value copies it once into storage that is never written to
+//! again, such as a large table of game data loaded once for many script
+//! workers. A state reads a frozen table in place, like a table without a
+//! metatable, and raises an error on any assignment to it.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::sync::Arc;
+
+use error::{Error, Result};
+use number;
+
+use super::{
+    ConvertValue, LuaInteger, LuaNumber, LuaString, LuaTable, Type, Value, ValueData, ValueRef,
+};
+
+/// A value that can't be changed, as made by `Value::freeze`, which is
+/// cheap to clone and can be sent to other threads
+#[derive(Clone)]
+pub struct Frozen(Data);
+
+#[derive(Clone)]
+enum Data {
+    Nil,
+    Boolean(bool),
+    Integer(LuaInteger),
+    Number(LuaNumber),
+    String(Arc<[u8]>),
+    Table(Arc<FrozenTable>),
+}
+
+impl Frozen {
+    /// The value in the state running, which reads tables and long strings
+    /// in place rather than copying them back
+    pub fn to_value(&self) -> Value {
+        match self.0 {
+            Data::Nil => Value::nil(),
+            Data::Boolean(val) => val.into_value(),
+            Data::Integer(val) => val.into_value(),
+            Data::Number(val) => val.into_value(),
+            Data::String(ref bytes) => Value::new(LuaString::from(bytes.clone())),
+            Data::Table(ref table) => Value::from_data(ValueData::Frozen(table.clone())),
+        }
+    }
+    pub fn type_of(&self) -> Type {
+        match self.0 {
+            Data::Nil => Type::Nil,
+            Data::Boolean(_) => Type::Boolean,
+            Data::Integer(_) | Data::Number(_) => Type::Number,
+            Data::String(_) => Type::String,
+            Data::Table(_) => Type::Table,
+        }
+    }
+}
+impl fmt::Debug for Frozen {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {
+            Data::Nil => f.write_str("nil"),
+            Data::Boolean(val) => fmt::Display::fmt(&val, f),
+            Data::Integer(val) => fmt::Display::fmt(&val, f),
+            Data::Number(val) => number::fmt_float(val, f),
+            Data::String(ref bytes) => write!(f, "{:?}", String::from_utf8_lossy(bytes)),
+            Data::Table(ref table) => write!(f, "table: {:#x}", table.addr()),
+        }
+    }
+}
+
+/// A key other than a string, where tables are told apart by address
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Boolean(bool),
+    Integer(LuaInteger),
+    /// the bits of a float with no integer value
+    Float(u64),
+    Table(usize),
+}
+impl Key {
+    /// The key `val` would be, if it is neither a string nor a value that
+    /// can't be frozen
+    // the bits are a u32 with the "f32" feature
+    #[allow(clippy::useless_conversion)]
+    fn of(val: &Value) -> Option<Key> {
+        Some(match val.repr.get() {
+            ValueRef::Boolean(&val) => Key::Boolean(val),
+            ValueRef::Integer(val) => Key::Integer(val),
+            ValueRef::Number(&val) => match number::float_to_int(val) {
+                Some(val) => Key::Integer(val),
+                None => Key::Float(u64::from(val.to_bits())),
+            },
+            ValueRef::Frozen(table) => Key::Table(table.addr()),
+            _ => return None,
+        })
+    }
+}
+
+/// A table that can't be changed
+pub(crate) struct FrozenTable {
+    /// the values of the keys from 1 to its length
+    array: Vec<Frozen>,
+    /// the other entries, in the order `next` traverses them
+    entries: Vec<(Frozen, Frozen)>,
+    /// where in `entries` the entry of each string key is
+    strings: HashMap<Arc<[u8]>, usize>,
+    /// where in `entries` the entry of each other key is
+    others: HashMap<Key, usize>,
+}
+impl FrozenTable {
+    pub fn get(&self, key: &Value) -> Value {
+        match self.position(key) {
+            Some(i) => self.entry(i).1,
+            None => Value::nil(),
+        }
+    }
+    pub fn len(&self) -> LuaInteger {
+        self.array.len() as LuaInteger
+    }
+    /// The entry after `key`, in the order of the array part and then the
+    /// other entries, as `Table::next` gives them
+    pub fn next(&self, key: &Value) -> Result<Option<(Value, Value)>> {
+        let at = match self.position(key) {
+            _ if key.is_nil() => 0,
+            Some(i) => i + 1,
+            None => return Err(Error::Runtime("invalid key to 'next'".to_string())),
+        };
+        if at < self.array.len() + self.entries.len() {
+            Ok(Some(self.entry(at)))
+        } else {
+            Ok(None)
+        }
+    }
+    /// Where the entry of `key` is, counting the array part first
+    fn position(&self, key: &Value) -> Option<usize> {
+        let array = self.array.len();
+        if let Some(bytes) = LuaString::from_value(key) {
+            return self.strings.get(&**bytes).map(|&i| array + i);
+        }
+        match Key::of(key)? {
+            Key::Integer(i) if i >= 1 && i as u64 <= array as u64 => Some(i as usize - 1),
+            key => self.others.get(&key).map(|&i| array + i),
+        }
+    }
+    fn entry(&self, i: usize) -> (Value, Value) {
+        match self.array.get(i) {
+            Some(val) => ((i as LuaInteger + 1).into_value(), val.to_value()),
+            None => {
+                let (ref key, ref val) = self.entries[i - self.array.len()];
+                (key.to_value(), val.to_value())
+            }
+        }
+    }
+    /// The address that identifies it, by which values of it compare
+    pub fn addr(&self) -> usize {
+        self as *const FrozenTable as usize
+    }
+}
+
+/// What has been frozen so far by one call to `Value::freeze`
+struct Freezer {
+    /// the tables already frozen, by address, so that one contained in
+    /// several places stays one table
+    done: HashMap<usize, Arc<FrozenTable>>,
+    /// the tables being frozen, which a table containing itself is among
+    open: HashSet<usize>,
+}
+impl Freezer {
+    fn freeze(&mut self, val: &Value) -> Result<Frozen> {
+        Ok(Frozen(match val.repr.get() {
+            ValueRef::Nil => Data::Nil,
+            ValueRef::Boolean(&val) => Data::Boolean(val),
+            ValueRef::Integer(val) => Data::Integer(val),
+            ValueRef::Number(&val) => Data::Number(val),
+            ValueRef::String(bytes) => Data::String(bytes.shared()),
+            ValueRef::Frozen(table) => Data::Table(table.clone()),
+            ValueRef::Table(table) => Data::Table(self.table(val.addr(), table)?),
+            _ => {
+                return Err(Error::Runtime(format!(
+                    "cannot freeze a {} value",
+                    val.type_of()
+                )))
+            }
+        }))
+    }
+    fn table(&mut self, addr: usize, table: &LuaTable) -> Result<Arc<FrozenTable>> {
+        if let Some(frozen) = self.done.get(&addr) {
+            return Ok(frozen.clone());
+        }
+        if table.metatable().is_some() {
+            return Err(Error::Runtime(
+                "cannot freeze a table with a metatable".to_string(),
+            ));
+        }
+        if !self.open.insert(addr) {
+            return Err(Error::Runtime(
+                "cannot freeze a table that contains itself".to_string(),
+            ));
+        }
+        let mut frozen = FrozenTable {
+            array: Vec::new(),
+            entries: Vec::new(),
+            strings: HashMap::new(),
+            others: HashMap::new(),
+        };
+        // the array part runs up to the first nil, so that its length is
+        // a border of the table
+        loop {
+            let val = table.get(&(frozen.len() + 1).into_value());
+            if val.is_nil() {
+                break;
+            }
+            frozen.array.push(self.freeze(&val)?);
+        }
+        let mut key = Value::nil();
+        while let Some((next, val)) = table.next(&key)? {
+            key = next;
+            match key.as_integer() {
+                Some(i) if key.is_integer() && i >= 1 && i <= frozen.len() => continue,
+                _ => (),
+            }
+            let at = frozen.entries.len();
+            let frozen_key = self.freeze(&key)?;
+            match frozen_key.0 {
+                Data::String(ref bytes) => frozen.strings.insert(bytes.clone(), at),
+                _ => frozen.others.insert(
+                    Key::of(&frozen_key.to_value()).expect("keys are never nil"),
+                    at,
+                ),
+            };
+            frozen.entries.push((frozen_key, self.freeze(&val)?));
+        }
+        self.open.remove(&addr);
+        let frozen = Arc::new(frozen);
+        self.done.insert(addr, frozen.clone());
+        Ok(frozen)
+    }
+}
+
+impl Value {
+    /// A deeply immutable copy of this value, which can be shared by any
+    /// number of states without copying it again
+    ///
+    /// Tables are copied along with every table they contain, which is
+    /// each copied once however many times it is contained. Functions,
+    /// userdata and threads can't be frozen, nor can tables that have a
+    /// metatable or that contain themselves. Freezing a frozen table gives
+    /// back the same table.
+    pub fn freeze(&self) -> Result<Frozen> {
+        let mut freezer = Freezer {
+            done: HashMap::new(),
+            open: HashSet::new(),
+        };
+        freezer.freeze(self)
+    }
+    /// Whether this is a frozen table, which can't be assigned to
+    pub fn is_frozen(&self) -> bool {
+        matches!(self.repr.get(), ValueRef::Frozen(_))
+    }
+}
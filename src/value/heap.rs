@@ -0,0 +1,212 @@
+//! The heap that the payloads of values live on, which only the collector
+//! frees
+//!
+//! A value refers to its payload by pointer, and each payload counts the
+//! values that refer to it, but a count dropping to zero frees nothing.
+//! Instead the collector in `gc` looks for the payloads that nothing
+//! outside the heap can reach and frees them, whether they refer to each
+//! other or not. The counts are how it finds the roots: references from
+//! the Rust stack, a VM stack, a state's globals or anywhere else outside
+//! the heap are what a payload's count has beyond the references the
+//! payloads it traces hold.
+//!
+//! A weak reference keeps the allocation of a payload, though not the
+//! payload itself, so that it can tell once the payload has been freed.
+
+use std::cell::Cell;
+use std::mem::ManuallyDrop;
+use std::ptr::NonNull;
+
+use gc::Edge;
+
+use super::{HeapData, Repr, Value};
+
+/// Where a payload is in being freed
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Life {
+    Alive,
+    /// found to be garbage by a collection freeing it, so that weak
+    /// references no longer reach it
+    Dying,
+    /// freed, with the allocation only kept for the weak references to it
+    Freed,
+}
+
+/// A payload on the heap, with how many values and weak references refer
+/// to it
+pub(crate) struct GcBox {
+    refs: Cell<usize>,
+    weak: Cell<usize>,
+    life: Cell<Life>,
+    /// dropped by the collector, which can leave the allocation to weak
+    /// references, so it is never dropped along with it
+    data: ManuallyDrop<HeapData>,
+}
+
+/// A payload on the heap, where copies of this are only good until the
+/// collector frees it, so the heap's lists of what it holds are what own it
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub(crate) struct Object(NonNull<GcBox>);
+impl Object {
+    /// Move `data` to the heap, referred to by one value, into `spare` if
+    /// there is an allocation to reuse
+    pub fn new(data: HeapData, spare: Option<Box<GcBox>>) -> Object {
+        let boxed = GcBox {
+            refs: Cell::new(1),
+            weak: Cell::new(0),
+            life: Cell::new(Life::Alive),
+            data: ManuallyDrop::new(data),
+        };
+        let boxed = match spare {
+            Some(mut spare) => {
+                // the payload it had was dropped as it was freed
+                *spare = boxed;
+                spare
+            }
+            None => Box::new(boxed),
+        };
+        Object(NonNull::from(Box::leak(boxed)))
+    }
+    /// The payload `ptr` points to, which a value referring to it got from
+    /// `as_ptr`
+    #[cfg(feature = "nan-boxing")]
+    pub unsafe fn from_ptr(ptr: *const GcBox) -> Object {
+        Object(NonNull::new_unchecked(ptr as *mut GcBox))
+    }
+    pub fn as_ptr(self) -> *const GcBox {
+        self.0.as_ptr()
+    }
+    pub fn addr(self) -> usize {
+        self.as_ptr() as usize
+    }
+    // the fields are borrowed one at a time, as the collector changes the
+    // payload while values it holds count their references
+    fn refs_cell(&self) -> &Cell<usize> {
+        // it is only freed by the collector, which forgets it first
+        unsafe { &(*self.0.as_ptr()).refs }
+    }
+    fn weak_cell(&self) -> &Cell<usize> {
+        unsafe { &(*self.0.as_ptr()).weak }
+    }
+    fn life(&self) -> &Cell<Life> {
+        unsafe { &(*self.0.as_ptr()).life }
+    }
+    /// The payload, which lives as long as a value refers to it
+    pub fn data(&self) -> &HeapData {
+        unsafe { &(*self.0.as_ptr()).data }
+    }
+    /// The payload `ptr` points to, borrowed for as long as the caller
+    /// keeps a value referring to it
+    #[cfg(feature = "nan-boxing")]
+    pub unsafe fn data_at<'a>(ptr: *const GcBox) -> &'a HeapData {
+        &(*ptr).data
+    }
+    /// How many values refer to it, from the heap or elsewhere
+    pub fn refs(self) -> usize {
+        self.refs_cell().get()
+    }
+    /// Count another value referring to it
+    pub fn retain(self) {
+        let refs = self.refs_cell();
+        refs.set(refs.get() + 1);
+    }
+    /// Count a value that referred to it being dropped, which leaves
+    /// freeing it to the collector
+    pub fn release(self) {
+        let refs = self.refs_cell();
+        refs.set(refs.get() - 1);
+    }
+    /// A value referring to it
+    pub fn value(self) -> Value {
+        self.retain();
+        Value {
+            repr: Repr::from_object(self),
+        }
+    }
+    fn is_alive(self) -> bool {
+        self.life().get() == Life::Alive
+    }
+    /// Pass each reference it holds to `visit`, for the collector
+    pub fn trace(self, visit: &mut dyn FnMut(Edge)) {
+        self.data().view().trace(visit)
+    }
+    /// Whether it is a table or userdata to be finalized when collected
+    pub fn finalizes(self) -> bool {
+        self.data().view().finalizes()
+    }
+    /// Stop it from being finalized when collected
+    pub fn take_finalizer(self) -> bool {
+        self.data().view().take_finalizer()
+    }
+}
+
+/// A reference to a payload that does not keep it alive
+pub(crate) struct WeakRef(Object);
+impl WeakRef {
+    pub fn new(object: Object) -> WeakRef {
+        let weak = object.weak_cell();
+        weak.set(weak.get() + 1);
+        WeakRef(object)
+    }
+    /// A value referring to the payload, unless it has been freed
+    pub fn upgrade(&self) -> Option<Value> {
+        if self.0.is_alive() {
+            Some(self.0.value())
+        } else {
+            None
+        }
+    }
+    pub fn is_alive(&self) -> bool {
+        self.0.is_alive()
+    }
+    /// The address the payload had, which the allocation kept for this
+    /// keeps from being reused
+    pub fn addr(&self) -> usize {
+        self.0.addr()
+    }
+}
+impl Clone for WeakRef {
+    fn clone(&self) -> WeakRef {
+        WeakRef::new(self.0)
+    }
+}
+impl Drop for WeakRef {
+    fn drop(&mut self) {
+        let weak = self.0.weak_cell();
+        weak.set(weak.get() - 1);
+        if weak.get() == 0 && self.0.life().get() == Life::Freed {
+            // the last thing keeping the allocation
+            drop(unsafe { Box::from_raw(self.0.as_ptr() as *mut GcBox) });
+        }
+    }
+}
+
+/// Free `garbage`, which nothing outside it refers to, keeping the
+/// allocations that no weak reference needs in the pools of the states
+/// they were charged to, where there is room
+///
+/// No copy of any of them may be used after this.
+pub(crate) fn free(garbage: &[Object]) {
+    // weak references mustn't bring any back while the others are freed
+    for object in garbage {
+        object.life().set(Life::Dying);
+    }
+    // the payloads only refer to each other, and to payloads still alive,
+    // whose counts dropping frees nothing
+    let owners: Vec<_> = garbage
+        .iter()
+        .map(|object| unsafe { (*(object.as_ptr() as *mut GcBox)).data.free() })
+        .collect();
+    for (object, owner) in garbage.iter().zip(owners) {
+        debug_assert_eq!(object.refs(), 0, "freed a payload still in use");
+        object.life().set(Life::Freed);
+        if object.weak_cell().get() > 0 {
+            continue;
+        }
+        let spare = unsafe { Box::from_raw(object.as_ptr() as *mut GcBox) };
+        match owner {
+            Some(owner) if owner.values.has_room() => owner.values.give(spare),
+            _ => drop(spare),
+        }
+    }
+}
@@ -0,0 +1,92 @@
+//! Interning of short strings, so that equal ones share an allocation and
+//! usually compare equal by address
+
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+
+use super::{LuaString, Repr, Value, ValueData, WeakRef};
+
+/// The length up to which strings are interned by default, as in Lua
+const DEFAULT_INTERN_LIMIT: usize = 40;
+
+/// Counters describing a string table
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct InternStats {
+    /// interned strings that are still alive
+    pub live: usize,
+    /// requests that found an existing string
+    pub hits: u64,
+    /// requests that had to allocate a new string
+    pub misses: u64,
+}
+
+/// A table of weakly held short strings, which are dropped from it once
+/// they have been collected
+pub struct StringTable {
+    strings: RefCell<HashMap<LuaString, WeakRef>>,
+    limit: Cell<usize>,
+    hits: Cell<u64>,
+    misses: Cell<u64>,
+    /// how many entries there were when dead ones were last removed
+    pruned_len: Cell<usize>,
+}
+impl StringTable {
+    pub fn new() -> StringTable {
+        StringTable {
+            strings: RefCell::new(HashMap::new()),
+            limit: Cell::new(DEFAULT_INTERN_LIMIT),
+            hits: Cell::new(0),
+            misses: Cell::new(0),
+            pruned_len: Cell::new(0),
+        }
+    }
+    /// Get a string value, sharing the existing one if `bytes` is short
+    /// enough to be interned and has been seen before
+    pub fn intern(&self, bytes: &[u8]) -> Value {
+        if bytes.len() > self.limit.get() {
+            return Value::string(bytes);
+        }
+        let mut strings = self.strings.borrow_mut();
+        if let Some(val) = strings.get(bytes).and_then(WeakRef::upgrade) {
+            self.hits.set(self.hits.get() + 1);
+            return val;
+        }
+        self.misses.set(self.misses.get() + 1);
+        let object = ValueData::String(LuaString::from(bytes)).into_heap();
+        strings.insert(LuaString::from(bytes), WeakRef::new(object));
+        // dead entries are removed whenever the table doubles in size
+        if strings.len() > 2 * self.pruned_len.get().max(32) {
+            strings.retain(|_, string| string.is_alive());
+            self.pruned_len.set(strings.len());
+        }
+        Value {
+            repr: Repr::from_object(object),
+        }
+    }
+    /// The longest string that is interned
+    pub fn limit(&self) -> usize {
+        self.limit.get()
+    }
+    /// Change the longest string that is interned, which only affects
+    /// strings created afterwards
+    pub fn set_limit(&self, limit: usize) {
+        self.limit.set(limit);
+    }
+    pub fn stats(&self) -> InternStats {
+        InternStats {
+            live: self
+                .strings
+                .borrow()
+                .values()
+                .filter(|string| string.is_alive())
+                .count(),
+            hits: self.hits.get(),
+            misses: self.misses.get(),
+        }
+    }
+}
+impl Default for StringTable {
+    fn default() -> StringTable {
+        StringTable::new()
+    }
+}
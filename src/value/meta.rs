@@ -0,0 +1,557 @@
+//! Operations on values that can be overridden by metamethods
+
+use std::cmp::Ordering;
+use std::io::Write;
+use std::ops::{Add, Div, Mul, Neg, Rem, Sub};
+
+use error::{Error, Result};
+use number;
+
+use super::{
+    ConvertValue, LuaFunction, LuaInteger, LuaString, LuaTable, MultiValue, Type, Value, ValueRef,
+};
+
+/// An arithmetic operator that can be overridden with a metamethod
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    /// floor division (`//`)
+    IDiv,
+    /// unary minus
+    Unm,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    /// bitwise not (unary `~`)
+    BNot,
+}
+impl ArithOp {
+    /// The name of the metamethod implementing this operator
+    pub fn event(self) -> &'static str {
+        match self {
+            ArithOp::Add => "__add",
+            ArithOp::Sub => "__sub",
+            ArithOp::Mul => "__mul",
+            ArithOp::Div => "__div",
+            ArithOp::Mod => "__mod",
+            ArithOp::Pow => "__pow",
+            ArithOp::IDiv => "__idiv",
+            ArithOp::Unm => "__unm",
+            ArithOp::BAnd => "__band",
+            ArithOp::BOr => "__bor",
+            ArithOp::BXor => "__bxor",
+            ArithOp::Shl => "__shl",
+            ArithOp::Shr => "__shr",
+            ArithOp::BNot => "__bnot",
+        }
+    }
+    /// The metamethod implementing this operator
+    pub(crate) fn meta_event(self) -> Event {
+        match self {
+            ArithOp::Add => Event::Add,
+            ArithOp::Sub => Event::Sub,
+            ArithOp::Mul => Event::Mul,
+            ArithOp::Div => Event::Div,
+            ArithOp::Mod => Event::Mod,
+            ArithOp::Pow => Event::Pow,
+            ArithOp::IDiv => Event::IDiv,
+            ArithOp::Unm => Event::Unm,
+            ArithOp::BAnd => Event::BAnd,
+            ArithOp::BOr => Event::BOr,
+            ArithOp::BXor => Event::BXor,
+            ArithOp::Shl => Event::Shl,
+            ArithOp::Shr => Event::Shr,
+            ArithOp::BNot => Event::BNot,
+        }
+    }
+    /// Whether this operates on the bits of integers
+    pub fn is_bitwise(self) -> bool {
+        matches!(
+            self,
+            ArithOp::BAnd
+                | ArithOp::BOr
+                | ArithOp::BXor
+                | ArithOp::Shl
+                | ArithOp::Shr
+                | ArithOp::BNot
+        )
+    }
+}
+
+/// A metamethod that values can have, which metatables remember the
+/// absence of so that operations on values without it skip the lookup
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Event {
+    Index,
+    NewIndex,
+    Call,
+    ToString,
+    Name,
+    Len,
+    Concat,
+    Eq,
+    Lt,
+    Le,
+    Pairs,
+    Gc,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    IDiv,
+    Unm,
+    BAnd,
+    BOr,
+    BXor,
+    Shl,
+    Shr,
+    BNot,
+}
+impl Event {
+    const ALL: [Event; 26] = [
+        Event::Index,
+        Event::NewIndex,
+        Event::Call,
+        Event::ToString,
+        Event::Name,
+        Event::Len,
+        Event::Concat,
+        Event::Eq,
+        Event::Lt,
+        Event::Le,
+        Event::Pairs,
+        Event::Gc,
+        Event::Add,
+        Event::Sub,
+        Event::Mul,
+        Event::Div,
+        Event::Mod,
+        Event::Pow,
+        Event::IDiv,
+        Event::Unm,
+        Event::BAnd,
+        Event::BOr,
+        Event::BXor,
+        Event::Shl,
+        Event::Shr,
+        Event::BNot,
+    ];
+    /// The key of the handler in a metatable
+    pub fn name(self) -> &'static str {
+        match self {
+            Event::Index => "__index",
+            Event::NewIndex => "__newindex",
+            Event::Call => "__call",
+            Event::ToString => "__tostring",
+            Event::Name => "__name",
+            Event::Len => "__len",
+            Event::Concat => "__concat",
+            Event::Eq => "__eq",
+            Event::Lt => "__lt",
+            Event::Le => "__le",
+            Event::Pairs => "__pairs",
+            Event::Gc => "__gc",
+            Event::Add => "__add",
+            Event::Sub => "__sub",
+            Event::Mul => "__mul",
+            Event::Div => "__div",
+            Event::Mod => "__mod",
+            Event::Pow => "__pow",
+            Event::IDiv => "__idiv",
+            Event::Unm => "__unm",
+            Event::BAnd => "__band",
+            Event::BOr => "__bor",
+            Event::BXor => "__bxor",
+            Event::Shl => "__shl",
+            Event::Shr => "__shr",
+            Event::BNot => "__bnot",
+        }
+    }
+    /// The name as a string value, made once per thread rather than on
+    /// every lookup
+    pub fn key(self) -> Value {
+        thread_local! {
+            static KEYS: Vec<Value> = Event::ALL
+                .iter()
+                .map(|event| Value::string(event.name()))
+                .collect();
+        }
+        KEYS.with(|keys| keys[self as usize].clone())
+    }
+}
+
+/// How many `__index`/`__newindex`/`__call` handlers to follow before giving up
+const MAX_META_CHAIN: usize = 2000;
+
+fn compare_error(a: &Value, b: &Value) -> Error {
+    let (a, b) = (a.type_of(), b.type_of());
+    Error::Runtime(if a == b {
+        format!("attempt to compare two {} values", a)
+    } else {
+        format!("attempt to compare {} with {}", a, b)
+    })
+}
+
+/// Where indexing a value leads, once metamethods that are tables have
+/// been followed
+pub(crate) enum Target {
+    /// the table the key is looked up or assigned in, with its value there
+    Table(Value, Value),
+    /// the metamethod to call instead, with the value it belongs to
+    Handler(Value, Value),
+}
+
+pub fn index_error(val: &Value) -> Error {
+    Error::Runtime(format!("attempt to index a {} value", val.type_of()))
+}
+
+impl Value {
+    /// Look up the handler for `event` in the metatable
+    pub(crate) fn metamethod(&self, event: Event) -> Option<Value> {
+        LuaTable::from_value(&self.get_metatable()?)?.metamethod(event)
+    }
+    /// Call this value with `args`, which for values other than functions
+    /// calls their `__call` metamethod with the value prepended to `args`
+    pub fn call(&self, mut args: Vec<Value>) -> Result<MultiValue> {
+        let mut func = self.clone();
+        for _ in 0..MAX_META_CHAIN {
+            if let Some(func) = LuaFunction::from_value(&func) {
+                return func(&args);
+            }
+            if let Some(closure) = func.as_interpreted() {
+                return closure.call(args);
+            }
+            if let Some(closure) = func.as_compiled() {
+                return closure.call(args);
+            }
+            match func.metamethod(Event::Call) {
+                Some(handler) => {
+                    args.insert(0, func);
+                    func = handler;
+                }
+                None => {
+                    return Err(Error::Runtime(format!(
+                        "attempt to call a {} value",
+                        func.type_of()
+                    )))
+                }
+            }
+        }
+        Err(Error::Runtime(
+            "'__call' chain too long; possible loop".to_string(),
+        ))
+    }
+    /// Index this value like `self[key]`, consulting `__index` when the key
+    /// is missing from a table or this is not a table
+    pub fn get_index(&self, key: &Value) -> Result<Value> {
+        match self.index_target(key, Event::Index)? {
+            Target::Table(_, val) => Ok(val),
+            Target::Handler(handler, obj) => handler
+                .call(vec![obj, key.clone()])
+                .map(MultiValue::into_first),
+        }
+    }
+    /// Assign to this value like `self[key] = val`, consulting `__newindex`
+    /// when the key is missing from a table or this is not a table
+    pub fn set_index(&self, key: Value, val: Value) -> Result<()> {
+        match self.index_target(&key, Event::NewIndex)? {
+            Target::Table(table, _) => table.raw_set(key, val),
+            Target::Handler(handler, obj) => handler.call(vec![obj, key, val]).map(|_| ()),
+        }
+    }
+    /// Follow the `__index` or `__newindex` metamethods, as `event` says,
+    /// from this value to where `key` is looked up or assigned
+    pub(crate) fn index_target(&self, key: &Value, event: Event) -> Result<Target> {
+        let mut obj = self.clone();
+        for _ in 0..MAX_META_CHAIN {
+            // frozen tables have no metatable
+            if let ValueRef::Frozen(table) = obj.repr.get() {
+                let val = table.get(key);
+                return Ok(Target::Table(obj, val));
+            }
+            let handler = match LuaTable::from_value(&obj) {
+                Some(table) => {
+                    let val = table.get(key);
+                    if !val.is_nil() {
+                        return Ok(Target::Table(obj, val));
+                    }
+                    match obj.metamethod(event) {
+                        Some(handler) => handler,
+                        None => return Ok(Target::Table(obj, val)),
+                    }
+                }
+                None => obj.metamethod(event).ok_or_else(|| index_error(&obj))?,
+            };
+            if handler.type_of() == Type::Function {
+                return Ok(Target::Handler(handler, obj));
+            }
+            obj = handler;
+        }
+        Err(Error::Runtime(format!(
+            "'{}' chain too long; possible loop",
+            event.name()
+        )))
+    }
+    /// Convert to a string like Lua's `tostring`, using the `__tostring`
+    /// metamethod if present and otherwise naming reference types by their
+    /// address (or the metatable's `__name`), e.g. `table: 0x55d0c5e4a2b0`
+    pub fn lua_tostring(&self) -> Result<Value> {
+        if let Some(handler) = self.metamethod(Event::ToString) {
+            let val = handler.call(vec![self.clone()])?.into_first();
+            return match val.type_of() {
+                Type::String => Ok(val),
+                _ => Err(Error::Runtime(
+                    "'__tostring' must return a string".to_string(),
+                )),
+            };
+        }
+        Ok(match self.type_of() {
+            Type::String => self.clone(),
+            Type::Nil | Type::Boolean | Type::Number => Value::string(self.to_string()),
+            _ => match self.metamethod(Event::Name) {
+                Some(ref name) if name.type_of() == Type::String => {
+                    let name = LuaString::from_value(name).expect("checked type");
+                    let mut bytes = name.to_vec();
+                    bytes.extend(format!(": {:#x}", self.addr()).bytes());
+                    Value::string(bytes)
+                }
+                _ => Value::string(self.to_string()),
+            },
+        })
+    }
+    /// Call the comparison metamethod `event` from either operand
+    fn compare_meta(&self, other: &Value, event: Event) -> Option<Result<bool>> {
+        let handler = self.metamethod(event).or_else(|| other.metamethod(event))?;
+        Some(
+            handler
+                .call(vec![self.clone(), other.clone()])
+                .map(|vals| vals.into_first().to_bool()),
+        )
+    }
+    /// Compare like Lua's `==`, calling `__eq` for two distinct tables or
+    /// two distinct userdata
+    pub fn lua_eq(&self, other: &Value) -> Result<bool> {
+        if self.raw_equal(other) {
+            return Ok(true);
+        }
+        match (self.repr.get(), other.repr.get()) {
+            (ValueRef::Table(_), ValueRef::Table(_))
+            | (ValueRef::Userdata(_), ValueRef::Userdata(_)) => {
+                self.compare_meta(other, Event::Eq).unwrap_or(Ok(false))
+            }
+            _ => Ok(false),
+        }
+    }
+    /// Order two numbers or two strings, or refer to the metamethod `event`
+    fn order(&self, other: &Value, event: Event, accept: &[Ordering]) -> Result<bool> {
+        if let (Some(a), Some(b)) = (self.number_value(), other.number_value()) {
+            return Ok(number::cmp(a, b).is_some_and(|ord| accept.contains(&ord)));
+        }
+        if let (Some(a), Some(b)) = (LuaString::from_value(self), LuaString::from_value(other)) {
+            return Ok(accept.contains(&Ord::cmp(a, b)));
+        }
+        self.compare_meta(other, event)
+            .unwrap_or_else(|| Err(compare_error(self, other)))
+    }
+    /// Compare like Lua's `<`, calling `__lt` for values other than two
+    /// numbers or two strings
+    pub fn lua_lt(&self, other: &Value) -> Result<bool> {
+        self.order(other, Event::Lt, &[Ordering::Less])
+    }
+    /// Compare like Lua's `<=`, calling `__le` for values other than two
+    /// numbers or two strings
+    pub fn lua_le(&self, other: &Value) -> Result<bool> {
+        self.order(other, Event::Le, &[Ordering::Less, Ordering::Equal])
+    }
+    /// Roughly how many bytes this value contributes to a concatenation,
+    /// if it is a string or a number
+    fn concat_len(&self) -> Option<usize> {
+        match self.repr.get() {
+            ValueRef::String(bytes) => Some(bytes.len()),
+            ValueRef::Number(_) | ValueRef::Integer(_) => Some(24),
+            _ => None,
+        }
+    }
+    /// Append the bytes this value contributes to a concatenation to `out`,
+    /// giving whether it is a string or a number, which are all that do
+    pub(crate) fn concat_into(&self, out: &mut Vec<u8>) -> bool {
+        match self.repr.get() {
+            ValueRef::String(bytes) => out.extend_from_slice(bytes),
+            ValueRef::Number(_) | ValueRef::Integer(_) => {
+                let _ = write!(out, "{}", self);
+            }
+            _ => return false,
+        }
+        true
+    }
+    /// Join strings and numbers into one string, allocating it only once
+    fn join(values: &[&Value]) -> Value {
+        let len = values.iter().filter_map(|val| val.concat_len()).sum();
+        let mut bytes = Vec::with_capacity(len);
+        for val in values {
+            val.concat_into(&mut bytes);
+        }
+        Value::new(LuaString::from(bytes))
+    }
+    /// A chain of concatenations like `a .. b .. c`, which joins each run of
+    /// strings and numbers in one go, rather than copying what has been
+    /// joined so far at each step, and calls `__concat` from the right,
+    /// as `..` associates, between the values where one isn't either
+    pub fn concat_all(values: &[Value]) -> Result<Value> {
+        let (last, values) = match values.split_last() {
+            Some(split) => split,
+            None => return Ok(Value::string("")),
+        };
+        let mut joined = last.clone();
+        let mut end = values.len();
+        while end > 0 {
+            let mut start = end;
+            if joined.concat_len().is_some() {
+                while start > 0 && values[start - 1].concat_len().is_some() {
+                    start -= 1;
+                }
+            }
+            if start == end {
+                joined = values[end - 1].concat(&joined)?;
+                end -= 1;
+            } else {
+                let mut run: Vec<&Value> = values[start..end].iter().collect();
+                run.push(&joined);
+                joined = Value::join(&run);
+                end = start;
+            }
+        }
+        Ok(joined)
+    }
+    /// The concatenation operator `..`, which joins strings and numbers and
+    /// otherwise calls `__concat` from either operand
+    pub fn concat(&self, other: &Value) -> Result<Value> {
+        if self.concat_len().is_some() && other.concat_len().is_some() {
+            return Ok(Value::join(&[self, other]));
+        }
+        match self
+            .metamethod(Event::Concat)
+            .or_else(|| other.metamethod(Event::Concat))
+        {
+            Some(handler) => handler
+                .call(vec![self.clone(), other.clone()])
+                .map(MultiValue::into_first),
+            None => {
+                let culprit = match self.concat_len() {
+                    Some(_) => other,
+                    None => self,
+                };
+                Err(Error::Runtime(format!(
+                    "attempt to concatenate a {} value",
+                    culprit.type_of()
+                )))
+            }
+        }
+    }
+    /// The length operator `#`, which is the byte length of strings, calls
+    /// `__len` when present, and is otherwise a border of a table
+    pub fn len(&self) -> Result<Value> {
+        if let Some(bytes) = LuaString::from_value(self) {
+            return Ok((bytes.len() as LuaInteger).into_value());
+        }
+        if let Some(handler) = self.metamethod(Event::Len) {
+            return handler.call(vec![self.clone()]).map(MultiValue::into_first);
+        }
+        match self.raw_len() {
+            Some(len) => Ok(len.into_value()),
+            None => Err(Error::Runtime(format!(
+                "attempt to get length of a {} value",
+                self.type_of()
+            ))),
+        }
+    }
+    /// Apply an arithmetic operator, coercing strings to numbers and falling
+    /// back to the operands' metamethods when either is not a number
+    ///
+    /// For the unary `ArithOp::Unm` and `ArithOp::BNot` the second operand
+    /// is ignored, though it is passed to the metamethod as Lua does.
+    pub fn arith(&self, op: ArithOp, other: &Value) -> Result<Value> {
+        if let (Some(a), Some(b)) = (self.to_number(), other.to_number()) {
+            return number::arith(op, a, b).map(number::Number::into_value);
+        }
+        let event = op.meta_event();
+        match self.metamethod(event).or_else(|| other.metamethod(event)) {
+            Some(handler) => handler
+                .call(vec![self.clone(), other.clone()])
+                .map(MultiValue::into_first),
+            None => {
+                let culprit = if self.to_number().is_some() {
+                    other
+                } else {
+                    self
+                };
+                let what = if op.is_bitwise() {
+                    "bitwise operation"
+                } else {
+                    "arithmetic"
+                };
+                Err(Error::Runtime(format!(
+                    "attempt to perform {} on a {} value",
+                    what,
+                    culprit.type_of()
+                )))
+            }
+        }
+    }
+    /// Floor division (`//`)
+    pub fn floor_div(&self, other: &Value) -> Result<Value> {
+        self.arith(ArithOp::IDiv, other)
+    }
+    /// Modulo (`%`), matching the sign of the divisor
+    pub fn modulo(&self, other: &Value) -> Result<Value> {
+        self.arith(ArithOp::Mod, other)
+    }
+    /// Exponentiation (`^`), which always produces a float
+    pub fn pow(&self, other: &Value) -> Result<Value> {
+        self.arith(ArithOp::Pow, other)
+    }
+}
+
+macro_rules! arith_impl {
+    ($trait:ident, $method:ident, $op:ident) => {
+        impl $trait for Value {
+            type Output = Result<Value>;
+            fn $method(self, other: Value) -> Result<Value> {
+                self.arith(ArithOp::$op, &other)
+            }
+        }
+        impl<'a> $trait for &'a Value {
+            type Output = Result<Value>;
+            fn $method(self, other: &'a Value) -> Result<Value> {
+                self.arith(ArithOp::$op, other)
+            }
+        }
+    };
+}
+arith_impl!(Add, add, Add);
+arith_impl!(Sub, sub, Sub);
+arith_impl!(Mul, mul, Mul);
+arith_impl!(Div, div, Div);
+arith_impl!(Rem, rem, Mod);
+
+impl Neg for Value {
+    type Output = Result<Value>;
+    fn neg(self) -> Result<Value> {
+        -&self
+    }
+}
+impl Neg for &Value {
+    type Output = Result<Value>;
+    fn neg(self) -> Result<Value> {
+        self.arith(ArithOp::Unm, self)
+    }
+}
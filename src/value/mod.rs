@@ -0,0 +1,705 @@
+use std::any::Any;
+use std::cell::{Ref, RefMut};
+use std::cmp::Ordering;
+
+use std::hash::{Hash, Hasher};
+use std::mem;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::{fmt, str};
+
+use error::{Error, Result};
+use gc::{self, Edge};
+use interp;
+use limits::{self, Account, Kind};
+use number::{self, Number};
+use table::Table;
+use userdata::{AnyUserData, LightUserdata, UserData};
+use vm;
+
+mod frozen;
+mod heap;
+mod intern;
+mod meta;
+mod multi;
+mod string;
+pub use self::frozen::Frozen;
+use self::frozen::FrozenTable;
+use self::heap::WeakRef;
+pub(crate) use self::heap::{free, GcBox, Object};
+pub use self::intern::{InternStats, StringTable};
+pub use self::meta::ArithOp;
+pub(crate) use self::meta::{Event, Target};
+pub use self::multi::MultiValue;
+pub use self::string::LuaString;
+#[cfg(not(feature = "nan-boxing"))]
+mod tagged;
+#[cfg(not(feature = "nan-boxing"))]
+use self::tagged::Repr;
+#[cfg(feature = "nan-boxing")]
+mod nan_box;
+#[cfg(feature = "nan-boxing")]
+use self::nan_box::Repr;
+
+#[derive(Copy, Clone, Eq, PartialEq, Debug, Hash, PartialOrd, Ord)]
+pub enum Type {
+    /// represents the absence of a value
+    Nil,
+    /// has two values, `false` and `true`
+    Boolean,
+    /// represents both integer numbers and real (floating-point) numbers
+    Number,
+    /// represents immutable sequences of bytes
+    String,
+    /// callable Rust or Lua function
+    Function,
+    Userdata,
+    /// represents independent threads of execution and used to implement coroutines
+    Thread,
+    /// implements associative arrays, that is, arrays that can have as indices not only numbers, but any Lua value except nil and NaN
+    Table,
+}
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Type::Nil => "nil",
+            Type::Boolean => "boolean",
+            Type::Number => "number",
+            Type::String => "string",
+            Type::Function => "function",
+            Type::Userdata => "userdata",
+            Type::Thread => "thread",
+            Type::Table => "table",
+        })
+    }
+}
+
+pub trait ConvertValue: Sized {
+    const TYPE: Type;
+    /// What `from_value` gives back: a reference, or a copy for types a value
+    /// may not hold in memory
+    type Ref<'a>;
+    fn into_value(self) -> Value;
+    fn from_value(val: &Value) -> Option<Self::Ref<'_>>;
+}
+
+pub type LuaNil = ();
+pub type LuaBool = bool;
+// NaN-boxing needs every float to be 64 bits, so it wins over "f32" when
+// both features are on
+#[cfg(any(not(feature = "f32"), feature = "nan-boxing"))]
+pub type LuaNumber = f64;
+#[cfg(all(feature = "f32", not(feature = "nan-boxing")))]
+pub type LuaNumber = f32;
+pub type LuaInteger = i64;
+pub type LuaUserdata = AnyUserData;
+pub type LuaTable = Table;
+pub type LuaFunction = Box<dyn Fn(&[Value]) -> Result<MultiValue>>;
+pub(crate) const LUA_NAN: LuaNumber = LuaNumber::NAN;
+
+macro_rules! convert_value {
+    ($ty:ty, $variant:ident) => {
+        impl ConvertValue for $ty {
+            const TYPE: Type = Type::$variant;
+            type Ref<'a> = &'a $ty;
+            fn into_value(self) -> Value {
+                Value::from_data(ValueData::$variant(self))
+            }
+            fn from_value(val: &Value) -> Option<&Self> {
+                match val.repr.get() {
+                    ValueRef::$variant(val) => Some(val),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+impl ConvertValue for LuaNil {
+    const TYPE: Type = Type::Nil;
+    type Ref<'a> = &'a LuaNil;
+    fn into_value(self) -> Value {
+        Value::from_data(ValueData::Nil)
+    }
+    fn from_value(val: &Value) -> Option<&Self> {
+        match val.repr.get() {
+            ValueRef::Nil => Some(&()),
+            _ => None,
+        }
+    }
+}
+convert_value!(LuaBool, Boolean);
+convert_value!(LuaNumber, Number);
+/// Integers are copied out, since the "nan-boxing" feature stores most of
+/// them inside the value's bits
+impl ConvertValue for LuaInteger {
+    const TYPE: Type = Type::Number;
+    type Ref<'a> = LuaInteger;
+    fn into_value(self) -> Value {
+        Value::from_data(ValueData::Integer(self))
+    }
+    fn from_value(val: &Value) -> Option<LuaInteger> {
+        match val.repr.get() {
+            ValueRef::Integer(val) => Some(val),
+            _ => None,
+        }
+    }
+}
+convert_value!(LuaString, String);
+convert_value!(LuaFunction, Function);
+convert_value!(LuaUserdata, Userdata);
+convert_value!(LuaTable, Table);
+
+/// The payload of a `Value`, tagged by its Lua type
+///
+/// How this is stored depends on the representation in use, so it is only
+/// read back through a `ValueRef`.
+enum ValueData {
+    Nil,
+    Boolean(LuaBool),
+    /// a float
+    Number(LuaNumber),
+    /// the integer subtype of numbers
+    Integer(LuaInteger),
+    String(LuaString),
+    Function(LuaFunction),
+    /// a function written in Lua, run by the tree-walking interpreter
+    Interpreted(interp::Closure),
+    /// a function written in Lua, compiled for the VM
+    Compiled(vm::Closure),
+    Userdata(LuaUserdata),
+    LightUserdata(LightUserdata),
+    Thread(vm::Coroutine),
+    Table(LuaTable),
+    /// a table shared with other states, which can't be changed
+    Frozen(Arc<FrozenTable>),
+}
+
+impl ValueData {
+    /// Move the payload to the heap, charging the memory it takes up to the
+    /// state running
+    fn into_heap(self) -> Object {
+        let owner = limits::charged();
+        if let (Some(owner), Some(kind)) = (&owner, self.kind()) {
+            owner.allocate(kind, self.size());
+        }
+        // the collector traces what these refer to, and takes everything
+        // else to refer to nothing it can see
+        let traced = matches!(
+            self,
+            ValueData::Interpreted(_)
+                | ValueData::Compiled(_)
+                | ValueData::Userdata(_)
+                | ValueData::Thread(_)
+                | ValueData::Table(_)
+        );
+        let spare = owner.as_ref().and_then(|owner| owner.values.take());
+        let object = Object::new(HeapData { data: self, owner }, spare);
+        gc::register(object, traced);
+        object
+    }
+    /// What the payload is charged as, where numbers, which NaN-boxing can
+    /// put on the heap, aren't charged at all
+    fn kind(&self) -> Option<Kind> {
+        match *self {
+            ValueData::String(_) => Some(Kind::Strings),
+            ValueData::Function(_) | ValueData::Interpreted(_) | ValueData::Compiled(_) => {
+                Some(Kind::Functions)
+            }
+            ValueData::Userdata(_) => Some(Kind::Userdata),
+            ValueData::Thread(_) => Some(Kind::Threads),
+            ValueData::Table(_) | ValueData::Frozen(_) => Some(Kind::Tables),
+            _ => None,
+        }
+    }
+    /// Roughly how many bytes the payload takes up on the heap, which is
+    /// nothing for the types that can be stored inline
+    ///
+    /// Tables count the memory their entries take up themselves, as it
+    /// changes while they are alive.
+    fn size(&self) -> usize {
+        let extra = match *self {
+            ValueData::Nil
+            | ValueData::Boolean(_)
+            | ValueData::Number(_)
+            | ValueData::Integer(_)
+            | ValueData::LightUserdata(_) => return 0,
+            ValueData::String(ref bytes) => bytes.heap_size(),
+            _ => 0,
+        };
+        // with the counts the heap keeps
+        mem::size_of::<GcBox>() + extra
+    }
+    fn view(&self) -> ValueRef<'_> {
+        match *self {
+            ValueData::Nil => ValueRef::Nil,
+            ValueData::Boolean(ref val) => ValueRef::Boolean(val),
+            ValueData::Number(ref val) => ValueRef::Number(val),
+            ValueData::Integer(val) => ValueRef::Integer(val),
+            ValueData::String(ref val) => ValueRef::String(val),
+            ValueData::Function(ref val) => ValueRef::Function(val),
+            ValueData::Interpreted(ref val) => ValueRef::Interpreted(val),
+            ValueData::Compiled(ref val) => ValueRef::Compiled(val),
+            ValueData::Userdata(ref val) => ValueRef::Userdata(val),
+            ValueData::LightUserdata(val) => ValueRef::LightUserdata(val),
+            ValueData::Thread(ref val) => ValueRef::Thread(val),
+            ValueData::Table(ref val) => ValueRef::Table(val),
+            ValueData::Frozen(ref val) => ValueRef::Frozen(val),
+        }
+    }
+}
+
+/// A payload on the heap, with the account of the state it is charged to
+pub(crate) struct HeapData {
+    data: ValueData,
+    owner: Option<Rc<Account>>,
+}
+impl HeapData {
+    fn view(&self) -> ValueRef<'_> {
+        self.data.view()
+    }
+    /// Drop the payload as the collector frees it, crediting the state it
+    /// was charged to, whose account this gives back so the allocation can
+    /// go to the state's next value
+    fn free(&mut self) -> Option<Rc<Account>> {
+        let owner = self.owner.take();
+        if let (Some(owner), Some(kind)) = (&owner, self.data.kind()) {
+            owner.free(kind, self.data.size());
+        }
+        self.data = ValueData::Nil;
+        owner
+    }
+}
+
+/// A borrowed view of the payload of a `Value`
+enum ValueRef<'a> {
+    Nil,
+    Boolean(&'a LuaBool),
+    Number(&'a LuaNumber),
+    /// by value, since representations may store it in place of a pointer
+    Integer(LuaInteger),
+    String(&'a LuaString),
+    Function(&'a LuaFunction),
+    Interpreted(&'a interp::Closure),
+    Compiled(&'a vm::Closure),
+    Userdata(&'a LuaUserdata),
+    /// by value, since representations may store it in place of a pointer
+    LightUserdata(LightUserdata),
+    Thread(&'a vm::Coroutine),
+    Table(&'a LuaTable),
+    Frozen(&'a Arc<FrozenTable>),
+}
+impl<'a> ValueRef<'a> {
+    /// Pass each reference this holds to `visit`, for the collector
+    fn trace(self, visit: &mut dyn FnMut(Edge)) {
+        match self {
+            ValueRef::Table(table) => table.trace(visit),
+            ValueRef::Interpreted(closure) => closure.trace(visit),
+            ValueRef::Compiled(closure) => closure.trace(visit),
+            ValueRef::Userdata(userdata) => userdata.trace(visit),
+            ValueRef::Thread(coroutine) => coroutine.trace(visit),
+            _ => (),
+        }
+    }
+    fn finalizes(self) -> bool {
+        match self {
+            ValueRef::Table(table) => table.finalizes(),
+            ValueRef::Userdata(userdata) => userdata.finalizes(),
+            _ => false,
+        }
+    }
+    fn take_finalizer(self) -> bool {
+        match self {
+            ValueRef::Table(table) => table.take_finalizer(),
+            ValueRef::Userdata(userdata) => userdata.take_finalizer(),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Value {
+    repr: Repr,
+}
+
+/// A reference to a table, function or userdata that does not keep it alive
+#[derive(Clone)]
+pub struct WeakValue {
+    ty: Type,
+    data: WeakRef,
+}
+impl WeakValue {
+    /// Get the value back, unless it has been collected
+    pub fn upgrade(&self) -> Option<Value> {
+        self.data.upgrade()
+    }
+    pub fn type_of(&self) -> Type {
+        self.ty
+    }
+    /// The address the value had, which stays unique while this exists
+    pub(crate) fn addr(&self) -> usize {
+        self.data.addr()
+    }
+    /// Whether the value has not been collected
+    pub(crate) fn is_alive(&self) -> bool {
+        self.data.is_alive()
+    }
+}
+impl Value {
+    pub fn nil() -> Value {
+        Value::new(())
+    }
+    pub fn new<T>(val: T) -> Value
+    where
+        T: ConvertValue,
+    {
+        ConvertValue::into_value(val)
+    }
+    /// Create a string value from raw bytes
+    pub fn string<S>(bytes: S) -> Value
+    where
+        S: AsRef<[u8]>,
+    {
+        Value::new(LuaString::from(bytes.as_ref()))
+    }
+    /// Create a function value from a Rust closure, which borrows its
+    /// arguments and can return one `Value` or a `MultiValue`
+    pub fn function<F, R>(func: F) -> Value
+    where
+        F: Fn(&[Value]) -> Result<R> + 'static,
+        R: Into<MultiValue>,
+    {
+        let func = move |args: &[Value]| func(args).map(Into::into);
+        Value::new(Box::new(func) as LuaFunction)
+    }
+    /// Create a userdata value from a Rust value, with the methods its type
+    /// declares
+    pub fn userdata<T>(data: T) -> Value
+    where
+        T: UserData,
+    {
+        Value::new(AnyUserData::with_methods(data))
+    }
+    pub(crate) fn interpreted(closure: interp::Closure) -> Value {
+        Value::from_data(ValueData::Interpreted(closure))
+    }
+    pub(crate) fn as_interpreted(&self) -> Option<&interp::Closure> {
+        match self.repr.get() {
+            ValueRef::Interpreted(closure) => Some(closure),
+            _ => None,
+        }
+    }
+    pub(crate) fn compiled(closure: vm::Closure) -> Value {
+        Value::from_data(ValueData::Compiled(closure))
+    }
+    pub(crate) fn as_compiled(&self) -> Option<&vm::Closure> {
+        match self.repr.get() {
+            ValueRef::Compiled(closure) => Some(closure),
+            _ => None,
+        }
+    }
+    pub(crate) fn thread(coroutine: vm::Coroutine) -> Value {
+        Value::from_data(ValueData::Thread(coroutine))
+    }
+    pub(crate) fn as_thread(&self) -> Option<&vm::Coroutine> {
+        match self.repr.get() {
+            ValueRef::Thread(coroutine) => Some(coroutine),
+            _ => None,
+        }
+    }
+    /// Borrow the Rust value inside a userdata, if it is a `T`
+    ///
+    /// This gives `None` while the value is mutably borrowed, such as by a
+    /// `downcast_mut` further up the stack.
+    pub fn downcast_ref<T>(&self) -> Option<Ref<'_, T>>
+    where
+        T: Any,
+    {
+        LuaUserdata::from_value(self)?.downcast_ref()
+    }
+    /// Mutably borrow the Rust value inside a userdata, if it is a `T` and
+    /// not already borrowed
+    pub fn downcast_mut<T>(&self) -> Option<RefMut<'_, T>>
+    where
+        T: Any,
+    {
+        LuaUserdata::from_value(self)?.downcast_mut()
+    }
+    /// Create a light userdata value, which is a bare pointer-sized handle
+    /// with no metatable
+    pub fn light_userdata(handle: LightUserdata) -> Value {
+        Value::from_data(ValueData::LightUserdata(handle))
+    }
+    /// The handle inside a light userdata
+    pub fn as_light_userdata(&self) -> Option<LightUserdata> {
+        match self.repr.get() {
+            ValueRef::LightUserdata(handle) => Some(handle),
+            _ => None,
+        }
+    }
+    fn from_data(data: ValueData) -> Value {
+        Value {
+            repr: Repr::new(data),
+        }
+    }
+    pub fn type_of(&self) -> Type {
+        match self.repr.get() {
+            ValueRef::Nil => Type::Nil,
+            ValueRef::Boolean(_) => Type::Boolean,
+            ValueRef::Number(_) | ValueRef::Integer(_) => Type::Number,
+            ValueRef::String(_) => Type::String,
+            ValueRef::Function(_) | ValueRef::Interpreted(_) | ValueRef::Compiled(_) => {
+                Type::Function
+            }
+            ValueRef::Userdata(_) | ValueRef::LightUserdata(_) => Type::Userdata,
+            ValueRef::Thread(_) => Type::Thread,
+            ValueRef::Table(_) | ValueRef::Frozen(_) => Type::Table,
+        }
+    }
+    pub fn is_index(&self) -> bool {
+        match self.repr.get() {
+            ValueRef::Nil => false,
+            ValueRef::Number(num) => !num.is_nan(),
+            _ => true,
+        }
+    }
+    pub fn to_bool(&self) -> bool {
+        match self.repr.get() {
+            ValueRef::Nil => false,
+            ValueRef::Boolean(&val) => val,
+            _ => true,
+        }
+    }
+    pub fn is_nil(&self) -> bool {
+        matches!(self.repr.get(), ValueRef::Nil)
+    }
+    /// Index a table without invoking metamethods, giving nil for other types
+    pub fn raw_get(&self, key: &Value) -> Value {
+        match self.repr.get() {
+            ValueRef::Table(table) => table.get(key),
+            ValueRef::Frozen(table) => table.get(key),
+            _ => Value::nil(),
+        }
+    }
+    /// Assign into a table without invoking metamethods
+    pub fn raw_set(&self, key: Value, val: Value) -> Result<()> {
+        match self.repr.get() {
+            ValueRef::Table(table) => table.set(key, val),
+            ValueRef::Frozen(_) => Err(Error::Runtime(
+                "attempt to modify a frozen table".to_string(),
+            )),
+            _ => Err(meta::index_error(self)),
+        }
+    }
+    /// The entry after `key` in a table, as `next` gives it, without
+    /// invoking metamethods
+    pub(crate) fn raw_next(&self, key: &Value) -> Result<Option<(Value, Value)>> {
+        match self.repr.get() {
+            ValueRef::Table(table) => table.next(key),
+            ValueRef::Frozen(table) => table.next(key),
+            _ => Err(meta::index_error(self)),
+        }
+    }
+    /// Equality without metamethods, where numbers compare mathematically
+    /// (so NaN is unequal to itself) and reference types by identity
+    pub fn raw_equal(&self, other: &Value) -> bool {
+        match (self.number_value(), other.number_value()) {
+            (Some(a), Some(b)) => number::cmp(a, b) == Some(Ordering::Equal),
+            _ => self == other,
+        }
+    }
+    /// The length of a string or table without invoking `__len`, or `None`
+    /// for other types
+    pub fn raw_len(&self) -> Option<LuaInteger> {
+        match self.repr.get() {
+            ValueRef::String(bytes) => Some(bytes.len() as LuaInteger),
+            ValueRef::Table(table) => Some(table.len()),
+            ValueRef::Frozen(table) => Some(table.len()),
+            _ => None,
+        }
+    }
+    /// The metatable of a table or userdata, if it has one
+    pub fn get_metatable(&self) -> Option<Value> {
+        match self.repr.get() {
+            ValueRef::Table(table) => table.metatable(),
+            ValueRef::Userdata(userdata) => userdata.metatable(),
+            _ => None,
+        }
+    }
+    /// Stop a table or userdata from being finalized when collected, giving
+    /// whether it would have been
+    pub(crate) fn take_finalizer(&self) -> bool {
+        self.repr.get().take_finalizer()
+    }
+    /// Set or clear the metatable of a table or userdata
+    pub fn set_metatable(&self, metatable: Option<Value>) -> Result<()> {
+        if let Some(ref mt) = metatable {
+            if mt.type_of() != Type::Table {
+                return Err(Error::Runtime(format!(
+                    "metatable must be a table, not a {}",
+                    mt.type_of()
+                )));
+            }
+        }
+        match self.repr.get() {
+            ValueRef::Table(table) => {
+                table.set_metatable(metatable);
+                if table.finalizes() {
+                    gc::track(self);
+                }
+                Ok(())
+            }
+            ValueRef::Userdata(userdata) => {
+                userdata.set_metatable(metatable);
+                if userdata.finalizes() {
+                    gc::track(self);
+                }
+                Ok(())
+            }
+            ValueRef::Frozen(_) => Err(Error::Runtime(
+                "cannot set the metatable of a frozen table".to_string(),
+            )),
+            _ => Err(Error::Runtime(format!(
+                "cannot set the metatable of a {} value",
+                self.type_of()
+            ))),
+        }
+    }
+    /// Whether this is a number with the integer subtype
+    pub fn is_integer(&self) -> bool {
+        matches!(self.repr.get(), ValueRef::Integer(_))
+    }
+    /// Coerce into number
+    pub fn as_number(&self) -> LuaNumber {
+        self.to_number().map_or(LUA_NAN, Number::to_float)
+    }
+    /// Coerce into an integer, which succeeds for integers, floats with an
+    /// exact integer value, and strings convertible to either
+    pub fn as_integer(&self) -> Option<LuaInteger> {
+        match self.to_number()? {
+            Number::Int(i) => Some(i),
+            Number::Float(f) => number::float_to_int(f),
+        }
+    }
+    /// Get either number subtype, without converting strings
+    pub(crate) fn number_value(&self) -> Option<Number> {
+        match self.repr.get() {
+            ValueRef::Number(&num) => Some(Number::Float(num)),
+            ValueRef::Integer(num) => Some(Number::Int(num)),
+            _ => None,
+        }
+    }
+    /// Coerce into either number subtype, converting strings
+    fn to_number(&self) -> Option<Number> {
+        match self.repr.get() {
+            ValueRef::Number(&num) => Some(Number::Float(num)),
+            ValueRef::Integer(num) => Some(Number::Int(num)),
+            ValueRef::String(bytes) => str::from_utf8(bytes).ok().and_then(number::parse),
+            _ => None,
+        }
+    }
+    /// A weak reference to this value, for the types that can be collected
+    /// (tables, functions, full userdata and threads)
+    pub fn downgrade(&self) -> Option<WeakValue> {
+        if !self.is_collectable() {
+            return None;
+        }
+        Some(WeakValue {
+            ty: self.type_of(),
+            data: WeakRef::new(self.repr.object()?),
+        })
+    }
+    /// Whether this is a table, function, full userdata or thread, which
+    /// are the types that are identified by their address
+    pub(crate) fn is_collectable(&self) -> bool {
+        matches!(
+            self.repr.get(),
+            ValueRef::Table(_)
+                | ValueRef::Function(_)
+                | ValueRef::Interpreted(_)
+                | ValueRef::Compiled(_)
+                | ValueRef::Userdata(_)
+                | ValueRef::Thread(_)
+        )
+    }
+    /// The address of the payload, which identifies reference types
+    ///
+    /// For a frozen table this is the address of the table itself, as
+    /// each value reading it has its own payload.
+    pub(crate) fn addr(&self) -> usize {
+        match self.repr.get() {
+            ValueRef::Frozen(table) => table.addr(),
+            _ => self.repr.addr(),
+        }
+    }
+}
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.repr.get() {
+            ValueRef::Nil => f.write_str("nil"),
+            ValueRef::Boolean(val) => fmt::Display::fmt(val, f),
+            ValueRef::Number(&num) => number::fmt_float(num, f),
+            ValueRef::Integer(num) => fmt::Display::fmt(&num, f),
+            ValueRef::String(bytes) => f.write_str(&String::from_utf8_lossy(bytes)),
+            ValueRef::LightUserdata(handle) => write!(f, "userdata: {:#x}", handle.0),
+            _ => write!(f, "{}: {:#x}", self.type_of(), self.addr()),
+        }
+    }
+}
+impl fmt::Debug for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.repr.get() {
+            ValueRef::String(bytes) => write!(f, "{:?}", String::from_utf8_lossy(bytes)),
+            _ => fmt::Display::fmt(self, f),
+        }
+    }
+}
+impl Eq for Value {}
+impl Hash for Value {
+    fn hash<H>(&self, state: &mut H)
+    where
+        H: Hasher,
+    {
+        self.type_of().hash(state);
+        match self.repr.get() {
+            ValueRef::Nil => (),
+            ValueRef::Boolean(val) => val.hash(state),
+            // floats equal to an integer must hash like that integer
+            ValueRef::Integer(num) => num.hash(state),
+            ValueRef::Number(&num) => match number::float_to_int(num) {
+                Some(num) => num.hash(state),
+                None => num.to_bits().hash(state),
+            },
+            ValueRef::String(bytes) => bytes.hash(state),
+            ValueRef::LightUserdata(handle) => handle.hash(state),
+            _ => self.addr().hash(state),
+        }
+    }
+}
+
+/// Raw equality, as used for table keys
+impl PartialEq for Value {
+    fn eq(&self, other: &Value) -> bool {
+        match (self.repr.get(), other.repr.get()) {
+            (ValueRef::Nil, ValueRef::Nil) => true,
+            (ValueRef::Boolean(a), ValueRef::Boolean(b)) => a == b,
+            // NaN never appears as a key, but `Eq` needs it equal to itself
+            (ValueRef::Number(a), ValueRef::Number(b)) => a == b || a.to_bits() == b.to_bits(),
+            (ValueRef::Integer(a), ValueRef::Integer(b)) => a == b,
+            (ValueRef::Integer(a), ValueRef::Number(&b))
+            | (ValueRef::Number(&b), ValueRef::Integer(a)) => {
+                number::cmp_int_float(a, b) == Some(Ordering::Equal)
+            }
+            // interned strings share an allocation, which is cheap to check
+            (ValueRef::String(a), ValueRef::String(b)) => self.addr() == other.addr() || a == b,
+            (ValueRef::LightUserdata(a), ValueRef::LightUserdata(b)) => a == b,
+            (ValueRef::Frozen(a), ValueRef::Frozen(b)) => Arc::ptr_eq(a, b),
+            // reference types compare by identity
+            _ => {
+                self.is_collectable()
+                    && self.type_of() == other.type_of()
+                    && self.addr() == other.addr()
+            }
+        }
+    }
+}
@@ -0,0 +1,190 @@
+//! Lists of values, as functions return them
+
+use std::iter::FromIterator;
+use std::ops::{Deref, DerefMut};
+use std::{iter, mem, slice, vec};
+
+use super::Value;
+
+/// The values a function returns, in order
+///
+/// Where only one value fits, such as in an arithmetic expression, the
+/// first is used, or nil if there are none. Most functions return one
+/// value, which is stored in place so that returning it doesn't allocate,
+/// while keeping a `MultiValue` as small as a `Vec`.
+#[derive(Clone, Debug)]
+pub struct MultiValue(Repr);
+
+#[derive(Clone, Debug)]
+enum Repr {
+    One(Value),
+    /// any other number of values, where none doesn't allocate either
+    Many(Vec<Value>),
+}
+
+impl MultiValue {
+    pub fn new() -> MultiValue {
+        MultiValue(Repr::Many(Vec::new()))
+    }
+    /// The first value, or nil if there are none
+    pub fn into_first(self) -> Value {
+        match self.0 {
+            Repr::One(val) => val,
+            Repr::Many(vals) => vals.into_iter().next().unwrap_or_else(Value::nil),
+        }
+    }
+    pub fn into_vec(self) -> Vec<Value> {
+        match self.0 {
+            Repr::One(val) => vec![val],
+            Repr::Many(vals) => vals,
+        }
+    }
+    /// The values as a `Vec`, which they are moved to if there is one
+    fn many(&mut self) -> &mut Vec<Value> {
+        if let Repr::One(_) = self.0 {
+            let vals = mem::take(self).into_vec();
+            self.0 = Repr::Many(vals);
+        }
+        match self.0 {
+            Repr::Many(ref mut vals) => vals,
+            Repr::One(_) => unreachable!("just moved to a Vec"),
+        }
+    }
+    pub fn push(&mut self, val: Value) {
+        match self.0 {
+            Repr::Many(ref vals) if vals.capacity() == 0 => self.0 = Repr::One(val),
+            _ => self.many().push(val),
+        }
+    }
+    pub fn insert(&mut self, index: usize, val: Value) {
+        self.many().insert(index, val);
+    }
+    /// Keep the first `len` values, dropping the rest
+    pub fn truncate(&mut self, len: usize) {
+        match self.0 {
+            Repr::One(_) if len == 0 => *self = MultiValue::new(),
+            Repr::One(_) => (),
+            Repr::Many(ref mut vals) => vals.truncate(len),
+        }
+    }
+    /// Keep exactly `len` values, dropping the rest or adding `val`s
+    pub fn resize(&mut self, len: usize, val: Value) {
+        match self.0 {
+            Repr::One(_) if len == 1 => (),
+            Repr::Many(ref vals) if len == 1 && vals.is_empty() => self.0 = Repr::One(val),
+            _ if len <= 1 => self.truncate(len),
+            _ => self.many().resize(len, val),
+        }
+    }
+}
+impl Default for MultiValue {
+    fn default() -> MultiValue {
+        MultiValue::new()
+    }
+}
+impl PartialEq for MultiValue {
+    fn eq(&self, other: &MultiValue) -> bool {
+        **self == **other
+    }
+}
+impl Deref for MultiValue {
+    type Target = [Value];
+    fn deref(&self) -> &[Value] {
+        match self.0 {
+            Repr::One(ref val) => slice::from_ref(val),
+            Repr::Many(ref vals) => vals,
+        }
+    }
+}
+impl DerefMut for MultiValue {
+    fn deref_mut(&mut self) -> &mut [Value] {
+        match self.0 {
+            Repr::One(ref mut val) => slice::from_mut(val),
+            Repr::Many(ref mut vals) => vals,
+        }
+    }
+}
+impl From<Value> for MultiValue {
+    fn from(val: Value) -> MultiValue {
+        MultiValue(Repr::One(val))
+    }
+}
+impl From<Vec<Value>> for MultiValue {
+    fn from(vals: Vec<Value>) -> MultiValue {
+        MultiValue(Repr::Many(vals))
+    }
+}
+impl FromIterator<Value> for MultiValue {
+    fn from_iter<I>(iter: I) -> MultiValue
+    where
+        I: IntoIterator<Item = Value>,
+    {
+        let mut vals = MultiValue::new();
+        vals.extend(iter);
+        vals
+    }
+}
+impl Extend<Value> for MultiValue {
+    fn extend<I>(&mut self, iter: I)
+    where
+        I: IntoIterator<Item = Value>,
+    {
+        let iter = iter.into_iter();
+        if iter.size_hint().0 > 1 {
+            return self.many().extend(iter);
+        }
+        for val in iter {
+            self.push(val);
+        }
+    }
+}
+
+/// The values of a `MultiValue`, moved out in order
+pub struct IntoIter(IterRepr);
+
+enum IterRepr {
+    One(iter::Once<Value>),
+    Many(vec::IntoIter<Value>),
+}
+impl Iterator for IntoIter {
+    type Item = Value;
+    fn next(&mut self) -> Option<Value> {
+        match self.0 {
+            IterRepr::One(ref mut vals) => vals.next(),
+            IterRepr::Many(ref mut vals) => vals.next(),
+        }
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        match self.0 {
+            IterRepr::One(ref vals) => vals.size_hint(),
+            IterRepr::Many(ref vals) => vals.size_hint(),
+        }
+    }
+}
+impl ExactSizeIterator for IntoIter {}
+impl DoubleEndedIterator for IntoIter {
+    fn next_back(&mut self) -> Option<Value> {
+        match self.0 {
+            IterRepr::One(ref mut vals) => vals.next_back(),
+            IterRepr::Many(ref mut vals) => vals.next_back(),
+        }
+    }
+}
+
+impl IntoIterator for MultiValue {
+    type Item = Value;
+    type IntoIter = IntoIter;
+    fn into_iter(self) -> IntoIter {
+        IntoIter(match self.0 {
+            Repr::One(val) => IterRepr::One(iter::once(val)),
+            Repr::Many(vals) => IterRepr::Many(vals.into_iter()),
+        })
+    }
+}
+impl<'a> IntoIterator for &'a MultiValue {
+    type Item = &'a Value;
+    type IntoIter = slice::Iter<'a, Value>;
+    fn into_iter(self) -> slice::Iter<'a, Value> {
+        self.iter()
+    }
+}
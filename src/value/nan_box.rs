@@ -0,0 +1,122 @@
+//! A NaN-boxed representation that fits every value in 8 bytes
+//!
+//! Floats are stored as their own bits, nil and booleans as reserved quiet
+//! NaN patterns, integers and light userdata in the NaN payload when they fit
+//! in 48 bits, and everything else (including larger integers) as a pointer
+//! to its payload on the heap in the NaN payload.
+
+use std::marker::PhantomData;
+
+use super::{GcBox, LightUserdata, LuaInteger, LuaNumber, Object, ValueData, ValueRef};
+
+#[cfg(not(target_pointer_width = "64"))]
+compile_error!("the \"nan-boxing\" feature requires a 64-bit target");
+
+const TAG_MASK: u64 = 0xFFFF_0000_0000_0000;
+const PAYLOAD_MASK: u64 = !TAG_MASK;
+const SIGN_BIT: u64 = 1 << 63;
+/// Tag for a pointer to a payload on the heap
+const TAG_HEAP: u64 = 0x7FFC_0000_0000_0000;
+/// Tag for nil and booleans, which are told apart by their payload
+const TAG_IMMEDIATE: u64 = 0x7FFD_0000_0000_0000;
+const NIL: u64 = TAG_IMMEDIATE;
+const FALSE: u64 = TAG_IMMEDIATE | 1;
+const TRUE: u64 = TAG_IMMEDIATE | 2;
+/// Tag for a light userdata, unless its handle needs more than 48 bits
+const TAG_LIGHT: u64 = 0x7FFE_0000_0000_0000;
+/// Tag for an integer, sign-extended from the 48-bit payload
+const TAG_INT: u64 = 0x7FFF_0000_0000_0000;
+/// How far a payload is shifted to sign-extend it
+const PAYLOAD_SHIFT: u32 = 16;
+/// What every NaN is stored as (keeping its sign), so none collide with tags
+const CANONICAL_NAN: u64 = 0x7FF8_0000_0000_0000;
+
+pub struct Repr {
+    bits: u64,
+    /// heap payloads are counted without atomics, so this must not be sent
+    /// between threads
+    _marker: PhantomData<*const GcBox>,
+}
+impl Repr {
+    pub fn new(data: ValueData) -> Repr {
+        let bits = match data {
+            ValueData::Nil => NIL,
+            ValueData::Boolean(false) => FALSE,
+            ValueData::Boolean(true) => TRUE,
+            ValueData::Number(num) if num.is_nan() => (num.to_bits() & SIGN_BIT) | CANONICAL_NAN,
+            ValueData::Number(num) => num.to_bits(),
+            ValueData::Integer(num) if Repr::fits(num) => TAG_INT | (num as u64 & PAYLOAD_MASK),
+            ValueData::LightUserdata(handle) if handle.0 as u64 & TAG_MASK == 0 => {
+                TAG_LIGHT | handle.0 as u64
+            }
+            data => return Repr::from_object(data.into_heap()),
+        };
+        Repr {
+            bits,
+            _marker: PhantomData,
+        }
+    }
+    /// Whether an integer survives being sign-extended from 48 bits
+    fn fits(num: LuaInteger) -> bool {
+        (num << PAYLOAD_SHIFT) >> PAYLOAD_SHIFT == num
+    }
+    /// Refer to `object`, which has already counted this
+    pub fn from_object(object: Object) -> Repr {
+        let ptr = object.as_ptr() as u64;
+        assert_eq!(ptr & TAG_MASK, 0, "pointer does not fit in a NaN payload");
+        Repr {
+            bits: TAG_HEAP | ptr,
+            _marker: PhantomData,
+        }
+    }
+    /// The payload on the heap, if there is one
+    pub fn object(&self) -> Option<Object> {
+        if self.bits & TAG_MASK == TAG_HEAP {
+            // the pointer came from `from_object`, and is counted as in use
+            Some(unsafe { Object::from_ptr((self.bits & PAYLOAD_MASK) as *const GcBox) })
+        } else {
+            None
+        }
+    }
+    pub fn get(&self) -> ValueRef<'_> {
+        if self.bits & TAG_MASK == TAG_HEAP {
+            // this counts as referring to the payload, which keeps it alive
+            let ptr = (self.bits & PAYLOAD_MASK) as *const GcBox;
+            return unsafe { Object::data_at(ptr) }.view();
+        }
+        match self.bits {
+            NIL => ValueRef::Nil,
+            FALSE => ValueRef::Boolean(&false),
+            TRUE => ValueRef::Boolean(&true),
+            bits if bits & TAG_MASK == TAG_INT => {
+                ValueRef::Integer(((bits << PAYLOAD_SHIFT) as LuaInteger) >> PAYLOAD_SHIFT)
+            }
+            bits if bits & TAG_MASK == TAG_LIGHT => {
+                ValueRef::LightUserdata(LightUserdata((bits & PAYLOAD_MASK) as usize))
+            }
+            // every other bit pattern is a float, and `u64` and `f64` share a layout
+            _ => ValueRef::Number(unsafe { &*(&self.bits as *const u64 as *const LuaNumber) }),
+        }
+    }
+    pub fn addr(&self) -> usize {
+        (self.bits & PAYLOAD_MASK) as usize
+    }
+}
+impl Clone for Repr {
+    fn clone(&self) -> Repr {
+        if let Some(object) = self.object() {
+            object.retain();
+        }
+        Repr {
+            bits: self.bits,
+            _marker: PhantomData,
+        }
+    }
+}
+impl Drop for Repr {
+    fn drop(&mut self) {
+        if let Some(object) = self.object() {
+            object.release();
+        }
+    }
+}
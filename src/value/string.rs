@@ -0,0 +1,144 @@
+//! The bytes of string values, with short ones stored inline
+//!
+//! Most strings a script makes are short, like identifiers, table keys and
+//! the pieces of messages. Those are stored in the string itself, which
+//! lives in the same allocation as the rest of the value's payload, so
+//! creating one allocates once rather than twice.
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+
+/// The longest string stored inline, which on 64-bit targets keeps a
+/// `LuaString` as small as a boxed slice and a tag
+const INLINE_LEN: usize = 22;
+
+/// An immutable string of bytes, which is what Lua strings are made of
+#[derive(Clone)]
+pub struct LuaString(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Inline {
+        len: u8,
+        bytes: [u8; INLINE_LEN],
+    },
+    Heap(Box<[u8]>),
+    /// the bytes of a frozen string, which other states can share
+    Shared(Arc<[u8]>),
+}
+
+impl LuaString {
+    pub fn as_bytes(&self) -> &[u8] {
+        match self.0 {
+            Repr::Inline { len, ref bytes } => &bytes[..len as usize],
+            Repr::Heap(ref bytes) => bytes,
+            Repr::Shared(ref bytes) => bytes,
+        }
+    }
+    /// How many bytes this takes up on the heap beyond itself, which is
+    /// nothing for short strings, or for shared ones as no one state owns
+    /// them
+    pub(crate) fn heap_size(&self) -> usize {
+        match self.0 {
+            Repr::Inline { .. } | Repr::Shared(_) => 0,
+            Repr::Heap(ref bytes) => bytes.len(),
+        }
+    }
+    /// The bytes, in an allocation that can be shared between threads,
+    /// which is copied to unless they are already shared
+    pub(crate) fn shared(&self) -> Arc<[u8]> {
+        match self.0 {
+            Repr::Shared(ref bytes) => bytes.clone(),
+            _ => Arc::from(self.as_bytes()),
+        }
+    }
+    /// Store `bytes` inline, if they are short enough
+    fn inline(bytes: &[u8]) -> Option<LuaString> {
+        if bytes.len() > INLINE_LEN {
+            return None;
+        }
+        let mut inline = [0; INLINE_LEN];
+        inline[..bytes.len()].copy_from_slice(bytes);
+        Some(LuaString(Repr::Inline {
+            len: bytes.len() as u8,
+            bytes: inline,
+        }))
+    }
+}
+impl<'a> From<&'a [u8]> for LuaString {
+    fn from(bytes: &'a [u8]) -> LuaString {
+        LuaString::inline(bytes).unwrap_or_else(|| LuaString(Repr::Heap(bytes.into())))
+    }
+}
+impl<'a> From<&'a str> for LuaString {
+    fn from(s: &'a str) -> LuaString {
+        LuaString::from(s.as_bytes())
+    }
+}
+impl From<Vec<u8>> for LuaString {
+    fn from(bytes: Vec<u8>) -> LuaString {
+        LuaString::inline(&bytes).unwrap_or_else(|| LuaString(Repr::Heap(bytes.into())))
+    }
+}
+impl From<Box<[u8]>> for LuaString {
+    fn from(bytes: Box<[u8]>) -> LuaString {
+        LuaString::inline(&bytes).unwrap_or(LuaString(Repr::Heap(bytes)))
+    }
+}
+/// Shares the bytes if they are too long to store inline
+impl From<Arc<[u8]>> for LuaString {
+    fn from(bytes: Arc<[u8]>) -> LuaString {
+        LuaString::inline(&bytes).unwrap_or(LuaString(Repr::Shared(bytes)))
+    }
+}
+impl Deref for LuaString {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+impl AsRef<[u8]> for LuaString {
+    fn as_ref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+/// So that tables of strings can be looked up by bytes
+impl Borrow<[u8]> for LuaString {
+    fn borrow(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+impl PartialEq for LuaString {
+    fn eq(&self, other: &LuaString) -> bool {
+        self.as_bytes() == other.as_bytes()
+    }
+}
+impl Eq for LuaString {}
+impl PartialOrd for LuaString {
+    fn partial_cmp(&self, other: &LuaString) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for LuaString {
+    fn cmp(&self, other: &LuaString) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
+    }
+}
+/// Hashed as its bytes, as `Borrow` needs
+impl Hash for LuaString {
+    fn hash<H>(&self, state: &mut H)
+    where
+        H: Hasher,
+    {
+        self.as_bytes().hash(state);
+    }
+}
+impl fmt::Debug for LuaString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", String::from_utf8_lossy(self))
+    }
+}
@@ -0,0 +1,76 @@
+//! The default representation, which stores nil, booleans, numbers and
+//! light userdata inline and only points to the payloads of reference
+//! types on the heap
+
+use super::{LightUserdata, LuaBool, LuaInteger, LuaNumber, Object, ValueData, ValueRef};
+
+pub enum Repr {
+    Nil,
+    Boolean(LuaBool),
+    Number(LuaNumber),
+    Integer(LuaInteger),
+    LightUserdata(LightUserdata),
+    /// counted as referring to the payload until it is dropped
+    Heap(Object),
+}
+impl Repr {
+    pub fn new(data: ValueData) -> Repr {
+        match data {
+            ValueData::Nil => Repr::Nil,
+            ValueData::Boolean(val) => Repr::Boolean(val),
+            ValueData::Number(val) => Repr::Number(val),
+            ValueData::Integer(val) => Repr::Integer(val),
+            ValueData::LightUserdata(val) => Repr::LightUserdata(val),
+            data => Repr::from_object(data.into_heap()),
+        }
+    }
+    pub fn get(&self) -> ValueRef<'_> {
+        match *self {
+            Repr::Nil => ValueRef::Nil,
+            Repr::Boolean(ref val) => ValueRef::Boolean(val),
+            Repr::Number(ref val) => ValueRef::Number(val),
+            Repr::Integer(val) => ValueRef::Integer(val),
+            Repr::LightUserdata(val) => ValueRef::LightUserdata(val),
+            Repr::Heap(ref object) => object.data().view(),
+        }
+    }
+    /// Refer to `object`, which has already counted this
+    pub fn from_object(object: Object) -> Repr {
+        Repr::Heap(object)
+    }
+    /// The payload on the heap, if there is one
+    pub fn object(&self) -> Option<Object> {
+        match *self {
+            Repr::Heap(object) => Some(object),
+            _ => None,
+        }
+    }
+    pub fn addr(&self) -> usize {
+        match *self {
+            Repr::Heap(object) => object.addr(),
+            _ => 0,
+        }
+    }
+}
+impl Clone for Repr {
+    fn clone(&self) -> Repr {
+        match *self {
+            Repr::Nil => Repr::Nil,
+            Repr::Boolean(val) => Repr::Boolean(val),
+            Repr::Number(val) => Repr::Number(val),
+            Repr::Integer(val) => Repr::Integer(val),
+            Repr::LightUserdata(val) => Repr::LightUserdata(val),
+            Repr::Heap(object) => {
+                object.retain();
+                Repr::Heap(object)
+            }
+        }
+    }
+}
+impl Drop for Repr {
+    fn drop(&mut self) {
+        if let Repr::Heap(object) = *self {
+            object.release();
+        }
+    }
+}
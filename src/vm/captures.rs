@@ -0,0 +1,227 @@
+//! Finding the locals of a function that functions nested in it use, which
+//! the compiler keeps in cells rather than registers
+//!
+//! This has to be known when a local is declared, before the functions that
+//! capture it are reached, so each function is scanned before it is
+//! compiled, with names resolved the way the compiler resolves them. The
+//! interpreter scans the same way for the names a function uses from
+//! enclosing ones, which are all it captures.
+
+use std::collections::HashSet;
+
+use ast::{Ast, Block, Expr, ExprKind, FieldKind, FuncBody, Name, Stat, StatKind};
+
+/// The declarations of captured locals, told apart by where their names
+/// are in the syntax tree, since one name can be declared many times
+pub type Captured = HashSet<*const Name>;
+
+/// The captured locals among `params` and those declared in `body`, a
+/// block of `ast`
+pub fn captured(ast: &Ast, params: &[Name], body: &Block) -> Captured {
+    let mut scan = Scan::new(ast);
+    for param in params {
+        scan.declare(param);
+    }
+    scan.block(body);
+    scan.found
+}
+
+/// The names `func` and the functions nested in it use without declaring
+/// them, in the order they are first used, which are either variables of
+/// enclosing functions or globals
+pub fn free(ast: &Ast, func: &FuncBody) -> Vec<Name> {
+    let mut scan = Scan::new(ast);
+    scan.function(func);
+    scan.free
+}
+
+struct Scan<'a> {
+    ast: &'a Ast,
+    /// the locals in scope, each with how deeply nested its function is
+    scope: Vec<(&'a Name, usize)>,
+    /// how deeply nested the function being scanned is
+    depth: usize,
+    found: Captured,
+    /// the names used outside of the scope of any declaration
+    free: Vec<Name>,
+}
+impl<'a> Scan<'a> {
+    fn new(ast: &'a Ast) -> Scan<'a> {
+        Scan {
+            ast,
+            scope: Vec::new(),
+            depth: 0,
+            found: HashSet::new(),
+            free: Vec::new(),
+        }
+    }
+    fn declare(&mut self, name: &'a Name) {
+        self.scope.push((name, self.depth));
+    }
+    fn reference(&mut self, name: &Name) {
+        let decl = self.scope.iter().rev().find(|(decl, _)| *decl == name);
+        // only the outermost function's locals are wanted, as the nested
+        // ones are scanned again when they are compiled
+        match decl {
+            Some(&(decl, 0)) => {
+                if self.depth > 0 {
+                    self.found.insert(decl as *const Name);
+                }
+            }
+            Some(_) => {}
+            None => {
+                if !self.free.contains(name) {
+                    self.free.push(name.clone());
+                }
+            }
+        }
+    }
+    fn block(&mut self, block: &'a Block) {
+        let len = self.scope.len();
+        self.block_body(block);
+        self.scope.truncate(len);
+    }
+    fn block_body(&mut self, block: &'a Block) {
+        let ast = self.ast;
+        for stat in &ast[block.stats] {
+            self.stat(stat);
+        }
+        if let Some(ret) = block.ret {
+            self.exprs(&ast[ret]);
+        }
+    }
+    fn exprs(&mut self, exprs: &'a [Expr]) {
+        for expr in exprs {
+            self.expr(expr);
+        }
+    }
+    fn stat(&mut self, stat: &'a Stat) {
+        let ast = self.ast;
+        match stat.kind {
+            StatKind::Assign(targets, exprs) => {
+                self.exprs(&ast[targets]);
+                self.exprs(&ast[exprs]);
+            }
+            StatKind::Call(call) => self.expr(&ast[call]),
+            StatKind::Do(ref block) => self.block(block),
+            StatKind::While(cond, ref body) => {
+                self.expr(&ast[cond]);
+                self.block(body);
+            }
+            StatKind::Repeat(ref body, cond) => {
+                // the condition can see the body's locals
+                let len = self.scope.len();
+                self.block_body(body);
+                self.expr(&ast[cond]);
+                self.scope.truncate(len);
+            }
+            StatKind::If(branches, ref else_block) => {
+                for &(cond, ref block) in &ast[branches] {
+                    self.expr(&ast[cond]);
+                    self.block(block);
+                }
+                if let Some(ref block) = *else_block {
+                    self.block(block);
+                }
+            }
+            StatKind::NumericFor {
+                ref var,
+                start,
+                limit,
+                step,
+                ref body,
+            } => {
+                self.expr(&ast[start]);
+                self.expr(&ast[limit]);
+                if let Some(step) = step {
+                    self.expr(&ast[step]);
+                }
+                let len = self.scope.len();
+                self.declare(var);
+                self.block(body);
+                self.scope.truncate(len);
+            }
+            StatKind::GenericFor {
+                vars,
+                exprs,
+                ref body,
+            } => {
+                self.exprs(&ast[exprs]);
+                let len = self.scope.len();
+                for var in &ast[vars] {
+                    self.declare(var);
+                }
+                self.block(body);
+                self.scope.truncate(len);
+            }
+            StatKind::Function(ref name, func) => {
+                self.reference(&ast[name.path][0]);
+                self.function(&ast[func]);
+            }
+            StatKind::LocalFunction(ref name, func) => {
+                self.declare(name);
+                self.function(&ast[func]);
+            }
+            StatKind::Local(names, exprs) => {
+                self.exprs(&ast[exprs]);
+                for name in &ast[names] {
+                    self.declare(name);
+                }
+            }
+            StatKind::Break | StatKind::Goto(_) | StatKind::Label(_) => {}
+        }
+    }
+    fn function(&mut self, func: &'a FuncBody) {
+        let len = self.scope.len();
+        self.depth += 1;
+        for param in &self.ast[func.params] {
+            self.declare(param);
+        }
+        self.block(&func.body);
+        self.depth -= 1;
+        self.scope.truncate(len);
+    }
+    fn expr(&mut self, expr: &'a Expr) {
+        let ast = self.ast;
+        match expr.kind {
+            ExprKind::Nil
+            | ExprKind::True
+            | ExprKind::False
+            | ExprKind::Number(_)
+            | ExprKind::String(_)
+            | ExprKind::Vararg => {}
+            ExprKind::Function(func) => self.function(&ast[func]),
+            ExprKind::Table(fields) => {
+                for field in &ast[fields] {
+                    match field.kind {
+                        FieldKind::Named(_, val) | FieldKind::Positional(val) => {
+                            self.expr(&ast[val])
+                        }
+                        FieldKind::Indexed(key, val) => {
+                            self.expr(&ast[key]);
+                            self.expr(&ast[val]);
+                        }
+                    }
+                }
+            }
+            ExprKind::Name(ref name) => self.reference(name),
+            ExprKind::Index(obj, key) => {
+                self.expr(&ast[obj]);
+                self.expr(&ast[key]);
+            }
+            ExprKind::Call(func, args) => {
+                self.expr(&ast[func]);
+                self.exprs(&ast[args]);
+            }
+            ExprKind::Method(obj, _, args) => {
+                self.expr(&ast[obj]);
+                self.exprs(&ast[args]);
+            }
+            ExprKind::Paren(inner) | ExprKind::Unary(_, inner) => self.expr(&ast[inner]),
+            ExprKind::Binary(_, lhs, rhs) => {
+                self.expr(&ast[lhs]);
+                self.expr(&ast[rhs]);
+            }
+        }
+    }
+}
@@ -0,0 +1,1131 @@
+//! Compiles syntax trees into functions for the VM
+//!
+//! Locals live in registers numbered by the order they are declared in, and
+//! expressions are evaluated into the registers above them, which are freed
+//! again at the end of each statement.
+
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use super::captures::{captured, Captured};
+use super::fold::fold;
+use super::instr::{Instr, Reg, MAX_REGS, MULTI};
+use super::peephole;
+use super::{LocalInfo, Proto, UpvalInfo, UpvalSource};
+use ast::{
+    Ast, BinOp, Block, Expr, ExprKind, FieldKind, FuncBody, FuncId, FuncName, Name, Stat, StatKind,
+    UnOp,
+};
+use error::{Error, Result};
+use interp::constructor_size;
+use number::Number;
+use value::{ArithOp, ConvertValue, LuaBool, LuaInteger, LuaString, StringTable, Value};
+
+/// Pending positional table fields are stored in batches of this many
+const FIELDS_PER_FLUSH: usize = 50;
+
+/// Compile a chunk into the function that runs it, which takes any
+/// arguments as varargs, running the peephole optimizer over each function
+/// if `optimize` is set and interning string constants in `strings`
+pub fn compile(ast: &Ast, optimize: bool, strings: &StringTable) -> Result<Proto> {
+    let block = &ast[ast.main()].body;
+    let mut compiler = Compiler {
+        ast,
+        funcs: vec![FuncState::new(0, true, 0, captured(ast, &[], block))],
+        chunk: block.loc.chunk.clone(),
+        line: block.loc.pos.line,
+        optimize,
+        strings,
+        pool: HashMap::new(),
+    };
+    compiler.block(block)?;
+    compiler.emit(Instr::Return(0, 0));
+    let func = compiler.funcs.pop().expect("the main chunk is open");
+    Ok(compiler.finish(func))
+}
+
+/// Join compiled chunks into one named `chunk` that runs each in turn,
+/// as `luac` does when given several files
+pub fn combine(chunks: Vec<Proto>, chunk: &str) -> Proto {
+    let mut code = Vec::new();
+    for i in 0..chunks.len() {
+        code.push(Instr::Closure(0, i as u32));
+        code.push(Instr::Call(0, 0, 0));
+    }
+    code.push(Instr::Return(0, 0));
+    Proto {
+        lines: vec![0; code.len()],
+        code,
+        constants: Vec::new(),
+        protos: chunks.into_iter().map(Rc::new).collect(),
+        params: 0,
+        vararg: true,
+        max_regs: 1,
+        chunk: chunk.into(),
+        line: 0,
+        locals: Vec::new(),
+        upvals: Vec::new(),
+    }
+}
+
+/// Compile the function `func` of `ast` on its own, as the interpreter
+/// created it, where `scope` holds the names of the locals in scope where
+/// it was defined, innermost last, and each upvalue it uses is taken from
+/// there as `UpvalSource::Local` with its position in `scope`
+pub fn compile_function(
+    ast: &Ast,
+    func: FuncId,
+    scope: &[Name],
+    optimize: bool,
+    strings: &StringTable,
+) -> Result<Rc<Proto>> {
+    let mut outer = FuncState::new(0, false, 0, Captured::new());
+    for (reg, name) in scope.iter().enumerate() {
+        outer.locals.push(LocalInfo {
+            name: name.clone(),
+            reg: reg as Reg,
+            start: 0,
+            end: 0,
+            captured: true,
+        });
+        outer.active.push((name.clone(), reg));
+    }
+    let body = &ast[func];
+    let mut compiler = Compiler {
+        ast,
+        funcs: vec![outer],
+        chunk: body.loc.chunk.clone(),
+        line: body.loc.pos.line,
+        optimize,
+        strings,
+        pool: HashMap::new(),
+    };
+    if scope.len() > MAX_REGS {
+        return compiler.error("too many local variables".to_string());
+    }
+    compiler.function(body, 0)?;
+    let mut outer = compiler
+        .funcs
+        .pop()
+        .expect("the enclosing function is open");
+    Ok(outer.protos.pop().expect("the function was compiled"))
+}
+
+/// How constants are deduplicated, keeping apart values that compare equal
+/// but differ in subtype, such as `1` and `1.0`, or `0.0` and `-0.0`
+#[derive(Clone, PartialEq, Eq, Hash)]
+enum ConstKey {
+    Int(LuaInteger),
+    Float(Vec<u8>),
+    String(Vec<u8>),
+}
+
+/// Something that can be assigned, with its parts already evaluated
+enum Place {
+    Local(Reg),
+    /// a local kept in a cell
+    Cell(Reg),
+    Upval(u8),
+    Global(u32),
+    Index(Reg, Reg),
+}
+
+/// A block being compiled
+struct Scope {
+    /// how many locals were active before it
+    active: usize,
+    /// the `break` jumps to patch, for loops
+    breaks: Option<Vec<usize>>,
+    /// the labels declared so far, with where they are
+    labels: Vec<(Name, usize)>,
+    /// how many `goto`s were waiting for their label before it
+    gotos: usize,
+}
+
+/// A function being compiled
+struct FuncState {
+    code: Vec<Instr>,
+    lines: Vec<u32>,
+    constants: Vec<Value>,
+    const_index: HashMap<ConstKey, u32>,
+    protos: Vec<Rc<Proto>>,
+    params: u8,
+    vararg: bool,
+    /// the line it is defined on, which is 0 for a chunk
+    line: u32,
+    /// every local declared, for debug information
+    locals: Vec<LocalInfo>,
+    /// the locals in scope with their index in `locals`, where the
+    /// register of each is its position
+    active: Vec<(Name, usize)>,
+    /// the first register not holding a local or a temporary
+    free: usize,
+    max_regs: usize,
+    scopes: Vec<Scope>,
+    /// the jumps of `goto`s to labels further on, to patch once those are
+    /// reached
+    gotos: Vec<(Name, usize)>,
+    /// the declarations of locals that nested functions use
+    captured: Captured,
+    upvals: Vec<UpvalInfo>,
+}
+impl FuncState {
+    fn new(params: u8, vararg: bool, line: u32, captured: Captured) -> FuncState {
+        FuncState {
+            code: Vec::new(),
+            lines: Vec::new(),
+            constants: Vec::new(),
+            const_index: HashMap::new(),
+            protos: Vec::new(),
+            params,
+            vararg,
+            line,
+            locals: Vec::new(),
+            active: Vec::new(),
+            free: 0,
+            max_regs: 0,
+            scopes: Vec::new(),
+            gotos: Vec::new(),
+            captured,
+            upvals: Vec::new(),
+        }
+    }
+    fn finish(self, chunk: &Rc<str>) -> Proto {
+        Proto {
+            code: self.code,
+            lines: self.lines,
+            constants: self.constants,
+            protos: self.protos,
+            params: self.params,
+            vararg: self.vararg,
+            max_regs: self.max_regs,
+            chunk: chunk.clone(),
+            line: self.line,
+            locals: self.locals,
+            upvals: self.upvals,
+        }
+    }
+    fn local(&self, name: &str) -> Option<Reg> {
+        self.active
+            .iter()
+            .rposition(|(local, _)| &**local == name)
+            .map(|reg| reg as Reg)
+    }
+    fn is_captured(&self, reg: Reg) -> bool {
+        self.locals[self.active[reg as usize].1].captured
+    }
+}
+
+struct Compiler<'a> {
+    /// the tree of the chunk, which the nodes compiled are from
+    ast: &'a Ast,
+    /// the functions being compiled, innermost last
+    funcs: Vec<FuncState>,
+    chunk: Rc<str>,
+    /// the line that emitted instructions are attributed to
+    line: u32,
+    optimize: bool,
+    strings: &'a StringTable,
+    /// the constants used so far by any function of the chunk, which share
+    /// one value each
+    pool: HashMap<ConstKey, Value>,
+}
+impl<'a> Compiler<'a> {
+    fn func(&mut self) -> &mut FuncState {
+        self.funcs.last_mut().expect("a function is open")
+    }
+    fn finish(&self, func: FuncState) -> Proto {
+        let mut proto = func.finish(&self.chunk);
+        if self.optimize {
+            peephole::optimize(&mut proto);
+        }
+        proto
+    }
+    fn error<T>(&self, msg: String) -> Result<T> {
+        Err(Error::Syntax(format!(
+            "{}:{}: {}",
+            self.chunk, self.line, msg
+        )))
+    }
+    fn emit(&mut self, instr: Instr) -> usize {
+        let line = self.line;
+        let func = self.func();
+        func.code.push(instr);
+        func.lines.push(line);
+        func.code.len() - 1
+    }
+    /// The index of the next instruction
+    fn here(&mut self) -> usize {
+        self.func().code.len()
+    }
+    /// Emit a jump to an earlier instruction
+    fn jump_back(&mut self, target: usize) {
+        let offset = target as i32 - self.here() as i32 - 1;
+        self.emit(Instr::Jump(offset));
+    }
+    /// Point the jump at `at` to `target`
+    fn patch(&mut self, at: usize, target: usize) {
+        let offset = target as i32 - at as i32 - 1;
+        self.func().code[at].set_jump_offset(offset);
+    }
+    fn alloc(&mut self) -> Result<Reg> {
+        let reg = self.func().free;
+        if reg >= MAX_REGS {
+            return self.error("function or expression needs too many registers".to_string());
+        }
+        let func = self.func();
+        func.free += 1;
+        func.max_regs = func.max_regs.max(func.free);
+        Ok(reg as Reg)
+    }
+    /// Make sure a call has `count` registers from `reg` to work in
+    fn reserve(&mut self, reg: Reg, count: usize) -> Result<()> {
+        let end = reg as usize + count;
+        if end > MAX_REGS {
+            return self.error("function or expression needs too many registers".to_string());
+        }
+        let func = self.func();
+        func.max_regs = func.max_regs.max(end);
+        Ok(())
+    }
+    /// The index of a constant in the current function, which `make`
+    /// creates if no function of the chunk has used it yet
+    fn constant<F>(&mut self, key: ConstKey, make: F) -> Result<u32>
+    where
+        F: FnOnce(&StringTable) -> Value,
+    {
+        if let Some(&index) = self.func().const_index.get(&key) {
+            return Ok(index);
+        }
+        let index = self.func().constants.len();
+        if index > u32::MAX as usize {
+            return self.error("too many constants".to_string());
+        }
+        let strings = self.strings;
+        let val = self
+            .pool
+            .entry(key.clone())
+            .or_insert_with(|| make(strings))
+            .clone();
+        let func = self.func();
+        func.constants.push(val);
+        func.const_index.insert(key, index as u32);
+        Ok(index as u32)
+    }
+    fn number(&mut self, num: Number) -> Result<u32> {
+        match num {
+            Number::Int(i) => self.constant(ConstKey::Int(i), |_| i.into_value()),
+            Number::Float(f) => self.constant(ConstKey::Float(f.to_ne_bytes().to_vec()), |_| {
+                f.into_value()
+            }),
+        }
+    }
+    fn string(&mut self, bytes: &[u8]) -> Result<u32> {
+        self.constant(ConstKey::String(bytes.to_vec()), |strings| {
+            strings.intern(bytes)
+        })
+    }
+
+    fn open_scope(&mut self, is_loop: bool) {
+        let func = self.func();
+        let active = func.active.len();
+        let gotos = func.gotos.len();
+        func.scopes.push(Scope {
+            active,
+            breaks: if is_loop { Some(Vec::new()) } else { None },
+            labels: Vec::new(),
+            gotos,
+        });
+    }
+    /// End the innermost scope, giving the `break` jumps out of it
+    fn close_scope(&mut self) -> Vec<usize> {
+        let end = self.here();
+        let func = self.func();
+        let scope = func.scopes.pop().expect("a scope is open");
+        for (_, index) in func.active.drain(scope.active..) {
+            func.locals[index].end = end;
+        }
+        func.free = func.active.len();
+        scope.breaks.unwrap_or_default()
+    }
+    /// Bring the next local into scope, in the register after the others
+    fn activate(&mut self, name: &Name) {
+        let start = self.here();
+        let func = self.func();
+        let reg = func.active.len() as Reg;
+        let captured = func.captured.contains(&(name as *const Name));
+        func.locals.push(LocalInfo {
+            name: name.clone(),
+            reg,
+            start,
+            end: start,
+            captured,
+        });
+        func.active.push((name.clone(), func.locals.len() - 1));
+    }
+    /// Move the values of the active locals from register `first` on into
+    /// cells, for those that nested functions use
+    fn make_cells(&mut self, first: usize) {
+        for reg in first..self.func().active.len() {
+            if self.func().is_captured(reg as Reg) {
+                self.emit(Instr::NewCell(reg as Reg));
+            }
+        }
+    }
+
+    fn block(&mut self, block: &Block) -> Result<()> {
+        self.open_scope(false);
+        self.block_body(block)?;
+        self.close_scope();
+        Ok(())
+    }
+    /// Compile a block into the innermost scope
+    fn block_body(&mut self, block: &Block) -> Result<()> {
+        let ast = self.ast;
+        for stat in &ast[block.stats] {
+            self.stat(stat)?;
+        }
+        if let Some(exprs) = block.ret {
+            let exprs = &ast[exprs];
+            // a return's line is where its values start, as the parser
+            // doesn't keep where the keyword is
+            self.line = exprs
+                .first()
+                .map_or(block.loc.pos.line, |expr| expr.loc.pos.line);
+            let base = self.func().free as Reg;
+            match exprs.len() {
+                0 => {
+                    self.emit(Instr::Return(0, 0));
+                }
+                1 if exprs[0].is_call() => {
+                    let reg = self.alloc()?;
+                    self.multi(&exprs[0], reg, MULTI)?;
+                    let call = self.here() - 1;
+                    if let Instr::Call(a, b, MULTI) = self.func().code[call] {
+                        self.func().code[call] = Instr::TailCall(a, b);
+                    }
+                    self.emit(Instr::Return(reg, MULTI));
+                }
+                1 if !exprs[0].is_multi() => {
+                    let reg = self.expr_any(&exprs[0])?;
+                    self.emit(Instr::Return(reg, 1));
+                }
+                n => {
+                    let open = self.open_list(exprs)?;
+                    self.emit(Instr::Return(base, if open { MULTI } else { n as u8 }));
+                }
+            }
+            let func = self.func();
+            func.free = func.active.len();
+        }
+        Ok(())
+    }
+    fn stat(&mut self, stat: &Stat) -> Result<()> {
+        let ast = self.ast;
+        self.line = stat.loc.pos.line;
+        match stat.kind {
+            StatKind::Assign(targets, exprs) => {
+                let (targets, exprs) = (&ast[targets], &ast[exprs]);
+                let mut places = Vec::with_capacity(targets.len());
+                for target in targets {
+                    places.push(self.place(target)?);
+                }
+                self.copy_conflicts(&mut places)?;
+                let vals = self.expr_list(exprs, places.len())?;
+                for (place, val) in places.into_iter().zip(vals) {
+                    self.assign(place, val);
+                }
+            }
+            StatKind::Call(call) => {
+                let reg = self.alloc()?;
+                self.multi(&ast[call], reg, 0)?;
+            }
+            StatKind::Do(ref block) => self.block(block)?,
+            StatKind::While(cond, ref body) => {
+                let start = self.here();
+                let exit = self.jump_if_not(&ast[cond])?;
+                self.open_scope(true);
+                self.block_body(body)?;
+                let breaks = self.close_scope();
+                self.jump_back(start);
+                let end = self.here();
+                self.patch(exit, end);
+                self.patch_all(&breaks, end);
+            }
+            StatKind::Repeat(ref body, cond) => {
+                let cond = &ast[cond];
+                let start = self.here();
+                // the condition can see the body's locals
+                self.open_scope(true);
+                self.block_body(body)?;
+                self.line = cond.loc.pos.line;
+                let reg = self.expr_any(cond)?;
+                let offset = start as i32 - self.here() as i32 - 1;
+                self.emit(Instr::JumpIfNot(reg, offset));
+                let breaks = self.close_scope();
+                let end = self.here();
+                self.patch_all(&breaks, end);
+            }
+            StatKind::If(branches, ref else_block) => {
+                let mut exits = Vec::new();
+                for (i, &(cond, ref block)) in ast[branches].iter().enumerate() {
+                    let skip = self.jump_if_not(&ast[cond])?;
+                    self.block(block)?;
+                    if i + 1 < branches.len() || else_block.is_some() {
+                        exits.push(self.emit(Instr::Jump(0)));
+                    }
+                    let next = self.here();
+                    self.patch(skip, next);
+                }
+                if let Some(ref block) = *else_block {
+                    self.block(block)?;
+                }
+                let end = self.here();
+                self.patch_all(&exits, end);
+            }
+            StatKind::NumericFor {
+                ref var,
+                start,
+                limit,
+                step,
+                ref body,
+            } => {
+                self.open_scope(true);
+                let base = self.alloc()?;
+                self.expr(&ast[start], base)?;
+                let reg = self.alloc()?;
+                self.expr(&ast[limit], reg)?;
+                let reg = self.alloc()?;
+                match step {
+                    Some(step) => self.expr(&ast[step], reg)?,
+                    None => {
+                        let one = self.number(Number::Int(1))?;
+                        self.emit(Instr::LoadK(reg, one));
+                    }
+                }
+                let state: Name = Rc::from("(for state)");
+                for _ in 0..3 {
+                    self.activate(&state);
+                }
+                let prep = self.emit(Instr::ForPrep(base, 0));
+                self.open_scope(false);
+                let reg = self.alloc()?;
+                self.activate(var);
+                // each iteration has a new variable
+                let body_start = self.here();
+                self.make_cells(reg as usize);
+                self.block_body(body)?;
+                self.close_scope();
+                let offset = body_start as i32 - self.here() as i32 - 1;
+                self.emit(Instr::ForLoop(base, offset));
+                let breaks = self.close_scope();
+                let end = self.here();
+                self.patch(prep, end);
+                self.patch_all(&breaks, end);
+            }
+            StatKind::GenericFor {
+                vars,
+                exprs,
+                ref body,
+            } => {
+                let vars = &ast[vars];
+                self.open_scope(true);
+                let vals = self.expr_list(&ast[exprs], 3)?;
+                let base = vals[0];
+                let state: Name = Rc::from("(for state)");
+                for _ in 0..3 {
+                    self.activate(&state);
+                }
+                self.func().free = base as usize + 3;
+                // the iterator is called with its arguments copied above
+                // the state
+                self.reserve(base, 6)?;
+                let enter = self.emit(Instr::Jump(0));
+                self.open_scope(false);
+                for var in vars {
+                    self.alloc()?;
+                    self.activate(var);
+                }
+                let body_start = self.here();
+                self.make_cells(base as usize + 3);
+                self.block_body(body)?;
+                self.close_scope();
+                let call = self.here();
+                self.patch(enter, call);
+                self.emit(Instr::TForCall(base, vars.len() as u8));
+                let offset = body_start as i32 - self.here() as i32 - 1;
+                self.emit(Instr::TForLoop(base, offset));
+                let breaks = self.close_scope();
+                let end = self.here();
+                self.patch_all(&breaks, end);
+            }
+            StatKind::Function(ref name, func) => {
+                let reg = self.alloc()?;
+                self.function(&ast[func], reg)?;
+                self.assign_function(name, reg)?;
+            }
+            StatKind::LocalFunction(ref name, func) => {
+                let func = &ast[func];
+                // declared first so that the function can call itself
+                let reg = self.alloc()?;
+                self.activate(name);
+                if self.func().is_captured(reg) {
+                    self.emit(Instr::NewCell(reg));
+                    let temp = self.alloc()?;
+                    self.function(func, temp)?;
+                    self.emit(Instr::SetCell(temp, reg));
+                } else {
+                    self.function(func, reg)?;
+                }
+            }
+            StatKind::Local(names, exprs) => {
+                let first = self.func().active.len();
+                self.expr_list(&ast[exprs], names.len())?;
+                for name in &ast[names] {
+                    self.activate(name);
+                }
+                self.make_cells(first);
+            }
+            StatKind::Break => {
+                let jump = self.emit(Instr::Jump(0));
+                let scope = self
+                    .func()
+                    .scopes
+                    .iter_mut()
+                    .rev()
+                    .find_map(|scope| scope.breaks.as_mut());
+                // the parser rejects a `break` outside of a loop
+                scope.expect("break is inside a loop").push(jump);
+            }
+            StatKind::Goto(ref label) => {
+                let target = self.func().scopes.iter().rev().find_map(|scope| {
+                    scope
+                        .labels
+                        .iter()
+                        .find(|(name, _)| name == label)
+                        .map(|&(_, at)| at)
+                });
+                match target {
+                    Some(target) => self.jump_back(target),
+                    None => {
+                        let jump = self.emit(Instr::Jump(0));
+                        self.func().gotos.push((label.clone(), jump));
+                    }
+                }
+            }
+            StatKind::Label(ref name) => {
+                let here = self.here();
+                let func = self.func();
+                let scope = func.scopes.last_mut().expect("a scope is open");
+                scope.labels.push((name.clone(), here));
+                // the parser has checked that the jumps here from this
+                // block and those inside it are allowed
+                let start = scope.gotos;
+                let (jumps, waiting): (Vec<_>, Vec<_>) = func
+                    .gotos
+                    .drain(start..)
+                    .partition(|(label, _)| label == name);
+                func.gotos.extend(waiting);
+                for (_, jump) in jumps {
+                    self.patch(jump, here);
+                }
+            }
+        }
+        let func = self.func();
+        func.free = func.active.len();
+        Ok(())
+    }
+    fn patch_all(&mut self, jumps: &[usize], target: usize) {
+        for &jump in jumps {
+            self.patch(jump, target);
+        }
+    }
+    /// Evaluate a condition and emit a jump to patch, taken when it is false
+    fn jump_if_not(&mut self, cond: &Expr) -> Result<usize> {
+        let free = self.func().free;
+        let reg = self.expr_any(cond)?;
+        self.func().free = free;
+        Ok(self.emit(Instr::JumpIfNot(reg, 0)))
+    }
+    /// Store the function in `reg` from a `function a.b:c()` statement
+    fn assign_function(&mut self, name: &FuncName, reg: Reg) -> Result<()> {
+        let path = &self.ast[name.path];
+        let (fields, key) = match name.method {
+            Some(ref method) => (&path[1..], method),
+            None if path.len() == 1 => {
+                let place = self.variable(&path[0])?;
+                self.assign(place, reg);
+                return Ok(());
+            }
+            None => (
+                &path[1..path.len() - 1],
+                path.last().expect("checked the length"),
+            ),
+        };
+        let obj = self.alloc()?;
+        self.name(&path[0], obj)?;
+        let key_reg = self.alloc()?;
+        for field in fields {
+            let k = self.string(field.as_bytes())?;
+            self.emit(Instr::LoadK(key_reg, k));
+            self.emit(Instr::GetTable(obj, obj, key_reg));
+        }
+        let k = self.string(key.as_bytes())?;
+        self.emit(Instr::LoadK(key_reg, k));
+        self.emit(Instr::SetTable(obj, key_reg, reg));
+        Ok(())
+    }
+    fn variable(&mut self, name: &Name) -> Result<Place> {
+        if let Some(reg) = self.func().local(name) {
+            return Ok(if self.func().is_captured(reg) {
+                Place::Cell(reg)
+            } else {
+                Place::Local(reg)
+            });
+        }
+        let level = self.funcs.len() - 1;
+        match self.upval(level, name)? {
+            Some(u) => Ok(Place::Upval(u)),
+            None => Ok(Place::Global(self.string(name.as_bytes())?)),
+        }
+    }
+    /// Find `name` among the upvalues of the function at `level`, adding it
+    /// there and to the functions in between if it is a local of an
+    /// enclosing function
+    fn upval(&mut self, level: usize, name: &Name) -> Result<Option<u8>> {
+        if level == 0 {
+            return Ok(None);
+        }
+        let source = match self.funcs[level - 1].local(name) {
+            Some(reg) => UpvalSource::Local(reg),
+            None => match self.upval(level - 1, name)? {
+                Some(u) => UpvalSource::Upval(u),
+                None => return Ok(None),
+            },
+        };
+        let upvals = &self.funcs[level].upvals;
+        if let Some(u) = upvals.iter().position(|upval| upval.source == source) {
+            return Ok(Some(u as u8));
+        }
+        if upvals.len() > u8::MAX as usize {
+            return self.error("function uses too many upvalues".to_string());
+        }
+        let upvals = &mut self.funcs[level].upvals;
+        upvals.push(UpvalInfo {
+            name: name.clone(),
+            source,
+        });
+        Ok(Some(upvals.len() as u8 - 1))
+    }
+    /// Evaluate the parts of an assignment target
+    fn place(&mut self, target: &Expr) -> Result<Place> {
+        match target.kind {
+            ExprKind::Name(ref name) => self.variable(name),
+            ExprKind::Index(obj, key) => {
+                let obj = self.expr_any(&self.ast[obj])?;
+                let key = self.expr_any(&self.ast[key])?;
+                Ok(Place::Index(obj, key))
+            }
+            _ => unreachable!("the parser only allows names and fields as targets"),
+        }
+    }
+    /// Copy the locals that the tables and keys of `places` are in when
+    /// another of `places` assigns to them, as in `i, t[i] = i + 1, 20`,
+    /// since the assignments all use the values from before any of them
+    fn copy_conflicts(&mut self, places: &mut [Place]) -> Result<()> {
+        let locals: Vec<Reg> = places
+            .iter()
+            .filter_map(|place| match *place {
+                Place::Local(reg) => Some(reg),
+                _ => None,
+            })
+            .collect();
+        for place in places.iter_mut() {
+            if let Place::Index(ref mut obj, ref mut key) = *place {
+                for reg in [obj, key] {
+                    if locals.contains(reg) {
+                        let copy = self.alloc()?;
+                        self.emit(Instr::Move(copy, *reg));
+                        *reg = copy;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+    fn assign(&mut self, place: Place, val: Reg) {
+        match place {
+            Place::Local(reg) => self.emit(Instr::Move(reg, val)),
+            Place::Cell(reg) => self.emit(Instr::SetCell(val, reg)),
+            Place::Upval(u) => self.emit(Instr::SetUpval(val, u)),
+            Place::Global(k) => self.emit(Instr::SetGlobal(val, k)),
+            Place::Index(obj, key) => self.emit(Instr::SetTable(obj, key, val)),
+        };
+    }
+    /// Evaluate `exprs` into new registers, then drop or pad with nils to
+    /// get `want` values, giving the registers holding them
+    ///
+    /// A call or `...` at the end gives as many of the values as are
+    /// missing.
+    fn expr_list(&mut self, exprs: &[Expr], want: usize) -> Result<Vec<Reg>> {
+        let mut regs = Vec::with_capacity(want.max(exprs.len()));
+        for (i, expr) in exprs.iter().enumerate() {
+            let reg = self.alloc()?;
+            if i + 1 == exprs.len() && expr.is_multi() && want > exprs.len() {
+                self.multi(expr, reg, (want - i) as u8)?;
+                regs.push(reg);
+                for _ in exprs.len()..want {
+                    regs.push(self.alloc()?);
+                }
+            } else {
+                self.expr(expr, reg)?;
+                regs.push(reg);
+            }
+        }
+        if regs.len() < want {
+            let first = self.func().free as Reg;
+            let missing = want - regs.len();
+            for _ in 0..missing {
+                regs.push(self.alloc()?);
+            }
+            self.emit(Instr::LoadNil(first, missing as u8));
+        }
+        regs.truncate(want);
+        Ok(regs)
+    }
+    /// Evaluate `exprs` into new registers, with every value of a call or
+    /// `...` at the end, giving whether there was one so the count is
+    /// `MULTI`
+    fn open_list(&mut self, exprs: &[Expr]) -> Result<bool> {
+        for (i, expr) in exprs.iter().enumerate() {
+            let reg = self.alloc()?;
+            if i + 1 == exprs.len() && expr.is_multi() {
+                self.multi(expr, reg, MULTI)?;
+                return Ok(true);
+            }
+            self.expr(expr, reg)?;
+        }
+        Ok(false)
+    }
+    /// Evaluate an expression into any register, which for a local is its
+    /// own
+    fn expr_any(&mut self, expr: &Expr) -> Result<Reg> {
+        if let ExprKind::Name(ref name) = expr.kind {
+            if let Some(reg) = self.func().local(name) {
+                if !self.func().is_captured(reg) {
+                    return Ok(reg);
+                }
+            }
+        }
+        let reg = self.alloc()?;
+        self.expr(expr, reg)?;
+        Ok(reg)
+    }
+    /// Evaluate an expression into `dest`, freeing any temporaries it used
+    fn expr(&mut self, expr: &Expr, dest: Reg) -> Result<()> {
+        let free = self.func().free;
+        let line = self.line;
+        self.line = expr.loc.pos.line;
+        self.expr_kind(expr, dest)?;
+        self.line = line;
+        self.func().free = free;
+        Ok(())
+    }
+    fn expr_kind(&mut self, expr: &Expr, dest: Reg) -> Result<()> {
+        let ast = self.ast;
+        if let ExprKind::Unary(..) | ExprKind::Binary(..) | ExprKind::Paren(_) = expr.kind {
+            if let Some(val) = fold(ast, expr) {
+                return self.load_constant(&val, dest);
+            }
+        }
+        match expr.kind {
+            ExprKind::Nil => {
+                self.emit(Instr::LoadNil(dest, 1));
+            }
+            ExprKind::True => {
+                self.emit(Instr::LoadBool(dest, true));
+            }
+            ExprKind::False => {
+                self.emit(Instr::LoadBool(dest, false));
+            }
+            ExprKind::Number(num) => {
+                let k = self.number(num)?;
+                self.emit(Instr::LoadK(dest, k));
+            }
+            ExprKind::String(ref bytes) => {
+                let k = self.string(bytes)?;
+                self.emit(Instr::LoadK(dest, k));
+            }
+            ExprKind::Vararg => {
+                self.emit(Instr::VarArg(dest, 1));
+            }
+            ExprKind::Function(func) => self.function(&ast[func], dest)?,
+            ExprKind::Table(fields) => {
+                let fields = &ast[fields];
+                let (narr, nrec) = constructor_size(ast, fields);
+                let hint = |n: usize| n.min(u16::MAX as usize) as u16;
+                self.emit(Instr::NewTable(dest, hint(narr), hint(nrec)));
+                let mut pending = Vec::new();
+                let mut next: u32 = 1;
+                let mut open = false;
+                for (i, field) in fields.iter().enumerate() {
+                    match field.kind {
+                        FieldKind::Named(ref name, val) => {
+                            let key = self.alloc()?;
+                            let k = self.string(name.as_bytes())?;
+                            self.emit(Instr::LoadK(key, k));
+                            let val = self.expr_any(&ast[val])?;
+                            self.emit(Instr::SetTable(dest, key, val));
+                            self.func().free = key as usize;
+                        }
+                        FieldKind::Indexed(key, val) => {
+                            let free = self.func().free;
+                            let key = self.expr_any(&ast[key])?;
+                            let val = self.expr_any(&ast[val])?;
+                            self.emit(Instr::SetTable(dest, key, val));
+                            self.func().free = free;
+                        }
+                        // a call or `...` ending the constructor fills in all
+                        // its values
+                        FieldKind::Positional(val)
+                            if i + 1 == fields.len() && ast[val].is_multi() =>
+                        {
+                            let reg = self.alloc()?;
+                            self.multi(&ast[val], reg, MULTI)?;
+                            pending.push(reg);
+                            open = true;
+                        }
+                        FieldKind::Positional(val) => {
+                            let reg = self.alloc()?;
+                            self.expr(&ast[val], reg)?;
+                            pending.push(reg);
+                            if pending.len() == FIELDS_PER_FLUSH {
+                                self.emit(Instr::SetList(
+                                    dest,
+                                    pending[0],
+                                    pending.len() as u8,
+                                    next,
+                                ));
+                                next += pending.len() as u32;
+                                self.func().free = pending[0] as usize;
+                                pending.clear();
+                            }
+                        }
+                    }
+                }
+                if !pending.is_empty() {
+                    let n = if open { MULTI } else { pending.len() as u8 };
+                    self.emit(Instr::SetList(dest, pending[0], n, next));
+                }
+            }
+            ExprKind::Name(ref name) => self.name(name, dest)?,
+            ExprKind::Index(obj, key) => {
+                let obj = self.expr_any(&ast[obj])?;
+                let key = self.expr_any(&ast[key])?;
+                self.emit(Instr::GetTable(dest, obj, key));
+            }
+            ExprKind::Call(..) | ExprKind::Method(..) => {
+                let base = self.call_base(dest)?;
+                self.multi_kind(expr, base, 1)?;
+                if base != dest {
+                    self.emit(Instr::Move(dest, base));
+                }
+            }
+            ExprKind::Paren(inner) => self.expr(&ast[inner], dest)?,
+            ExprKind::Binary(op @ BinOp::And, lhs, rhs)
+            | ExprKind::Binary(op @ BinOp::Or, lhs, rhs) => {
+                let (lhs, rhs) = (&ast[lhs], &ast[rhs]);
+                // a constant left operand that isn't the result, as in
+                // `true and x`, needs no test, since folding failed on `x`
+                if fold(ast, lhs).is_some() {
+                    return self.expr(rhs, dest);
+                }
+                self.expr(lhs, dest)?;
+                let jump = match op {
+                    BinOp::And => self.emit(Instr::JumpIfNot(dest, 0)),
+                    _ => self.emit(Instr::JumpIf(dest, 0)),
+                };
+                self.expr(rhs, dest)?;
+                let end = self.here();
+                self.patch(jump, end);
+            }
+            // evaluated into consecutive registers to be joined at once
+            ExprKind::Binary(BinOp::Concat, _, rhs) if ast[rhs].is_concat() => {
+                let operands = ast.concat_operands(expr);
+                let base = self.func().free as Reg;
+                for operand in &operands {
+                    let reg = self.alloc()?;
+                    self.expr(operand, reg)?;
+                }
+                self.emit(Instr::ConcatAll(dest, base, operands.len() as u8));
+            }
+            ExprKind::Binary(op, lhs, rhs) => {
+                let lhs = self.expr_any(&ast[lhs])?;
+                let rhs = self.expr_any(&ast[rhs])?;
+                self.emit(binary(op, dest, lhs, rhs));
+            }
+            ExprKind::Unary(op, operand) => {
+                let reg = self.expr_any(&ast[operand])?;
+                self.emit(match op {
+                    UnOp::Neg => Instr::Unary(ArithOp::Unm, dest, reg),
+                    UnOp::Not => Instr::Not(dest, reg),
+                    UnOp::Len => Instr::Len(dest, reg),
+                    UnOp::BNot => Instr::Unary(ArithOp::BNot, dest, reg),
+                });
+            }
+        }
+        Ok(())
+    }
+    /// Load a folded constant into `dest`
+    fn load_constant(&mut self, val: &Value, dest: Reg) -> Result<()> {
+        if val.is_nil() {
+            self.emit(Instr::LoadNil(dest, 1));
+        } else if let Some(&val) = LuaBool::from_value(val) {
+            self.emit(Instr::LoadBool(dest, val));
+        } else if let Some(num) = val.number_value() {
+            let k = self.number(num)?;
+            self.emit(Instr::LoadK(dest, k));
+        } else {
+            let bytes = LuaString::from_value(val).expect("constants are strings if not numbers");
+            let k = self.string(bytes)?;
+            self.emit(Instr::LoadK(dest, k));
+        }
+        Ok(())
+    }
+    fn name(&mut self, name: &Name, dest: Reg) -> Result<()> {
+        match self.variable(name)? {
+            Place::Local(reg) => {
+                if reg != dest {
+                    self.emit(Instr::Move(dest, reg));
+                }
+            }
+            Place::Cell(reg) => {
+                self.emit(Instr::GetCell(dest, reg));
+            }
+            Place::Upval(u) => {
+                self.emit(Instr::GetUpval(dest, u));
+            }
+            Place::Global(k) => {
+                self.emit(Instr::GetGlobal(dest, k));
+            }
+            Place::Index(..) => unreachable!("variables are not fields"),
+        }
+        Ok(())
+    }
+    /// Where to put a function to call, which is `dest` if nothing is above
+    /// it
+    fn call_base(&mut self, dest: Reg) -> Result<Reg> {
+        if dest as usize + 1 == self.func().free {
+            Ok(dest)
+        } else {
+            self.alloc()
+        }
+    }
+    /// Compile a call or `...` into `base`, the last register in use,
+    /// keeping `results` of its values from there, which can be `MULTI`
+    fn multi(&mut self, expr: &Expr, base: Reg, results: u8) -> Result<()> {
+        let free = self.func().free;
+        let line = self.line;
+        self.line = expr.loc.pos.line;
+        self.multi_kind(expr, base, results)?;
+        self.line = line;
+        self.func().free = free;
+        Ok(())
+    }
+    fn multi_kind(&mut self, expr: &Expr, base: Reg, results: u8) -> Result<()> {
+        let ast = self.ast;
+        match expr.kind {
+            ExprKind::Vararg => {
+                if results != MULTI {
+                    self.reserve(base, results as usize)?;
+                }
+                self.emit(Instr::VarArg(base, results));
+                Ok(())
+            }
+            ExprKind::Call(func, args) => {
+                self.expr(&ast[func], base)?;
+                self.call(base, &ast[args], 0, results)
+            }
+            ExprKind::Method(obj, ref name, args) => {
+                let obj = self.expr_any(&ast[obj])?;
+                let k = self.string(name.as_bytes())?;
+                self.emit(Instr::GetMethod(base, obj, k));
+                self.func().free = base as usize + 1;
+                self.alloc()?;
+                self.call(base, &ast[args], 1, results)
+            }
+            _ => unreachable!("only calls and `...` have several values"),
+        }
+    }
+    /// Evaluate the arguments after the function in `base` and the `extra`
+    /// arguments already above it, then call it
+    fn call(&mut self, base: Reg, args: &[Expr], extra: usize, results: u8) -> Result<()> {
+        let open = self.open_list(args)?;
+        let nargs = if open {
+            MULTI
+        } else {
+            (args.len() + extra) as u8
+        };
+        if results != MULTI {
+            self.reserve(base, results as usize)?;
+        }
+        self.emit(Instr::Call(base, nargs, results));
+        Ok(())
+    }
+    /// Compile a nested function, creating a closure of it in `dest`
+    fn function(&mut self, body: &FuncBody, dest: Reg) -> Result<()> {
+        let line = self.line;
+        let params = &self.ast[body.params];
+        let captured = captured(self.ast, params, &body.body);
+        let mut func = FuncState::new(params.len() as u8, body.vararg, line, captured);
+        func.free = params.len();
+        func.max_regs = func.free;
+        self.funcs.push(func);
+        if params.len() > MAX_REGS {
+            return self.error("too many parameters".to_string());
+        }
+        // the parameters share the body's scope
+        self.open_scope(false);
+        for param in params {
+            self.activate(param);
+        }
+        self.make_cells(0);
+        self.block_body(&body.body)?;
+        self.line = body.body.loc.pos.line;
+        self.emit(Instr::Return(0, 0));
+        self.close_scope();
+        let func = self.funcs.pop().expect("the function is open");
+        let proto = self.finish(func);
+        self.line = line;
+        let index = self.func().protos.len() as u32;
+        self.func().protos.push(Rc::new(proto));
+        self.emit(Instr::Closure(dest, index));
+        Ok(())
+    }
+}
+
+fn binary(op: BinOp, dest: Reg, lhs: Reg, rhs: Reg) -> Instr {
+    let arith = match op {
+        BinOp::Add => ArithOp::Add,
+        BinOp::Sub => ArithOp::Sub,
+        BinOp::Mul => ArithOp::Mul,
+        BinOp::Div => ArithOp::Div,
+        BinOp::IDiv => ArithOp::IDiv,
+        BinOp::Mod => ArithOp::Mod,
+        BinOp::Pow => ArithOp::Pow,
+        BinOp::BAnd => ArithOp::BAnd,
+        BinOp::BOr => ArithOp::BOr,
+        BinOp::BXor => ArithOp::BXor,
+        BinOp::Shl => ArithOp::Shl,
+        BinOp::Shr => ArithOp::Shr,
+        BinOp::Concat => return Instr::Concat(dest, lhs, rhs),
+        BinOp::Eq => return Instr::Eq(dest, lhs, rhs),
+        BinOp::Ne => return Instr::Ne(dest, lhs, rhs),
+        BinOp::Lt => return Instr::Lt(dest, lhs, rhs),
+        BinOp::Le => return Instr::Le(dest, lhs, rhs),
+        BinOp::Gt => return Instr::Lt(dest, rhs, lhs),
+        BinOp::Ge => return Instr::Le(dest, rhs, lhs),
+        BinOp::And | BinOp::Or => unreachable!("short-circuit operators jump"),
+    };
+    Instr::Arith(arith, dest, lhs, rhs)
+}
@@ -0,0 +1,218 @@
+//! Coroutines, which are threads of the VM that can be suspended
+//!
+//! A coroutine runs its function on a stack of its own, and yielding keeps
+//! its frames there until it is resumed, so only calls the VM makes itself
+//! can be suspended. Besides calls in Lua code, those include the functions
+//! `pcall` and `xpcall` call and `__index` and `__newindex` functions,
+//! whose frames return their results where those want them. Functions the
+//! interpreter created are compiled when a coroutine calls them, sharing
+//! the variables they captured, while a coroutine running any other Rust
+//! function, or a Lua function called by one, cannot yield until that call
+//! returns.
+
+use std::cell::RefCell;
+use std::mem;
+
+use error::{Error, Result};
+use gc::Edge;
+use limits;
+use value::{MultiValue, Value};
+
+use super::{Step, Thread};
+
+thread_local! {
+    /// the coroutines being run, innermost last
+    static RUNNING: RefCell<Vec<Value>> = const { RefCell::new(Vec::new()) };
+    /// `coroutine.yield`, which the VM recognizes when a coroutine calls it
+    static YIELD: Value = Value::function(|_| -> Result<MultiValue> {
+        Err(Error::Runtime(if running().is_some() {
+            "attempt to yield across a C-call boundary".to_string()
+        } else {
+            "attempt to yield from outside a coroutine".to_string()
+        }))
+    });
+}
+
+/// A thread value, which runs a function as a coroutine
+pub struct Coroutine {
+    state: RefCell<State>,
+}
+
+enum State {
+    /// not yet resumed, with the function to run
+    Fresh(Value),
+    /// suspended in a call to `coroutine.yield`
+    Suspended(Thread),
+    /// running, or resuming another coroutine
+    Running,
+    /// finished, by returning or by an error
+    Dead,
+}
+
+/// What a coroutine is doing, as `coroutine.status` says
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Status {
+    Suspended,
+    Running,
+    /// resuming another coroutine
+    Normal,
+    Dead,
+}
+impl Status {
+    pub fn name(self) -> &'static str {
+        match self {
+            Status::Suspended => "suspended",
+            Status::Running => "running",
+            Status::Normal => "normal",
+            Status::Dead => "dead",
+        }
+    }
+}
+
+impl Coroutine {
+    pub fn new(func: Value) -> Coroutine {
+        Coroutine {
+            state: RefCell::new(State::Fresh(func)),
+        }
+    }
+    pub fn status(&self) -> Status {
+        match *self.state.borrow() {
+            State::Fresh(_) | State::Suspended(_) => Status::Suspended,
+            State::Dead => Status::Dead,
+            State::Running if self.is_innermost() => Status::Running,
+            State::Running => Status::Normal,
+        }
+    }
+    /// Kill a coroutine that is not running, dropping its frames
+    pub fn close(&self) -> Result<()> {
+        let status = self.status();
+        match status {
+            Status::Running | Status::Normal => Err(Error::Runtime(format!(
+                "cannot close a {} coroutine",
+                status.name()
+            ))),
+            Status::Suspended | Status::Dead => {
+                *self.state.borrow_mut() = State::Dead;
+                Ok(())
+            }
+        }
+    }
+    /// Pass the function of a fresh coroutine, or what a suspended one
+    /// refers to, to `visit`, for the collector
+    pub(crate) fn trace(&self, visit: &mut dyn FnMut(Edge)) {
+        match self.state.try_borrow().as_deref() {
+            Ok(State::Fresh(func)) => visit(Edge::Value(func)),
+            Ok(State::Suspended(thread)) => thread.trace(visit),
+            _ => (),
+        }
+    }
+    /// Whether this is the innermost coroutine being run
+    fn is_innermost(&self) -> bool {
+        running()
+            .as_ref()
+            .and_then(Value::as_thread)
+            .is_some_and(|co| ::std::ptr::eq(co, self))
+    }
+}
+
+/// Run the coroutine in `co` until it yields or returns, giving what it
+/// passed to `coroutine.yield` or returned
+///
+/// Where it yields, `args` are the results of the call to `coroutine.yield`,
+/// and to start with they are passed to its function. Once it raises an
+/// error it is dead.
+pub fn resume(co: &Value, args: Vec<Value>) -> Result<MultiValue> {
+    let coroutine = co.as_thread().expect("resuming a thread");
+    match coroutine.status() {
+        Status::Suspended => {}
+        Status::Dead => {
+            return Err(Error::Runtime("cannot resume dead coroutine".to_string()));
+        }
+        Status::Running | Status::Normal => {
+            return Err(Error::Runtime(
+                "cannot resume non-suspended coroutine".to_string(),
+            ));
+        }
+    }
+    let state = mem::replace(&mut *coroutine.state.borrow_mut(), State::Running);
+    let mut thread = match state {
+        State::Fresh(func) => {
+            let mut thread = Thread::new(true);
+            match thread.lua_closure(&func) {
+                Some(closure) => {
+                    if let Err(err) = closure.limits.enter_native() {
+                        // too deep in calls to start, so it can be resumed
+                        // later
+                        *coroutine.state.borrow_mut() = State::Fresh(func.clone());
+                        return Err(err);
+                    }
+                    thread.start(closure, args)
+                }
+                None => {
+                    // Rust functions run to completion
+                    let result = with_running(co, || func.call(args));
+                    *coroutine.state.borrow_mut() = State::Dead;
+                    return result;
+                }
+            }
+            thread
+        }
+        State::Suspended(mut thread) => {
+            let limits = thread.frames[0].limits.clone();
+            if let Err(err) = limits.enter_native() {
+                *coroutine.state.borrow_mut() = State::Suspended(thread);
+                return Err(err);
+            }
+            let (at, want) = thread.yielded;
+            thread.set_results(at, args.into(), want);
+            thread.resume_levels();
+            thread
+        }
+        State::Running | State::Dead => unreachable!("the coroutine is suspended"),
+    };
+    let step = with_running(co, || thread.run());
+    limits::leave_native();
+    let mut state = coroutine.state.borrow_mut();
+    match step {
+        Ok(Step::Yield(vals)) => {
+            thread.suspend_levels();
+            *state = State::Suspended(thread);
+            Ok(vals)
+        }
+        Ok(Step::Done(vals)) => {
+            *state = State::Dead;
+            Ok(vals)
+        }
+        Ok(Step::Switch) => unreachable!("the thread runs until it stops"),
+        Err(err) => {
+            *state = State::Dead;
+            Err(err)
+        }
+    }
+}
+
+/// Run `f` with `co` as the innermost running coroutine
+fn with_running<T, F>(co: &Value, f: F) -> T
+where
+    F: FnOnce() -> T,
+{
+    RUNNING.with(|running| running.borrow_mut().push(co.clone()));
+    let result = f();
+    RUNNING.with(|running| running.borrow_mut().pop());
+    result
+}
+
+/// The innermost coroutine being run, if there is one
+pub fn running() -> Option<Value> {
+    RUNNING.with(|running| running.borrow().last().cloned())
+}
+
+/// The `coroutine.yield` function
+pub fn yield_function() -> Value {
+    YIELD.with(Value::clone)
+}
+
+/// Whether `func` is `coroutine.yield`
+pub fn is_yield(func: &Value) -> bool {
+    YIELD.with(|yield_fn| func.raw_equal(yield_fn))
+}
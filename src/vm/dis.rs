@@ -0,0 +1,251 @@
+//! Listing compiled functions as text, to debug the compiler and to see
+//! how the VM runs a chunk
+//!
+//! Each function is listed with its instructions, numbered from 1 with the
+//! line each one is from, and what they refer to noted after them: the
+//! constants and upvalues they use, where they jump to and the functions
+//! they create. Then come its constants, locals and upvalues, and the
+//! functions defined in it, in the same way.
+
+use std::fmt::Write;
+
+use super::instr::{Instr, MULTI};
+use super::{Proto, UpvalSource};
+use value::{Type, Value};
+
+/// List `proto` and the functions defined in it
+pub fn disassemble(proto: &Proto) -> String {
+    let mut out = String::new();
+    function(&mut out, proto);
+    out
+}
+
+fn function(out: &mut String, proto: &Proto) {
+    let kind = if proto.line == 0 { "main" } else { "function" };
+    let _ = writeln!(
+        out,
+        "{} <{}:{}> ({})",
+        kind,
+        proto.chunk,
+        proto.line,
+        count(proto.code.len(), "instruction")
+    );
+    let _ = writeln!(
+        out,
+        "{}{} params, {}, {}, {}, {}, {}",
+        proto.params,
+        if proto.vararg { "+" } else { "" },
+        count(proto.max_regs, "register"),
+        count(proto.upvals.len(), "upvalue"),
+        count(proto.locals.len(), "local"),
+        count(proto.constants.len(), "constant"),
+        count(proto.protos.len(), "function")
+    );
+    for (pc, instr) in proto.code.iter().enumerate() {
+        let line = match proto.lines.get(pc) {
+            Some(line) => format!("[{}]", line),
+            None => "[-]".to_string(),
+        };
+        let (name, operands) = operands(instr);
+        let _ = write!(
+            out,
+            "\t{}\t{}\t{:<10}{}",
+            pc + 1,
+            line,
+            name,
+            operands.join(" ")
+        );
+        if let Some(note) = note(proto, pc, instr) {
+            let _ = write!(out, "\t; {}", note);
+        }
+        out.push('\n');
+    }
+    let _ = writeln!(out, "constants ({}):", proto.constants.len());
+    for (i, val) in proto.constants.iter().enumerate() {
+        let _ = writeln!(out, "\t{}\t{}", i, constant(val));
+    }
+    let _ = writeln!(out, "locals ({}):", proto.locals.len());
+    for local in &proto.locals {
+        let _ = writeln!(
+            out,
+            "\tR{}\t{}\t{}\t{}{}",
+            local.reg,
+            name_or_missing(&local.name),
+            local.start + 1,
+            local.end + 1,
+            if local.captured { "\tin a cell" } else { "" }
+        );
+    }
+    let _ = writeln!(out, "upvalues ({}):", proto.upvals.len());
+    for (i, upval) in proto.upvals.iter().enumerate() {
+        let source = match upval.source {
+            UpvalSource::Local(reg) => format!("local R{}", reg),
+            UpvalSource::Upval(u) => format!("upvalue U{}", u),
+        };
+        let _ = writeln!(
+            out,
+            "\tU{}\t{}\t{}",
+            i,
+            name_or_missing(&upval.name),
+            source
+        );
+    }
+    for proto in &proto.protos {
+        out.push('\n');
+        function(out, proto);
+    }
+}
+
+/// The name of an instruction and its operands
+fn operands(instr: &Instr) -> (String, Vec<String>) {
+    // counts of values, which can be `MULTI` for all of them
+    let count = |n: u8| {
+        if n == MULTI {
+            "*".to_string()
+        } else {
+            n.to_string()
+        }
+    };
+    let (name, operands): (&str, Vec<String>) = match *instr {
+        Instr::Move(a, b) => ("Move", vec![a.to_string(), b.to_string()]),
+        Instr::LoadK(a, k) => ("LoadK", vec![a.to_string(), k.to_string()]),
+        Instr::LoadNil(a, n) => ("LoadNil", vec![a.to_string(), n.to_string()]),
+        Instr::LoadBool(a, b) => ("LoadBool", vec![a.to_string(), b.to_string()]),
+        Instr::GetGlobal(a, k) => ("GetGlobal", vec![a.to_string(), k.to_string()]),
+        Instr::SetGlobal(a, k) => ("SetGlobal", vec![a.to_string(), k.to_string()]),
+        Instr::GetTable(a, b, c) => (
+            "GetTable",
+            vec![a.to_string(), b.to_string(), c.to_string()],
+        ),
+        Instr::SetTable(a, b, c) => (
+            "SetTable",
+            vec![a.to_string(), b.to_string(), c.to_string()],
+        ),
+        Instr::GetMethod(a, b, k) => (
+            "GetMethod",
+            vec![a.to_string(), b.to_string(), k.to_string()],
+        ),
+        Instr::NewTable(a, b, c) => (
+            "NewTable",
+            vec![a.to_string(), b.to_string(), c.to_string()],
+        ),
+        Instr::SetList(a, b, n, first) => (
+            "SetList",
+            vec![a.to_string(), b.to_string(), count(n), first.to_string()],
+        ),
+        Instr::Arith(op, a, b, c) => {
+            return (
+                format!("{:?}", op),
+                vec![a.to_string(), b.to_string(), c.to_string()],
+            );
+        }
+        Instr::Unary(op, a, b) => return (format!("{:?}", op), vec![a.to_string(), b.to_string()]),
+        Instr::Not(a, b) => ("Not", vec![a.to_string(), b.to_string()]),
+        Instr::Len(a, b) => ("Len", vec![a.to_string(), b.to_string()]),
+        Instr::Concat(a, b, c) => ("Concat", vec![a.to_string(), b.to_string(), c.to_string()]),
+        Instr::ConcatAll(a, b, n) => (
+            "ConcatAll",
+            vec![a.to_string(), b.to_string(), n.to_string()],
+        ),
+        Instr::Eq(a, b, c) => ("Eq", vec![a.to_string(), b.to_string(), c.to_string()]),
+        Instr::Ne(a, b, c) => ("Ne", vec![a.to_string(), b.to_string(), c.to_string()]),
+        Instr::Lt(a, b, c) => ("Lt", vec![a.to_string(), b.to_string(), c.to_string()]),
+        Instr::Le(a, b, c) => ("Le", vec![a.to_string(), b.to_string(), c.to_string()]),
+        Instr::Jump(offset) => ("Jump", vec![offset.to_string()]),
+        Instr::JumpIf(a, offset) => ("JumpIf", vec![a.to_string(), offset.to_string()]),
+        Instr::JumpIfNot(a, offset) => ("JumpIfNot", vec![a.to_string(), offset.to_string()]),
+        Instr::JumpEq(a, b, cond, offset) => (
+            "JumpEq",
+            vec![
+                a.to_string(),
+                b.to_string(),
+                cond.to_string(),
+                offset.to_string(),
+            ],
+        ),
+        Instr::JumpLt(a, b, cond, offset) => (
+            "JumpLt",
+            vec![
+                a.to_string(),
+                b.to_string(),
+                cond.to_string(),
+                offset.to_string(),
+            ],
+        ),
+        Instr::JumpLe(a, b, cond, offset) => (
+            "JumpLe",
+            vec![
+                a.to_string(),
+                b.to_string(),
+                cond.to_string(),
+                offset.to_string(),
+            ],
+        ),
+        Instr::Call(a, b, c) => ("Call", vec![a.to_string(), count(b), count(c)]),
+        Instr::TailCall(a, b) => ("TailCall", vec![a.to_string(), count(b)]),
+        Instr::Return(a, n) => ("Return", vec![a.to_string(), count(n)]),
+        Instr::ForPrep(a, offset) => ("ForPrep", vec![a.to_string(), offset.to_string()]),
+        Instr::ForLoop(a, offset) => ("ForLoop", vec![a.to_string(), offset.to_string()]),
+        Instr::TForCall(a, n) => ("TForCall", vec![a.to_string(), n.to_string()]),
+        Instr::TForLoop(a, offset) => ("TForLoop", vec![a.to_string(), offset.to_string()]),
+        Instr::Closure(a, p) => ("Closure", vec![a.to_string(), p.to_string()]),
+        Instr::VarArg(a, n) => ("VarArg", vec![a.to_string(), count(n)]),
+        Instr::NewCell(a) => ("NewCell", vec![a.to_string()]),
+        Instr::GetCell(a, b) => ("GetCell", vec![a.to_string(), b.to_string()]),
+        Instr::SetCell(a, b) => ("SetCell", vec![a.to_string(), b.to_string()]),
+        Instr::GetUpval(a, u) => ("GetUpval", vec![a.to_string(), u.to_string()]),
+        Instr::SetUpval(a, u) => ("SetUpval", vec![a.to_string(), u.to_string()]),
+    };
+    (name.to_string(), operands)
+}
+
+/// What an instruction refers to, for the note after it
+fn note(proto: &Proto, pc: usize, instr: &Instr) -> Option<String> {
+    let constant = |k: u32| {
+        proto
+            .constants
+            .get(k as usize)
+            .map_or_else(|| "?".to_string(), constant)
+    };
+    let upval = |u: u8| {
+        proto
+            .upvals
+            .get(u as usize)
+            .map_or("?", |upval| name_or_missing(&upval.name))
+            .to_string()
+    };
+    if let Some(offset) = instr.jump_offset() {
+        return Some(format!("to {}", pc as i64 + 2 + offset as i64));
+    }
+    Some(match *instr {
+        Instr::LoadK(_, k) | Instr::GetGlobal(_, k) | Instr::SetGlobal(_, k) => constant(k),
+        Instr::GetMethod(_, _, k) => constant(k),
+        Instr::GetUpval(_, u) | Instr::SetUpval(_, u) => upval(u),
+        Instr::Closure(_, p) => match proto.protos.get(p as usize) {
+            Some(nested) => format!("function <{}:{}>", nested.chunk, nested.line),
+            None => "?".to_string(),
+        },
+        _ => return None,
+    })
+}
+
+/// A constant as it would be written in source
+fn constant(val: &Value) -> String {
+    match val.type_of() {
+        Type::String => format!("{:?}", val),
+        _ => val.to_string(),
+    }
+}
+
+fn count(n: usize, thing: &str) -> String {
+    format!("{} {}{}", n, thing, if n == 1 { "" } else { "s" })
+}
+
+/// Names are left out of stripped chunks
+fn name_or_missing(name: &str) -> &str {
+    if name.is_empty() {
+        "-"
+    } else {
+        name
+    }
+}
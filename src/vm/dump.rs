@@ -0,0 +1,597 @@
+//! Saving compiled functions as binary chunks and loading them back
+//!
+//! A chunk starts with a header recording the format version and how
+//! numbers are stored, so that bytecode from another version or build is
+//! rejected rather than misread. Loading also checks every operand, since a
+//! binary chunk may have been damaged or written by hand.
+
+use std::rc::Rc;
+use std::str;
+
+use super::instr::{Instr, Reg, MULTI};
+use super::{LocalInfo, Proto, UpvalInfo, UpvalSource};
+use error::{Error, Result};
+use value::{ArithOp, ConvertValue, LuaInteger, LuaNumber, LuaString, StringTable, Type};
+
+/// How every binary chunk starts, which is also how Lua's do
+pub const SIGNATURE: &[u8] = b"\x1bLua";
+/// Identifies chunks written by this implementation
+const MAGIC: &[u8] = b"looa";
+/// Changed whenever the format or the instruction set changes
+const VERSION: u8 = 10;
+/// Catches chunks mangled by newline conversion
+const DATA: &[u8] = b"\x19\x93\r\n\x1a\n";
+/// Numbers stored in the header to check how they are encoded
+const CHECK_INT: LuaInteger = 0x5678;
+const CHECK_NUM: LuaNumber = 370.5;
+
+/// The arithmetic operators in the order they are numbered in chunks
+const ARITH_OPS: [ArithOp; 14] = [
+    ArithOp::Add,
+    ArithOp::Sub,
+    ArithOp::Mul,
+    ArithOp::Div,
+    ArithOp::Mod,
+    ArithOp::Pow,
+    ArithOp::IDiv,
+    ArithOp::Unm,
+    ArithOp::BAnd,
+    ArithOp::BOr,
+    ArithOp::BXor,
+    ArithOp::Shl,
+    ArithOp::Shr,
+    ArithOp::BNot,
+];
+
+/// Save `proto` as a binary chunk, leaving out line numbers and the names of
+/// locals and upvalues if `strip` is set
+pub fn dump(proto: &Proto, strip: bool) -> Vec<u8> {
+    let mut out = Writer(Vec::new());
+    out.0.extend_from_slice(SIGNATURE);
+    out.0.extend_from_slice(MAGIC);
+    out.u8(VERSION);
+    out.0.extend_from_slice(DATA);
+    out.u8(::std::mem::size_of::<LuaInteger>() as u8);
+    out.u8(::std::mem::size_of::<LuaNumber>() as u8);
+    out.int(CHECK_INT);
+    out.num(CHECK_NUM);
+    out.bytes(if strip { b"?" } else { proto.chunk.as_bytes() });
+    out.proto(proto, &proto.chunk, strip);
+    out.0
+}
+
+/// Load a binary chunk saved by `dump`, naming it `name` in errors and
+/// interning its string constants in `strings`
+pub fn undump(chunk: &[u8], name: &str, strings: &StringTable) -> Result<Proto> {
+    let mut input = Reader {
+        chunk,
+        pos: 0,
+        name,
+        strings,
+    };
+    input.expect(SIGNATURE, "not a binary chunk")?;
+    input.expect(MAGIC, "not a looa chunk")?;
+    if input.u8()? != VERSION {
+        return Err(input.error("version mismatch"));
+    }
+    input.expect(DATA, "corrupted chunk")?;
+    if input.u8()? as usize != ::std::mem::size_of::<LuaInteger>() {
+        return Err(input.error("integer size mismatch"));
+    }
+    if input.u8()? as usize != ::std::mem::size_of::<LuaNumber>() {
+        return Err(input.error("float size mismatch"));
+    }
+    if input.int()? != CHECK_INT {
+        return Err(input.error("integer format mismatch"));
+    }
+    if input.num()?.to_bits() != CHECK_NUM.to_bits() {
+        return Err(input.error("float format mismatch"));
+    }
+    let chunk_name = input.bytes()?;
+    let chunk_name: Rc<str> = Rc::from(String::from_utf8_lossy(chunk_name).as_ref());
+    let proto = input.proto(&chunk_name)?;
+    if input.pos != chunk.len() {
+        return Err(input.error("trailing data"));
+    }
+    Ok(proto)
+}
+
+struct Writer(Vec<u8>);
+impl Writer {
+    fn u8(&mut self, val: u8) {
+        self.0.push(val);
+    }
+    fn u16(&mut self, val: u16) {
+        self.0.extend_from_slice(&val.to_le_bytes());
+    }
+    fn u32(&mut self, val: u32) {
+        self.0.extend_from_slice(&val.to_le_bytes());
+    }
+    fn len(&mut self, len: usize) {
+        self.u32(len as u32);
+    }
+    fn i32(&mut self, val: i32) {
+        self.0.extend_from_slice(&val.to_le_bytes());
+    }
+    fn int(&mut self, val: LuaInteger) {
+        self.0.extend_from_slice(&val.to_le_bytes());
+    }
+    fn num(&mut self, val: LuaNumber) {
+        self.0.extend_from_slice(&val.to_le_bytes());
+    }
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.len(bytes.len());
+        self.0.extend_from_slice(bytes);
+    }
+    /// Save `proto`, whose chunk name is only written if it differs from
+    /// the name of the function it is inside, `parent`
+    fn proto(&mut self, proto: &Proto, parent: &str, strip: bool) {
+        let same = strip || *proto.chunk == *parent;
+        self.bytes(if same { b"" } else { proto.chunk.as_bytes() });
+        self.u32(proto.line);
+        self.u8(proto.params);
+        self.u8(proto.vararg as u8);
+        self.u8(proto.max_regs as u8);
+        self.len(proto.code.len());
+        for instr in &proto.code {
+            self.instr(*instr);
+        }
+        self.len(proto.constants.len());
+        for val in &proto.constants {
+            if let Some(i) = LuaInteger::from_value(val) {
+                self.u8(0);
+                self.int(i);
+            } else if let Some(&f) = LuaNumber::from_value(val) {
+                self.u8(1);
+                self.num(f);
+            } else {
+                let bytes = LuaString::from_value(val).expect("constants are numbers or strings");
+                self.u8(2);
+                self.bytes(bytes);
+            }
+        }
+        self.len(proto.protos.len());
+        for inner in &proto.protos {
+            self.proto(inner, &proto.chunk, strip);
+        }
+        self.len(proto.upvals.len());
+        for upval in &proto.upvals {
+            match upval.source {
+                UpvalSource::Local(reg) => self.regs(0, &[reg]),
+                UpvalSource::Upval(u) => self.regs(1, &[u]),
+            }
+            self.bytes(if strip { b"" } else { upval.name.as_bytes() });
+        }
+        if strip {
+            self.len(0);
+            self.len(0);
+            return;
+        }
+        self.len(proto.lines.len());
+        for &line in &proto.lines {
+            self.u32(line);
+        }
+        self.len(proto.locals.len());
+        for local in &proto.locals {
+            self.bytes(local.name.as_bytes());
+            self.u8(local.reg);
+            self.len(local.start);
+            self.len(local.end);
+            self.u8(local.captured as u8);
+        }
+    }
+    fn instr(&mut self, instr: Instr) {
+        match instr {
+            Instr::Move(a, b) => self.regs(0, &[a, b]),
+            Instr::LoadK(a, k) => {
+                self.regs(1, &[a]);
+                self.u32(k);
+            }
+            Instr::LoadNil(a, n) => self.regs(2, &[a, n]),
+            Instr::LoadBool(a, val) => self.regs(3, &[a, val as u8]),
+            Instr::GetGlobal(a, k) => {
+                self.regs(4, &[a]);
+                self.u32(k);
+            }
+            Instr::SetGlobal(a, k) => {
+                self.regs(5, &[a]);
+                self.u32(k);
+            }
+            Instr::GetTable(a, b, c) => self.regs(6, &[a, b, c]),
+            Instr::SetTable(a, b, c) => self.regs(7, &[a, b, c]),
+            Instr::GetMethod(a, b, k) => {
+                self.regs(8, &[a, b]);
+                self.u32(k);
+            }
+            Instr::NewTable(a, b, c) => {
+                self.regs(9, &[a]);
+                self.u16(b);
+                self.u16(c);
+            }
+            Instr::SetList(a, b, n, first) => {
+                self.regs(10, &[a, b, n]);
+                self.u32(first);
+            }
+            Instr::Arith(op, a, b, c) => self.regs(11, &[arith_index(op), a, b, c]),
+            Instr::Unary(op, a, b) => self.regs(12, &[arith_index(op), a, b]),
+            Instr::Not(a, b) => self.regs(13, &[a, b]),
+            Instr::Len(a, b) => self.regs(14, &[a, b]),
+            Instr::Concat(a, b, c) => self.regs(15, &[a, b, c]),
+            Instr::Eq(a, b, c) => self.regs(16, &[a, b, c]),
+            Instr::Ne(a, b, c) => self.regs(17, &[a, b, c]),
+            Instr::Lt(a, b, c) => self.regs(18, &[a, b, c]),
+            Instr::Le(a, b, c) => self.regs(19, &[a, b, c]),
+            Instr::Jump(offset) => {
+                self.u8(20);
+                self.i32(offset);
+            }
+            Instr::JumpIf(a, offset) => {
+                self.regs(21, &[a]);
+                self.i32(offset);
+            }
+            Instr::JumpIfNot(a, offset) => {
+                self.regs(22, &[a]);
+                self.i32(offset);
+            }
+            Instr::Call(a, b, c) => self.regs(23, &[a, b, c]),
+            Instr::TailCall(a, b) => self.regs(39, &[a, b]),
+            Instr::Return(a, n) => self.regs(24, &[a, n]),
+            Instr::ForPrep(a, offset) => {
+                self.regs(25, &[a]);
+                self.i32(offset);
+            }
+            Instr::ForLoop(a, offset) => {
+                self.regs(26, &[a]);
+                self.i32(offset);
+            }
+            Instr::TForCall(a, n) => self.regs(27, &[a, n]),
+            Instr::TForLoop(a, offset) => {
+                self.regs(28, &[a]);
+                self.i32(offset);
+            }
+            Instr::Closure(a, p) => {
+                self.regs(29, &[a]);
+                self.u32(p);
+            }
+            Instr::VarArg(a, n) => self.regs(30, &[a, n]),
+            Instr::JumpEq(a, b, cond, offset) => {
+                self.regs(31, &[a, b, cond as u8]);
+                self.i32(offset);
+            }
+            Instr::JumpLt(a, b, cond, offset) => {
+                self.regs(32, &[a, b, cond as u8]);
+                self.i32(offset);
+            }
+            Instr::JumpLe(a, b, cond, offset) => {
+                self.regs(33, &[a, b, cond as u8]);
+                self.i32(offset);
+            }
+            Instr::NewCell(a) => self.regs(34, &[a]),
+            Instr::GetCell(a, b) => self.regs(35, &[a, b]),
+            Instr::SetCell(a, b) => self.regs(36, &[a, b]),
+            Instr::GetUpval(a, u) => self.regs(37, &[a, u]),
+            Instr::SetUpval(a, u) => self.regs(38, &[a, u]),
+            Instr::ConcatAll(a, b, n) => self.regs(40, &[a, b, n]),
+        }
+    }
+    /// An opcode followed by byte operands
+    fn regs(&mut self, op: u8, operands: &[u8]) {
+        self.u8(op);
+        self.0.extend_from_slice(operands);
+    }
+}
+
+fn arith_index(op: ArithOp) -> u8 {
+    ARITH_OPS
+        .iter()
+        .position(|&known| known == op)
+        .expect("every operator is listed") as u8
+}
+
+struct Reader<'a> {
+    chunk: &'a [u8],
+    pos: usize,
+    name: &'a str,
+    strings: &'a StringTable,
+}
+impl<'a> Reader<'a> {
+    fn error(&self, why: &str) -> Error {
+        Error::Syntax(format!("{}: bad binary format ({})", self.name, why))
+    }
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.chunk.len() - self.pos < len {
+            return Err(self.error("truncated chunk"));
+        }
+        let bytes = &self.chunk[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+    fn expect(&mut self, bytes: &[u8], why: &str) -> Result<()> {
+        let found = self.chunk.get(self.pos..self.pos + bytes.len());
+        if found != Some(bytes) {
+            return Err(self.error(why));
+        }
+        self.pos += bytes.len();
+        Ok(())
+    }
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let mut array = [0; N];
+        array.copy_from_slice(self.take(N)?);
+        Ok(array)
+    }
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+    fn u16(&mut self) -> Result<u16> {
+        Ok(u16::from_le_bytes(self.array()?))
+    }
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+    fn len(&mut self) -> Result<usize> {
+        let len = self.u32()? as usize;
+        // every item takes at least a byte, so longer lengths are damage
+        if len > self.chunk.len() - self.pos {
+            return Err(self.error("truncated chunk"));
+        }
+        Ok(len)
+    }
+    fn i32(&mut self) -> Result<i32> {
+        Ok(i32::from_le_bytes(self.array()?))
+    }
+    fn int(&mut self) -> Result<LuaInteger> {
+        Ok(LuaInteger::from_le_bytes(self.array()?))
+    }
+    fn num(&mut self) -> Result<LuaNumber> {
+        Ok(LuaNumber::from_le_bytes(self.array()?))
+    }
+    fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.len()?;
+        self.take(len)
+    }
+    /// Load a function, which is from the chunk `parent` unless it names
+    /// another
+    fn proto(&mut self, parent: &Rc<str>) -> Result<Proto> {
+        let chunk = match self.bytes()? {
+            b"" => parent.clone(),
+            name => Rc::from(String::from_utf8_lossy(name).as_ref()),
+        };
+        let line = self.u32()?;
+        let params = self.u8()?;
+        let vararg = self.u8()? != 0;
+        let max_regs = self.u8()? as usize;
+        let mut code = Vec::new();
+        for _ in 0..self.len()? {
+            code.push(self.instr()?);
+        }
+        let mut constants = Vec::new();
+        for _ in 0..self.len()? {
+            constants.push(match self.u8()? {
+                0 => self.int()?.into_value(),
+                1 => self.num()?.into_value(),
+                2 => {
+                    let bytes = self.bytes()?;
+                    self.strings.intern(bytes)
+                }
+                _ => return Err(self.error("invalid constant")),
+            });
+        }
+        let mut protos = Vec::new();
+        for _ in 0..self.len()? {
+            protos.push(Rc::new(self.proto(&chunk)?));
+        }
+        let mut upvals = Vec::new();
+        for _ in 0..self.len()? {
+            let source = match self.u8()? {
+                0 => UpvalSource::Local(self.u8()?),
+                1 => UpvalSource::Upval(self.u8()?),
+                _ => return Err(self.error("invalid upvalue")),
+            };
+            let name = match self.bytes()? {
+                b"" => Rc::from("?"),
+                name => match str::from_utf8(name) {
+                    Ok(name) => Rc::from(name),
+                    Err(_) => return Err(self.error("invalid upvalue name")),
+                },
+            };
+            upvals.push(UpvalInfo { name, source });
+        }
+        let mut lines = Vec::new();
+        for _ in 0..self.len()? {
+            lines.push(self.u32()?);
+        }
+        let mut locals = Vec::new();
+        for _ in 0..self.len()? {
+            let name = match str::from_utf8(self.bytes()?) {
+                Ok(name) => Rc::from(name),
+                Err(_) => return Err(self.error("invalid local name")),
+            };
+            locals.push(LocalInfo {
+                name,
+                reg: self.u8()?,
+                start: self.u32()? as usize,
+                end: self.u32()? as usize,
+                captured: self.u8()? != 0,
+            });
+        }
+        let proto = Proto {
+            code,
+            lines,
+            constants,
+            protos,
+            params,
+            vararg,
+            max_regs,
+            chunk,
+            line,
+            locals,
+            upvals,
+        };
+        self.verify(&proto)?;
+        Ok(proto)
+    }
+    fn instr(&mut self) -> Result<Instr> {
+        Ok(match self.u8()? {
+            0 => Instr::Move(self.u8()?, self.u8()?),
+            1 => Instr::LoadK(self.u8()?, self.u32()?),
+            2 => Instr::LoadNil(self.u8()?, self.u8()?),
+            3 => Instr::LoadBool(self.u8()?, self.u8()? != 0),
+            4 => Instr::GetGlobal(self.u8()?, self.u32()?),
+            5 => Instr::SetGlobal(self.u8()?, self.u32()?),
+            6 => Instr::GetTable(self.u8()?, self.u8()?, self.u8()?),
+            7 => Instr::SetTable(self.u8()?, self.u8()?, self.u8()?),
+            8 => Instr::GetMethod(self.u8()?, self.u8()?, self.u32()?),
+            9 => Instr::NewTable(self.u8()?, self.u16()?, self.u16()?),
+            10 => Instr::SetList(self.u8()?, self.u8()?, self.u8()?, self.u32()?),
+            11 => Instr::Arith(self.arith_op()?, self.u8()?, self.u8()?, self.u8()?),
+            12 => Instr::Unary(self.arith_op()?, self.u8()?, self.u8()?),
+            13 => Instr::Not(self.u8()?, self.u8()?),
+            14 => Instr::Len(self.u8()?, self.u8()?),
+            15 => Instr::Concat(self.u8()?, self.u8()?, self.u8()?),
+            16 => Instr::Eq(self.u8()?, self.u8()?, self.u8()?),
+            17 => Instr::Ne(self.u8()?, self.u8()?, self.u8()?),
+            18 => Instr::Lt(self.u8()?, self.u8()?, self.u8()?),
+            19 => Instr::Le(self.u8()?, self.u8()?, self.u8()?),
+            20 => Instr::Jump(self.i32()?),
+            21 => Instr::JumpIf(self.u8()?, self.i32()?),
+            22 => Instr::JumpIfNot(self.u8()?, self.i32()?),
+            23 => Instr::Call(self.u8()?, self.u8()?, self.u8()?),
+            24 => Instr::Return(self.u8()?, self.u8()?),
+            25 => Instr::ForPrep(self.u8()?, self.i32()?),
+            26 => Instr::ForLoop(self.u8()?, self.i32()?),
+            27 => Instr::TForCall(self.u8()?, self.u8()?),
+            28 => Instr::TForLoop(self.u8()?, self.i32()?),
+            29 => Instr::Closure(self.u8()?, self.u32()?),
+            30 => Instr::VarArg(self.u8()?, self.u8()?),
+            31 => Instr::JumpEq(self.u8()?, self.u8()?, self.u8()? != 0, self.i32()?),
+            32 => Instr::JumpLt(self.u8()?, self.u8()?, self.u8()? != 0, self.i32()?),
+            33 => Instr::JumpLe(self.u8()?, self.u8()?, self.u8()? != 0, self.i32()?),
+            34 => Instr::NewCell(self.u8()?),
+            35 => Instr::GetCell(self.u8()?, self.u8()?),
+            36 => Instr::SetCell(self.u8()?, self.u8()?),
+            37 => Instr::GetUpval(self.u8()?, self.u8()?),
+            38 => Instr::SetUpval(self.u8()?, self.u8()?),
+            39 => Instr::TailCall(self.u8()?, self.u8()?),
+            40 => Instr::ConcatAll(self.u8()?, self.u8()?, self.u8()?),
+            _ => return Err(self.error("invalid instruction")),
+        })
+    }
+    fn arith_op(&mut self) -> Result<ArithOp> {
+        match ARITH_OPS.get(self.u8()? as usize) {
+            Some(&op) => Ok(op),
+            None => Err(self.error("invalid instruction")),
+        }
+    }
+    /// Check that running `proto` stays within its registers, constants
+    /// and code
+    fn verify(&self, proto: &Proto) -> Result<()> {
+        let invalid = || self.error("invalid instruction");
+        if proto.params as usize > proto.max_regs
+            || (!proto.lines.is_empty() && proto.lines.len() != proto.code.len())
+        {
+            return Err(self.error("corrupted chunk"));
+        }
+        // closures take their upvalues from the function creating them
+        for upval in proto.protos.iter().flat_map(|inner| &inner.upvals) {
+            let valid = match upval.source {
+                UpvalSource::Local(reg) => (reg as usize) < proto.max_regs,
+                UpvalSource::Upval(u) => (u as usize) < proto.upvals.len(),
+            };
+            if !valid {
+                return Err(self.error("invalid upvalue"));
+            }
+        }
+        // falling off the end of the code is prevented by it ending in a
+        // return or a jump
+        match proto.code.last() {
+            Some(Instr::Return(..)) | Some(Instr::Jump(_)) => {}
+            _ => return Err(invalid()),
+        }
+        for (pc, instr) in proto.code.iter().enumerate() {
+            if registers(instr) > proto.max_regs {
+                return Err(invalid());
+            }
+            let constant = match *instr {
+                Instr::LoadK(_, k) => Some((k, false)),
+                Instr::GetGlobal(_, k) | Instr::SetGlobal(_, k) | Instr::GetMethod(_, _, k) => {
+                    Some((k, true))
+                }
+                _ => None,
+            };
+            if let Some((k, names)) = constant {
+                match proto.constants.get(k as usize) {
+                    Some(val) if !names || val.type_of() == Type::String => {}
+                    _ => return Err(invalid()),
+                }
+            }
+            match *instr {
+                Instr::Closure(_, p) if p as usize >= proto.protos.len() => return Err(invalid()),
+                Instr::GetUpval(_, u) | Instr::SetUpval(_, u)
+                    if u as usize >= proto.upvals.len() =>
+                {
+                    return Err(invalid())
+                }
+                _ => {}
+            }
+            if let Some(offset) = instr.jump_offset() {
+                let target = pc as i64 + 1 + i64::from(offset);
+                if target < 0 || target >= proto.code.len() as i64 {
+                    return Err(invalid());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// How many registers an instruction needs, counting from the first
+///
+/// Values up to the top of the stack are counted where they are used, as
+/// the stack is made big enough for them then.
+fn registers(instr: &Instr) -> usize {
+    let top = |regs: &[Reg]| regs.iter().map(|&reg| reg as usize + 1).max().unwrap_or(0);
+    match *instr {
+        Instr::Return(a, MULTI) | Instr::TailCall(a, MULTI) => top(&[a]),
+        Instr::TailCall(a, b) => a as usize + 1 + b as usize,
+        Instr::SetList(a, b, MULTI, _) => top(&[a, b]),
+        Instr::Call(a, b, c) => {
+            let args = if b == MULTI { 0 } else { b as usize };
+            let results = if c == MULTI { 1 } else { c as usize };
+            (a as usize + 1 + args).max(a as usize + results)
+        }
+        Instr::Move(a, b) | Instr::Not(a, b) | Instr::Len(a, b) | Instr::Unary(_, a, b) => {
+            top(&[a, b])
+        }
+        Instr::LoadK(a, _)
+        | Instr::LoadBool(a, _)
+        | Instr::GetGlobal(a, _)
+        | Instr::SetGlobal(a, _)
+        | Instr::NewTable(a, _, _)
+        | Instr::JumpIf(a, _)
+        | Instr::JumpIfNot(a, _)
+        | Instr::Closure(a, _)
+        | Instr::VarArg(a, MULTI) => top(&[a]),
+        Instr::VarArg(a, n) => top(&[a]).max(a as usize + n as usize),
+        Instr::LoadNil(a, n) | Instr::Return(a, n) => a as usize + n as usize,
+        Instr::GetTable(a, b, c)
+        | Instr::SetTable(a, b, c)
+        | Instr::Arith(_, a, b, c)
+        | Instr::Concat(a, b, c)
+        | Instr::Eq(a, b, c)
+        | Instr::Ne(a, b, c)
+        | Instr::Lt(a, b, c)
+        | Instr::Le(a, b, c) => top(&[a, b, c]),
+        Instr::JumpEq(a, b, _, _) | Instr::JumpLt(a, b, _, _) | Instr::JumpLe(a, b, _, _) => {
+            top(&[a, b])
+        }
+        Instr::NewCell(a) | Instr::GetUpval(a, _) | Instr::SetUpval(a, _) => top(&[a]),
+        Instr::GetCell(a, b) | Instr::SetCell(a, b) => top(&[a, b]),
+        Instr::GetMethod(a, b, _) => (a as usize + 2).max(b as usize + 1),
+        Instr::SetList(a, b, n, _) | Instr::ConcatAll(a, b, n) => {
+            (a as usize + 1).max(b as usize + n as usize)
+        }
+        Instr::Jump(_) => 0,
+        Instr::ForPrep(a, _) | Instr::ForLoop(a, _) | Instr::TForLoop(a, _) => a as usize + 4,
+        Instr::TForCall(a, n) => a as usize + 3 + (n as usize).max(3),
+    }
+}
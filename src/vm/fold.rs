@@ -0,0 +1,110 @@
+//! Constant folding, which works out operations on constants while
+//! compiling, as in `2 * 60 * 60` becoming `7200`, or `false and f()`
+//! becoming `false` without the call
+//!
+//! Folding uses the same arithmetic as running the code would, so integer
+//! and float results keep their subtype. Operations that would raise an
+//! error, and results that cannot be written exactly as a constant, are left
+//! to run.
+
+use ast::{Ast, BinOp, Expr, ExprKind, UnOp};
+use number::{self, Number};
+use value::{ArithOp, ConvertValue, Type, Value};
+
+/// The value of an expression of `ast` made only of constants
+pub fn fold(ast: &Ast, expr: &Expr) -> Option<Value> {
+    match expr.kind {
+        ExprKind::Nil => Some(Value::nil()),
+        ExprKind::True => Some(true.into_value()),
+        ExprKind::False => Some(false.into_value()),
+        ExprKind::Number(num) => Some(num.into_value()),
+        ExprKind::String(ref bytes) => Some(Value::string(bytes)),
+        ExprKind::Paren(inner) => fold(ast, &ast[inner]),
+        ExprKind::Unary(op, operand) => {
+            let val = fold(ast, &ast[operand])?;
+            match op {
+                UnOp::Not => Some((!val.to_bool()).into_value()),
+                UnOp::Neg => arith(ArithOp::Unm, &val, &val),
+                UnOp::BNot => arith(ArithOp::BNot, &val, &val),
+                UnOp::Len => None,
+            }
+        }
+        ExprKind::Binary(op @ BinOp::And, lhs, rhs)
+        | ExprKind::Binary(op @ BinOp::Or, lhs, rhs) => {
+            // only the left operand need be constant when it is the result
+            let val = fold(ast, &ast[lhs])?;
+            if val.to_bool() == (op == BinOp::And) {
+                fold(ast, &ast[rhs])
+            } else {
+                Some(val)
+            }
+        }
+        ExprKind::Binary(op, lhs, rhs) => {
+            binary(op, &fold(ast, &ast[lhs])?, &fold(ast, &ast[rhs])?)
+        }
+        _ => None,
+    }
+}
+
+fn binary(op: BinOp, lhs: &Value, rhs: &Value) -> Option<Value> {
+    let arith_op = match op {
+        BinOp::Add => ArithOp::Add,
+        BinOp::Sub => ArithOp::Sub,
+        BinOp::Mul => ArithOp::Mul,
+        BinOp::Div => ArithOp::Div,
+        BinOp::IDiv => ArithOp::IDiv,
+        BinOp::Mod => ArithOp::Mod,
+        BinOp::Pow => ArithOp::Pow,
+        BinOp::BAnd => ArithOp::BAnd,
+        BinOp::BOr => ArithOp::BOr,
+        BinOp::BXor => ArithOp::BXor,
+        BinOp::Shl => ArithOp::Shl,
+        BinOp::Shr => ArithOp::Shr,
+        BinOp::Concat => {
+            let joinable =
+                |val: &Value| val.type_of() == Type::String || val.type_of() == Type::Number;
+            if joinable(lhs) && joinable(rhs) {
+                return lhs.concat(rhs).ok();
+            }
+            return None;
+        }
+        BinOp::Eq => return Some(lhs.raw_equal(rhs).into_value()),
+        BinOp::Ne => return Some((!lhs.raw_equal(rhs)).into_value()),
+        BinOp::Lt => return compare(lhs, rhs, Value::lua_lt),
+        BinOp::Le => return compare(lhs, rhs, Value::lua_le),
+        BinOp::Gt => return compare(rhs, lhs, Value::lua_lt),
+        BinOp::Ge => return compare(rhs, lhs, Value::lua_le),
+        BinOp::And | BinOp::Or => unreachable!("short-circuit operators are folded lazily"),
+    };
+    arith(arith_op, lhs, rhs)
+}
+
+/// Fold arithmetic on numbers, but not on strings, which are converted
+/// when the code runs
+fn arith(op: ArithOp, lhs: &Value, rhs: &Value) -> Option<Value> {
+    let (a, b) = (lhs.number_value()?, rhs.number_value()?);
+    // division by zero gives an infinity or NaN, or is an error
+    let divides = op == ArithOp::Div || op == ArithOp::IDiv || op == ArithOp::Mod;
+    if divides && b.to_float() == 0.0 {
+        return None;
+    }
+    match number::arith(op, a, b).ok()? {
+        // NaN has no literal, and a zero might really be -0.0
+        Number::Float(f) if f.is_nan() || f == 0.0 => None,
+        result => Some(result.into_value()),
+    }
+}
+
+/// Fold an ordering between two numbers or two strings, which are the
+/// values that compare without metamethods
+fn compare<F>(lhs: &Value, rhs: &Value, cmp: F) -> Option<Value>
+where
+    F: Fn(&Value, &Value) -> ::error::Result<bool>,
+{
+    match (lhs.type_of(), rhs.type_of()) {
+        (Type::Number, Type::Number) | (Type::String, Type::String) => {
+            cmp(lhs, rhs).ok().map(ConvertValue::into_value)
+        }
+        _ => None,
+    }
+}
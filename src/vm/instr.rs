@@ -0,0 +1,266 @@
+//! The instruction set of the bytecode VM
+
+use value::ArithOp;
+
+/// A register, which is a slot in the current call's part of the stack
+pub type Reg = u8;
+
+/// The most registers one function can use
+pub const MAX_REGS: usize = 250;
+
+/// A count of values meaning all of them up to the top of the stack, which
+/// the last call giving `MULTI` results set
+pub const MULTI: u8 = u8::MAX;
+
+/// One VM instruction, where `R[x]` is a register, `K[k]` a constant of the
+/// function and jump offsets count from the following instruction
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Instr {
+    /// `R[a] = R[b]`
+    Move(Reg, Reg),
+    /// `R[a] = K[k]`
+    LoadK(Reg, u32),
+    /// `R[a..a + n] = nil`
+    LoadNil(Reg, u8),
+    LoadBool(Reg, bool),
+    /// `R[a] = env[K[k]]`
+    GetGlobal(Reg, u32),
+    /// `env[K[k]] = R[a]`
+    SetGlobal(Reg, u32),
+    /// `R[a] = R[b][R[c]]`
+    GetTable(Reg, Reg, Reg),
+    /// `R[a][R[b]] = R[c]`
+    SetTable(Reg, Reg, Reg),
+    /// `R[a + 1] = R[b]; R[a] = R[b][K[k]]`, ready to call a method
+    GetMethod(Reg, Reg, u32),
+    /// `R[a] = {}`, with room for `b` items in its sequence and `c` other
+    /// entries
+    NewTable(Reg, u16, u16),
+    /// `R[a][first + i] = R[b + i]` for each `i` in `0..n`, where `n` can
+    /// be `MULTI`
+    SetList(Reg, Reg, u8, u32),
+    /// `R[a] = R[b] op R[c]`
+    Arith(ArithOp, Reg, Reg, Reg),
+    /// `R[a] = op R[b]`, for `ArithOp::Unm` and `ArithOp::BNot`
+    Unary(ArithOp, Reg, Reg),
+    /// `R[a] = not R[b]`
+    Not(Reg, Reg),
+    /// `R[a] = #R[b]`
+    Len(Reg, Reg),
+    /// `R[a] = R[b] .. R[c]`
+    Concat(Reg, Reg, Reg),
+    /// `R[a] = R[b] .. ... .. R[b + n - 1]`, joining each run of strings
+    /// and numbers at once
+    ConcatAll(Reg, Reg, u8),
+    /// `R[a] = R[b] == R[c]`
+    Eq(Reg, Reg, Reg),
+    /// `R[a] = R[b] ~= R[c]`
+    Ne(Reg, Reg, Reg),
+    /// `R[a] = R[b] < R[c]`
+    Lt(Reg, Reg, Reg),
+    /// `R[a] = R[b] <= R[c]`
+    Le(Reg, Reg, Reg),
+    Jump(i32),
+    /// jump if `R[a]` is truthy
+    JumpIf(Reg, i32),
+    /// jump if `R[a]` is falsy
+    JumpIfNot(Reg, i32),
+    /// jump if `(R[a] == R[b]) == cond`
+    JumpEq(Reg, Reg, bool, i32),
+    /// jump if `(R[a] < R[b]) == cond`
+    JumpLt(Reg, Reg, bool, i32),
+    /// jump if `(R[a] <= R[b]) == cond`
+    JumpLe(Reg, Reg, bool, i32),
+    /// call `R[a]` with the `b` arguments after it, leaving `c` results
+    /// from `R[a]`, dropping or padding with nils as needed, where either
+    /// count can be `MULTI`
+    Call(Reg, u8, u8),
+    /// call `R[a]` with the `b` arguments after it in place of this
+    /// function, which returns what it does
+    ///
+    /// Functions other than compiled ones are called as by
+    /// `Call(a, b, MULTI)`, leaving the following `Return` to return their
+    /// results.
+    TailCall(Reg, u8),
+    /// return `R[a..a + n]`, where `n` can be `MULTI`
+    Return(Reg, u8),
+    /// check and convert the `for` loop parameters in `R[a..a + 3]`, then
+    /// set the loop variable `R[a + 3]` or jump past the loop
+    ForPrep(Reg, i32),
+    /// step the `for` loop counter and jump back unless it passed the limit
+    ForLoop(Reg, i32),
+    /// call the iterator `R[a]` with `R[a + 1]` and `R[a + 2]`, putting
+    /// `n` results from `R[a + 3]`
+    TForCall(Reg, u8),
+    /// if `R[a + 3]` is not nil, copy it into the control variable
+    /// `R[a + 2]` and jump back
+    TForLoop(Reg, i32),
+    /// `R[a]` = a closure of the nested function `protos[p]`
+    Closure(Reg, u32),
+    /// `R[a..a + n]` = the extra arguments, padded with nils, where `n` can
+    /// be `MULTI`
+    VarArg(Reg, u8),
+    /// move `R[a]` into a new cell, which the local in `R[a]` is reached
+    /// through from then on as nested functions capture it
+    NewCell(Reg),
+    /// `R[a]` = the value in the cell of the local in `R[b]`
+    GetCell(Reg, Reg),
+    /// the cell of the local in `R[b]` = `R[a]`
+    SetCell(Reg, Reg),
+    /// `R[a] = U[u]`, where `U` is the closure's upvalues
+    GetUpval(Reg, u8),
+    /// `U[u] = R[a]`
+    SetUpval(Reg, u8),
+}
+impl Instr {
+    /// The offset of a jumping instruction
+    pub fn jump_offset(&self) -> Option<i32> {
+        match *self {
+            Instr::Jump(offset)
+            | Instr::JumpIf(_, offset)
+            | Instr::JumpIfNot(_, offset)
+            | Instr::JumpEq(_, _, _, offset)
+            | Instr::JumpLt(_, _, _, offset)
+            | Instr::JumpLe(_, _, _, offset)
+            | Instr::ForPrep(_, offset)
+            | Instr::ForLoop(_, offset)
+            | Instr::TForLoop(_, offset) => Some(offset),
+            _ => None,
+        }
+    }
+    /// Change the offset of a jumping instruction
+    pub fn set_jump_offset(&mut self, to: i32) {
+        match *self {
+            Instr::Jump(ref mut offset)
+            | Instr::JumpIf(_, ref mut offset)
+            | Instr::JumpIfNot(_, ref mut offset)
+            | Instr::JumpEq(_, _, _, ref mut offset)
+            | Instr::JumpLt(_, _, _, ref mut offset)
+            | Instr::JumpLe(_, _, _, ref mut offset)
+            | Instr::ForPrep(_, ref mut offset)
+            | Instr::ForLoop(_, ref mut offset)
+            | Instr::TForLoop(_, ref mut offset) => *offset = to,
+            _ => panic!("{:?} does not jump", self),
+        }
+    }
+    /// The register this sets, for the instructions that set one
+    pub fn target(&self) -> Option<Reg> {
+        match *self {
+            Instr::Move(a, _)
+            | Instr::LoadK(a, _)
+            | Instr::LoadBool(a, _)
+            | Instr::GetGlobal(a, _)
+            | Instr::GetTable(a, _, _)
+            | Instr::GetMethod(a, _, _)
+            | Instr::NewTable(a, _, _)
+            | Instr::Arith(_, a, _, _)
+            | Instr::Unary(_, a, _)
+            | Instr::Not(a, _)
+            | Instr::Len(a, _)
+            | Instr::Concat(a, _, _)
+            | Instr::ConcatAll(a, _, _)
+            | Instr::Eq(a, _, _)
+            | Instr::Ne(a, _, _)
+            | Instr::Lt(a, _, _)
+            | Instr::Le(a, _, _)
+            | Instr::Call(a, _, _)
+            | Instr::Closure(a, _)
+            | Instr::VarArg(a, _)
+            | Instr::GetCell(a, _)
+            | Instr::GetUpval(a, _) => Some(a),
+            _ => None,
+        }
+    }
+    /// The same instruction setting `to` instead, for those that set one
+    /// register without reading or writing the ones around it
+    pub fn retarget(&self, to: Reg) -> Option<Instr> {
+        Some(match *self {
+            Instr::Move(_, b) => Instr::Move(to, b),
+            Instr::LoadK(_, k) => Instr::LoadK(to, k),
+            Instr::LoadNil(_, 1) => Instr::LoadNil(to, 1),
+            Instr::LoadBool(_, b) => Instr::LoadBool(to, b),
+            Instr::GetGlobal(_, k) => Instr::GetGlobal(to, k),
+            Instr::GetTable(_, b, c) => Instr::GetTable(to, b, c),
+            Instr::NewTable(_, b, c) => Instr::NewTable(to, b, c),
+            Instr::Arith(op, _, b, c) => Instr::Arith(op, to, b, c),
+            Instr::Unary(op, _, b) => Instr::Unary(op, to, b),
+            Instr::Not(_, b) => Instr::Not(to, b),
+            Instr::Len(_, b) => Instr::Len(to, b),
+            Instr::Concat(_, b, c) => Instr::Concat(to, b, c),
+            Instr::Eq(_, b, c) => Instr::Eq(to, b, c),
+            Instr::Ne(_, b, c) => Instr::Ne(to, b, c),
+            Instr::Lt(_, b, c) => Instr::Lt(to, b, c),
+            Instr::Le(_, b, c) => Instr::Le(to, b, c),
+            Instr::Closure(_, p) => Instr::Closure(to, p),
+            Instr::VarArg(_, 1) => Instr::VarArg(to, 1),
+            Instr::GetCell(_, b) => Instr::GetCell(to, b),
+            Instr::GetUpval(_, u) => Instr::GetUpval(to, u),
+            _ => return None,
+        })
+    }
+    /// Whether this uses the value in `reg`
+    pub fn reads(&self, reg: Reg) -> bool {
+        let range = |a: Reg, n: usize| (a as usize..a as usize + n).contains(&(reg as usize));
+        match *self {
+            Instr::Move(_, b)
+            | Instr::GetMethod(_, b, _)
+            | Instr::Unary(_, _, b)
+            | Instr::Not(_, b)
+            | Instr::Len(_, b) => reg == b,
+            Instr::SetGlobal(a, _)
+            | Instr::JumpIf(a, _)
+            | Instr::JumpIfNot(a, _)
+            | Instr::NewCell(a)
+            | Instr::SetCell(a, _)
+            | Instr::SetUpval(a, _) => reg == a,
+            Instr::GetTable(_, b, c)
+            | Instr::Arith(_, _, b, c)
+            | Instr::Concat(_, b, c)
+            | Instr::Eq(_, b, c)
+            | Instr::Ne(_, b, c)
+            | Instr::Lt(_, b, c)
+            | Instr::Le(_, b, c)
+            | Instr::JumpEq(b, c, _, _)
+            | Instr::JumpLt(b, c, _, _)
+            | Instr::JumpLe(b, c, _, _) => reg == b || reg == c,
+            Instr::SetTable(a, b, c) => reg == a || reg == b || reg == c,
+            Instr::SetList(a, _, _, _) if reg == a => true,
+            Instr::SetList(_, b, MULTI, _)
+            | Instr::Call(b, MULTI, _)
+            | Instr::TailCall(b, MULTI)
+            | Instr::Return(b, MULTI) => reg >= b,
+            Instr::SetList(_, b, n, _) | Instr::ConcatAll(_, b, n) => range(b, n as usize),
+            Instr::Call(a, n, _) | Instr::TailCall(a, n) => range(a, n as usize + 1),
+            Instr::Return(a, n) => range(a, n as usize),
+            Instr::ForPrep(a, _) | Instr::ForLoop(a, _) | Instr::TForCall(a, _) => range(a, 3),
+            Instr::TForLoop(a, _) => reg as usize == a as usize + 3,
+            Instr::LoadK(..)
+            | Instr::LoadNil(..)
+            | Instr::LoadBool(..)
+            | Instr::GetGlobal(..)
+            | Instr::NewTable(..)
+            | Instr::Jump(_)
+            | Instr::Closure(..)
+            | Instr::VarArg(..)
+            | Instr::GetCell(..)
+            | Instr::GetUpval(..) => false,
+        }
+    }
+    /// Whether this sets `reg`
+    pub fn writes(&self, reg: Reg) -> bool {
+        let range = |a: Reg, n: usize| (a as usize..a as usize + n).contains(&(reg as usize));
+        match *self {
+            Instr::LoadNil(a, n) => range(a, n as usize),
+            Instr::GetMethod(a, _, _) => range(a, 2),
+            // there might be no values at all
+            Instr::Call(_, _, MULTI) | Instr::TailCall(..) | Instr::VarArg(_, MULTI) => false,
+            Instr::Call(a, _, n) | Instr::VarArg(a, n) => range(a, n as usize),
+            Instr::ForPrep(a, _) => range(a, 4),
+            Instr::ForLoop(a, _) => reg == a || reg as usize == a as usize + 3,
+            // compiled iterators are called with copies of their arguments there
+            Instr::TForCall(a, n) => range(a + 3, (n as usize).max(3)),
+            Instr::TForLoop(a, _) => reg as usize == a as usize + 2,
+            _ => self.target() == Some(reg),
+        }
+    }
+}
@@ -0,0 +1,1119 @@
+//! A register-based bytecode VM, which runs chunks compiled from their
+//! syntax trees in a dispatch loop rather than walking the trees
+//!
+//! Calls between compiled functions stay in the loop, with each call's
+//! registers being a window of one shared stack.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use ast::Name;
+use error::{Error, Result};
+use gc::Edge;
+use hook::{FrameInfo, HookEvent};
+use interp::{for_in_range, for_prep, is_callable};
+use limits::{self, Limits};
+use number::Number;
+use stdlib::base::{self, Protected};
+use table::Table;
+use trace;
+use value::{
+    ConvertValue, Event, LuaFunction, LuaInteger, MultiValue, StringTable, Target, Type, Value,
+};
+
+mod captures;
+mod compile;
+mod coroutine;
+mod dis;
+mod dump;
+mod fold;
+mod instr;
+mod peephole;
+pub use self::captures::free;
+pub use self::compile::{combine, compile, compile_function};
+pub use self::coroutine::{resume, running, yield_function, Coroutine};
+pub use self::dis::disassemble;
+pub use self::dump::{dump, undump, SIGNATURE};
+pub use self::instr::{Instr, Reg, MULTI};
+
+/// A compiled function
+pub struct Proto {
+    pub code: Vec<Instr>,
+    /// the source line of each instruction
+    pub lines: Vec<u32>,
+    pub constants: Vec<Value>,
+    /// the functions defined inside this one
+    pub protos: Vec<Rc<Proto>>,
+    pub params: u8,
+    pub vararg: bool,
+    /// how many registers a call uses
+    pub max_regs: usize,
+    /// the name of the chunk the function is from
+    pub chunk: Rc<str>,
+    /// the line the function is defined on, which is 0 for a chunk
+    pub line: u32,
+    pub locals: Vec<LocalInfo>,
+    /// the variables of enclosing functions it uses
+    pub upvals: Vec<UpvalInfo>,
+}
+impl Proto {
+    /// The name of the local variable in `reg` at instruction `pc`
+    pub fn local_name(&self, reg: Reg, pc: usize) -> Option<&Name> {
+        self.locals
+            .iter()
+            .rev()
+            .find(|local| local.reg == reg && local.start <= pc && pc < local.end)
+            .map(|local| &local.name)
+    }
+    /// Where instruction `pc` is in the source, as in `chunk:3`, which is
+    /// `chunk:?` for stripped chunks, as they have no line numbers
+    fn place(&self, pc: usize) -> String {
+        match self.lines.get(pc) {
+            Some(line) => format!("{}:{}", self.chunk, line),
+            None => format!("{}:?", self.chunk),
+        }
+    }
+}
+
+/// Where a local variable is, for error messages and debuggers
+pub struct LocalInfo {
+    pub name: Name,
+    pub reg: Reg,
+    /// the first instruction in its scope
+    pub start: usize,
+    /// the instruction after its scope
+    pub end: usize,
+    /// whether it is kept in a cell, as nested functions use it
+    pub captured: bool,
+}
+
+/// A variable of an enclosing function that a function uses
+pub struct UpvalInfo {
+    pub name: Name,
+    pub source: UpvalSource,
+}
+
+/// Where a closure gets an upvalue from when it is created
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum UpvalSource {
+    /// the cell of a local of the function creating it
+    Local(Reg),
+    /// an upvalue of the function creating it
+    Upval(u8),
+}
+
+/// A variable shared between a function and the closures capturing it
+///
+/// Captured locals are kept in these from their declaration on, rather than
+/// in a register until their scope ends as in Lua, since a closure can be
+/// called on another thread's stack, such as by a library function.
+type Upval = Rc<RefCell<Value>>;
+
+/// A compiled function along with what it captured
+pub struct Closure {
+    proto: Rc<Proto>,
+    /// the table that global variables are read from and written to
+    env: Value,
+    upvals: Rc<[Upval]>,
+    limits: Rc<Limits>,
+    /// the string table of the state it belongs to, which its constants
+    /// were interned in
+    strings: Rc<StringTable>,
+}
+impl Closure {
+    /// Create a closure of a function that has nothing to capture from,
+    /// such as a chunk, which gives any upvalues it has fresh nil values
+    pub fn new(
+        proto: Rc<Proto>,
+        env: Value,
+        limits: Rc<Limits>,
+        strings: Rc<StringTable>,
+    ) -> Closure {
+        let upvals = proto
+            .upvals
+            .iter()
+            .map(|_| Rc::new(RefCell::new(Value::nil())))
+            .collect();
+        Closure {
+            proto,
+            env,
+            upvals,
+            limits,
+            strings,
+        }
+    }
+    /// Create a closure of a function with the given upvalues, which are
+    /// in the order the function uses them
+    pub fn with_upvals(
+        proto: Rc<Proto>,
+        env: Value,
+        upvals: Vec<Upval>,
+        limits: Rc<Limits>,
+        strings: Rc<StringTable>,
+    ) -> Closure {
+        Closure {
+            proto,
+            env,
+            upvals: upvals.into(),
+            limits,
+            strings,
+        }
+    }
+    pub fn proto(&self) -> &Proto {
+        &self.proto
+    }
+    /// Pass its upvalues and environment to `visit`, for the collector
+    pub(crate) fn trace(&self, visit: &mut dyn FnMut(Edge)) {
+        visit(Edge::Value(&self.env));
+        visit(Edge::Cells(&self.upvals));
+    }
+    pub fn call(&self, args: Vec<Value>) -> Result<MultiValue> {
+        self.limits.enter_native()?;
+        let mut thread = Thread::new(false);
+        thread.start(self, args);
+        let step = thread.run();
+        limits::leave_native();
+        match step? {
+            Step::Done(vals) => Ok(vals),
+            _ => unreachable!("only coroutines yield"),
+        }
+    }
+}
+
+/// One call to a compiled function
+struct Frame {
+    proto: Rc<Proto>,
+    env: Value,
+    /// where its registers start on the stack
+    base: usize,
+    /// the next instruction
+    pc: usize,
+    /// how many values the caller wants back, which can be `MULTI`
+    results: u8,
+    /// the arguments beyond the named parameters
+    varargs: Vec<Value>,
+    upvals: Rc<[Upval]>,
+    limits: Rc<Limits>,
+    strings: Rc<StringTable>,
+    /// the cells of its captured locals, by register
+    cells: Vec<Option<Upval>>,
+    /// whether it was entered by a tail call, replacing its caller's frame
+    tail: bool,
+    /// where its results go
+    ret: Return,
+}
+
+/// Where a frame's results go when it returns, which in a coroutine can be
+/// other than to its caller, for the calls the VM makes itself so that
+/// they can yield
+enum Return {
+    /// to the stack slot of its function, as for any call
+    Call,
+    /// after true in the stack slot of the `pcall` or `xpcall` below its
+    /// function, which catches its errors, passing them to the message
+    /// handler if there is one
+    Protected(Option<Value>),
+    /// the first to the stack slot, as what an `__index` function gives
+    Index(usize),
+    /// nowhere, as for a `__newindex` function
+    NewIndex,
+}
+
+impl Frame {
+    /// The cell of the captured local in `reg`, which the compiler always
+    /// creates before use, while damaged binary chunks get one holding `val`
+    fn cell(&mut self, reg: Reg, val: &Value) -> &Upval {
+        let reg = reg as usize;
+        if self.cells.len() <= reg {
+            self.cells.resize(reg + 1, None);
+        }
+        self.cells[reg].get_or_insert_with(|| Rc::new(RefCell::new(val.clone())))
+    }
+}
+
+/// What made the dispatch loop stop running a frame
+enum Step {
+    /// the frame called into or returned to another
+    Switch,
+    /// the outermost frame returned
+    Done(MultiValue),
+    /// a coroutine called `coroutine.yield` with these values
+    Yield(MultiValue),
+}
+
+/// The calls in progress, with the registers of each
+struct Thread {
+    stack: Vec<Value>,
+    frames: Vec<Frame>,
+    /// the stack slot after the last value of the last call that gave all
+    /// its results
+    top: usize,
+    /// whether it runs a coroutine, which can yield from any of its frames
+    coroutine: bool,
+    /// where the call to `coroutine.yield` a suspended coroutine is in puts
+    /// its results, and how many it wants
+    yielded: (usize, u8),
+}
+impl Thread {
+    /// Pass what its stack and frames refer to to `visit`, for the
+    /// collector
+    fn trace(&self, visit: &mut dyn FnMut(Edge)) {
+        for val in &self.stack {
+            visit(Edge::Value(val));
+        }
+        for frame in &self.frames {
+            visit(Edge::Value(&frame.env));
+            for val in &frame.varargs {
+                visit(Edge::Value(val));
+            }
+            visit(Edge::Cells(&frame.upvals));
+            for cell in frame.cells.iter().flatten() {
+                visit(Edge::Cell(cell));
+            }
+            if let Return::Protected(Some(ref handler)) = frame.ret {
+                visit(Edge::Value(handler));
+            }
+        }
+    }
+    fn new(coroutine: bool) -> Thread {
+        Thread {
+            stack: Vec::new(),
+            frames: Vec::new(),
+            top: 0,
+            coroutine,
+            yielded: (0, 0),
+        }
+    }
+    /// Start calling `closure` as the outermost frame
+    fn start(&mut self, closure: &Closure, args: Vec<Value>) {
+        let nargs = args.len();
+        self.stack.reserve(nargs + 1);
+        self.stack.push(Value::nil());
+        self.stack.extend(args);
+        self.enter(closure, 0, nargs, MULTI);
+    }
+    /// The compiled function to run in this thread's frames for `func`,
+    /// which in a coroutine includes those the interpreter created, so that
+    /// they can yield
+    fn lua_closure<'v>(&self, func: &'v Value) -> Option<&'v Closure> {
+        match func.as_compiled() {
+            Some(closure) => Some(closure),
+            None if self.coroutine => func.as_interpreted()?.compiled(),
+            None => None,
+        }
+    }
+    /// Check that there is room on the stack for a call to `closure` in
+    /// the stack slot `func`
+    fn check_stack(&self, closure: &Closure, func: usize) -> Result<()> {
+        closure
+            .limits
+            .check_stack(func + 1 + closure.proto.max_regs)
+    }
+    /// Start calling `closure`, which is in the stack slot `func` with its
+    /// `nargs` arguments above it, to give `results` values, once there is
+    /// known to be room for it on the stack
+    fn enter(&mut self, closure: &Closure, func: usize, nargs: usize, results: u8) {
+        limits::enter_calls(1);
+        let proto = closure.proto.clone();
+        trace::enter(
+            &proto.chunk,
+            proto.line,
+            proto.lines.first().cloned().unwrap_or(0),
+        );
+        let base = func + 1;
+        let params = proto.params as usize;
+        let varargs = if proto.vararg && nargs > params {
+            self.stack[base + params..base + nargs].to_vec()
+        } else {
+            Vec::new()
+        };
+        self.stack.truncate(base + nargs.min(params));
+        self.stack.resize(base + proto.max_regs, Value::nil());
+        self.frames.push(Frame {
+            proto,
+            env: closure.env.clone(),
+            base,
+            pc: 0,
+            results,
+            varargs,
+            upvals: closure.upvals.clone(),
+            limits: closure.limits.clone(),
+            strings: closure.strings.clone(),
+            cells: Vec::new(),
+            tail: false,
+            ret: Return::Call,
+        });
+    }
+    /// Return from the innermost frame the `count` values from the stack
+    /// slot `from`, moving those its caller wants to its function's slot
+    fn leave(&mut self, from: usize, count: usize) -> Step {
+        let frame = self.frames.pop().expect("a function is running");
+        limits::leave_calls(1);
+        trace::leave(1);
+        let end = match self.frames.last() {
+            Some(caller) => caller.base + caller.proto.max_regs,
+            None => return Step::Done(self.stack.drain(from..from + count).collect()),
+        };
+        let func = frame.base - 1;
+        let mut results = frame.results;
+        match frame.ret {
+            Return::Call => {}
+            Return::Protected(_) => {
+                // the results follow true, where `pcall` was
+                self.stack[func - 1] = true.into_value();
+                results = match results {
+                    MULTI => MULTI,
+                    0 => 0,
+                    results => results - 1,
+                };
+            }
+            Return::Index(dest) => {
+                let val = match count {
+                    0 => Value::nil(),
+                    _ => ::std::mem::replace(&mut self.stack[from], Value::nil()),
+                };
+                self.stack.truncate(end);
+                self.stack[dest] = val;
+                return Step::Switch;
+            }
+            Return::NewIndex => {
+                self.stack.truncate(end);
+                return Step::Switch;
+            }
+        }
+        let want = match results {
+            MULTI => {
+                self.top = func + count;
+                count
+            }
+            results => results as usize,
+        };
+        let kept = count.min(want);
+        for i in 0..kept {
+            let val = ::std::mem::replace(&mut self.stack[from + i], Value::nil());
+            self.stack[func + i] = val;
+        }
+        self.stack.truncate(func + kept);
+        self.stack.resize(end.max(func + want), Value::nil());
+        Step::Switch
+    }
+    /// Put the values a function returned in the stack from `at`, keeping
+    /// `want` of them, which can be `MULTI`
+    fn set_results(&mut self, at: usize, mut vals: MultiValue, want: u8) {
+        match want {
+            MULTI => self.top = at + vals.len(),
+            want => vals.resize(want as usize, Value::nil()),
+        }
+        if self.stack.len() < at + vals.len() {
+            self.stack.resize(at + vals.len(), Value::nil());
+        }
+        for (slot, val) in self.stack[at..].iter_mut().zip(vals) {
+            *slot = val;
+        }
+    }
+    /// Call a value other than a VM closure with the `nargs` values from
+    /// the stack slot `from`, which Rust functions borrow in place
+    fn call_value(&self, func: &Value, from: usize, nargs: usize) -> Result<MultiValue> {
+        let args = &self.stack[from..from + nargs];
+        match LuaFunction::from_value(func) {
+            Some(func) => func(args),
+            None => func.call(args.to_vec()),
+        }
+    }
+    /// How many values there are from the stack slot `from` to the top,
+    /// which a damaged binary chunk can leave anywhere
+    fn to_top(&self, from: usize) -> usize {
+        self.top.min(self.stack.len()).saturating_sub(from)
+    }
+    /// Run until the outermost frame returns or the coroutine yields
+    fn run(&mut self) -> Result<Step> {
+        loop {
+            let (proto, env, upvals, limits, base, mut pc) = {
+                let frame = self.frames.last().expect("a function is running");
+                (
+                    frame.proto.clone(),
+                    frame.env.clone(),
+                    frame.upvals.clone(),
+                    frame.limits.clone(),
+                    frame.base,
+                    frame.pc,
+                )
+            };
+            match self.execute(&proto, &env, &upvals, &limits, base, &mut pc) {
+                Ok(Step::Switch) => {}
+                Ok(step) => return Ok(step),
+                Err(err) => {
+                    self.frames.last_mut().expect("a function is running").pc = pc;
+                    let protected = self
+                        .frames
+                        .iter()
+                        .rposition(|frame| matches!(frame.ret, Return::Protected(_)));
+                    match protected {
+                        Some(at) => self.catch(at, err),
+                        None => return Err(self.unwind(0, err)),
+                    }
+                }
+            }
+        }
+    }
+    /// Give an error the position of the innermost frame, adding every
+    /// frame from `from` on to its traceback, as it leaves them
+    #[cold]
+    fn unwind(&self, from: usize, mut err: Error) -> Error {
+        for frame in self.frames[from..].iter().rev() {
+            // the instruction before `pc` is the one running
+            let place = frame.proto.place(frame.pc - 1);
+            err = err.located(&place);
+            if frame.proto.line == 0 {
+                trace::unwind(place, "main chunk");
+            } else {
+                let proto = &frame.proto;
+                trace::unwind(
+                    place,
+                    format_args!("function <{}:{}>", proto.chunk, proto.line),
+                );
+            }
+        }
+        limits::leave_calls(self.frames.len() - from);
+        trace::leave(self.frames.len() - from);
+        err
+    }
+    /// Stop running the frames from `at` on, the outermost of which a
+    /// `pcall` or `xpcall` is running, for the error `err` they raised, and
+    /// give false and the error as that call's results
+    #[cold]
+    fn catch(&mut self, at: usize, err: Error) {
+        let err = self.unwind(at, err);
+        let (func, results, handler) = {
+            let frame = &self.frames[at];
+            let handler = match frame.ret {
+                Return::Protected(ref handler) => handler.clone(),
+                _ => unreachable!("the frame is protected"),
+            };
+            (frame.base - 2, frame.results, handler)
+        };
+        self.frames.truncate(at);
+        let val = match handler {
+            Some(handler) => match trace::handling(|| handler.call(vec![err.into_value()])) {
+                Ok(vals) => vals.into_first(),
+                Err(err) => err.into_value(),
+            },
+            None => err.into_value(),
+        };
+        trace::catch();
+        let caller = self.frames.last().expect("the caller of pcall is running");
+        let end = caller.base + caller.proto.max_regs;
+        self.stack.resize(end, Value::nil());
+        self.set_results(func, vec![false.into_value(), val].into(), results);
+    }
+    /// Record the frames of a coroutine being resumed as running again, as
+    /// they were when it yielded
+    fn resume_levels(&self) {
+        limits::enter_calls(self.frames.len());
+        for frame in &self.frames {
+            let line = frame.proto.lines.get(frame.pc - 1).cloned().unwrap_or(0);
+            trace::enter(&frame.proto.chunk, frame.proto.line, line);
+        }
+    }
+    /// Record the frames of a coroutine that yielded as no longer running
+    fn suspend_levels(&self) {
+        limits::leave_calls(self.frames.len());
+        trace::leave(self.frames.len());
+    }
+    /// Start the call the `pcall` or `xpcall` in the stack slot `slot`
+    /// makes with its `nargs` arguments in a frame of its own, if it calls
+    /// a Lua function, giving whether it did so
+    fn protect(
+        &mut self,
+        kind: Protected,
+        slot: usize,
+        nargs: usize,
+        results: u8,
+        pc: usize,
+    ) -> Result<bool> {
+        let (handler, nfixed) = match kind {
+            Protected::Pcall => (None, 1),
+            Protected::Xpcall => (self.stack.get(slot + 2).cloned(), 2),
+        };
+        // let them report missing arguments
+        if nargs < nfixed {
+            return Ok(false);
+        }
+        let func = self.stack[slot + 1].clone();
+        let closure = match self.lua_closure(&func) {
+            Some(closure) => closure,
+            None => return Ok(false),
+        };
+        if self.check_stack(closure, slot + 1).is_err() {
+            // so that it catches the error
+            return Ok(false);
+        }
+        if kind == Protected::Xpcall {
+            // the arguments follow the function, where the handler was
+            for i in slot + 2..slot + nargs {
+                let val = ::std::mem::replace(&mut self.stack[i + 1], Value::nil());
+                self.stack[i] = val;
+            }
+        }
+        self.frames.last_mut().expect("a function is running").pc = pc;
+        self.enter(closure, slot + 1, nargs - nfixed, results);
+        self.frames.last_mut().expect("just entered").ret = Return::Protected(handler);
+        Ok(true)
+    }
+    /// Index `obj` with `key`, or assign `val` to it, as `event` says,
+    /// giving what that gives unless it calls a Lua function as a
+    /// metamethod, which is started instead with its result going to the
+    /// stack slot `dest`
+    #[allow(clippy::too_many_arguments)]
+    fn index(
+        &mut self,
+        obj: &Value,
+        key: &Value,
+        val: Option<&Value>,
+        event: Event,
+        dest: usize,
+        pc: usize,
+    ) -> Result<Option<Value>> {
+        let (handler, obj) = match obj.index_target(key, event)? {
+            Target::Handler(handler, obj) => (handler, obj),
+            Target::Table(table, found) => {
+                return match val {
+                    Some(val) => table
+                        .raw_set(key.clone(), val.clone())
+                        .map(|()| Some(found)),
+                    None => Ok(Some(found)),
+                }
+            }
+        };
+        let mut args = vec![obj, key.clone()];
+        args.extend(val.cloned());
+        let closure = match self.lua_closure(&handler) {
+            Some(closure) => closure,
+            None => return handler.call(args).map(|vals| Some(vals.into_first())),
+        };
+        let frame = self.frames.last_mut().expect("a function is running");
+        frame.pc = pc;
+        let func = frame.base + frame.proto.max_regs;
+        self.check_stack(closure, func)?;
+        let nargs = args.len();
+        self.stack.truncate(func);
+        self.stack.push(handler.clone());
+        self.stack.extend(args);
+        self.enter(closure, func, nargs, 1);
+        self.frames.last_mut().expect("just entered").ret = match val {
+            Some(_) => Return::NewIndex,
+            None => Return::Index(dest),
+        };
+        Ok(None)
+    }
+    /// Report the instruction at `pc` to the hook, as a call if it starts
+    /// the function, and as a line if it starts one or jumped back from
+    /// `last`, the instruction run before it
+    #[cold]
+    fn hook(&self, proto: &Proto, pc: usize, last: &mut Option<usize>) -> Result<()> {
+        let line = proto.lines.get(pc).cloned();
+        let frame = self.frames.last().expect("a function is running");
+        let hooks = &frame.limits.hooks;
+        if pc == 0 && last.is_none() {
+            let event = if frame.tail {
+                HookEvent::TailCall
+            } else {
+                HookEvent::Call
+            };
+            hooks.report(event, &proto.chunk, line, &|| self.snapshot(pc))?;
+        }
+        if hooks.count() {
+            hooks.report(HookEvent::Count, &proto.chunk, line, &|| self.snapshot(pc))?;
+        }
+        if let Some(line) = line {
+            // if the hook was just set, the instruction before set it
+            let new = match last.or_else(|| pc.checked_sub(1)) {
+                Some(last) => pc <= last || proto.lines.get(last) != Some(&line),
+                None => true,
+            };
+            if new {
+                let event = HookEvent::Line(line);
+                hooks.report(event, &proto.chunk, Some(line), &|| self.snapshot(pc))?;
+            }
+        }
+        *last = Some(pc);
+        Ok(())
+    }
+    /// The innermost frame's variables for debuggers, where it is running
+    /// the instruction at `pc`, sharing those kept in cells
+    fn snapshot(&self, pc: usize) -> FrameInfo {
+        let frame = self.frames.last().expect("a function is running");
+        let proto = &frame.proto;
+        let locals = proto
+            .locals
+            .iter()
+            .filter(|local| local.start <= pc && pc < local.end)
+            .map(|local| {
+                let reg = local.reg as usize;
+                let cell = match frame.cells.get(reg) {
+                    Some(Some(cell)) if local.captured => cell.clone(),
+                    _ => Rc::new(RefCell::new(self.stack[frame.base + reg].clone())),
+                };
+                (local.name.clone(), cell)
+            })
+            .collect();
+        let upvalues = proto
+            .upvals
+            .iter()
+            .zip(frame.upvals.iter())
+            .map(|(info, cell)| (info.name.clone(), cell.clone()))
+            .collect();
+        FrameInfo {
+            chunk: proto.chunk.clone(),
+            defined: proto.line,
+            line: proto.lines.get(pc).cloned(),
+            locals,
+            upvalues,
+            env: frame.env.clone(),
+            limits: frame.limits.clone(),
+            strings: frame.strings.clone(),
+        }
+    }
+    /// Run the innermost frame until it calls or returns, with `pc` left
+    /// after the last instruction run
+    fn execute(
+        &mut self,
+        proto: &Proto,
+        env: &Value,
+        upvals: &[Upval],
+        limits: &Limits,
+        base: usize,
+        pc: &mut usize,
+    ) -> Result<Step> {
+        macro_rules! reg {
+            ($reg:expr) => {
+                self.stack[base + $reg as usize]
+            };
+        }
+        macro_rules! cell {
+            ($reg:expr) => {
+                self.frames
+                    .last_mut()
+                    .expect("a function is running")
+                    .cell($reg, &self.stack[base + $reg as usize])
+            };
+        }
+        // the last instruction run in this frame, for line events
+        let mut last = pc.checked_sub(1);
+        // the line last recorded for tracebacks
+        let mut traced = None;
+        loop {
+            let instr = proto.code[*pc];
+            let line = proto.lines.get(*pc);
+            if line != traced {
+                traced = line;
+                trace::set_line(line.cloned().unwrap_or(0));
+            }
+            *pc += 1;
+            limits.step()?;
+            if limits.hooks.active() {
+                self.hook(proto, *pc - 1, &mut last)?;
+            }
+            match instr {
+                Instr::Move(a, b) => {
+                    let val = reg!(b).clone();
+                    reg!(a) = val;
+                }
+                Instr::LoadK(a, k) => reg!(a) = proto.constants[k as usize].clone(),
+                Instr::LoadNil(a, n) => {
+                    for reg in a..a + n {
+                        reg!(reg) = Value::nil();
+                    }
+                }
+                Instr::LoadBool(a, val) => reg!(a) = val.into_value(),
+                Instr::GetGlobal(a, k) => reg!(a) = env.get_index(&proto.constants[k as usize])?,
+                Instr::SetGlobal(a, k) => {
+                    env.set_index(proto.constants[k as usize].clone(), reg!(a).clone())?
+                }
+                Instr::GetTable(a, b, c) => {
+                    check_index(&reg!(b), Event::Index, proto, *pc - 1, b)?;
+                    let val = if self.coroutine {
+                        let (obj, key) = (reg!(b).clone(), reg!(c).clone());
+                        match self.index(&obj, &key, None, Event::Index, base + a as usize, *pc)? {
+                            Some(val) => val,
+                            None => return Ok(Step::Switch),
+                        }
+                    } else {
+                        reg!(b).get_index(&reg!(c))?
+                    };
+                    reg!(a) = val;
+                }
+                Instr::SetTable(a, b, c) => {
+                    check_index(&reg!(a), Event::NewIndex, proto, *pc - 1, a)?;
+                    if self.coroutine {
+                        let (obj, key, val) = (reg!(a).clone(), reg!(b).clone(), reg!(c).clone());
+                        if self
+                            .index(&obj, &key, Some(&val), Event::NewIndex, 0, *pc)?
+                            .is_none()
+                        {
+                            return Ok(Step::Switch);
+                        }
+                    } else {
+                        reg!(a).set_index(reg!(b).clone(), reg!(c).clone())?;
+                    }
+                }
+                Instr::GetMethod(a, b, k) => {
+                    let obj = reg!(b).clone();
+                    check_index(&obj, Event::Index, proto, *pc - 1, b)?;
+                    let key = &proto.constants[k as usize];
+                    let method = if self.coroutine {
+                        // the object is in place before an `__index`
+                        // function runs instead of the lookup
+                        reg!(a as usize + 1) = obj.clone();
+                        match self.index(&obj, key, None, Event::Index, base + a as usize, *pc)? {
+                            Some(method) => method,
+                            None => return Ok(Step::Switch),
+                        }
+                    } else {
+                        obj.get_index(key)?
+                    };
+                    reg!(a as usize + 1) = obj;
+                    reg!(a) = method;
+                }
+                Instr::NewTable(a, b, c) => {
+                    reg!(a) = Table::with_capacity(b as usize, c as usize).into_value()
+                }
+                Instr::SetList(a, b, n, first) => {
+                    let n = match n {
+                        MULTI => {
+                            let n = self.to_top(base + b as usize);
+                            // the constructor couldn't tell how many there are
+                            if let Some(table) = Table::from_value(&reg!(a)) {
+                                table.reserve(n);
+                            }
+                            n
+                        }
+                        n => n as usize,
+                    };
+                    for i in 0..n {
+                        let key = (LuaInteger::from(first) + i as LuaInteger).into_value();
+                        reg!(a).raw_set(key, reg!(b as usize + i).clone())?;
+                    }
+                }
+                Instr::Arith(op, a, b, c) => {
+                    let val = reg!(b).arith(op, &reg!(c))?;
+                    reg!(a) = val;
+                }
+                Instr::Unary(op, a, b) => {
+                    let val = reg!(b).arith(op, &reg!(b))?;
+                    reg!(a) = val;
+                }
+                Instr::Not(a, b) => {
+                    let val = !reg!(b).to_bool();
+                    reg!(a) = val.into_value();
+                }
+                Instr::Len(a, b) => {
+                    let val = reg!(b).len()?;
+                    reg!(a) = val;
+                }
+                Instr::Concat(a, b, c) => {
+                    let val = reg!(b).concat(&reg!(c))?;
+                    reg!(a) = val;
+                }
+                Instr::ConcatAll(a, b, n) => {
+                    let from = base + b as usize;
+                    let val = Value::concat_all(&self.stack[from..from + n as usize])?;
+                    reg!(a) = val;
+                }
+                Instr::Eq(a, b, c) => {
+                    let val = reg!(b).lua_eq(&reg!(c))?;
+                    reg!(a) = val.into_value();
+                }
+                Instr::Ne(a, b, c) => {
+                    let val = !reg!(b).lua_eq(&reg!(c))?;
+                    reg!(a) = val.into_value();
+                }
+                Instr::Lt(a, b, c) => {
+                    let val = reg!(b).lua_lt(&reg!(c))?;
+                    reg!(a) = val.into_value();
+                }
+                Instr::Le(a, b, c) => {
+                    let val = reg!(b).lua_le(&reg!(c))?;
+                    reg!(a) = val.into_value();
+                }
+                Instr::Jump(offset) => *pc = jump(*pc, offset),
+                Instr::JumpIf(a, offset) => {
+                    if reg!(a).to_bool() {
+                        *pc = jump(*pc, offset);
+                    }
+                }
+                Instr::JumpIfNot(a, offset) => {
+                    if !reg!(a).to_bool() {
+                        *pc = jump(*pc, offset);
+                    }
+                }
+                Instr::JumpEq(a, b, cond, offset) => {
+                    if reg!(a).lua_eq(&reg!(b))? == cond {
+                        *pc = jump(*pc, offset);
+                    }
+                }
+                Instr::JumpLt(a, b, cond, offset) => {
+                    if reg!(a).lua_lt(&reg!(b))? == cond {
+                        *pc = jump(*pc, offset);
+                    }
+                }
+                Instr::JumpLe(a, b, cond, offset) => {
+                    if reg!(a).lua_le(&reg!(b))? == cond {
+                        *pc = jump(*pc, offset);
+                    }
+                }
+                Instr::Call(a, b, _) | Instr::TailCall(a, b) => {
+                    let func = reg!(a).clone();
+                    let slot = base + a as usize;
+                    let nargs = match b {
+                        MULTI => self.to_top(slot + 1),
+                        b => b as usize,
+                    };
+                    let results = match instr {
+                        Instr::Call(_, _, c) => c,
+                        _ => MULTI,
+                    };
+                    if let Some(closure) = self.lua_closure(&func) {
+                        self.check_stack(closure, slot)?;
+                        if let Instr::TailCall(..) = instr {
+                            // the function and arguments replace this frame's
+                            let frame = self.frames.pop().expect("a function is running");
+                            limits::leave_calls(1);
+                            trace::leave(1);
+                            let dest = frame.base - 1;
+                            for i in 0..=nargs {
+                                let val =
+                                    ::std::mem::replace(&mut self.stack[slot + i], Value::nil());
+                                self.stack[dest + i] = val;
+                            }
+                            self.enter(closure, dest, nargs, frame.results);
+                            let entered = self.frames.last_mut().expect("just entered");
+                            entered.tail = true;
+                            entered.ret = frame.ret;
+                        } else {
+                            self.frames.last_mut().expect("a function is running").pc = *pc;
+                            self.enter(closure, slot, nargs, results);
+                        }
+                        return Ok(Step::Switch);
+                    }
+                    if !is_callable(&func) {
+                        return Err(Error::Runtime(format!(
+                            "attempt to call a {} value{}",
+                            func.type_of(),
+                            describe(proto, *pc - 1, a)
+                        )));
+                    }
+                    if self.coroutine {
+                        if let Some(kind) = base::protected(&func) {
+                            if self.protect(kind, slot, nargs, results, *pc)? {
+                                return Ok(Step::Switch);
+                            }
+                        }
+                    }
+                    if self.coroutine && coroutine::is_yield(&func) {
+                        self.frames.last_mut().expect("a function is running").pc = *pc;
+                        self.yielded = (slot, results);
+                        let args = self.stack[slot + 1..slot + 1 + nargs].to_vec();
+                        return Ok(Step::Yield(args.into()));
+                    }
+                    let vals = self.call_value(&func, slot + 1, nargs)?;
+                    self.set_results(slot, vals, results);
+                }
+                Instr::Return(a, n) => {
+                    if limits.hooks.active() {
+                        let at = *pc - 1;
+                        let line = proto.lines.get(at).cloned();
+                        limits
+                            .hooks
+                            .report(HookEvent::Return, &proto.chunk, line, &|| self.snapshot(at))?;
+                    }
+                    let from = base + a as usize;
+                    let count = match n {
+                        MULTI => self.to_top(from),
+                        n => n as usize,
+                    };
+                    return Ok(self.leave(from, count));
+                }
+                Instr::ForPrep(a, offset) => {
+                    let a = a as usize;
+                    let params =
+                        for_prep(reg!(a).clone(), reg!(a + 1).clone(), reg!(a + 2).clone())?;
+                    match params {
+                        Some([start, limit, step]) => {
+                            reg!(a) = start.into_value();
+                            reg!(a + 1) = limit.into_value();
+                            reg!(a + 2) = step.into_value();
+                            reg!(a + 3) = start.into_value();
+                        }
+                        None => *pc = jump(*pc, offset),
+                    }
+                }
+                Instr::ForLoop(a, offset) => {
+                    let a = a as usize;
+                    let (counter, limit, step) = match (
+                        reg!(a).number_value(),
+                        reg!(a + 1).number_value(),
+                        reg!(a + 2).number_value(),
+                    ) {
+                        (Some(counter), Some(limit), Some(step)) => (counter, limit, step),
+                        _ => unreachable!("the loop state is set by ForPrep"),
+                    };
+                    let next = match (counter, step) {
+                        (Number::Int(counter), Number::Int(step)) => {
+                            counter.checked_add(step).map(Number::Int)
+                        }
+                        _ => Some(Number::Float(counter.to_float() + step.to_float())),
+                    };
+                    if let Some(next) = next.filter(|&next| for_in_range(next, limit, step)) {
+                        reg!(a) = next.into_value();
+                        reg!(a + 3) = next.into_value();
+                        *pc = jump(*pc, offset);
+                    }
+                }
+                Instr::TForCall(a, n) => {
+                    let a = a as usize;
+                    let func = reg!(a).clone();
+                    if let Some(closure) = self.lua_closure(&func) {
+                        self.check_stack(closure, base + a + 3)?;
+                        // the results replace the copied function and
+                        // arguments
+                        reg!(a + 3) = func.clone();
+                        reg!(a + 4) = reg!(a + 1).clone();
+                        reg!(a + 5) = reg!(a + 2).clone();
+                        self.frames.last_mut().expect("a function is running").pc = *pc;
+                        self.enter(closure, base + a + 3, 2, n);
+                        return Ok(Step::Switch);
+                    }
+                    let vals = self.call_value(&func, base + a + 1, 2)?;
+                    self.set_results(base + a + 3, vals, n);
+                }
+                Instr::TForLoop(a, offset) => {
+                    let a = a as usize;
+                    if !reg!(a + 3).is_nil() {
+                        let val = reg!(a + 3).clone();
+                        reg!(a + 2) = val;
+                        *pc = jump(*pc, offset);
+                    }
+                }
+                Instr::Closure(a, p) => {
+                    let inner = proto.protos[p as usize].clone();
+                    let frame = self.frames.last_mut().expect("a function is running");
+                    let stack = &self.stack;
+                    let captured = inner
+                        .upvals
+                        .iter()
+                        .map(|upval| match upval.source {
+                            UpvalSource::Local(reg) => {
+                                frame.cell(reg, &stack[base + reg as usize]).clone()
+                            }
+                            UpvalSource::Upval(u) => upvals[u as usize].clone(),
+                        })
+                        .collect();
+                    let closure = Closure {
+                        proto: inner,
+                        env: env.clone(),
+                        upvals: captured,
+                        limits: frame.limits.clone(),
+                        strings: frame.strings.clone(),
+                    };
+                    reg!(a) = Value::compiled(closure);
+                }
+                Instr::VarArg(a, n) => {
+                    let varargs = &self.frames.last().expect("a function is running").varargs;
+                    let at = base + a as usize;
+                    let count = match n {
+                        MULTI => {
+                            self.top = at + varargs.len();
+                            varargs.len()
+                        }
+                        n => n as usize,
+                    };
+                    if self.stack.len() < at + count {
+                        self.stack.resize(at + count, Value::nil());
+                    }
+                    for i in 0..count {
+                        self.stack[at + i] = varargs.get(i).cloned().unwrap_or_else(Value::nil);
+                    }
+                }
+                Instr::NewCell(a) => {
+                    let val = ::std::mem::replace(&mut reg!(a), Value::nil());
+                    let frame = self.frames.last_mut().expect("a function is running");
+                    if frame.cells.len() <= a as usize {
+                        frame.cells.resize(a as usize + 1, None);
+                    }
+                    frame.cells[a as usize] = Some(Rc::new(RefCell::new(val)));
+                }
+                Instr::GetCell(a, b) => {
+                    let val = cell!(b).borrow().clone();
+                    reg!(a) = val;
+                }
+                Instr::SetCell(a, b) => {
+                    let val = reg!(a).clone();
+                    *cell!(b).borrow_mut() = val;
+                }
+                Instr::GetUpval(a, u) => reg!(a) = upvals[u as usize].borrow().clone(),
+                Instr::SetUpval(a, u) => *upvals[u as usize].borrow_mut() = reg!(a).clone(),
+            }
+        }
+    }
+}
+
+/// The instruction a jump from before `pc` lands on
+fn jump(pc: usize, offset: i32) -> usize {
+    (pc as isize + offset as isize) as usize
+}
+
+/// Check that `obj`, from register `reg`, can be indexed, as the
+/// indexing itself would fail without saying which variable was involved
+fn check_index(obj: &Value, event: Event, proto: &Proto, pc: usize, reg: Reg) -> Result<()> {
+    if obj.type_of() != Type::Table && obj.metamethod(event).is_none() {
+        return Err(Error::Runtime(format!(
+            "attempt to index a {} value{}",
+            obj.type_of(),
+            describe(proto, pc, reg)
+        )));
+    }
+    Ok(())
+}
+
+/// Say what `reg` holds at instruction `pc` for an error message, as in
+/// ` (global 'f')`, by finding the instruction that set it
+fn describe(proto: &Proto, pc: usize, reg: Reg) -> String {
+    if let Some(name) = proto.local_name(reg, pc) {
+        return format!(" (local '{}')", name);
+    }
+    let set = match proto.code[..pc]
+        .iter()
+        .rposition(|instr| instr.target() == Some(reg))
+    {
+        Some(set) => set,
+        None => return String::new(),
+    };
+    match proto.code[set] {
+        Instr::GetGlobal(_, k) => format!(" (global '{}')", proto.constants[k as usize]),
+        Instr::GetMethod(_, _, k) => format!(" (method '{}')", proto.constants[k as usize]),
+        Instr::GetTable(_, _, key) => match constant(proto, set, key) {
+            Some(name) => format!(" (field '{}')", name),
+            None => String::new(),
+        },
+        Instr::Move(_, from) | Instr::GetCell(_, from) => match proto.local_name(from, set) {
+            Some(name) => format!(" (local '{}')", name),
+            None => String::new(),
+        },
+        Instr::GetUpval(_, u) => format!(" (upvalue '{}')", proto.upvals[u as usize].name),
+        _ => String::new(),
+    }
+}
+
+/// The string constant loaded into `reg` before instruction `pc`
+fn constant(proto: &Proto, pc: usize, reg: Reg) -> Option<&Value> {
+    let set = proto.code[..pc]
+        .iter()
+        .rposition(|instr| instr.target() == Some(reg))?;
+    match proto.code[set] {
+        Instr::LoadK(_, k) if proto.constants[k as usize].type_of() == Type::String => {
+            Some(&proto.constants[k as usize])
+        }
+        _ => None,
+    }
+}
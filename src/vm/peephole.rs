@@ -0,0 +1,240 @@
+//! A peephole optimizer, which tidies up the code of each compiled function
+//!
+//! The compiler evaluates every expression into a register of its own and
+//! branches on conditions it has stored, so this rewrites short runs of
+//! instructions into fewer: results are written straight to where they are
+//! moved, a comparison followed by a conditional jump becomes one fused
+//! jump, and code that nothing can reach is dropped. Rewrites that remove a
+//! register's value only happen when the value is never read again.
+
+use super::instr::{Instr, Reg};
+use super::Proto;
+
+/// How many instructions are followed when checking that a register's
+/// value is unused, after which it is assumed to be needed
+const SCAN_LIMIT: usize = 64;
+
+/// Optimize `proto` in place, keeping its lines and locals in step
+///
+/// Nested functions are optimized as they are compiled, so only this one's
+/// code is changed.
+pub fn optimize(proto: &mut Proto) {
+    loop {
+        let mut removed = vec![false; proto.code.len()];
+        let mut changed = combine(proto, &mut removed);
+        if !changed {
+            changed = unreachable(&proto.code, &mut removed);
+        }
+        if !changed {
+            return;
+        }
+        compact(proto, &removed);
+    }
+}
+
+/// Rewrite pairs of instructions, marking those no longer needed
+fn combine(proto: &mut Proto, removed: &mut [bool]) -> bool {
+    let targets = jump_targets(&proto.code);
+    let mut changed = false;
+    let mut pc = 0;
+    while pc < proto.code.len() {
+        let instr = proto.code[pc];
+        match instr {
+            Instr::Move(a, b) if a == b => {
+                removed[pc] = true;
+                changed = true;
+            }
+            Instr::Jump(0) => {
+                removed[pc] = true;
+                changed = true;
+            }
+            _ => {}
+        }
+        // the second of a pair must not be jumped to, as it would then run
+        // without the first
+        let next = match proto.code.get(pc + 1) {
+            Some(&next) if !targets[pc + 1] && !removed[pc] => next,
+            _ => {
+                pc += 1;
+                continue;
+            }
+        };
+        if let Some(rewritten) = pair(proto, pc, instr, next) {
+            match rewritten {
+                Some(instr) => proto.code[pc] = instr,
+                None => removed[pc] = true,
+            }
+            removed[pc + 1] = true;
+            changed = true;
+            pc += 2;
+        } else {
+            pc += 1;
+        }
+    }
+    changed
+}
+
+/// What a pair of instructions starting at `pc` can be replaced with,
+/// which is either one instruction or none
+fn pair(proto: &Proto, pc: usize, first: Instr, second: Instr) -> Option<Option<Instr>> {
+    let temp = first.target()?;
+    match second {
+        // a result that is only moved somewhere else can be put there
+        Instr::Move(dest, src) if src == temp && dest != temp => {
+            let instr = first.retarget(dest)?;
+            if is_dead(proto, pc + 2, temp) {
+                Some(Some(instr))
+            } else {
+                None
+            }
+        }
+        Instr::JumpIf(cond, offset) | Instr::JumpIfNot(cond, offset) if cond == temp => {
+            let target = jump(pc + 1, offset);
+            if !is_dead(proto, pc + 2, temp) || !is_dead(proto, target, temp) {
+                return None;
+            }
+            let when = matches!(second, Instr::JumpIf(..));
+            // the fused jump sits one instruction earlier
+            let offset = offset + 1;
+            Some(match first {
+                Instr::Eq(_, b, c) => Some(Instr::JumpEq(b, c, when, offset)),
+                Instr::Ne(_, b, c) => Some(Instr::JumpEq(b, c, !when, offset)),
+                Instr::Lt(_, b, c) => Some(Instr::JumpLt(b, c, when, offset)),
+                Instr::Le(_, b, c) => Some(Instr::JumpLe(b, c, when, offset)),
+                // branching on a constant, which folded conditions leave
+                Instr::LoadBool(_, val) if val == when => Some(Instr::Jump(offset)),
+                Instr::LoadK(..) if when => Some(Instr::Jump(offset)),
+                Instr::LoadNil(_, 1) if !when => Some(Instr::Jump(offset)),
+                Instr::LoadBool(..) | Instr::LoadK(..) | Instr::LoadNil(_, 1) => None,
+                _ => return None,
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Mark the instructions that cannot be reached from the start
+fn unreachable(code: &[Instr], removed: &mut [bool]) -> bool {
+    let mut reached = vec![false; code.len()];
+    let mut work = vec![0];
+    while let Some(mut pc) = work.pop() {
+        while pc < code.len() && !reached[pc] {
+            reached[pc] = true;
+            let instr = code[pc];
+            if let Some(offset) = instr.jump_offset() {
+                work.push(jump(pc, offset));
+            }
+            match instr {
+                Instr::Jump(_) | Instr::Return(..) => break,
+                _ => pc += 1,
+            }
+        }
+    }
+    let mut changed = false;
+    for (pc, reached) in reached.into_iter().enumerate() {
+        if !reached {
+            removed[pc] = true;
+            changed = true;
+        }
+    }
+    changed
+}
+
+/// Drop the removed instructions, adjusting jumps and local scopes to the
+/// instructions' new places
+///
+/// Jumps to a removed instruction go to the next one kept, which is where
+/// running it would have led, as only instructions that do nothing there
+/// are removed from the middle of the code.
+fn compact(proto: &mut Proto, removed: &[bool]) {
+    let mut moved = Vec::with_capacity(removed.len() + 1);
+    let mut kept = 0;
+    for &removed in removed {
+        moved.push(kept);
+        if !removed {
+            kept += 1;
+        }
+    }
+    moved.push(kept);
+    for pc in 0..proto.code.len() {
+        if let Some(offset) = proto.code[pc].jump_offset() {
+            let target = jump(pc, offset);
+            let offset = moved[target] as i32 - moved[pc] as i32 - 1;
+            proto.code[pc].set_jump_offset(offset);
+        }
+    }
+    let mut pc = 0;
+    proto.code.retain(|_| {
+        pc += 1;
+        !removed[pc - 1]
+    });
+    if !proto.lines.is_empty() {
+        let mut pc = 0;
+        proto.lines.retain(|_| {
+            pc += 1;
+            !removed[pc - 1]
+        });
+    }
+    for local in &mut proto.locals {
+        local.start = moved[local.start];
+        local.end = moved[local.end];
+    }
+}
+
+/// Whether each instruction is the target of a jump
+fn jump_targets(code: &[Instr]) -> Vec<bool> {
+    let mut targets = vec![false; code.len() + 1];
+    for (pc, instr) in code.iter().enumerate() {
+        if let Some(offset) = instr.jump_offset() {
+            targets[jump(pc, offset)] = true;
+        }
+    }
+    targets
+}
+
+/// Whether the value in `reg` goes unused when running from `start`, that
+/// is every way on sets it again or returns before reading it
+///
+/// Values of local variables are kept even if the code does not read them,
+/// so that debuggers still see them.
+fn is_dead(proto: &Proto, start: usize, reg: Reg) -> bool {
+    let code = &proto.code;
+    let mut seen = vec![false; code.len()];
+    let mut work = vec![start];
+    let mut budget = SCAN_LIMIT;
+    while let Some(mut pc) = work.pop() {
+        while pc < code.len() && !seen[pc] {
+            seen[pc] = true;
+            if budget == 0 {
+                return false;
+            }
+            budget -= 1;
+            let instr = code[pc];
+            if instr.reads(reg) {
+                return false;
+            }
+            if instr.writes(reg) {
+                break;
+            }
+            if proto.local_name(reg, pc).is_some() {
+                return false;
+            }
+            match instr {
+                Instr::Return(..) => break,
+                Instr::Jump(offset) => pc = jump(pc, offset),
+                _ => {
+                    if let Some(offset) = instr.jump_offset() {
+                        work.push(jump(pc, offset));
+                    }
+                    pc += 1;
+                }
+            }
+        }
+    }
+    true
+}
+
+/// Where the jump at `pc` goes
+fn jump(pc: usize, offset: i32) -> usize {
+    (pc as isize + 1 + offset as isize) as usize
+}
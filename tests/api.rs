@@ -0,0 +1,962 @@
+//! The embedding API of `Lua`, with each backend where it matters
+
+extern crate looa;
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::thread;
+
+use looa::{
+    Backend, ConvertValue, GcMode, HookEvent, HookMask, Lua, LuaInteger, LuaString, MultiValue,
+    Value,
+};
+
+/// A state running with each backend, and with the VM's optimizer off
+fn states() -> Vec<Lua> {
+    [
+        (Backend::Interpreter, true),
+        (Backend::Vm, true),
+        (Backend::Vm, false),
+    ]
+    .iter()
+    .map(|&(backend, optimize)| {
+        let mut lua = Lua::new();
+        lua.set_backend(backend);
+        lua.set_optimize(optimize);
+        lua
+    })
+    .collect()
+}
+
+fn integer(val: &Value) -> LuaInteger {
+    val.as_integer().expect("an integer")
+}
+
+#[test]
+fn globals_and_eval() {
+    for mut lua in states() {
+        lua.set_global("x", Value::new(20)).unwrap();
+        lua.exec("y = x + 1").unwrap();
+        assert_eq!(integer(&lua.get_global("y").unwrap()), 21);
+        assert_eq!(integer(&lua.eval("x * 2 + y").unwrap()), 61);
+        assert_eq!(lua.eval("'a' .. 'b'").unwrap().to_string(), "ab");
+        assert!(lua.eval("nil").unwrap().is_nil());
+    }
+}
+
+#[test]
+fn scripts_share_interned_strings() {
+    for mut lua in states() {
+        lua.exec("greeting = 'hello'").unwrap();
+        let before = lua.intern_stats();
+        let greeting = lua.string("hello");
+        let after = lua.intern_stats();
+        assert_eq!(after.hits, before.hits + 1);
+        assert_eq!(after.misses, before.misses);
+        assert!(greeting == lua.get_global("greeting").unwrap());
+        // and binary chunks intern their constants when loaded
+        let chunk = lua.compile(b"return 'hello'", "hello", false).unwrap();
+        let before = lua.intern_stats();
+        lua.load(&chunk, "hello").unwrap();
+        assert_eq!(lua.intern_stats().hits, before.hits + 1);
+    }
+}
+
+#[test]
+fn chunk_cache() {
+    for mut lua in states() {
+        let source = b"count = (count or 0) + 1 return count";
+        for expected in 1..=3 {
+            let chunk = lua.load(source, "counter").unwrap();
+            assert_eq!(
+                integer(&chunk.call(Vec::new()).unwrap().into_first()),
+                expected
+            );
+        }
+        let stats = lua.chunk_cache_stats();
+        assert_eq!((stats.cached, stats.hits, stats.misses), (1, 2, 1));
+        // the name shows in errors, so it is part of what is cached
+        lua.load(source, "other").unwrap();
+        assert_eq!(lua.chunk_cache_stats().misses, 2);
+        // while chunks that fail to compile are not cached
+        assert!(lua.load(b"return +", "bad").is_err());
+        assert!(lua.load(b"return +", "bad").is_err());
+        assert_eq!(lua.chunk_cache_stats().cached, 2);
+        lua.set_chunk_cache_limit(1);
+        assert_eq!(lua.chunk_cache_stats().cached, 1);
+        lua.set_chunk_cache_limit(0);
+        lua.load(source, "counter").unwrap();
+        assert_eq!(lua.chunk_cache_stats().cached, 0);
+    }
+}
+
+#[test]
+fn presized_tables() {
+    let lua = Lua::new();
+    let before = lua.memory_used();
+    let table = lua.create_table_with_capacity(100, 10);
+    let sized = lua.memory_used();
+    assert!(sized > before);
+    for i in 1..=100 {
+        table.raw_set(Value::new(i), Value::new(true)).unwrap();
+    }
+    for i in 1001..=1010 {
+        table.raw_set(Value::new(i), Value::new(true)).unwrap();
+    }
+    assert_eq!(lua.memory_used(), sized);
+    // constructors are sized the same way, leaving out a trailing call
+    let listing = lua
+        .disassemble(b"local t = {1, 2, x = 3, [4] = 5, ...}", "shape")
+        .unwrap();
+    assert!(listing.contains("NewTable  0 2 2"), "{}", listing);
+}
+
+#[test]
+fn concatenation_chains_join_at_once() {
+    let lua = Lua::new();
+    let listing = lua
+        .disassemble(b"local a, b = ... return a .. b .. 'x' .. a", "chain")
+        .unwrap();
+    assert!(listing.contains("ConcatAll"), "{}", listing);
+    assert!(!listing.contains("Concat "), "{}", listing);
+    // a single one needs no registers of its own
+    let listing = lua
+        .disassemble(b"local a, b = ... return a .. b", "pair")
+        .unwrap();
+    assert!(listing.contains("Concat "), "{}", listing);
+}
+
+#[test]
+fn calling_functions_both_ways() {
+    for mut lua in states() {
+        let add =
+            Value::function(|args: &[Value]| Ok(Value::new(integer(&args[0]) + integer(&args[1]))));
+        lua.set_global("add", add).unwrap();
+        lua.exec("function twice(f, x) return f(f(x, x), x) end")
+            .unwrap();
+        let twice = lua.get_global("twice").unwrap();
+        let add = lua.get_global("add").unwrap();
+        let vals = twice.call(vec![add, Value::new(3)]).unwrap();
+        assert_eq!(integer(&vals.into_first()), 9);
+    }
+}
+
+#[test]
+fn multiple_values() {
+    let mut vals = MultiValue::new();
+    assert!(vals.is_empty());
+    assert!(vals.clone().into_first().is_nil());
+    vals.push(Value::new(1));
+    assert_eq!(vals.len(), 1);
+    vals.push(Value::new(2));
+    vals.insert(0, Value::new(0));
+    let ints: Vec<LuaInteger> = vals.iter().map(integer).collect();
+    assert_eq!(ints, [0, 1, 2]);
+    vals.resize(5, Value::nil());
+    assert_eq!(vals.len(), 5);
+    vals.truncate(1);
+    assert_eq!(vals, MultiValue::from(Value::new(0)));
+    vals.resize(0, Value::nil());
+    assert!(vals.is_empty());
+    let collected: MultiValue = (1..4).map(Value::new).collect();
+    let ints: Vec<LuaInteger> = collected
+        .into_iter()
+        .rev()
+        .map(|val| integer(&val))
+        .collect();
+    assert_eq!(ints, [3, 2, 1]);
+    let one: MultiValue = Some(Value::new(7)).into_iter().collect();
+    assert_eq!(one.into_vec().len(), 1);
+}
+
+/// Game data, with a shared table, long strings and keys of each type
+const DATA: &str = "local shared = {hp = 10}
+return {
+  'first', 'second', 'third',
+  name = 'a name too long to be stored inline',
+  [true] = 'yes', [2.5] = 'float', [-1] = 'negative',
+  goblin = shared, orc = shared,
+  levels = {{size = 1}, {size = 2}},
+}";
+
+/// What running `src` as a chunk returns first
+fn returned(lua: &Lua, src: &str) -> Value {
+    let chunk = lua.load(src.as_bytes(), "chunk").unwrap();
+    chunk.call(Vec::new()).unwrap().into_first()
+}
+
+#[test]
+fn frozen_values_are_shared_between_threads() {
+    let data = returned(&Lua::new(), DATA).freeze().unwrap();
+    assert_eq!(data.type_of(), looa::Type::Table);
+    let workers: Vec<_> = (0..3)
+        .map(|_| {
+            let data = data.clone();
+            thread::spawn(move || {
+                for mut lua in states() {
+                    lua.set_global("data", data.to_value()).unwrap();
+                    lua.set_global("again", data.to_value()).unwrap();
+                    let src = "local keys = 0
+                        for _ in pairs(data) do keys = keys + 1 end
+                        local sizes = 0
+                        for _, level in ipairs(data.levels) do sizes = sizes + level.size end
+                        return keys, #data, data[3], data.name,
+                          data[true], data[2.5], data[-1], data.goblin == data.orc,
+                          data == again, data.levels == again.levels, sizes, type(data),
+                          rawget(data, 1), rawlen(data), getmetatable(data)";
+                    let vals: Vec<String> = lua
+                        .load(src.as_bytes(), "read")
+                        .unwrap()
+                        .call(Vec::new())
+                        .unwrap()
+                        .iter()
+                        .map(|val| val.to_string())
+                        .collect();
+                    assert_eq!(
+                        vals,
+                        [
+                            "10",
+                            "3",
+                            "third",
+                            "a name too long to be stored inline",
+                            "yes",
+                            "float",
+                            "negative",
+                            "true",
+                            "true",
+                            "true",
+                            "3",
+                            "table",
+                            "first",
+                            "3",
+                            "nil",
+                        ]
+                    );
+                    assert!(lua.get_global("data").unwrap().is_frozen());
+                }
+            })
+        })
+        .collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+}
+
+#[test]
+fn frozen_values_cannot_change() {
+    for mut lua in states() {
+        let data = returned(&lua, DATA).freeze().unwrap();
+        lua.set_global("data", data.to_value()).unwrap();
+        for src in [
+            "data.name = 'other'",
+            "data[1] = nil",
+            "data.levels[1].size = 3",
+            "rawset(data, 'x', 1)",
+        ] {
+            let err = lua.exec(src).unwrap_err().to_string();
+            assert!(err.contains("attempt to modify a frozen table"), "{}", err);
+        }
+        let err = lua.exec("setmetatable(data, {})").unwrap_err().to_string();
+        assert!(err.contains("frozen table"), "{}", err);
+        // a frozen table can be a key of a table that can change
+        lua.exec("local t = {[data.goblin] = 1} assert(t[data.orc] == 1)")
+            .unwrap();
+        // freezing it again shares it
+        let again = lua.get_global("data").unwrap().freeze().unwrap();
+        assert!(again.to_value() == data.to_value());
+    }
+}
+
+#[test]
+fn freezing_rejects_what_could_change() {
+    let lua = Lua::new();
+    for (src, msg) in [
+        ("return print", "cannot freeze a function value"),
+        ("return {f = print}", "cannot freeze a function value"),
+        ("return setmetatable({}, {})", "with a metatable"),
+        ("local t = {} t.t = t return t", "contains itself"),
+        (
+            "return coroutine.create(print)",
+            "cannot freeze a thread value",
+        ),
+    ] {
+        let err = returned(&lua, src).freeze().unwrap_err().to_string();
+        assert!(err.contains(msg), "{}: {}", src, err);
+    }
+    let val = returned(&lua, "return 'text'").freeze().unwrap().to_value();
+    assert_eq!(val.to_string(), "text");
+    assert!(!val.is_frozen());
+    let val = returned(&lua, "return 1.5").freeze().unwrap().to_value();
+    assert!(val == Value::new(1.5));
+}
+
+#[test]
+fn errors_carry_their_place() {
+    for mut lua in states() {
+        let err = lua.exec("local t = nil\nreturn t.x").unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with(":2: attempt to index a nil value (local 't')"));
+        assert!(lua.exec("x = = 1").is_err());
+        let err = lua.exec("error({code = 7})").unwrap_err();
+        let val = err.into_value();
+        assert_eq!(integer(&val.raw_get(&Value::string("code"))), 7);
+    }
+}
+
+/// Run `test` on a thread with a stack as big as a main thread's, since
+/// test threads have smaller stacks than the call limit allows for
+fn on_main_stack<F>(test: F)
+where
+    F: FnOnce() + Send + 'static,
+{
+    thread::Builder::new()
+        .stack_size(8 << 20)
+        .spawn(test)
+        .unwrap()
+        .join()
+        .unwrap();
+}
+
+#[test]
+fn deep_recursion() {
+    on_main_stack(deep_recursion_test);
+}
+
+fn deep_recursion_test() {
+    for mut lua in states() {
+        let vm = lua.backend() == Backend::Vm;
+        lua.exec("function d(n) if n == 0 then return 0 end return 1 + d(n - 1) end")
+            .unwrap();
+        assert_eq!(integer(&lua.eval("d(300)").unwrap()), 300);
+        // calls between functions the VM runs don't nest on the stack
+        assert_eq!(lua.eval("d(100000)").is_ok(), vm);
+        let err = lua.eval("d(10000000)").unwrap_err();
+        assert!(err.to_string().ends_with("stack overflow"), "{}", err);
+    }
+}
+
+#[test]
+fn recursion_stops_before_the_stack_runs_out() {
+    // a spawned thread's stack is a quarter of the main thread's, and the
+    // call limit alone would let the interpreter overflow it
+    thread::spawn(|| {
+        for lua in states() {
+            let src = "local function r(n) if n == 0 then return 0 end return 1 + r(n - 1) end \
+                       local ok, err = pcall(r, 100000) \
+                       return ok, err, r(100)";
+            let vals = lua.load(src.as_bytes(), "deep").unwrap().call(vec![]).unwrap();
+            let vals: Vec<Value> = vals.into_iter().collect();
+            if lua.backend() == Backend::Vm {
+                assert_eq!(integer(&vals[1]), 100000);
+            } else {
+                assert!(vals[0] == Value::new(false));
+                assert!(vals[1].to_string().ends_with("stack overflow"), "{}", vals[1]);
+            }
+            assert_eq!(integer(&vals[2]), 100);
+            // as do metamethods, library functions calling back into Lua
+            // and coroutines resuming each other, with either backend
+            for src in &[
+                "t = setmetatable({}, {__index = function(t, k) return t[k + 1] end}) return t[1]",
+                "local t = setmetatable({}, {__tostring = function(t) return tostring(t) end}) return tostring(t)",
+                "local function c() return coroutine.wrap(c)() end return c()",
+            ] {
+                let err = lua.load(src.as_bytes(), "deep").unwrap().call(vec![]);
+                let err = err.unwrap_err().to_string();
+                assert!(err.ends_with("stack overflow"), "{}: {}", src, err);
+            }
+        }
+    })
+    .join()
+    .unwrap();
+}
+
+#[test]
+fn call_and_stack_limits() {
+    for mut lua in states() {
+        lua.set_call_limit(50);
+        lua.set_stack_limit(1000);
+        assert_eq!(lua.call_limit(), 50);
+        assert_eq!(lua.stack_limit(), 1000);
+        lua.exec("function d(n) if n == 0 then return 0 end return 1 + d(n - 1) end")
+            .unwrap();
+        assert!(lua.eval("d(30)").is_ok());
+        assert!(lua.eval("d(2000)").is_err());
+        // metamethods nest on the stack with either backend
+        lua.exec("t = setmetatable({}, {__index = function(t, k) return k > 0 and t[k - 1] end})")
+            .unwrap();
+        assert!(lua.eval("t[20]").is_ok());
+        assert!(lua.eval("t[100]").is_err());
+    }
+}
+
+#[test]
+fn instruction_limit() {
+    for mut lua in states() {
+        lua.set_instruction_limit(Some(10000));
+        let err = lua.exec("while true do end").unwrap_err();
+        assert!(err.to_string().ends_with("instruction limit exceeded"));
+        // catching the error doesn't get around it
+        assert!(lua
+            .exec("pcall(function() while true do end end) x = 1")
+            .is_err());
+        lua.set_instruction_limit(None);
+        assert!(lua.exec("for i = 1, 100000 do end").is_ok());
+    }
+}
+
+#[test]
+fn interrupting() {
+    for mut lua in states() {
+        let handle = lua.interrupt_handle();
+        handle.interrupt();
+        let err = lua.exec("while true do end").unwrap_err();
+        assert!(err.to_string().ends_with("interrupted!"));
+        handle.reset();
+        assert!(lua.exec("x = 1").is_ok());
+    }
+}
+
+#[test]
+fn memory_limit() {
+    for mut lua in states() {
+        lua.set_memory_limit(Some(lua.memory_used() + 1_000_000));
+        let err = lua
+            .exec("local t = {} for i = 1, 1e7 do t[i] = {} end")
+            .unwrap_err();
+        assert!(err.to_string().ends_with("not enough memory"), "{}", err);
+        // what the script held is let go of with the error
+        lua.set_memory_limit(None);
+        assert!(lua.exec("x = {}").is_ok());
+    }
+}
+
+#[test]
+fn memory_is_counted_per_state() {
+    for mut lua in states() {
+        let fresh = Lua::new().memory_used();
+        let before = lua.memory_used();
+        lua.exec("t = {} for i = 1, 1000 do t[i] = {} end").unwrap();
+        assert!(lua.memory_used() > before + 1000);
+        // what one state holds on to isn't counted against the next
+        assert_eq!(Lua::new().memory_used(), fresh);
+    }
+}
+
+#[test]
+fn memory_stats() {
+    for mut lua in states() {
+        let before = lua.memory_stats();
+        assert_eq!(before.total(), lua.memory_used());
+        assert!(before.tables > 0 && before.functions > 0);
+        lua.exec("t = {} for i = 1, 100 do t[i] = {} end s = '' for i = 1, 100 do s = s .. '0123456789' end")
+            .unwrap();
+        let after = lua.memory_stats();
+        assert!(after.tables > before.tables + 100);
+        assert!(after.strings > before.strings + 1000);
+        assert_eq!(after.userdata, before.userdata);
+        assert_eq!(after.total(), lua.memory_used());
+    }
+}
+
+#[test]
+fn pool_stats() {
+    for mut lua in states() {
+        let churn = "for i = 1, 100 do local t = {x = i} local s = 'k' .. i end";
+        // the values of one are only freed by the collection after it, for
+        // the next to reuse their room
+        for _ in 0..2 {
+            lua.exec(churn).unwrap();
+            lua.collect_garbage();
+        }
+        let stats = lua.pool_stats();
+        assert!(stats.values.hits >= 50 && stats.hashes.hits >= 50);
+        assert!(stats.hashes.kept > 0 && stats.hashes.kept <= lua.pool_limit());
+        // what the pools keep isn't counted as used
+        let used = lua.memory_used();
+        lua.set_pool_limit(0);
+        assert_eq!(lua.memory_used(), used);
+        let emptied = lua.pool_stats();
+        assert_eq!(
+            emptied.values.kept + emptied.arrays.kept + emptied.hashes.kept,
+            0
+        );
+        lua.exec(churn).unwrap();
+        let after = lua.pool_stats();
+        assert_eq!(after.hashes.hits, stats.hashes.hits);
+        assert!(after.hashes.misses >= stats.hashes.misses + 100);
+    }
+}
+
+#[test]
+fn short_strings_are_stored_inline() {
+    let lua = Lua::new();
+    let size = |bytes: &[u8]| {
+        let before = lua.memory_stats().strings;
+        let val = lua.string(bytes);
+        let size = lua.memory_stats().strings - before;
+        assert_eq!(&LuaString::from_value(&val).unwrap()[..], bytes);
+        size
+    };
+    let empty = size(b"");
+    assert_eq!(size(&[b'a'; 22]), empty);
+    assert_eq!(size(&[b'b'; 23]), empty + 23);
+    assert_eq!(size(&[b'c'; 100]), empty + 100);
+    for len in 0..40 {
+        let bytes: Vec<u8> = (0..len as u8).collect();
+        let val = Value::string(&bytes);
+        assert_eq!(LuaString::from_value(&val).unwrap().as_bytes(), &bytes[..]);
+        assert_eq!(val, lua.string(&bytes));
+        assert_eq!(val.raw_len(), Some(len));
+    }
+}
+
+#[test]
+fn cycles_are_collected() {
+    for mut lua in states() {
+        let leak = |lua: &mut Lua| {
+            lua.exec("local function r(n) if n > 0 then return r(n - 1) end end r(3)")
+                .unwrap();
+            lua.exec("local t = {} t.self = t setmetatable(t, {__index = t})")
+                .unwrap();
+        };
+        // the chunks and their strings stay cached
+        leak(&mut lua);
+        lua.collect_garbage();
+        let before = lua.memory_used();
+        for _ in 0..100 {
+            leak(&mut lua);
+        }
+        assert!(lua.memory_used() > before);
+        assert!(!lua.find_garbage().is_empty());
+        // `r`, `t` and its metatable, along with the chunks and the strings
+        // made as they ran
+        assert!(lua.collect_garbage() >= 100 * 3);
+        assert_eq!(lua.memory_used(), before);
+        assert!(lua.find_garbage().is_empty());
+    }
+}
+
+#[test]
+fn values_are_freed_by_collections() {
+    for mut lua in states() {
+        let call = |lua: &mut Lua, src: &str| {
+            lua.load(src.as_bytes(), "alloc")
+                .unwrap()
+                .call(Vec::new())
+                .unwrap()
+                .into_first()
+        };
+        let dropped = call(&mut lua, "return {}").downgrade().unwrap();
+        // nothing is freed until a collection
+        assert!(dropped.upgrade().is_some());
+        let held = call(&mut lua, "local t = {} t.self = t return t");
+        lua.collect_garbage();
+        assert!(dropped.upgrade().is_none());
+        // what the host holds is in use, along with what it refers to
+        assert!(held.raw_get(&Value::string("self")).raw_equal(&held));
+        let weak = held.downgrade().unwrap();
+        drop(held);
+        assert!(weak.upgrade().is_some());
+        lua.collect_garbage();
+        assert!(weak.upgrade().is_none());
+    }
+}
+
+#[test]
+fn cycles_are_collected_as_memory_grows() {
+    for mut lua in states() {
+        lua.set_memory_limit(Some(lua.memory_used() + 1_000_000));
+        let src = "
+            finalized = 0
+            for i = 1, 20000 do
+                local t = {}
+                t.self = t
+                if i % 200 == 0 then
+                    setmetatable(t, {__gc = function() finalized = finalized + 1 end})
+                end
+            end
+        ";
+        lua.exec(src).unwrap();
+        lua.set_memory_limit(None);
+        let finalized = lua.eval("finalized").unwrap().as_integer().unwrap();
+        assert!(finalized > 0, "{}", finalized);
+    }
+}
+
+#[test]
+fn cycles_are_collected_in_steps() {
+    for mut lua in states() {
+        let before = lua.memory_used();
+        let src = "
+            for i = 1, 20000 do
+                local t = {}
+                t.self = t
+            end
+        ";
+        lua.exec(src).unwrap();
+        // steps were done as the tables were made, rather than all at the end
+        assert!(
+            lua.memory_used() - before < 1_000_000,
+            "{}",
+            lua.memory_used()
+        );
+        lua.exec("local t = {} t.self = t").unwrap();
+        let mut steps = 0;
+        while !lua.gc_step(0) {
+            steps += 1;
+        }
+        assert!(steps > 0);
+        while !lua.gc_step(0) {}
+        assert!(lua.find_garbage().is_empty());
+    }
+}
+
+#[test]
+fn cycles_moved_during_steps_are_kept() {
+    for mut lua in states() {
+        lua.exec("kept = {} kept.self = kept").unwrap();
+        // enough values that a step doesn't finish a collection
+        lua.exec("for i = 1, 1000 do local t = {} t.self = t end")
+            .unwrap();
+        assert!(!lua.gc_step(0));
+        let kept = lua.get_global("kept").unwrap();
+        lua.set_global("kept", Value::nil()).unwrap();
+        while !lua.gc_step(0) {}
+        assert!(kept.raw_get(&Value::string("self")).raw_equal(&kept));
+        drop(kept);
+        while !lua.gc_step(0) {}
+        assert!(lua.find_garbage().is_empty());
+    }
+}
+
+#[test]
+fn cycles_are_collected_by_generation() {
+    for mut lua in states() {
+        assert_eq!(lua.set_gc_mode(GcMode::Generational), GcMode::Incremental);
+        lua.exec("old = {} old.self = old").unwrap();
+        assert!(lua.gc_step(0));
+        lua.set_global("old", Value::nil()).unwrap();
+        lua.exec("local t = {} t.self = t").unwrap();
+        // a minor collection frees the new cycle but not the old one
+        assert!(lua.gc_step(0));
+        let cycles = lua.find_garbage();
+        assert_eq!(cycles.len(), 1);
+        assert!(cycles[0][0]
+            .raw_get(&Value::string("self"))
+            .raw_equal(&cycles[0][0]));
+        drop(cycles);
+        lua.collect_garbage();
+        assert!(lua.find_garbage().is_empty());
+
+        let before = lua.memory_used();
+        let src = "
+            for i = 1, 20000 do
+                local t = {}
+                t.self = t
+            end
+        ";
+        lua.exec(src).unwrap();
+        assert!(
+            lua.memory_used() - before < 1_000_000,
+            "{}",
+            lua.memory_used()
+        );
+        assert_eq!(lua.set_gc_mode(GcMode::Incremental), GcMode::Generational);
+    }
+}
+
+#[test]
+fn gc_parameters() {
+    let mut lua = Lua::new();
+    assert_eq!((lua.gc_pause(), lua.gc_step_multiplier()), (200, 100));
+    lua.set_gc_pause(150);
+    lua.set_gc_step_multiplier(400);
+    assert_eq!(
+        lua.eval("collectgarbage('setpause', 100)")
+            .unwrap()
+            .as_integer(),
+        Some(150)
+    );
+    assert_eq!(
+        lua.eval("collectgarbage('setstepmul', 200)")
+            .unwrap()
+            .as_integer(),
+        Some(400)
+    );
+    assert_eq!((lua.gc_pause(), lua.gc_step_multiplier()), (100, 200));
+}
+
+#[test]
+fn cycles_in_use_are_kept() {
+    for mut lua in states() {
+        let src = "
+            local function r(n) if n > 0 then return r(n - 1) end return n end
+            kept = {r = r}
+            kept.self = kept
+            local co = coroutine.create(function() coroutine.yield(r) end)
+            coroutine.resume(co)
+            kept.co = co
+            local t = {} t.self = t
+            return t
+        ";
+        let held = lua
+            .load(src.as_bytes(), "kept")
+            .unwrap()
+            .call(Vec::new())
+            .unwrap()[0]
+            .clone();
+        lua.collect_garbage();
+        assert!(held.raw_get(&Value::string("self")).raw_equal(&held));
+        assert_eq!(lua.eval("kept.r(5)").unwrap(), Value::new(0 as LuaInteger));
+        assert!(lua.exec("assert(coroutine.resume(kept.co))").is_ok());
+        // the table, `r` and the coroutine, which has finished
+        let kept: Vec<_> = ["kept", "kept.r", "kept.co"]
+            .iter()
+            .map(|src| lua.eval(src).unwrap().downgrade().unwrap())
+            .collect();
+        lua.exec("kept = nil").unwrap();
+        lua.collect_garbage();
+        assert!(kept.iter().all(|weak| weak.upgrade().is_none()));
+        // a suspended coroutine, whose frames keep the variable that refers
+        // to it
+        let src = "local co co = coroutine.create(function() local me = co coroutine.yield() end) coroutine.resume(co) return co";
+        let co = lua
+            .load(src.as_bytes(), "co")
+            .unwrap()
+            .call(Vec::new())
+            .unwrap()
+            .into_first()
+            .downgrade()
+            .unwrap();
+        lua.collect_garbage();
+        assert!(co.upgrade().is_none());
+    }
+}
+
+#[test]
+fn cycles_are_finalized() {
+    for mut lua in states() {
+        let src = "
+            finalized = 0
+            local t = setmetatable({}, {__gc = function(t) finalized = finalized + 1 end})
+            t.self = t
+            t.inner = {t}
+        ";
+        lua.collect_garbage();
+        lua.exec(src).unwrap();
+        let sizes: Vec<usize> = lua.find_garbage().iter().map(Vec::len).collect();
+        // the chunk, and the table, the one inside it, the metatable and the
+        // finalizer
+        assert_eq!(sizes, [1, 4]);
+        // it is kept until it has been finalized
+        lua.collect_garbage();
+        assert!(lua.find_garbage().is_empty());
+        lua.run_finalizers().unwrap();
+        assert_eq!(lua.eval("finalized").unwrap(), Value::new(1 as LuaInteger));
+        let sizes: Vec<usize> = lua.find_garbage().iter().map(Vec::len).collect();
+        assert!(sizes.contains(&4), "{:?}", sizes);
+        lua.collect_garbage();
+        assert!(lua.find_garbage().is_empty());
+        lua.run_finalizers().unwrap();
+        assert_eq!(lua.eval("finalized").unwrap(), Value::new(1 as LuaInteger));
+    }
+}
+
+#[test]
+fn hooks() {
+    for mut lua in states() {
+        let lines = Rc::new(Cell::new(0));
+        let calls = Rc::new(Cell::new(0));
+        {
+            let (lines, calls) = (lines.clone(), calls.clone());
+            let mask = HookMask {
+                call: true,
+                line: true,
+                ..HookMask::default()
+            };
+            lua.set_hook(mask, move |info| {
+                match info.event() {
+                    HookEvent::Line(_) => lines.set(lines.get() + 1),
+                    HookEvent::Call | HookEvent::TailCall => calls.set(calls.get() + 1),
+                    _ => {}
+                }
+                Ok(())
+            });
+        }
+        lua.exec("local function f() return 1 end\nf()\nf()")
+            .unwrap();
+        lua.remove_hook();
+        assert_eq!(lines.get(), 5);
+        assert_eq!(calls.get(), 3);
+        // an error from the hook is raised where it happened
+        lua.set_hook(
+            HookMask {
+                line: true,
+                ..HookMask::default()
+            },
+            |_| Err(looa::Error::Runtime("stop".to_string())),
+        );
+        assert!(lua.exec("x = 1").is_err());
+        lua.remove_hook();
+    }
+}
+
+#[test]
+fn hooks_belong_to_their_state() {
+    let lines = Rc::new(Cell::new(0));
+    let mut hooked = Lua::new();
+    let counted = lines.clone();
+    let mask = HookMask {
+        line: true,
+        ..HookMask::default()
+    };
+    hooked.set_hook(mask, move |_| {
+        counted.set(counted.get() + 1);
+        Ok(())
+    });
+    hooked.exec("f = function() return 1 end").unwrap();
+    lines.set(0);
+    // other states on the thread run without it
+    let mut other = Lua::new();
+    other.exec("x = 1\ny = 2").unwrap();
+    assert_eq!(lines.get(), 0);
+    // while its functions call it wherever they are called from
+    other
+        .set_global("f", hooked.get_global("f").unwrap())
+        .unwrap();
+    other.exec("f()").unwrap();
+    assert_eq!(lines.get(), 1);
+    // and closing the state removes it
+    let f = hooked.get_global("f").unwrap();
+    drop(hooked);
+    f.call(Vec::new()).unwrap();
+    assert_eq!(lines.get(), 1);
+    assert_eq!(Rc::strong_count(&lines), 1);
+    // as with one a script sets
+    other
+        .exec("debug.sethook(function() error('hooked') end, 'l')")
+        .unwrap();
+    assert!(other.exec("y = 3").is_err());
+    assert!(Lua::new().exec("y = 3").is_ok());
+}
+
+#[test]
+fn binary_chunks() {
+    let lua = Lua::new();
+    let chunk = lua
+        .compile(b"local a = ... return a * 2", "double", false)
+        .unwrap();
+    let func = lua.load(&chunk, "double").unwrap();
+    assert_eq!(
+        integer(&func.call(vec![Value::new(21)]).unwrap().into_first()),
+        42
+    );
+    let stripped = lua.compile(&chunk, "double", true).unwrap();
+    assert!(stripped.len() < chunk.len());
+    let listing = lua.disassemble(b"return 1 + x", "add").unwrap();
+    assert!(
+        listing.contains("GetGlobal") || listing.contains("GETGLOBAL"),
+        "{}",
+        listing
+    );
+}
+
+#[test]
+fn finalizers() {
+    for mut lua in states() {
+        lua.exec("log = {} setmetatable({}, {__gc = function() log[#log + 1] = 'gc' end})")
+            .unwrap();
+        lua.collect_garbage();
+        lua.run_finalizers().unwrap();
+        assert_eq!(integer(&lua.eval("#log").unwrap()), 1);
+    }
+}
+
+#[test]
+fn tracebacks() {
+    for mut lua in states() {
+        let src =
+            "local function inner() error('deep') end\nlocal function outer() inner() end\nouter()";
+        assert!(lua.exec(src).is_err());
+        let traceback = lua.traceback();
+        assert!(traceback.starts_with("stack traceback:"), "{}", traceback);
+        assert!(traceback.contains("in main chunk"), "{}", traceback);
+        assert_eq!(traceback.lines().count(), 4, "{}", traceback);
+    }
+}
+
+/// Run `src` as a chunk named `name`, for the string it returns
+fn run(lua: &Lua, src: &str, name: &str) -> String {
+    let chunk = lua.load(src.as_bytes(), name).unwrap();
+    chunk.call(Vec::new()).unwrap()[0].to_string()
+}
+
+#[test]
+fn live_tracebacks() {
+    for lua in states() {
+        let src = "local function here()\n  return debug.traceback('here')\nend\nlocal s = here()\nreturn s";
+        let traceback = run(&lua, src, "live");
+        let lines: Vec<&str> = traceback.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "here",
+                "stack traceback:",
+                "\tlive:2: in function <live:1>",
+                "\tlive:4: in main chunk",
+            ],
+            "{}",
+            traceback
+        );
+    }
+}
+
+#[test]
+fn long_tracebacks_skip_levels() {
+    for lua in states() {
+        let src = "local function deep(n)\n  if n == 0 then error('bottom') end\n  return 1 + deep(n - 1)\nend\n\
+                   return select(2, xpcall(deep, debug.traceback, 150))";
+        let traceback = run(&lua, src, "deep");
+        // the error, the header, the first 10, the note and the last 11
+        assert_eq!(traceback.lines().count(), 24, "{}", traceback);
+        assert!(
+            traceback.contains("\n\t...\t(skipping 131 levels)\n"),
+            "{}",
+            traceback
+        );
+        assert!(traceback.ends_with("in main chunk"), "{}", traceback);
+    }
+}
+
+#[test]
+fn integers_round_trip_at_every_size() {
+    let edges = [
+        0,
+        1,
+        -1,
+        (1 << 47) - 1,
+        -(1 << 47),
+        1 << 47,
+        -(1 << 47) - 1,
+        LuaInteger::MAX,
+        LuaInteger::MIN,
+    ];
+    for mut lua in states() {
+        for &num in &edges {
+            let val = Value::new(num);
+            assert!(val.is_integer());
+            assert_eq!(LuaInteger::from_value(&val), Some(num));
+            assert_eq!(val, Value::new(num));
+            assert_eq!(val.to_string(), num.to_string());
+            lua.set_global("n", val).unwrap();
+            // keys made either way must find each other
+            let src = format!(
+                "local t = {{[n] = true}} ok = t[{}] and n - 1 + 1 == n",
+                num
+            );
+            lua.exec(&src).unwrap();
+            assert_eq!(lua.get_global("ok").unwrap(), Value::new(true), "{}", num);
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Walking the syntax trees the parser builds, whose nodes are looked up in
+//! the tree they belong to
+
+extern crate looa;
+
+use std::rc::Rc;
+
+use looa::ast::{ExprKind, FieldKind, StatKind};
+use looa::parser;
+use looa::Number;
+
+#[test]
+fn lists_are_indexed_in_the_tree() {
+    let ast = parser::parse_chunk(b"local a, b = 1, f(x, {y = 2})", "ast").unwrap();
+    let body = &ast[ast.main()].body;
+    assert_eq!(body.stats.len(), 1);
+    let (names, values) = match ast[body.stats][0].kind {
+        StatKind::Local(names, values) => (names, values),
+        ref kind => panic!("not a local statement: {:?}", kind),
+    };
+    let names: Vec<&str> = ast[names].iter().map(|name| &**name).collect();
+    assert_eq!(names, ["a", "b"]);
+    let values = &ast[values];
+    assert!(matches!(values[0].kind, ExprKind::Number(Number::Int(1))));
+    let args = match values[1].kind {
+        ExprKind::Call(func, args) => {
+            assert!(matches!(ast[func].kind, ExprKind::Name(ref name) if &**name == "f"));
+            &ast[args]
+        }
+        ref kind => panic!("not a call: {:?}", kind),
+    };
+    assert_eq!(args.len(), 2);
+    match args[1].kind {
+        ExprKind::Table(fields) => match ast[fields][0].kind {
+            FieldKind::Named(ref name, val) => {
+                assert_eq!(&**name, "y");
+                assert!(matches!(ast[val].kind, ExprKind::Number(Number::Int(2))));
+            }
+            ref kind => panic!("not a named field: {:?}", kind),
+        },
+        ref kind => panic!("not a table: {:?}", kind),
+    }
+}
+
+#[test]
+fn function_bodies_are_nodes_of_their_own() {
+    let src = b"function t.f(a, ...) return function() return a end end";
+    let ast = parser::parse_chunk(src, "ast").unwrap();
+    let body = &ast[ast.main()].body;
+    let (name, func) = match ast[body.stats][0].kind {
+        StatKind::Function(ref name, func) => (name, func),
+        ref kind => panic!("not a function statement: {:?}", kind),
+    };
+    assert_eq!(name.path.len(), 2);
+    let func = &ast[func];
+    assert!(func.vararg);
+    assert_eq!(ast[func.params].len(), 1);
+    let ret = &ast[func.body.ret.expect("returns a value")];
+    match ret[0].kind {
+        ExprKind::Function(inner) => {
+            assert!(ast[inner].params.is_empty());
+            assert_ne!(inner, ast.main());
+        }
+        ref kind => panic!("not a function: {:?}", kind),
+    }
+}
+
+#[test]
+fn names_are_shared_within_a_tree() {
+    let ast = parser::parse_chunk(b"x = x + 1", "ast").unwrap();
+    let body = &ast[ast.main()].body;
+    let (targets, values) = match ast[body.stats][0].kind {
+        StatKind::Assign(targets, values) => (targets, values),
+        ref kind => panic!("not an assignment: {:?}", kind),
+    };
+    let name = |kind: &ExprKind| match *kind {
+        ExprKind::Name(ref name) => name.clone(),
+        ref kind => panic!("not a name: {:?}", kind),
+    };
+    let target = name(&ast[targets][0].kind);
+    let read = match ast[values][0].kind {
+        ExprKind::Binary(_, lhs, _) => name(&ast[lhs].kind),
+        ref kind => panic!("not a binary expression: {:?}", kind),
+    };
+    assert!(Rc::ptr_eq(&target, &read));
+}
+
+#[test]
+fn statements_after_errors_are_kept() {
+    let src = b"local a = 1\nlocal b = (2\nlocal c = {3, 4}\nreturn a";
+    let (ast, errors) = parser::parse_chunk_recovering(src, "ast");
+    assert_eq!(errors.len(), 1);
+    let body = &ast[ast.main()].body;
+    let names: Vec<&str> = ast[body.stats]
+        .iter()
+        .map(|stat| match stat.kind {
+            StatKind::Local(names, _) => &*ast[names][0],
+            ref kind => panic!("not a local statement: {:?}", kind),
+        })
+        .collect();
+    assert_eq!(names, ["a", "c"]);
+    match ast[body.stats][1].kind {
+        StatKind::Local(_, values) => match ast[values][0].kind {
+            ExprKind::Table(fields) => assert_eq!(fields.len(), 2),
+            ref kind => panic!("not a table: {:?}", kind),
+        },
+        _ => unreachable!(),
+    }
+    assert!(body.ret.is_some());
+}
@@ -0,0 +1,113 @@
+//! Benchmarks timed by `looa::bench`, and the statistics of their runs
+
+extern crate looa;
+
+use std::time::Duration;
+
+use looa::bench::{Bencher, Stats, Time};
+use looa::Lua;
+
+fn ms(ms: &[u64]) -> Vec<Duration> {
+    ms.iter().map(|&ms| Duration::from_millis(ms)).collect()
+}
+
+fn approx(d: Duration, ms: f64) -> bool {
+    (d.as_secs_f64() * 1e3 - ms).abs() < 1e-6
+}
+
+#[test]
+fn leaves_out_outliers() {
+    let stats = Stats::of(&ms(&[10, 12, 11, 9, 10, 11, 100, 10])).unwrap();
+    assert_eq!((stats.runs, stats.outliers), (7, 1));
+    assert!(approx(stats.mean, 73.0 / 7.0), "{:?}", stats);
+    assert_eq!(stats.min, Duration::from_millis(9));
+    assert_eq!(stats.max, Duration::from_millis(12));
+    assert_eq!(stats.median, Duration::from_millis(10));
+    // the sample standard deviation of what was kept
+    let mean = 73.0 / 7.0;
+    let var = [10.0, 12.0, 11.0, 9.0, 10.0, 11.0, 10.0]
+        .iter()
+        .map(|t: &f64| (t - mean) * (t - mean))
+        .sum::<f64>()
+        / 6.0;
+    assert!(approx(stats.std_dev, var.sqrt()), "{:?}", stats);
+}
+
+#[test]
+fn handles_few_runs() {
+    assert_eq!(Stats::of(&[]), None);
+    let one = Stats::of(&ms(&[5])).unwrap();
+    assert_eq!((one.runs, one.outliers), (1, 0));
+    assert_eq!(
+        (one.mean, one.std_dev),
+        (Duration::from_millis(5), Duration::new(0, 0))
+    );
+    let same = Stats::of(&ms(&[3, 3, 3, 3])).unwrap();
+    assert_eq!((same.runs, same.outliers), (4, 0));
+}
+
+#[test]
+fn writes_times_in_units_that_suit_them() {
+    let times = [
+        Duration::from_nanos(850),
+        Duration::from_nanos(12_345),
+        Duration::from_micros(1_500),
+        Duration::from_millis(250),
+        Duration::from_millis(2_000),
+    ];
+    let shown: Vec<String> = times.iter().map(|&t| Time(t).to_string()).collect();
+    assert_eq!(shown, ["850 ns", "12.3 µs", "1.50 ms", "250 ms", "2.00 s"]);
+    let stats = Stats::of(&ms(&[10, 10, 10, 10, 40])).unwrap();
+    assert_eq!(
+        stats.to_string(),
+        "10.0 ms ± 0 ns (min 10.0 ms, max 10.0 ms, 4 runs, 1 outlier)"
+    );
+}
+
+#[test]
+fn runs_the_functions_a_script_returns() {
+    let mut lua = Lua::new();
+    let source = b"
+        calls = {}
+        local function count(name) calls[name] = (calls[name] or 0) + 1 end
+        return {
+            b = function() count('b') end,
+            a = function() count('a') end,
+            c = function() count('c') end,
+            not_a_function = 1,
+        }";
+    let mut bencher = Bencher::new();
+    bencher.set_warmup(2);
+    bencher.set_iterations(5);
+    bencher.set_filter(Some("a".to_string()));
+    let benches = bencher.run(&lua, source, "b.lua").unwrap();
+    let names: Vec<&str> = benches.iter().map(|b| &*b.name).collect();
+    assert_eq!(names, ["a"]);
+    assert_eq!(benches[0].stats.runs + benches[0].stats.outliers, 5);
+    let calls = lua.eval("{calls.a, calls.b}").unwrap();
+    assert_eq!(format!("{:?}", lua.eval("calls.a").unwrap()), "7");
+    assert!(calls.raw_get(&looa::Value::new(2)).is_nil());
+}
+
+#[test]
+fn runs_a_whole_script() {
+    let mut lua = Lua::new();
+    let mut bencher = Bencher::new();
+    bencher.set_warmup(1);
+    bencher.set_iterations(3);
+    let benches = bencher
+        .run(&lua, b"runs = (runs or 0) + 1", "whole.lua")
+        .unwrap();
+    assert_eq!(benches.len(), 1);
+    assert_eq!(benches[0].name, "whole.lua");
+    // once to see what it returns, once to warm up, and three times timed
+    assert_eq!(format!("{:?}", lua.eval("runs").unwrap()), "5");
+    let err = bencher
+        .run(
+            &lua,
+            b"return { f = function() error('boom') end }",
+            "e.lua",
+        )
+        .unwrap_err();
+    assert!(err.to_string().ends_with("boom"), "{}", err);
+}
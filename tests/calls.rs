@@ -0,0 +1,144 @@
+//! How much calls and new values allocate, counted on each test's own
+//! thread, since the allocator counting them is shared by the whole test
+//! binary
+
+extern crate looa;
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+
+use looa::lexer::Lexer;
+use looa::{parser, Backend, Lua, Value};
+
+struct Counting;
+
+thread_local! {
+    static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+}
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+const CALLS: usize = 1000;
+
+/// The allocations `body` makes on average, beyond those of the loop it is
+/// run `CALLS` times in, with `f` in scope
+fn per_call(backend: Backend, f: &str, body: &str) -> usize {
+    per_call_in(&mut state(backend), f, body)
+}
+
+fn per_call_in(lua: &mut Lua, f: &str, body: &str) -> usize {
+    allocations(lua, f, body) - allocations(lua, f, "")
+}
+
+fn state(backend: Backend) -> Lua {
+    let mut lua = Lua::new();
+    lua.set_backend(backend);
+    let first = Value::function(|args: &[Value]| Ok(args[0].clone()));
+    lua.set_global("first", first).unwrap();
+    lua
+}
+
+fn allocations(lua: &mut Lua, f: &str, body: &str) -> usize {
+    let src = format!("{}\nfor i = 1, {} do {} end", f, CALLS, body);
+    let chunk = lua.load(src.as_bytes(), "calls").unwrap();
+    // the first run fills the spare lists calls reuse, and collecting what
+    // it made fills the pools
+    chunk.call(Vec::new()).unwrap();
+    lua.collect_garbage();
+    let before = ALLOCATIONS.with(Cell::get);
+    chunk.call(Vec::new()).unwrap();
+    (ALLOCATIONS.with(Cell::get) - before) / CALLS
+}
+
+#[test]
+fn vm_calls_do_not_allocate() {
+    let rust = per_call(Backend::Vm, "local f = first", "f(i, i)");
+    assert_eq!(rust, 0);
+    let lua = per_call(
+        Backend::Vm,
+        "local function f(a, b) return b, a end",
+        "f(i, i)",
+    );
+    assert_eq!(lua, 0);
+}
+
+#[test]
+fn interpreted_calls_only_allocate_locals() {
+    let rust = per_call(Backend::Interpreter, "local f = first", "f(i, i)");
+    assert_eq!(rust, 0);
+    // the parameters, which closures could capture, and the list of the
+    // two results
+    let lua = per_call(
+        Backend::Interpreter,
+        "local function f(a, b) return b, a end",
+        "f(i, i)",
+    );
+    assert_eq!(lua, 3);
+    let lua = per_call(
+        Backend::Interpreter,
+        "local function f(a, b) return a end",
+        "f(i, i)",
+    );
+    assert_eq!(lua, 2);
+}
+
+#[test]
+fn small_tables_reuse_the_room_of_dropped_ones() {
+    let mut lua = state(Backend::Vm);
+    // with room for everything a run makes
+    lua.set_pool_limit(CALLS);
+    let bodies = [
+        "local t = {x = i, y = i}",
+        "local t = {i, i, i}",
+        "local t = {} t.x = i t[1] = i",
+    ];
+    let pooled: Vec<usize> = bodies
+        .iter()
+        .map(|body| per_call_in(&mut lua, "", body))
+        .collect();
+    // neither the tables nor their parts
+    assert_eq!(pooled, [0, 0, 0]);
+    lua.set_pool_limit(0);
+    for (body, pooled) in bodies.iter().zip(pooled) {
+        assert!(per_call_in(&mut lua, "", body) > pooled, "{}", body);
+    }
+}
+
+#[test]
+fn strings_reuse_dropped_values() {
+    let mut lua = state(Backend::Vm);
+    lua.set_pool_limit(CALLS);
+    // only the buffer the string is joined in
+    assert_eq!(per_call_in(&mut lua, "", "local s = 'k' .. i"), 1);
+    lua.set_pool_limit(0);
+    assert!(per_call_in(&mut lua, "", "local s = 'k' .. i") > 1);
+}
+
+#[test]
+fn syntax_trees_allocate_lists_rather_than_nodes() {
+    let count = |f: &dyn Fn()| {
+        let before = ALLOCATIONS.with(Cell::get);
+        f();
+        ALLOCATIONS.with(Cell::get) - before
+    };
+    // beyond the names the lexer allocates for each token
+    let tree = |src: &str| {
+        let lexed = count(&|| Lexer::new(src.as_bytes()).for_each(drop));
+        let parsed = count(&|| drop(parser::parse_chunk(src.as_bytes(), "calls").unwrap()));
+        parsed - lexed
+    };
+    let stat = "t.x = f(t[1] + 2 * x, {y = -x})\n";
+    let per_stat = (tree(&stat.repeat(CALLS)) - tree(stat)) as f64 / (CALLS - 1) as f64;
+    assert!(per_stat < 0.1, "{} allocations a statement", per_stat);
+}
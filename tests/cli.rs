@@ -0,0 +1,834 @@
+//! The `looa` binary, run as a subprocess
+
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+fn looa(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_looa"))
+        .args(args)
+        // the user's looa.toml isn't read
+        .env("LOOA_CONFIG", "")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(stdin.as_bytes())
+        .unwrap();
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stdout).into_owned()
+}
+
+fn stderr(output: &Output) -> String {
+    String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn runs_stat_options_and_stdin() {
+    let output = looa(&["-e", "x = 2", "-e", "print(x * 3)"], "");
+    assert!(output.status.success());
+    assert_eq!(stdout(&output), "6\n");
+    let output = looa(&["-"], "print(...)");
+    assert_eq!(stdout(&output), "\n");
+}
+
+#[test]
+fn selects_the_vm() {
+    // only the VM runs calls between Lua functions without nesting them
+    let src = |n| {
+        format!(
+            "local function d(n) if n == 0 then return 0 end return 1 + d(n - 1) end print(d({}))",
+            n
+        )
+    };
+    let output = looa(&["--vm", "-e", &src(200000)], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "200000\n");
+    let output = looa(&["-e", &src(1000000)], "");
+    assert!(!output.status.success());
+    assert!(stderr(&output).contains("stack overflow"));
+}
+
+#[test]
+fn scripts_run_on_a_big_stack() {
+    // so the interpreter's calls nest as deeply as ordinary scripts need
+    let src = "local function d(n) if n == 0 then return 0 end return 1 + d(n - 1) end \
+               print(d(10000))";
+    let output = looa(&["-e", src], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "10000\n");
+}
+
+#[test]
+fn reports_errors_with_a_traceback() {
+    let output = looa(&["-"], "local function f() error('boom') end\nf()");
+    assert_eq!(output.status.code(), Some(1));
+    let err = stderr(&output);
+    assert!(
+        err.starts_with(
+            "looa: stdin:1: boom\n1 | local function f() error('boom') end\nstack traceback:"
+        ),
+        "{}",
+        err
+    );
+    let output = looa(&["-e", "x = = 1"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(!stderr(&output).contains("traceback"));
+}
+
+#[test]
+fn reports_error_objects_with_tostring() {
+    let src = "error(setmetatable({}, {__tostring = function() return 'custom' end}))";
+    let output = looa(&["-e", src], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).starts_with("looa: custom\n"),
+        "{}",
+        stderr(&output)
+    );
+    let output = looa(&["-e", "error({})"], "");
+    assert!(
+        stderr(&output).starts_with("looa: (error object is a table value)\n"),
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn exits_with_the_code_given_to_os_exit() {
+    for &(code, status) in &[("", 0), ("true", 0), ("false", 1), ("3", 3)] {
+        let src = format!("print('out') os.exit({}) print('not reached')", code);
+        let output = looa(&["-e", &src], "");
+        assert_eq!(output.status.code(), Some(status), "{}", stderr(&output));
+        assert_eq!(stdout(&output), "out\n");
+    }
+    let output = looa(&["-e", "os.exit(1.5)"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).contains("number has no integer representation"));
+}
+
+#[test]
+fn os_exit_can_close_the_state() {
+    let src = "kept = setmetatable({}, {__gc = function() print('kept') end})\n\
+               setmetatable({}, {__gc = function() print('collected') end})\n\
+               os.exit(true, true)";
+    for &backend in &[&[][..], &["--vm"][..]] {
+        let mut args = backend.to_vec();
+        args.push("-");
+        let output = looa(&args, src);
+        assert_eq!(output.status.code(), Some(0), "{}", stderr(&output));
+        assert_eq!(stdout(&output), "collected\nkept\n");
+    }
+    let output = looa(
+        &["-"],
+        "kept = setmetatable({}, {__gc = print})\nos.exit(true)",
+    );
+    assert_eq!(stdout(&output), "");
+}
+
+#[test]
+fn dumps_a_script_without_running_it() {
+    let src = "print('ran')\nlocal x = 1";
+    let output = looa(&["--dump-tokens", "-"], src);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let tokens = stdout(&output);
+    assert!(
+        tokens.starts_with("1:1\tName(\"print\")\n1:6\tLeftParen\n"),
+        "{}",
+        tokens
+    );
+    assert!(
+        tokens.ends_with("2:11\tNumber(Int(1))\n2:12\tEof\n"),
+        "{}",
+        tokens
+    );
+    let output = looa(&["--dump-ast", "-"], src);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).starts_with("Block {\n    stats: [\n"));
+    assert!(!stdout(&output).contains("ran\n"));
+    // listings are printed in the order asked for
+    let output = looa(&["--dump-bytecode", "--dump-tokens", "-"], src);
+    let listing = stdout(&output);
+    assert!(listing.starts_with("main <stdin:0>"), "{}", listing);
+    assert!(listing.contains("GetGlobal") && listing.contains("1:1\tName"));
+    let output = looa(&["--dump-ast", "-"], "x = = 1");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).starts_with("looa: stdin:1:5: unexpected symbol near '='\n"));
+    let output = looa(&["--dump-ast"], "");
+    assert_eq!(output.status.code(), Some(1));
+}
+
+#[cfg(unix)]
+#[test]
+fn ctrl_c_interrupts_a_script() {
+    use std::io::{BufRead, BufReader};
+
+    let dir = std::env::temp_dir().join(format!("looa-cli-sigint-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("spin.lua");
+    std::fs::write(&script, "print('ready')\nwhile true do end\n").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_looa"))
+        .arg(&script)
+        .env("LOOA_CONFIG", "")
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut line = String::new();
+    BufReader::new(child.stdout.take().unwrap())
+        .read_line(&mut line)
+        .unwrap();
+    assert_eq!(line, "ready\n");
+    let killed = Command::new("kill")
+        .args(["-INT", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(killed.success());
+    let output = child.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("spin.lua:2: interrupted!"),
+        "{}",
+        stderr(&output)
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn rejects_unknown_options() {
+    let output = looa(&["--nonsense"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).starts_with("looa: unrecognized option '--nonsense'"));
+}
+
+#[test]
+fn checks_syntax_like_luac() {
+    let dir = std::env::temp_dir().join(format!("looa-cli-p-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let good = dir.join("good.lua");
+    let bad = dir.join("bad.lua");
+    std::fs::write(&good, "print('never run')\n").unwrap();
+    std::fs::write(&bad, "local x = = 1\n").unwrap();
+    let output = looa(&["-p", good.to_str().unwrap()], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+    let output = looa(&["-p", good.to_str().unwrap(), bad.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).contains("bad.lua:1:"),
+        "{}",
+        stderr(&output)
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn compiles_several_scripts_into_one_chunk() {
+    let dir = std::env::temp_dir().join(format!("looa-cli-compile-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("a.lua"), "shared = 'from a' print('a')\n").unwrap();
+    std::fs::write(dir.join("b.lua"), "print('b', shared)\nerror('late')\n").unwrap();
+    let out = dir.join("both.out");
+    let paths: Vec<String> = ["a.lua", "b.lua"]
+        .iter()
+        .map(|name| dir.join(name).to_str().unwrap().to_string())
+        .collect();
+    let output = looa(
+        &["compile", "-o", out.to_str().unwrap(), &paths[0], &paths[1]],
+        "",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    for backend in &[None, Some("--vm")] {
+        let mut args: Vec<&str> = backend.iter().cloned().collect();
+        args.push(out.to_str().unwrap());
+        let output = looa(&args, "");
+        assert_eq!(stdout(&output), "a\nb\tfrom a\n");
+        // each function keeps the name of the file it came from
+        assert!(
+            stderr(&output).contains("b.lua:2: late"),
+            "{}",
+            stderr(&output)
+        );
+    }
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn checks_stdin() {
+    let output = looa(&["check", "-"], "local x = = 1\nlocal y = = 2\n");
+    assert!(!output.status.success());
+    assert_eq!(
+        stderr(&output).matches("stdin:").count(),
+        2,
+        "{}",
+        stderr(&output)
+    );
+}
+
+#[test]
+fn formats_stdin() {
+    let output = looa(&["fmt", "-"], "local  x=1\n");
+    assert_eq!(stdout(&output), "local x = 1\n");
+}
+
+#[test]
+fn lints_stdin() {
+    let output = looa(&["lint", "-"], "local x = 1\n");
+    assert!(!output.status.success());
+    assert!(stdout(&output).contains("[unused-local]"));
+}
+
+#[test]
+fn formats_files_in_place() {
+    let dir = std::env::temp_dir().join(format!("looa-cli-fmt-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let messy = dir.join("messy.lua");
+    let tidy = dir.join("tidy.lua");
+    std::fs::write(&messy, "local  x=1\n").unwrap();
+    std::fs::write(&tidy, "local y = 2\n").unwrap();
+    let paths = [messy.to_str().unwrap(), tidy.to_str().unwrap()];
+    let output = looa(&["fmt", "--check", paths[0], paths[1]], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(stdout(&output), format!("{}\n", paths[0]));
+    assert_eq!(std::fs::read_to_string(&messy).unwrap(), "local  x=1\n");
+    let output = looa(&["fmt", paths[0], paths[1]], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "");
+    assert_eq!(std::fs::read_to_string(&messy).unwrap(), "local x = 1\n");
+    assert_eq!(std::fs::read_to_string(&tidy).unwrap(), "local y = 2\n");
+    let output = looa(&["fmt", "--check", paths[0], paths[1]], "");
+    assert!(output.status.success(), "{}", stdout(&output));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn graphs_requires() {
+    let dir = std::env::temp_dir().join(format!("looa-cli-deps-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(dir.join("main.lua"), "require 'used'\n").unwrap();
+    std::fs::write(dir.join("used.lua"), "").unwrap();
+    std::fs::write(dir.join("dead.lua"), "").unwrap();
+    let path = format!("{}/?.lua", dir.display());
+    let main = dir.join("main.lua");
+    let main = main.to_str().unwrap();
+    let output = looa(&["deps", "-p", &path, main], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(stdout(&output).contains("\"main\" -> \"used\";"));
+    let output = looa(
+        &["deps", "-p", &path, "--unused", dir.to_str().unwrap(), main],
+        "",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        stdout(&output),
+        format!("{}\n", dir.join("dead.lua").display())
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn documents_scripts() {
+    let dir = std::env::temp_dir().join(format!("looa-cli-doc-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("greet.lua");
+    std::fs::write(
+        &script,
+        "--- Greetings.\nlocal M = {}\n--- Say hello.\n-- @param name who to greet\n\
+         function M.hello(name) end\n--- Hidden.\nlocal function helper() end\nreturn M\n",
+    )
+    .unwrap();
+    let output = looa(&["doc", script.to_str().unwrap()], "");
+    assert!(output.status.success(), "{}", stderr(&output));
+    let out = stdout(&output);
+    assert!(
+        out.starts_with("# Module `greet`\n\nGreetings.\n"),
+        "{}",
+        out
+    );
+    assert!(out.contains("- `name`: who to greet\n"), "{}", out);
+    assert!(!out.contains("helper"), "{}", out);
+    let pages = dir.join("pages");
+    let output = looa(
+        &[
+            "doc",
+            "--html",
+            "-o",
+            pages.to_str().unwrap(),
+            script.to_str().unwrap(),
+        ],
+        "",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert!(pages.join("greet.html").is_file());
+    assert!(pages.join("index.html").is_file());
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn runs_test_files() {
+    let dir = std::env::temp_dir().join(format!("looa-cli-test-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("spec")).unwrap();
+    std::fs::write(
+        dir.join("spec/good_spec.lua"),
+        "describe('sums', function()\n  it('adds', function() assert.equal(2, 1 + 1) end)\nend)\n",
+    )
+    .unwrap();
+    let output = looa(&["test", dir.to_str().unwrap()], "");
+    assert!(output.status.success(), "{}", stdout(&output));
+    assert!(
+        stdout(&output).ends_with("\n1 passed, 0 failed, 0 pending\n"),
+        "{}",
+        stdout(&output)
+    );
+    std::fs::write(
+        dir.join("spec/bad_test.lua"),
+        "it('fails', function() assert.same({1}, {2}) end)\n",
+    )
+    .unwrap();
+    let output = looa(&["test", "--tap", "-j", "1", dir.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(1));
+    let out = stdout(&output);
+    assert!(
+        out.starts_with("TAP version 13\n1..2\nnot ok 1 - "),
+        "{}",
+        out
+    );
+    assert!(out.contains("expected 1 at [1], got 2"), "{}", out);
+    let output = looa(&["test", dir.join("nothing").to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(1));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_files_have_hooks_of_their_own() {
+    let dir = std::env::temp_dir().join(format!("looa-cli-hooks-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("a_test.lua"),
+        "it('x', function() debug.sethook(function() error('hooked from a') end, 'l') end)\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("b_test.lua"),
+        "it('y', function() assert.equal(1, 1) end)\n",
+    )
+    .unwrap();
+    let output = looa(&["test", "-j", "1", dir.to_str().unwrap()], "");
+    let out = stdout(&output);
+    assert!(!out.contains("hooked from a"), "{}", out);
+    assert!(out.contains("2 passed, 0 failed"), "{}", out);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn benchmarks_scripts() {
+    let dir = std::env::temp_dir().join(format!("looa-cli-bench-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("b.lua");
+    std::fs::write(
+        &script,
+        "return { loop = function() for i = 1, 10 do end end, other = function() end }",
+    )
+    .unwrap();
+    let output = looa(
+        &[
+            "bench",
+            "-n",
+            "4",
+            "-w",
+            "0",
+            "--filter",
+            "loop",
+            script.to_str().unwrap(),
+        ],
+        "",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    let out = stdout(&output);
+    assert!(out.starts_with("loop  "), "{}", out);
+    assert!(out.contains(" runs"), "{}", out);
+    assert!(!out.contains("other"), "{}", out);
+    std::fs::write(&script, "error('no')").unwrap();
+    let output = looa(&["bench", script.to_str().unwrap()], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).ends_with(":1: no\n"), "{}", stderr(&output));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn diffs_with_a_reference_binary() {
+    use std::os::unix::fs::PermissionsExt;
+    let dir = std::env::temp_dir().join(format!("looa-cli-diff-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    // a stand-in for the reference, giving what it would for the script
+    let reference = dir.join("lua");
+    std::fs::write(
+        &reference,
+        "#!/bin/sh\ncat > /dev/null\n\
+         printf 'hi\\ttable: 0x55d0\\n\\000looa diff\\000\\nreturn number 3\\n'\n",
+    )
+    .unwrap();
+    std::fs::set_permissions(&reference, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let script = dir.join("s.lua");
+    let (reference, script_path) = (reference.to_str().unwrap(), script.to_str().unwrap());
+    std::fs::write(&script, "print('hi', {})\nreturn 3\n").unwrap();
+    let output = looa(&["diff", "--lua", reference, script_path], "");
+    assert!(output.status.success(), "{}", stdout(&output));
+    assert_eq!(stdout(&output), format!("same {}\n", script_path));
+    std::fs::write(&script, "print('hi', {})\nerror('no')\n").unwrap();
+    let output = looa(&["diff", "--vm", "--lua", reference, script_path], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        stdout(&output),
+        format!(
+            "differs {0}\n  hi\ttable: 0x?\n- return number 3\n+ error {0}:2: no\n",
+            script_path
+        )
+    );
+    let output = looa(
+        &[
+            "diff",
+            "--lua",
+            &dir.join("none").to_string_lossy(),
+            script_path,
+        ],
+        "",
+    );
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr(&output).starts_with("looa: cannot run "),
+        "{}",
+        stderr(&output)
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn prompt_prints_tables_with_their_contents() {
+    let input = "t = {1, x = {y = {z = {}}}}\nt\n:set depth 1\nt\n\
+                 :set pretty off\n{}\n:set\n:set depth x\n\
+                 :set pretty on\nsetmetatable({}, {__tostring = function() return 'mine' end})\n";
+    let output = looa(&["-i"], input);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let out = stdout(&output);
+    let lines: Vec<&str> = out
+        .lines()
+        .map(|line| line.trim_start_matches("> "))
+        .collect();
+    assert_eq!(lines[1], "{ 1, x = { y = { z = {} } } }", "{}", out);
+    assert_eq!(lines[2], "{ 1, x = {...} }", "{}", out);
+    assert!(lines[3].starts_with("table: 0x"), "{}", out);
+    assert_eq!(&lines[4..6], ["pretty off", "depth 1"], "{}", out);
+    assert!(out.contains("mine\n"), "{}", out);
+    assert_eq!(stderr(&output), "depth must be a number, not 'x'\n");
+}
+
+#[test]
+fn colors_errors_when_asked() {
+    let output = looa(&["-"], "prnt('x')");
+    let err = stderr(&output);
+    assert!(!err.contains('\x1b'), "{}", err);
+    assert!(
+        err.contains("\n  | ^^^^ is nil\nhelp: did you mean 'print'?\n"),
+        "{}",
+        err
+    );
+    let output = looa(&["--color", "always", "-"], "x = = 1");
+    let err = stderr(&output);
+    assert!(err.starts_with("\x1b[1;31mlooa: \x1b[0m"), "{:?}", err);
+    assert!(!err.contains("traceback"), "{}", err);
+    let output = Command::new(env!("CARGO_BIN_EXE_looa"))
+        .args(["--color", "auto", "-e", "x = = 1"])
+        .env("NO_COLOR", "1")
+        .output()
+        .unwrap();
+    assert_eq!(
+        stderr(&output),
+        "looa: (command line):1:5: unexpected symbol near '='\n\
+         1 | x = = 1\n  |     ^ unexpected symbol\n"
+    );
+    let output = looa(&["--color", "sometimes", "-e", ""], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).starts_with("looa: '--color' needs 'always', 'never' or 'auto'"));
+}
+
+#[test]
+fn reads_settings_from_looa_toml() {
+    let dir = std::env::temp_dir().join(format!("looa-config-{}", std::process::id()));
+    let sub = dir.join("src");
+    std::fs::create_dir_all(sub.join("lib")).unwrap();
+    std::fs::write(
+        dir.join("looa.toml"),
+        "[sandbox]\nremove = [\"os.exit\"]\ninstruction-limit = 10_000\n\n\
+         [modules]\npath = \"./lib/?.lua\"\n",
+    )
+    .unwrap();
+    std::fs::write(sub.join("lib").join("m.lua"), "return {n = 7}").unwrap();
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_looa"))
+            .args(args)
+            .current_dir(&sub)
+            .env_remove("LOOA_CONFIG")
+            .env("XDG_CONFIG_HOME", &dir)
+            .output()
+            .unwrap()
+    };
+    // the project's file is found from a directory below it
+    let output = run(&["-l", "m", "-e", "print(m.n, os.exit)"]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "7\tnil\n");
+    let output = run(&["-e", "while true do end"]);
+    assert!(stderr(&output).contains("instruction limit exceeded"));
+    let output = run(&["-l", "nosuch", "-e", ""]);
+    assert!(stderr(&output).contains("no file './lib/nosuch.lua'"));
+    // as does require, which -l uses
+    let output = run(&["-e", "print(require('m').n, package.path)"]);
+    assert_eq!(stdout(&output), "7\t./lib/?.lua\n");
+    // and the user's applies under it, where the project's doesn't say
+    std::fs::create_dir_all(dir.join("looa")).unwrap();
+    std::fs::write(
+        dir.join("looa").join("looa.toml"),
+        "[run]\ncolor = 'always'\n[modules]\npath = 'nowhere/?.lua'\n",
+    )
+    .unwrap();
+    let output = run(&["-l", "m", "-e", "error('x')"]);
+    assert!(
+        stderr(&output).starts_with("\x1b[1;31mlooa: "),
+        "{:?}",
+        stderr(&output)
+    );
+    let output = run(&["--color", "never", "-l", "m", "-e", "error('x')"]);
+    assert!(
+        stderr(&output).starts_with("looa: "),
+        "{:?}",
+        stderr(&output)
+    );
+    std::fs::write(dir.join("looa.toml"), "[run]\nvm = 1\n").unwrap();
+    let output = run(&["-e", ""]);
+    assert_eq!(output.status.code(), Some(1));
+    assert_eq!(
+        stderr(&output),
+        format!(
+            "looa: {}:2: 'run.vm' needs a boolean, not an integer\n",
+            dir.join("looa.toml").display()
+        )
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn watches_a_script_and_what_it_requires() {
+    use std::io::{BufRead, BufReader};
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    let dir = std::env::temp_dir().join(format!("looa-watch-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("main.lua"),
+        "local helper = require 'helper'\nprint('one', helper.n, ...)",
+    )
+    .unwrap();
+    std::fs::write(dir.join("helper.lua"), "return {n = 0}").unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_looa"))
+        .args(["watch", "--interval", "20", "main.lua", "x"])
+        .current_dir(&dir)
+        .env("LOOA_CONFIG", "")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let (send, lines) = mpsc::channel();
+    let out = BufReader::new(child.stdout.take().unwrap());
+    std::thread::spawn(move || {
+        for line in out.lines() {
+            if send.send(line.unwrap()).is_err() {
+                break;
+            }
+        }
+    });
+    let next_run = || -> Vec<String> {
+        let mut run = Vec::new();
+        loop {
+            let line = lines.recv_timeout(Duration::from_secs(10)).unwrap();
+            let done = line.contains(" in ") && line.ends_with(" ---");
+            run.push(line);
+            if done {
+                return run;
+            }
+        }
+    };
+    let run = next_run();
+    assert_eq!(
+        run[..2],
+        ["--- looa watch: main.lua (run 1) ---", "one\t0\tx"]
+    );
+    assert!(
+        run[2].starts_with("--- main.lua finished in ")
+            && run[2].ends_with("; watching 2 files ---"),
+        "{:?}",
+        run
+    );
+    std::fs::write(dir.join("helper.lua"), "return {n = 1}").unwrap();
+    let run = next_run();
+    // each run loads what it requires afresh
+    assert_eq!(
+        run[..2],
+        ["--- looa watch: main.lua (run 2) ---", "one\t1\tx"]
+    );
+    std::fs::write(dir.join("main.lua"), "print('two')\nerror('stop')").unwrap();
+    let run = next_run();
+    assert_eq!(run[..2], ["--- looa watch: main.lua (run 3) ---", "two"]);
+    assert!(run[2].starts_with("--- main.lua failed in "), "{:?}", run);
+    assert!(run[2].ends_with("; watching 1 file ---"), "{:?}", run);
+    child.kill().unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(stderr(&output).starts_with("looa: main.lua:2: stop\n"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn debugs_a_script_from_piped_commands() {
+    let dir = std::env::temp_dir().join(format!("looa-cli-debug-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("f.lua");
+    std::fs::write(
+        &path,
+        "local function f(a, b)\n  return a + b\nend\nprint(f(1, 2))\n",
+    )
+    .unwrap();
+    let path = path.to_str().unwrap();
+    let commands = "break f.lua:2\ncontinue\np a + b\nbt\ncontinue\n";
+    let output = looa(&["debug", path], commands);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let out = stdout(&output);
+    let expected = format!(
+        "{0}:1\n1\tlocal function f(a, b)\n\
+         (looa) breakpoint at f.lua:2\n\
+         (looa) breakpoint at {0}:2\n2\t  return a + b\n\
+         (looa) 3\n\
+         (looa) *#0 {0}:2 in function at line 1\n #1 {0}:4 in main chunk\n\
+         (looa) 3\n",
+        path
+    );
+    assert!(out.contains(&expected), "{}", out);
+    // commands run out at the end of the input, which quits
+    let output = looa(&["debug", path], "");
+    assert!(output.status.success());
+    assert!(!stdout(&output).contains("3\n"), "{}", stdout(&output));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn profiles_a_script() {
+    let dir = std::env::temp_dir().join(format!("looa-profile-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let script = dir.join("p.lua");
+    std::fs::write(
+        &script,
+        "local M = {}\nfunction M.spin(n) for i = 1, n do end end\n\
+         M.spin(20000)\nprint(...)",
+    )
+    .unwrap();
+    let script = script.to_str().unwrap();
+    let out = dir.join("p.folded");
+    let output = looa(
+        &[
+            "profile",
+            "--out",
+            out.to_str().unwrap(),
+            "--every",
+            "100",
+            "-n",
+            "1",
+            script,
+            "x",
+        ],
+        "",
+    );
+    assert!(output.status.success(), "{}", stderr(&output));
+    assert_eq!(stdout(&output), "x\n");
+    let err = stderr(&output);
+    let lines: Vec<&str> = err.lines().collect();
+    assert!(
+        lines[1].ends_with(" samples, one every 100 steps"),
+        "{}",
+        err
+    );
+    assert_eq!(lines[2], "   self   total  function");
+    let spin = format!("M.spin ({}:2)", script);
+    assert!(lines[3].ends_with(&format!("%  {}", spin)), "{}", err);
+    assert_eq!(lines.len(), 4, "{}", err);
+    let folded = std::fs::read_to_string(&out).unwrap();
+    assert!(
+        folded.starts_with(&format!("{};{} ", script, spin)),
+        "{}",
+        folded
+    );
+    let output = looa(&["profile", "-e"], "");
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).starts_with("looa: unrecognized option '-e'"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn reports_the_coverage_of_test_runs() {
+    let dir = std::env::temp_dir().join(format!("looa-cli-cover-{}", std::process::id()));
+    std::fs::create_dir_all(dir.join("spec")).unwrap();
+    std::fs::write(
+        dir.join("sign.lua"),
+        "local M = {}\nfunction M.sign(x)\n  if x < 0 then\n    return -1\n  end\n  \
+         if x == 0 then\n    return 0\n  end\n  return 1\nend\nreturn M\n",
+    )
+    .unwrap();
+    // the spec requires the module it tests, which is measured too
+    std::fs::write(
+        dir.join("spec/sign_spec.lua"),
+        "local sign = require('sign').sign\n\
+         it('is 1 for positive numbers', function()\n  assert.equal(1, sign(5))\nend)\n",
+    )
+    .unwrap();
+    let cover = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_looa"))
+            .arg("cover")
+            .args(args)
+            .current_dir(&dir)
+            .env("LOOA_CONFIG", "")
+            .output()
+            .unwrap()
+    };
+    let output = cover(&["--lcov", "out.info", "."]);
+    assert!(output.status.success(), "{}", stderr(&output));
+    let out = stdout(&output);
+    assert!(
+        out.ends_with(
+            "\n1 passed, 0 failed, 0 pending\n\n \
+             lines    hit   cover  file\n     \
+             8      6   75.0%  sign.lua  (missed 4, 7)\n     \
+             3      3  100.0%  spec/sign_spec.lua\n    \
+             11      9   81.8%  total\n"
+        ),
+        "{}",
+        out
+    );
+    let info = std::fs::read_to_string(dir.join("out.info")).unwrap();
+    assert!(info.starts_with("TN:\nSF:sign.lua\nDA:1,1\nDA:2,1\nDA:3,1\nDA:4,0\n"));
+    assert!(
+        info.contains("LF:8\nLH:6\nend_of_record\nTN:\nSF:spec/sign_spec.lua\n"),
+        "{}",
+        info
+    );
+    assert!(info.ends_with("LF:3\nLH:3\nend_of_record\n"), "{}", info);
+    let output = cover(&["--lcov"]);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr(&output).starts_with("looa: '--lcov' needs argument"));
+    let _ = std::fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,153 @@
+extern crate looa;
+
+use looa::config::{self, Config, ConfigError};
+use looa::{Backend, Lua, Value};
+
+#[test]
+fn parses_each_setting() {
+    let text = r#"
+# shared by the team
+[run]
+vm = true
+optimize = false   # for debugging the compiler
+color = "never"
+
+[sandbox]
+instruction-limit = 1_000_000
+memory-limit = 65536
+call-limit = 50
+remove = [
+    "os.exit",  # scripts can't stop the host
+    'collectgarbage',
+]
+
+[modules]
+path = './?.lua;./lib/?.lua'
+
+[ repl ]
+pretty = false
+depth = 1
+"#;
+    assert_eq!(
+        Config::parse(text, "looa.toml"),
+        Ok(Config {
+            vm: Some(true),
+            optimize: Some(false),
+            color: Some("never".into()),
+            instruction_limit: Some(1_000_000),
+            memory_limit: Some(65536),
+            call_limit: Some(50),
+            remove: Some(vec!["os.exit".into(), "collectgarbage".into()]),
+            path: Some("./?.lua;./lib/?.lua".into()),
+            pretty: Some(false),
+            depth: Some(1),
+        })
+    );
+    let config = Config::parse("[modules]\npath = \"a\\\\b#/?.lua\" # c\n", "f").unwrap();
+    assert_eq!(config.module_path(), "a\\b#/?.lua");
+    assert_eq!(Config::default().module_path(), "./?.lua;./?/init.lua");
+}
+
+#[test]
+fn reports_errors_with_their_line() {
+    let error = |text: &str| Config::parse(text, "looa.toml").unwrap_err().to_string();
+    assert_eq!(
+        error("[run]\nvm = true\nvm = false"),
+        "looa.toml:3: 'run.vm' is given twice"
+    );
+    assert_eq!(
+        error("[run]\nvx = true"),
+        "looa.toml:2: unknown setting 'run.vx'"
+    );
+    assert_eq!(error("vm = true"), "looa.toml:1: unknown setting 'vm'");
+    assert_eq!(error("\n[runs]"), "looa.toml:2: unknown table [runs]");
+    assert_eq!(error("[run"), "looa.toml:1: bad table header '[run'");
+    assert_eq!(error("[run]\nvm"), "looa.toml:2: '=' expected after 'vm'");
+    assert_eq!(
+        error("[repl]\ndepth = \"2\""),
+        "looa.toml:2: 'repl.depth' needs an integer, not a string"
+    );
+    assert_eq!(
+        error("[repl]\ndepth = -2"),
+        "looa.toml:2: 'repl.depth' can't be negative"
+    );
+    assert_eq!(
+        error("[run]\ncolor = 'sometimes'"),
+        "looa.toml:2: 'run.color' needs 'always', 'never' or 'auto', not 'sometimes'"
+    );
+    assert_eq!(
+        error("[sandbox]\nremove = [\"os\", 1]"),
+        "looa.toml:2: 'sandbox.remove' needs an array of strings, not an integer"
+    );
+    assert_eq!(
+        error("[sandbox]\nremove = [\"os\""),
+        "looa.toml:2: unfinished array"
+    );
+    assert_eq!(
+        error("[modules]\npath = \"./?.lua"),
+        "looa.toml:2: unfinished string"
+    );
+    assert_eq!(
+        error("[run]\nvm = true false"),
+        "looa.toml:2: unexpected 'false' after value"
+    );
+    assert_eq!(
+        error("[sandbox]\ncall-limit = 1__0"),
+        "looa.toml:2: bad value '1__0'"
+    );
+    let missing = Config::read("no/such/looa.toml".as_ref()).unwrap_err();
+    assert_eq!(
+        (missing.file.as_str(), missing.line),
+        ("no/such/looa.toml", 0)
+    );
+    assert!(missing.message.starts_with("cannot read: "));
+    let unreadable = ConfigError {
+        file: "looa.toml".into(),
+        line: 0,
+        message: "cannot read: gone".into(),
+    };
+    assert_eq!(unreadable.to_string(), "looa.toml: cannot read: gone");
+}
+
+#[test]
+fn merges_with_later_settings_winning() {
+    let user = Config::parse("[run]\nvm = true\n[repl]\ndepth = 5", "user").unwrap();
+    let project = Config::parse("[run]\nvm = false\ncolor = 'always'", "project").unwrap();
+    let merged = user.merge(project);
+    assert_eq!(merged.vm, Some(false));
+    assert_eq!(merged.color.as_deref(), Some("always"));
+    assert_eq!(merged.depth, Some(5));
+    assert_eq!(merged.pretty, None);
+}
+
+#[test]
+fn applies_to_a_state() {
+    let text = "[run]\nvm = true\n[sandbox]\ncall-limit = 20\n\
+                instruction-limit = 5000\nremove = ['os.exit', 'print', 'no.such']";
+    let config = Config::parse(text, "looa.toml").unwrap();
+    let mut lua = Lua::new();
+    config.apply(&mut lua).unwrap();
+    assert_eq!(lua.backend(), Backend::Vm);
+    assert_eq!(lua.call_limit(), 20);
+    assert_eq!(lua.instruction_limit(), Some(5000));
+    let removed = lua
+        .eval("os.exit == nil and print == nil and type(os) == 'table'")
+        .unwrap();
+    assert_eq!(removed, Value::new(true));
+}
+
+#[test]
+fn finds_the_nearest_project_file() {
+    let dir = std::env::temp_dir().join(format!("looa-config-files-{}", std::process::id()));
+    let sub = dir.join("a").join("b");
+    std::fs::create_dir_all(&sub).unwrap();
+    std::fs::write(dir.join(config::FILE_NAME), "[repl]\ndepth = 4").unwrap();
+    let files = config::files(&sub);
+    assert_eq!(files.last(), Some(&dir.join(config::FILE_NAME)));
+    std::fs::write(dir.join("a").join(config::FILE_NAME), "").unwrap();
+    assert_eq!(
+        config::files(&sub).last(),
+        Some(&dir.join("a").join(config::FILE_NAME))
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+}
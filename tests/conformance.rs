@@ -0,0 +1,338 @@
+//! Runs the test suite of the reference implementation, lua-5.4.x-tests,
+//! and reports how much of it passes, by feature area
+//!
+//! The suite isn't part of this repository, so the test that runs it is
+//! ignored. Unpack the suite and point `LUA_TESTS` at it to run it:
+//!
+//! ```text
+//! LUA_TESTS=~/lua-5.4.6-tests cargo test --test conformance -- --ignored --nocapture
+//! ```
+//!
+//! Each file of the suite runs in a new state with `_port` and `_soft`
+//! set, as its `all.lua` does for a portable, quick run, and passes if it
+//! runs to the end without raising an error. The report is printed and
+//! also written to `conformance.md` in Cargo's temporary directory for
+//! tests, so it can be kept and compared between versions.
+
+extern crate looa;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+
+use looa::{Backend, Lua, Value};
+
+/// How many instructions a file of the suite may run, so one that loops
+/// forever is a failure rather than a hang
+const INSTRUCTION_LIMIT: u64 = 2_000_000_000;
+
+/// The stack each file runs on, since the suite checks deep recursion
+const STACK_SIZE: usize = 64 << 20;
+
+/// The feature area of each file of the suite, as the reference manual
+/// groups them
+const AREAS: &[(&str, &str)] = &[
+    ("api.lua", "C API"),
+    ("attrib.lua", "modules"),
+    ("big.lua", "tables"),
+    ("bitwise.lua", "operators"),
+    ("bwcoercion.lua", "operators"),
+    ("calls.lua", "functions"),
+    ("closure.lua", "functions"),
+    ("code.lua", "bytecode"),
+    ("constructs.lua", "syntax"),
+    ("coroutine.lua", "coroutines"),
+    ("cstack.lua", "functions"),
+    ("db.lua", "debug library"),
+    ("errors.lua", "errors"),
+    ("events.lua", "metatables"),
+    ("files.lua", "io and os libraries"),
+    ("gc.lua", "garbage collection"),
+    ("gengc.lua", "garbage collection"),
+    ("goto.lua", "syntax"),
+    ("heavy.lua", "limits"),
+    ("literals.lua", "syntax"),
+    ("locals.lua", "syntax"),
+    ("main.lua", "standalone interpreter"),
+    ("math.lua", "math library"),
+    ("nextvar.lua", "tables"),
+    ("pm.lua", "string library"),
+    ("sort.lua", "table library"),
+    ("strings.lua", "string library"),
+    ("tpack.lua", "string library"),
+    ("tracegc.lua", "garbage collection"),
+    ("utf8.lua", "utf8 library"),
+    ("vararg.lua", "functions"),
+    ("verybig.lua", "limits"),
+];
+
+/// Why a file of the suite failed
+#[derive(Clone, Debug, PartialEq)]
+enum Failure {
+    /// it called a function that doesn't exist, such as one of a library
+    /// that is yet to be written
+    Missing(String),
+    Syntax(String),
+    /// one of its `assert`s failed
+    Assertion(String),
+    /// it ran past the instruction limit
+    Limit(u64),
+    /// the interpreter panicked running it
+    Panic(String),
+    Other(String),
+}
+impl Failure {
+    fn of(msg: &str, limit: u64) -> Failure {
+        let first = msg.lines().next().unwrap_or("").to_string();
+        if let Some(name) = called_nil(&first) {
+            Failure::Missing(name)
+        } else if first.contains("syntax error") {
+            Failure::Syntax(first)
+        } else if first.contains("assertion failed") {
+            Failure::Assertion(first)
+        } else if first.contains("instruction limit exceeded") {
+            Failure::Limit(limit)
+        } else {
+            Failure::Other(first)
+        }
+    }
+    fn kind(&self) -> &'static str {
+        match *self {
+            Failure::Missing(_) => "missing function",
+            Failure::Syntax(_) => "syntax error",
+            Failure::Assertion(_) => "failed assertion",
+            Failure::Limit(_) => "instruction limit",
+            Failure::Panic(_) => "panic",
+            Failure::Other(_) => "error",
+        }
+    }
+    fn detail(&self) -> String {
+        match *self {
+            Failure::Missing(ref name) => format!("`{}`", name),
+            Failure::Limit(limit) => format!("more than {} instructions", limit),
+            Failure::Syntax(ref msg)
+            | Failure::Assertion(ref msg)
+            | Failure::Panic(ref msg)
+            | Failure::Other(ref msg) => msg.clone(),
+        }
+    }
+}
+
+/// The name in an error from calling nil, such as `string.pack` from
+/// "attempt to call a nil value (field 'pack')", as far as it is given
+fn called_nil(msg: &str) -> Option<String> {
+    let rest = &msg[msg.find("attempt to call a nil value")?..];
+    let start = rest.find('\'')? + 1;
+    let len = rest[start..].find('\'')?;
+    Some(rest[start..start + len].to_string())
+}
+
+/// A file of the suite and how it went
+struct FileReport {
+    name: String,
+    area: &'static str,
+    failure: Option<Failure>,
+}
+
+/// The files of the suite in `dir`, by name, leaving out the driver that
+/// runs the others
+fn suite_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap_or_else(|err| panic!("cannot read {}: {}", dir.display(), err))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lua"))
+        .filter(|path| path.file_name().is_some_and(|name| name != "all.lua"))
+        .collect();
+    files.sort();
+    files
+}
+
+/// Run the file at `path`, on a thread of its own in a new state that
+/// stops after `limit` instructions
+fn run_file(path: &Path, backend: Backend, limit: u64) -> Option<Failure> {
+    let path = path.to_path_buf();
+    let run = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(move || {
+            let mut lua = Lua::new();
+            lua.set_backend(backend);
+            lua.set_instruction_limit(Some(limit));
+            for name in &["_port", "_soft"] {
+                lua.set_global(name, Value::new(true)).unwrap();
+            }
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let result = fs::read(&path)
+                .map_err(|err| format!("cannot read {}: {}", path.display(), err))
+                .and_then(|source| {
+                    lua.load(&source, &name)
+                        .and_then(|main| main.call(Vec::new()))
+                        .map_err(|err| err.to_string())
+                });
+            result.err().map(|err| Failure::of(&err, limit))
+        })
+        .expect("threads can be started");
+    run.join().unwrap_or_else(|panic| {
+        let msg = panic
+            .downcast_ref::<String>()
+            .cloned()
+            .or_else(|| panic.downcast_ref::<&str>().map(|msg| msg.to_string()))
+            .unwrap_or_default();
+        Some(Failure::Panic(msg))
+    })
+}
+
+/// Run each file of the suite in `dir`, from that directory as the suite
+/// expects
+fn run_suite(dir: &Path, backend: Backend, limit: u64) -> Vec<FileReport> {
+    let dir = &dir.canonicalize().unwrap();
+    let cwd = env::current_dir().unwrap();
+    env::set_current_dir(dir).unwrap();
+    let reports = suite_files(dir)
+        .iter()
+        .map(|path| {
+            let name = path.file_name().unwrap().to_string_lossy().into_owned();
+            let area = AREAS
+                .iter()
+                .find(|&&(file, _)| file == name)
+                .map_or("other", |&(_, area)| area);
+            FileReport {
+                failure: run_file(path, backend, limit),
+                name,
+                area,
+            }
+        })
+        .collect();
+    env::set_current_dir(cwd).unwrap();
+    reports
+}
+
+fn percent(passed: usize, total: usize) -> f64 {
+    match total {
+        0 => 0.0,
+        _ => 100.0 * passed as f64 / total as f64,
+    }
+}
+
+/// The report of a run of the suite with `backend`, as Markdown
+fn report(reports: &[FileReport], backend: Backend) -> String {
+    let passed = reports.iter().filter(|r| r.failure.is_none()).count();
+    let mut out = format!(
+        "# Conformance ({:?})\n\n{} of {} files pass ({:.1}%)\n\n",
+        backend,
+        passed,
+        reports.len(),
+        percent(passed, reports.len())
+    );
+    let mut areas: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    for r in reports {
+        let area = areas.entry(r.area).or_insert((0, 0));
+        area.0 += r.failure.is_none() as usize;
+        area.1 += 1;
+    }
+    out.push_str("| area | passing | |\n|---|---|---|\n");
+    for (area, &(passed, total)) in &areas {
+        out.push_str(&format!(
+            "| {} | {}/{} | {:.0}% |\n",
+            area,
+            passed,
+            total,
+            percent(passed, total)
+        ));
+    }
+    let mut kinds: BTreeMap<&str, usize> = BTreeMap::new();
+    for failure in reports.iter().filter_map(|r| r.failure.as_ref()) {
+        *kinds.entry(failure.kind()).or_insert(0) += 1;
+    }
+    if !kinds.is_empty() {
+        out.push_str("\n## Failures\n\n");
+        for (kind, count) in &kinds {
+            out.push_str(&format!("- {}: {}\n", kind, count));
+        }
+        out.push('\n');
+        for r in reports {
+            if let Some(ref failure) = r.failure {
+                out.push_str(&format!(
+                    "- `{}` ({}): {}: {}\n",
+                    r.name,
+                    r.area,
+                    failure.kind(),
+                    failure.detail()
+                ));
+            }
+        }
+    }
+    out
+}
+
+#[test]
+#[ignore]
+fn reference_suite() {
+    let dir = match env::var_os("LUA_TESTS") {
+        Some(dir) => PathBuf::from(dir),
+        None => panic!("set LUA_TESTS to the directory of the unpacked lua-5.4.x-tests"),
+    };
+    let mut out = String::new();
+    for &backend in &[Backend::Interpreter, Backend::Vm] {
+        out.push_str(&report(
+            &run_suite(&dir, backend, INSTRUCTION_LIMIT),
+            backend,
+        ));
+        out.push('\n');
+    }
+    print!("{}", out);
+    let path = Path::new(env!("CARGO_TARGET_TMPDIR")).join("conformance.md");
+    fs::write(&path, &out).unwrap();
+    println!("written to {}", path.display());
+}
+
+/// The harness itself, on a made-up suite with a file failing each way
+#[test]
+fn classifies_failures() {
+    let dir = env::temp_dir().join(format!("looa-conformance-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let files = [
+        ("all.lua", "error('the driver is not run')"),
+        ("calls.lua", "assert(_port and _soft)"),
+        ("closure.lua", "assert(1 == 2)"),
+        ("strings.lua", "string.nosuch('x')"),
+        ("goto.lua", "local = 1"),
+        ("heavy.lua", "while true do end"),
+        ("extra.lua", "error('odd')"),
+    ];
+    for &(name, source) in &files {
+        fs::write(dir.join(name), source).unwrap();
+    }
+    let reports = run_suite(&dir, Backend::Interpreter, 100_000);
+    let got: Vec<(&str, &str, Option<&str>)> = reports
+        .iter()
+        .map(|r| (&*r.name, r.area, r.failure.as_ref().map(Failure::kind)))
+        .collect();
+    assert_eq!(
+        got,
+        [
+            ("calls.lua", "functions", None),
+            ("closure.lua", "functions", Some("failed assertion")),
+            ("extra.lua", "other", Some("error")),
+            ("goto.lua", "syntax", Some("syntax error")),
+            ("heavy.lua", "limits", Some("instruction limit")),
+            ("strings.lua", "string library", Some("missing function")),
+        ]
+    );
+    assert_eq!(reports[5].failure, Some(Failure::Missing("nosuch".into())));
+    let out = report(&reports, Backend::Interpreter);
+    assert!(
+        out.starts_with("# Conformance (Interpreter)\n\n1 of 6 files pass (16.7%)\n"),
+        "{}",
+        out
+    );
+    assert!(out.contains("| functions | 1/2 | 50% |\n"), "{}", out);
+    assert!(out.contains("- missing function: 1\n"), "{}", out);
+    assert!(
+        out.contains("- `strings.lua` (string library): missing function: `nosuch`\n"),
+        "{}",
+        out
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
@@ -0,0 +1,106 @@
+extern crate looa;
+
+use std::fs;
+use std::path::PathBuf;
+
+use looa::coverage::{self, Coverage};
+use looa::testing::Runner;
+use looa::{Backend, Lua};
+
+const SOURCE: &str = "\
+local function f(x)
+  if x > 1 then
+    return 'big'
+  elseif x < 0 then
+    return 'negative'
+  end
+  return 'small'
+end
+::top::
+for i = 1, 2 do
+  f(i)
+end
+return f(0)
+";
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("looa-{}-{}", name, std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn finds_the_lines_that_can_run() {
+    let lines = coverage::executable_lines(SOURCE.as_bytes(), "m.lua").unwrap();
+    let lines: Vec<u32> = lines.into_iter().collect();
+    assert_eq!(lines, [1, 2, 3, 5, 7, 10, 11, 13]);
+    assert!(coverage::executable_lines(b"x = = 1", "m.lua").is_err());
+}
+
+#[test]
+fn counts_the_lines_run() {
+    let dir = temp_dir("coverage");
+    let path = dir.join("m.lua");
+    fs::write(&path, SOURCE).unwrap();
+    let name = path.to_str().unwrap();
+    for &backend in &[Backend::Interpreter, Backend::Vm] {
+        let coverage = Coverage::new();
+        let mut lua = Lua::new();
+        lua.set_backend(backend);
+        coverage.hook(&mut lua);
+        lua.load(SOURCE.as_bytes(), name)
+            .unwrap()
+            .call(Vec::new())
+            .unwrap();
+        // another chunk's lines aren't reported
+        lua.load(b"local x = 1", "=(load)")
+            .unwrap()
+            .call(Vec::new())
+            .unwrap();
+        lua.remove_hook();
+        let report = coverage.report();
+        assert_eq!(report.len(), 1, "{:?}", report);
+        let file = &report[0];
+        assert_eq!(file.path, name);
+        assert_eq!(file.lines[&11], 2, "{:?}", file);
+        assert_eq!(file.lines[&2], 3, "{:?}", file);
+        assert_eq!(file.missed(), [5], "{:?}", file);
+        assert_eq!(file.found(), file.hit() + 1, "{:?}", file);
+    }
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn covers_test_runs_on_every_thread() {
+    let dir = temp_dir("coverage-runs");
+    for i in 0..4 {
+        let source = format!(
+            "describe('s', function()\n  it('runs', function()\n    assert({} >= 0)\n  end)\n  \
+             it('is filtered out', function()\n    error('not run')\n  end)\nend)\n",
+            i
+        );
+        fs::write(dir.join(format!("t{}_spec.lua", i)), source).unwrap();
+    }
+    let coverage = Coverage::new();
+    let mut runner = Runner::new();
+    runner.set_jobs(4);
+    runner.set_filter(Some("runs".into()));
+    runner.set_setup(Some(coverage.setup()));
+    let files = looa::testing::discover(&dir).unwrap();
+    let results = runner.run(&files);
+    assert!(results.iter().all(|file| file.ok()), "{:?}", results);
+    let report = coverage.report();
+    assert_eq!(report.len(), 4);
+    for file in &report {
+        assert_eq!(file.missed(), [6], "{:?}", file);
+    }
+    let lcov = coverage::lcov(&report[..1]);
+    assert_eq!(
+        lcov,
+        format!(
+            "TN:\nSF:{}\nDA:1,1\nDA:2,1\nDA:3,1\nDA:5,1\nDA:6,0\nLF:5\nLH:4\nend_of_record\n",
+            report[0].path
+        )
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
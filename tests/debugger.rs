@@ -0,0 +1,133 @@
+//! Pausing scripts with `looa::Debugger`, and looking at them while they
+//! are paused
+
+extern crate looa;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use looa::{Backend, Debugger, Lua, PauseReason, Resume, Value};
+
+const SOURCE: &str = "\
+local function f(a, b)
+  local c = a + b
+  return c
+end
+local y = f(1, 2)
+local z = f(y, 4)
+return z
+";
+
+/// What a handler saw at one pause
+#[derive(Debug, PartialEq)]
+struct Pause {
+    reason: PauseReason,
+    /// the line of each frame, innermost first
+    lines: Vec<Option<u32>>,
+    value: Value,
+}
+
+#[test]
+fn pauses_at_breakpoints_and_steps() {
+    for &backend in &[Backend::Interpreter, Backend::Vm] {
+        let mut lua = Lua::new();
+        lua.set_backend(backend);
+        let pauses = Rc::new(RefCell::new(Vec::new()));
+        let seen = pauses.clone();
+        let debugger = Debugger::attach(&mut lua, move |paused| {
+            let lines = paused.frames().iter().map(|frame| frame.line()).collect();
+            let expr = match paused.reason() {
+                PauseReason::Breakpoint => "a + b",
+                PauseReason::Step => "c * 10",
+            };
+            let mut pauses = seen.borrow_mut();
+            pauses.push(Pause {
+                reason: paused.reason(),
+                lines,
+                value: paused.eval(0, expr)?,
+            });
+            // the second call runs to the end
+            if pauses.len() == 2 {
+                paused.debugger().clear_breakpoint("m.lua", 2);
+                return Ok(Resume::Continue);
+            }
+            Ok(Resume::StepOver)
+        });
+        debugger.set_breakpoint("m.lua", 2);
+        let vals = lua
+            .load(SOURCE.as_bytes(), "@dir/m.lua")
+            .unwrap()
+            .call(Vec::new())
+            .unwrap();
+        assert_eq!(vals.into_first(), Value::new(7));
+        assert_eq!(
+            *pauses.borrow(),
+            [
+                Pause {
+                    reason: PauseReason::Breakpoint,
+                    lines: vec![Some(2), Some(5)],
+                    value: Value::new(3),
+                },
+                Pause {
+                    reason: PauseReason::Step,
+                    lines: vec![Some(3), Some(5)],
+                    value: Value::new(30),
+                },
+            ],
+            "{:?}",
+            backend
+        );
+        assert!(debugger.breakpoints().is_empty());
+    }
+}
+
+#[test]
+fn evaluates_in_any_frame() {
+    let mut lua = Lua::new();
+    let results = Rc::new(RefCell::new(Vec::new()));
+    let seen = results.clone();
+    let debugger = Debugger::attach(&mut lua, move |paused| {
+        let mut results = seen.borrow_mut();
+        let show = |val: Value| format!("{:?}", val);
+        results.push(paused.eval(1, "y").map(show));
+        results.push(paused.eval(1, "f ~= nil").map(show));
+        results.push(paused.eval(2, "1").map(show));
+        results.push(paused.eval(0, "a +").map(show));
+        // setting a variable changes what the function goes on with
+        if results.len() == 4 {
+            assert!(paused.frames()[0].set("c", Value::new(40)));
+        }
+        Ok(Resume::Continue)
+    });
+    debugger.set_breakpoint("m.lua", 7);
+    debugger.set_breakpoint("m.lua", 3);
+    debugger.set_breakpoint("m.lua", 3);
+    assert!(debugger.clear_breakpoint("m.lua", 7));
+    assert!(!debugger.clear_breakpoint("m.lua", 7));
+    assert_eq!(debugger.breakpoints(), [("m.lua".to_string(), 3)]);
+    let vals = lua
+        .load(SOURCE.as_bytes(), "m.lua")
+        .unwrap()
+        .call(Vec::new())
+        .unwrap();
+    assert_eq!(vals.into_first(), Value::new(44));
+    let results = results.borrow();
+    assert_eq!(results.len(), 8);
+    // `y` isn't in scope until the first call returns
+    assert_eq!(results[0].as_ref().unwrap(), "nil");
+    assert_eq!(results[4].as_ref().unwrap(), "40");
+    assert_eq!(results[1].as_ref().unwrap(), "true");
+    assert!(results[2].is_err());
+    assert!(results[3].is_err());
+}
+
+#[test]
+fn handler_errors_are_raised_where_the_script_paused() {
+    let mut lua = Lua::new();
+    let debugger = Debugger::attach(&mut lua, |_| Err(looa::Error::Runtime("stop".into())));
+    debugger.pause();
+    let result = lua.load(b"x = 1", "m.lua").unwrap().call(Vec::new());
+    assert!(result.is_err());
+    lua.remove_hook();
+    assert_eq!(lua.eval("x").unwrap(), Value::nil());
+}
@@ -0,0 +1,123 @@
+//! Errors written out by `looa::diagnostic`, with their source and
+//! suggestions
+
+extern crate looa;
+
+use looa::{diagnostic, parser, Lua};
+
+fn syntax(source: &str) -> String {
+    let err = parser::parse_chunk(source.as_bytes(), "m.lua").unwrap_err();
+    diagnostic::syntax_error(&err, source.as_bytes(), "looa: ", false)
+}
+
+fn help(source: &str) -> Option<String> {
+    let err = parser::parse_chunk(source.as_bytes(), "m.lua").unwrap_err();
+    diagnostic::suggestion(&err, source.as_bytes())
+}
+
+#[test]
+fn labels_syntax_errors() {
+    assert_eq!(
+        syntax("local x = 1\nif x = 1 then end"),
+        "looa: m.lua:2:6: 'then' expected near '='\n\
+         2 | if x = 1 then end\n  \
+           |      ^ 'then' expected\n\
+         help: '=' assigns; compare with '=='\n"
+    );
+    // the block that wasn't closed is shown too
+    let source = "function f()\n".to_string() + &"\n".repeat(9) + "x = 1";
+    assert_eq!(
+        syntax(&source),
+        "looa: m.lua:11:6: 'end' expected (to close 'function' at line 1) near <eof>\n \
+         1 | function f()\n   \
+           | -------- this 'function'\n\
+         11 | x = 1\n   \
+            |      ^ 'end' expected\n"
+    );
+}
+
+#[test]
+fn suggests_fixes_for_common_mistakes() {
+    let cases = [
+        ("while x = 1 do end", "'=' assigns; compare with '=='"),
+        (
+            "if x == 1 print(x) end",
+            "a condition is followed by 'then'",
+        ),
+        (
+            "for i = 1, 2 print(i) end",
+            "the loop's body starts with 'do'",
+        ),
+        ("if x != 1 then end", "Lua writes 'not equal' as '~='"),
+        ("if !x then end", "Lua writes 'not' rather than '!'"),
+        ("x -= 1", "Lua has no '-='; write 'x = x - y'"),
+        (
+            "if a then else if b then end",
+            "'else if' starts an 'if' needing an 'end' of its own; did you mean 'elseif'?",
+        ),
+        (
+            "function f() return 1 x = 2 end",
+            "'return' can only be the last statement of a block",
+        ),
+        (
+            "t = {1 2}",
+            "separate the fields of a table with ',' or ';'",
+        ),
+        ("f(1, 2,)", "remove the trailing ','"),
+    ];
+    for &(source, expected) in &cases {
+        assert_eq!(help(source).as_deref(), Some(expected), "{}", source);
+    }
+    assert_eq!(help("x = = 1"), None);
+}
+
+#[test]
+fn colors_only_when_asked() {
+    let source = b"x = = 1";
+    let err = parser::parse_chunk(source, "m.lua").unwrap_err();
+    let plain = diagnostic::syntax_error(&err, source, "looa: ", false);
+    assert!(!plain.contains('\x1b'));
+    let colored = diagnostic::syntax_error(&err, source, "looa: ", true);
+    assert!(
+        colored.starts_with("\x1b[1;31mlooa: \x1b[0m\x1b[1mm.lua:1:5:"),
+        "{:?}",
+        colored
+    );
+    assert!(
+        colored.contains("\x1b[1;31m^ unexpected symbol\x1b[0m"),
+        "{:?}",
+        colored
+    );
+    // without the escapes it is the same
+    let mut stripped = String::new();
+    let mut rest = &*colored;
+    while let Some(at) = rest.find('\x1b') {
+        stripped.push_str(&rest[..at]);
+        rest = &rest[at + rest[at..].find('m').unwrap() + 1..];
+    }
+    stripped.push_str(rest);
+    assert_eq!(stripped, plain);
+}
+
+#[test]
+fn suggests_globals_for_misspelled_ones() {
+    let mut lua = Lua::new();
+    lua.set_global("counter", looa::Value::new(1)).unwrap();
+    let source = b"local t = {}\nprnt(countr)";
+    let err = lua
+        .load(source, "m.lua")
+        .and_then(|main| main.call(Vec::new()))
+        .unwrap_err();
+    let out = diagnostic::runtime_error(&lua, &err, Some(("m.lua", source)), "", "looa: ", false);
+    assert_eq!(
+        out,
+        "looa: m.lua:2: attempt to call a nil value (global 'prnt')\n\
+         2 | prnt(countr)\n  \
+           | ^^^^ is nil\n\
+         help: did you mean 'print'?\n"
+    );
+    // nothing is close to this, and it isn't in a script
+    let err = lua.exec("zzzzzz()").unwrap_err();
+    let out = diagnostic::runtime_error(&lua, &err, None, "", "", false);
+    assert_eq!(out, format!("{}\n", err));
+}
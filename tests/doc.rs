@@ -0,0 +1,130 @@
+//! Documentation extracted by `looa::doc` from doc comments
+
+extern crate looa;
+
+use looa::doc::{self, ModuleDoc};
+
+fn extract(src: &str) -> ModuleDoc {
+    doc::extract(src.as_bytes(), "lib/strings.lua").unwrap()
+}
+
+const STRINGS: &str = "\
+--- Helpers for strings. They work on bytes.
+local M = {}
+
+--- Wrap a string in brackets.
+-- The brackets can be changed.
+-- @tparam string s the string
+-- @param open the opening bracket,
+--   '<' by default
+-- @return the wrapped string
+-- @usage
+--   M.wrap('x')  --> '<x>'
+-- @see M.join
+function M.wrap(s, open, close) end
+
+--- Join strings with `sep`.
+function M.join(sep, ...) end
+
+--- Count the items.
+function M:count() end
+
+--- Not part of the interface
+local function helper() end
+
+-- not a doc comment
+function M.undocumented() end
+
+return M
+";
+
+#[test]
+fn documents_functions_from_their_definitions() {
+    let doc = extract(STRINGS);
+    assert_eq!(doc.name, "strings");
+    assert_eq!(doc.summary, "Helpers for strings.");
+    assert_eq!(doc.description, "They work on bytes.");
+    let names: Vec<&str> = doc.functions.iter().map(|f| &*f.name).collect();
+    assert_eq!(names, ["M.wrap", "M.join", "M:count", "helper"]);
+    assert!(doc.functions[3].local);
+    let wrap = &doc.functions[0];
+    assert_eq!(wrap.summary, "Wrap a string in brackets.");
+    assert_eq!(wrap.description, "The brackets can be changed.");
+    assert_eq!(wrap.signature(), "M.wrap(s, open, close)");
+    let params: Vec<(&str, Option<&str>, &str)> = wrap
+        .params
+        .iter()
+        .map(|p| (&*p.name, p.ty.as_deref(), &*p.description))
+        .collect();
+    assert_eq!(
+        params,
+        [
+            ("s", Some("string"), "the string"),
+            ("open", None, "the opening bracket, '<' by default"),
+            ("close", None, "")
+        ]
+    );
+    assert_eq!(wrap.returns[0].description, "the wrapped string");
+    assert_eq!(wrap.usage.as_deref(), Some("M.wrap('x')  --> '<x>'"));
+    assert_eq!(wrap.see, ["M.join"]);
+    assert_eq!(doc.functions[1].signature(), "M.join(sep, ...)");
+    // `self` is implied by the colon
+    assert_eq!(doc.functions[2].signature(), "M:count()");
+    assert!(doc.warnings.is_empty(), "{:?}", doc.warnings);
+}
+
+#[test]
+fn documents_tables_of_functions() {
+    let src = "\
+--- Shapes.
+-- @module geometry.shapes
+return {
+  --- The area of a circle.
+  -- @treturn number the area
+  area = function(r) end,
+  other = function() end,
+}
+";
+    let doc = extract(src);
+    assert_eq!(doc.name, "geometry.shapes");
+    assert_eq!(doc.functions.len(), 1);
+    assert_eq!(doc.functions[0].name, "shapes.area");
+    assert_eq!(doc.functions[0].returns[0].ty.as_deref(), Some("number"));
+    let src = "local M = {\n  --[[-- Say hello.\n  @param name who to greet]]\n  hello = function(name) end,\n}\n";
+    let doc = extract(src);
+    assert_eq!(doc.functions[0].name, "M.hello");
+    assert_eq!(doc.functions[0].summary, "Say hello.");
+    assert_eq!(doc.functions[0].params[0].description, "who to greet");
+}
+
+#[test]
+fn warns_of_misused_tags() {
+    let doc = extract("--- F.\n-- @param x no such\n-- @frob\nfunction f(a) end");
+    assert_eq!(
+        doc.warnings,
+        [
+            "lib/strings.lua:3: unknown tag '@frob'",
+            "lib/strings.lua:1: 'x' is not a parameter of 'f'"
+        ]
+    );
+}
+
+#[test]
+fn renders_markdown_and_html() {
+    let doc = extract(STRINGS);
+    let markdown = doc.markdown();
+    assert!(markdown.starts_with("# Module `strings`\n\nHelpers for strings.\n\n"));
+    assert!(markdown.contains("\n- `M.wrap(s, open, close)`: Wrap a string in brackets.\n"));
+    assert!(markdown.contains("\n### `M.join(sep, ...)`\n\nJoin strings with `sep`.\n"));
+    assert!(markdown.contains("\n- `s` (`string`): the string\n"));
+    assert!(markdown.contains("\n```lua\nM.wrap('x')  --> '<x>'\n```\n"));
+    let html = doc.html();
+    assert!(html.contains("<h3 id=\"M.wrap\"><code>M.wrap(s, open, close)</code></h3>"));
+    assert!(html.contains("<pre><code>M.wrap('x')  --&gt; '&lt;x&gt;'</code></pre>"));
+    assert!(html.ends_with("</html>\n"));
+    let index = doc::markdown_index(&[doc]);
+    assert_eq!(
+        index,
+        "# Modules\n\n- [`strings`](strings.md): Helpers for strings.\n"
+    );
+}
@@ -0,0 +1,82 @@
+//! Formatting with `looa::format`, which has to keep what code does and
+//! its comments, and leave formatted code as it is
+
+extern crate looa;
+
+use std::fs;
+use std::path::Path;
+
+use looa::format::{self, Quotes, Style};
+use looa::Lua;
+
+fn format(src: &str, style: &Style) -> String {
+    let formatted = format::format(src.as_bytes(), "test", style).unwrap();
+    String::from_utf8(formatted).unwrap()
+}
+
+#[test]
+fn lays_out_statements() {
+    let src = "local  x=1\nif x then print( \"a\" ) end\nfunction f(a,b) return a+b end\n";
+    assert_eq!(
+        format(src, &Style::default()),
+        "local x = 1\nif x then\n    print(\"a\")\nend\nfunction f(a, b)\n    return a + b\nend\n"
+    );
+}
+
+#[test]
+fn keeps_comments() {
+    let src = "local x = 1 -- one\n--[[ block ]]\n-- before\nreturn x\n";
+    assert_eq!(format(src, &Style::default()), src);
+}
+
+#[test]
+fn follows_the_style() {
+    let style = Style {
+        indent: "  ".to_string(),
+        quotes: Quotes::Single,
+        ..Style::default()
+    };
+    let src = "if x then print(\"a\", 'b') end\n";
+    assert_eq!(format(src, &style), "if x then\n  print('a', 'b')\nend\n");
+    // the other quotes are kept where they take fewer escapes
+    assert_eq!(format("x = \"it's\"\n", &style), "x = \"it's\"\n");
+}
+
+#[test]
+fn breaks_long_lines() {
+    let style = Style {
+        width: 30,
+        ..Style::default()
+    };
+    let src = "call(argument_one, argument_two, argument_three)\n";
+    let formatted = format(src, &style);
+    assert!(
+        formatted.lines().all(|line| line.len() <= 30),
+        "{}",
+        formatted
+    );
+    assert_eq!(format(&formatted, &style), formatted);
+}
+
+#[test]
+fn reports_syntax_errors() {
+    assert!(format::format(b"local = 1", "test", &Style::default()).is_err());
+}
+
+/// Formatting the test scripts again changes nothing, and they still pass
+#[test]
+fn round_trips_the_test_scripts() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    for entry in fs::read_dir(dir).unwrap() {
+        let path = entry.unwrap().path();
+        let src = fs::read_to_string(&path).unwrap();
+        let style = Style::default();
+        let once = format(&src, &style);
+        assert_eq!(format(&once, &style), once, "{}", path.display());
+        // the scripts use a helper the script tests define
+        let mut lua = Lua::new();
+        lua.exec("function contains() return true end").unwrap();
+        lua.exec(&once)
+            .unwrap_or_else(|err| panic!("{}: {}", path.display(), err));
+    }
+}
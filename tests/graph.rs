@@ -0,0 +1,185 @@
+//! The modules `looa::graph` finds by following requires, and the calls
+//! between their functions
+
+extern crate looa;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use looa::graph::Graph;
+
+/// A directory of its own for `test` with the files `files` in it, given
+/// as paths within it and their sources
+fn project(test: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("looa-graph-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    for &(path, source) in files {
+        let path = dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, source).unwrap();
+    }
+    dir
+}
+
+fn graph(dir: &Path) -> Graph {
+    let dir = dir.to_str().unwrap();
+    Graph::new(&format!("{0}/?.lua;{0}/?/init.lua", dir))
+}
+
+#[test]
+fn follows_requires_of_string_constants() {
+    let dir = project(
+        "requires",
+        &[
+            (
+                "main.lua",
+                "local a = require 'a'\nrequire('b.c')\nrequire(x)",
+            ),
+            ("a/init.lua", "require 'b.c'\nrequire 'missing'"),
+            ("b/c.lua", "require 'a'"),
+        ],
+    );
+    let mut graph = graph(&dir);
+    let main = graph.add(&dir.join("main.lua"));
+    let modules = graph.modules();
+    let names: Vec<&str> = modules.iter().map(|m| &*m.name).collect();
+    assert_eq!(names, ["main", "a", "b.c"]);
+    let requires = |m: usize| -> Vec<(&str, Option<usize>)> {
+        modules[m]
+            .requires
+            .iter()
+            .map(|r| (&*r.name, r.module))
+            .collect()
+    };
+    assert_eq!(requires(main), [("a", Some(1)), ("b.c", Some(2))]);
+    // a cycle ends at the module already added
+    assert_eq!(requires(1), [("b.c", Some(2)), ("missing", None)]);
+    assert_eq!(requires(2), [("a", Some(1))]);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn finds_unused_modules() {
+    let dir = project(
+        "unused",
+        &[
+            ("main.lua", "require 'used'"),
+            ("used.lua", ""),
+            ("dead.lua", "require 'used'"),
+        ],
+    );
+    let mut graph = graph(&dir);
+    let main = graph.add(&dir.join("main.lua"));
+    for file in &["used.lua", "dead.lua", "main.lua"] {
+        graph.add(&dir.join(file));
+    }
+    assert_eq!(graph.modules().len(), 3);
+    let unused: Vec<&str> = graph
+        .unused(&[main])
+        .into_iter()
+        .map(|m| &*graph.modules()[m].name)
+        .collect();
+    assert_eq!(unused, ["dead"]);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn calls_are_found_by_name() {
+    let dir = project(
+        "calls",
+        &[
+            (
+                "main.lua",
+                "local util = require 'util'\n\
+                 local function go() util.greet(); util:greet(); print() end\n\
+                 go()",
+            ),
+            (
+                "util.lua",
+                "local M = {}\n\
+                 function M.greet() M.helper() end\n\
+                 M.helper = function() end\n\
+                 return M",
+            ),
+        ],
+    );
+    let mut graph = graph(&dir);
+    graph.add(&dir.join("main.lua"));
+    let modules = graph.modules();
+    let functions: Vec<&str> = modules[1].functions.iter().map(|f| &*f.name).collect();
+    assert_eq!(functions, ["", "M.greet", "M.helper"]);
+    let calls = |m: usize, f: usize| -> Vec<(&str, Option<(usize, usize)>)> {
+        modules[m].functions[f]
+            .calls
+            .iter()
+            .map(|c| (&*c.name, c.target))
+            .collect()
+    };
+    assert_eq!(calls(0, 0), [("go", Some((0, 1)))]);
+    assert_eq!(
+        calls(0, 1),
+        [
+            ("util.greet", Some((1, 1))),
+            ("util:greet", Some((1, 1))),
+            ("print", None)
+        ]
+    );
+    assert_eq!(calls(1, 1), [("M.helper", Some((1, 2)))]);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn names_the_fields_of_returned_tables() {
+    let dir = project(
+        "returned",
+        &[
+            ("main.lua", "local s = require 'lib.strings'\ns.wrap()"),
+            ("lib/strings.lua", "return {wrap = function() end}"),
+        ],
+    );
+    let mut graph = graph(&dir);
+    graph.add(&dir.join("main.lua"));
+    let modules = graph.modules();
+    assert_eq!(modules[1].functions[1].name, "strings.wrap");
+    assert_eq!(modules[0].functions[0].calls[0].target, Some((1, 1)));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn writes_dot_and_json() {
+    let dir = project(
+        "output",
+        &[
+            ("main.lua", "require 'a'\nrequire 'gone'"),
+            ("a.lua", "local function f() end\nf()"),
+            ("broken.lua", "local = 1"),
+        ],
+    );
+    let mut graph = graph(&dir);
+    graph.add(&dir.join("main.lua"));
+    let broken = graph.add(&dir.join("broken.lua"));
+    assert!(graph.modules()[broken].error.is_some());
+    let dot = graph.dot(false);
+    assert!(dot.starts_with("digraph modules {\n"), "{}", dot);
+    assert!(dot.contains("\n    \"main\" -> \"a\";\n"), "{}", dot);
+    assert!(
+        dot.contains("\n    \"gone\" [style = dashed];\n"),
+        "{}",
+        dot
+    );
+    let dot = graph.dot(true);
+    assert!(dot.contains("\n    \"a:\" -> \"a:f\";\n"), "{}", dot);
+    assert!(
+        dot.contains("\n    \"main:\" -> \"a:\" [style = dashed];\n"),
+        "{}",
+        dot
+    );
+    let json = graph.json(true);
+    assert!(json.contains("{\"name\": \"gone\", \"line\": 2, \"path\": null}"));
+    assert!(json.contains(
+        "{\"name\": \"f\", \"line\": 2, \"function\": {\"module\": \"a\", \"name\": \"f\"}}"
+    ));
+    assert!(json.contains("\"name\": \"broken\""));
+    assert!(!graph.json(false).contains("\"functions\""));
+    let _ = fs::remove_dir_all(&dir);
+}
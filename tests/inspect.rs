@@ -0,0 +1,70 @@
+//! Values written out by `looa::inspect`, with the contents of tables
+
+extern crate looa;
+
+use looa::inspect::inspect;
+use looa::Lua;
+
+fn show(source: &str, depth: usize) -> String {
+    let mut lua = Lua::new();
+    let val = lua.eval(source).unwrap();
+    inspect(&val, depth).unwrap()
+}
+
+#[test]
+fn writes_plain_values() {
+    assert_eq!(show("nil", 3), "nil");
+    assert_eq!(show("1.5", 3), "1.5");
+    assert_eq!(
+        show("'say \"hi\"\\n\\1\\0012'", 3),
+        "\"say \\\"hi\\\"\\n\\001\\0012\""
+    );
+    assert!(show("print", 3).starts_with("function: 0x"));
+}
+
+#[test]
+fn writes_the_sequence_and_then_sorted_keys() {
+    assert_eq!(show("{}", 3), "{}");
+    assert_eq!(
+        show("{3, 2, 1, b = 1, a = 2, [10] = 'x', [-1] = 0}", 3),
+        "{ 3, 2, 1, [-1] = 0, [10] = \"x\", a = 2, b = 1 }"
+    );
+    assert_eq!(
+        show("{[true] = 1, ['a b'] = 1, ['end'] = 1, [1.5] = 1}", 3),
+        "{ [1.5] = 1, [\"a b\"] = 1, [\"end\"] = 1, [true] = 1 }"
+    );
+}
+
+#[test]
+fn breaks_long_tables_over_lines() {
+    let source = "{ name = 'a fairly long name', items = { 'one', 'two', 'three' }, \
+                  more = { 'another rather long string', 'and one more' } }";
+    assert_eq!(
+        show(source, 3),
+        "{\n  items = { \"one\", \"two\", \"three\" },\n  \
+         more = { \"another rather long string\", \"and one more\" },\n  \
+         name = \"a fairly long name\",\n}"
+    );
+}
+
+#[test]
+fn limits_depth_and_notes_cycles_and_metatables() {
+    let mut lua = Lua::new();
+    lua.exec("t = { inner = { deeper = { 1 } } } t.inner.up = t setmetatable(t, { __index = {} })")
+        .unwrap();
+    let t = lua.get_global("t").unwrap();
+    assert_eq!(
+        inspect(&t, 3).unwrap(),
+        "{\n  inner = { deeper = { 1 }, up = <cycle> },\n  <metatable> = { __index = {} },\n}"
+    );
+    assert_eq!(
+        inspect(&t, 1).unwrap(),
+        "{ inner = {...}, <metatable> = {...} }"
+    );
+    assert_eq!(inspect(&t, 0).unwrap(), "{...}");
+    // a table seen twice but not within itself is written both times
+    assert_eq!(
+        show("(function() local s = {1} return {s, s} end)()", 3),
+        "{ { 1 }, { 1 } }"
+    );
+}
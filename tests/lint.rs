@@ -0,0 +1,78 @@
+//! The rules of `looa::lint`, and the comments that turn them off
+
+extern crate looa;
+
+use std::collections::HashSet;
+
+use looa::lint::{self, Rule};
+
+/// The rule and line of each warning for `src`, taking `print` as the only
+/// global defined
+fn lint(src: &str) -> Vec<(Option<Rule>, u32)> {
+    let globals: HashSet<String> = vec!["print".to_string()].into_iter().collect();
+    lint::lint(src.as_bytes(), "test", &globals)
+        .unwrap()
+        .into_iter()
+        .map(|warning| (warning.rule, warning.loc.pos.line))
+        .collect()
+}
+
+#[test]
+fn undefined_global() {
+    assert_eq!(lint("print(x)"), [(Some(Rule::UndefinedGlobal), 1)]);
+    // assigning a global anywhere defines it
+    assert_eq!(lint("print(x)\nx = 1"), []);
+}
+
+#[test]
+fn unused_local() {
+    assert_eq!(lint("local x = 1"), [(Some(Rule::UnusedLocal), 1)]);
+    assert_eq!(lint("local x = 1\nprint(x)"), []);
+    // an underscore marks a local as unused on purpose
+    assert_eq!(lint("local _ = 1"), []);
+}
+
+#[test]
+fn shadowed_local() {
+    let warnings = lint("local x = 1\nprint(x)\nlocal x = 2\nprint(x)");
+    assert_eq!(warnings, [(Some(Rule::ShadowedLocal), 3)]);
+}
+
+#[test]
+fn unreachable_code() {
+    let warnings = lint("local function f()\n    do return end\n    print(1)\nend\nf()");
+    assert_eq!(warnings, [(Some(Rule::UnreachableCode), 3)]);
+}
+
+#[test]
+fn empty_if() {
+    assert_eq!(
+        lint("local x = 1\nif x then end"),
+        [(Some(Rule::EmptyIf), 2)]
+    );
+}
+
+#[test]
+fn comments_turn_rules_off() {
+    assert_eq!(lint("print(x) -- lint: ignore"), []);
+    assert_eq!(lint("-- lint: ignore undefined-global\nprint(x)"), []);
+    assert_eq!(
+        lint("-- lint: ignore unused-local\nprint(x)"),
+        [(Some(Rule::UndefinedGlobal), 2)]
+    );
+    let src = "-- lint: disable\nprint(x)\nlocal y = 1\n-- lint: enable\nprint(z)";
+    assert_eq!(lint(src), [(Some(Rule::UndefinedGlobal), 5)]);
+}
+
+#[test]
+fn unreadable_comments_are_reported() {
+    assert_eq!(lint("-- lint: ignore no-such-rule\nprint(1)"), [(None, 1)]);
+}
+
+#[test]
+fn rule_names() {
+    for &rule in Rule::ALL.iter() {
+        assert_eq!(Rule::from_name(rule.name()), Some(rule));
+    }
+    assert_eq!(Rule::from_name("nonsense"), None);
+}
@@ -0,0 +1,110 @@
+extern crate looa;
+
+use looa::profile::{Frame, Profile, Profiler};
+use looa::{Backend, Lua};
+
+const SOURCE: &str = "\
+local function leaf(n)
+  local s = 0
+  for i = 1, n do s = s + i end
+  return s
+end
+local function outer()
+  return leaf(5000) + leaf(5000)
+end
+local function fails()
+  for i = 1, 3000 do end
+  error('stop')
+end
+outer()
+pcall(fails)
+leaf(3000)
+";
+
+fn profile(backend: Backend) -> Profile {
+    let mut lua = Lua::new();
+    lua.set_backend(backend);
+    let main = lua.load(SOURCE.as_bytes(), "p.lua").unwrap();
+    let mut profiler = Profiler::new();
+    profiler.set_interval(100);
+    let (profile, result) = profiler.run(&mut lua, &main, Vec::new());
+    result.unwrap();
+    profile
+}
+
+fn frame(line: u32) -> Frame {
+    Frame {
+        chunk: "p.lua".into(),
+        line,
+    }
+}
+
+#[test]
+fn samples_the_stack_of_functions() {
+    for &backend in &[Backend::Interpreter, Backend::Vm] {
+        let profile = profile(backend);
+        let stacks = profile.stacks();
+        let seen: Vec<&[Frame]> = stacks.iter().map(|&(stack, _)| stack).collect();
+        assert!(
+            seen.contains(&&[frame(0), frame(6), frame(1)][..]),
+            "{:?}",
+            seen
+        );
+        assert!(seen.contains(&&[frame(0), frame(9)][..]), "{:?}", seen);
+        // the function the error left is gone by the time leaf runs again
+        assert!(seen.contains(&&[frame(0), frame(1)][..]), "{:?}", seen);
+        assert!(seen
+            .iter()
+            .all(|stack| stack[0] == frame(0) && stack.len() <= 3));
+        let functions = profile.functions();
+        assert_eq!(functions[0].frame, frame(1), "{:?}", functions);
+        let main = functions.iter().find(|f| f.frame == frame(0)).unwrap();
+        assert_eq!(main.total, profile.samples());
+        let outer = functions.iter().find(|f| f.frame == frame(6)).unwrap();
+        assert!(outer.own < outer.total, "{:?}", outer);
+        // leaf runs 13000 iterations and fails 3000
+        let leaf = functions[0].own as f64 / profile.samples() as f64;
+        assert!(
+            0.6 < leaf && leaf < 0.9,
+            "{:?} of {}",
+            functions,
+            profile.samples()
+        );
+    }
+}
+
+#[test]
+fn writes_folded_stacks() {
+    let profile = profile(Backend::Vm);
+    let folded = profile.folded(&|frame| match frame.line {
+        1 => "leaf;1".to_string(),
+        _ => frame.to_string(),
+    });
+    let lines: Vec<&str> = folded.lines().collect();
+    assert_eq!(lines.len(), profile.stacks().len());
+    let total: u64 = lines
+        .iter()
+        .map(|line| line.rsplit(' ').next().unwrap().parse::<u64>().unwrap())
+        .sum();
+    assert_eq!(total, profile.samples());
+    assert!(lines
+        .iter()
+        .any(|line| line.starts_with("p.lua;p.lua:6;leaf,1 ")));
+    assert!(lines.iter().any(|line| line.starts_with("p.lua;p.lua:9 ")));
+}
+
+#[test]
+fn keeps_the_profile_of_a_call_that_fails() {
+    let mut lua = Lua::new();
+    let main = lua
+        .load(b"for i = 1, 1000 do end error('late')", "f.lua")
+        .unwrap();
+    let mut profiler = Profiler::new();
+    profiler.set_interval(0);
+    assert_eq!(profiler.interval(), 1);
+    let (profile, result) = profiler.run(&mut lua, &main, Vec::new());
+    assert!(result.unwrap_err().to_string().contains("late"));
+    assert!(profile.samples() > 1000);
+    assert_eq!(Profile::default().samples(), 0);
+    assert!(Profile::default().functions().is_empty());
+}
@@ -0,0 +1,77 @@
+//! Runs the Lua scripts in `tests/scripts` with each backend, where a
+//! script passes if it runs without raising an error
+
+extern crate looa;
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use looa::{Backend, ConvertValue, Lua, LuaString, Value};
+
+/// The scripts to run, in order of name so failures are listed the same
+/// way every time
+fn scripts() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/scripts");
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("the scripts are there")
+        .map(|entry| entry.expect("the scripts can be listed").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lua"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+/// A state for running the scripts, with the Rust helpers they use
+fn state(backend: Backend, optimize: bool) -> Lua {
+    let mut lua = Lua::new();
+    lua.set_backend(backend);
+    lua.set_optimize(optimize);
+    // there is no string library to search with
+    let contains = Value::function(|args: &[Value]| {
+        let bytes = |n: usize| {
+            args.get(n)
+                .and_then(LuaString::from_value)
+                .map(|s| s.to_vec())
+                .unwrap_or_default()
+        };
+        let (haystack, needle) = (bytes(0), bytes(1));
+        let found = needle.is_empty()
+            || haystack
+                .windows(needle.len())
+                .any(|window| window == &needle[..]);
+        Ok(found.into_value())
+    });
+    lua.set_global("contains", contains).unwrap();
+    lua
+}
+
+fn run_all(backend: Backend, optimize: bool) {
+    let mut failed = Vec::new();
+    for path in scripts() {
+        let lua = state(backend, optimize);
+        let name = format!("scripts/{}", path.file_name().unwrap().to_string_lossy());
+        let source = fs::read(&path).unwrap();
+        let result = lua
+            .load(&source, &name)
+            .and_then(|chunk| chunk.call(Vec::new()));
+        if let Err(err) = result {
+            failed.push(format!("{}\n{}", err, lua.traceback()));
+        }
+    }
+    assert!(failed.is_empty(), "\n{}", failed.join("\n\n"));
+}
+
+#[test]
+fn interpreter() {
+    run_all(Backend::Interpreter, true);
+}
+
+#[test]
+fn vm() {
+    run_all(Backend::Vm, true);
+}
+
+#[test]
+fn vm_unoptimized() {
+    run_all(Backend::Vm, false);
+}
@@ -0,0 +1,55 @@
+-- integer and float arithmetic, and conversions between them
+
+assert(1 + 2 == 3)
+assert(7 // 2 == 3 and -7 // 2 == -4)
+assert(7 % 3 == 1 and -7 % 3 == 2 and 7 % -3 == -2)
+assert(7 / 2 == 3.5 and 4 / 2 == 2.0)
+assert(2 ^ 10 == 1024.0)
+assert(tostring(10 / 2) == "5.0")
+assert(tostring(3) == "3")
+assert(tostring(1e15) == "1e+15")
+assert(tostring(0.1) == "0.1")
+assert(tostring(-0.0) == "-0.0")
+assert(1 == 1.0 and "1" ~= 1)
+
+-- integers wrap around on overflow
+local max = 9223372036854775807
+assert(max + 1 == -max - 1)
+assert(-(-max - 1) == -max - 1)
+
+-- bitwise operators work on integers and floats with integral values
+assert(5 & 3 == 1 and 5 | 3 == 7 and 5 ~ 3 == 6 and ~0 == -1)
+assert(1 << 62 == 4611686018427387904 and 1 << 64 == 0 and -1 >> 63 == 1)
+assert(3.0 | 0 == 3)
+assert(not pcall(function() return 1.5 | 0 end))
+
+-- strings are converted to numbers for arithmetic
+assert("10" + 1 == 11 and "0x10" + 0 == 16 and "1e1" * 1 == 10.0)
+assert(tonumber("  12  ") == 12 and tonumber("z", 36) == 35 and tonumber("ff", 16) == 255)
+assert(tonumber("1e") == nil and tonumber("") == nil)
+
+local ok, err = pcall(function() return 1 // 0 end)
+assert(not ok and contains(err, "attempt to perform 'n//0'"))
+ok, err = pcall(function() return 1 % 0 end)
+assert(not ok and contains(err, "attempt to perform 'n%0'"))
+assert(1 // 0.0 == 1 / 0 and -1 // 0.0 == -1 / 0)
+
+local ok, err = pcall(function() return {} + 1 end)
+assert(not ok and contains(err, "attempt to perform arithmetic on a table value"))
+
+-- comparisons
+assert(1 < 2 and 1 <= 1.0 and "a" < "b" and "a" < "aa" and not ("b" < "a"))
+assert(not pcall(function() return 1 < "2" end))
+
+-- numeric for loops count in integers unless given floats
+local n = 0
+for i = 1, 10 do n = n + i end
+assert(n == 55)
+local last
+for i = 1, 2, 0.5 do last = i end
+assert(last == 2.0 and tostring(last) == "2.0")
+for i = 10, 1 do error("never runs") end
+n = 0
+for i = max - 2, max do n = n + 1 end
+assert(n == 3)
+assert(not pcall(function() for i = 1, 10, 0 do end end))
@@ -0,0 +1,41 @@
+-- chains of concatenations join strings and numbers at once
+local a, b = "x", 2
+assert(a .. b .. "y" .. 1.5 .. a == "x2y1.5x")
+assert("" .. "" .. "" == "")
+local n = 0
+for i = 1, 10 do
+    n = n + #(a .. i .. a)
+end
+assert(n == 31)
+
+-- `__concat` is called from the right, once the strings after it are joined
+local log = {}
+local mt = {__concat = function(l, r)
+    local name = function(v) return type(v) == "table" and v.name or v end
+    log[#log + 1] = name(l) .. "|" .. name(r)
+    return setmetatable({name = name(l) .. name(r)}, getmetatable(l) or getmetatable(r))
+end}
+local t = setmetatable({name = "T"}, mt)
+assert(("a" .. "b" .. t .. "c" .. "d").name == "abTcd")
+assert(#log == 3 and log[1] == "T|cd" and log[2] == "b|Tcd" and log[3] == "a|bTcd")
+
+local ok, err = pcall(function() return "a" .. "b" .. {} .. "c" end)
+assert(not ok and contains(err, "attempt to concatenate a table value"))
+ok, err = pcall(function() return "a" .. nil .. "c" end)
+assert(not ok and contains(err, "attempt to concatenate a nil value"))
+
+-- buffers grow in place
+local buf = buffer.new()
+for i = 1, 100 do
+    buf:put("line ", i, "\n")
+end
+assert(#buf == 100 * 6 + 9 * 1 + 90 * 2 + 3)
+local text = buf:tostring()
+assert(tostring(buf) == text and contains(text, "line 42\n"))
+assert(buf:get() == text and #buf == 0)
+buf:put("again")
+buf:reset()
+assert(buf:get() == "")
+ok, err = pcall(buf.put, buf, "x", {})
+assert(not ok and contains(err, "bad argument #3 to 'put' (string expected, got table)"))
+assert(type(buf) == "userdata")
@@ -0,0 +1,79 @@
+-- closures, upvalues and scoping
+
+local function counter()
+    local n = 0
+    return function()
+        n = n + 1
+        return n
+    end
+end
+local c1, c2 = counter(), counter()
+assert(c1() == 1 and c1() == 2 and c2() == 1)
+
+-- each iteration of a loop has a fresh variable
+local fs = {}
+for i = 1, 3 do fs[i] = function() return i end end
+assert(fs[1]() == 1 and fs[2]() == 2 and fs[3]() == 3)
+fs = {}
+for _, v in ipairs({"a", "b"}) do fs[#fs + 1] = function() return v end end
+assert(fs[1]() == "a" and fs[2]() == "b")
+local i = 1
+fs = {}
+while i <= 2 do
+    local j = i
+    fs[i] = function() return j end
+    i = i + 1
+end
+assert(fs[1]() == 1 and fs[2]() == 2)
+
+-- closures share the variable, not its value
+local x = 1
+local function get() return x end
+local function set(v) x = v end
+set(5)
+assert(get() == 5 and x == 5)
+
+-- nested closures capture through the functions between them
+local function outer()
+    local a = "a"
+    return function()
+        return function() return a end
+    end
+end
+assert(outer()()() == "a")
+
+-- shadowed locals are separate variables
+local s = 1
+local f = function() return s end
+local s = 2
+assert(f() == 1 and s == 2)
+
+-- recursive local functions see themselves
+local function fact(n)
+    if n <= 1 then
+        return 1
+    end
+    return n * fact(n - 1)
+end
+assert(fact(10) == 3628800)
+
+-- varargs
+local function pack(...) return select("#", ...), ... end
+assert(pack() == 0)
+assert(pack(nil, nil) == 2)
+local function tail(...) return ... end
+assert(select("#", tail(1, 2, 3)) == 3)
+local function adjust(...)
+    local a, b = ...
+    return b
+end
+assert(adjust(1, 2, 3) == 2)
+
+-- deep tail calls take no space
+local function loop(n)
+    if n == 0 then
+        return "done"
+    end
+    return loop(n - 1)
+end
+assert(loop(100000) == "done")
@@ -0,0 +1,59 @@
+-- control flow: if, loops, break, goto and multiple assignment
+
+local function sign(n)
+    if n < 0 then
+        return -1
+    elseif n == 0 then
+        return 0
+    else
+        return 1
+    end
+end
+assert(sign(-5) == -1 and sign(0) == 0 and sign(3) == 1)
+
+local n = 0
+while true do
+    n = n + 1
+    if n == 5 then break end
+end
+assert(n == 5)
+
+-- the condition of repeat sees the body's locals
+n = 0
+repeat
+    local done = n >= 3
+    n = n + 1
+until done
+assert(n == 4)
+
+-- goto, including continue-style jumps
+local odd = 0
+for i = 1, 10 do
+    if i % 2 == 0 then goto continue end
+    odd = odd + 1
+    ::continue::
+end
+assert(odd == 5)
+do
+    local k = 0
+    ::top::
+    k = k + 1
+    if k < 3 then goto top end
+    assert(k == 3)
+end
+
+-- assignments evaluate every expression before assigning
+local a, b = 1, 2
+a, b = b, a
+assert(a == 2 and b == 1)
+local t = {}
+local i = 1
+i, t[i] = i + 1, 20
+assert(i == 2 and t[1] == 20)
+local x, y, z = 1
+assert(x == 1 and y == nil and z == nil)
+
+-- and/or short-circuit and give operands
+assert((nil or 1) == 1 and (false and error("no")) == false)
+assert((1 and 2) == 2 and (nil and 1) == nil)
+assert(not nil and not false and not not 0)
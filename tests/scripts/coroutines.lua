@@ -0,0 +1,105 @@
+-- coroutines
+
+local co = coroutine.create(function(a, b)
+    assert(coroutine.isyieldable())
+    local c = coroutine.yield(a + b)
+    local d, e = coroutine.yield(c * 2)
+    return d + e
+end)
+assert(coroutine.status(co) == "suspended")
+local ok, v = coroutine.resume(co, 1, 2)
+assert(ok and v == 3)
+ok, v = coroutine.resume(co, 10)
+assert(ok and v == 20)
+ok, v = coroutine.resume(co, 3, 4)
+assert(ok and v == 7 and coroutine.status(co) == "dead")
+ok, v = coroutine.resume(co)
+assert(not ok and v == "cannot resume dead coroutine")
+assert(not coroutine.isyieldable())
+
+-- generators with wrap
+local function range(n)
+    return coroutine.wrap(function()
+        for i = 1, n do coroutine.yield(i) end
+    end)
+end
+local sum = 0
+for i in range(10) do sum = sum + i end
+assert(sum == 55)
+
+-- errors end the coroutine
+co = coroutine.create(function() error("fail", 0) end)
+ok, v = coroutine.resume(co)
+assert(not ok and v == "fail" and coroutine.status(co) == "dead")
+
+-- running and status from inside
+local inner
+co = coroutine.create(function()
+    inner = coroutine.running()
+    assert(coroutine.status(inner) == "running")
+end)
+coroutine.resume(co)
+assert(inner == co)
+local main, ismain = coroutine.running()
+assert(ismain)
+
+-- yielding across nested Lua calls
+co = coroutine.wrap(function()
+    local function deep(n)
+        if n == 0 then
+            return coroutine.yield("bottom")
+        end
+        return deep(n - 1)
+    end
+    return deep(100)
+end)
+assert(co() == "bottom" and co("back") == "back")
+
+-- a suspended coroutine can be closed
+co = coroutine.create(function() coroutine.yield() end)
+coroutine.resume(co)
+assert(coroutine.close(co) and coroutine.status(co) == "dead")
+
+-- a coroutine resuming itself fails
+co = coroutine.create(function() return coroutine.resume(coroutine.running()) end)
+local _, ok2, msg = coroutine.resume(co)
+assert(not ok2 and msg == "cannot resume non-suspended coroutine")
+
+-- yielding inside pcall and xpcall, which still catch errors once resumed
+co = coroutine.wrap(function(a)
+    local ok3, x, y = pcall(function(b)
+        return coroutine.yield(b), "second"
+    end, a)
+    assert(ok3 and x == "x" and y == "second")
+    local ok4, err = pcall(function()
+        coroutine.yield("again")
+        error("boom", 0)
+    end)
+    assert(not ok4 and err == "boom")
+    local ok5, handled = xpcall(function(c)
+        coroutine.yield(c)
+        error({})
+    end, function(e)
+        return type(e)
+    end, "handler")
+    assert(not ok5 and handled == "table")
+    return "caught"
+end)
+assert(co(1) == 1 and co("x") == "again" and co() == "handler" and co() == "caught")
+
+-- yielding inside __index and __newindex functions
+local proxy = setmetatable({}, {
+    __index = function(_, k)
+        return coroutine.yield("get " .. k)
+    end,
+    __newindex = function(t, k, v)
+        coroutine.yield("set " .. k)
+        rawset(t, k, v)
+    end,
+})
+co = coroutine.wrap(function()
+    local v = proxy.field
+    proxy.other = v + 1
+    return rawget(proxy, "other")
+end)
+assert(co() == "get field" and co(41) == "set other" and co() == 42)
@@ -0,0 +1,54 @@
+-- raising and catching errors
+
+local ok, err = pcall(error, "msg")
+assert(not ok and err == "msg")
+ok, err = pcall(error, "msg", 0)
+assert(err == "msg")
+ok, err = pcall(function() error("here") end)
+assert(contains(err, ": here"))
+ok, err = pcall(function() error({code = 1}) end)
+assert(type(err) == "table" and err.code == 1)
+ok, err = pcall(function() error() end)
+assert(not ok and err == nil)
+
+-- errors raised by the runtime name what went wrong
+ok, err = pcall(function() local t = nil; return t.x end)
+assert(contains(err, "attempt to index a nil value (local 't')"))
+ok, err = pcall(function() return undefined_global() end)
+assert(contains(err, "attempt to call a nil value (global 'undefined_global')"))
+ok, err = pcall(function() local t = {} return t.field.x end)
+assert(contains(err, "attempt to index a nil value (field 'field')"))
+
+-- pcall gives every result on success
+local a, b, c = pcall(function() return 1, 2 end)
+assert(a and b == 1 and c == 2)
+
+-- xpcall runs the handler with the error
+ok, err = xpcall(function() error("oops", 0) end, function(e) return "handled " .. e end)
+assert(not ok and err == "handled oops")
+assert(select("#", xpcall(function(...) return ... end, print, 1, 2)) == 3)
+
+-- assert gives its message, or its other arguments when it passes
+ok, err = pcall(assert, false, "custom")
+assert(err == "custom")
+ok, err = pcall(assert, nil)
+assert(err == "assertion failed!")
+assert(select("#", assert(1, 2, 3)) == 3)
+
+-- errors in metamethods pass through
+ok, err = pcall(function()
+    return setmetatable({}, {__index = function() error("from index", 0) end}).x
+end)
+assert(err == "from index")
+
+-- nested pcalls
+ok, err = pcall(function()
+    local ok2, err2 = pcall(error, "inner", 0)
+    assert(not ok2 and err2 == "inner")
+    error("outer", 0)
+end)
+assert(err == "outer")
+
+-- tostring and error values
+ok, err = pcall(error, setmetatable({}, {__tostring = function() return "custom error" end}))
+assert(tostring(err) == "custom error")
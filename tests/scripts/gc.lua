@@ -0,0 +1,46 @@
+-- collectgarbage frees cycles and runs finalizers
+local before = collectgarbage("count")
+assert(type(before) == "number" and before > 0)
+for i = 1, 100 do
+    local t = {}
+    t.self = t
+end
+local during = collectgarbage("count")
+assert(during > before)
+assert(collectgarbage() == 0)
+-- a register can still hold the last table
+assert(collectgarbage("count") < during - 5)
+
+local finalized = false
+do
+    local t = setmetatable({}, {__gc = function() finalized = true end})
+    t.self = t
+end
+-- steps eventually finish a collection, though one already going on
+-- might not have included the table
+local steps = 0
+repeat
+    steps = steps + 1
+until collectgarbage("step") and finalized or steps == 1000
+assert(finalized)
+assert(collectgarbage("isrunning"))
+
+assert(collectgarbage("incremental", 150, 300) == "incremental")
+assert(collectgarbage("generational", 10, 50) == "incremental")
+
+-- minor collections free cycles of new values
+finalized = false
+do
+    local t = setmetatable({}, {__gc = function() finalized = true end})
+    t.self = t
+end
+assert(collectgarbage("step"))
+assert(finalized)
+assert(collectgarbage("incremental") == "generational")
+assert(collectgarbage("setpause", 200) == 150)
+assert(collectgarbage("setstepmul", 100) == 300)
+assert(collectgarbage("setpause") == 200)
+collectgarbage("setpause", 200)
+
+local ok, err = pcall(collectgarbage, "sweep")
+assert(not ok and contains(err, "invalid option 'sweep'"))
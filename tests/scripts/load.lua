@@ -0,0 +1,39 @@
+-- load, environments and binary chunks
+
+local f = load("return 1 + 1")
+assert(f() == 2)
+f = load("local a, b = ... return a * b")
+assert(f(6, 7) == 42)
+
+local ok, err = load("return +")
+assert(ok == nil and contains(err, "unexpected symbol"))
+
+-- a custom environment
+local env = {x = 5}
+f = load("y = x * 2 return y", "chunk", "t", env)
+assert(f() == 10 and env.y == 10 and y == nil)
+
+-- a loader function giving pieces
+local parts = {"return ", "'pie", "ces'"}
+local i = 0
+f = load(function()
+    i = i + 1
+    return parts[i]
+end)
+assert(f() == "pieces")
+
+-- string.dump gives a binary chunk load takes back
+local function add(a, b) return a + b end
+local dumped = string.dump(add)
+assert(contains(dumped, "\27Lua"))
+local add2 = load(dumped, "add", "b")
+assert(add2(2, 3) == 5)
+ok, err = load(dumped, "add", "t")
+assert(ok == nil and contains(err, "attempt to load a binary chunk"))
+
+-- _G is the globals table
+assert(_G._G == _G and _G.print == print)
+global_value = 1
+assert(_G.global_value == 1)
+_G.global_value = nil
+assert(global_value == nil)
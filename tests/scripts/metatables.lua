@@ -0,0 +1,69 @@
+-- metatables and metamethods
+
+local Vec = {}
+Vec.__index = Vec
+function Vec.new(x, y) return setmetatable({x = x, y = y}, Vec) end
+function Vec.__add(a, b) return Vec.new(a.x + b.x, a.y + b.y) end
+function Vec.__eq(a, b) return a.x == b.x and a.y == b.y end
+function Vec.__lt(a, b) return a:len() < b:len() end
+function Vec.__le(a, b) return a:len() <= b:len() end
+function Vec.__tostring(v) return "(" .. v.x .. ", " .. v.y .. ")" end
+function Vec.__concat(a, b) return tostring(a) .. tostring(b) end
+function Vec.__unm(v) return Vec.new(-v.x, -v.y) end
+function Vec.__len() return 2 end
+function Vec.__call(v, k) return v[k] end
+function Vec:len() return self.x * self.x + self.y * self.y end
+
+local a, b = Vec.new(1, 2), Vec.new(3, 4)
+local c = a + b
+assert(c.x == 4 and c.y == 6)
+assert(a == Vec.new(1, 2) and a ~= b)
+assert(a < b and a <= b and not (b < a) and b > a)
+assert(tostring(a) == "(1, 2)" and a .. b == "(1, 2)(3, 4)")
+assert((-a).x == -1 and #a == 2 and a("y") == 2)
+
+-- __index and __newindex as tables and functions
+local log = {}
+local proxy = setmetatable({}, {
+    __index = function(_, k) return k .. "!" end,
+    __newindex = function(_, k, v) log[#log + 1] = k .. "=" .. v end,
+})
+assert(proxy.hi == "hi!")
+proxy.a = 1
+assert(log[1] == "a=1" and rawget(proxy, "a") == nil)
+local base = {greet = "hello"}
+local derived = setmetatable({}, {__index = setmetatable({}, {__index = base})})
+assert(derived.greet == "hello")
+
+-- a protected metatable
+local locked = setmetatable({}, {__metatable = "locked"})
+assert(getmetatable(locked) == "locked")
+assert(not pcall(setmetatable, locked, {}))
+
+-- __index chains that loop are caught
+local loop = {}
+setmetatable(loop, {__index = loop})
+assert(not pcall(function() return loop.x end))
+
+-- __close-free __gc metatables can still be set
+setmetatable({}, {__gc = function() end})
+
+-- arithmetic metamethods for strings and numbers mixing
+local Num = setmetatable({}, {__add = function(a, b) return "added" end})
+assert(Num + 1 == "added" and 1 + Num == "added")
+
+-- comparing different types never uses __eq
+local e = setmetatable({}, {__eq = function() return true end})
+assert(e ~= 1 and e == setmetatable({}, getmetatable(e)))
+
+-- metamethods added after a lookup found them missing are seen
+local mt = {}
+local obj = setmetatable({}, mt)
+assert(not pcall(function() return obj + 1 end))
+mt.__add = function() return "added" end
+assert(obj + 1 == "added")
+mt.__add = nil
+assert(not pcall(function() return obj + 1 end))
+assert(obj.missing == nil)
+rawset(mt, "__index", function(_, key) return key end)
+assert(obj.missing == "missing")
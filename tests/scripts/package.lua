@@ -0,0 +1,40 @@
+-- require, package.loaded and package.preload
+
+-- the libraries are already loaded
+assert(require("string") == string and package.loaded.table == table)
+
+-- a preloaded module runs once, with its name and where it was found
+local runs = 0
+package.preload.counter = function(name, place)
+    runs = runs + 1
+    assert(name == "counter" and place == ":preload:")
+    return {runs = runs}
+end
+local first, place = require("counter")
+assert(first.runs == 1 and place == ":preload:")
+assert(require("counter") == first and runs == 1)
+assert(package.loaded.counter == first)
+
+-- a module returning nothing is stored as true, unless it set its entry
+package.preload.quiet = function() end
+assert(require("quiet") == true)
+package.preload.own = function(name) package.loaded[name] = "set" end
+assert(require("own") == "set")
+
+-- a module not found lists everywhere it was looked for
+package.path = "./nowhere/?.lua;./nowhere/?/init.lua"
+local ok, err = pcall(require, "a.b")
+assert(not ok)
+assert(contains(err, "module 'a.b' not found:"), err)
+assert(contains(err, "no field package.preload['a.b']"), err)
+assert(contains(err, "no file './nowhere/a/b.lua'"), err)
+assert(contains(err, "no file './nowhere/a/b/init.lua'"), err)
+
+-- and errors from loading one reach the caller
+package.preload.broken = function() error("cannot start") end
+ok, err = pcall(require, "broken")
+assert(not ok and contains(err, "cannot start"))
+assert(package.loaded.broken == nil)
+
+ok, err = pcall(require)
+assert(not ok and contains(err, "bad argument #1 to 'require'"))
@@ -0,0 +1,38 @@
+-- string literals, escapes, concatenation and length
+
+assert("a" .. "b" .. 1 .. 2.0 == "ab12.0")
+assert(#"hello" == 5 and #"" == 0)
+assert("\65\066\x43\u{44}" == "ABCD")
+assert("a\z
+        b" == "ab")
+assert([[
+line]] == "line")
+assert([==[a]]b]==] == "a]]b")
+assert('\'' == "'" and "\"" == '"')
+assert(#"\0\0" == 2)
+
+local ok, err = pcall(function() return "a" .. {} end)
+assert(not ok and contains(err, "attempt to concatenate a table value"))
+
+-- long strings keep their bytes
+local s = "x"
+for i = 1, 10 do s = s .. s end
+assert(#s == 1024)
+
+-- strings compare by their bytes
+assert("\0a" < "\0b" and "" < "\0")
+assert(rawequal("abc", "a" .. "bc"))
+
+-- and are keys by their contents
+local t = {}
+t["k" .. 1] = true
+assert(t.k1)
+
+-- strings either side of the length stored inline behave alike
+local s = ""
+for i = 1, 40 do
+    s = s .. i % 10
+    local t = {[s] = i}
+    assert(#s == i and t[s] == i)
+    assert(s .. "" == s and s < s .. "!")
+end
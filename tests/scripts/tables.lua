@@ -0,0 +1,72 @@
+-- table constructors, length, iteration and raw access
+
+local t = {1, 2, 3, x = 1, ["y"] = 2, [10] = 10}
+assert(#t == 3 and t.x == 1 and t.y == 2 and t[10] == 10)
+
+local function three() return 1, 2, 3 end
+assert(#{three()} == 3 and #{three(), three()} == 4 and #{(three())} == 1)
+
+-- integral floats address the same slot as integers
+t = {}
+t[1.0] = "a"
+t[2] = "b"
+assert(t[1] == "a" and t[2.0] == "b" and #t == 2)
+
+assert(not pcall(function() local t = {} t[nil] = 1 end))
+assert(not pcall(function() local t = {} t[0 / 0] = 1 end))
+assert(({})[nil] == nil)
+
+-- a sequence filled in backwards moves to the array part once complete
+t = {}
+for i = 100, 1, -1 do t[i] = i end
+assert(#t == 100)
+local count = 0
+for k, v in pairs(t) do count = count + 1 assert(k == v) end
+assert(count == 100)
+t.extra = true
+assert(#t == 100 and t.extra)
+
+-- pairs visits every key once
+t = {10, 20, 30, a = 1, b = 2, c = 3}
+count = 0
+local sum = 0
+for k, v in pairs(t) do
+    count = count + 1
+    sum = sum + v
+end
+assert(count == 6 and sum == 66)
+
+-- keys can be removed while iterating
+for k in pairs(t) do t[k] = nil end
+assert(next(t) == nil)
+
+-- ipairs stops at the first nil
+local seen = {}
+for i, v in ipairs({1, 2, nil, 4}) do seen[#seen + 1] = v end
+assert(#seen == 2)
+
+-- rawget, rawset, rawlen and rawequal skip metamethods
+local mt = {__index = function() return "meta" end, __len = function() return 42 end}
+t = setmetatable({}, mt)
+assert(t.x == "meta" and rawget(t, "x") == nil)
+assert(#t == 42 and rawlen(t) == 0)
+rawset(t, "x", 1)
+assert(t.x == 1)
+
+assert(select("#", 1, nil, nil) == 3 and select(2, "a", "b", "c") == "b")
+assert(select(-1, "a", "b") == "b")
+local packed = table.pack(1, nil, 3)
+assert(packed.n == 3 and packed[3] == 3)
+assert(select("#", table.unpack({1, 2, 3})) == 3)
+assert(select("#", table.unpack({1, 2, 3}, 2)) == 2)
+
+-- keys are still found after the hash part is rebuilt, by removals or by
+-- a weak mode being set
+local keys = {}
+for i = 1, 200 do keys["k" .. i] = i end
+for i = 1, 150 do keys["k" .. i] = nil end
+keys.extra = true
+setmetatable(keys, {__mode = "k"})
+local found = 0
+for i = 151, 200 do if keys["k" .. i] == i then found = found + 1 end end
+assert(found == 50 and keys.extra and keys.k1 == nil)
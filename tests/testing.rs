@@ -0,0 +1,223 @@
+//! Test files run by `looa::testing`, and the reports of how they went
+
+extern crate looa;
+
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use looa::testing::{self, FileResult, Outcome, Runner};
+
+/// A directory of its own for `test` with the files `files` in it, given
+/// as names and sources
+fn suite(test: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("looa-testing-{}-{}", test, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    for &(name, source) in files {
+        let path = dir.join(name);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, source).unwrap();
+    }
+    dir
+}
+
+fn outcomes(file: &FileResult) -> Vec<(&str, &Outcome)> {
+    file.tests
+        .iter()
+        .map(|test| (&*test.name, &test.outcome))
+        .collect()
+}
+
+const SPEC: &str = "\
+local log = {}
+describe('outer', function()
+  before_each(function() log[#log + 1] = 'before' end)
+  after_each(function() log[#log + 1] = 'after' end)
+  it('passes', function() assert.equal(2, 1 + 1) end)
+  describe('inner', function()
+    after_each(function() log[#log + 1] = 'inner after' end)
+    it('fails', function() assert.equal('a', 'b') end)
+  end)
+  pending('is pending')
+end)
+it('saw the hooks', function()
+  assert.same({'before', 'after', 'before', 'inner after', 'after'}, log)
+end)
+";
+
+#[test]
+fn runs_describe_and_it() {
+    let dir = suite("describe", &[("a_spec.lua", SPEC)]);
+    let results = Runner::new().run(&[dir.join("a_spec.lua")]);
+    let file = &results[0];
+    assert_eq!(file.error, None);
+    let failure = format!(
+        "{}:8: expected \"a\", got \"b\"",
+        dir.join("a_spec.lua").display()
+    );
+    assert_eq!(
+        outcomes(file),
+        [
+            ("outer passes", &Outcome::Passed),
+            ("outer inner fails", &Outcome::Failed(failure)),
+            ("outer is pending", &Outcome::Pending),
+            ("saw the hooks", &Outcome::Passed),
+        ]
+    );
+    assert_eq!((file.passed(), file.failed(), file.pending()), (2, 1, 1));
+    assert!(!file.ok());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn asserts_say_what_was_wrong() {
+    let src = "\
+it('same', function() assert.same({1, {x = 2}}, {1, {x = 3}}) end)
+it('extra', function() assert.same({}, {y = 1}) end)
+it('near', function() assert.near(1, 1.05, 0.1) end)
+it('not near', function() assert.near(1, 2, 0.1) end)
+it('truthy', function() assert.truthy(false) end)
+it('is_nil', function() assert.is_nil(1, 'custom message') end)
+it('has_error', function() assert.has_error(function() error('boom') end, 'boo') end)
+it('no error', function() assert.has_error(function() end) end)
+it('plain', function() assert(false, 'plain assert') end)
+";
+    let dir = suite("asserts", &[("b_test.lua", src)]);
+    let results = Runner::new().run(&[dir.join("b_test.lua")]);
+    let messages: Vec<String> = results[0]
+        .tests
+        .iter()
+        .map(|test| match test.outcome {
+            // without the position, which the plain assert doesn't add
+            Outcome::Failed(ref msg) => match msg.split_once(": ") {
+                Some((at, rest)) if at.contains(".lua:") => rest.to_string(),
+                _ => msg.clone(),
+            },
+            ref outcome => format!("{:?}", outcome),
+        })
+        .collect();
+    assert_eq!(
+        messages,
+        [
+            "expected 2 at [2][\"x\"], got 3",
+            "unexpected 1 at [\"y\"]",
+            "Passed",
+            "expected 1 within 0.1, got 2",
+            "expected a truthy value, got false",
+            "custom message",
+            "Passed",
+            "expected an error",
+            "plain assert",
+        ]
+    );
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn files_run_apart_in_order() {
+    // a global set by one file is not seen by the others
+    let files: Vec<(String, String)> = (0..6)
+        .map(|i| {
+            let src = format!(
+                "it('is alone', function() assert.is_nil(shared) end)\nshared = {}",
+                i
+            );
+            (format!("f{}_spec.lua", i), src)
+        })
+        .collect();
+    let files: Vec<(&str, &str)> = files.iter().map(|(n, s)| (&**n, &**s)).collect();
+    let dir = suite("parallel", &files);
+    let found = testing::discover(&dir).unwrap();
+    assert_eq!(found.len(), 6);
+    let mut runner = Runner::new();
+    runner.set_jobs(3);
+    let results = runner.run(&found);
+    assert_eq!(
+        results.iter().map(|r| r.path.clone()).collect::<Vec<_>>(),
+        found
+    );
+    assert!(results.iter().all(FileResult::ok));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn errors_outside_tests_stop_the_file() {
+    let dir = suite(
+        "errors",
+        &[
+            ("syntax_spec.lua", "it('x', function() end) local = 1"),
+            (
+                "runtime_spec.lua",
+                "it('runs', function() end)\ndescribe('d', function() error('in d') end)\nerror('late')",
+            ),
+        ],
+    );
+    let found = testing::discover(&dir).unwrap();
+    let results = Runner::new().run(&found);
+    let runtime = &results[0];
+    assert_eq!(runtime.tests.len(), 2);
+    assert!(matches!(runtime.tests[1].outcome, Outcome::Failed(ref msg) if msg.ends_with("in d")));
+    assert!(runtime.error.as_ref().unwrap().ends_with("late"));
+    let syntax = &results[1];
+    assert!(syntax.tests.is_empty());
+    assert!(syntax.error.as_ref().unwrap().starts_with("syntax error"));
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn filters_and_sets_up_states() {
+    let dir = suite(
+        "filter",
+        &[(
+            "c_spec.lua",
+            "it('one', function() end)\nit('two', function() assert.equal(1, given) end)",
+        )],
+    );
+    let seen = Arc::new(Mutex::new(0));
+    let counter = seen.clone();
+    let mut runner = Runner::new();
+    runner.set_filter(Some("two".to_string()));
+    runner.set_setup(Some(Arc::new(move |lua: &mut looa::Lua| {
+        *counter.lock().unwrap() += 1;
+        lua.set_global("given", looa::Value::new(1)).unwrap();
+    })));
+    let results = runner.run(&[dir.join("c_spec.lua")]);
+    assert_eq!(outcomes(&results[0]), [("two", &Outcome::Passed)]);
+    assert_eq!(*seen.lock().unwrap(), 1);
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn writes_tap_and_junit() {
+    let dir = suite(
+        "reports",
+        &[("a_spec.lua", SPEC), ("bad_spec.lua", "local = 1")],
+    );
+    let results = Runner::new().run(&testing::discover(&dir).unwrap());
+    let tap = testing::tap(&results);
+    let a = dir.join("a_spec.lua");
+    let a = a.display();
+    assert!(tap.starts_with("TAP version 13\n1..5\n"), "{}", tap);
+    assert!(
+        tap.contains(&format!("\nok 1 - {}: outer passes\n", a)),
+        "{}",
+        tap
+    );
+    assert!(tap.contains(&format!("\nnot ok 2 - {}: outer inner fails\n  ---\n", a)));
+    assert!(tap.contains(&format!(
+        "\nok 3 - {}: outer is pending # SKIP pending\n",
+        a
+    )));
+    assert!(tap.contains("\nnot ok 5 - "), "{}", tap);
+    let junit = testing::junit(&results);
+    assert!(junit.starts_with("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites tests=\"4\" failures=\"1\" errors=\"1\" skipped=\"1\""), "{}", junit);
+    assert!(junit.contains("<failure message=\""), "{}", junit);
+    assert!(
+        junit.contains("expected &quot;a&quot;, got &quot;b&quot;"),
+        "{}",
+        junit
+    );
+    assert!(junit.contains("<skipped/>"), "{}", junit);
+    assert!(junit.trim_end().ends_with("</testsuites>"));
+    let _ = fs::remove_dir_all(&dir);
+}